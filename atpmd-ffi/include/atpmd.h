@@ -0,0 +1,275 @@
+#ifndef ATPMD_H
+#define ATPMD_H
+
+/* Generated with cbindgen from atpmd-ffi, see the README */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * The result of a call
+ */
+typedef enum AtpmdStatus {
+  ATPMD_STATUS_OK = 0,
+  /**
+   * A pointer is null
+   */
+  ATPMD_STATUS_NULL_POINTER = 1,
+  /**
+   * A buffer is not a valid key, request, response or token
+   */
+  ATPMD_STATUS_MALFORMED = 2,
+  /**
+   * The signature or the proof does not verify, or it is for another key
+   */
+  ATPMD_STATUS_BAD_SIGNATURE = 3,
+  /**
+   * The request has already been unrandomized
+   */
+  ATPMD_STATUS_UNRANDOMIZED = 4,
+  /**
+   * Any other error, e.g. a panic
+   */
+  ATPMD_STATUS_FAILED = 5,
+} AtpmdStatus;
+
+/**
+ * A token of a client, randomized for the issuer
+ */
+typedef struct AtpmdNizkpRequest AtpmdNizkpRequest;
+
+/**
+ * A token of a client, randomized for the issuer
+ */
+typedef struct AtpmdPairingRequest AtpmdPairingRequest;
+
+/**
+ * A buffer that is owned by the library
+ */
+typedef struct AtpmdBuffer {
+  uint8_t *data;
+  size_t len;
+} AtpmdBuffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Free a buffer that the library returned, the bytes are zeroized first
+ *
+ * # Safety
+ *
+ * The buffer is one the library returned, and it is not used or freed again.
+ */
+void atpmd_buffer_free(AtpmdBuffer buffer);
+
+/**
+ * Generate a private key, to be stored by the issuer
+ *
+ * # Safety
+ *
+ * `out_key` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_private_key_new(AtpmdBuffer *out_key);
+
+/**
+ * The public key of a private key, for the clients to check the proofs
+ *
+ * # Safety
+ *
+ * `key` is valid for `key_len` bytes, and `out_public_key` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_public_key(const uint8_t *key, size_t key_len, AtpmdBuffer *out_public_key);
+
+/**
+ * Sign the randomized token of a client
+ *
+ * # Safety
+ *
+ * `key` and `request` are valid for their lengths, and `out_response` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_sign(const uint8_t *key,
+                             size_t key_len,
+                             const uint8_t *request,
+                             size_t request_len,
+                             AtpmdBuffer *out_response);
+
+/**
+ * Generate a token with the public metadata, and randomize it
+ *
+ * # Safety
+ *
+ * `metadata` is valid for `metadata_len` bytes, and `out_request` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_request_new(const uint8_t *metadata,
+                                    size_t metadata_len,
+                                    AtpmdNizkpRequest **out_request);
+
+/**
+ * The randomized token, to be sent to the issuer
+ *
+ * # Safety
+ *
+ * `request` is a live request, and `out_randomized` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_request_randomized(const AtpmdNizkpRequest *request,
+                                           AtpmdBuffer *out_randomized);
+
+/**
+ * Verify the proof of the issuer, and remove the randomization
+ *
+ * The request is kept if the response does not verify, such that it may be tried with another.
+ *
+ * # Safety
+ *
+ * `request` is a live request, `response` and `public_key` are valid for their lengths, and
+ * `out_token` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_request_unrandomize(AtpmdNizkpRequest *request,
+                                            const uint8_t *response,
+                                            size_t response_len,
+                                            const uint8_t *public_key,
+                                            size_t public_key_len,
+                                            AtpmdBuffer *out_token);
+
+/**
+ * Free a request
+ *
+ * # Safety
+ *
+ * `request` is null, or a request that is not used or freed again.
+ */
+void atpmd_nizkp_request_free(AtpmdNizkpRequest *request);
+
+/**
+ * Verify a token with the private key of the issuer
+ *
+ * # Safety
+ *
+ * `token` and `key` are valid for their lengths.
+ */
+AtpmdStatus atpmd_nizkp_verify(const uint8_t *token,
+                               size_t token_len,
+                               const uint8_t *key,
+                               size_t key_len);
+
+/**
+ * The public metadata of a token, without verifying it
+ *
+ * # Safety
+ *
+ * `token` is valid for `token_len` bytes, and `out_metadata` is valid for a write.
+ */
+AtpmdStatus atpmd_nizkp_token_metadata(const uint8_t *token,
+                                       size_t token_len,
+                                       AtpmdBuffer *out_metadata);
+
+/**
+ * Generate a private key, to be stored by the issuer
+ *
+ * # Safety
+ *
+ * `out_key` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_private_key_new(AtpmdBuffer *out_key);
+
+/**
+ * The public key of a private key, for the clients and the verifiers
+ *
+ * # Safety
+ *
+ * `key` is valid for `key_len` bytes, and `out_public_key` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_public_key(const uint8_t *key,
+                                     size_t key_len,
+                                     AtpmdBuffer *out_public_key);
+
+/**
+ * Sign the randomized token of a client
+ *
+ * # Safety
+ *
+ * `key` and `request` are valid for their lengths, and `out_response` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_sign(const uint8_t *key,
+                               size_t key_len,
+                               const uint8_t *request,
+                               size_t request_len,
+                               AtpmdBuffer *out_response);
+
+/**
+ * Generate a token with the public metadata, and randomize it
+ *
+ * # Safety
+ *
+ * `metadata` is valid for `metadata_len` bytes, and `out_request` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_request_new(const uint8_t *metadata,
+                                      size_t metadata_len,
+                                      AtpmdPairingRequest **out_request);
+
+/**
+ * The randomized token, to be sent to the issuer
+ *
+ * # Safety
+ *
+ * `request` is a live request, and `out_randomized` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_request_randomized(const AtpmdPairingRequest *request,
+                                             AtpmdBuffer *out_randomized);
+
+/**
+ * Verify the response of the issuer, and remove the randomization
+ *
+ * The request is kept if the response does not verify, such that it may be tried with another.
+ *
+ * # Safety
+ *
+ * `request` is a live request, `response` and `public_key` are valid for their lengths, and
+ * `out_token` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_request_unrandomize(AtpmdPairingRequest *request,
+                                              const uint8_t *response,
+                                              size_t response_len,
+                                              const uint8_t *public_key,
+                                              size_t public_key_len,
+                                              AtpmdBuffer *out_token);
+
+/**
+ * Free a request
+ *
+ * # Safety
+ *
+ * `request` is null, or a request that is not used or freed again.
+ */
+void atpmd_pairing_request_free(AtpmdPairingRequest *request);
+
+/**
+ * Verify a token with the public key of the issuer
+ *
+ * # Safety
+ *
+ * `token` and `public_key` are valid for their lengths.
+ */
+AtpmdStatus atpmd_pairing_verify(const uint8_t *token,
+                                 size_t token_len,
+                                 const uint8_t *public_key,
+                                 size_t public_key_len);
+
+/**
+ * The public metadata of a token, without verifying it
+ *
+ * # Safety
+ *
+ * `token` is valid for `token_len` bytes, and `out_metadata` is valid for a write.
+ */
+AtpmdStatus atpmd_pairing_token_metadata(const uint8_t *token,
+                                         size_t token_len,
+                                         AtpmdBuffer *out_metadata);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* ATPMD_H */
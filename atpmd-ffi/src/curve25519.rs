@@ -0,0 +1,284 @@
+//! The curve25519 engine, the tokens are verified with the private key of the issuer
+
+use atpmd::nizkp_curve25519::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{NizkpSignedToken, NizkpTokenEngine, RandomizedSignedToken, RandomizedUnsignedToken},
+};
+use atpmd::{SignedToken, TokenEngine};
+
+use crate::{
+    bytes, decode, decode_private, encode, encode_private, output, run, write, AtpmdBuffer,
+    AtpmdStatus, Request,
+};
+
+type Engine = NizkpTokenEngine<Box<[u8]>>;
+
+// {{{ Issuer
+
+/// Generate a private key, to be stored by the issuer
+///
+/// # Safety
+///
+/// `out_key` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_private_key_new(out_key: *mut AtpmdBuffer) -> AtpmdStatus {
+    run(|| {
+        let out_key = output(out_key)?;
+        write(out_key, encode_private(&PrivateKey::new())?)
+    })
+}
+
+/// The public key of a private key, for the clients to check the proofs
+///
+/// # Safety
+///
+/// `key` is valid for `key_len` bytes, and `out_public_key` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_public_key(
+    key: *const u8,
+    key_len: usize,
+    out_public_key: *mut AtpmdBuffer,
+) -> AtpmdStatus {
+    run(|| {
+        let out_public_key = output(out_public_key)?;
+        let key: PrivateKey = decode_private(bytes(key, key_len)?)?;
+        write(out_public_key, encode(&PublicKey::from(&key))?)
+    })
+}
+
+/// Sign the randomized token of a client
+///
+/// # Safety
+///
+/// `key` and `request` are valid for their lengths, and `out_response` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_sign(
+    key: *const u8,
+    key_len: usize,
+    request: *const u8,
+    request_len: usize,
+    out_response: *mut AtpmdBuffer,
+) -> AtpmdStatus {
+    run(|| {
+        let out_response = output(out_response)?;
+        let key: PrivateKey = decode_private(bytes(key, key_len)?)?;
+        let request: RandomizedUnsignedToken<Box<[u8]>> = decode(bytes(request, request_len)?)?;
+        let response = Engine::sign_randomized(&request, &key)?;
+        write(out_response, encode(&response)?)
+    })
+}
+
+// }}}
+
+// {{{ Client
+
+/// A token of a client, randomized for the issuer
+pub struct AtpmdNizkpRequest(Request<Engine>);
+
+/// Generate a token with the public metadata, and randomize it
+///
+/// # Safety
+///
+/// `metadata` is valid for `metadata_len` bytes, and `out_request` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_request_new(
+    metadata: *const u8,
+    metadata_len: usize,
+    out_request: *mut *mut AtpmdNizkpRequest,
+) -> AtpmdStatus {
+    run(|| {
+        let out_request = output(out_request)?;
+        let metadata = Box::from(bytes(metadata, metadata_len)?);
+        let request = AtpmdNizkpRequest(Request::new(Engine::generate(metadata)));
+        out_request.as_ptr().write(Box::into_raw(Box::new(request)));
+        Ok(())
+    })
+}
+
+/// The randomized token, to be sent to the issuer
+///
+/// # Safety
+///
+/// `request` is a live request, and `out_randomized` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_request_randomized(
+    request: *const AtpmdNizkpRequest,
+    out_randomized: *mut AtpmdBuffer,
+) -> AtpmdStatus {
+    run(|| {
+        let out_randomized = output(out_randomized)?;
+        let request = request.as_ref().ok_or(AtpmdStatus::NullPointer)?;
+        write(out_randomized, encode(request.0.randomized()?)?)
+    })
+}
+
+/// Verify the proof of the issuer, and remove the randomization
+///
+/// The request is kept if the response does not verify, such that it may be tried with another.
+///
+/// # Safety
+///
+/// `request` is a live request, `response` and `public_key` are valid for their lengths, and
+/// `out_token` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_request_unrandomize(
+    request: *mut AtpmdNizkpRequest,
+    response: *const u8,
+    response_len: usize,
+    public_key: *const u8,
+    public_key_len: usize,
+    out_token: *mut AtpmdBuffer,
+) -> AtpmdStatus {
+    run(|| {
+        let out_token = output(out_token)?;
+        let request = request.as_mut().ok_or(AtpmdStatus::NullPointer)?;
+        let response: RandomizedSignedToken<Box<[u8]>> = decode(bytes(response, response_len)?)?;
+        let public_key: PublicKey = decode(bytes(public_key, public_key_len)?)?;
+        let token = request.0.unrandomize(response, &public_key)?;
+        write(out_token, encode(&token)?)
+    })
+}
+
+/// Free a request
+///
+/// # Safety
+///
+/// `request` is null, or a request that is not used or freed again.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_request_free(request: *mut AtpmdNizkpRequest) {
+    if !request.is_null() {
+        drop(Box::from_raw(request));
+    }
+}
+
+// }}}
+
+// {{{ Verifier
+
+/// Verify a token with the private key of the issuer
+///
+/// # Safety
+///
+/// `token` and `key` are valid for their lengths.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_verify(
+    token: *const u8,
+    token_len: usize,
+    key: *const u8,
+    key_len: usize,
+) -> AtpmdStatus {
+    run(|| {
+        let token: NizkpSignedToken<Box<[u8]>> = decode(bytes(token, token_len)?)?;
+        let key: PrivateKey = decode_private(bytes(key, key_len)?)?;
+        Ok(Engine::verify(&token, &key)?)
+    })
+}
+
+/// The public metadata of a token, without verifying it
+///
+/// # Safety
+///
+/// `token` is valid for `token_len` bytes, and `out_metadata` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_nizkp_token_metadata(
+    token: *const u8,
+    token_len: usize,
+    out_metadata: *mut AtpmdBuffer,
+) -> AtpmdStatus {
+    run(|| {
+        let out_metadata = output(out_metadata)?;
+        let token: NizkpSignedToken<Box<[u8]>> = decode(bytes(token, token_len)?)?;
+        write(out_metadata, token.metadata_bytes().to_vec())
+    })
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::ptr;
+
+    use crate::tests::{empty, take};
+
+    fn call(f: impl FnOnce(*mut AtpmdBuffer) -> AtpmdStatus) -> Vec<u8> {
+        let mut buffer = empty();
+        assert_eq!(f(&mut buffer), AtpmdStatus::Ok);
+        take(buffer)
+    }
+
+    #[test]
+    fn test_issue() {
+        let key = call(|out| unsafe { atpmd_nizkp_private_key_new(out) });
+        let public_key =
+            call(|out| unsafe { atpmd_nizkp_public_key(key.as_ptr(), key.len(), out) });
+
+        let mut request = ptr::null_mut();
+        let status = unsafe { atpmd_nizkp_request_new(b"resource".as_ptr(), 8, &mut request) };
+        assert_eq!(status, AtpmdStatus::Ok);
+        let randomized = call(|out| unsafe { atpmd_nizkp_request_randomized(request, out) });
+        let response = call(|out| unsafe {
+            atpmd_nizkp_sign(
+                key.as_ptr(),
+                key.len(),
+                randomized.as_ptr(),
+                randomized.len(),
+                out,
+            )
+        });
+
+        // a proof for another key does not verify, and the request is kept
+        let other_key = call(|out| unsafe { atpmd_nizkp_private_key_new(out) });
+        let other =
+            call(|out| unsafe { atpmd_nizkp_public_key(other_key.as_ptr(), other_key.len(), out) });
+        let mut token = empty();
+        let unrandomize = |public_key: &[u8], token: *mut AtpmdBuffer| unsafe {
+            atpmd_nizkp_request_unrandomize(
+                request,
+                response.as_ptr(),
+                response.len(),
+                public_key.as_ptr(),
+                public_key.len(),
+                token,
+            )
+        };
+        assert_eq!(unrandomize(&other, &mut token), AtpmdStatus::BadSignature);
+        assert_eq!(unrandomize(&public_key, &mut token), AtpmdStatus::Ok);
+        let token = take(token);
+        assert_eq!(
+            unrandomize(&public_key, &mut empty()),
+            AtpmdStatus::Unrandomized
+        );
+        unsafe { atpmd_nizkp_request_free(request) };
+
+        let verify = |key: &[u8]| unsafe {
+            atpmd_nizkp_verify(token.as_ptr(), token.len(), key.as_ptr(), key.len())
+        };
+        assert_eq!(verify(&key), AtpmdStatus::Ok);
+        assert_eq!(verify(&other_key), AtpmdStatus::BadSignature);
+        assert_eq!(verify(b"nonsense"), AtpmdStatus::Malformed);
+        assert_eq!(
+            call(|out| unsafe { atpmd_nizkp_token_metadata(token.as_ptr(), token.len(), out) }),
+            b"resource"
+        );
+    }
+
+    #[test]
+    fn fail_null() {
+        assert_eq!(
+            unsafe { atpmd_nizkp_private_key_new(ptr::null_mut()) },
+            AtpmdStatus::NullPointer
+        );
+        let mut buffer = empty();
+        assert_eq!(
+            unsafe { atpmd_nizkp_request_randomized(ptr::null(), &mut buffer) },
+            AtpmdStatus::NullPointer
+        );
+        assert_eq!(
+            unsafe { atpmd_nizkp_public_key(ptr::null(), 32, &mut buffer) },
+            AtpmdStatus::NullPointer
+        );
+        unsafe { atpmd_nizkp_request_free(ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,219 @@
+//! # C bindings of atpmd
+//!
+//! `extern "C"` functions over the pairing and curve25519 engines, for issuers and verifiers that
+//! are not written in Rust. The header is `include/atpmd.h`, generated with
+//! `cbindgen --config cbindgen.toml --output include/atpmd.h`.
+//!
+//! Everything is passed as byte buffers. The public keys, requests, responses and tokens are in
+//! the wire format of `atpmd::wire`, and the private keys are opaque bytes for the issuer to
+//! store. The buffers the library returns are freed with [`atpmd_buffer_free`], and the token
+//! requests of a client are handles that are freed with `atpmd_*_request_free`.
+//!
+//! Every function returns an [`AtpmdStatus`], the outputs are only written on
+//! [`AtpmdStatus::Ok`]. A panic is caught and returned as [`AtpmdStatus::Failed`].
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use atpmd::wire::WireFormat;
+use atpmd::{Error, TokenEngine, VerifyError, Zeroize};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub mod curve25519;
+pub mod pairing;
+
+// {{{ Status
+
+/// The result of a call
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtpmdStatus {
+    Ok = 0,
+    /// A pointer is null
+    NullPointer = 1,
+    /// A buffer is not a valid key, request, response or token
+    Malformed = 2,
+    /// The signature or the proof does not verify, or it is for another key
+    BadSignature = 3,
+    /// The request has already been unrandomized
+    Unrandomized = 4,
+    /// Any other error, e.g. a panic
+    Failed = 5,
+}
+
+impl From<Error> for AtpmdStatus {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::BadProof
+            | Error::BadSignature
+            | Error::KeyMismatch
+            | Error::MetadataMismatch => AtpmdStatus::BadSignature,
+            Error::MalformedPoint | Error::Malformed(_) => AtpmdStatus::Malformed,
+            _ => AtpmdStatus::Failed,
+        }
+    }
+}
+
+/// Run the body of a call, such that a panic does not unwind into C
+fn run(f: impl FnOnce() -> Result<(), AtpmdStatus>) -> AtpmdStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => AtpmdStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => AtpmdStatus::Failed,
+    }
+}
+
+// }}}
+
+// {{{ Buffers
+
+/// A buffer that is owned by the library
+#[repr(C)]
+pub struct AtpmdBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl AtpmdBuffer {
+    fn new(bytes: Vec<u8>) -> Self {
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+        Self {
+            data: bytes as *mut u8,
+            len: bytes.len(),
+        }
+    }
+}
+
+/// Free a buffer that the library returned, the bytes are zeroized first
+///
+/// # Safety
+///
+/// The buffer is one the library returned, and it is not used or freed again.
+#[no_mangle]
+pub unsafe extern "C" fn atpmd_buffer_free(buffer: AtpmdBuffer) {
+    if !buffer.data.is_null() {
+        let mut bytes = Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len));
+        bytes.zeroize();
+    }
+}
+
+/// The bytes of an input, a null pointer is only allowed for an empty input
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8], AtpmdStatus> {
+    match data.is_null() {
+        true if len == 0 => Ok(&[]),
+        true => Err(AtpmdStatus::NullPointer),
+        false => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+fn output<T>(out: *mut T) -> Result<NonNull<T>, AtpmdStatus> {
+    NonNull::new(out).ok_or(AtpmdStatus::NullPointer)
+}
+
+unsafe fn write(out: NonNull<AtpmdBuffer>, bytes: Vec<u8>) -> Result<(), AtpmdStatus> {
+    out.as_ptr().write(AtpmdBuffer::new(bytes));
+    Ok(())
+}
+
+fn decode<T: WireFormat>(bytes: &[u8]) -> Result<T, AtpmdStatus> {
+    T::from_bytes(bytes).map_err(|_| AtpmdStatus::Malformed)
+}
+
+fn encode<T: WireFormat>(value: &T) -> Result<Vec<u8>, AtpmdStatus> {
+    value.try_to_bytes().map_err(|_| AtpmdStatus::Malformed)
+}
+
+/// The private keys are stored as their serde serialization
+fn decode_private<K: DeserializeOwned>(bytes: &[u8]) -> Result<K, AtpmdStatus> {
+    serde_json::from_slice(bytes).map_err(|_| AtpmdStatus::Malformed)
+}
+
+fn encode_private<K: Serialize>(key: &K) -> Result<Vec<u8>, AtpmdStatus> {
+    serde_json::to_vec(key).map_err(|_| AtpmdStatus::Failed)
+}
+
+// }}}
+
+// {{{ Request
+
+/// A randomized token of a client, waiting for the signature of the issuer
+struct Request<E: TokenEngine> {
+    state: Option<(
+        E::UnsignedToken,
+        E::RandomizedUnsignedToken,
+        E::Randomization,
+    )>,
+}
+
+impl<E: TokenEngine> Request<E> {
+    fn new(unsigned_token: E::UnsignedToken) -> Self {
+        let (r, randomized) = E::randomize(&unsigned_token);
+        Self {
+            state: Some((unsigned_token, randomized, r)),
+        }
+    }
+
+    fn randomized(&self) -> Result<&E::RandomizedUnsignedToken, AtpmdStatus> {
+        match &self.state {
+            Some((_, randomized, _)) => Ok(randomized),
+            None => Err(AtpmdStatus::Unrandomized),
+        }
+    }
+
+    /// Check the signature and remove the randomization, the token is kept if it fails
+    fn unrandomize(
+        &mut self,
+        signed: E::RandomizedSignedToken,
+        verification_data: &E::UserVerification,
+    ) -> Result<E::SignedToken, AtpmdStatus> {
+        let (unsigned_token, randomized, r) = self.state.take().ok_or(AtpmdStatus::Unrandomized)?;
+
+        E::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized,
+            signed,
+            verification_data,
+            r,
+        )
+        .map_err(|e: VerifyError<E>| {
+            self.state = Some((e.unsigned_token, e.randomized_unsigned, e.randomization));
+            e.error.into()
+        })
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The bytes of a buffer, which is then freed
+    pub(crate) fn take(buffer: AtpmdBuffer) -> Vec<u8> {
+        let bytes = unsafe { slice::from_raw_parts(buffer.data, buffer.len) }.to_vec();
+        unsafe { atpmd_buffer_free(buffer) };
+        bytes
+    }
+
+    pub(crate) fn empty() -> AtpmdBuffer {
+        AtpmdBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    #[test]
+    fn test_buffers() {
+        assert_eq!(take(AtpmdBuffer::new(b"abc".to_vec())), b"abc");
+        assert_eq!(take(AtpmdBuffer::new(Vec::new())), b"");
+        unsafe { atpmd_buffer_free(empty()) };
+
+        assert_eq!(unsafe { bytes(ptr::null(), 0) }, Ok(&[][..]));
+        assert_eq!(
+            unsafe { bytes(ptr::null(), 1) },
+            Err(AtpmdStatus::NullPointer)
+        );
+        assert_eq!(run(|| panic!("oops")), AtpmdStatus::Failed);
+    }
+}
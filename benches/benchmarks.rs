@@ -1,8 +1,15 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
 
 use atpmd::{
-    nizkp_curve25519::{self, tokens::NizkpTokenEngine, tokens_batched::BatchedNizkpTokenEngine},
-    atpm_pairing::{self, tokens::PairingTokenEngine, tokens_batched::BatchedPairingTokenEngine},
+    nizkp_curve25519::{
+        self, tokens::NizkpTokenEngine, tokens_batched::BatchedNizkpTokenEngine,
+        tokens_batched_dyn::DynBatchedNizkpTokenEngine,
+    },
+    atpm_pairing::{
+        self, aggregate::AggregateToken, prepared::PreparedPublicKey, tokens::PairingTokenEngine,
+        tokens_batched::BatchedPairingTokenEngine,
+        tokens_batched_dyn::DynBatchedPairingTokenEngine,
+    },
     SignedToken, TokenEngine, UnsignedToken,
 };
 
@@ -27,8 +34,8 @@ where
     unsigned_tokens
         .into_iter()
         .map(|unsigned: E::UnsignedToken| {
-            E::sign(unsigned, &public_key, |randomized| {
-                E::sign_randomized(randomized, &secret_key)
+            E::sign(unsigned, public_key, |randomized| {
+                E::sign_randomized(randomized, secret_key)
             })
         })
         .map(|maybe_signed| maybe_signed.unwrap())
@@ -49,8 +56,8 @@ where
     let metadata = b"this is some metadata";
 
     let unsigned = E::generate(metadata);
-    E::sign(unsigned, &public_key, |randomized| {
-        E::sign_randomized(randomized, &secret_key)
+    E::sign(unsigned, public_key, |randomized| {
+        E::sign_randomized(randomized, secret_key)
     })
     .unwrap()
 }
@@ -74,14 +81,11 @@ fn bench_all(c: &mut Criterion) {
 
         group.bench_function("pairing", |b| {
             b.iter(|| {
-                black_box(
-                    std::iter::repeat_with(|| PairingTokenEngine::generate(metadata))
-                        .take(10)
-                        .fold((), |s, elem| {
-                            black_box(elem);
-                            s
-                        }),
-                )
+                std::iter::repeat_with(|| PairingTokenEngine::generate(metadata))
+                    .take(10)
+                    .for_each(|elem| {
+                        black_box(elem);
+                    })
             })
         });
 
@@ -91,14 +95,11 @@ fn bench_all(c: &mut Criterion) {
 
         group.bench_function("nizkp", |b| {
             b.iter(|| {
-                black_box(
-                    std::iter::repeat_with(|| NizkpTokenEngine::generate(metadata))
-                        .take(10)
-                        .fold((), |s, elem| {
-                            black_box(elem);
-                            s
-                        }),
-                )
+                std::iter::repeat_with(|| NizkpTokenEngine::generate(metadata))
+                    .take(10)
+                    .for_each(|elem| {
+                        black_box(elem);
+                    })
             })
         });
 
@@ -171,14 +172,43 @@ fn bench_all(c: &mut Criterion) {
 
         group.bench_function("pairing", |b| {
             b.iter(|| {
-                assert!(
-                    (tokens
-                        .iter()
-                        .fold(true, |s, token| s && token.verify(&pairing_public_key)))
-                )
+                assert!(black_box(
+                    tokens.iter().all(|token| token.verify(&pairing_public_key))
+                ))
+            })
+        });
+
+        let prepared = PreparedPublicKey::from(&pairing_public_key);
+
+        group.bench_function("pairing prepared", |b| {
+            b.iter(|| assert!(black_box(tokens.iter().all(|token| prepared.verify(token)))))
+        });
+
+        group.bench_function("pairing verify_batch", |b| {
+            b.iter(|| {
+                assert!(black_box(atpm_pairing::tokens::verify_batch(
+                    &tokens,
+                    &pairing_public_key
+                )))
             })
         });
 
+        group.bench_function("pairing prepared verify_batch", |b| {
+            b.iter(|| assert!(black_box(prepared.verify_batch(&tokens))))
+        });
+
+        let verifier = prepared.verifier(b"this is some metadata");
+
+        group.bench_function("pairing prepared verifier", |b| {
+            b.iter(|| assert!(black_box(tokens.iter().all(|token| verifier.verify(token)))))
+        });
+
+        let aggregate = AggregateToken::aggregate(&tokens);
+
+        group.bench_function("pairing aggregate", |b| {
+            b.iter(|| assert!(black_box(aggregate.verify(&pairing_public_key))))
+        });
+
         let token = get_token::<BatchedPairingTokenEngine<&[u8; 21], 10>>(
             &pairing_public_key,
             &pairing_private_key,
@@ -194,9 +224,7 @@ fn bench_all(c: &mut Criterion) {
         group.bench_function("nizkp", |b| {
             b.iter(|| {
                 assert!(black_box(
-                    tokens
-                        .iter()
-                        .fold(true, |s, token| s && token.verify(&nizkp_private_key))
+                    tokens.iter().all(|token| token.verify(&nizkp_private_key))
                 ))
             })
         });
@@ -479,5 +507,92 @@ fn bench_verify_pairing_batched(c: &mut Criterion) {
 
 // }}}
 
-criterion_group!(benches, bench_all, bench_verify_pairing_batched);
+// {{{ dyn batches -- compare with and without the parallel feature
+
+fn bench_dyn_batched(c: &mut Criterion) {
+    let pairing_private_key = atpm_pairing::keys::PrivateKey::new();
+    let pairing_public_key = atpm_pairing::keys::PublicKey::from(&pairing_private_key);
+
+    let nizkp_private_key = nizkp_curve25519::keys::PrivateKey::new();
+    let nizkp_public_key = nizkp_curve25519::keys::PublicKey::from(&nizkp_private_key);
+
+    let metadata = &b"dummy metadata"[..];
+
+    let mut group = c.benchmark_group("dyn batch 512");
+
+    macro_rules! benchmark {
+        ($type:ty, $sign_key:expr, $verify_key:expr, $v_key:expr, $name:expr, $group:expr) => {
+            let token = <$type>::generate((metadata, 512));
+            let (r, randomized) = <$type>::randomize(&token);
+            let signed = <$type>::sign_randomized(&randomized, &$sign_key).unwrap();
+
+            $group.bench_function(concat!($name, " sign_randomized"), |b| {
+                b.iter(|| black_box(<$type>::sign_randomized(&randomized, &$sign_key).unwrap()))
+            });
+
+            let tokens =
+                <$type>::verify_signature_and_unrandomize(token, randomized, signed, &$verify_key, r)
+                    .ok()
+                    .unwrap();
+
+            $group.bench_function(concat!($name, " unrandomize"), |b| {
+                b.iter_batched(
+                    || {
+                        let token = <$type>::generate((metadata, 512));
+                        let (r, randomized) = <$type>::randomize(&token);
+                        let signed = <$type>::sign_randomized(&randomized, &$sign_key).unwrap();
+                        (token, randomized, signed, r)
+                    },
+                    |(token, randomized, signed, r)| {
+                        black_box(
+                            <$type>::verify_signature_and_unrandomize(
+                                token,
+                                randomized,
+                                signed,
+                                &$verify_key,
+                                r,
+                            )
+                            .ok()
+                            .unwrap(),
+                        )
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+
+            $group.bench_function(concat!($name, " verify"), |b| {
+                b.iter(|| assert!(black_box(tokens.verify(&$v_key))))
+            });
+        };
+    }
+
+    benchmark!(
+        DynBatchedPairingTokenEngine<_>,
+        pairing_private_key,
+        pairing_public_key,
+        pairing_public_key,
+        "pairing",
+        group
+    );
+
+    benchmark!(
+        DynBatchedNizkpTokenEngine<_>,
+        nizkp_private_key,
+        nizkp_public_key,
+        nizkp_private_key,
+        "nizkp",
+        group
+    );
+
+    group.finish();
+}
+
+// }}}
+
+criterion_group!(
+    benches,
+    bench_all,
+    bench_verify_pairing_batched,
+    bench_dyn_batched
+);
 criterion_main!(benches);
@@ -0,0 +1,256 @@
+mod util;
+
+use atpmd::atpm_pairing::{
+    keys::PublicKey,
+    refusal::PairingSignedRefusal,
+    tokens::{PairingSignedToken, PairingTokenEngine, RandomizedSignedToken},
+    tokens_batched_dyn::{
+        DynBatchedPairingTokenEngine, DynBatchedRandomizedSignedToken,
+        DynBatchedRandomizedUnsignedToken,
+    },
+};
+use atpmd::chunked::{yield_now, Chunking, YieldNow};
+use atpmd::discovery::{discover, DiscoveryError, IssuerDocument};
+use atpmd::http::HeaderToken;
+use atpmd::issuance::{IssuanceRequestMsg, IssuanceResponseMsg};
+use atpmd::jwk::JwkSet;
+use atpmd::refusal::SignResponse;
+use atpmd::{Error, TokenEngine};
+use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::StatusCode;
+use std::convert::TryFrom;
+use std::fmt;
+
+use util::{now, GetTokens, BATCH_CONTENT_TYPE};
+
+const SERVER: &str = "http://127.0.0.1:8000";
+
+// This is a bad way of using password authentication, do not do the same
+const USERNAME: &str = "user";
+const PASSWORD: &str = "password123";
+
+type Token = PairingSignedToken<Box<[u8]>>;
+
+enum RefillError {
+    Http(reqwest::Error),
+    Discovery(DiscoveryError<reqwest::Error>),
+    Tokens(Error),
+}
+
+impl fmt::Debug for RefillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "request failed: {}", e),
+            Self::Discovery(e) => write!(f, "discovery failed: {}", e),
+            Self::Tokens(e) => write!(f, "refill failed: {}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for RefillError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl From<DiscoveryError<reqwest::Error>> for RefillError {
+    fn from(e: DiscoveryError<reqwest::Error>) -> Self {
+        Self::Discovery(e)
+    }
+}
+
+impl From<Error> for RefillError {
+    fn from(e: Error) -> Self {
+        Self::Tokens(e)
+    }
+}
+
+/// The steps of a refill, for the progress callback
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    Randomizing,
+    Signing,
+    Unrandomizing,
+}
+
+/// Chunking that reports the progress of a stage between the chunks
+fn chunking<'a>(
+    stage: Stage,
+    count: usize,
+    progress: &'a mut impl FnMut(Stage, usize, usize),
+) -> Chunking<impl FnMut() -> YieldNow + 'a> {
+    let chunk_size = 16;
+    let mut done = 0;
+
+    Chunking::new(chunk_size, move || {
+        done += chunk_size;
+        progress(stage, done.min(count), count);
+        yield_now()
+    })
+}
+
+/// Refill a wallet with `count` tokens for a resource, reporting the progress as
+/// `(stage, done, count)`
+///
+/// The tokens are asked for in one batch. If the server does not answer with a batch, e.g. as it
+/// is too old, one token is asked for at a time.
+fn refill(
+    client: &Client,
+    issuer: &IssuerDocument,
+    key: &PublicKey,
+    resource: &[u8],
+    count: usize,
+    mut progress: impl FnMut(Stage, usize, usize),
+) -> Result<Vec<Token>, RefillError> {
+    let count = issuer.batch_limits.clamp(count);
+    let unsigned = DynBatchedPairingTokenEngine::generate((Box::from(resource), count));
+
+    let (r, randomized) =
+        futures::executor::block_on(DynBatchedPairingTokenEngine::randomize_chunked(
+            &unsigned,
+            &mut chunking(Stage::Randomizing, count, &mut progress),
+        ))?;
+    progress(Stage::Randomizing, count, count);
+
+    let get_tokens = GetTokens {
+        request: IssuanceRequestMsg::from(randomized),
+        username: USERNAME.to_owned(),
+        password: PASSWORD.to_owned(),
+    };
+
+    let response = client
+        .post(&issuer.issuance)
+        .header(ACCEPT, BATCH_CONTENT_TYPE)
+        .json(&get_tokens)
+        .send()?;
+
+    let is_batch = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == BATCH_CONTENT_TYPE);
+
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND || (status.is_success() && !is_batch) {
+        return refill_single(client, key, resource, count, progress);
+    }
+
+    if !status.is_success() {
+        return Err(Error::NotSigned.into());
+    }
+
+    let signed: IssuanceResponseMsg<Box<[u8]>> = response.json()?;
+    let signed =
+        SignResponse::<DynBatchedRandomizedSignedToken<_>, PairingSignedRefusal>::try_from(signed)
+            .map_err(|_e| Error::NotSigned)?
+            .into_result(resource, key, now())?;
+    progress(Stage::Signing, count, count);
+
+    // the request is only given back in the message
+    let randomized = DynBatchedRandomizedUnsignedToken::try_from(get_tokens.request)
+        .map_err(|_e| Error::NotSigned)?;
+
+    let signed = futures::executor::block_on(
+        DynBatchedPairingTokenEngine::verify_signature_and_unrandomize_chunked(
+            unsigned,
+            randomized,
+            signed,
+            key,
+            r,
+            &mut chunking(Stage::Unrandomizing, count, &mut progress),
+        ),
+    )
+    .map_err(Error::from)?;
+    progress(Stage::Unrandomizing, count, count);
+
+    // split the batch into tokens that can be spent one at a time
+    Ok(signed.iter().collect())
+}
+
+/// Refill a wallet with one request per token
+fn refill_single(
+    client: &Client,
+    key: &PublicKey,
+    resource: &[u8],
+    count: usize,
+    mut progress: impl FnMut(Stage, usize, usize),
+) -> Result<Vec<Token>, RefillError> {
+    (0..count)
+        .map(|done| {
+            let unsigned = PairingTokenEngine::generate(Box::from(resource));
+
+            let token = PairingTokenEngine::sign(unsigned, key, |unsigned| {
+                let get_token = GetTokens {
+                    request: IssuanceRequestMsg::from(unsigned.clone()),
+                    username: USERNAME.to_owned(),
+                    password: PASSWORD.to_owned(),
+                };
+
+                client
+                    .post(format!("{}/sign", SERVER))
+                    .json(&get_token)
+                    .send()
+                    .and_then(|res| res.json::<IssuanceResponseMsg<_>>())
+                    .map_err(|_e| Error::NotSigned)
+                    .and_then(|signed| {
+                        SignResponse::<RandomizedSignedToken<_>, PairingSignedRefusal>::try_from(
+                            signed,
+                        )
+                        .map_err(|_e| Error::NotSigned)
+                    })?
+                    .into_result(resource, key, now())
+            })?;
+
+            progress(Stage::Signing, done + 1, count);
+            Ok(token)
+        })
+        .collect()
+}
+
+fn main() -> Result<(), RefillError> {
+    // Dirty hack with blocking client to not having to deal with async in the closure
+    let client = Client::new();
+    // Find the keys and the issuance of the server
+    let issuer = discover(SERVER, |url| client.get(url).send()?.text())?;
+
+    if !issuer.supports(Token::ENGINE_ID) {
+        return Err(Error::NotSigned.into());
+    }
+
+    // Get the latest public key
+    let keys: JwkSet = client.get(&issuer.key_bundle).send()?.json()?;
+    let key = keys
+        .to_key_set::<PublicKey>()
+        .ok()
+        .and_then(|keys| keys.latest().cloned())
+        .ok_or(Error::NotSigned)?;
+
+    let mut wallet = refill(
+        &client,
+        &issuer,
+        &key,
+        b"resource",
+        100,
+        |stage, done, count| println!("{:?}: {}/{}", stage, done, count),
+    )?;
+
+    println!("got {} tokens", wallet.len());
+
+    // Spend one token per request
+    for _ in 0..2 {
+        let token = wallet.pop().expect("the wallet is empty");
+
+        let resource = client
+            .get(format!("{}/resource", SERVER))
+            .header(
+                AUTHORIZATION,
+                token.try_to_authorization_header().map_err(Error::from)?,
+            )
+            .send()?
+            .text()?;
+
+        println!("{}", resource);
+    }
+
+    Ok(())
+}
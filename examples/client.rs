@@ -1,17 +1,20 @@
 mod util;
 
+use atpmd::atpm_pairing::refusal::PairingSignedRefusal;
 use atpmd::atpm_pairing::{
     keys::PublicKey,
     tokens::{PairingTokenEngine, RandomizedSignedToken},
 };
-use atpmd::TokenEngine;
-use reqwest::blocking::Response;
-use subtle::{Choice, CtOption};
+use atpmd::issuance::{IssuanceRequestMsg, IssuanceResponseMsg};
+use atpmd::refusal::SignResponse;
+use atpmd::session::IssuanceSession;
+use atpmd::{Error, TokenEngine};
+use std::convert::TryFrom;
 
-use util::GetToken;
+use util::{now, GetTokens};
 
 fn main() -> Result<(), reqwest::Error> {
-    // Dirty hack with blocking client to not having to deal with async in the closure
+    // The blocking client, to not have to deal with async
     let client = reqwest::blocking::Client::new();
     // Get the public key
     let key: PublicKey = client
@@ -22,33 +25,33 @@ fn main() -> Result<(), reqwest::Error> {
     // The resource we want access to
     let message = b"resource";
 
-    // Create a new token
-    let unsigned_token = PairingTokenEngine::generate(message);
+    // Create a new token, and randomize it
+    let session =
+        IssuanceSession::<PairingTokenEngine<_>>::new(PairingTokenEngine::generate(message));
 
-    // Get access to the resource
-    let signed_token = PairingTokenEngine::sign(unsigned_token, &key, |unsigned| {
-        // This is a bad way of using password authentication, do not do the same
-        let get_token = GetToken {
-            point: unsigned.clone(),
-            username: "user".to_owned(),
-            password: "password123".to_owned(),
-        };
+    // This is a bad way of using password authentication, do not do the same
+    let get_token = GetTokens {
+        request: IssuanceRequestMsg::from(session.request().clone()),
+        username: "user".to_owned(),
+        password: "password123".to_owned(),
+    };
 
-        // Send the token and the cidentials to the server to get the token signed
-        let signed = client
-            .post("http://127.0.0.1:8000/sign")
-            .json(&get_token)
-            .send()
-            .and_then(|res: Response| res.json());
+    // Send the token and the cidentials to the server to get the token signed
+    let session = session.send();
+    let signed: IssuanceResponseMsg<_> = client
+        .post("http://127.0.0.1:8000/sign")
+        .json(&get_token)
+        .send()?
+        .json()?;
 
-        // Return the signed token
-        let is_signed = signed.is_ok();
-        CtOption::new(
-            signed.unwrap_or_else(|_e| RandomizedSignedToken::default()),
-            Choice::from(if is_signed { 1 } else { 0 }),
-        )
-    })
-    .unwrap();
+    // The signed token, or why the server refused
+    let signed = SignResponse::<RandomizedSignedToken<_>, PairingSignedRefusal>::try_from(signed)
+        .map_err(|_e| Error::NotSigned)
+        .and_then(|signed| signed.into_result(message, &key, now()))
+        .unwrap();
+
+    // Get access to the resource
+    let signed_token = session.receive(signed, &key).unwrap();
 
     // Get the resource, anonlymously
     let resource = client
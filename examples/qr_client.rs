@@ -4,12 +4,15 @@ use atpmd::atpm_pairing::{
     keys::PublicKey,
     tokens::{PairingSignedToken, PairingTokenEngine, RandomizedSignedToken},
 };
-use atpmd::TokenEngine;
+use atpmd::atpm_pairing::refusal::PairingSignedRefusal;
+use atpmd::issuance::{IssuanceRequestMsg, IssuanceResponseMsg};
+use atpmd::refusal::SignResponse;
+use atpmd::{Error, RandomizedUnsignedToken, TokenEngine};
 use reqwest::blocking::{Client, Response};
 use serde::Serialize;
-use subtle::{Choice, CtOption};
+use std::convert::TryFrom;
 
-use util::GetToken;
+use util::{now, GetTokens};
 
 use qrcode::QrCode;
 use image::Luma;
@@ -19,10 +22,10 @@ fn get_token<T: AsRef<[u8]> + Clone + Serialize>(client: &Client, key: &PublicKe
     let unsigned_token = PairingTokenEngine::generate(message);
 
     // Get access to the resource
-    PairingTokenEngine::sign(unsigned_token, &key, |unsigned| {
+    PairingTokenEngine::sign(unsigned_token, key, |unsigned| {
         // This is a bad way of using password authentication, do not do the same
-        let get_token = GetToken {
-            point: unsigned.clone(),
+        let get_token = GetTokens {
+            request: IssuanceRequestMsg::from(unsigned.clone()),
             username: "user".to_owned(),
             password: "password123".to_owned(),
         };
@@ -32,14 +35,13 @@ fn get_token<T: AsRef<[u8]> + Clone + Serialize>(client: &Client, key: &PublicKe
             .post("http://127.0.0.1:8000/sign")
             .json(&get_token)
             .send()
-            .and_then(|res: Response| res.json());
+            .and_then(|res: Response| res.json::<IssuanceResponseMsg<_>>());
 
-        // Return the signed token
-        let is_signed = signed.is_ok();
-        CtOption::new(
-            signed.unwrap_or_else(|_e| RandomizedSignedToken::default()),
-            Choice::from(if is_signed { 1 } else { 0 }),
-        )
+        // Return the signed token, or why the server refused
+        let signed = signed.map_err(|_e| Error::NotSigned)?;
+        SignResponse::<RandomizedSignedToken<_>, PairingSignedRefusal>::try_from(signed)
+            .map_err(|_e| Error::NotSigned)?
+            .into_result(unsigned.metadata(), key, now())
     })
     .unwrap()
 }
@@ -57,7 +59,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Verify that the token is valid myself, not strictly needed since the sign function takes
     // care fo this
-    let success = PairingTokenEngine::verify(&signed_token, &key);
+    let success = PairingTokenEngine::verify(&signed_token, &key).is_ok();
     if success {
         println!("Got a valid token");
     } else {
@@ -1,5 +1,3 @@
-mod util;
-
 use atpmd::atpm_pairing::{
     keys::{PrivateKey, PublicKey},
     tokens::PairingTokenEngine,
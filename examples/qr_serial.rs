@@ -1,11 +1,10 @@
-mod util;
-
 use std::fmt::Display;
 
 use atpmd::atpm_pairing::{
     keys::PublicKey,
     tokens::{PairingSignedToken, PairingTokenEngine},
 };
+use atpmd::presets::access_control::FrameDecoder;
 use atpmd::TokenEngine;
 use serialport::SerialPort;
 
@@ -27,7 +26,6 @@ enum Errors {
     Io,
     Serial,
     Deserialization,
-    Utf8,
 }
 
 impl Display for Errors {
@@ -44,12 +42,6 @@ impl From<serialport::Error> for Errors {
     }
 }
 
-impl From<std::str::Utf8Error> for Errors {
-    fn from(_: std::str::Utf8Error) -> Self {
-        Self::Utf8
-    }
-}
-
 impl From<serde_json::Error> for Errors {
     fn from(_: serde_json::Error) -> Self {
         Self::Deserialization
@@ -65,7 +57,7 @@ impl From<std::io::Error> for Errors {
 // }}}
 
 fn get_data(port: &mut dyn SerialPort) -> Result<PairingSignedToken<Box<[u8]>>, Errors> {
-    let mut data: Vec<u8> = vec![];
+    let mut decoder = FrameDecoder::new();
 
     loop {
         if port.bytes_to_read()? == 0 {
@@ -73,22 +65,12 @@ fn get_data(port: &mut dyn SerialPort) -> Result<PairingSignedToken<Box<[u8]>>,
         }
 
         let mut b = [0];
-        port.read(&mut b)?;
-        data.push(b[0]);
-
-        if data.len() >= 4 {
-            let len = data.len();
-            if data[len - 4] == b'\r'
-                && data[len - 3] == b'\n'
-                && data[len - 2] == b'\r'
-                && data[len - 1] == b'\n'
-            {
-                break;
-            }
+        port.read_exact(&mut b)?;
+
+        if let Some(frame) = decoder.push(b[0]) {
+            return Ok(serde_json::from_slice(&frame)?);
         }
     }
-
-    Ok(serde_json::from_str(std::str::from_utf8(&data)?)?)
 }
 
 fn open_port_and_run(
@@ -122,7 +104,7 @@ fn open_port_and_run(
         let signed_token = signed_token.unwrap();
 
         // Verify that the token is valid myself
-        if !PairingTokenEngine::verify(&signed_token, &key) {
+        if PairingTokenEngine::verify(&signed_token, key).is_err() {
             println!("This is an invalid token");
             continue;
         }
@@ -149,11 +131,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let key: PublicKey = client.get(format!("{}/keys/public", uri)).send()?.json()?;
 
     loop {
-        match open_port_and_run(&mut client, &uri, &key) {
-            Err(e) => {
-                println!("{}", e);
-            }
-            Ok(()) => (),
+        if let Err(e) = open_port_and_run(&mut client, &uri, &key) {
+            println!("{}", e);
         }
     }
 }
@@ -3,25 +3,41 @@ extern crate rocket;
 
 mod util;
 
-use atpmd::atpm_pairing::tokens::{PairingSignedToken, RandomizedSignedToken};
+use atpmd::atpm_pairing::refusal::PairingSignedRefusal;
+use atpmd::atpm_pairing::tokens::{
+    PairingSignedToken, RandomizedSignedToken, RandomizedUnsignedToken,
+};
+use atpmd::atpm_pairing::tokens_batched_dyn::{
+    DynBatchedPairingTokenEngine, DynBatchedRandomizedUnsignedToken,
+};
 use atpmd::{
     atpm_pairing::{
         keys::{PrivateKey, PublicKey},
         tokens::PairingTokenEngine,
     },
-    RandomizedUnsignedToken, TokenEngine,
+    discovery::{BatchLimits, IssuerDocument},
+    http::{AuthorizationHeaderError, HeaderToken},
+    issuance::IssuanceResponseMsg,
+    jwk::JwkSet,
+    policy::{PolicyError, SignerPolicy},
+    redemption::MemoryRedemptionStore,
+    refusal::{Refusal, RefusalReason, SignResponse},
+    Error, PublicKeySet, RandomizedUnsignedToken as _, SignedToken as _, TokenEngine,
 };
 
-use rocket::http::Status;
+use rocket::http::{ContentType, Status};
 use rocket::fs::NamedFile;
+use rocket::request::{FromRequest, Outcome, Request};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::State;
 use sha2::{Digest, Sha512};
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{collections::HashMap, sync::Mutex};
 
-use util::GetToken;
+use util::{now, GetTokens, BATCH_CONTENT_TYPE, MAX_BATCH};
 
 struct Keys {
     private: PrivateKey,
@@ -34,40 +50,133 @@ fn public_key(keys: &State<Keys>) -> Json<&PublicKey> {
     Json::from(&keys.public)
 }
 
-#[post("/", data = "<point>")]
+#[get("/jwks.json")]
+/// The public key as a JWK set, for JOSE infrastructure that caches the keys of issuers
+fn jwks(keys: &State<Keys>) -> Json<JwkSet> {
+    let mut key_set = PublicKeySet::new();
+    key_set.insert(1, keys.public.clone());
+    Json::from(JwkSet::from_key_set(&key_set))
+}
+
+#[get("/anon-token-issuer")]
+/// Where the keys and the issuance are, so the clients only need the URL of the server
+fn issuer() -> Json<IssuerDocument> {
+    Json::from(IssuerDocument {
+        engines: vec![PairingSignedToken::<Box<[u8]>>::ENGINE_ID.to_owned()],
+        key_bundle: "/.well-known/jwks.json".to_owned(),
+        issuance: "/sign/batch".to_owned(),
+        batch_limits: BatchLimits::new(1, MAX_BATCH),
+        ..IssuerDocument::default()
+    })
+}
+
+type Signed = SignResponse<RandomizedSignedToken<Box<[u8]>>, PairingSignedRefusal>;
+
+type Response = IssuanceResponseMsg<Box<[u8]>>;
+
+/// A refusal of a request for the metadata, signed with the key of the server
+fn refuse(keys: &Keys, metadata: &[u8], reason: RefusalReason, retry_after: Option<u64>) -> Signed {
+    let refusal = Refusal::new(reason, retry_after, now());
+    SignResponse::Refused(PairingSignedRefusal::sign(refusal, metadata, &keys.private))
+}
+
+/// The response of the server, in the message of the issuance protocol
+fn respond(signed: Signed) -> Json<Response> {
+    Json::from(Response::from(signed))
+}
+
+#[post("/", data = "<request>")]
 /// If it is a valid user, and the user has access to the resource, their token will be signed.
+/// Otherwise the server refuses, and says why.
 fn sign(
     keys: &State<Keys>,
     access_control: &State<AccessControl>,
     users: &State<Users>,
-    point: Json<GetToken<Box<[u8]>>>,
-) -> Json<Option<RandomizedSignedToken<Box<[u8]>>>> {
-    let get_token = point.into_inner();
-    if !users.verify(&get_token.username, get_token.password) {
-        return Json::from(None);
+    load: &State<Load>,
+    request: Json<GetTokens<Box<[u8]>>>,
+) -> Result<Json<Response>, Status> {
+    let GetTokens {
+        request,
+        username,
+        password,
+    } = request.into_inner();
+    // the server only signs single pairing tokens here
+    let point = RandomizedUnsignedToken::try_from(request).map_err(|_e| Status::BadRequest)?;
+    let metadata = point.metadata();
+    let refused = |reason, retry_after| Ok(respond(refuse(keys, &metadata, reason, retry_after)));
+
+    // shed load before the expensive checks
+    let _signing = match load.enter() {
+        Some(signing) => signing,
+        None => return refused(RefusalReason::Overloaded, Some(1)),
+    };
+
+    if !users.verify(&username, password) {
+        return refused(RefusalReason::PolicyDenied, None);
     }
 
-    let metadata = get_token.point.metadata();
-
-    let resource = std::str::from_utf8(&metadata);
+    let signed = match PairingTokenEngine::sign_randomized_with_policy(
+        &point,
+        &keys.private,
+        access_control.inner(),
+        username.as_str(),
+    ) {
+        Ok(signed) => SignResponse::Signed(signed),
+        Err(Error::Policy(e)) => refuse(keys, &metadata, e.reason, e.retry_after),
+        // the metadata can not be signed with this key
+        Err(_e) => refuse(keys, &metadata, RefusalReason::PolicyDenied, None),
+    };
+    Ok(respond(signed))
+}
 
-    if resource.is_err() {
-        return Json::from(None);
+#[post("/batch", data = "<request>")]
+/// Sign a batch of tokens for the same resource, with the same checks as a single token.
+fn sign_batch(
+    keys: &State<Keys>,
+    access_control: &State<AccessControl>,
+    users: &State<Users>,
+    request: Json<GetTokens<Box<[u8]>>>,
+) -> Result<(ContentType, Json<Response>), Status> {
+    let get_tokens = request.into_inner();
+    if !users.verify(&get_tokens.username, get_tokens.password) {
+        return Err(Status::Unauthorized);
     }
 
-    if !access_control.check_access(get_token.username, resource.unwrap()) {
-        return Json::from(None);
+    let batch = DynBatchedRandomizedUnsignedToken::try_from(get_tokens.request)
+        .map_err(|_e| Status::BadRequest)?;
+
+    if batch.is_empty() {
+        return Err(Status::BadRequest);
     }
 
-    let signed = PairingTokenEngine::sign_randomized(&get_token.point, &keys.private);
+    if batch.len() > MAX_BATCH {
+        return Err(Status::PayloadTooLarge);
+    }
 
-    Json::from(if bool::from(signed.is_some()) {
-        Some(signed.unwrap())
-    } else {
-        None
-    })
+    let signed = DynBatchedPairingTokenEngine::sign_randomized_with_policy(
+        &batch,
+        &keys.private,
+        access_control.inner(),
+        get_tokens.username.as_str(),
+    )
+    .map_err(|e| match e {
+        Error::Policy(_e) => Status::Forbidden,
+        _ => Status::BadRequest,
+    })?;
+
+    // so the client knows the response is a batch
+    let (top, sub) = BATCH_CONTENT_TYPE.split_once('/').unwrap();
+    Ok((
+        ContentType::new(top, sub),
+        Json::from(Response::from(
+            SignResponse::<_, PairingSignedRefusal>::Signed(signed),
+        )),
+    ))
 }
 
+/// The metadata of the tokens of the resource
+const RESOURCE: &[u8] = b"resource";
+
 #[post("/", data = "<point>")]
 /// If it is a valid, unused token, the resource will be returned.
 fn resource(
@@ -75,39 +184,104 @@ fn resource(
     used: &State<UsedTokens>,
     point: Json<PairingSignedToken<Box<[u8]>>>,
 ) -> Result<&'static str, Status> {
-    let point = point.into_inner();
+    redeem(keys, used, point.into_inner())
+}
+
+#[get("/")]
+/// Same as posting the token, but the token is sent as `Authorization: AnonToken v1 <token>`.
+fn resource_header(
+    keys: &State<Keys>,
+    used: &State<UsedTokens>,
+    token: AuthorizationToken,
+) -> Result<&'static str, Status> {
+    redeem(keys, used, token.0)
+}
+
+fn redeem(
+    keys: &Keys,
+    used: &UsedTokens,
+    point: PairingSignedToken<Box<[u8]>>,
+) -> Result<&'static str, Status> {
+    // the metadata is only the resource, so the tokens do not expire
+    point
+        .verify_for(&keys.public, RESOURCE)
+        .map_err(|e| match e {
+            Error::WrongResource => Status::Forbidden,
+            _ => Status::Unauthorized,
+        })?;
+
+    let mut store = used
+        .store
+        .lock()
+        .map_err(|_e| Status::InternalServerError)?;
+    PairingTokenEngine::redeem_without_expiry(&point, &keys.public, &mut *store)
+        .map(|_| "you have access to this resource")
+        .map_err(|_e| Status::Unauthorized)
+}
+
+/// A token from the `Authorization` header
+struct AuthorizationToken(PairingSignedToken<Box<[u8]>>);
 
-    if !used.contains(&point) && PairingTokenEngine::verify(&point, &keys.public) {
-        used.push(point);
-        Ok("you have access to this resource")
-    } else {
-        Err(Status::Unauthorized)
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthorizationToken {
+    type Error = AuthorizationHeaderError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("Authorization") {
+            Some(header) => match PairingSignedToken::parse_authorization_header(header) {
+                Ok(token) => Outcome::Success(AuthorizationToken(token)),
+                Err(e) => Outcome::Error((Status::BadRequest, e)),
+            },
+            None => Outcome::Error((Status::Unauthorized, AuthorizationHeaderError::Token)),
+        }
     }
 }
 
-/// List of used tokens
-struct UsedTokens {
-    tokens: Mutex<Vec<PairingSignedToken<Box<[u8]>>>>,
+/// The number of signings running, so the server can refuse when there are too many
+struct Load {
+    signing: AtomicUsize,
 }
 
-impl UsedTokens {
+/// The most signings at once
+const MAX_SIGNING: usize = 64;
+
+impl Load {
     fn new() -> Self {
         Self {
-            tokens: Mutex::new(Vec::new()),
+            signing: AtomicUsize::new(0),
         }
     }
 
-    fn contains(&self, token: &PairingSignedToken<Box<[u8]>>) -> bool {
-        self.tokens
-            .lock()
-            .map(|list| if list.contains(token) { Some(()) } else { None })
-            .ok()
-            .flatten()
-            .is_some()
+    /// Start a signing, none if the server is overloaded
+    fn enter(&self) -> Option<Signing<'_>> {
+        if self.signing.fetch_add(1, Ordering::SeqCst) >= MAX_SIGNING {
+            self.signing.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(Signing(self))
+    }
+}
+
+/// A running signing, it is done when this is dropped
+struct Signing<'a>(&'a Load);
+
+impl Drop for Signing<'_> {
+    fn drop(&mut self) {
+        self.0.signing.fetch_sub(1, Ordering::SeqCst);
     }
+}
+
+/// The used tokens
+struct UsedTokens {
+    store: Mutex<MemoryRedemptionStore>,
+}
 
-    fn push(&self, token: PairingSignedToken<Box<[u8]>>) {
-        self.tokens.lock().map(|mut list| list.push(token)).unwrap()
+impl UsedTokens {
+    fn new() -> Self {
+        Self {
+            store: Mutex::new(MemoryRedemptionStore::new()),
+        }
     }
 }
 
@@ -175,7 +349,7 @@ impl AccessControl {
         } else {
             let mut map = HashMap::new();
             map.insert(user, ());
-            self.resources.insert(resource.into(), map);
+            self.resources.insert(resource, map);
         }
     }
 
@@ -190,6 +364,20 @@ impl AccessControl {
     }
 }
 
+/// The users get tokens of the resources they have access to
+impl SignerPolicy<str> for AccessControl {
+    fn allow(&self, metadata: &[u8], user: &str) -> Result<(), PolicyError> {
+        let has_access = std::str::from_utf8(metadata)
+            .is_ok_and(|resource| self.check_access(user, resource));
+
+        if has_access {
+            Ok(())
+        } else {
+            Err(PolicyError::denied())
+        }
+    }
+}
+
 // Follow the structure of Express
 // It is whether you ignore the serve shows it couldn't find / or /user
 // or you edit manually index.html and other paths for images etc.
@@ -238,9 +426,11 @@ fn rocket() -> _ {
         .manage(users)
         .manage(ac)
         .manage(UsedTokens::new())
+        .manage(Load::new())
         .mount("/keys", routes![public_key])
-        .mount("/sign", routes![sign])
-        .mount("/resource", routes![resource])
+        .mount("/.well-known", routes![jwks, issuer])
+        .mount("/sign", routes![sign, sign_batch])
+        .mount("/resource", routes![resource, resource_header])
         .mount("/static", routes![file])
         .mount("/", routes![home])
 }
@@ -1,9 +1,29 @@
-use atpmd::atpm_pairing::tokens::RandomizedUnsignedToken;
+use atpmd::issuance::IssuanceRequestMsg;
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// The media type of batched requests and responses
+///
+/// A client asks for it in `Accept`, and a server without batched issuance does not answer with
+/// it, so the client knows to fall back to single tokens.
+#[allow(dead_code)] // only used by the batched examples
+pub const BATCH_CONTENT_TYPE: &str = "application/vnd.atpmd.batch+json";
+
+/// The most tokens a server signs in one request
+#[allow(dead_code)]
+pub const MAX_BATCH: usize = 256;
+
+/// The randomized tokens, and the credentials of the user
 #[derive(Serialize, Deserialize)]
-pub struct GetToken<M: AsRef<[u8]>> {
-    pub point: RandomizedUnsignedToken<M>,
+pub struct GetTokens<M: AsRef<[u8]>> {
+    pub request: IssuanceRequestMsg<M>,
     pub username: String,
     pub password: String,
 }
+
+/// The time in seconds, for the refusals of the server
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
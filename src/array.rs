@@ -0,0 +1,189 @@
+//! # Arrays on the heap
+//!
+//! The batched tokens have N points and N identifiers. As arrays, a batch of 512 tokens is tens of
+//! kilobytes on the stack, which overflows the small stacks of threads and embedded targets, and
+//! the points computed in a `Vec` are converted to the array with a check of the length that can
+//! not fail. A [`BoxedArray`] keeps the values in a boxed slice instead: the length is checked
+//! once, when it is made from a `Vec`, and the methods that make one from another keep it.
+//!
+//! ```
+//!     use atpmd::array::BoxedArray;
+//!     use std::convert::TryFrom;
+//!
+//!     let squares = BoxedArray::<u64, 512>::from_fn(|i| (i * i) as u64);
+//!     let doubled = squares.map(|square| 2 * square);
+//!     assert_eq!(doubled[3], 18);
+//!
+//!     // a vec of another length is given back
+//!     assert_eq!(BoxedArray::<u64, 512>::try_from(vec![1, 2, 3]), Err(vec![1, 2, 3]));
+//! ```
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::convert::TryFrom;
+use core::ops::{Deref, DerefMut};
+
+/// N values, on the heap
+///
+/// It derefs to a slice, whose values may be changed, but not its length.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoxedArray<T, const N: usize>(Box<[T]>);
+
+impl<T, const N: usize> BoxedArray<T, N> {
+    /// The array of `f` of each index
+    pub fn from_fn(f: impl FnMut(usize) -> T) -> Self {
+        Self((0..N).map(f).collect())
+    }
+
+    /// The array of `f` of each index, or none if `f` is none for one of them
+    pub fn try_from_fn(f: impl FnMut(usize) -> Option<T>) -> Option<Self> {
+        (0..N).map(f).collect::<Option<_>>().map(Self)
+    }
+
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> BoxedArray<U, N> {
+        BoxedArray(self.0.iter().map(f).collect())
+    }
+
+    /// `f` of the values of both arrays at each index
+    pub fn zip_map<U, V>(
+        &self,
+        other: &BoxedArray<U, N>,
+        mut f: impl FnMut(&T, &U) -> V,
+    ) -> BoxedArray<V, N> {
+        BoxedArray(
+            self.0
+                .iter()
+                .zip(other.iter())
+                .map(|(t, u)| f(t, u))
+                .collect(),
+        )
+    }
+
+    pub fn into_map<U>(self, f: impl FnMut(T) -> U) -> BoxedArray<U, N> {
+        BoxedArray(self.into_iter().map(f).collect())
+    }
+
+    /// [`map`](Self::map) on the thread pool, with the `parallel` feature
+    #[cfg(any(feature = "pairing", feature = "curve25519"))]
+    pub(crate) fn par_map<U: Send>(&self, f: impl Fn(&T) -> U + Sync + Send) -> BoxedArray<U, N>
+    where
+        T: Sync,
+    {
+        BoxedArray(crate::chunked::par_map(&self.0, f).into_boxed_slice())
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.0.into_vec()
+    }
+}
+
+impl<T, U, const N: usize> BoxedArray<(T, U), N> {
+    /// The arrays of the first and of the second values of the pairs
+    pub fn unzip(self) -> (BoxedArray<T, N>, BoxedArray<U, N>) {
+        let (t, u): (Vec<_>, Vec<_>) = self.into_iter().unzip();
+        (
+            BoxedArray(t.into_boxed_slice()),
+            BoxedArray(u.into_boxed_slice()),
+        )
+    }
+}
+
+impl<T, const N: usize> From<[T; N]> for BoxedArray<T, N> {
+    fn from(array: [T; N]) -> Self {
+        Self(Box::new(array))
+    }
+}
+
+/// The values, if there are N of them, else the vec is given back
+impl<T, const N: usize> TryFrom<Vec<T>> for BoxedArray<T, N> {
+    type Error = Vec<T>;
+
+    fn try_from(values: Vec<T>) -> Result<Self, Vec<T>> {
+        if values.len() == N {
+            Ok(Self(values.into_boxed_slice()))
+        } else {
+            Err(values)
+        }
+    }
+}
+
+impl<T, const N: usize> Deref for BoxedArray<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T, const N: usize> DerefMut for BoxedArray<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        &mut self.0
+    }
+}
+
+impl<T, const N: usize> AsRef<[T]> for BoxedArray<T, N> {
+    fn as_ref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T, const N: usize> IntoIterator for BoxedArray<T, N> {
+    type Item = T;
+    type IntoIter = vec::IntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_vec().into_iter()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a BoxedArray<T, N> {
+    type Item = &'a T;
+    type IntoIter = core::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boxed_array() {
+        let array = BoxedArray::<usize, 4>::from_fn(|i| i + 1);
+        assert_eq!(&*array, [1, 2, 3, 4]);
+        assert_eq!(array.map(|x| x * 2).into_vec(), [2, 4, 6, 8]);
+        assert_eq!(
+            array.par_map(|x| x * 2),
+            array.zip_map(&array, |x, y| x + y)
+        );
+        assert_eq!(BoxedArray::from([1, 2, 3, 4]), array);
+
+        assert_eq!(
+            BoxedArray::<usize, 4>::try_from_fn(|i| i.checked_sub(1)),
+            None
+        );
+        assert_eq!(
+            BoxedArray::<usize, 4>::try_from_fn(|i| Some(i + 1)),
+            Some(array.clone())
+        );
+
+        let (first, second) = array.map(|x| (*x, x * 10)).unzip();
+        assert_eq!(first, array);
+        assert_eq!(second.into_map(|x| x / 10), array);
+
+        let mut swapped = array.clone();
+        swapped.swap(0, 3);
+        assert_eq!(swapped.into_iter().collect::<Vec<_>>(), [4, 2, 3, 1]);
+
+        assert!(BoxedArray::<usize, 4>::try_from(array.into_vec()).is_ok());
+        assert_eq!(
+            BoxedArray::<usize, 4>::try_from(vec![1, 2, 3]),
+            Err(vec![1, 2, 3])
+        );
+
+        // this is far more than the stack of a test thread
+        let large = BoxedArray::<[u8; 1024], 4096>::from_fn(|_i| [0; 1024]);
+        assert_eq!(large.len(), 4096);
+    }
+}
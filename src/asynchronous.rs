@@ -0,0 +1,103 @@
+//! # Signing with an asynchronous signer
+//!
+//! [`TokenEngine::sign`] calls the signer with a synchronous closure. A client whose signer is an
+//! HTTP round trip, e.g. with `reqwest` or in the browser, signs with [`sign_async`] instead, whose
+//! closure returns a future of the response. The closure gets the randomized token by reference,
+//! so it encodes the request before the future, which then owns what it sends.
+//!
+//! ```
+//!     use atpmd::{Error, TokenEngine};
+//!     use atpmd::asynchronous::sign_async;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::NizkpTokenEngine,
+//!     };
+//!     use atpmd::wire::WireFormat;
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     // the issuer, as it would answer a request
+//!     let issuer = |request: Vec<u8>| async move {
+//!         let randomized = WireFormat::from_bytes(&request).map_err(Error::Malformed)?;
+//!         NizkpTokenEngine::sign_randomized(&randomized, &secret_key)
+//!     };
+//!
+//!     let signed = futures::executor::block_on(sign_async::<NizkpTokenEngine<_>, _, _>(
+//!         NizkpTokenEngine::generate(b"metadata".to_vec()),
+//!         &public_key,
+//!         |randomized| issuer(randomized.to_bytes()),
+//!     ))
+//!     .unwrap();
+//! ```
+
+use core::future::Future;
+
+use crate::common::{Error, TokenEngine};
+
+/// [`TokenEngine::sign`], with a signer that answers asynchronously
+///
+/// The token is randomized, sent to the signer by the closure, and the response is verified and
+/// unrandomized when the future of the closure is ready.
+pub async fn sign_async<E, F, Fut>(
+    unsigned_token: E::UnsignedToken,
+    verification_data: &E::UserVerification,
+    sign_func: F,
+) -> Result<E::SignedToken, Error>
+where
+    E: TokenEngine,
+    F: FnOnce(&E::RandomizedUnsignedToken) -> Fut,
+    Fut: Future<Output = Result<E::RandomizedSignedToken, Error>>,
+{
+    let (r, randomized_unsigned) = E::randomize(&unsigned_token);
+
+    let randomized_signed = sign_func(&randomized_unsigned).await?;
+
+    Ok(E::verify_signature_and_unrandomize(
+        unsigned_token,
+        randomized_unsigned,
+        randomized_signed,
+        verification_data,
+        r,
+    )?)
+}
+
+#[cfg(all(test, feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use crate::common::SignedToken;
+    use crate::nizkp_curve25519::{
+        keys::{PrivateKey, PublicKey},
+        tokens::NizkpTokenEngine,
+    };
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_sign_async() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let signed = block_on(sign_async::<NizkpTokenEngine<_>, _, _>(
+            NizkpTokenEngine::generate(&b"metadata"[..]),
+            &public_key,
+            |randomized| {
+                let response = NizkpTokenEngine::sign_randomized(randomized, &private);
+                async move { response }
+            },
+        ))
+        .unwrap();
+        assert!(signed.verify(&private));
+
+        // a response of another key is not unrandomized
+        let other = PrivateKey::new();
+        let result = block_on(sign_async::<NizkpTokenEngine<_>, _, _>(
+            NizkpTokenEngine::generate(&b"metadata"[..]),
+            &public_key,
+            |randomized| {
+                let response = NizkpTokenEngine::sign_randomized(randomized, &other);
+                async move { response }
+            },
+        ));
+        assert!(result.is_err());
+    }
+}
@@ -7,33 +7,140 @@
 //!
 //!     let private_key = PrivateKey::<Secp256k1>::new();
 //!     let public_key = PublicKey::from(&private_key);
+//!
+//!     // a key per origin
+//!     let derived = PublicKey::from(&private_key.derive(b"example.com"));
+//!     assert_eq!(derived.to_affine(), public_key.derive(b"example.com").to_affine());
 //! ```
 
 use elliptic_curve::{
-    group::Curve as Crv, AffineArithmetic, AffinePoint, Curve, Group, ProjectiveArithmetic,
-    ProjectivePoint, Scalar, ScalarArithmetic,
+    group::{Curve as Crv, GroupEncoding},
+    AffineArithmetic, AffinePoint, Curve, Group, ProjectiveArithmetic, ProjectivePoint, Scalar,
+    ScalarArithmetic,
 };
 
-use super::util::gen_vartime;
+#[cfg(feature = "private_key_serde")]
+use elliptic_curve::{group::ff::PrimeField, FieldBytes};
+use rand::{CryptoRng, RngCore};
+#[cfg(feature = "private_key_serde")]
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+use alloc::vec::Vec;
+
+use super::util::{from_bytes_wide, gen_ct, h_derive, point_from_bytes, point_to_bytes};
+#[cfg(feature = "private_key_serde")]
+use super::PrivateKeyBytes;
+use super::Secret;
+use crate::kdf::hkdf_sha256;
+use crate::KeyFingerprint;
 
 #[derive(Debug, Clone)]
 /// The private key for the nizkp protocol
+///
+/// The key is zeroized when it is dropped
 pub struct PrivateKey<C: Curve + ScalarArithmetic> {
-    scalar: Scalar<C>,
+    scalar: Secret<Scalar<C>>,
 }
 
 impl<C: Curve + ScalarArithmetic> PrivateKey<C> {
     pub fn to_scalar(&self) -> Scalar<C> {
-        self.scalar
+        self.scalar.0
     }
 }
 
 impl<C: Curve + ProjectiveArithmetic> PrivateKey<C> {
     pub fn new() -> Self {
+        Self::new_with_rng(&mut crate::rng::default_rng())
+    }
+
+    /// A private key from the given rng, e.g. for reproducible tests
+    pub fn new_with_rng<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self {
+            scalar: Secret(gen_ct::<C, _>(rng)),
+        }
+    }
+}
+
+impl<C: Curve + AffineArithmetic + ProjectiveArithmetic> PrivateKey<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    /// The private key of a seed, e.g. from a secrets manager, to derive the same key again
+    ///
+    /// The scalar is the HKDF-SHA256 of the seed, reduced from 64 bytes. The info of the HKDF has
+    /// the encoding of the generator of the curve, so the keys of one seed on two curves are not
+    /// related.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let mut info = b"atpmd nizkp private key".to_vec();
+        info.extend(point_to_bytes::<C>(
+            &ProjectivePoint::<C>::generator().to_affine(),
+        ));
+
+        let mut okm = [0; 64];
+        hkdf_sha256(seed, &info, &mut okm);
+        let scalar = from_bytes_wide::<C>(&okm);
+        okm.zeroize();
+
+        Self {
+            scalar: Secret(scalar),
+        }
+    }
+
+    /// The key for a label, e.g. an origin, derived from this master key
+    ///
+    /// See [`PublicKey::derive`] for the public key.
+    pub fn derive(&self, label: impl AsRef<[u8]>) -> Self {
+        let tweak = h_derive::<C>(&PublicKey::from(self).point, label.as_ref());
         Self {
-            scalar: gen_vartime::<C, _>(&mut rand::thread_rng()),
+            scalar: Secret(self.scalar.0 + tweak),
         }
     }
+
+    /// The [`PublicKey::fingerprint`] of the public key
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        PublicKey::from(self).fingerprint()
+    }
+}
+
+impl<C: Curve + ScalarArithmetic> Zeroize for PrivateKey<C> {
+    fn zeroize(&mut self) {
+        self.scalar.zeroize();
+    }
+}
+
+impl<C: Curve + ScalarArithmetic> Drop for PrivateKey<C> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl<C: Curve + ScalarArithmetic> Serialize for PrivateKey<C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PrivateKeyBytes {
+            key: self.scalar.0.to_repr().to_vec(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl<'de, C: Curve + ScalarArithmetic> Deserialize<'de> for PrivateKey<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = PrivateKeyBytes::deserialize(deserializer)?;
+        let mut repr = FieldBytes::<C>::default();
+        let len = repr.len();
+        repr.copy_from_slice(bytes.bytes(len)?);
+
+        // from_repr takes the bytes by value, so that copy is not zeroized
+        Scalar::<C>::from_repr(repr)
+            .map(|scalar| PrivateKey {
+                scalar: Secret(scalar),
+            })
+            .ok_or_else(|| de::Error::custom("Scalar is not canonical"))
+    }
 }
 
 impl<C: Curve + ProjectiveArithmetic> Default for PrivateKey<C> {
@@ -43,16 +150,72 @@ impl<C: Curve + ProjectiveArithmetic> Default for PrivateKey<C> {
 }
 
 /// The public key for the nizkp protocol
+///
+/// It is serialized as the compressed encoding of the point.
+#[derive(Debug, Clone)]
 pub struct PublicKey<C: Curve + AffineArithmetic> {
     point: AffinePoint<C>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct PublicKeyBytes {
+    point: Vec<u8>,
+}
+
+impl<C: Curve + AffineArithmetic> Serialize for PublicKey<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PublicKeyBytes {
+            point: point_to_bytes::<C>(&self.point),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, C: Curve + AffineArithmetic> Deserialize<'de> for PublicKey<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = PublicKeyBytes::deserialize(deserializer)?;
+
+        Ok(Self {
+            point: point_from_bytes::<C, _>(&bytes.point)?,
+        })
+    }
+}
+
+/// A multiple of the generator by an arbitrary scalar, so it is always in the group
+#[cfg(feature = "arbitrary")]
+impl<'a, C: Curve + AffineArithmetic + ProjectiveArithmetic> arbitrary::Arbitrary<'a>
+    for PublicKey<C>
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let scalar = super::util::from_bytes_wide::<C>(&u.arbitrary()?);
+        Ok(Self {
+            point: (ProjectivePoint::<C>::generator() * scalar).to_affine(),
+        })
+    }
+}
+
 impl<C: Curve + AffineArithmetic> PublicKey<C> {
     pub fn to_affine(&self) -> AffinePoint<C> {
         self.point
     }
 }
 
+impl<C: Curve + AffineArithmetic> PublicKey<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    /// The SHA-256 of the compressed encoding of the point
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        KeyFingerprint::of_encoding(point_to_bytes::<C>(&self.point))
+    }
+}
+
 impl<C: Curve + AffineArithmetic + ProjectiveArithmetic> From<&PrivateKey<C>> for PublicKey<C> {
     fn from(key: &PrivateKey<C>) -> Self {
         Self {
@@ -66,3 +229,42 @@ impl<C: Curve + ProjectiveArithmetic> From<PrivateKey<C>> for PublicKey<C> {
         Self::from(&key)
     }
 }
+
+impl<C: Curve + AffineArithmetic + ProjectiveArithmetic> crate::keypair::Keys for PrivateKey<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    type Public = PublicKey<C>;
+    type Scalar = Scalar<C>;
+
+    fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::new_with_rng(rng)
+    }
+
+    fn public(&self) -> PublicKey<C> {
+        PublicKey::from(self)
+    }
+
+    fn to_scalar(&self) -> Scalar<C> {
+        PrivateKey::to_scalar(self)
+    }
+
+    fn fingerprint(&self) -> KeyFingerprint {
+        PrivateKey::fingerprint(self)
+    }
+}
+
+impl<C: Curve + AffineArithmetic + ProjectiveArithmetic> PublicKey<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    /// The public key of [`PrivateKey::derive`] with the same label
+    pub fn derive(&self, label: impl AsRef<[u8]>) -> Self {
+        let tweak = h_derive::<C>(&self.point, label.as_ref());
+        Self {
+            point: (ProjectivePoint::<C>::from(self.point)
+                + ProjectivePoint::<C>::generator() * tweak)
+                .to_affine(),
+        }
+    }
+}
@@ -1,6 +1,8 @@
 //! # Anonymous tokens
 //!
 //! These are nonymous tokens, where the tokens are on the elliptic curve [K256](https://docs.rs/k256)
+//! or on other curves of `elliptic-curve` with a [`HashToCurve`], like P-256 in [`p256`] with the
+//! `nizkp_p256` feature
 //!
 //! ## Usage
 //!
@@ -35,13 +37,16 @@
 //!     ).unwrap();
 //!
 //!     // The verifier may verify that the token is signed
-//!     let is_properly_signed = NizkpTokenEngine::verify(&signed, &secret_key);
+//!     let is_properly_signed = NizkpTokenEngine::verify(&signed, &secret_key).is_ok();
 //!     assert!(is_properly_signed);
 //! ```
 
 pub (crate) use super::common::*;
 
 mod util;
+pub use util::HashToCurve;
 pub mod tokens;
 pub mod keys;
 pub mod tokens_batched;
+#[cfg(feature = "nizkp_p256")]
+pub mod p256;
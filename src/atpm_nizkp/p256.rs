@@ -0,0 +1,155 @@
+//! # The nizkp engine on P-256
+//!
+//! The generic engine on the NIST curve P-256 (secp256r1), for deployments that have to use the
+//! NIST curves. Build with the `nizkp_p256` feature.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_nizkp::p256::{NizkpP256TokenEngine, P256PrivateKey, P256PublicKey};
+//!
+//!     let private_key = P256PrivateKey::new();
+//!     let public_key = P256PublicKey::from(&private_key);
+//!
+//!     let signed = NizkpP256TokenEngine::sign(
+//!         NizkpP256TokenEngine::generate(&b"metadata"[..]),
+//!         &public_key,
+//!         |randomized| NizkpP256TokenEngine::sign_randomized(randomized, &private_key),
+//!     )
+//!     .unwrap();
+//!
+//!     assert!(NizkpP256TokenEngine::verify(&signed, &private_key).is_ok());
+//! ```
+
+pub use p256::NistP256;
+
+use super::keys::{PrivateKey, PublicKey};
+use super::tokens::{NizkpSignedToken, NizkpTokenEngine};
+use super::tokens_batched::{BatchedNizkpTokenEngine, NizkpSignedTokenBatched};
+
+pub type P256PrivateKey = PrivateKey<NistP256>;
+pub type P256PublicKey = PublicKey<NistP256>;
+
+pub type NizkpP256TokenEngine<M> = NizkpTokenEngine<M, NistP256>;
+pub type NizkpP256SignedToken<M> = NizkpSignedToken<M, NistP256>;
+
+pub type BatchedNizkpP256TokenEngine<M, const N: usize> = BatchedNizkpTokenEngine<M, NistP256, N>;
+pub type NizkpP256SignedTokenBatched<M, const N: usize> = NizkpSignedTokenBatched<M, NistP256, N>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SignedToken, TokenEngine};
+
+    #[test]
+    fn test_all() {
+        let private_key = P256PrivateKey::new();
+        let public_key = P256PublicKey::from(&private_key);
+
+        let token = NizkpP256TokenEngine::generate_with_hidden(&b"metadata"[..], &b"hidden"[..]);
+        let signed = NizkpP256TokenEngine::sign(token, &public_key, |randomized| {
+            NizkpP256TokenEngine::sign_randomized(randomized, &private_key)
+        })
+        .unwrap();
+
+        assert!(signed.verify(&private_key));
+        assert!(!signed.verify(&P256PrivateKey::new()));
+
+        // the derived keys sign and verify the same way
+        let derived = private_key.derive(b"example.com");
+        let signed = NizkpP256TokenEngine::sign(
+            NizkpP256TokenEngine::generate(&b"metadata"[..]),
+            &public_key.derive(b"example.com"),
+            |randomized| NizkpP256TokenEngine::sign_randomized(randomized, &derived),
+        )
+        .unwrap();
+        assert!(signed.verify(&derived));
+        assert!(!signed.verify(&private_key));
+    }
+
+    #[test]
+    fn test_batched() {
+        let private_key = P256PrivateKey::new();
+        let public_key = P256PublicKey::from(&private_key);
+
+        let signed: NizkpP256SignedTokenBatched<_, 4> = BatchedNizkpP256TokenEngine::sign(
+            BatchedNizkpP256TokenEngine::generate(&b"metadata"[..]),
+            &public_key,
+            |randomized| BatchedNizkpP256TokenEngine::sign_randomized(randomized, &private_key),
+        )
+        .unwrap();
+        assert!(signed.verify(&private_key));
+
+        // the wrong key does not prove the signature
+        let signed = BatchedNizkpP256TokenEngine::<_, 4>::sign(
+            BatchedNizkpP256TokenEngine::generate(&b"metadata"[..]),
+            &public_key,
+            |randomized| {
+                BatchedNizkpP256TokenEngine::sign_randomized(randomized, &P256PrivateKey::new())
+            },
+        );
+        assert!(signed.is_err());
+    }
+
+    #[test]
+    fn test_from_seed() {
+        use crate::atpm_nizkp::util::from_bytes_wide;
+        use crate::kdf::hkdf_sha256;
+
+        let seed = [7; 32];
+        assert_eq!(
+            P256PrivateKey::from_seed(&seed).to_scalar(),
+            P256PrivateKey::from_seed(&seed).to_scalar()
+        );
+
+        // the curve is in the info of the HKDF
+        let mut okm = [0; 64];
+        hkdf_sha256(&seed, b"atpmd nizkp private key", &mut okm);
+        assert_ne!(
+            P256PrivateKey::from_seed(&seed).to_scalar(),
+            from_bytes_wide::<NistP256>(&okm)
+        );
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+        use rand::RngCore;
+
+        for _ in 0..32 {
+            let mut entropy = [0; 64];
+            crate::rng::default_rng().fill_bytes(&mut entropy);
+
+            let key = P256PublicKey::arbitrary(&mut Unstructured::new(&entropy)).unwrap();
+            let json = serde_json::to_string(&key).unwrap();
+            let decoded: P256PublicKey = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded.to_affine(), key.to_affine());
+        }
+    }
+
+    #[test]
+    fn test_key_serde() {
+        let private_key = P256PrivateKey::new();
+        let public_key = P256PublicKey::from(&private_key);
+
+        let serialized = serde_json::to_string(&public_key).unwrap();
+        let deserialized: P256PublicKey = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.to_affine(), public_key.to_affine());
+
+        // the compressed point is 33 bytes
+        assert!(serde_json::from_str::<P256PublicKey>(r#"{"point":[2,1,2,3]}"#).is_err());
+
+        assert_eq!(deserialized.fingerprint(), private_key.fingerprint());
+        assert_ne!(
+            P256PrivateKey::new().fingerprint(),
+            public_key.fingerprint()
+        );
+
+        #[cfg(feature = "private_key_serde")]
+        {
+            let serialized = serde_json::to_string(&private_key).unwrap();
+            let deserialized: P256PrivateKey = serde_json::from_str(&serialized).unwrap();
+            assert_eq!(deserialized.to_scalar(), private_key.to_scalar());
+        }
+    }
+}
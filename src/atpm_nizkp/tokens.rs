@@ -2,100 +2,23 @@ use alloc::boxed::Box;
 use core::marker::PhantomData;
 
 use super::{
+    check_metadata, invertible,
     keys::{PrivateKey, PublicKey},
-    util::gen_vartime,
-    SignedToken, TokenEngine, TokenIdentifier, UnsignedToken,
+    util::EllipticCurve,
+    Error, KeyEpoch, SignedToken, TokenEngine, TokenIdentifier, UnsignedToken, VerifyError,
 };
 
 use elliptic_curve::{
     group::{Curve as Cur, GroupEncoding},
     ops::Invert,
     AffineArithmetic, AffinePoint, Curve, Group, ProjectiveArithmetic, ProjectivePoint, Scalar,
-    ScalarArithmetic,
 };
+use rand::{CryptoRng, RngCore};
 
-use sha2::{Digest, Sha256};
-use subtle::CtOption;
-
-use super::util::{h_t, hash_to_scalar};
-
-// {{{ DLEQProof
-
-#[derive(Clone)]
-struct DLEQProof<C: Curve + ScalarArithmetic> {
-    c: Scalar<C>,
-    z: Scalar<C>,
-}
-
-impl<C: Curve + AffineArithmetic + ProjectiveArithmetic> DLEQProof<C>
-where
-    AffinePoint<C>: GroupEncoding,
-{
-    fn hash_data(
-        u: AffinePoint<C>,
-        t: AffinePoint<C>,
-        w: AffinePoint<C>,
-        a: AffinePoint<C>,
-        b: AffinePoint<C>,
-    ) -> Scalar<C> {
-        let mut hasher = Sha256::new();
-
-        // domain of the oracle, to have separate oracles
-        hasher.update(b"This is DLEQ_PROOF hash");
-
-        hasher.update(GroupEncoding::to_bytes(
-            &ProjectivePoint::<C>::generator().to_affine(),
-        ));
-        hasher.update(GroupEncoding::to_bytes(&u));
-        hasher.update(GroupEncoding::to_bytes(&t));
-        hasher.update(GroupEncoding::to_bytes(&w));
-        hasher.update(GroupEncoding::to_bytes(&a));
-        hasher.update(GroupEncoding::to_bytes(&b));
-
-        // Turn the bytes uniformly and deterministically into a scalar
-        hash_to_scalar::<C, _>(&hasher.finalize())
-    }
-
-    /// Create a proof of the fact that log_w t = k
-    ///
-    /// If you create w=(d+k)^{-1} t, then create this proof with create(t, w, d + k)
-    pub fn create(t: AffinePoint<C>, w: AffinePoint<C>, k: Scalar<C>) -> Self {
-        let r: Scalar<C> = gen_vartime::<C, _>(&mut rand::thread_rng());
-        let a = ProjectivePoint::<C>::generator() * r;
-        let b = ProjectivePoint::<C>::from(w) * r;
-
-        let c = DLEQProof::<C>::hash_data(
-            (ProjectivePoint::<C>::generator() * k).to_affine(),
-            t,
-            w,
-            a.to_affine(),
-            b.to_affine(),
-        );
-
-        let z = r - k * c;
-
-        Self { c, z }
-    }
-
-    /// Verify the proof that log_w t = k
-    ///
-    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
-    pub fn verify(
-        &self,
-        t: AffinePoint<C>,
-        w: AffinePoint<C>,
-        public_key: AffinePoint<C>,
-    ) -> bool {
-        let a = ProjectivePoint::<C>::generator() * self.z
-            + ProjectivePoint::<C>::from(public_key) * self.c;
-        let b = ProjectivePoint::<C>::from(w) * self.z + ProjectivePoint::<C>::from(t) * self.c;
-        let c = DLEQProof::<C>::hash_data(public_key, t, w, a.to_affine(), b.to_affine());
-
-        c == self.c
-    }
-}
-
-// }}}
+use super::util::{h_t_with, hash_to_scalar_with, HashToCurve};
+use crate::hash_suite::{HashSuite, Sha2};
+use crate::group::{blinding, prove_verification, sign_point, DleqProof};
+use crate::redemption::{Fingerprint, Nullifier, Redeemable};
 
 // {{{ UnsignedToken
 
@@ -105,11 +28,19 @@ pub struct NizkpUnsignedToken<M: AsRef<[u8]>, C: Curve> {
     _c: PhantomData<C>,
 }
 
-impl<M: AsRef<[u8]>, C: Curve + AffineArithmetic> NizkpUnsignedToken<M, C> {
+impl<M: AsRef<[u8]>, C: HashToCurve> NizkpUnsignedToken<M, C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
     pub fn get_point(&self) -> AffinePoint<C> {
+        self.point_with::<Sha2>()
+    }
+
+    /// The point of the token with the hashes of the suite
+    pub fn point_with<H: HashSuite>(&self) -> AffinePoint<C> {
         let t: [u8; 16] = (&self.id).into();
 
-        h_t::<C, _, _>(t, &self.metadata)
+        h_t_with::<C, H, _, _>(t, &self.metadata)
     }
 }
 
@@ -117,17 +48,21 @@ impl<M: AsRef<[u8]>, C: Curve> UnsignedToken for NizkpUnsignedToken<M, C> {
     type Metadata = M;
     type HiddenMetadata = M;
 
-    fn new(metadata: Self::Metadata) -> Self {
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
         Self {
-            id: TokenIdentifier::new(),
+            id: TokenIdentifier::new_with_rng(rng),
             metadata,
             _c: PhantomData {},
         }
     }
 
-    fn with_hidden(metadata: Self::Metadata, hidden: Self::HiddenMetadata) -> Self {
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
         Self {
-            id: TokenIdentifier::with_hidden(hidden),
+            id: TokenIdentifier::with_hidden_with_rng(hidden, rng),
             metadata,
             _c: PhantomData {},
         }
@@ -140,10 +75,26 @@ impl<M: AsRef<[u8]>, C: Curve> UnsignedToken for NizkpUnsignedToken<M, C> {
 
 pub struct RandomizedSignedToken<M: AsRef<[u8]>, C: Curve + AffineArithmetic> {
     point: AffinePoint<C>,
-    proof: DLEQProof<C>,
+    proof: DleqProof<Scalar<C>>,
+    key_epoch: Option<KeyEpoch>,
     _m: PhantomData<M>,
 }
 
+impl<M: AsRef<[u8]>, C: Curve + AffineArithmetic> crate::common::RandomizedSignedToken
+    for RandomizedSignedToken<M, C>
+{
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
 // }}}
 
 // {{{ randomized unsigned
@@ -166,7 +117,7 @@ impl<M: AsRef<[u8]>, C: Curve + AffineArithmetic> crate::common::RandomizedUnsig
 
 // {{{ Signed token
 
-pub struct NizkpSignedToken<M: AsRef<[u8]>, C>
+pub struct NizkpSignedToken<M: AsRef<[u8]>, C, H = Sha2>
 where
     C: Curve + ProjectiveArithmetic,
     Scalar<C>: Invert<Output = Scalar<C>>,
@@ -174,37 +125,145 @@ where
     id: TokenIdentifier<M>,
     metadata: M,
     point: AffinePoint<C>,
+    _h: PhantomData<H>,
 }
 
-impl<M: AsRef<[u8]>, C> SignedToken for NizkpSignedToken<M, C>
+impl<M: AsRef<[u8]>, C, H: HashSuite> SignedToken for NizkpSignedToken<M, C, H>
 where
-    C: Curve + ProjectiveArithmetic,
+    C: HashToCurve + ProjectiveArithmetic,
     Scalar<C>: Invert<Output = Scalar<C>>,
+    AffinePoint<C>: GroupEncoding,
 {
     type VerificationKey = PrivateKey<C>;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
         let t: [u8; 16] = (&self.id).into();
-        let t: AffinePoint<C> = h_t::<C, _, _>(t, &self.metadata);
+        let t: AffinePoint<C> = h_t_with::<C, H, _, _>(t, &self.metadata);
 
         // We may do this, since
         // w == e * t is the same as e^-1 w == t
         // We then do not need to do the inversion step, and maybe it could be easier to build
         // batch verification
         let e_inverse: Scalar<C> =
-            hash_to_scalar::<C, _>(&self.metadata) + verification_key.to_scalar();
+            hash_to_scalar_with::<C, H, _>(&self.metadata) + verification_key.to_scalar();
 
         let signed: ProjectivePoint<C> = ProjectivePoint::<C>::from(self.point) * e_inverse;
 
         signed == ProjectivePoint::<C>::from(t)
     }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn hidden_metadata(&self) -> Option<&[u8]> {
+        self.id.hidden().map(AsRef::as_ref)
+    }
+
+    fn verify_with_hidden(
+        &self,
+        verification_key: &Self::VerificationKey,
+        expected_hidden: &[u8],
+    ) -> bool {
+        bool::from(self.id.is_hidden(expected_hidden)) && self.verify(verification_key)
+    }
+}
+
+impl<M: AsRef<[u8]>, C, H: HashSuite> Redeemable for NizkpSignedToken<M, C, H>
+where
+    C: HashToCurve + ProjectiveArithmetic,
+    Scalar<C>: Invert<Output = Scalar<C>>,
+    AffinePoint<C>: GroupEncoding,
+{
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_token(&self.id, self.metadata.as_ref())
+    }
+
+    fn nullifier(&self) -> Nullifier {
+        Nullifier::of_token(&self.id, self.point.to_bytes().as_ref(), self.metadata.as_ref())
+    }
+}
+
+// }}}
+
+// {{{ Verification proof
+
+/// A proof of the outcome of verifying a token, that anyone with the public key can check
+///
+/// See the proof of the curve25519 engine, this is the same for any curve.
+pub struct VerificationProof<C: Curve + AffineArithmetic> {
+    point: AffinePoint<C>,
+    proof: DleqProof<Scalar<C>>,
+}
+
+impl<C> VerificationProof<C>
+where
+    C: HashToCurve + ProjectiveArithmetic,
+    AffinePoint<C>: GroupEncoding,
+    Scalar<C>: Invert<Output = Scalar<C>>,
+{
+    /// Whether the token verified, or none if this is not a proof for the token and public key
+    pub fn check<M: AsRef<[u8]>, H: HashSuite>(
+        &self,
+        token: &NizkpSignedToken<M, C, H>,
+        public_key: &PublicKey<C>,
+    ) -> Option<bool> {
+        let u: ProjectivePoint<C> = ProjectivePoint::<C>::generator()
+            * hash_to_scalar_with::<C, H, _>(&token.metadata)
+            + public_key.to_affine();
+
+        if !self.proof.verify_with_domain::<EllipticCurve<C>>(
+            self.point.into(),
+            token.point.into(),
+            u,
+            &H::DOMAIN,
+        ) {
+            return None;
+        }
+
+        let t: [u8; 16] = (&token.id).into();
+        let t: AffinePoint<C> = h_t_with::<C, H, _, _>(t, &token.metadata);
+        Some(ProjectivePoint::<C>::from(self.point) == ProjectivePoint::<C>::from(t))
+    }
+}
+
+impl<M: AsRef<[u8]>, C, H: HashSuite> NizkpSignedToken<M, C, H>
+where
+    C: HashToCurve + ProjectiveArithmetic,
+    AffinePoint<C>: GroupEncoding,
+    Scalar<C>: Invert<Output = Scalar<C>>,
+{
+    /// Verify the token, and prove the outcome to anyone with the public key
+    pub fn verify_with_proof(
+        &self,
+        verification_key: &PrivateKey<C>,
+    ) -> (bool, VerificationProof<C>) {
+        let (point, proof) = prove_verification::<EllipticCurve<C>>(
+            self.point.into(),
+            hash_to_scalar_with::<C, H, _>(&self.metadata),
+            verification_key.to_scalar(),
+            &H::DOMAIN,
+        );
+
+        let t: [u8; 16] = (&self.id).into();
+        let t: AffinePoint<C> = h_t_with::<C, H, _, _>(t, &self.metadata);
+
+        (
+            point == ProjectivePoint::<C>::from(t),
+            VerificationProof {
+                point: point.to_affine(),
+                proof,
+            },
+        )
+    }
 }
 
 // }}}
 
 // {{{ Token engine
 
-pub struct NizkpTokenEngine<M: AsRef<[u8]>, C>
+/// The engine of the tokens of a curve, hashing with the suite
+pub struct HashedNizkpTokenEngine<M: AsRef<[u8]>, C, H>
 where
     C: Curve + ProjectiveArithmetic,
     AffinePoint<C>: GroupEncoding,
@@ -212,32 +271,73 @@ where
 {
     _m: PhantomData<M>,
     _c: PhantomData<C>,
+    _h: PhantomData<H>,
 }
 
-impl<M: AsRef<[u8]>, C> TokenEngine for NizkpTokenEngine<M, C>
+/// The engine of the tokens of a curve, hashing with [`Sha2`]
+pub type NizkpTokenEngine<M, C> = HashedNizkpTokenEngine<M, C, Sha2>;
+
+impl<M: AsRef<[u8]>, C, H: HashSuite> HashedNizkpTokenEngine<M, C, H>
 where
     C: Curve + ProjectiveArithmetic,
     AffinePoint<C>: GroupEncoding,
     Scalar<C>: Invert<Output = Scalar<C>>,
+{
+    /// Check the proof of the signer against the randomized token
+    fn check_proof(
+        unsigned_token: &NizkpUnsignedToken<M, C>,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M, C>,
+        signed_token: &RandomizedSignedToken<M, C>,
+        verification_data: &PublicKey<C>,
+    ) -> Result<(), Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // get the public key
+        let u: ProjectivePoint<C> = ProjectivePoint::<C>::generator()
+            * hash_to_scalar_with::<C, H, _>(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        // verify proof
+        if signed_token.proof.verify_with_domain::<EllipticCurve<C>>(
+            randomized_unsigned_token.point.into(),
+            signed_token.point.into(),
+            u,
+            &H::DOMAIN,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::BadProof)
+        }
+    }
+}
+
+impl<M: AsRef<[u8]>, C, H: HashSuite> TokenEngine for HashedNizkpTokenEngine<M, C, H>
+where
+    C: HashToCurve + ProjectiveArithmetic,
+    AffinePoint<C>: GroupEncoding,
+    Scalar<C>: Invert<Output = Scalar<C>>,
 {
     type UnsignedToken = NizkpUnsignedToken<M, C>;
     type RandomizedUnsignedToken = RandomizedUnsignedToken<M, C>;
     type RandomizedSignedToken = RandomizedSignedToken<M, C>;
-    type SignedToken = NizkpSignedToken<M, C>;
+    type SignedToken = NizkpSignedToken<M, C, H>;
     type Randomization = Scalar<C>;
     type UserVerification = PublicKey<C>;
     type SignKey = PrivateKey<C>;
 
     //For batched tokens we generate a seed for an rng to reduce memory usage. It had to be verified that all scalars are invertible
-    fn randomize(
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
         unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
-        let r = gen_vartime::<C, _>(&mut rand::thread_rng());
-        let inverse = r.invert().unwrap();
+        let (r, inverse) = blinding::<EllipticCurve<C>, _>(rng);
         (
             r,
             Self::RandomizedUnsignedToken {
-                point: (ProjectivePoint::<C>::from(unsigned_token.get_point()) * inverse)
+                point: (ProjectivePoint::<C>::from(unsigned_token.point_with::<H>()) * inverse)
                     .to_affine(),
                 metadata: Box::from(unsigned_token.metadata.as_ref()),
                 _m: PhantomData {},
@@ -251,44 +351,53 @@ where
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken> {
-        // get the public key
-        let u: ProjectivePoint<C> = ProjectivePoint::<C>::generator()
-            * hash_to_scalar::<C, _>(&unsigned_token.metadata)
-            + verification_data.to_affine();
-
-        // verify proof
-        if signed_token.proof.verify(
-            randomized_unsigned_token.point,
-            signed_token.point,
-            u.to_affine(),
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::check_proof(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
         ) {
             // Remove randomization
-            Some(Self::SignedToken {
+            Ok(()) => Ok(Self::SignedToken {
                 point: (ProjectivePoint::<C>::from(signed_token.point) * randomization)
                     .to_affine(),
                 metadata: unsigned_token.metadata,
                 id: unsigned_token.id,
-            })
-        } else {
-            None
+                _h: PhantomData {},
+            }),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
         }
     }
 
-    fn sign_randomized(
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
         t_prime: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> CtOption<Self::RandomizedSignedToken> {
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
         // This should be a constant time implementation
-        let d = hash_to_scalar::<C, _>(&t_prime.metadata);
-        (d + sign_key.to_scalar())
-            .invert()
-            .map(|e| (ProjectivePoint::<C>::from(t_prime.point) * e).to_affine())
-            .map(|w| Self::RandomizedSignedToken {
-                point: w,
-                proof: DLEQProof::create(t_prime.point, w, d + sign_key.to_scalar()),
-                _m: PhantomData {},
-            })
+        let d = hash_to_scalar_with::<C, H, _>(&t_prime.metadata);
+        invertible(
+            sign_point::<EllipticCurve<C>>(t_prime.point.into(), d, sign_key.to_scalar())
+                .map(|w| w.to_affine())
+                .map(|w| Self::RandomizedSignedToken {
+                    point: w,
+                    proof: DleqProof::create_with_domain::<EllipticCurve<C>, _>(
+                        t_prime.point.into(),
+                        w.into(),
+                        d + sign_key.to_scalar(),
+                        &H::DOMAIN,
+                        rng,
+                    ),
+                    key_epoch: None,
+                    _m: PhantomData {},
+                }),
+        )
     }
 }
 
@@ -298,9 +407,8 @@ where
 
 #[cfg(test)]
 mod tests {
-    use elliptic_curve::group::prime::PrimeCurveAffine;
-
     use super::super::keys::{PrivateKey, PublicKey};
+    use super::super::util::{gen_ct, hash_to_scalar};
     use super::*;
 
     use k256::{AffinePoint, ProjectivePoint, Scalar, Secp256k1};
@@ -308,11 +416,11 @@ mod tests {
     #[test]
     fn test_proof() {
         // setup
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
 
         // create keys
-        let private_key: Scalar = Scalar::generate_biased(&mut rng);
-        let public_key: AffinePoint = (AffinePoint::generator() * private_key).to_affine();
+        let private_key: Scalar = gen_ct::<Secp256k1, _>(&mut rng);
+        let public_key: AffinePoint = (ProjectivePoint::generator() * private_key).to_affine();
 
         // token metadata
         let metadata = b"kake";
@@ -320,7 +428,7 @@ mod tests {
 
         // create token
         let t =
-            (ProjectivePoint::generator() * (Scalar::generate_biased(&mut rng) + d)).to_affine();
+            (ProjectivePoint::generator() * (gen_ct::<Secp256k1, _>(&mut rng) + d)).to_affine();
 
         // create u
         let u = ProjectivePoint::generator() * d + public_key;
@@ -332,10 +440,11 @@ mod tests {
         let w = w.to_affine();
 
         // create proof
-        let proof = DLEQProof::<Secp256k1>::create(t, w, private_key + d);
+        let proof =
+            DleqProof::create::<EllipticCurve<Secp256k1>>(t.into(), w.into(), private_key + d);
 
         // verify
-        assert!(proof.verify(t, w, u));
+        assert!(proof.verify::<EllipticCurve<Secp256k1>>(t.into(), w.into(), u.into()));
     }
 
     #[test]
@@ -362,7 +471,7 @@ mod tests {
             &public_key,
             r,
         );
-        assert!(signed.is_some());
+        assert!(signed.is_ok());
 
         // verify personalized token
         assert!(signed.unwrap().verify(&private));
@@ -394,10 +503,16 @@ mod tests {
             r,
         );
 
-        assert!(signed.is_some());
+        assert!(signed.is_ok());
 
         // verify personalized token
-        assert!(signed.unwrap().verify(&private));
+        let signed = signed.unwrap();
+        assert!(signed.verify(&private));
+
+        // the verifier sees the hidden metadata, and checks that it is the expected
+        assert_eq!(signed.hidden_metadata(), Some(&hidden_metadata[..]));
+        assert!(signed.verify_with_hidden(&private, hidden_metadata));
+        assert!(!signed.verify_with_hidden(&private, b"other hidden metadata"));
     }
 
     #[test]
@@ -416,7 +531,7 @@ mod tests {
             NizkpTokenEngine::sign_randomized(randomized, &bad)
         });
 
-        assert!(signed.is_none());
+        assert!(signed.is_err());
     }
 
     #[test]
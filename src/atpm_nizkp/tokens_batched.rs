@@ -1,14 +1,16 @@
-use alloc::{boxed::Box, vec::Vec};
-use core::{convert::TryInto, iter::repeat_with, marker::PhantomData};
-use rand::{prelude::StdRng, SeedableRng};
-// use serde::{Deserialize, Serialize};
+use alloc::{boxed::Box, format, vec::Vec};
+use core::{convert::TryFrom, marker::PhantomData};
+use rand::{prelude::StdRng, CryptoRng, RngCore, SeedableRng};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::common::fill_bytes;
+use crate::array::BoxedArray;
+use crate::common::{check_batch_response, check_metadata, fill_bytes, invertible};
 
 use super::{
     keys::{PrivateKey, PublicKey},
-    util::gen_vartime,
-    SignedToken, TokenEngine, TokenIdentifier, UnsignedToken,
+    util::{gen_vartime, EllipticCurve},
+    BatchResponseError, Error, KeyEpoch, SignedToken, TokenEngine, TokenIdentifier, UnsignedToken,
+    VerifyError,
 };
 
 use elliptic_curve::{
@@ -16,175 +18,21 @@ use elliptic_curve::{
     ProjectiveArithmetic, ProjectivePoint, Scalar,
 };
 
-use sha2::{Digest, Sha256};
-use subtle::CtOption;
-
-use super::util::{h_t, hash_to_scalar};
-
-// {{{ DLEQProof
-
-#[derive(Clone)]
-struct DLEQProof<C: Curve + ProjectiveArithmetic> {
-    c: Scalar<C>,
-    z: Scalar<C>,
-}
-
-impl<C: Curve + ProjectiveArithmetic> DLEQProof<C>
-where
-    AffinePoint<C>: GroupEncoding,
-{
-    fn hash_data(
-        u: AffinePoint<C>,
-        t: AffinePoint<C>,
-        w: AffinePoint<C>,
-        a: AffinePoint<C>,
-        b: AffinePoint<C>,
-    ) -> Scalar<C> {
-        let mut hasher = Sha256::new();
-        hasher.update(b"This is DLEQ_PROOF hash");
-        hasher.update(GroupEncoding::to_bytes(
-            &ProjectivePoint::<C>::generator().to_affine(),
-        ));
-        hasher.update(GroupEncoding::to_bytes(&u));
-        hasher.update(GroupEncoding::to_bytes(&t));
-        hasher.update(GroupEncoding::to_bytes(&w));
-        hasher.update(GroupEncoding::to_bytes(&a));
-        hasher.update(GroupEncoding::to_bytes(&b));
-
-        hash_to_scalar::<C, _>(&hasher.finalize())
-    }
-
-    /// Create a proof of the fact that log_w t = k
-    ///
-    /// If you create w=(d+k)^{-1} t, then create this proof with create(t, w, d + k)
-    pub fn create(t: AffinePoint<C>, w: AffinePoint<C>, k: Scalar<C>) -> Self {
-        let r = gen_vartime::<C, _>(&mut rand::thread_rng());
-        let a = ProjectivePoint::<C>::generator() * r;
-        let b = ProjectivePoint::<C>::from(w) * r;
-
-        let c = DLEQProof::<C>::hash_data(
-            (ProjectivePoint::<C>::generator() * k).to_affine(),
-            t,
-            w,
-            a.to_affine(),
-            b.to_affine(),
-        );
-
-        let z = r - k * c;
-
-        Self { c, z }
-    }
-
-    /// Verify the proof that log_w t = k
-    ///
-    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
-    pub fn verify(
-        &self,
-        t: AffinePoint<C>,
-        w: AffinePoint<C>,
-        public_key: AffinePoint<C>,
-    ) -> bool {
-        let a = ProjectivePoint::<C>::generator() * self.z
-            + ProjectivePoint::<C>::from(public_key) * self.c;
-        let b = ProjectivePoint::<C>::from(w) * self.z + ProjectivePoint::<C>::from(t) * self.c;
-        let c = DLEQProof::<C>::hash_data(public_key, t, w, a.to_affine(), b.to_affine());
-
-        c == self.c
-    }
-}
-
-struct DLEQProofBatched<C: Curve + ProjectiveArithmetic> {
-    proof: DLEQProof<C>,
-}
-
-impl<C: Curve + ProjectiveArithmetic> DLEQProofBatched<C>
-where
-    AffinePoint<C>: GroupEncoding,
-{
-    fn hash_data(
-        unsignedvec: impl AsRef<[AffinePoint<C>]>,
-        signedvec: impl AsRef<[AffinePoint<C>]>,
-        public_key: AffinePoint<C>,
-    ) -> StdRng {
-        let mut hasher = Sha256::new();
-        hasher.update(b"This is DLEQ_PROOF hash");
-        hasher.update(GroupEncoding::to_bytes(
-            &ProjectivePoint::<C>::generator().to_affine(),
-        ));
-        hasher.update(GroupEncoding::to_bytes(&public_key));
-        unsignedvec.as_ref().iter().for_each(|thing| {
-            hasher.update(GroupEncoding::to_bytes(thing));
-        });
-
-        signedvec.as_ref().iter().for_each(|item| {
-            hasher.update(GroupEncoding::to_bytes(item));
-        });
-
-        // seedable determinizstic rng
-        StdRng::from_seed(hasher.finalize().into())
-    }
-
-    ///For use in batched verification
-    /// Creates a random linear combination of the batch of tokens given trough use of hash function which seeds an rng
-    fn hash_random_linear_combination(
-        t_list: impl AsRef<[AffinePoint<C>]>,
-        w_list: impl AsRef<[AffinePoint<C>]>,
-        public_key: AffinePoint<C>,
-    ) -> (AffinePoint<C>, AffinePoint<C>) {
-        let mut c = DLEQProofBatched::<C>::hash_data(&t_list, &w_list, public_key);
-        let (newt, neww) = t_list
-            .as_ref()
-            .iter()
-            .zip(w_list.as_ref().iter())
-            .map(|(t, w)| {
-                let c = gen_vartime::<C, _>(&mut c);
-                (
-                    ((ProjectivePoint::<C>::from(*t)) * c).to_affine(),
-                    (ProjectivePoint::<C>::from(*w) * c).to_affine(),
-                )
-            })
-            .fold(
-                (
-                    ProjectivePoint::<C>::identity(),
-                    ProjectivePoint::<C>::identity(),
-                ),
-                |(tsum, wsum), (t, w)| (tsum + t, wsum + w),
-            );
-        (newt.to_affine(), neww.to_affine())
-    }
-
-    pub fn create(
-        t_list: impl AsRef<[AffinePoint<C>]>,
-        w_list: impl AsRef<[AffinePoint<C>]>,
-        k: Scalar<C>,
-    ) -> Self {
-        let (m, z) = DLEQProofBatched::<C>::hash_random_linear_combination(
-            t_list,
-            w_list,
-            (ProjectivePoint::<C>::generator() * k).to_affine(),
-        );
-        let proof = DLEQProof::create(m, z, k);
-        Self { proof }
-    }
-    /// Verifies the proof for the linear combination of the tokens in the batch
-    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
-    pub fn verify<const N: usize>(
-        &self,
-        unsignedvec: [AffinePoint<C>; N],
-        signedvec: [AffinePoint<C>; N],
-        public_key: AffinePoint<C>,
-    ) -> bool {
-        let (m, z) = DLEQProofBatched::<C>::hash_random_linear_combination(
-            unsignedvec,
-            signedvec,
-            public_key,
-        );
-        self.proof.verify(m, z, public_key)
-    }
+use super::util::{
+    h_t, hash_to_scalar, point_from_bytes, point_to_bytes, BatchedProofBytes, HashToCurve,
+};
+use crate::group::DleqProofBatched;
+
+fn projective<C: Curve + ProjectiveArithmetic>(
+    points: &[AffinePoint<C>],
+) -> Vec<ProjectivePoint<C>> {
+    points
+        .iter()
+        .copied()
+        .map(ProjectivePoint::<C>::from)
+        .collect()
 }
 
-// }}}
-
 // {{{ UnsignedToken
 
 pub struct NizkpUnsignedTokenBatched<
@@ -192,45 +40,48 @@ pub struct NizkpUnsignedTokenBatched<
     C: Curve + ProjectiveArithmetic,
     const N: usize,
 > {
-    ids: [TokenIdentifier<M>; N],
+    ids: BoxedArray<TokenIdentifier<M>, N>,
     metadata: M,
     _c: PhantomData<C>,
 }
-impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize>
-    From<&NizkpUnsignedTokenBatched<M, C, N>> for [AffinePoint<C>; N]
+impl<M: AsRef<[u8]>, C: HashToCurve + ProjectiveArithmetic, const N: usize>
+    From<&NizkpUnsignedTokenBatched<M, C, N>> for BoxedArray<AffinePoint<C>, N>
+where
+    AffinePoint<C>: GroupEncoding,
 {
     fn from(token: &NizkpUnsignedTokenBatched<M, C, N>) -> Self {
-        let points: [AffinePoint<C>; N] = (&token.ids)
-            .iter()
-            .map(|id| {
-                let t: [u8; 16] = id.into();
-                h_t::<C, _, _>(t, &token.metadata)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .ok()
-            .unwrap();
-        points
+        token.ids.map(|id| {
+            let t: [u8; 16] = id.into();
+            h_t::<C, _, _>(t, &token.metadata)
+        })
     }
 }
 
-impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> UnsignedToken
+impl<M: AsRef<[u8]> + Clone, C: Curve + ProjectiveArithmetic, const N: usize> UnsignedToken
     for NizkpUnsignedTokenBatched<M, C, N>
 {
     type Metadata = M;
     type HiddenMetadata = M;
 
-    fn new(metadata: Self::Metadata) -> Self {
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
         Self {
-            ids: TokenIdentifier::generate(),
+            ids: TokenIdentifier::generate_with_rng(rng),
             metadata,
             _c: PhantomData {},
         }
     }
 
-    // needs thinking
-    fn with_hidden(_metadata: Self::Metadata, _hidden: Self::HiddenMetadata) -> Self {
-        todo!()
+    /// The hidden metadata is the same for all the tokens in the batch
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
+        Self {
+            ids: TokenIdentifier::generate_with_hidden_with_rng(hidden, rng),
+            metadata,
+            _c: PhantomData {},
+        }
     }
 }
 
@@ -243,17 +94,33 @@ pub struct RandomizedSignedTokenBatched<
     C: Curve + ProjectiveArithmetic,
     const N: usize,
 > {
-    points: [AffinePoint<C>; N],
-    proof: DLEQProofBatched<C>,
+    points: BoxedArray<AffinePoint<C>, N>,
+    proof: DleqProofBatched<Scalar<C>>,
+    key_epoch: Option<KeyEpoch>,
     _m: PhantomData<M>,
 }
 
+impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize>
+    crate::common::RandomizedSignedToken for RandomizedSignedTokenBatched<M, C, N>
+{
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
 pub struct RandomizedUnsignedTokenBatched<
     M: AsRef<[u8]>,
     C: Curve + ProjectiveArithmetic,
     const N: usize,
 > {
-    points: [AffinePoint<C>; N],
+    points: BoxedArray<AffinePoint<C>, N>,
     metadata: Box<[u8]>,
     _m: PhantomData<M>,
 }
@@ -268,34 +135,127 @@ impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize>
 
 // }}}
 
+// {{{ serialization
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "RandomizedUnsignedTokenBatched")]
+struct RandomizedUnsignedBytes {
+    points: Vec<Vec<u8>>,
+    metadata: Box<[u8]>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "RandomizedSignedTokenBatched")]
+struct RandomizedSignedBytes {
+    points: Vec<Vec<u8>>,
+    proof: BatchedProofBytes,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
+}
+
+/// Decode the points, and check that there are N of them
+fn points_from_bytes<C: Curve + ProjectiveArithmetic, E: de::Error, const N: usize>(
+    points: &[Vec<u8>],
+) -> Result<BoxedArray<AffinePoint<C>, N>, E>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    let points = points
+        .iter()
+        .map(|bytes| point_from_bytes::<C, E>(bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    BoxedArray::try_from(points).map_err(|points| {
+        E::custom(format!("expected {} points, not {}", N, points.len()).as_str())
+    })
+}
+
+impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> Serialize
+    for RandomizedUnsignedTokenBatched<M, C, N>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RandomizedUnsignedBytes {
+            points: self.points.iter().map(point_to_bytes::<C>).collect(),
+            metadata: self.metadata.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> Deserialize<'de>
+    for RandomizedUnsignedTokenBatched<M, C, N>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = RandomizedUnsignedBytes::deserialize(deserializer)?;
+
+        Ok(Self {
+            points: points_from_bytes::<C, _, N>(&bytes.points)?,
+            metadata: bytes.metadata,
+            _m: PhantomData {},
+        })
+    }
+}
+
+impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> Serialize
+    for RandomizedSignedTokenBatched<M, C, N>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RandomizedSignedBytes {
+            points: self.points.iter().map(point_to_bytes::<C>).collect(),
+            proof: BatchedProofBytes::new::<C>(&self.proof),
+            key_epoch: self.key_epoch,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> Deserialize<'de>
+    for RandomizedSignedTokenBatched<M, C, N>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = RandomizedSignedBytes::deserialize(deserializer)?;
+
+        Ok(Self {
+            points: points_from_bytes::<C, _, N>(&bytes.points)?,
+            proof: bytes.proof.decode::<C, _>()?,
+            key_epoch: bytes.key_epoch,
+            _m: PhantomData {},
+        })
+    }
+}
+
+// }}}
+
 // {{{ Signed token
 
 pub struct NizkpSignedTokenBatched<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize>
 {
-    ids: [TokenIdentifier<M>; N],
+    ids: BoxedArray<TokenIdentifier<M>, N>,
     metadata: M,
-    points: [AffinePoint<C>; N],
+    points: BoxedArray<AffinePoint<C>, N>,
 }
 
-impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> SignedToken
+impl<M: AsRef<[u8]>, C: HashToCurve + ProjectiveArithmetic, const N: usize> SignedToken
     for NizkpSignedTokenBatched<M, C, N>
 where
     Scalar<C>: Invert<Output = Scalar<C>>,
-    AffinePoint<C>: PartialEq,
+    AffinePoint<C>: PartialEq + GroupEncoding,
 {
     type VerificationKey = PrivateKey<C>;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
-        let tpoints: [AffinePoint<C>; N] = (&self.ids)
-            .iter()
-            .map(|id| {
-                let t: [u8; 16] = id.into();
-                h_t::<C, _, _>(t, &self.metadata)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .ok()
-            .unwrap();
+        let tpoints = self.ids.map(|id| {
+            let t: [u8; 16] = id.into();
+            h_t::<C, _, _>(t, &self.metadata)
+        });
         // We may do this, since
         // w == e * t is the same as e^-1 w == t
         // We then do not need to do the inversion step, and maybe it could be easier to build
@@ -313,6 +273,10 @@ where
                 .fold(ProjectivePoint::<C>::identity(), |sum, point| sum + point)
                 .to_affine()
     }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
 }
 
 // }}}
@@ -327,7 +291,70 @@ where
     _c: PhantomData<C>,
 }
 
-impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize> TokenEngine
+impl<M: AsRef<[u8]>, C: Curve + ProjectiveArithmetic, const N: usize>
+    BatchedNizkpTokenEngine<M, C, N>
+where
+    AffinePoint<C>: GroupEncoding + PartialEq,
+{
+    /// Check that the signer returned one point for each point sent, that they are distinct, and
+    /// that none of them are the identity.
+    ///
+    /// This is done by `verify_signature_and_unrandomize`, but may be used to find out why a
+    /// response was rejected.
+    pub fn check_response(
+        randomized_unsigned: &RandomizedUnsignedTokenBatched<M, C, N>,
+        signed_token: &RandomizedSignedTokenBatched<M, C, N>,
+    ) -> Result<(), BatchResponseError> {
+        // the encoding of the identity is shorter than the other points, and panics in to_bytes
+        let identity = signed_token
+            .points
+            .iter()
+            .any(|point| bool::from(ProjectivePoint::<C>::from(*point).is_identity()));
+        if identity {
+            return Err(BatchResponseError::IdentityPoint);
+        }
+
+        check_batch_response(
+            randomized_unsigned.points.len(),
+            signed_token.points.iter().map(GroupEncoding::to_bytes),
+            [],
+        )
+    }
+
+    /// Check the response and the proof of the signer against the randomized tokens
+    fn check_proof(
+        unsigned_token: &NizkpUnsignedTokenBatched<M, C, N>,
+        randomized_unsigned_token: &RandomizedUnsignedTokenBatched<M, C, N>,
+        signed_token: &RandomizedSignedTokenBatched<M, C, N>,
+        verification_data: &PublicKey<C>,
+    ) -> Result<(), Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned_token, signed_token)?;
+
+        // get the public key
+        let u = ProjectivePoint::<C>::generator()
+            * hash_to_scalar::<C, _>(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        // verify proof
+        if signed_token.proof.verify::<EllipticCurve<C>>(
+            &projective::<C>(&randomized_unsigned_token.points),
+            &projective::<C>(&signed_token.points),
+            u,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::BadProof)
+        }
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone, C: HashToCurve + ProjectiveArithmetic, const N: usize> TokenEngine
     for BatchedNizkpTokenEngine<M, C, N>
 where
     AffinePoint<C>: GroupEncoding + PartialEq,
@@ -342,60 +369,38 @@ where
     type SignKey = PrivateKey<C>;
 
     //For batched tokens we generate a seed for an rng to reduce memory usage. It had to be verified that all scalars are invertible
-    fn randomize(
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
         unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
         // create random seed
         let mut randomization = [0; 32];
-        fill_bytes(&mut rand::thread_rng(), &mut randomization);
+        fill_bytes(rng, &mut randomization);
 
         // seed an rng for the series of r
         let mut rng = StdRng::from_seed(randomization);
 
-        let nums = repeat_with(|| gen_vartime::<C, _>(&mut rng)) // generate random r's
-            .take(N)
-            .map(|r| r.invert())
-            .fold(Some(Vec::new()), |v, e| match v {
-                // Fold down the list to remove failed random numbers,
-                // should be None
-                Some(mut v) => {
-                    if bool::from(e.is_some()) {
-                        v.push(e.unwrap());
-                        Some(v)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            });
-
-        if nums.is_none() {
-            Self::randomize(unsigned_token)
-        } else {
-            (
+        // generate random r's, and their inverses, fails if one of them is not invertible
+        let nums = BoxedArray::<_, N>::try_from_fn(|_i| {
+            Option::from(gen_vartime::<C, _>(&mut rng).invert())
+        });
+
+        match nums {
+            None => Self::randomize(unsigned_token),
+            Some(nums) => (
                 randomization,
                 Self::RandomizedUnsignedToken {
-                    points: nums
-                        .unwrap()
-                        .into_iter()
-                        .zip(unsigned_token.ids.iter())
-                        .map(|(r, id)| {
-                            let t: [u8; 16] = id.into();
-                            // T' = [r]T
-                            (ProjectivePoint::<C>::from(h_t::<C, _, _>(
-                                t,
-                                &unsigned_token.metadata,
-                            )) * r)
-                                .to_affine()
-                        })
-                        .collect::<Vec<_>>()
-                        .try_into()
-                        .ok()
-                        .unwrap(),
+                    points: nums.zip_map(&unsigned_token.ids, |r, id| {
+                        let t: [u8; 16] = id.into();
+                        // T' = [r]T
+                        (ProjectivePoint::<C>::from(h_t::<C, _, _>(t, &unsigned_token.metadata))
+                            * r)
+                            .to_affine()
+                    }),
                     metadata: Box::from(unsigned_token.metadata.as_ref()),
                     _m: PhantomData {},
                 },
-            )
+            ),
         }
     }
 
@@ -405,68 +410,63 @@ where
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken> {
-        // get the public key
-        let u = ProjectivePoint::<C>::generator()
-            * hash_to_scalar::<C, _>(&unsigned_token.metadata)
-            + verification_data.to_affine();
-
-        // verify proof
-        if signed_token.proof.verify(
-            randomized_unsigned_token.points,
-            signed_token.points,
-            u.to_affine(),
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::check_proof(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
         ) {
             // needs fix
             // Remove randomization
-
-            let mut rng = StdRng::from_seed(randomization);
-            let rlist = repeat_with(|| gen_vartime::<C, _>(&mut rng)).take(N);
-            Some(Self::SignedToken {
-                points: (signed_token
-                    .points
-                    .iter()
-                    .zip(rlist)
-                    .map(|(point, r)| (ProjectivePoint::<C>::from(*point) * r).to_affine())
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .ok()
-                    .unwrap()),
-                metadata: unsigned_token.metadata,
-                ids: unsigned_token.ids,
-            })
-        } else {
-            None
+            Ok(()) => {
+                let mut rng = StdRng::from_seed(randomization);
+                Ok(Self::SignedToken {
+                    points: signed_token.points.map(|point| {
+                        (ProjectivePoint::<C>::from(*point) * gen_vartime::<C, _>(&mut rng))
+                            .to_affine()
+                    }),
+                    metadata: unsigned_token.metadata,
+                    ids: unsigned_token.ids,
+                })
+            }
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
         }
     }
 
-    fn sign_randomized(
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
         t_prime: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> CtOption<Self::RandomizedSignedToken> {
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
         // This should be a constant time implementation
         let d = hash_to_scalar::<C, _>(&t_prime.metadata);
-        (d + sign_key.to_scalar()).invert().map(|e| {
+        invertible((d + sign_key.to_scalar()).invert().map(|e| {
             // list of W'
             let w_prime_list = t_prime
                 .points
-                .iter()
-                .map(|t_prime| (ProjectivePoint::<C>::from(*t_prime) * e).to_affine())
-                .collect::<Vec<_>>()
-                .try_into()
-                .ok()
-                .unwrap();
+                .map(|t_prime| (ProjectivePoint::<C>::from(*t_prime) * e).to_affine());
 
             //
 
-            let proof =
-                DLEQProofBatched::create(&t_prime.points, &w_prime_list, d + sign_key.to_scalar());
+            let proof = DleqProofBatched::create_with_rng::<EllipticCurve<C>, _>(
+                &projective::<C>(&t_prime.points),
+                &projective::<C>(&w_prime_list),
+                d + sign_key.to_scalar(),
+                rng,
+            );
             RandomizedSignedTokenBatched {
                 points: w_prime_list,
                 proof,
+                key_epoch: None,
                 _m: PhantomData {},
             }
-        })
+        }))
     }
 }
 
@@ -477,19 +477,20 @@ where
 #[cfg(test)]
 mod tests {
     use super::super::keys::{PrivateKey, PublicKey};
+    use super::super::util::gen_ct;
     use super::*;
+    use crate::group::DleqProof;
 
-    use elliptic_curve::group::prime::PrimeCurveAffine;
-    use k256::{AffinePoint, ProjectivePoint, Scalar, Secp256k1};
+    use k256::{ProjectivePoint, Secp256k1};
 
     #[test]
     fn test_proof() {
         // setup
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
 
         // create keys
-        let private_key = Scalar::generate_biased(&mut rng);
-        let public_key = AffinePoint::generator() * private_key;
+        let private_key = gen_ct::<Secp256k1, _>(&mut rng);
+        let public_key = ProjectivePoint::generator() * private_key;
         let public_key = public_key.to_affine();
 
         // token metadata
@@ -497,7 +498,7 @@ mod tests {
         let d = hash_to_scalar::<Secp256k1, _>(metadata);
 
         // create token
-        let t = ProjectivePoint::generator() * (Scalar::generate_biased(&mut rng) + d);
+        let t = ProjectivePoint::generator() * (gen_ct::<Secp256k1, _>(&mut rng) + d);
         let t = t.to_affine();
 
         // create u
@@ -510,10 +511,11 @@ mod tests {
         let w = w.to_affine();
 
         // create proof
-        let proof = DLEQProof::<Secp256k1>::create(t, w, private_key + d);
+        let proof =
+            DleqProof::create::<EllipticCurve<Secp256k1>>(t.into(), w.into(), private_key + d);
 
         // verify
-        assert!(proof.verify(t, w, u));
+        assert!(proof.verify::<EllipticCurve<Secp256k1>>(t.into(), w.into(), u.into()));
     }
 
     #[test]
@@ -540,12 +542,52 @@ mod tests {
             &public_key,
             r,
         );
-        assert!(signed.is_some());
+        assert!(signed.is_ok());
 
         // verify personalized token
         assert!(signed.unwrap().verify(&private));
     }
 
+    #[test]
+    fn test_serialization() {
+        // fixed scalars, the points do not have to be tokens
+        let k = hash_to_scalar::<Secp256k1, _>(b"key");
+
+        let t_list = [
+            ProjectivePoint::generator() * hash_to_scalar::<Secp256k1, _>(b"first"),
+            ProjectivePoint::generator() * hash_to_scalar::<Secp256k1, _>(b"second"),
+        ];
+        let w_list = [t_list[0] * k, t_list[1] * k];
+
+        let signed = RandomizedSignedTokenBatched::<Box<[u8]>, Secp256k1, 2> {
+            points: BoxedArray::from([w_list[0].to_affine(), w_list[1].to_affine()]),
+            proof: DleqProofBatched::create_with_rng::<EllipticCurve<Secp256k1>, _>(
+                &w_list,
+                &t_list,
+                k,
+                &mut crate::rng::default_rng(),
+            ),
+            key_epoch: Some(3),
+            _m: PhantomData {},
+        };
+
+        let serialized = serde_json::to_string(&signed).unwrap();
+        let deserialized: RandomizedSignedTokenBatched<Box<[u8]>, Secp256k1, 2> =
+            serde_json::from_str(&serialized).unwrap();
+
+        assert!(deserialized.points == signed.points);
+        assert!(deserialized.proof == signed.proof);
+        assert_eq!(deserialized.key_epoch, Some(3));
+
+        // the number of points is checked
+        assert!(
+            serde_json::from_str::<RandomizedSignedTokenBatched<Box<[u8]>, Secp256k1, 3>>(
+                &serialized
+            )
+            .is_err()
+        );
+    }
+
     #[test]
     fn fail_bad_signkey() {
         // generate keys
@@ -563,7 +605,7 @@ mod tests {
             BatchedNizkpTokenEngine::sign_randomized(randomized, &bad)
         });
 
-        assert!(signed.is_none());
+        assert!(signed.is_err());
     }
 
     #[test]
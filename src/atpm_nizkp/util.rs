@@ -1,19 +1,42 @@
-use core::convert::TryFrom;
+use alloc::{format, vec, vec::Vec};
+use core::{convert::TryFrom, marker::PhantomData};
 
 use elliptic_curve::{
-    AffineArithmetic, AffinePoint, Curve, FieldBytes, ProjectiveArithmetic,
-    Scalar, ScalarBytes,
+    group::{
+        ff::{Field, PrimeField},
+        Curve as _, GroupEncoding,
+    },
+    AffineArithmetic, AffinePoint, Curve, FieldBytes, Group, ProjectiveArithmetic, ProjectivePoint,
+    Scalar, ScalarArithmetic, ScalarBytes,
 };
 use rand::{CryptoRng, RngCore};
+use serde::{de, Deserialize, Serialize};
+use sha2::digest::{
+    consts::U32,
+    generic_array::{typenum::Unsigned, GenericArray},
+    BlockInput,
+};
 use sha2::{Digest, Sha256};
+use subtle::{Choice, ConditionallySelectable, CtOption};
+use zeroize::Zeroize;
+
+use crate::group::{DleqProof, DleqProofBatched, PrimeOrderGroup};
+use crate::hash_suite::{HashSuite, Sha2};
 
 /// hash the input bytes uniformly to a scalar
 ///
 /// This is a variable time implementation, to get uniform randomness by rejection sampling
 pub fn hash_to_scalar<C: Curve + ProjectiveArithmetic, D: AsRef<[u8]>>(data: D) -> Scalar<C> {
-    let mut hasher = Sha256::new();
+    hash_to_scalar_with::<C, Sha2, D>(data)
+}
+
+/// [`hash_to_scalar`] with the narrow hash of the suite
+pub fn hash_to_scalar_with<C: Curve + ProjectiveArithmetic, H: HashSuite, D: AsRef<[u8]>>(
+    data: D,
+) -> Scalar<C> {
+    let mut hasher = H::Narrow::new();
     // domain of the oracle, to have separate oracles
-    hasher.update(b"This is hash_to_scalar hash");
+    hasher.update(H::DOMAIN.hash_to_scalar);
 
     // input data
     hasher.update(data);
@@ -25,69 +48,182 @@ pub fn hash_to_scalar<C: Curve + ProjectiveArithmetic, D: AsRef<[u8]>>(data: D)
 
     // Try to get a scalar
     // This is tail recursive, so should be compiled to replace the stack frame
-    let scalar_bytes = ScalarBytes::<C>::try_from(bytes);
-    if scalar_bytes.is_ok() {
-        scalar_bytes.unwrap().into_scalar()
-    } else {
+    match ScalarBytes::<C>::try_from(bytes) {
+        Ok(scalar_bytes) => scalar_bytes.into_scalar(),
         // If there was not a scalar, try again recursivly
-        hash_to_scalar::<C, _>(b)
+        Err(_) => hash_to_scalar_with::<C, H, _>(b),
     }
 }
 
-/// hash to the curve
-///
-/// This uses a variable time hash to scalar, and multiplies the generator by this scalar to get a
-/// curve point
-pub fn h_t<C: Curve + AffineArithmetic, T: AsRef<[u8]>, M: AsRef<[u8]>>(
-    t: T,
-    m: M,
-) -> AffinePoint<C> {
-    let mut hasher = Sha256::new();
+/// Hash a public key and a label to the tweak of a derived key
+pub(crate) fn h_derive<C: Curve + AffineArithmetic + ProjectiveArithmetic>(
+    public_key: &AffinePoint<C>,
+    label: &[u8],
+) -> Scalar<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
     // domain of the oracle, to have separate oracles
-    hasher.update(b"This is h_t hash");
+    let mut data = b"This is h_derive hash".to_vec();
 
-    // Input the data to the oracle
-    hasher.update(t);
-    hasher.update(m);
+    // the point has a fixed length, so the label needs no length prefix
+    data.extend_from_slice(&point_to_bytes::<C>(public_key));
+    data.extend_from_slice(label);
 
-    let bytes = hasher.finalize();
+    hash_to_scalar::<C, _>(data)
+}
 
-    if let Some(point) = bytes_to_curve::<C, _>(&bytes) {
-        point
-    } else {
-        hash_to_curve::<C, _>(bytes)
+// {{{ Hash to curve
+
+/// A curve with the map to the curve of RFC 9380, for [`h_t`]
+///
+/// elliptic-curve 0.10 has no hash to curve, so the curves of the crate map the field elements
+/// with the SSWU map of the 0.13 releases of their crates. Another curve implements this with
+/// the map of its suite in RFC 9380.
+pub trait HashToCurve: Curve + AffineArithmetic {
+    /// The number of uniform bytes of a field element, L in RFC 9380
+    const FIELD_LENGTH: usize;
+
+    /// The sum of the maps of the two field elements of the uniform bytes, which are
+    /// `2 * FIELD_LENGTH` bytes long
+    fn map_to_curve(uniform: &[u8]) -> AffinePoint<Self>;
+}
+
+/// Convert a point of another version of the crate of the curve, by its compressed encoding
+///
+/// The identity has no compressed encoding, and is the default point.
+fn from_compressed<C: AffineArithmetic>(compressed: &[u8]) -> AffinePoint<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    let mut repr = <AffinePoint<C> as GroupEncoding>::Repr::default();
+    if repr.as_ref().len() != compressed.len() {
+        return AffinePoint::<C>::default();
     }
+
+    repr.as_mut().copy_from_slice(compressed);
+    Option::from(AffinePoint::<C>::from_bytes(&repr)).unwrap_or_default()
 }
 
-fn hash_to_curve<C: Curve + AffineArithmetic, T: AsRef<[u8]>>(t: T) -> AffinePoint<C> {
-    let mut hasher = Sha256::new();
-    // domain of the oracle, to have separate oracles
-    hasher.update(b"This is hash to curve");
+impl HashToCurve for k256::Secp256k1 {
+    const FIELD_LENGTH: usize = 48;
 
-    // Input the data to the oracle
-    hasher.update(t);
+    fn map_to_curve(uniform: &[u8]) -> AffinePoint<Self> {
+        use k256_hash2curve::elliptic_curve::{
+            generic_array::GenericArray,
+            group::cofactor::CofactorGroup,
+            hash2curve::{FromOkm, GroupDigest, MapToCurve},
+            sec1::ToEncodedPoint,
+        };
 
-    let bytes = hasher.finalize();
+        type Field = <k256_hash2curve::Secp256k1 as GroupDigest>::FieldElement;
+        let (u0, u1) = uniform.split_at(Self::FIELD_LENGTH);
+        let q = Field::from_okm(GenericArray::from_slice(u0)).map_to_curve()
+            + Field::from_okm(GenericArray::from_slice(u1)).map_to_curve();
 
-    if let Some(point) = bytes_to_curve::<C, _>(&bytes) {
-        point
-    } else {
-        hash_to_curve::<C, _>(bytes)
+        from_compressed::<Self>(
+            q.clear_cofactor()
+                .to_affine()
+                .to_encoded_point(true)
+                .as_bytes(),
+        )
     }
 }
 
-fn bytes_to_curve<C: Curve + AffineArithmetic, T: AsRef<[u8]>>(_t: T) -> Option<AffinePoint<C>> {
-    unimplemented!()
+#[cfg(feature = "nizkp_p256")]
+impl HashToCurve for p256::NistP256 {
+    const FIELD_LENGTH: usize = 48;
+
+    fn map_to_curve(uniform: &[u8]) -> AffinePoint<Self> {
+        use p256_hash2curve::elliptic_curve::{
+            generic_array::GenericArray,
+            group::cofactor::CofactorGroup,
+            hash2curve::{FromOkm, GroupDigest, MapToCurve},
+            sec1::ToEncodedPoint,
+        };
+
+        type Field = <p256_hash2curve::NistP256 as GroupDigest>::FieldElement;
+        let (u0, u1) = uniform.split_at(Self::FIELD_LENGTH);
+        let q = Field::from_okm(GenericArray::from_slice(u0)).map_to_curve()
+            + Field::from_okm(GenericArray::from_slice(u1)).map_to_curve();
+
+        from_compressed::<Self>(
+            q.clear_cofactor()
+                .to_affine()
+                .to_encoded_point(true)
+                .as_bytes(),
+        )
+    }
+}
+
+/// hash to the curve
+///
+/// This is the `hash_to_curve` of RFC 9380, with `expand_message_xmd` of the narrow hash and the
+/// `hash_to_curve` tag of the suite as the DST. With [`Sha2`] it is the suite
+/// `secp256k1_XMD:SHA-256_SSWU_RO_` or `P256_XMD:SHA-256_SSWU_RO_` of the curve, and it is
+/// constant time in the input.
+pub fn h_t<C: HashToCurve, T: AsRef<[u8]>, M: AsRef<[u8]>>(t: T, m: M) -> AffinePoint<C> {
+    h_t_with::<C, Sha2, T, M>(t, m)
+}
+
+/// [`h_t`] with the narrow hash of the suite
+pub fn h_t_with<C: HashToCurve, H: HashSuite, T: AsRef<[u8]>, M: AsRef<[u8]>>(
+    t: T,
+    m: M,
+) -> AffinePoint<C> {
+    let mut uniform = vec![0; 2 * C::FIELD_LENGTH];
+
+    // t has a fixed length, so m needs no length prefix
+    expand_message_xmd::<H>(
+        &[t.as_ref(), m.as_ref()],
+        H::DOMAIN.hash_to_curve,
+        &mut uniform,
+    );
+
+    C::map_to_curve(&uniform)
 }
-//     let x = FieldBytes::<C>::from_slice(t.as_ref());
 
-//     let point = DecompactPoint::decompact(x);
-//     if bool::from(point.is_some()) {
-//         Some(point.unwrap())
-//     } else {
-//         None
-//     }
-// }
+/// `expand_message_xmd` of RFC 9380 with the narrow hash, to fill the uniform bytes
+///
+/// The DST is at most 255 bytes, and the bytes at most 255 hashes, which the tags and the curves
+/// of the crate are.
+fn expand_message_xmd<H: HashSuite>(msg: &[&[u8]], dst: &[u8], uniform: &mut [u8]) {
+    let block_size = <<H::Narrow as BlockInput>::BlockSize as Unsigned>::USIZE;
+    let blocks = uniform.len().div_ceil(32);
+    debug_assert!(dst.len() <= 255 && blocks <= 255);
+
+    let dst_prime = |hasher: &mut H::Narrow| {
+        hasher.update(dst);
+        hasher.update([dst.len() as u8]);
+    };
+
+    let mut hasher = H::Narrow::new();
+    hasher.update(vec![0; block_size]);
+    msg.iter().for_each(|part| hasher.update(part));
+    hasher.update((uniform.len() as u16).to_be_bytes());
+    hasher.update([0]);
+    dst_prime(&mut hasher);
+    let b_0 = hasher.finalize();
+
+    let mut b_i = GenericArray::<u8, U32>::default();
+    for (i, chunk) in uniform.chunks_mut(32).enumerate() {
+        // b_1 = H(b_0 || 1 || DST'), b_i = H((b_0 xor b_{i-1}) || i || DST')
+        let mut hasher = H::Narrow::new();
+        let xored = b_0
+            .iter()
+            .zip(b_i.iter())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+        hasher.update(xored);
+        hasher.update([i as u8 + 1]);
+        dst_prime(&mut hasher);
+        b_i = hasher.finalize();
+
+        chunk.copy_from_slice(&b_i[..chunk.len()]);
+    }
+}
+
+// }}}
 
 pub fn gen_vartime<C: Curve + ProjectiveArithmetic, R: RngCore + CryptoRng>(
     rng: &mut R,
@@ -104,3 +240,228 @@ pub fn gen_vartime<C: Curve + ProjectiveArithmetic, R: RngCore + CryptoRng>(
         gen_vartime::<C, _>(rng)
     }
 }
+
+/// Generate a random scalar in constant time
+///
+/// The scalar is 512 random bits reduced by the order of the group, bit by bit, so the bias from
+/// uniform is negligible.
+pub fn gen_ct<C: Curve + ProjectiveArithmetic, R: RngCore + CryptoRng>(rng: &mut R) -> Scalar<C> {
+    let mut bytes = [0u8; 64];
+    rng.fill_bytes(&mut bytes);
+
+    let scalar = from_bytes_wide::<C>(&bytes);
+    bytes.zeroize();
+    scalar
+}
+
+/// Reduce 512 bits by the order of the group in constant time, bit by bit
+pub(crate) fn from_bytes_wide<C: Curve + ProjectiveArithmetic>(bytes: &[u8; 64]) -> Scalar<C> {
+    let mut scalar = Scalar::<C>::zero();
+    for byte in bytes.iter() {
+        for i in (0..8).rev() {
+            scalar = scalar.double();
+            scalar.conditional_assign(
+                &(scalar + Scalar::<C>::one()),
+                Choice::from((byte >> i) & 1),
+            );
+        }
+    }
+
+    scalar
+}
+
+// {{{ Group
+
+/// The group of points of an elliptic curve
+pub struct EllipticCurve<C>(PhantomData<C>);
+
+impl<C: Curve + ProjectiveArithmetic> PrimeOrderGroup for EllipticCurve<C>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    type Scalar = Scalar<C>;
+    type Element = ProjectivePoint<C>;
+
+    fn generator() -> ProjectivePoint<C> {
+        ProjectivePoint::<C>::generator()
+    }
+
+    fn identity() -> ProjectivePoint<C> {
+        ProjectivePoint::<C>::identity()
+    }
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Scalar<C> {
+        gen_ct::<C, _>(rng)
+    }
+
+    fn invert(scalar: &Scalar<C>) -> CtOption<Scalar<C>> {
+        scalar.invert()
+    }
+
+    fn encode(element: &ProjectivePoint<C>) -> Vec<u8> {
+        element.to_affine().to_bytes().as_ref().to_vec()
+    }
+
+    fn challenge(transcript: &[u8]) -> Scalar<C> {
+        hash_to_scalar::<C, _>(&Sha256::digest(transcript))
+    }
+}
+
+// }}}
+
+// {{{ serialization
+
+/// A proof as the encodings of its two scalars
+#[derive(Serialize, Deserialize)]
+struct ProofBytes {
+    c: Vec<u8>,
+    z: Vec<u8>,
+}
+
+/// A batched proof as the proof of the linear combination
+#[derive(Serialize, Deserialize)]
+pub(crate) struct BatchedProofBytes {
+    proof: ProofBytes,
+}
+
+impl BatchedProofBytes {
+    pub(crate) fn new<C: Curve + ScalarArithmetic>(proof: &DleqProofBatched<Scalar<C>>) -> Self {
+        Self {
+            proof: ProofBytes {
+                c: proof.proof.c.to_repr().to_vec(),
+                z: proof.proof.z.to_repr().to_vec(),
+            },
+        }
+    }
+
+    pub(crate) fn decode<C: Curve + ScalarArithmetic, E: de::Error>(
+        &self,
+    ) -> Result<DleqProofBatched<Scalar<C>>, E> {
+        Ok(DleqProofBatched {
+            proof: DleqProof {
+                c: scalar_from_bytes::<C, E>(&self.proof.c)?,
+                z: scalar_from_bytes::<C, E>(&self.proof.z)?,
+            },
+        })
+    }
+}
+
+pub(crate) fn point_to_bytes<C: Curve + AffineArithmetic>(point: &AffinePoint<C>) -> Vec<u8>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    point.to_bytes().as_ref().to_vec()
+}
+
+/// Decode a point, and check that it is on the curve
+pub(crate) fn point_from_bytes<C: Curve + AffineArithmetic, E: de::Error>(
+    bytes: &[u8],
+) -> Result<AffinePoint<C>, E>
+where
+    AffinePoint<C>: GroupEncoding,
+{
+    let mut repr = <AffinePoint<C> as GroupEncoding>::Repr::default();
+    if repr.as_ref().len() != bytes.len() {
+        return Err(E::custom(
+            format!(
+                "point bytes has to be {} bytes, not {}",
+                repr.as_ref().len(),
+                bytes.len()
+            )
+            .as_str(),
+        ));
+    }
+    repr.as_mut().copy_from_slice(bytes);
+
+    Option::from(AffinePoint::<C>::from_bytes(&repr))
+        .ok_or_else(|| E::custom("Failed to decompress token point"))
+}
+
+/// Decode a scalar, and reject non canonical encodings
+pub(crate) fn scalar_from_bytes<C: Curve + ScalarArithmetic, E: de::Error>(
+    bytes: &[u8],
+) -> Result<Scalar<C>, E> {
+    let mut repr = FieldBytes::<C>::default();
+    if repr.len() != bytes.len() {
+        return Err(E::custom(
+            format!(
+                "scalar bytes has to be {} bytes, not {}",
+                repr.len(),
+                bytes.len()
+            )
+            .as_str(),
+        ));
+    }
+    repr.copy_from_slice(bytes);
+
+    Scalar::<C>::from_repr(repr).ok_or_else(|| E::custom("Scalar is not canonical"))
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+    use k256::Secp256k1;
+
+    fn hex<C: HashToCurve>(point: AffinePoint<C>) -> String
+    where
+        AffinePoint<C>: GroupEncoding,
+    {
+        point
+            .to_bytes()
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// hash_to_curve of RFC 9380 with the DST of its test vectors
+    fn hash_to_curve<C: HashToCurve>(msg: &[u8], dst: &[u8]) -> String
+    where
+        AffinePoint<C>: GroupEncoding,
+    {
+        let mut uniform = vec![0; 2 * C::FIELD_LENGTH];
+        expand_message_xmd::<Sha2>(&[msg], dst, &mut uniform);
+        hex::<C>(C::map_to_curve(&uniform))
+    }
+
+    #[test]
+    fn test_secp256k1() {
+        // the vector of msg "abc" in appendix J.8.1 of RFC 9380
+        assert_eq!(
+            hash_to_curve::<Secp256k1>(
+                b"abc",
+                b"QUUX-V01-CS02-with-secp256k1_XMD:SHA-256_SSWU_RO_"
+            ),
+            "023377e01eab42db296b512293120c6cee72b6ecf9f9205760bd9ff11fb3cb2c4b"
+        );
+
+        assert_eq!(
+            hex::<Secp256k1>(h_t::<Secp256k1, _, _>([7; 16], b"metadata")),
+            "03fa56d6ffaa2192b6698afef6fae42024eb327ece48d5bf75fb6e06048a91d8e8"
+        );
+        assert_ne!(
+            hex::<Secp256k1>(h_t::<Secp256k1, _, _>([7; 16], b"metadata")),
+            hex::<Secp256k1>(h_t::<Secp256k1, _, _>([7; 16], b"metadatb"))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "nizkp_p256")]
+    fn test_p256() {
+        use p256::NistP256;
+
+        // the vector of msg "abc" in appendix J.1.1 of RFC 9380
+        assert_eq!(
+            hash_to_curve::<NistP256>(b"abc", b"QUUX-V01-CS02-with-P256_XMD:SHA-256_SSWU_RO_"),
+            "020bb8b87485551aa43ed54f009230450b492fead5f1cc91658775dac4a3388a0f"
+        );
+
+        assert_eq!(
+            hex::<NistP256>(h_t::<NistP256, _, _>([7; 16], b"metadata")),
+            "03b4e82d0fd3fff686b58c97960bb503b5d7f624b79b2fef74941c228e76fbe53e"
+        );
+    }
+}
@@ -0,0 +1,505 @@
+//! # Aggregated tokens
+//!
+//! The signatures of tokens with the same metadata add up, like BLS signatures: every token has
+//! e(w, u) = e(t, g2) with the same u = g2 * h_m(metadata) + pk, so the sum of the signatures w has
+//! e(sum w, u) = e(sum t, g2). An [`AggregateToken`] has the ids of the tokens and one signature
+//! for each metadata, so a client may redeem a bundle of tokens, e.g. for several resources, in
+//! one message. It is verified with one Miller loop of a pairing for each metadata and one more,
+//! and one final exponentiation.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         aggregate::AggregateToken,
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let tokens: Vec<_> = [&b"article"[..], b"video", b"article"]
+//!         .iter()
+//!         .map(|metadata| {
+//!             PairingTokenEngine::sign(
+//!                 PairingTokenEngine::generate(*metadata),
+//!                 &public_key,
+//!                 |randomized| PairingTokenEngine::sign_randomized(randomized, &secret_key),
+//!             )
+//!             .unwrap()
+//!         })
+//!         .collect();
+//!
+//!     let bundle = AggregateToken::aggregate(&tokens);
+//!     assert_eq!((bundle.len(), bundle.groups().count()), (3, 2));
+//!     assert!(bundle.verify(&public_key));
+//! ```
+
+use bls12_381::{multi_miller_loop, G1Affine, G1Projective, G2Affine, G2Prepared, Gt};
+use serde::{Deserialize, Serialize};
+
+use alloc::{collections::BTreeSet, vec::Vec};
+use core::convert::TryInto;
+
+use super::keys::PublicKey;
+use super::tokens::PairingSignedToken;
+use super::util::{h_1, h_m, CurvePoint};
+use super::{KeyId, SignedToken, TokenIdentifier};
+use crate::expiry;
+use crate::redemption::{Fingerprint, RedeemError, Redeemable, RedemptionStore};
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
+
+/// The tokens of a bundle with one metadata, and the sum of their signatures
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AggregateGroup<M: AsRef<[u8]>> {
+    metadata: M,
+    ids: Vec<TokenIdentifier<M>>,
+    signature: CurvePoint,
+}
+
+impl<M: AsRef<[u8]>> AggregateGroup<M> {
+    pub fn metadata(&self) -> &M {
+        &self.metadata
+    }
+
+    /// The number of tokens with the metadata
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Whether the token is one of the group, by its fingerprint
+    pub fn contains(&self, token: &PairingSignedToken<M>) -> bool {
+        token.metadata_bytes() == self.metadata.as_ref()
+            && self
+                .ids
+                .iter()
+                .any(|id| Fingerprint::of_token(id, self.metadata.as_ref()) == token.fingerprint())
+    }
+
+    /// The sum of the points t of the ids
+    fn t(&self) -> G1Projective {
+        self.ids.iter().fold(G1Projective::identity(), |sum, id| {
+            let t: [u8; 16] = id.into();
+            sum + h_1(t, &self.metadata)
+        })
+    }
+}
+
+/// A bundle of tokens, which may have different metadata, with one signature for each metadata
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AggregateToken<M: AsRef<[u8]>> {
+    groups: Vec<AggregateGroup<M>>,
+    /// The key of the tokens, if they all had the same
+    key_id: Option<KeyId>,
+}
+
+impl<M: AsRef<[u8]> + Clone> AggregateToken<M> {
+    /// Aggregate the tokens, in groups of the same metadata in the order they first appear
+    ///
+    /// The tokens must be signed with the same key for the bundle to verify.
+    pub fn aggregate(tokens: &[PairingSignedToken<M>]) -> Self {
+        let mut groups: Vec<(AggregateGroup<M>, G1Projective)> = Vec::new();
+
+        for token in tokens {
+            let (id, signature, metadata, _key_id) = token.clone().unpack();
+            let w = G1Affine::from(&signature);
+
+            match groups
+                .iter_mut()
+                .find(|(group, _)| group.metadata.as_ref() == metadata.as_ref())
+            {
+                Some((group, sum)) => {
+                    group.ids.push(id);
+                    *sum += w;
+                }
+                None => groups.push((
+                    AggregateGroup {
+                        metadata,
+                        ids: alloc::vec![id],
+                        signature: CurvePoint::from(w),
+                    },
+                    w.into(),
+                )),
+            }
+        }
+
+        let key_id = tokens.first().and_then(SignedToken::key_id);
+        let same_key = tokens.iter().all(|token| token.key_id() == key_id);
+
+        Self {
+            groups: groups
+                .into_iter()
+                .map(|(group, sum)| AggregateGroup {
+                    signature: sum.into(),
+                    ..group
+                })
+                .collect(),
+            key_id: key_id.filter(|_| same_key),
+        }
+    }
+}
+
+impl<M: AsRef<[u8]>> AggregateToken<M> {
+    /// The key of the tokens, if they all had the same
+    pub fn key_id(&self) -> Option<KeyId> {
+        self.key_id
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = &AggregateGroup<M>> + '_ {
+        self.groups.iter()
+    }
+
+    /// The number of tokens in the bundle
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(AggregateGroup::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The fingerprints of the tokens, the same as of the single tokens
+    pub fn fingerprints(&self) -> impl Iterator<Item = Fingerprint> + '_ {
+        self.groups.iter().flat_map(|group| {
+            group
+                .ids
+                .iter()
+                .map(move |id| Fingerprint::of_token(id, group.metadata.as_ref()))
+        })
+    }
+
+    /// Verify all the tokens of the bundle
+    ///
+    /// It checks prod e(w_i, u_i) * e(-sum t, g2) = 1 over the groups i, with one Miller loop for
+    /// all the pairings. A bundle that is empty, has an empty group, has two groups with the same
+    /// metadata or has a token twice does not verify.
+    pub fn verify(&self, key: &PublicKey) -> bool {
+        if self.groups.iter().any(AggregateGroup::is_empty) || self.groups.is_empty() {
+            return false;
+        }
+
+        let metadata = self
+            .groups
+            .iter()
+            .map(|group| group.metadata.as_ref())
+            .collect::<BTreeSet<_>>();
+        let fingerprints = self.fingerprints().collect::<BTreeSet<_>>();
+        if metadata.len() != self.groups.len() || fingerprints.len() != self.len() {
+            return false;
+        }
+
+        let pk = G2Affine::from(key);
+        let t = self
+            .groups
+            .iter()
+            .fold(G1Projective::identity(), |sum, group| sum + group.t());
+
+        let mut terms = self
+            .groups
+            .iter()
+            .map(|group| {
+                let u = G2Affine::from(G2Affine::generator() * h_m(&group.metadata) + pk);
+                (G1Affine::from(&group.signature), G2Prepared::from(u))
+            })
+            .collect::<Vec<_>>();
+        terms.push((-G1Affine::from(t), G2Prepared::from(G2Affine::generator())));
+
+        let terms = terms.iter().map(|(w, u)| (w, u)).collect::<Vec<_>>();
+        multi_miller_loop(&terms).final_exponentiation() == Gt::identity()
+    }
+
+    /// The number of tokens of the bundle, if they all have the metadata and the bundle verifies
+    ///
+    /// This is for a client that shows that it holds n tokens of the same kind, e.g. 20 units of
+    /// a resource. The signature of such a bundle is one point, but the ids of the tokens are
+    /// still needed to hash their points.
+    pub fn verify_units(&self, key: &PublicKey, metadata: impl AsRef<[u8]>) -> Option<usize> {
+        match self.groups.as_slice() {
+            [group] if group.metadata.as_ref() == metadata.as_ref() && self.verify(key) => {
+                Some(group.len())
+            }
+            _ => None,
+        }
+    }
+
+    /// Verify the bundle, if no token has expired at `now`, and mark all its tokens as redeemed
+    ///
+    /// A bundle with a token that already was redeemed is [`RedeemError::DoubleSpend`], the
+    /// other tokens of such a bundle may be marked as redeemed anyway.
+    pub fn redeem<S: RedemptionStore>(
+        &self,
+        key: &PublicKey,
+        store: &mut S,
+        now: u64,
+    ) -> Result<(), RedeemError> {
+        let expiry = self
+            .groups
+            .iter()
+            .map(|group| {
+                expiry::expires_at(group.metadata.as_ref()).filter(|expires_at| now < *expires_at)
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(RedeemError::Expired)?;
+
+        if !self.verify(key) {
+            return Err(RedeemError::Invalid);
+        }
+
+        for (group, expires_at) in self.groups.iter().zip(expiry) {
+            for id in &group.ids {
+                if !store.insert(
+                    Fingerprint::of_token(id, group.metadata.as_ref()),
+                    expires_at,
+                ) {
+                    return Err(RedeemError::DoubleSpend);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> PairingSignedToken<M> {
+    /// Aggregate the tokens into one bundle, see [`AggregateToken::aggregate`]
+    pub fn aggregate(tokens: &[Self]) -> AggregateToken<M> {
+        AggregateToken::aggregate(tokens)
+    }
+}
+
+/// Groups, and tokens in a group, are counted with a big endian u32
+impl<M: AsRef<[u8]> + for<'a> From<&'a [u8]>> WireFormat for AggregateToken<M> {
+    const SUITE: Ciphersuite = Ciphersuite::PairingV1;
+    const TYPE: u8 = 0x05;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.key_id(self.key_id);
+        writer.fixed(count(self.groups.len())?);
+        for group in &self.groups {
+            writer.prefixed(&group.metadata)?;
+            writer.fixed(group.signature.to_compressed());
+            writer.fixed(count(group.ids.len())?);
+            for id in &group.ids {
+                writer.id(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let key_id = reader.key_id()?;
+        let groups = (0..u32::from_be_bytes(reader.fixed()?))
+            .map(|_| {
+                let metadata = M::from(reader.prefixed()?);
+                let signature =
+                    CurvePoint::from_compressed(&reader.fixed()?).ok_or(WireError::InvalidPoint)?;
+                let ids = (0..u32::from_be_bytes(reader.fixed()?))
+                    .map(|_| reader.id())
+                    .collect::<Result<_, _>>()?;
+
+                Ok(AggregateGroup {
+                    metadata,
+                    ids,
+                    signature,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { groups, key_id })
+    }
+}
+
+fn count(len: usize) -> Result<[u8; 4], WireError> {
+    let len: u32 = len.try_into().map_err(|_| WireError::MetadataLength)?;
+    Ok(len.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::keys::PrivateKey;
+    use super::super::tokens::PairingTokenEngine;
+    use crate::expiry::Metadata;
+    use crate::redemption::MemoryRedemptionStore;
+    use crate::{HasKeyId, TokenEngine};
+    use alloc::boxed::Box;
+
+    fn token<M: AsRef<[u8]>>(metadata: M, key: &PrivateKey) -> PairingSignedToken<M> {
+        PairingTokenEngine::sign(
+            PairingTokenEngine::generate(metadata),
+            &PublicKey::from(key),
+            |randomized| PairingTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+    }
+
+    fn bundle(
+        metadata: &[&'static [u8]],
+        key: &PrivateKey,
+    ) -> Vec<PairingSignedToken<&'static [u8]>> {
+        metadata
+            .iter()
+            .map(|metadata| token(*metadata, key))
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let tokens = bundle(
+            &[b"first", b"second", b"first", b"third", b"first"],
+            &secret_key,
+        );
+        let aggregate = AggregateToken::aggregate(&tokens);
+
+        let sizes: Vec<_> = aggregate
+            .groups()
+            .map(|group| (*group.metadata(), group.len()))
+            .collect();
+        assert_eq!(
+            sizes,
+            [(&b"first"[..], 3), (&b"second"[..], 1), (&b"third"[..], 1)]
+        );
+        assert_eq!(aggregate.key_id(), Some(public_key.key_id()));
+        assert!(aggregate.verify(&public_key));
+        assert!(!aggregate.verify(&PublicKey::from(&PrivateKey::new())));
+
+        // the tokens are in the groups, with the fingerprints of the single tokens
+        assert!(tokens
+            .iter()
+            .all(|token| aggregate.groups().any(|group| group.contains(token))));
+        let fingerprints: BTreeSet<_> = aggregate.fingerprints().collect();
+        assert_eq!(
+            fingerprints,
+            tokens.iter().map(Redeemable::fingerprint).collect()
+        );
+
+        // one metadata is the same as a sum of signatures
+        assert!(
+            AggregateToken::aggregate(&bundle(&[b"only", b"only"], &secret_key))
+                .verify(&public_key)
+        );
+        assert!(!AggregateToken::<&[u8]>::aggregate(&[]).verify(&public_key));
+    }
+
+    #[test]
+    fn test_verify_units() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let units = PairingSignedToken::aggregate(&bundle(&[&b"unit"[..]; 20], &secret_key));
+        assert_eq!(units.verify_units(&public_key, b"unit"), Some(20));
+        assert_eq!(units.verify_units(&public_key, b"other"), None);
+        assert_eq!(
+            units.verify_units(&PublicKey::from(&PrivateKey::new()), b"unit"),
+            None
+        );
+
+        // all the tokens must have the metadata
+        let mixed = PairingSignedToken::aggregate(&bundle(&[b"unit", b"other"], &secret_key));
+        assert!(mixed.verify(&public_key));
+        assert_eq!(mixed.verify_units(&public_key, b"unit"), None);
+    }
+
+    #[test]
+    fn fail_aggregate() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        // a token of another key
+        let mut tokens = bundle(&[b"first", b"second"], &secret_key);
+        tokens.push(token(b"second", &PrivateKey::new()));
+        let aggregate = AggregateToken::aggregate(&tokens);
+        assert_eq!(aggregate.key_id(), None);
+        assert!(!aggregate.verify(&public_key));
+
+        // a token twice, whose signature is then doubled
+        let tokens = bundle(&[b"first", b"second"], &secret_key);
+        let twice = [tokens.clone(), tokens[..1].to_vec()].concat();
+        assert!(!AggregateToken::aggregate(&twice).verify(&public_key));
+
+        // the signatures can not be moved between the groups
+        let mut aggregate = AggregateToken::aggregate(&tokens);
+        let (first, second) = aggregate.groups.split_at_mut(1);
+        let (w1, w2) = (
+            G1Affine::from(&first[0].signature),
+            G1Affine::from(&second[0].signature),
+        );
+        first[0].signature = CurvePoint::from(G1Projective::from(w1) + w2);
+        second[0].signature = CurvePoint::from(G1Projective::identity());
+        assert!(!aggregate.verify(&public_key));
+
+        // nor can the metadata of a group be changed
+        let mut aggregate = AggregateToken::aggregate(&tokens);
+        aggregate.groups[1].metadata = b"third";
+        assert!(!aggregate.verify(&public_key));
+    }
+
+    #[test]
+    fn test_redeem() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let mut store = MemoryRedemptionStore::new();
+        let now = 1_600_000_000;
+
+        let tokens: Vec<_> = [(now + 60, &b"article"[..]), (now + 3600, b"video")]
+            .iter()
+            .map(|(expires_at, resource)| token(Metadata::new(*expires_at, resource), &secret_key))
+            .collect();
+        let aggregate = AggregateToken::aggregate(&tokens);
+
+        assert_eq!(
+            aggregate.redeem(&public_key, &mut store, now + 60),
+            Err(RedeemError::Expired)
+        );
+        assert_eq!(aggregate.redeem(&public_key, &mut store, now), Ok(()));
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            aggregate.redeem(&public_key, &mut store, now),
+            Err(RedeemError::DoubleSpend)
+        );
+
+        // a token of the bundle can not be redeemed alone
+        assert_eq!(
+            crate::redemption::redeem(&tokens[1], &public_key, &mut store, now),
+            Err(RedeemError::DoubleSpend)
+        );
+    }
+
+    #[test]
+    fn test_wire() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let tokens: Vec<_> = [&b"first"[..], b"second", b"first"]
+            .iter()
+            .map(|metadata| token(Box::<[u8]>::from(*metadata), &secret_key))
+            .collect();
+        let aggregate = AggregateToken::aggregate(&tokens);
+
+        let bytes = aggregate.to_bytes();
+        // one signature for each metadata
+        let single: usize = tokens.iter().map(|token| token.to_bytes().len()).sum();
+        assert!(bytes.len() < single - 48);
+
+        let decoded = AggregateToken::<Box<[u8]>>::from_bytes(&bytes).unwrap();
+        assert!(decoded.verify(&public_key));
+        assert_eq!(decoded.len(), 3);
+
+        let json = serde_json::to_string(&aggregate).unwrap();
+        let decoded: AggregateToken<Box<[u8]>> = serde_json::from_str(&json).unwrap();
+        assert!(decoded.verify(&public_key));
+
+        assert_eq!(
+            AggregateToken::<Box<[u8]>>::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some(WireError::Truncated)
+        );
+    }
+}
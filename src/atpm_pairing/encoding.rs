@@ -0,0 +1,157 @@
+//! # The serde encoding of the points
+//!
+//! The signatures of the pairing tokens are points in G1. They are serialized as a struct with
+//! the compressed point by default, `{"point": [...]}` in JSON, as in the earlier versions. A
+//! deployment that exchanges tokens with other BLS12-381 libraries picks an encoding that they
+//! read, with [`set_point_encoding`]:
+//!
+//! | encoding                          | serialized as                                        |
+//! |-----------------------------------|------------------------------------------------------|
+//! | [`PointEncoding::Struct`]         | the struct, the default                              |
+//! | [`PointEncoding::Compressed`]     | the 48 bytes of the compressed point                 |
+//! | [`PointEncoding::Uncompressed`]   | the 96 bytes of the uncompressed point               |
+//! | [`PointEncoding::Hex`]            | the compressed point as a hex string                 |
+//! | [`PointEncoding::HumanReadable`]  | hex in human-readable formats, such as JSON, else the compressed bytes |
+//!
+//! The compressed and uncompressed points are those of the ZCash serialization, as in the other
+//! libraries. A human-readable format is deserialized from any of the encodings, so a verifier
+//! reads the tokens of the signers that have not changed the encoding yet.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         encoding::{point_encoding, set_point_encoding, PointEncoding},
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::{PairingSignedToken, PairingTokenEngine},
+//!     };
+//!
+//!     assert_eq!(point_encoding(), PointEncoding::Struct);
+//!     set_point_encoding(PointEncoding::HumanReadable);
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(b"metadata".to_vec()),
+//!         &public_key,
+//!         |randomized| PairingTokenEngine::sign_randomized(randomized, &secret_key),
+//!     ).unwrap();
+//!
+//!     let json = serde_json::to_value(&signed).unwrap();
+//!     assert_eq!(json["signature"].as_str().unwrap().len(), 96);
+//!
+//!     let decoded: PairingSignedToken<Vec<u8>> = serde_json::from_value(json).unwrap();
+//!     assert!(PairingTokenEngine::verify(&decoded, &public_key).is_ok());
+//! ```
+
+use alloc::{string::String, vec::Vec};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// How the points in G1 are serialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PointEncoding {
+    /// A struct with the compressed point, the default
+    #[default]
+    Struct,
+    /// The 48 bytes of the compressed point
+    Compressed,
+    /// The 96 bytes of the uncompressed point
+    Uncompressed,
+    /// The compressed point as a lower-case hex string
+    Hex,
+    /// [`PointEncoding::Hex`] if the format is human-readable, else [`PointEncoding::Compressed`]
+    HumanReadable,
+}
+
+impl PointEncoding {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Compressed,
+            2 => Self::Uncompressed,
+            3 => Self::Hex,
+            4 => Self::HumanReadable,
+            _ => Self::Struct,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Struct => 0,
+            Self::Compressed => 1,
+            Self::Uncompressed => 2,
+            Self::Hex => 3,
+            Self::HumanReadable => 4,
+        }
+    }
+
+    /// The encoding in a format, [`PointEncoding::HumanReadable`] is resolved
+    pub(crate) fn in_format(self, human_readable: bool) -> Self {
+        match self {
+            Self::HumanReadable if human_readable => Self::Hex,
+            Self::HumanReadable => Self::Compressed,
+            encoding => encoding,
+        }
+    }
+}
+
+static POINT_ENCODING: AtomicU8 = AtomicU8::new(0);
+
+/// Serialize the points with the encoding, for the whole program
+///
+/// This is set once at startup, before any point is serialized, as the tokens that are serialized
+/// before are in the earlier encoding.
+pub fn set_point_encoding(encoding: PointEncoding) {
+    POINT_ENCODING.store(encoding.to_u8(), Ordering::Release);
+}
+
+/// The encoding the points are serialized with
+pub fn point_encoding() -> PointEncoding {
+    PointEncoding::from_u8(POINT_ENCODING.load(Ordering::Acquire))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    bytes
+        .iter()
+        .flat_map(|byte| {
+            [
+                DIGITS[usize::from(byte >> 4)] as char,
+                DIGITS[usize::from(byte & 0xf)] as char,
+            ]
+        })
+        .collect()
+}
+
+/// The bytes of a hex string, in upper or lower case
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    fn digit(c: u8) -> Option<u8> {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            b'A'..=b'F' => Some(c - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let pairs = hex.as_bytes().chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+
+    pairs
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0x00, 0x9f, 0xff]), "009fff");
+        assert_eq!(from_hex("009fFF"), Some(alloc::vec![0x00, 0x9f, 0xff]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("0g"), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! # The hash of the metadata
+//!
+//! The metadata of a token is hashed to the scalar d of u = g2 * d + pk. There are two hashes:
+//!
+//! - [`HmUniform`] hashes with SHA-256 until the digest is a scalar, which is uniform, but the
+//!   time depends on the metadata. This is the hash of [`PairingTokenEngine`].
+//! - [`HmReduced`] reduces SHA-512 by the modulus, which is constant time, but the scalars are
+//!   not exactly uniform.
+//!
+//! A token only verifies with the hash it was signed with. The hash was picked by the
+//! `uniform_hm` feature before, so the tokens of two builds did not verify with each other. It
+//! is now a parameter of [`HashedPairingTokenEngine`], and the wire format of a signed token names
+//! it in the [`Ciphersuite`] byte, so a token of the other hash is not decoded as one of this.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         hm::HmReduced,
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::{HashedPairingTokenEngine, PairingSignedToken},
+//!     };
+//!     use atpmd::wire::{WireError, WireFormat};
+//!
+//!     type Engine = HashedPairingTokenEngine<Vec<u8>, HmReduced>;
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let signed = Engine::sign(
+//!         Engine::generate(b"metadata".to_vec()),
+//!         &public_key,
+//!         |randomized| Engine::sign_randomized(randomized, &secret_key),
+//!     ).unwrap();
+//!     assert!(Engine::verify(&signed, &public_key).is_ok());
+//!
+//!     assert_eq!(
+//!         PairingSignedToken::<Vec<u8>>::from_bytes(&signed.to_bytes()).err(),
+//!         Some(WireError::Ciphersuite)
+//!     );
+//! ```
+//!
+//! [`PairingTokenEngine`]: super::tokens::PairingTokenEngine
+//! [`HashedPairingTokenEngine`]: super::tokens::HashedPairingTokenEngine
+
+use bls12_381::Scalar;
+
+use super::util::{h_m_reduce_modulus, h_m_uniform};
+use crate::wire::Ciphersuite;
+
+/// A hash of the metadata to a scalar
+pub trait MetadataHash {
+    /// The ciphersuite of the tokens, see [`trust`](crate::trust)
+    const CIPHERSUITE: &'static str;
+
+    /// The first byte of the wire encoding of the signed tokens
+    const SUITE: Ciphersuite;
+
+    fn h_m(md: &[u8]) -> Scalar;
+}
+
+/// SHA-256 until the digest is a scalar, the default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HmUniform;
+
+impl MetadataHash for HmUniform {
+    const CIPHERSUITE: &'static str = "BLS12-381-SHA256-uniform-hm";
+    const SUITE: Ciphersuite = Ciphersuite::PairingV1;
+
+    fn h_m(md: &[u8]) -> Scalar {
+        h_m_uniform(md)
+    }
+}
+
+/// SHA-512 reduced by the modulus, in constant time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HmReduced;
+
+impl MetadataHash for HmReduced {
+    const CIPHERSUITE: &'static str = "BLS12-381-SHA512-reduced-hm";
+    const SUITE: Ciphersuite = Ciphersuite::PairingReducedV1;
+
+    fn h_m(md: &[u8]) -> Scalar {
+        h_m_reduce_modulus(md)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        assert_eq!(
+            HmUniform::h_m(b"metadata"),
+            super::super::util::h_m(b"metadata")
+        );
+        assert_ne!(HmUniform::h_m(b"metadata"), HmReduced::h_m(b"metadata"));
+        assert_ne!(HmUniform::CIPHERSUITE, HmReduced::CIPHERSUITE);
+    }
+
+    #[test]
+    fn test_ciphersuites() {
+        use sha2::{Digest, Sha256, Sha512};
+
+        // the identifiers name the hash on the wire, so they are pinned to it
+        assert_eq!(HmUniform::CIPHERSUITE, "BLS12-381-SHA256-uniform-hm");
+        assert_eq!(HmReduced::CIPHERSUITE, "BLS12-381-SHA512-reduced-hm");
+
+        // the first metadata whose SHA-256 is a scalar, so that the uniform hash takes one try
+        let (md, digest) = (0u8..)
+            .map(|i| {
+                let digest: [u8; 32] = Sha256::new()
+                    .chain(b"this is h_m_uniform")
+                    .chain([i])
+                    .finalize()
+                    .into();
+                ([i], digest)
+            })
+            .find(|(_, digest)| bool::from(Scalar::from_bytes(digest).is_some()))
+            .unwrap();
+        assert_eq!(HmUniform::h_m(&md), Scalar::from_bytes(&digest).unwrap());
+
+        let mut wide = [0u8; 64];
+        wide.copy_from_slice(
+            &Sha512::new()
+                .chain(b"this is h_m_biased")
+                .chain(md)
+                .finalize(),
+        );
+        assert_eq!(HmReduced::h_m(&md), Scalar::from_bytes_wide(&wide));
+    }
+}
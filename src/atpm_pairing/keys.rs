@@ -1,10 +1,22 @@
 use core::convert::TryInto;
 use core::fmt;
-
-use alloc::{format, vec::Vec};
-
-use super::util::random_vartime;
-use bls12_381::{G2Affine, Scalar};
+use core::str::FromStr;
+
+use alloc::{format, string::String, vec::Vec};
+
+use super::util::{h_derive, random_biased, Bls12G1};
+#[cfg(feature = "private_key_serde")]
+use super::PrivateKeyBytes;
+use super::{HasKeyId, KeyId, Secret};
+use crate::group::sign_point;
+use crate::kdf::hkdf_sha256;
+use crate::pem::{self, PemError};
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
+use crate::KeyFingerprint;
+use bls12_381::{G1Affine, G1Projective, G2Affine, Scalar};
+use rand::{CryptoRng, RngCore};
+use subtle::CtOption;
+use zeroize::Zeroize;
 
 use serde::de::MapAccess;
 use serde::de::{self, Deserialize, Deserializer, Visitor};
@@ -12,17 +24,53 @@ use serde::ser::{Serialize, SerializeStruct, Serializer};
 
 #[derive(Debug, Clone)]
 /// The pivate key for the pairing protocol
+///
+/// The key is zeroized when it is dropped
 pub struct PrivateKey {
-    key: Scalar,
+    key: Secret<Scalar>,
 }
 
 impl PrivateKey {
     /// Generate a new random private key
     pub fn new() -> Self {
+        Self::new_with_rng(&mut crate::rng::default_rng())
+    }
+
+    /// Generate a private key from the given rng, e.g. for reproducible tests
+    pub fn new_with_rng<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        PrivateKey {
+            key: Secret(random_biased(rng)),
+        }
+    }
+
+    /// The private key of a seed, e.g. from a secrets manager, to derive the same key again
+    ///
+    /// The scalar is the HKDF-SHA256 of the seed, reduced from 64 bytes.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let mut okm = [0; 64];
+        hkdf_sha256(seed, b"atpmd pairing private key", &mut okm);
+        let key = Scalar::from_bytes_wide(&okm);
+        okm.zeroize();
+
+        PrivateKey { key: Secret(key) }
+    }
+
+    /// The key for a label, e.g. an origin, derived from this master key
+    ///
+    /// The public key of the derived key is [`PublicKey::derive`] of the master public key, so the
+    /// master private key is the only one to keep. The derived keys are independent for whoever
+    /// does not know the master public key.
+    pub fn derive(&self, label: impl AsRef<[u8]>) -> Self {
+        let tweak = h_derive(PublicKey::from(self).key.to_compressed(), label);
         PrivateKey {
-            key: random_vartime(&mut rand::thread_rng()),
+            key: Secret(self.key.0 + tweak),
         }
     }
+
+    /// The [`PublicKey::fingerprint`] of the public key
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        PublicKey::from(self).fingerprint()
+    }
 }
 
 impl Default for PrivateKey {
@@ -34,11 +82,38 @@ impl Default for PrivateKey {
 impl From<&PrivateKey> for Scalar {
     /// get the scalar from the private key
     fn from(sk: &PrivateKey) -> Self {
-        sk.key
+        sk.key.0
+    }
+}
+
+/// A handle to a private key, that may be kept in a secure element
+///
+/// The holder of the key only needs to do the part of the signing that touches the key, see
+/// [`super::tokens::PairingTokenEngine::complete_signing`].
+pub trait KeyHandle {
+    /// Compute w = (d + k)^{-1} t for the key k, none if d + k is not invertible
+    fn sign_point(&self, d: &Scalar, t: &G1Affine) -> CtOption<G1Affine>;
+}
+
+impl KeyHandle for PrivateKey {
+    fn sign_point(&self, d: &Scalar, t: &G1Affine) -> CtOption<G1Affine> {
+        sign_point::<Bls12G1>(G1Projective::from(t), *d, self.key.0).map(G1Affine::from)
     }
 }
 
-#[derive(Debug)]
+impl Zeroize for PrivateKey {
+    fn zeroize(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+#[derive(Debug, Clone)]
 /// The public key for the pairing protocol
 pub struct PublicKey {
     key: G2Affine,
@@ -47,7 +122,7 @@ pub struct PublicKey {
 impl From<&PrivateKey> for PublicKey {
     fn from(sk: &PrivateKey) -> Self {
         PublicKey {
-            key: (G2Affine::generator() * sk.key).into(),
+            key: (G2Affine::generator() * sk.key.0).into(),
         }
     }
 }
@@ -58,6 +133,21 @@ impl From<PrivateKey> for PublicKey {
     }
 }
 
+impl PublicKey {
+    /// The public key of [`PrivateKey::derive`] with the same label
+    pub fn derive(&self, label: impl AsRef<[u8]>) -> Self {
+        let tweak = h_derive(self.key.to_compressed(), label);
+        PublicKey {
+            key: (self.key + G2Affine::generator() * tweak).into(),
+        }
+    }
+
+    /// The SHA-256 of the wire encoding of the key
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        KeyFingerprint::of_encoding(self.to_bytes())
+    }
+}
+
 impl From<&PublicKey> for G2Affine {
     fn from(pk: &PublicKey) -> Self {
         pk.key
@@ -70,6 +160,151 @@ impl From<G2Affine> for PublicKey {
     }
 }
 
+/// The public key of an arbitrary private key
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PublicKey {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::from(G2Affine::from(
+            G2Affine::generator() * Scalar::from_bytes_wide(&u.arbitrary()?),
+        )))
+    }
+}
+
+impl HasKeyId for PublicKey {
+    fn key_id(&self) -> KeyId {
+        KeyId::of_public_key(self.key.to_compressed())
+    }
+}
+
+impl HasKeyId for PrivateKey {
+    fn key_id(&self) -> KeyId {
+        PublicKey::from(self).key_id()
+    }
+}
+
+impl crate::keypair::Keys for PrivateKey {
+    type Public = PublicKey;
+    type Scalar = Scalar;
+
+    fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::new_with_rng(rng)
+    }
+
+    fn public(&self) -> PublicKey {
+        PublicKey::from(self)
+    }
+
+    fn to_scalar(&self) -> Scalar {
+        self.key.0
+    }
+
+    fn fingerprint(&self) -> KeyFingerprint {
+        PrivateKey::fingerprint(self)
+    }
+}
+
+impl WireFormat for PublicKey {
+    const SUITE: Ciphersuite = Ciphersuite::PairingV1;
+    const TYPE: u8 = 0x04;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.key.to_compressed());
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Option::from(G2Affine::from_compressed(&reader.fixed()?))
+            .map(|key| PublicKey { key })
+            .ok_or(WireError::InvalidPoint)
+    }
+}
+
+impl crate::jwk::JwkPublicKey for PublicKey {
+    const CURVE: &'static str = "BLS12381G2";
+}
+
+// {{{ PEM and base64url
+
+impl PublicKey {
+    /// The label of the PEM of the key
+    pub const PEM_LABEL: &'static str = "ATPMD PAIRING PUBLIC KEY";
+
+    /// The wire format of the key as PEM, e.g. for a configuration file
+    pub fn to_pem(&self) -> String {
+        pem::encode(Self::PEM_LABEL, &self.to_bytes())
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, PemError> {
+        Self::from_bytes(&pem::decode(Self::PEM_LABEL, pem)?).map_err(PemError::Malformed)
+    }
+}
+
+/// The wire format of the key as base64url, e.g. for an HTTP header
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&pem::encode_url(&self.to_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = PemError;
+
+    fn from_str(encoded: &str) -> Result<Self, PemError> {
+        Self::from_bytes(&pem::decode_url(encoded)?).map_err(PemError::Malformed)
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl PrivateKey {
+    /// The label of the PEM of the key
+    pub const PEM_LABEL: &'static str = "ATPMD PAIRING PRIVATE KEY";
+
+    /// The scalar of the key as PEM, the caller has to keep it secret
+    pub fn to_pem(&self) -> String {
+        let mut bytes = self.key.0.to_bytes();
+        let pem = pem::encode(Self::PEM_LABEL, &bytes);
+        bytes.zeroize();
+        pem
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, PemError> {
+        Self::from_scalar_bytes(pem::scalar_bytes(pem::decode(Self::PEM_LABEL, pem)?)?)
+    }
+
+    fn from_scalar_bytes(mut bytes: [u8; 32]) -> Result<Self, PemError> {
+        let key = Scalar::from_bytes(&bytes);
+        bytes.zeroize();
+
+        Option::from(key)
+            .map(|key| PrivateKey { key: Secret(key) })
+            .ok_or(PemError::Malformed(WireError::InvalidPoint))
+    }
+}
+
+/// The scalar of the key as base64url, which is as secret as the key, so it is not to be logged
+#[cfg(feature = "private_key_serde")]
+impl fmt::Display for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = self.key.0.to_bytes();
+        let mut encoded = pem::encode_url(&bytes);
+        let result = f.write_str(&encoded);
+        bytes.zeroize();
+        encoded.zeroize();
+        result
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl FromStr for PrivateKey {
+    type Err = PemError;
+
+    fn from_str(encoded: &str) -> Result<Self, PemError> {
+        Self::from_scalar_bytes(pem::scalar_bytes(pem::decode_url(encoded)?)?)
+    }
+}
+
+// }}}
+
 // {{{ serialization
 
 impl Serialize for PublicKey {
@@ -128,13 +363,8 @@ impl<'de> Deserialize<'de> for PublicKey {
                     )
                 })?;
 
-                let maybe_point = G2Affine::from_compressed(&key_bytes);
-
-                let key_point = if bool::from(maybe_point.is_some()) {
-                    Ok(maybe_point.unwrap())
-                } else {
-                    Err(de::Error::custom("Failed to decompress key"))
-                }?;
+                let key_point: G2Affine = Option::from(G2Affine::from_compressed(key_bytes))
+                    .ok_or_else(|| de::Error::custom("Failed to decompress key"))?;
 
                 Ok(PublicKey::from(key_point))
             }
@@ -145,6 +375,39 @@ impl<'de> Deserialize<'de> for PublicKey {
     }
 }
 
+#[cfg(feature = "private_key_serde")]
+impl Serialize for PrivateKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        PrivateKeyBytes {
+            key: self.key.0.to_bytes().to_vec(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl<'de> Deserialize<'de> for PrivateKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let bytes = PrivateKeyBytes::deserialize(deserializer)?;
+        let key = Scalar::from_bytes(
+            bytes
+                .bytes(32)?
+                .try_into()
+                .map_err(|_e| de::Error::invalid_length(32, &"the length of a scalar"))?,
+        );
+
+        Option::from(key)
+            .map(|key| PrivateKey { key: Secret(key) })
+            .ok_or_else(|| de::Error::custom("Scalar is not canonical"))
+    }
+}
+
 // }}}
 
 #[cfg(test)]
@@ -163,6 +426,99 @@ mod tests {
         assert!(pb == (G2Affine::generator() * sec).into());
     }
 
+    #[test]
+    fn test_key_id() {
+        let sk = PrivateKey::new();
+        let pk = PublicKey::from(&sk);
+
+        assert_eq!(sk.key_id(), pk.key_id());
+        assert_eq!(
+            PublicKey::from_bytes(&pk.to_bytes()).unwrap().key_id(),
+            pk.key_id()
+        );
+        assert_ne!(PublicKey::from(PrivateKey::new()).key_id(), pk.key_id());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        use sha2::{Digest, Sha256};
+
+        let sk = PrivateKey::new();
+        let pk = PublicKey::from(&sk);
+
+        assert_eq!(sk.fingerprint(), pk.fingerprint());
+        assert_eq!(
+            pk.fingerprint().to_bytes(),
+            <[u8; 32]>::from(Sha256::digest(&pk.to_bytes()))
+        );
+        assert_ne!(pk.derive(b"example.com").fingerprint(), pk.fingerprint());
+        assert_eq!(format!("{}", pk.fingerprint()).len(), 64);
+    }
+
+    #[test]
+    fn test_pem() {
+        use alloc::string::ToString;
+
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let pem = public_key.to_pem();
+        assert_eq!(
+            PublicKey::from_pem(&pem).unwrap().fingerprint(),
+            public_key.fingerprint()
+        );
+        let parsed: PublicKey = public_key.to_string().parse().unwrap();
+        assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+
+        assert_eq!(
+            PublicKey::from_pem(&pem.replace("PUBLIC", "PRIVATE")).err(),
+            Some(PemError::Label)
+        );
+        assert_eq!(
+            "AAAA".parse::<PublicKey>().err(),
+            Some(PemError::Malformed(WireError::Ciphersuite))
+        );
+
+        #[cfg(feature = "private_key_serde")]
+        {
+            let parsed = PrivateKey::from_pem(&private_key.to_pem()).unwrap();
+            assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+            let parsed: PrivateKey = private_key.to_string().parse().unwrap();
+            assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+
+            assert_eq!(
+                PrivateKey::from_str(&pem::encode_url(&[0xff; 32])).err(),
+                Some(PemError::Malformed(WireError::InvalidPoint))
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_seed() {
+        let seed = [7; 32];
+
+        // the same seed is the same key
+        assert_eq!(
+            PrivateKey::from_seed(&seed).fingerprint(),
+            PrivateKey::from_seed(&seed).fingerprint()
+        );
+        assert_ne!(
+            PrivateKey::from_seed(&seed).fingerprint(),
+            PrivateKey::from_seed(&[8; 32]).fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_derive() {
+        let sk = PrivateKey::new();
+        let pk = PublicKey::from(&sk);
+
+        let derived = PublicKey::from(sk.derive(b"example.com"));
+        assert!(derived.key == pk.derive(b"example.com").key);
+        assert_ne!(derived.key_id(), pk.key_id());
+        assert_ne!(derived.key_id(), pk.derive(b"example.org").key_id());
+    }
+
     #[test]
     fn test_serde() {
         let sk = PrivateKey::default();
@@ -175,6 +531,32 @@ mod tests {
         assert!(deserialized.key == pk.key);
     }
 
+    #[test]
+    fn test_zeroize() {
+        let mut sk = PrivateKey::new();
+        sk.zeroize();
+
+        assert!(Scalar::from(&sk) == Scalar::zero());
+    }
+
+    #[cfg(feature = "private_key_serde")]
+    #[test]
+    fn test_private_key_serde() {
+        let sk = PrivateKey::new();
+
+        let serialized = serde_json::to_string(&sk).unwrap();
+        let deserialized: PrivateKey = serde_json::from_str(&serialized).unwrap();
+        assert!(Scalar::from(&deserialized) == Scalar::from(&sk));
+
+        // not canonical
+        let deserialized: Result<PrivateKey, _> =
+            serde_json::from_str(&format!("{{\"key\":{:?}}}", [255u8; 32]));
+        assert!(deserialized.is_err());
+
+        let deserialized: Result<PrivateKey, _> = serde_json::from_str(r#"{"key":[1,2,3]}"#);
+        assert!(deserialized.is_err());
+    }
+
     #[test]
     fn test_serde_fail() {
         let deserialized: Result<PublicKey, serde_json::Error> = serde_json::from_str(
@@ -7,7 +7,7 @@
 //!
 //! ```
 //!     // Use the trait to get access to the methods
-//!     use atpmd::TokenEngine;
+//!     use atpmd::{SignedToken, TokenEngine};
 //!     // The actual structs
 //!     use atpmd::atpm_pairing::{
 //!         keys::{PrivateKey, PublicKey},
@@ -35,13 +35,32 @@
 //!     ).unwrap();
 //!
 //!     // The verifier may verify that the token is signed
-//!     let is_properly_signed = PairingTokenEngine::verify(&signed, &public_key);
+//!     let is_properly_signed = PairingTokenEngine::verify(&signed, &public_key).is_ok();
 //!     assert!(is_properly_signed);
+//!
+//!     // The verifier sees the hidden metadata, and may check that it is the expected
+//!     assert_eq!(signed.hidden_metadata(), Some(&hidden_metadata[..]));
+//!     assert!(signed.verify_with_hidden(&public_key, hidden_metadata));
 //! ```
 
 pub(crate) use super::common::*;
 
+/// The curve, the hash and the hash to the metadata scalar of the engine, see
+/// [`trust`](crate::trust)
+///
+/// The tokens of the two hashes of the metadata do not verify with each other, see [`hm`].
+pub const CIPHERSUITE: &str = <hm::HmUniform as hm::MetadataHash>::CIPHERSUITE;
+
 mod util;
+pub mod aggregate;
+pub mod encoding;
+pub mod hm;
 pub mod keys;
+pub mod prepared;
+pub mod presentation;
+pub mod refusal;
+pub mod signer;
+pub mod threshold;
 pub mod tokens;
 pub mod tokens_batched; 
+pub mod tokens_batched_dyn;
@@ -0,0 +1,195 @@
+//! # Prepared public keys
+//!
+//! Verifying a token computes u = g2 * h_m(metadata) + pk, and prepares the G2 points of both
+//! pairings for the Miller loop. A verifier of many tokens with the same key does that work once
+//! with a [`PreparedPublicKey`], and once for each metadata with a [`PreparedVerifier`]. It also
+//! verifies batches of tokens like [`verify_batch`](super::tokens::verify_batch).
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         prepared::PreparedPublicKey,
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(&b"resource"[..]),
+//!         &public_key,
+//!         |randomized| PairingTokenEngine::sign_randomized(randomized, &secret_key),
+//!     )
+//!     .unwrap();
+//!
+//!     // once, when the verifier starts
+//!     let prepared = PreparedPublicKey::from(&public_key);
+//!     let verifier = prepared.verifier(b"resource");
+//!
+//!     // for every token
+//!     assert!(prepared.verify(&signed));
+//!     assert!(verifier.verify(&signed));
+//! ```
+
+use bls12_381::{multi_miller_loop, G1Affine, G2Affine, G2Prepared, Gt};
+
+use alloc::vec::Vec;
+
+use super::hm::MetadataHash;
+use super::keys::PublicKey;
+use super::tokens::{combination, PairingSignedToken};
+use super::util::h_m;
+
+/// A public key with its point, and the generator, prepared for the Miller loop
+#[derive(Debug, Clone)]
+pub struct PreparedPublicKey {
+    key: PublicKey,
+    key_prepared: G2Prepared,
+    generator: G2Prepared,
+}
+
+impl From<&PublicKey> for PreparedPublicKey {
+    fn from(key: &PublicKey) -> Self {
+        Self {
+            key: key.clone(),
+            key_prepared: G2Affine::from(key).into(),
+            generator: G2Affine::generator().into(),
+        }
+    }
+}
+
+impl PreparedPublicKey {
+    pub fn key(&self) -> &PublicKey {
+        &self.key
+    }
+
+    /// Verify a token with any metadata, without a G2 multiplication
+    ///
+    /// e(w, g2 * d + pk) = e(t, g2) is e(d * w - t, g2) * e(w, pk) = 1, so the metadata only
+    /// multiplies the signature in G1.
+    pub fn verify<M: AsRef<[u8]>, H: MetadataHash>(
+        &self,
+        token: &PairingSignedToken<M, H>,
+    ) -> bool {
+        let (w, t, d) = token.points();
+        let wd = G1Affine::from(w * d - t);
+
+        multi_miller_loop(&[(&wd, &self.generator), (&w, &self.key_prepared)])
+            .final_exponentiation()
+            == Gt::identity()
+    }
+
+    /// Verify many tokens, which may have different metadata, with one Miller loop
+    ///
+    /// See [`verify_batch`](super::tokens::verify_batch).
+    pub fn verify_batch<M: AsRef<[u8]>, H: MetadataHash>(
+        &self,
+        tokens: &[PairingSignedToken<M, H>],
+    ) -> bool {
+        let (w, t) = combination(tokens);
+
+        multi_miller_loop(&[(&w, &self.key_prepared), (&-t, &self.generator)])
+            .final_exponentiation()
+            == Gt::identity()
+    }
+
+    /// A verifier of the tokens with the metadata
+    pub fn verifier(&self, metadata: impl AsRef<[u8]>) -> PreparedVerifier {
+        let metadata = metadata.as_ref();
+        let u = G2Affine::from(G2Affine::generator() * h_m(metadata) + G2Affine::from(&self.key));
+
+        PreparedVerifier {
+            metadata: metadata.to_vec(),
+            u: u.into(),
+            generator: self.generator.clone(),
+        }
+    }
+}
+
+/// A verifier of the tokens with one metadata, with u = g2 * h_m(metadata) + pk prepared
+#[derive(Debug, Clone)]
+pub struct PreparedVerifier {
+    metadata: Vec<u8>,
+    u: G2Prepared,
+    generator: G2Prepared,
+}
+
+impl PreparedVerifier {
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+
+    /// Verify a token, which fails for tokens with other metadata
+    pub fn verify<M: AsRef<[u8]>>(&self, token: &PairingSignedToken<M>) -> bool {
+        if token.metadata().as_ref() != &self.metadata[..] {
+            return false;
+        }
+
+        let (w, t, _d) = token.points();
+
+        multi_miller_loop(&[(&w, &self.u), (&-t, &self.generator)]).final_exponentiation()
+            == Gt::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::keys::PrivateKey;
+    use super::super::tokens::PairingTokenEngine;
+    use crate::{SignedToken, TokenEngine};
+
+    fn token(metadata: &'static [u8], key: &PrivateKey) -> PairingSignedToken<&'static [u8]> {
+        PairingTokenEngine::sign(
+            PairingTokenEngine::generate(metadata),
+            &PublicKey::from(key),
+            |randomized| PairingTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_prepared() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let prepared = PreparedPublicKey::from(&public_key);
+        let verifier = prepared.verifier(b"first");
+
+        for metadata in &[&b"first"[..], b"second", b""] {
+            let token = token(metadata, &secret_key);
+            assert!(token.verify(&public_key));
+            assert!(prepared.verify(&token));
+        }
+
+        assert!(verifier.verify(&token(b"first", &secret_key)));
+        assert!(!verifier.verify(&token(b"second", &secret_key)));
+
+        let mut tokens: Vec<_> = [&b"first"[..], b"second", b"first"]
+            .iter()
+            .map(|metadata| token(metadata, &secret_key))
+            .collect();
+        assert!(prepared.verify_batch(&tokens));
+
+        tokens.push(token(b"third", &PrivateKey::new()));
+        assert!(!prepared.verify_batch(&tokens));
+    }
+
+    #[test]
+    fn fail_prepared() {
+        let secret_key = PrivateKey::new();
+        let other = PreparedPublicKey::from(&PublicKey::from(&PrivateKey::new()));
+        let token = token(b"first", &secret_key);
+
+        assert!(!other.verify(&token));
+        assert!(!other.verifier(b"first").verify(&token));
+
+        // a token with its metadata changed does not verify
+        let (id, signature, _metadata, key_id) = token.unpack();
+        let forged = PairingSignedToken::create(id, signature, &b"second"[..], key_id);
+        let prepared = PreparedPublicKey::from(&PublicKey::from(&secret_key));
+        assert!(!prepared.verify(&forged));
+        assert!(!prepared.verifier(b"second").verify(&forged));
+    }
+}
@@ -0,0 +1,242 @@
+//! # Presentations of pairing tokens
+//!
+//! The client shows a multiple of its signature, w' = p * w, for the point t' = p * t, with a
+//! proof of knowledge of p bound to the nonce of the verifier, see [`crate::presentation`].
+//! e(w', u) = e(t', g2) holds if and only if w is the signature of t, and only the client knows p,
+//! so none who sees the presentation may prove it for another nonce.
+//!
+//! The proof is a Schnorr proof of t' = p * t: the client picks a random a, and with the challenge
+//! c = H(nonce, t, t', w', a * t), sends z = a + c * p.
+
+use bls12_381::{G1Affine, G1Projective, G2Affine, Scalar};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+
+use super::keys::PublicKey;
+use super::tokens::PairingSignedToken;
+use super::util::{h_1, h_m, pairing_check, random_biased, CurvePoint};
+use super::{KeyId, TokenIdentifier};
+use crate::presentation::Presentable;
+use crate::redemption::Fingerprint;
+
+/// A pairing token presented for a nonce, see [`crate::presentation`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PairingPresentation<M: AsRef<[u8]>> {
+    id: TokenIdentifier<M>,
+    metadata: M,
+    key_id: Option<KeyId>,
+    /// w' = p * w
+    signature: CurvePoint,
+    /// t' = p * t
+    point: CurvePoint,
+    challenge: [u8; 32],
+    response: [u8; 32],
+}
+
+impl<M: AsRef<[u8]>> PairingPresentation<M> {
+    pub fn id(&self) -> &TokenIdentifier<M> {
+        &self.id
+    }
+
+    pub fn metadata(&self) -> &M {
+        &self.metadata
+    }
+
+    pub fn key_id(&self) -> Option<KeyId> {
+        self.key_id
+    }
+
+    /// The fingerprint of the token, to redeem it once
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_token(&self.id, self.metadata.as_ref())
+    }
+
+    fn t(&self) -> G1Affine {
+        let t: [u8; 16] = (&self.id).into();
+        h_1(t, &self.metadata)
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> Presentable for PairingSignedToken<M> {
+    type Presentation = PairingPresentation<M>;
+
+    fn present_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        nonce: &[u8],
+        rng: &mut R,
+    ) -> PairingPresentation<M> {
+        let (w, t, _d) = self.points();
+
+        // zero with negligible probability
+        let p = random_biased(rng);
+        let (signature, point) = (G1Affine::from(w * p), G1Affine::from(t * p));
+
+        let a = random_biased(rng);
+        let commitment = G1Affine::from(t * a);
+        let c = challenge(nonce, &t, &point, &signature, &commitment);
+
+        let (id, _signature, metadata, key_id) = self.clone().unpack();
+
+        PairingPresentation {
+            id,
+            metadata,
+            key_id,
+            signature: signature.into(),
+            point: point.into(),
+            challenge: c.to_bytes(),
+            response: (a + c * p).to_bytes(),
+        }
+    }
+
+    fn verify_presentation(
+        presentation: &PairingPresentation<M>,
+        verification_key: &PublicKey,
+        nonce: &[u8],
+    ) -> bool {
+        let scalars = (
+            Option::<Scalar>::from(Scalar::from_bytes(&presentation.challenge)),
+            Option::<Scalar>::from(Scalar::from_bytes(&presentation.response)),
+        );
+        let (c, z) = match scalars {
+            (Some(c), Some(z)) => (c, z),
+            _ => return false,
+        };
+
+        let t = presentation.t();
+        let signature = G1Affine::from(&presentation.signature);
+        let point = G1Affine::from(&presentation.point);
+
+        // p = 0 would make any w' verify
+        if bool::from(point.is_identity()) {
+            return false;
+        }
+
+        let commitment = G1Affine::from(t * z - G1Projective::from(point) * c);
+        if challenge(nonce, &t, &point, &signature, &commitment) != c {
+            return false;
+        }
+
+        let u =
+            G2Affine::generator() * h_m(&presentation.metadata) + G2Affine::from(verification_key);
+        pairing_check(signature, u, point)
+    }
+}
+
+/// The challenge of the proof, from the nonce, the points and the commitment
+fn challenge(
+    nonce: &[u8],
+    t: &G1Affine,
+    point: &G1Affine,
+    signature: &G1Affine,
+    commitment: &G1Affine,
+) -> Scalar {
+    let mut hasher = Sha512::new();
+    hasher.update(b"This is presentation challenge hash");
+    // the length separates the nonce from the points
+    hasher.update((nonce.len() as u64).to_be_bytes());
+    hasher.update(nonce);
+    for point in &[t, point, signature, commitment] {
+        hasher.update(point.to_compressed());
+    }
+
+    let mut bytes = [0u8; 64];
+    bytes.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_wide(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::keys::PrivateKey;
+    use super::super::tokens::PairingTokenEngine;
+    use crate::presentation::{present, verify_presentation};
+    use crate::TokenEngine;
+    use alloc::boxed::Box;
+
+    type Token = PairingSignedToken<&'static [u8]>;
+
+    fn token(key: &PrivateKey) -> Token {
+        PairingTokenEngine::sign(
+            PairingTokenEngine::generate(&b"resource"[..]),
+            &PublicKey::from(key),
+            |randomized| PairingTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_presentation() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let signed = token(&secret_key);
+
+        let presentation = present(&signed, b"nonce");
+        assert!(verify_presentation::<Token>(
+            &presentation,
+            &public_key,
+            b"nonce"
+        ));
+        assert!(!verify_presentation::<Token>(
+            &presentation,
+            &public_key,
+            b"other"
+        ));
+        assert!(!verify_presentation::<Token>(
+            &presentation,
+            &PublicKey::from(&PrivateKey::new()),
+            b"nonce"
+        ));
+        assert_eq!(
+            presentation.fingerprint(),
+            crate::redemption::Redeemable::fingerprint(&signed)
+        );
+
+        // the signature is not in the presentation, and every presentation is different
+        let again = present(&signed, b"nonce");
+        assert!(verify_presentation::<Token>(&again, &public_key, b"nonce"));
+        assert!(presentation.signature != again.signature);
+        assert!(presentation.signature != CurvePoint::from(signed.points().0));
+
+        let serialized = serde_json::to_string(&presentation).unwrap();
+        let deserialized: PairingPresentation<Box<[u8]>> =
+            serde_json::from_str(&serialized).unwrap();
+        assert!(verify_presentation::<PairingSignedToken<Box<[u8]>>>(
+            &deserialized,
+            &public_key,
+            b"nonce"
+        ));
+    }
+
+    #[test]
+    fn fail_presentation() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let presentation = present(&token(&secret_key), b"nonce");
+
+        // a token of another key
+        let other = present(&token(&PrivateKey::new()), b"nonce");
+        assert!(!verify_presentation::<Token>(&other, &public_key, b"nonce"));
+
+        // the points of the presentation for another id
+        let mut moved = present(&token(&secret_key), b"nonce");
+        moved.signature = presentation.signature.clone();
+        moved.point = presentation.point.clone();
+        assert!(!verify_presentation::<Token>(&moved, &public_key, b"nonce"));
+
+        // p = 0
+        let mut zero = presentation.clone();
+        zero.signature = CurvePoint::from(G1Projective::identity());
+        zero.point = CurvePoint::from(G1Projective::identity());
+        assert!(!verify_presentation::<Token>(&zero, &public_key, b"nonce"));
+
+        let mut changed = presentation;
+        changed.metadata = b"other resource";
+        assert!(!verify_presentation::<Token>(
+            &changed,
+            &public_key,
+            b"nonce"
+        ));
+    }
+}
@@ -0,0 +1,122 @@
+//! # Signed refusals
+//!
+//! A refusal is signed like a token, w = (d + k)^{-1} t for the hash t of the refusal, but with a
+//! key derived from the private key of the issuer, see [`PrivateKey::derive`]. Otherwise a client
+//! could have a refusal signed as a token.
+//!
+//! ```
+//!     use atpmd::Error;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         refusal::PairingSignedRefusal,
+//!         tokens::RandomizedSignedToken,
+//!     };
+//!     use atpmd::refusal::{Refusal, RefusalReason, SignResponse};
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let now = 1_600_000_000;
+//!
+//!     // the issuer is overloaded
+//!     let refusal = Refusal::new(RefusalReason::Overloaded, Some(30), now);
+//!     let response: SignResponse<RandomizedSignedToken<&[u8]>, _> =
+//!         SignResponse::Refused(PairingSignedRefusal::sign(refusal, b"resource", &secret_key));
+//!
+//!     // and the client knows when to ask again
+//!     assert_eq!(
+//!         response.into_result(b"resource", &public_key, now).err(),
+//!         Some(Error::Refused(refusal))
+//!     );
+//! ```
+
+use bls12_381::{G1Affine, G2Affine};
+
+use super::keys::{KeyHandle, PrivateKey, PublicKey};
+use super::util::{h_1, h_m, pairing_check, CurvePoint};
+use crate::refusal::{Refusal, SignedRefusal};
+
+/// The label of the key that signs refusals
+const REFUSAL_KEY: &[u8] = b"refusal";
+
+/// A refusal with the signature of the issuer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PairingSignedRefusal {
+    refusal: Refusal,
+    signature: CurvePoint,
+}
+
+impl PairingSignedRefusal {
+    /// Sign a refusal of a request for the metadata
+    pub fn sign(refusal: Refusal, metadata: impl AsRef<[u8]>, private_key: &PrivateKey) -> Self {
+        let message = refusal.message(metadata.as_ref());
+        let signature = private_key
+            .derive(REFUSAL_KEY)
+            .sign_point(&h_m(&message), &h_1(&message, b""))
+            // d + k is zero with a negligible probability, there is no refusal for that message
+            .unwrap_or_else(G1Affine::identity);
+
+        Self {
+            refusal,
+            signature: signature.into(),
+        }
+    }
+
+    /// The refusal, without checking the signature
+    pub fn refusal(&self) -> &Refusal {
+        &self.refusal
+    }
+}
+
+impl SignedRefusal for PairingSignedRefusal {
+    type PublicKey = PublicKey;
+
+    fn verify(&self, metadata: &[u8], public_key: &PublicKey) -> Option<Refusal> {
+        let message = self.refusal.message(metadata);
+        let key = G2Affine::from(&public_key.derive(REFUSAL_KEY));
+        let u = G2Affine::generator() * h_m(&message) + key;
+
+        let signature = G1Affine::from(&self.signature);
+        let valid =
+            !bool::from(signature.is_identity()) && pairing_check(signature, u, h_1(&message, b""));
+
+        if valid {
+            Some(self.refusal)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::refusal::RefusalReason;
+
+    #[test]
+    fn test_refusal() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let refusal = Refusal::new(RefusalReason::RateLimited, Some(60), 1000);
+
+        let signed = PairingSignedRefusal::sign(refusal, b"a", &private_key);
+        assert_eq!(signed.verify(b"a", &public_key), Some(refusal));
+        assert_eq!(signed.verify(b"b", &public_key), None);
+        assert_eq!(
+            signed.verify(b"a", &PublicKey::from(PrivateKey::new())),
+            None
+        );
+
+        // deterministic
+        let again = PairingSignedRefusal::sign(refusal, b"a", &private_key);
+        assert_eq!(
+            G1Affine::from(&again.signature),
+            G1Affine::from(&signed.signature)
+        );
+
+        // the refusal is signed
+        let mut changed = signed;
+        changed.refusal.retry_after = Some(1);
+        assert_eq!(changed.verify(b"a", &public_key), None);
+    }
+}
@@ -0,0 +1,210 @@
+//! # Signer contexts
+//!
+//! Signing a token computes the inverse (d + k)^{-1} of the hash d of the metadata and the key k.
+//! An issuer signing many tokens with the same few metadata values does that inversion once for
+//! each metadata with a [`SignerContext`], which is a [`KeyHandle`] of the key.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         signer::SignerContext,
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     // once, when the issuer starts
+//!     let context = SignerContext::with_metadata(&secret_key, [&b"resource"[..]]).unwrap();
+//!
+//!     // for every token
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(&b"resource"[..]),
+//!         &public_key,
+//!         |randomized| context.sign_randomized(randomized),
+//!     )
+//!     .unwrap();
+//!     assert!(PairingTokenEngine::verify(&signed, &public_key).is_ok());
+//! ```
+
+use bls12_381::{G1Affine, Scalar};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq, CtOption};
+use zeroize::Zeroize;
+
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+use super::hm::{HmUniform, MetadataHash};
+use super::keys::{KeyHandle, PrivateKey};
+use super::tokens::{HashedPairingTokenEngine, RandomizedSignedToken, RandomizedUnsignedToken};
+use super::{invertible, Error, Secret};
+use crate::group::sign_point;
+
+use super::util::Bls12G1;
+
+/// A private key with the inverses of the metadata it signs, hashing the metadata with `H`
+///
+/// The key and the inverses are zeroized when this is dropped.
+pub struct HashedSignerContext<H> {
+    key: Secret<Scalar>,
+    scalars: Vec<Scalar>,
+    inverses: Vec<Secret<Scalar>>,
+    _h: PhantomData<H>,
+}
+
+/// A signer context of the tokens of [`PairingTokenEngine`](super::tokens::PairingTokenEngine)
+pub type SignerContext = HashedSignerContext<HmUniform>;
+
+impl<H: MetadataHash> HashedSignerContext<H> {
+    /// A context without any inverses, see [`HashedSignerContext::insert`]
+    pub fn new(key: &PrivateKey) -> Self {
+        Self {
+            key: Secret(Scalar::from(key)),
+            scalars: Vec::new(),
+            inverses: Vec::new(),
+            _h: PhantomData {},
+        }
+    }
+
+    /// A context with the inverses of the metadata
+    pub fn with_metadata<T: AsRef<[u8]>>(
+        key: &PrivateKey,
+        metadata: impl IntoIterator<Item = T>,
+    ) -> Result<Self, Error> {
+        let mut context = Self::new(key);
+        for metadata in metadata {
+            context.insert(metadata)?;
+        }
+        Ok(context)
+    }
+
+    /// Compute the inverse of the metadata, if it is not there
+    pub fn insert(&mut self, metadata: impl AsRef<[u8]>) -> Result<(), Error> {
+        let d = H::h_m(metadata.as_ref());
+        if bool::from(self.inverse(&d).is_some()) {
+            return Ok(());
+        }
+
+        let inverse = invertible((d + self.key.0).invert())?;
+        self.scalars.push(d);
+        self.inverses.push(Secret(inverse));
+        Ok(())
+    }
+
+    /// The number of metadata values with an inverse
+    pub fn len(&self) -> usize {
+        self.scalars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scalars.is_empty()
+    }
+
+    /// Sign a randomized token, with the inverse of its metadata if there is one
+    pub fn sign_randomized<M: AsRef<[u8]>>(
+        &self,
+        t_prime: &RandomizedUnsignedToken<M>,
+    ) -> Result<RandomizedSignedToken<M>, Error> {
+        invertible(HashedPairingTokenEngine::<M, H>::complete_signing(
+            HashedPairingTokenEngine::<M, H>::prepare_signing(t_prime),
+            self,
+        ))
+    }
+
+    /// The inverse of d + k, if it is in the context
+    ///
+    /// Every inverse is read, so the time does not tell which one it is.
+    fn inverse(&self, d: &Scalar) -> CtOption<Scalar> {
+        let mut found = Choice::from(0);
+        let mut inverse = Scalar::zero();
+
+        for (scalar, candidate) in self.scalars.iter().zip(&self.inverses) {
+            let equal = scalar.ct_eq(d);
+            inverse.conditional_assign(&candidate.0, equal);
+            found |= equal;
+        }
+
+        CtOption::new(inverse, found)
+    }
+}
+
+impl<H: MetadataHash> KeyHandle for HashedSignerContext<H> {
+    fn sign_point(&self, d: &Scalar, t: &G1Affine) -> CtOption<G1Affine> {
+        let inverse = self.inverse(d);
+
+        // the metadata is public, so it does not matter that another metadata takes longer
+        if bool::from(inverse.is_some()) {
+            inverse.map(|e| G1Affine::from(t * e))
+        } else {
+            sign_point::<Bls12G1>(t.into(), *d, self.key.0).map(G1Affine::from)
+        }
+    }
+}
+
+impl<H> Drop for HashedSignerContext<H> {
+    fn drop(&mut self) {
+        self.key.zeroize();
+        self.inverses.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::hm::HmReduced;
+    use super::super::keys::PublicKey;
+    use super::super::tokens::PairingTokenEngine;
+    use crate::TokenEngine;
+
+    #[test]
+    fn test_signer_context() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let mut context =
+            SignerContext::with_metadata(&secret_key, [&b"first"[..], b"second"]).unwrap();
+        assert_eq!(context.len(), 2);
+
+        context.insert(b"first").unwrap();
+        assert_eq!(context.len(), 2);
+
+        // with an inverse, and without one
+        for metadata in &[&b"first"[..], b"second", b"third"] {
+            let signed = PairingTokenEngine::sign(
+                PairingTokenEngine::generate(*metadata),
+                &public_key,
+                |randomized| context.sign_randomized(randomized),
+            )
+            .unwrap();
+            assert!(PairingTokenEngine::verify(&signed, &public_key).is_ok());
+        }
+
+        // the other hash
+        type Reduced = HashedPairingTokenEngine<&'static [u8], HmReduced>;
+        let context =
+            HashedSignerContext::<HmReduced>::with_metadata(&secret_key, [&b"first"[..]]).unwrap();
+        let signed = Reduced::sign(
+            Reduced::generate(&b"first"[..]),
+            &public_key,
+            |randomized| context.sign_randomized(randomized),
+        )
+        .unwrap();
+        assert!(Reduced::verify(&signed, &public_key).is_ok());
+    }
+
+    #[test]
+    fn fail_signer_context() {
+        let secret_key = PrivateKey::new();
+        let context = SignerContext::with_metadata(&secret_key, [&b"first"[..]]).unwrap();
+
+        // the inverse of another key does not verify
+        let other = PublicKey::from(&PrivateKey::new());
+        let result = PairingTokenEngine::sign(
+            PairingTokenEngine::generate(&b"first"[..]),
+            &other,
+            |randomized| context.sign_randomized(randomized),
+        );
+        assert_eq!(result.err(), Some(Error::BadSignature));
+    }
+}
@@ -0,0 +1,534 @@
+//! # Threshold signing
+//!
+//! The private key may be split into shares, such that the issuer is not compromised by the
+//! compromise of one machine. Any `threshold - 1` of the share holders learn nothing about the key.
+//!
+//! The signature w = (d + k)^{-1} t is not linear in the key, so the share holders can not sign
+//! on their own and have the signatures interpolated. Instead they make a random nonce ρ together,
+//! and open u = (d + k)ρ, which hides the key as ρ is random, and the point ρt, interpolated in
+//! the exponent. Then w = u^{-1} ρt.
+//! As (d + k)ρ is the product of two shared values, a signing needs `2 * threshold - 1` of the
+//! share holders, see [`signers_needed`]. The products are masked with a sharing of zero, such
+//! that the coordinator learns u but not the product of each share holder.
+//!
+//! A signing takes two rounds between the share holders of the signing:
+//!
+//! 1. every share holder deals a fresh nonce to all of them, with [`KeyShare::deal_nonce`]
+//! 2. every share holder signs with the nonce shares it got, with [`KeyShare::sign_partial`]
+//!
+//! and the coordinator combines the partial signatures with [`combine`], which checks the
+//! signature with the public key, such that a share holder can not make the coordinator send a bad
+//! response.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         threshold::{self, KeyShare},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     // the key is split once, and the shares are given to the share holders
+//!     let private_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&private_key);
+//!     let shares = threshold::split(&private_key, 2, 3).unwrap();
+//!     drop(private_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(&b"metadata"[..]),
+//!         &public_key,
+//!         |randomized_unsigned| {
+//!             let signers: Vec<u32> = shares.iter().map(KeyShare::index).collect();
+//!
+//!             // round 1, the nonce shares are sent to the share holders they are for
+//!             let dealt: Vec<_> = shares
+//!                 .iter()
+//!                 .flat_map(|share| share.deal_nonce(&signers).unwrap())
+//!                 .collect();
+//!
+//!             // round 2
+//!             let challenge = PairingTokenEngine::prepare_signing(randomized_unsigned);
+//!             let partials: Vec<_> = shares
+//!                 .iter()
+//!                 .map(|share| {
+//!                     let nonce = dealt.iter().filter(|nonce| nonce.recipient() == share.index());
+//!                     share.sign_partial(&challenge, nonce.cloned().collect()).unwrap()
+//!                 })
+//!                 .collect();
+//!
+//!             threshold::combine(challenge, &partials, &public_key)
+//!                 .map_err(|_e| atpmd::Error::NotSigned)
+//!         },
+//!     ).unwrap();
+//!
+//!     assert!(PairingTokenEngine::verify(&signed, &public_key).is_ok());
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use bls12_381::{G1Affine, G1Projective, G2Affine, Scalar};
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+use super::keys::{PrivateKey, PublicKey};
+use super::tokens::{RandomizedSignedToken, SigningChallenge};
+use super::util::{pairing_check, random_biased};
+use super::Secret;
+
+/// The reason a threshold signing failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThresholdError {
+    /// The threshold is zero, or there are too few shares to ever sign
+    Parameters,
+    /// There are fewer than [`signers_needed`] signers
+    TooFewSigners,
+    /// The nonce shares are not one from each signer, for this share holder
+    NonceShares,
+    /// The partial signatures are not from the same signing
+    Mismatch,
+    /// The combined signature does not verify with the public key
+    BadSignature,
+}
+
+impl fmt::Display for ThresholdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parameters => f.write_str("the shares can never sign with this threshold"),
+            Self::TooFewSigners => f.write_str("too few share holders to sign"),
+            Self::NonceShares => f.write_str("the nonce shares are not one from each signer"),
+            Self::Mismatch => f.write_str("the partial signatures are from different signings"),
+            Self::BadSignature => f.write_str("the combined signature does not verify"),
+        }
+    }
+}
+
+/// The number of share holders needed to sign with a threshold, none for a threshold of zero or
+/// one so large that no shares can sign
+pub fn signers_needed(threshold: usize) -> Option<usize> {
+    threshold.checked_mul(2)?.checked_sub(1)
+}
+
+/// Evaluate a polynomial with the coefficients from the constant term and up
+fn evaluate(coefficients: &[Scalar], x: u32) -> Scalar {
+    let x = Scalar::from(u64::from(x));
+    coefficients
+        .iter()
+        .rev()
+        .fold(Scalar::zero(), |sum, coefficient| sum * x + coefficient)
+}
+
+/// A random polynomial of degree `threshold - 1`, with the constant term `secret`
+///
+/// The threshold of the shares is at least one, [`split`] checks it.
+fn polynomial<R: CryptoRng + RngCore>(
+    secret: Scalar,
+    threshold: usize,
+    rng: &mut R,
+) -> Vec<Scalar> {
+    core::iter::once(secret)
+        .chain(core::iter::repeat_with(|| random_biased(rng)).take(threshold.saturating_sub(1)))
+        .collect()
+}
+
+/// The Lagrange coefficient of `x` for interpolating at zero from `xs`
+fn lagrange(x: u32, xs: &[u32]) -> Scalar {
+    let x_i = Scalar::from(u64::from(x));
+    let (numerator, denominator) = xs.iter().filter(|x_j| **x_j != x).fold(
+        (Scalar::one(), Scalar::one()),
+        |(numerator, denominator), x_j| {
+            let x_j = Scalar::from(u64::from(*x_j));
+            (numerator * x_j, denominator * (x_j - x_i))
+        },
+    );
+
+    // the indices are distinct, so the denominator is not zero
+    numerator * denominator.invert().unwrap()
+}
+
+/// Split a private key into `shares` shares, of which `threshold` are needed to know the key
+///
+/// Signing needs [`signers_needed`] of the shares, so there must be at least that many.
+pub fn split(
+    key: &PrivateKey,
+    threshold: usize,
+    shares: usize,
+) -> Result<Vec<KeyShare>, ThresholdError> {
+    split_with_rng(key, threshold, shares, &mut crate::rng::default_rng())
+}
+
+pub fn split_with_rng<R: CryptoRng + RngCore>(
+    key: &PrivateKey,
+    threshold: usize,
+    shares: usize,
+    rng: &mut R,
+) -> Result<Vec<KeyShare>, ThresholdError> {
+    match signers_needed(threshold) {
+        Some(needed) if needed <= shares && shares <= u32::MAX as usize => (),
+        _ => return Err(ThresholdError::Parameters),
+    }
+
+    let mut coefficients = polynomial(Scalar::from(key), threshold, rng);
+    let shares = (1..=shares as u32)
+        .map(|index| KeyShare {
+            index,
+            share: Secret(evaluate(&coefficients, index)),
+            threshold,
+        })
+        .collect();
+    coefficients.iter_mut().for_each(|c| *c = Scalar::zero());
+
+    Ok(shares)
+}
+
+// {{{ Key share
+
+/// The share of the private key of one share holder
+///
+/// The share is zeroized when it is dropped
+#[derive(Clone)]
+pub struct KeyShare {
+    index: u32,
+    share: Secret<Scalar>,
+    threshold: usize,
+}
+
+impl KeyShare {
+    /// The index of the share holder, from 1
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Deal a fresh nonce to the signers of a signing, the indices of the share holders
+    ///
+    /// A nonce must only be used for one signing, or the key may be found from the responses.
+    pub fn deal_nonce(&self, signers: &[u32]) -> Result<Vec<NonceShare>, ThresholdError> {
+        self.deal_nonce_with_rng(signers, &mut crate::rng::default_rng())
+    }
+
+    pub fn deal_nonce_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        signers: &[u32],
+        rng: &mut R,
+    ) -> Result<Vec<NonceShare>, ThresholdError> {
+        let signers = sorted_signers(signers, self.threshold)?;
+        if !signers.contains(&self.index) {
+            return Err(ThresholdError::NonceShares);
+        }
+        let needed = signers_needed(self.threshold).ok_or(ThresholdError::Parameters)?;
+
+        let mut coefficients = polynomial(random_biased(rng), self.threshold, rng);
+        // of the same degree as the products, opened together with them
+        let mut masks = polynomial(Scalar::zero(), needed, rng);
+        let shares = signers
+            .iter()
+            .map(|recipient| NonceShare {
+                dealer: self.index,
+                recipient: *recipient,
+                signers: signers.clone(),
+                share: evaluate(&coefficients, *recipient),
+                mask: evaluate(&masks, *recipient),
+            })
+            .collect();
+        coefficients.iter_mut().for_each(|c| *c = Scalar::zero());
+        masks.iter_mut().for_each(|c| *c = Scalar::zero());
+
+        Ok(shares)
+    }
+
+    /// Sign with the share, and the nonce shares dealt to this share holder by every signer
+    pub fn sign_partial<M>(
+        &self,
+        challenge: &SigningChallenge<M>,
+        mut nonce: Vec<NonceShare>,
+    ) -> Result<PartialSignature, ThresholdError> {
+        let signers = match nonce.first() {
+            Some(first) => first.signers.clone(),
+            None => return Err(ThresholdError::NonceShares),
+        };
+
+        // one share from each signer, to this share holder, for the same signing
+        let mut dealers: Vec<u32> = nonce.iter().map(|share| share.dealer).collect();
+        dealers.sort_unstable();
+        if dealers != signers
+            || nonce
+                .iter()
+                .any(|share| share.recipient != self.index || share.signers != signers)
+        {
+            return Err(ThresholdError::NonceShares);
+        }
+
+        let (rho, mask) = nonce
+            .iter()
+            .fold((Scalar::zero(), Scalar::zero()), |(rho, mask), share| {
+                (rho + share.share, mask + share.mask)
+            });
+        nonce.iter_mut().for_each(Zeroize::zeroize);
+
+        Ok(PartialSignature {
+            index: self.index,
+            signers,
+            threshold: self.threshold,
+            u: (challenge.metadata_scalar() + self.share.0) * rho + mask,
+            point: G1Affine::from(challenge.point() * rho),
+        })
+    }
+}
+
+impl Zeroize for KeyShare {
+    fn zeroize(&mut self) {
+        self.share.zeroize();
+    }
+}
+
+impl Drop for KeyShare {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+/// The signers sorted, if they are distinct and enough for the threshold
+fn sorted_signers(signers: &[u32], threshold: usize) -> Result<Vec<u32>, ThresholdError> {
+    let mut sorted = signers.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    if sorted.len() != signers.len() || sorted.first() == Some(&0) {
+        return Err(ThresholdError::Mismatch);
+    }
+
+    let needed = signers_needed(threshold).ok_or(ThresholdError::Parameters)?;
+    if sorted.len() < needed {
+        return Err(ThresholdError::TooFewSigners);
+    }
+
+    Ok(sorted)
+}
+
+// }}}
+
+// {{{ Messages
+
+/// The share of a nonce, from the dealer to one of the signers
+///
+/// This must only be seen by the recipient.
+#[derive(Clone)]
+pub struct NonceShare {
+    dealer: u32,
+    recipient: u32,
+    signers: Vec<u32>,
+    share: Scalar,
+    mask: Scalar,
+}
+
+impl NonceShare {
+    pub fn dealer(&self) -> u32 {
+        self.dealer
+    }
+
+    /// The index of the share holder to send the share to
+    pub fn recipient(&self) -> u32 {
+        self.recipient
+    }
+}
+
+impl Zeroize for NonceShare {
+    fn zeroize(&mut self) {
+        self.share = Scalar::zero();
+        self.mask = Scalar::zero();
+    }
+}
+
+/// The partial signature of one share holder, sent to the coordinator
+#[derive(Clone)]
+pub struct PartialSignature {
+    index: u32,
+    signers: Vec<u32>,
+    threshold: usize,
+    /// The share of (d + k)ρ
+    u: Scalar,
+    /// The share of ρt
+    point: G1Affine,
+}
+
+impl PartialSignature {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+/// Combine the partial signatures of all the signers into the response to the user
+pub fn combine<M>(
+    challenge: SigningChallenge<M>,
+    partials: &[PartialSignature],
+    public_key: &PublicKey,
+) -> Result<RandomizedSignedToken<M>, ThresholdError> {
+    let first = partials.first().ok_or(ThresholdError::TooFewSigners)?;
+    let signers = sorted_signers(&first.signers, first.threshold)?;
+
+    let mut indices: Vec<u32> = partials.iter().map(|partial| partial.index).collect();
+    indices.sort_unstable();
+    if indices != signers
+        || partials
+            .iter()
+            .any(|partial| partial.signers != signers || partial.threshold != first.threshold)
+    {
+        return Err(ThresholdError::Mismatch);
+    }
+
+    let (u, point) = partials.iter().fold(
+        (Scalar::zero(), G1Projective::identity()),
+        |(u, point), partial| {
+            let lambda = lagrange(partial.index, &signers);
+            (u + partial.u * lambda, point + partial.point * lambda)
+        },
+    );
+
+    let u_inverse = Option::<Scalar>::from(u.invert()).ok_or(ThresholdError::BadSignature)?;
+    let w = G1Affine::from(point * u_inverse);
+
+    // e(w, d g2 + pk) == e(t, g2), as the user checks
+    let d_pk = G2Affine::from(
+        G2Affine::generator() * challenge.metadata_scalar() + G2Affine::from(public_key),
+    );
+    if bool::from(w.is_identity()) || !pairing_check(w, d_pk, challenge.point()) {
+        return Err(ThresholdError::BadSignature);
+    }
+
+    Ok(challenge.into_signed(w))
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use super::super::tokens::{PairingTokenEngine, PairingUnsignedToken, RandomizedUnsignedToken};
+    use super::super::{TokenEngine, UnsignedToken};
+
+    fn sign_partials(
+        shares: &[&KeyShare],
+        randomized: &RandomizedUnsignedToken<&[u8]>,
+    ) -> Vec<PartialSignature> {
+        let signers: Vec<u32> = shares.iter().map(|share| share.index()).collect();
+        let dealt: Vec<NonceShare> = shares
+            .iter()
+            .flat_map(|share| share.deal_nonce(&signers).unwrap())
+            .collect();
+
+        shares
+            .iter()
+            .map(|share| {
+                let nonce = dealt
+                    .iter()
+                    .filter(|nonce| nonce.recipient() == share.index())
+                    .cloned()
+                    .collect();
+                let challenge = PairingTokenEngine::prepare_signing(randomized);
+                share.sign_partial(&challenge, nonce).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_threshold() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let shares = split(&private_key, 3, 6).unwrap();
+
+        let unsigned = PairingUnsignedToken::new(&b"metadata"[..]);
+        let (r, randomized) = PairingTokenEngine::randomize(&unsigned);
+
+        // any 5 of the 6 shares
+        let signers = [&shares[5], &shares[0], &shares[2], &shares[3], &shares[1]];
+        let partials = sign_partials(&signers, &randomized);
+        let challenge = PairingTokenEngine::prepare_signing(&randomized);
+        let response = combine(challenge, &partials, &public_key).unwrap();
+
+        let signed = PairingTokenEngine::verify_signature_and_unrandomize(
+            unsigned,
+            randomized,
+            response,
+            &public_key,
+            r,
+        )
+        .unwrap();
+        assert!(PairingTokenEngine::verify(&signed, &public_key).is_ok());
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let secret = random_biased(&mut crate::rng::default_rng());
+        let coefficients = polynomial(secret, 3, &mut crate::rng::default_rng());
+
+        let xs = [2, 5, 7];
+        let interpolated = xs.iter().fold(Scalar::zero(), |sum, x| {
+            sum + evaluate(&coefficients, *x) * lagrange(*x, &xs)
+        });
+        assert_eq!(interpolated, secret);
+    }
+
+    #[test]
+    fn fail_threshold() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        assert_eq!(signers_needed(0), None);
+        assert_eq!(signers_needed(usize::MAX), None);
+        assert_eq!(signers_needed(3), Some(5));
+        assert_eq!(
+            split(&private_key, 0, 3).err(),
+            Some(ThresholdError::Parameters)
+        );
+        assert_eq!(
+            split(&private_key, 0, 0).err(),
+            Some(ThresholdError::Parameters)
+        );
+        assert_eq!(
+            split(&private_key, usize::MAX, 3).err(),
+            Some(ThresholdError::Parameters)
+        );
+        assert!(split(&private_key, 3, 4).is_err());
+
+        let shares = split(&private_key, 2, 4).unwrap();
+        assert_eq!(
+            shares[0].deal_nonce(&[1, 2]).err(),
+            Some(ThresholdError::TooFewSigners)
+        );
+        assert_eq!(
+            shares[0].deal_nonce(&[2, 3, 4]).err(),
+            Some(ThresholdError::NonceShares)
+        );
+
+        let unsigned = PairingUnsignedToken::new(&b"metadata"[..]);
+        let (_r, randomized) = PairingTokenEngine::randomize(&unsigned);
+        let challenge = || PairingTokenEngine::prepare_signing(&randomized);
+
+        // a partial signature is missing
+        let mut partials = sign_partials(&[&shares[0], &shares[1], &shares[2]], &randomized);
+        assert_eq!(
+            combine(challenge(), &partials[..2], &public_key).err(),
+            Some(ThresholdError::Mismatch)
+        );
+
+        // a share holder does not follow the protocol
+        partials[1].u += Scalar::one();
+        assert_eq!(
+            combine(challenge(), &partials, &public_key).err(),
+            Some(ThresholdError::BadSignature)
+        );
+
+        // the shares of another key
+        let other = split(&PrivateKey::new(), 2, 3).unwrap();
+        let partials = sign_partials(&[&other[0], &other[1], &other[2]], &randomized);
+        assert_eq!(
+            combine(challenge(), &partials, &public_key).err(),
+            Some(ThresholdError::BadSignature)
+        );
+    }
+}
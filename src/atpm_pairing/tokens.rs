@@ -1,25 +1,38 @@
-use bls12_381::{Bls12, G1Affine, G2Affine, G2Projective, Scalar};
-use pairing::Engine;
+use bls12_381::{G1Affine, G1Projective, G2Affine, G2Projective, Scalar};
+use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use subtle::CtOption;
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use core::marker::PhantomData;
 
-use super::keys::{PrivateKey, PublicKey};
-use super::util::{h_1, h_m, random_vartime, CurvePoint};
-use super::{SignedToken, TokenEngine, TokenIdentifier, UnsignedToken};
+use super::hm::{HmUniform, MetadataHash};
+use super::keys::{KeyHandle, PrivateKey, PublicKey};
+use super::util::{h_1, pairing_check, random_biased, Bls12G1, CurvePoint};
+use super::{
+    check_metadata, invertible, Error, HasKeyId, KeyEpoch, KeyId, SignedToken, TokenEngine,
+    TokenIdentifier, UnsignedToken, VerifyError,
+};
+use crate::group::blinding;
+use crate::redemption::{Fingerprint, Nullifier, Redeemable};
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
 
 // {{{ Signed Token
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PairingSignedToken<M: AsRef<[u8]>> {
+/// A signed token, of the hash of the metadata `H`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PairingSignedToken<M: AsRef<[u8]>, H = HmUniform> {
     id: TokenIdentifier<M>,
     metadata: M,
     signature: CurvePoint,
+    /// The key the user unrandomized the token with, missing in tokens from before key ids
+    #[serde(default)]
+    key_id: Option<KeyId>,
+    #[serde(skip)]
+    _h: PhantomData<H>,
 }
 
-impl<M: AsRef<[u8]>> PartialEq for PairingSignedToken<M> {
+impl<M: AsRef<[u8]>, H> PartialEq for PairingSignedToken<M, H> {
     fn eq(&self, other: &Self) -> bool {
         // has to have the same id
         let same_id = self.id == other.id;
@@ -36,12 +49,29 @@ impl<M: AsRef<[u8]>> PartialEq for PairingSignedToken<M> {
             .zip(other.metadata.as_ref().iter())
             .fold(true, |s, (l, r)| l == r && s);
 
-        // all conditions has to be true
+        // all conditions has to be true, the key id is only a hint
         same_signature && same_id && same_metadata
     }
 }
 
-impl<M: AsRef<[u8]>> SignedToken for PairingSignedToken<M> {
+/// A token of arbitrary fields, that is well formed but does not verify
+#[cfg(feature = "arbitrary")]
+impl<'a, M, H> arbitrary::Arbitrary<'a> for PairingSignedToken<M, H>
+where
+    M: AsRef<[u8]> + arbitrary::Arbitrary<'a>,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            id: u.arbitrary()?,
+            metadata: u.arbitrary()?,
+            signature: u.arbitrary()?,
+            key_id: u.arbitrary()?,
+            _h: PhantomData,
+        })
+    }
+}
+
+impl<M: AsRef<[u8]>, H: MetadataHash> SignedToken for PairingSignedToken<M, H> {
     type VerificationKey = PublicKey;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
@@ -49,35 +79,212 @@ impl<M: AsRef<[u8]>> SignedToken for PairingSignedToken<M> {
         let t: [u8; 16] = (&self.id).into();
 
         // create the point on the cuve
-        let t_point = h_1(&t, &self.metadata);
+        let t_point = h_1(t, &self.metadata);
 
         // get the public key and other useful points on the curve
         let pk: G2Affine = <&PublicKey>::into(verification_key);
-        let u: G2Projective = G2Affine::generator() * h_m(&self.metadata) + pk;
+        let u: G2Projective = G2Affine::generator() * H::h_m(self.metadata.as_ref()) + pk;
 
         // Verify that the signature is from the provided public key
-        Bls12::pairing(&G1Affine::from(&self.signature), &u.into())
-            == Bls12::pairing(&t_point, &G2Affine::generator())
+        pairing_check(&self.signature, u, t_point)
+    }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn hidden_metadata(&self) -> Option<&[u8]> {
+        self.id.hidden().map(AsRef::as_ref)
+    }
+
+    fn verify_with_hidden(
+        &self,
+        verification_key: &Self::VerificationKey,
+        expected_hidden: &[u8],
+    ) -> bool {
+        bool::from(self.id.is_hidden(expected_hidden)) && self.verify(verification_key)
+    }
+
+    fn key_id(&self) -> Option<KeyId> {
+        self.key_id
     }
 }
 
-impl<M: AsRef<[u8]>> PairingSignedToken<M> {
-    pub(crate) fn create(id: TokenIdentifier<M>, signature: CurvePoint, metadata: M) -> Self {
+impl<M: AsRef<[u8]>, H: MetadataHash> Redeemable for PairingSignedToken<M, H> {
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_token(&self.id, self.metadata.as_ref())
+    }
+
+    fn nullifier(&self) -> Nullifier {
+        Nullifier::of_token(
+            &self.id,
+            &self.signature.to_compressed(),
+            self.metadata.as_ref(),
+        )
+    }
+}
+
+impl<M: AsRef<[u8]> + for<'a> From<&'a [u8]>> crate::http::HeaderToken for PairingSignedToken<M> {
+    const ENGINE_ID: &'static str = "pairing";
+}
+
+impl<M: AsRef<[u8]>, H: MetadataHash> PairingSignedToken<M, H> {
+    pub fn metadata(&self) -> &M {
+        &self.metadata
+    }
+
+    pub(crate) fn create(
+        id: TokenIdentifier<M>,
+        signature: CurvePoint,
+        metadata: M,
+        key_id: Option<KeyId>,
+    ) -> Self {
         Self {
             id,
             signature,
             metadata,
+            key_id,
+            _h: PhantomData {},
         }
     }
 
-    pub(crate) fn unpack(self) -> (TokenIdentifier<M>, CurvePoint, M) {
+    /// The signature w, the point t of the id and the hashed metadata d, for verifying the token
+    pub(crate) fn points(&self) -> (G1Affine, G1Affine, Scalar) {
+        let t: [u8; 16] = (&self.id).into();
+        (
+            G1Affine::from(&self.signature),
+            h_1(t, &self.metadata),
+            H::h_m(self.metadata.as_ref()),
+        )
+    }
+
+    pub(crate) fn unpack(self) -> (TokenIdentifier<M>, CurvePoint, M, Option<KeyId>) {
         let PairingSignedToken {
             id,
             metadata,
             signature,
+            key_id,
+            ..
         } = self;
 
-        (id, signature, metadata)
+        (id, signature, metadata, key_id)
+    }
+}
+
+impl<M: AsRef<[u8]> + for<'a> From<&'a [u8]>, H: MetadataHash> WireFormat
+    for PairingSignedToken<M, H>
+{
+    const SUITE: Ciphersuite = H::SUITE;
+    const TYPE: u8 = 0x01;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.id(&self.id)?;
+        writer.prefixed(&self.metadata)?;
+        writer.fixed(self.signature.to_compressed());
+        writer.key_id(self.key_id);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            id: reader.id()?,
+            metadata: M::from(reader.prefixed()?),
+            signature: CurvePoint::from_compressed(&reader.fixed()?)
+                .ok_or(WireError::InvalidPoint)?,
+            key_id: reader.key_id()?,
+            _h: PhantomData {},
+        })
+    }
+}
+
+/// Check that single tokens can be collected into one batch
+///
+/// A batch has one metadata and one key id, so the tokens must all have the same ones, and there
+/// must be at least one token.
+pub(crate) fn check_collect<M: AsRef<[u8]>, H: MetadataHash>(
+    tokens: &[PairingSignedToken<M, H>],
+) -> Result<(), Error> {
+    let first = tokens.first().ok_or(Error::EmptyBatch)?;
+    for token in tokens {
+        check_metadata(&first.metadata, token.metadata.as_ref())?;
+        if token.key_id != first.key_id {
+            return Err(Error::KeyMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// Verify many tokens with two pairings, instead of two for each token
+///
+/// The two pairings are done with one Miller loop and one final exponentiation.
+/// The tokens may have different metadata. Every token verifies with
+/// e(w, pk) = e(t - h_m * w, g2), so a random linear combination of the tokens does too, and a
+/// bad token makes the combination fail, unless the random scalars are guessed.
+pub fn verify_batch<M: AsRef<[u8]>, H: MetadataHash>(
+    tokens: &[PairingSignedToken<M, H>],
+    key: &PublicKey,
+) -> bool {
+    let (w, t) = combination(tokens);
+    pairing_check(w, key, t)
+}
+
+/// The random linear combination of the tokens, the sums of r * w and r * (t - h_m * w)
+pub(crate) fn combination<M: AsRef<[u8]>, H: MetadataHash>(
+    tokens: &[PairingSignedToken<M, H>],
+) -> (G1Affine, G1Affine) {
+    let mut rng = crate::rng::default_rng();
+
+    let (w, t) = tokens.iter().fold(
+        (G1Projective::identity(), G1Projective::identity()),
+        |(wsum, tsum), token| {
+            // may use biased, since it only needs to be unpredictable
+            let r = random_biased(&mut rng);
+            let w = G1Affine::from(&token.signature) * r;
+            let t: [u8; 16] = (&token.id).into();
+
+            (
+                wsum + w,
+                tsum + h_1(t, &token.metadata) * r - w * H::h_m(token.metadata.as_ref()),
+            )
+        },
+    );
+
+    (w.into(), t.into())
+}
+
+/// Verify many tokens, and find the ones that do not verify
+///
+/// The batch is halved until the halves verify, so a few bad tokens cost a few more pairings.
+/// Returns the indices of the bad tokens, in order, which is empty if all tokens verify.
+pub fn verify_batch_failures<M: AsRef<[u8]>, H: MetadataHash>(
+    tokens: &[PairingSignedToken<M, H>],
+    key: &PublicKey,
+) -> Vec<usize> {
+    let mut failures = Vec::new();
+    bisect(tokens, key, 0, &mut failures);
+    failures
+}
+
+fn bisect<M: AsRef<[u8]>, H: MetadataHash>(
+    tokens: &[PairingSignedToken<M, H>],
+    key: &PublicKey,
+    offset: usize,
+    failures: &mut Vec<usize>,
+) {
+    match tokens {
+        [] => {}
+        [token] => {
+            if !token.verify(key) {
+                failures.push(offset);
+            }
+        }
+        _ => {
+            if !verify_batch(tokens, key) {
+                let (left, right) = tokens.split_at(tokens.len() / 2);
+                bisect(left, key, offset, failures);
+                bisect(right, key, offset + left.len(), failures);
+            }
+        }
     }
 }
 
@@ -95,16 +302,20 @@ impl<M: AsRef<[u8]>> UnsignedToken for PairingUnsignedToken<M> {
     type HiddenMetadata = M;
     type Metadata = M;
 
-    fn new(metadata: Self::Metadata) -> Self {
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
         Self {
-            id: TokenIdentifier::new(),
+            id: TokenIdentifier::new_with_rng(rng),
             metadata,
         }
     }
 
-    fn with_hidden(metadata: Self::Metadata, hidden: Self::HiddenMetadata) -> Self {
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
         Self {
-            id: TokenIdentifier::with_hidden(hidden),
+            id: TokenIdentifier::with_hidden_with_rng(hidden, rng),
             metadata,
         }
     }
@@ -129,6 +340,8 @@ impl<M: AsRef<[u8]>> PairingUnsignedToken<M> {
             id: self.id,
             signature,
             metadata: self.metadata,
+            key_id: None,
+            _h: PhantomData {},
         }
     }
 }
@@ -160,6 +373,24 @@ impl<M: AsRef<[u8]>> RandomizedUnsignedToken<M> {
     }
 }
 
+impl<M> WireFormat for RandomizedUnsignedToken<M> {
+    const SUITE: Ciphersuite = Ciphersuite::PairingV1;
+    const TYPE: u8 = 0x02;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.point.to_compressed());
+        writer.prefixed(&self.metadata)
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            point: CurvePoint::from_compressed(&reader.fixed()?).ok_or(WireError::InvalidPoint)?,
+            metadata: Box::from(reader.prefixed()?),
+            _m: PhantomData {},
+        })
+    }
+}
+
 // }}}
 
 // {{{ RandomizedSignedToken
@@ -168,6 +399,8 @@ impl<M: AsRef<[u8]>> RandomizedUnsignedToken<M> {
 pub struct RandomizedSignedToken<M> {
     point: CurvePoint,
     metadata: Box<[u8]>,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
     _m: PhantomData<M>,
 }
 
@@ -176,11 +409,46 @@ impl<M: AsRef<[u8]>> Default for RandomizedSignedToken<M> {
         Self {
             point: CurvePoint::from(G1Affine::identity()),
             metadata: Box::from([]),
+            key_epoch: None,
             _m: PhantomData {},
         }
     }
 }
 
+impl<M> crate::common::RandomizedSignedToken for RandomizedSignedToken<M> {
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
+impl<M> WireFormat for RandomizedSignedToken<M> {
+    const SUITE: Ciphersuite = Ciphersuite::PairingV1;
+    const TYPE: u8 = 0x03;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.point.to_compressed());
+        writer.prefixed(&self.metadata)?;
+        writer.key_epoch(self.key_epoch);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            point: CurvePoint::from_compressed(&reader.fixed()?).ok_or(WireError::InvalidPoint)?,
+            metadata: Box::from(reader.prefixed()?),
+            key_epoch: reader.key_epoch()?,
+            _m: PhantomData {},
+        })
+    }
+}
+
 impl<M: AsRef<[u8]>> From<&RandomizedSignedToken<M>> for G1Affine {
     fn from(tok: &RandomizedSignedToken<M>) -> Self {
         G1Affine::from(&tok.point)
@@ -189,89 +457,206 @@ impl<M: AsRef<[u8]>> From<&RandomizedSignedToken<M>> for G1Affine {
 
 // }}}
 
+// {{{ SigningChallenge
+
+/// The part of signing a token that does not need the private key
+///
+/// This is the hash of the metadata and the point to sign, so that a secure element holding the
+/// key only has to do the scalar inversion and the point multiplication.
+pub struct SigningChallenge<M> {
+    d: Scalar,
+    point: G1Affine,
+    metadata: Box<[u8]>,
+    _m: PhantomData<M>,
+}
+
+impl<M> SigningChallenge<M> {
+    /// The hash of the metadata, d
+    pub fn metadata_scalar(&self) -> Scalar {
+        self.d
+    }
+
+    /// The randomized point to sign, t
+    pub fn point(&self) -> G1Affine {
+        self.point
+    }
+
+    /// The response with the signed point, w
+    pub(crate) fn into_signed(self, point: G1Affine) -> RandomizedSignedToken<M> {
+        RandomizedSignedToken {
+            metadata: self.metadata,
+            point: CurvePoint::from(point),
+            key_epoch: None,
+            _m: PhantomData {},
+        }
+    }
+}
+
+// }}}
+
 // {{{ Token Engine
 
-pub struct PairingTokenEngine<M: AsRef<[u8]>> {
+/// The engine of the tokens, hashing the metadata with `H`, see [`hm`](super::hm)
+pub struct HashedPairingTokenEngine<M: AsRef<[u8]>, H> {
     _m: PhantomData<M>,
+    _h: PhantomData<H>,
 }
 
-impl<M: AsRef<[u8]>> TokenEngine for PairingTokenEngine<M> {
+/// The engine of the tokens, hashing the metadata with [`HmUniform`]
+pub type PairingTokenEngine<M> = HashedPairingTokenEngine<M, HmUniform>;
+
+impl<M: AsRef<[u8]>, H: MetadataHash> HashedPairingTokenEngine<M, H> {
+    /// Hash the metadata of a randomized token, this does not touch the private key
+    pub fn prepare_signing(t_prime: &RandomizedUnsignedToken<M>) -> SigningChallenge<M> {
+        SigningChallenge {
+            d: H::h_m(&t_prime.metadata),
+            point: G1Affine::from(&t_prime.point),
+            metadata: t_prime.metadata.clone(),
+            _m: PhantomData {},
+        }
+    }
+
+    /// The signature without the randomization, if it verifies
+    fn unrandomize(
+        unsigned_token: &PairingUnsignedToken<M>,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        signed_token: &RandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: Scalar,
+    ) -> Result<G1Affine, Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // the public key point
+        let pk: G2Affine = <&PublicKey>::into(verification_data);
+        let u_point: G2Projective =
+            G2Affine::generator() * H::h_m(unsigned_token.metadata.as_ref()) + pk;
+
+        // remove randomization
+        let w = (G1Affine::from(&signed_token.point) * randomization).into();
+
+        // The token identifier
+        let t: [u8; 16] = (&unsigned_token.id).into();
+
+        // Verify that the signature is correct
+        if pairing_check(w, u_point, h_1(t, &unsigned_token.metadata)) {
+            Ok(w)
+        } else {
+            Err(Error::BadSignature)
+        }
+    }
+
+    /// Sign the prepared challenge with the key, this is all of the signing that needs the key
+    pub fn complete_signing(
+        challenge: SigningChallenge<M>,
+        key_handle: &impl KeyHandle,
+    ) -> CtOption<RandomizedSignedToken<M>> {
+        // This should be a constant time implementation
+        key_handle
+            .sign_point(&challenge.d, &challenge.point)
+            .map(|point| challenge.into_signed(point))
+    }
+
+    /// Sign the randomized tokens of many clients, in order
+    ///
+    /// The inverse (d + k)^{-1} is computed once per metadata, and not once per token, so this is
+    /// for an issuer signing the requests of many clients with the same few metadata values.
+    pub fn sign_randomized_many(
+        tokens: &[RandomizedUnsignedToken<M>],
+        sign_key: &PrivateKey,
+    ) -> Result<Vec<RandomizedSignedToken<M>>, Error> {
+        let k = Scalar::from(sign_key);
+        let mut inverses: BTreeMap<&[u8], Scalar> = BTreeMap::new();
+
+        tokens
+            .iter()
+            .map(|t_prime| {
+                let inverse = match inverses.get(&*t_prime.metadata) {
+                    Some(inverse) => *inverse,
+                    None => {
+                        let inverse = invertible((H::h_m(&t_prime.metadata) + k).invert())?;
+                        inverses.insert(&t_prime.metadata, inverse);
+                        inverse
+                    }
+                };
+                Ok(RandomizedSignedToken {
+                    metadata: t_prime.metadata.clone(),
+                    point: CurvePoint::from(G1Affine::from(
+                        G1Affine::from(&t_prime.point) * inverse,
+                    )),
+                    key_epoch: None,
+                    _m: PhantomData {},
+                })
+            })
+            .collect()
+    }
+}
+
+impl<M: AsRef<[u8]>, H: MetadataHash> TokenEngine for HashedPairingTokenEngine<M, H> {
     type UnsignedToken = PairingUnsignedToken<M>;
     type RandomizedUnsignedToken = RandomizedUnsignedToken<M>;
     type RandomizedSignedToken = RandomizedSignedToken<M>;
-    type SignedToken = PairingSignedToken<M>;
+    type SignedToken = PairingSignedToken<M, H>;
     type Randomization = Scalar;
     type UserVerification = PublicKey;
     type SignKey = PrivateKey;
 
-    fn randomize(
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
         unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
         let t: [u8; 16] = (&unsigned_token.id).into();
-        let t = h_1(&t, &unsigned_token.metadata);
-
-        loop {
-            // Pick random stuff until it is invertible (should be the first)
-            let r = random_vartime(&mut rand::thread_rng());
-            let result = r.invert();
-
-            if bool::from(result.is_some()) {
-                let rinv = result.unwrap();
-                let rut = RandomizedUnsignedToken {
-                    metadata: Box::from(unsigned_token.metadata.as_ref()),
-                    point: CurvePoint::from(t * rinv),
-                    _m: PhantomData {},
-                };
-                return (r, rut);
-            }
-        }
+        let t = h_1(t, &unsigned_token.metadata);
+
+        let (r, rinv) = blinding::<Bls12G1, _>(rng);
+        let rut = RandomizedUnsignedToken {
+            metadata: Box::from(unsigned_token.metadata.as_ref()),
+            point: CurvePoint::from(t * rinv),
+            _m: PhantomData {},
+        };
+        (r, rut)
     }
 
-    fn sign_randomized(
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
         t_prime: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> CtOption<Self::RandomizedSignedToken> {
-        // This should be a constant time implementation
-        let d = h_m(&t_prime.metadata);
-        let k: Scalar = <&PrivateKey>::into(sign_key);
-        (d + k)
-            .invert()
-            .map(|inverse| (G1Affine::from(&t_prime.point) * inverse))
-            .map(|point| RandomizedSignedToken {
-                metadata: t_prime.metadata.clone(),
-                point: CurvePoint::from(point),
-                _m: PhantomData {},
-            })
+        _rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        invertible(Self::complete_signing(
+            Self::prepare_signing(t_prime),
+            sign_key,
+        ))
     }
 
     fn verify_signature_and_unrandomize(
         unsigned_token: Self::UnsignedToken,
-        _randomized_unsigned_token: Self::RandomizedUnsignedToken,
+        randomized_unsigned_token: Self::RandomizedUnsignedToken,
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken> {
-        // the public key point
-        let pk: G2Affine = <&PublicKey>::into(verification_data);
-        let u_point: G2Projective = G2Affine::generator() * h_m(&unsigned_token.metadata) + pk;
-
-        // remove randomization
-        let w = (G1Affine::from(&signed_token.point) * randomization).into();
-
-        // The token identifier
-        let t: [u8; 16] = (&unsigned_token.id).into();
-
-        // Verify that the signature is correct
-        if Bls12::pairing(&w, &u_point.into())
-            == Bls12::pairing(&h_1(&t, &unsigned_token.metadata), &G2Affine::generator())
-        {
-            Some(Self::SignedToken {
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::unrandomize(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+            randomization,
+        ) {
+            Ok(w) => Ok(Self::SignedToken {
                 signature: w.into(),
                 id: unsigned_token.id,
                 metadata: unsigned_token.metadata,
-            })
-        } else {
-            None
+                key_id: Some(verification_data.key_id()),
+                _h: PhantomData {},
+            }),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
         }
     }
 }
@@ -284,9 +669,10 @@ impl<M: AsRef<[u8]>> TokenEngine for PairingTokenEngine<M> {
 mod tests {
     use super::*;
 
+    use super::super::util::h_m;
     use super::super::{
         keys::{PrivateKey, PublicKey},
-        UnsignedToken,
+        KeyRing, PublicKeySet, RandomizedSignedToken as _, UnsignedToken,
     };
 
     #[test]
@@ -314,6 +700,120 @@ mod tests {
         assert!(signed_token.verify(&public_key));
     }
 
+    /// Verify a batch of the tokens of the hash H
+    fn check_verify_batch<H: MetadataHash>() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let mut tokens: Vec<PairingSignedToken<&[u8], H>> =
+            [&b"first"[..], b"second", b"first", b"third", b"second"]
+                .iter()
+                .map(|metadata| {
+                    HashedPairingTokenEngine::sign(
+                        PairingUnsignedToken::new(*metadata),
+                        &public_key,
+                        |randomized| {
+                            HashedPairingTokenEngine::<_, H>::sign_randomized(randomized, &secret_key)
+                        },
+                    )
+                    .unwrap()
+                })
+                .collect();
+
+        assert!(verify_batch(&tokens, &public_key));
+        assert!(verify_batch::<&[u8], H>(&[], &public_key));
+        assert!(verify_batch_failures(&tokens, &public_key).is_empty());
+        assert!(!verify_batch(&tokens, &PublicKey::from(&PrivateKey::new())));
+
+        // signatures of other tokens
+        tokens[1].signature = tokens[0].signature.clone();
+        tokens[4].metadata = b"first";
+
+        assert!(!verify_batch(&tokens, &public_key));
+        assert_eq!(verify_batch_failures(&tokens, &public_key), [1, 4]);
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        check_verify_batch::<HmUniform>();
+        check_verify_batch::<super::super::hm::HmReduced>();
+    }
+
+    #[test]
+    fn test_sign_randomized_many() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let unsigned: Vec<_> = [&b"first"[..], b"second", b"first", b"first"]
+            .iter()
+            .map(|metadata| PairingUnsignedToken::new(*metadata))
+            .collect();
+        let randomized: Vec<_> = unsigned.iter().map(PairingTokenEngine::randomize).collect();
+        let requests: Vec<_> = randomized.iter().map(|(_, t)| t.clone()).collect();
+
+        let signed = PairingTokenEngine::sign_randomized_many(&requests, &secret_key).unwrap();
+        assert_eq!(signed.len(), 4);
+        assert!(
+            PairingTokenEngine::<&[u8]>::sign_randomized_many(&[], &secret_key)
+                .unwrap()
+                .is_empty()
+        );
+
+        for ((unsigned, (r, request)), signed) in unsigned.into_iter().zip(randomized).zip(signed) {
+            let token = PairingTokenEngine::verify_signature_and_unrandomize(
+                unsigned,
+                request,
+                signed,
+                &public_key,
+                r,
+            )
+            .unwrap();
+            assert!(token.verify(&public_key));
+        }
+    }
+
+    #[test]
+    fn test_split_signing() {
+        /// A secure element, that only knows how to multiply points
+        struct Element {
+            key: Scalar,
+        }
+
+        impl KeyHandle for Element {
+            fn sign_point(&self, d: &Scalar, t: &G1Affine) -> CtOption<G1Affine> {
+                (d + self.key).invert().map(|e| G1Affine::from(t * e))
+            }
+        }
+
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let element = Element {
+            key: Scalar::from(&secret_key),
+        };
+
+        let unsigned_token = PairingUnsignedToken::new(b"this is public metadata");
+        let (r, anonymized_token) = PairingTokenEngine::randomize(&unsigned_token);
+
+        let challenge = PairingTokenEngine::prepare_signing(&anonymized_token);
+        assert!(challenge.metadata_scalar() == h_m(b"this is public metadata"));
+
+        let signed = PairingTokenEngine::complete_signing(challenge, &element).unwrap();
+
+        // the same as signing with the key in the process
+        let expected = PairingTokenEngine::sign_randomized(&anonymized_token, &secret_key).unwrap();
+        assert!(G1Affine::from(&signed) == G1Affine::from(&expected));
+
+        let signed_token = PairingTokenEngine::verify_signature_and_unrandomize(
+            unsigned_token,
+            anonymized_token,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap();
+        assert!(signed_token.verify(&public_key));
+    }
+
     #[test]
     fn test_wrong_sign_key() {
         let message = b"this is public metadata";
@@ -330,15 +830,29 @@ mod tests {
         let signed =
             PairingTokenEngine::sign_randomized(&anonymized_token, &wrong_secret_key).unwrap();
 
-        let signed_token = PairingTokenEngine::verify_signature_and_unrandomize(
+        let e = PairingTokenEngine::verify_signature_and_unrandomize(
             unsigned_token,
             anonymized_token,
             signed,
             &public_key,
             r,
-        );
+        )
+        .err()
+        .unwrap();
+        assert_eq!(e.error, Error::BadSignature);
 
-        assert!(signed_token.is_none())
+        // the tokens are given back, and may be signed by the right key
+        let signed =
+            PairingTokenEngine::sign_randomized(&e.randomized_unsigned, &secret_key).unwrap();
+        let signed_token = PairingTokenEngine::verify_signature_and_unrandomize(
+            e.unsigned_token,
+            e.randomized_unsigned,
+            signed,
+            &public_key,
+            e.randomization,
+        )
+        .unwrap();
+        assert!(PairingTokenEngine::verify(&signed_token, &public_key).is_ok());
     }
 
     #[test]
@@ -368,6 +882,191 @@ mod tests {
 
         assert!(!signed_token.verify(&wrong_public_key));
     }
+
+    #[test]
+    fn test_wire_format() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from_bytes(&PublicKey::from(&private_key).to_bytes()).unwrap();
+
+        let unsigned_token = PairingTokenEngine::generate_with_hidden(
+            Box::from(&b"metadata"[..]),
+            Box::from(&b"hidden"[..]),
+        );
+        let (r, randomized) = PairingTokenEngine::randomize(&unsigned_token);
+
+        // the signer only sees the bytes
+        let request: RandomizedUnsignedToken<Box<[u8]>> =
+            RandomizedUnsignedToken::from_bytes(&randomized.to_bytes()).unwrap();
+        let signed = PairingTokenEngine::sign_randomized(&request, &private_key)
+            .unwrap()
+            .with_key_epoch(4);
+        let signed = RandomizedSignedToken::from_bytes(&signed.to_bytes()).unwrap();
+        assert_eq!(signed.key_epoch(), Some(4));
+
+        let signed = PairingTokenEngine::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap();
+
+        let bytes = signed.to_bytes();
+        let decoded = PairingSignedToken::<Box<[u8]>>::from_bytes(&bytes).unwrap();
+        assert!(decoded == signed);
+        assert_eq!(decoded.key_id(), Some(public_key.key_id()));
+        assert!(PairingTokenEngine::verify(&decoded, &public_key).is_ok());
+
+        // a token is not a public key
+        assert_eq!(PublicKey::from_bytes(&bytes).err(), Some(WireError::Type));
+
+        // the signature has to be on the curve
+        let mut bad = bytes;
+        let len = bad.len();
+        bad[len - 19] ^= 1;
+        assert!(PairingSignedToken::<Box<[u8]>>::from_bytes(&bad).is_err());
+    }
+
+    #[test]
+    fn fail_malformed() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let signed = PairingTokenEngine::sign(
+            PairingTokenEngine::generate_with_hidden(
+                Box::from(&b"metadata"[..]),
+                Box::from(&b"hidden"[..]),
+            ),
+            &public_key,
+            |randomized| PairingTokenEngine::sign_randomized(randomized, &private_key),
+        )
+        .unwrap();
+        let bytes = signed.to_bytes();
+        let decode = PairingSignedToken::<Box<[u8]>>::from_bytes;
+
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "decoded {} bytes", len);
+        }
+
+        // only the key id is not signed
+        for i in 0..bytes.len() {
+            let mut bad = bytes.clone();
+            bad[i] ^= 0xff;
+            if let Ok(token) = decode(&bad) {
+                assert!(
+                    !token.verify(&public_key) || token.key_id() != signed.key_id(),
+                    "byte {} is not signed",
+                    i
+                );
+            }
+        }
+
+        let json = serde_json::to_string(&signed).unwrap();
+        for len in 0..json.len() {
+            assert!(serde_json::from_str::<PairingSignedToken<Box<[u8]>>>(&json[..len]).is_err());
+        }
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let public_key = PublicKey::from(&PrivateKey::new());
+        for _ in 0..32 {
+            let mut entropy = [0; 256];
+            crate::rng::default_rng().fill_bytes(&mut entropy);
+            let mut u = Unstructured::new(&entropy);
+
+            let token = PairingSignedToken::<Box<[u8]>>::arbitrary(&mut u).unwrap();
+            let decoded = PairingSignedToken::<Box<[u8]>>::from_bytes(&token.to_bytes()).unwrap();
+            assert!(decoded == token);
+            assert_eq!(decoded.to_bytes(), token.to_bytes());
+            assert!(!token.verify(&public_key));
+
+            let json = serde_json::to_string(&token).unwrap();
+            assert!(serde_json::from_str::<PairingSignedToken<Box<[u8]>>>(&json).unwrap() == token);
+
+            let key = PublicKey::arbitrary(&mut u).unwrap();
+            assert_eq!(
+                key.to_bytes(),
+                PublicKey::from_bytes(&key.to_bytes()).unwrap().to_bytes()
+            );
+
+            // the decoders take any bytes
+            let _ = PairingSignedToken::<Box<[u8]>>::from_bytes(&entropy);
+            let _ = PublicKey::from_bytes(&entropy);
+        }
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let message = b"this is public metadata";
+
+        let old_secret_key = PrivateKey::new();
+        let new_secret_key = PrivateKey::new();
+
+        // The user fetched the keys before the rotation, and only knows the old key
+        let mut old_keys = PublicKeySet::new();
+        old_keys.insert(1, PublicKey::from(&old_secret_key));
+
+        let mut keys = PublicKeySet::new();
+        keys.insert(1, PublicKey::from(&old_secret_key));
+        keys.insert(2, PublicKey::from(&new_secret_key));
+
+        let unsigned_token = PairingUnsignedToken::new(message);
+        let (r, anonymized_token) = PairingTokenEngine::randomize(&unsigned_token);
+
+        // The signer signs with the new key, and fills in the key epoch
+        let signed = PairingTokenEngine::sign_randomized(&anonymized_token, &new_secret_key)
+            .unwrap()
+            .with_key_epoch(2);
+
+        let serialized = serde_json::to_string(&signed).unwrap();
+
+        let signed_token = PairingTokenEngine::verify_signature_and_unrandomize_with_key_set(
+            unsigned_token,
+            anonymized_token.clone(),
+            serde_json::from_str(&serialized).unwrap(),
+            &keys,
+            r,
+        )
+        .unwrap();
+
+        assert!(signed_token.verify(keys.get(2).unwrap()));
+
+        // The verifier finds the key by the key id in the token
+        let mut key_ring = KeyRing::new();
+        key_ring.insert(1, PublicKey::from(&old_secret_key));
+        key_ring.insert(2, PublicKey::from(&new_secret_key));
+        assert_eq!(signed_token.key_id(), Some(new_secret_key.key_id()));
+        assert!(PairingTokenEngine::verify_with_key_ring(&signed_token, &key_ring).is_ok());
+
+        // and tokens from before key ids are checked against all the keys
+        let mut old_token = serde_json::to_value(&signed_token).unwrap();
+        old_token.as_object_mut().unwrap().remove("key_id");
+        let old_token: PairingSignedToken<Box<[u8]>> = serde_json::from_value(old_token).unwrap();
+        assert_eq!(old_token.key_id(), None);
+        assert!(key_ring.verify(&old_token));
+
+        // The old key set does not have the key the token is signed with
+        let unsigned_token = PairingUnsignedToken::new(message);
+        let (r, anonymized_token) = PairingTokenEngine::randomize(&unsigned_token);
+        let signed = PairingTokenEngine::sign_randomized(&anonymized_token, &new_secret_key)
+            .unwrap()
+            .with_key_epoch(2);
+
+        assert!(
+            PairingTokenEngine::verify_signature_and_unrandomize_with_key_set(
+                unsigned_token,
+                anonymized_token,
+                signed,
+                &old_keys,
+                r,
+            )
+            .is_err()
+        );
+    }
 }
 
 // }}}
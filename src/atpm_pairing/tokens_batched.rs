@@ -1,20 +1,23 @@
-use core::{convert::TryInto, iter::repeat_with, marker::PhantomData};
+use core::{convert::TryFrom, marker::PhantomData};
 
 use alloc::{boxed::Box, vec::Vec};
-use bls12_381::{Bls12, G1Affine, G1Projective, G2Affine, G2Projective, Scalar};
-use pairing::Engine;
-use rand::{prelude::StdRng, SeedableRng};
+use bls12_381::{G1Affine, G1Projective, G2Affine, G2Projective, Scalar};
+use rand::{prelude::StdRng, CryptoRng, RngCore, SeedableRng};
 // use serde::{Deserialize, Serialize};
 
 use crate::{
-    atpm_pairing::util::random_vartime, common::fill_bytes, RandomizedUnsignedToken, SignedToken,
-    TokenEngine, UnsignedToken,
+    array::BoxedArray,
+    atpm_pairing::util::random_vartime,
+    chunked::par_map,
+    common::{check_batch_response, check_metadata, fill_bytes, invertible},
+    BatchResponseError, Error, HasKeyId, KeyEpoch, KeyId, RandomizedSignedToken,
+    RandomizedUnsignedToken, SignedToken, TokenEngine, UnsignedToken, VerifyError,
 };
 
 use super::{
     keys::{PrivateKey, PublicKey},
-    tokens::PairingSignedToken,
-    util::{h_1, h_m, random_biased, CurvePoint},
+    tokens::{check_collect, verify_batch_failures, PairingSignedToken},
+    util::{h_1, h_m, pairing_check, random_biased, CurvePoint},
     TokenIdentifier,
 };
 
@@ -22,34 +25,31 @@ use super::{
 
 // #[derive(Serialize, Deserialize)]
 pub struct BatchedPairingUnsignedToken<M: AsRef<[u8]>, const N: usize> {
-    ids: [TokenIdentifier<M>; N],
+    ids: BoxedArray<TokenIdentifier<M>, N>,
     metadata: M,
 }
 
-impl<M: AsRef<[u8]>, const N: usize> UnsignedToken for BatchedPairingUnsignedToken<M, N> {
+impl<M: AsRef<[u8]> + Clone, const N: usize> UnsignedToken for BatchedPairingUnsignedToken<M, N> {
     type HiddenMetadata = M;
     type Metadata = M;
 
-    fn new(metadata: Self::Metadata) -> Self {
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
         Self {
-            ids: TokenIdentifier::generate(),
+            ids: TokenIdentifier::generate_with_rng(rng),
             metadata,
         }
     }
 
-    /// This is unimplemented, since there is some trouble that the hidden metadata is used in
-    /// several tokenidentifiers
-    fn with_hidden(_metadata: Self::Metadata, _hidden: Self::HiddenMetadata) -> Self {
-        todo!()
-        // Self {
-        //     ids: repeat_with(move || TokenIdentifier::with_hidden(&hidden))
-        //         .take(N)
-        //         .collect::<Vec<_>>()
-        //         .try_into()
-        //         .ok()
-        //         .unwrap(),
-        //     metadata,
-        // }
+    /// The hidden metadata is the same for all the tokens in the batch
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
+        Self {
+            ids: TokenIdentifier::generate_with_hidden_with_rng(hidden, rng),
+            metadata,
+        }
     }
 }
 
@@ -58,7 +58,7 @@ impl<M: AsRef<[u8]>, const N: usize> UnsignedToken for BatchedPairingUnsignedTok
 // {{{ Randomized unsigned
 
 pub struct BatchedRandomizedUnsignedToken<M, const N: usize> {
-    points: [CurvePoint; N],
+    points: BoxedArray<CurvePoint, N>,
     metadata: Box<[u8]>,
     _m: PhantomData<M>,
 }
@@ -76,21 +76,31 @@ impl<M: AsRef<[u8]>, const N: usize> RandomizedUnsignedToken
 // {{{ Randomized Signed token
 
 pub struct BatchedRandomizedSignedToken<M, const N: usize> {
-    points: [CurvePoint; N],
+    points: BoxedArray<CurvePoint, N>,
     // metadata: Box<[u8]>,
+    key_epoch: Option<KeyEpoch>,
     _m: PhantomData<M>,
 }
 
+impl<M, const N: usize> RandomizedSignedToken for BatchedRandomizedSignedToken<M, N> {
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
 impl<M: AsRef<[u8]>, const N: usize> Default for BatchedRandomizedSignedToken<M, N> {
     fn default() -> Self {
         Self {
-            points: repeat_with(|| CurvePoint::from(G1Affine::identity()))
-                .take(N)
-                .collect::<Vec<_>>()
-                .try_into()
-                .ok()
-                .unwrap(),
+            points: BoxedArray::from_fn(|_i| CurvePoint::from(G1Affine::identity())),
             // metadata: Box::from([]),
+            key_epoch: None,
             _m: PhantomData {},
         }
     }
@@ -101,9 +111,10 @@ impl<M: AsRef<[u8]>, const N: usize> Default for BatchedRandomizedSignedToken<M,
 // {{{ Signed token
 
 pub struct BatchedPairingSignedToken<M: AsRef<[u8]>, const N: usize> {
-    ids: [TokenIdentifier<M>; N],
+    ids: BoxedArray<TokenIdentifier<M>, N>,
     metadata: M,
-    signatures: [CurvePoint; N],
+    signatures: BoxedArray<CurvePoint, N>,
+    key_id: Option<KeyId>,
 }
 
 impl<M: AsRef<[u8]>, const N: usize> BatchedPairingSignedToken<M, N> {
@@ -113,28 +124,46 @@ impl<M: AsRef<[u8]>, const N: usize> BatchedPairingSignedToken<M, N> {
             place: 0,
         }
     }
+
+    /// Find the tokens of the batch that do not verify, such that only those are thrown away
+    ///
+    /// Returns the indices of the bad tokens, which is empty if the batch verifies. The batch is
+    /// halved until the halves verify, see [`verify_batch_failures`].
+    pub fn verify_detailed(&self, verification_key: &PublicKey) -> Vec<usize>
+    where
+        M: Clone,
+    {
+        verify_batch_failures(&self.iter().collect::<Vec<_>>(), verification_key)
+    }
 }
 
-impl<M: AsRef<[u8]> + core::fmt::Debug, const N: usize> From<[PairingSignedToken<M>; N]>
+impl<M: AsRef<[u8]> + core::fmt::Debug, const N: usize> TryFrom<[PairingSignedToken<M>; N]>
     for BatchedPairingSignedToken<M, N>
 {
-    fn from(tokens: [PairingSignedToken<M>; N]) -> Self {
-        let (ids, signatures, metadata) = IntoIterator::into_iter(tokens).fold(
-            (Vec::new(), Vec::new(), None),
-            |(mut ids, mut signs, _metadata), s| {
-                let (id, point, metadata) = s.unpack();
-                ids.push(id);
-                signs.push(point);
-                (ids, signs, Some(metadata))
-            },
-        );
+    type Error = Error;
+
+    /// Collect single tokens into a batch
+    ///
+    /// The tokens must have the same metadata and key id, and `N` must not be zero, see
+    /// [`DynBatchedPairingSignedToken`](super::tokens_batched_dyn::DynBatchedPairingSignedToken).
+    fn try_from(tokens: [PairingSignedToken<M>; N]) -> Result<Self, Error> {
+        check_collect(&tokens)?;
+        let (mut metadata, mut key_id) = (None, None);
+        let (ids, signatures) = BoxedArray::from(tokens)
+            .into_map(|token| {
+                let (id, point, token_metadata, token_key_id) = token.unpack();
+                metadata = Some(token_metadata);
+                key_id = token_key_id;
+                (id, point)
+            })
+            .unzip();
 
-        // Is ok to unwrap, since there are exactly N elements in array
-        Self {
-            ids: ids.try_into().unwrap(),
-            signatures: signatures.try_into().unwrap(),
-            metadata: metadata.unwrap(),
-        }
+        Ok(Self {
+            ids,
+            signatures,
+            metadata: metadata.ok_or(Error::EmptyBatch)?,
+            key_id,
+        })
     }
 }
 
@@ -142,31 +171,39 @@ impl<M: AsRef<[u8]>, const N: usize> SignedToken for BatchedPairingSignedToken<M
     type VerificationKey = PublicKey;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
 
-        let (t, w) = self
+        let terms = self
             .ids
             .iter()
             .zip(self.signatures.iter())
-            .zip(core::iter::repeat_with(|| random_biased(&mut rng))) // may use biased, since it only needs to be unpredictable
-            .fold(
-                (G1Projective::identity(), G1Projective::identity()),
-                |(tsum, wsum), ((id, w), r)| {
-                    let t: [u8; 16] = id.into();
-                    (
-                        tsum + h_1(t, &self.metadata) * r,
-                        wsum + G1Affine::from(w) * r,
-                    )
-                },
-            );
+            .map(|(id, w)| (<[u8; 16]>::from(id), w, random_biased(&mut rng))) // may use biased, since it only needs to be unpredictable
+            .collect::<Vec<_>>();
+
+        let metadata = self.metadata.as_ref();
+        let (t, w) = par_map(&terms, |(t, w, r)| {
+            (h_1(t, metadata) * r, G1Affine::from(*w) * r)
+        })
+        .into_iter()
+        .fold(
+            (G1Projective::identity(), G1Projective::identity()),
+            |(tsum, wsum), (t, w)| (tsum + t, wsum + w),
+        );
 
         // get the public key and other useful points on the curve
         let pk = G2Affine::from(verification_key);
-        let u = (G2Affine::generator() * h_m(&self.metadata) + pk).into();
+        let u = G2Affine::generator() * h_m(&self.metadata) + pk;
 
         // Verify that the signature is from the provided public key
-        Bls12::pairing(&G1Affine::from(w), &u)
-            == Bls12::pairing(&G1Affine::from(t), &G2Affine::generator())
+        pairing_check(w, u, t)
+    }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn key_id(&self) -> Option<KeyId> {
+        self.key_id
     }
 }
 
@@ -190,11 +227,10 @@ fn verify_no_lin_comb<M: AsRef<[u8]>, const N: usize>(
 
     // get the public key and other useful points on the curve
     let pk = G2Affine::from(key);
-    let u = (G2Affine::generator() * h_m(&token.metadata) + pk).into();
+    let u = G2Affine::generator() * h_m(&token.metadata) + pk;
 
     // Verify that the signature is from the provided public key
-    Bls12::pairing(&G1Affine::from(&w), &u)
-        == Bls12::pairing(&G1Affine::from(&t), &G2Affine::generator())
+    pairing_check(w, u, t)
 }
 
 pub struct BatchedPairingSignedTokenIterator<'a, M: AsRef<[u8]>, const N: usize> {
@@ -212,6 +248,7 @@ impl<'a, M: AsRef<[u8]> + Clone, const N: usize> Iterator
                 self.tokens.ids[self.place].clone(),
                 self.tokens.signatures[self.place].clone(),
                 self.tokens.metadata.clone(),
+                self.tokens.key_id,
             );
             self.place += 1;
             Some(token)
@@ -225,10 +262,109 @@ impl<'a, M: AsRef<[u8]> + Clone, const N: usize> Iterator
 
 // {{{ Token engine
 
+/// The next r of the series, the zeros are skipped, since they are not invertible
+///
+/// Only the zero is drawn again, not the whole series, so [`next_r_inverse`] of the same series
+/// gives the inverses of the same r's.
+fn next_r<R: CryptoRng + RngCore>(series: &mut R) -> Scalar {
+    loop {
+        let r = random_vartime(series);
+        if r != Scalar::zero() {
+            return r;
+        }
+    }
+}
+
+/// The inverse of the next r of the series, see [`next_r`]
+fn next_r_inverse<R: CryptoRng + RngCore>(series: &mut R) -> Scalar {
+    loop {
+        if let Some(inverse) = Option::from(random_vartime(series).invert()) {
+            return inverse;
+        }
+    }
+}
+
 pub struct BatchedPairingTokenEngine<M: AsRef<[u8]> + Clone, const N: usize> {
     _m: PhantomData<M>,
 }
 
+impl<M: AsRef<[u8]> + Clone, const N: usize> BatchedPairingTokenEngine<M, N> {
+    /// Check that the signer returned one point for each point sent, that they are distinct, and
+    /// that none of them are the identity.
+    ///
+    /// This is done by `verify_signature_and_unrandomize`, but may be used to find out why a
+    /// response was rejected.
+    pub fn check_response(
+        randomized_unsigned: &BatchedRandomizedUnsignedToken<M, N>,
+        signed_token: &BatchedRandomizedSignedToken<M, N>,
+    ) -> Result<(), BatchResponseError> {
+        check_batch_response(
+            randomized_unsigned.points.len(),
+            signed_token
+                .points
+                .iter()
+                .map(|point| G1Affine::from(point).to_compressed()),
+            G1Affine::identity().to_compressed(),
+        )
+    }
+
+    /// The signatures without the randomization, if they verify
+    fn unrandomize(
+        unsigned_token: &BatchedPairingUnsignedToken<M, N>,
+        randomized_unsigned: &BatchedRandomizedUnsignedToken<M, N>,
+        signed_token: &BatchedRandomizedSignedToken<M, N>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+    ) -> Result<BoxedArray<CurvePoint, N>, Error> {
+        check_metadata(&unsigned_token.metadata, &randomized_unsigned.metadata)?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned, signed_token)?;
+
+        // the public key point
+        let pk: G2Affine = <&PublicKey>::into(verification_data);
+        let u_point: G2Projective = G2Affine::generator() * h_m(&unsigned_token.metadata) + pk;
+
+        // seed an rng for the series of r
+        let mut rng = StdRng::from_seed(randomization);
+
+        // remove randomization from w
+        // this will in addition work as a random linear combination of the signatures to make sure
+        // that the signer has not given a bad batch
+        let items = signed_token
+            .points
+            .zip_map(&unsigned_token.ids, |w_prime, id| {
+                (
+                    next_r(&mut rng),
+                    G1Affine::from(w_prime),
+                    <[u8; 16]>::from(id),
+                )
+            });
+
+        let metadata = unsigned_token.metadata.as_ref();
+        let (signatures, t_list) = items
+            .par_map(|(r, w_prime, t)| (G1Affine::from(w_prime * r), h_1(t, metadata)))
+            .unzip();
+
+        // sum the w's
+        let w = signatures
+            .iter()
+            .fold(G1Projective::identity(), |s, w| s + w);
+
+        // Sum the t's
+        let t = t_list
+            .into_iter()
+            .fold(G1Projective::identity(), |s, t| s + t);
+
+        // Verify that the signature is correct
+        if pairing_check(w, u_point, t) {
+            Ok(signatures.into_map(CurvePoint::from))
+        } else {
+            Err(Error::BadSignature)
+        }
+    }
+}
+
 impl<M: AsRef<[u8]> + Clone, const N: usize> TokenEngine for BatchedPairingTokenEngine<M, N> {
     type UnsignedToken = BatchedPairingUnsignedToken<M, N>;
     type RandomizedUnsignedToken = BatchedRandomizedUnsignedToken<M, N>;
@@ -239,139 +375,80 @@ impl<M: AsRef<[u8]> + Clone, const N: usize> TokenEngine for BatchedPairingToken
     type UserVerification = PublicKey;
     type SignKey = PrivateKey;
 
-    fn randomize(
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
         unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
         // create random seed
         let mut randomization = [0; 32];
-        fill_bytes(&mut rand::thread_rng(), &mut randomization);
+        fill_bytes(rng, &mut randomization);
 
         // seed an rng for the series of r
         let mut rng = StdRng::from_seed(randomization);
 
-        let nums = repeat_with(|| random_vartime(&mut rng)) // generate random r's
-            .take(N)
-            .map(|r| r.invert())
-            .fold(Some(Vec::new()), |v, e| match v {
-                // Fold down the list to remove failed random numbers,
-                // should be None
-                Some(mut v) => {
-                    if bool::from(e.is_some()) {
-                        v.push(e.unwrap());
-                        Some(v)
-                    } else {
-                        None
-                    }
-                }
-                None => None,
-            });
+        // generate the inverses of the random r's
+        let nums = BoxedArray::<Scalar, N>::from_fn(|_i| next_r_inverse(&mut rng));
 
-        if nums.is_none() {
-            Self::randomize(unsigned_token)
-        } else {
-            (
-                randomization,
-                BatchedRandomizedUnsignedToken {
-                    points: nums
-                        .unwrap()
-                        .into_iter()
-                        .zip(unsigned_token.ids.iter())
-                        .map(|(r, id)| {
-                            let t: [u8; 16] = id.into();
-                            // T' = [r]T
-                            h_1(t, &unsigned_token.metadata) * r
-                        })
-                        .map(|t| G1Affine::from(t).into())
-                        .collect::<Vec<_>>()
-                        .try_into()
-                        .ok()
-                        .unwrap(),
-                    metadata: Box::from(unsigned_token.metadata.as_ref()),
-                    _m: PhantomData {},
-                },
-            )
-        }
+        (
+            randomization,
+            BatchedRandomizedUnsignedToken {
+                points: nums.zip_map(&unsigned_token.ids, |r, id| {
+                    let t: [u8; 16] = id.into();
+                    // T' = [r]T
+                    G1Affine::from(h_1(t, &unsigned_token.metadata) * r).into()
+                }),
+                metadata: Box::from(unsigned_token.metadata.as_ref()),
+                _m: PhantomData {},
+            },
+        )
     }
 
-    fn sign_randomized(
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
         randomized_unsigned: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> subtle::CtOption<Self::RandomizedSignedToken> {
+        _rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
         // This should be a constant time implementation
         let d = h_m(&randomized_unsigned.metadata);
         let k: Scalar = <&PrivateKey>::into(sign_key);
-        (d + k)
-            .invert()
-            .map(|inverse| BatchedRandomizedSignedToken {
+        invertible((d + k).invert().map(|inverse| {
+            BatchedRandomizedSignedToken {
                 // metadata: randomized_unsigned.metadata.clone(),
+                key_epoch: None,
                 _m: PhantomData {},
                 points: randomized_unsigned
                     .points
-                    .iter()
-                    .map(|point| G1Affine::from(point) * inverse)
-                    .map(|w_prime| G1Affine::from(w_prime).into())
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .ok()
-                    .unwrap(),
-            })
+                    .par_map(|point| G1Affine::from(G1Affine::from(point) * inverse).into()),
+            }
+        }))
     }
 
     fn verify_signature_and_unrandomize(
         unsigned_token: Self::UnsignedToken,
-        _randomized_unsigned: Self::RandomizedUnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken> {
-        // the public key point
-        let pk: G2Affine = <&PublicKey>::into(verification_data);
-        let u_point: G2Projective = G2Affine::generator() * h_m(&unsigned_token.metadata) + pk;
-
-        // seed an rng for the series of r
-        let mut rng = StdRng::from_seed(randomization);
-
-        // remove randomization from w
-        // this will in addition work as a random linear combination of the signatures to make sure
-        // that the signer has not given a bad batch
-        let signatures = repeat_with(|| random_vartime(&mut rng))
-            .take(N)
-            .zip(signed_token.points.iter())
-            .map(|(r, w_prime)| G1Affine::from(w_prime) * r)
-            .collect::<Vec<_>>();
-
-        // sum the w's
-        let w = signatures
-            .iter()
-            .fold(G1Projective::identity(), |s, w| s + w);
-
-        // Sum the t's
-        let t = unsigned_token
-            .ids
-            .iter()
-            .map(|id| {
-                let t: [u8; 16] = id.into();
-                h_1(t, &unsigned_token.metadata)
-            })
-            .fold(G1Projective::identity(), |s, t| s + t);
-
-        // Verify that the signature is correct
-        if Bls12::pairing(&w.into(), &u_point.into())
-            == Bls12::pairing(&G1Affine::from(t), &G2Affine::generator())
-        {
-            Some(BatchedPairingSignedToken {
-                signatures: signatures
-                    .iter()
-                    .map(|w| G1Affine::from(w).into())
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .ok()
-                    .unwrap(),
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::unrandomize(
+            &unsigned_token,
+            &randomized_unsigned,
+            &signed_token,
+            verification_data,
+            randomization,
+        ) {
+            Ok(signatures) => Ok(BatchedPairingSignedToken {
+                signatures,
                 metadata: unsigned_token.metadata,
                 ids: unsigned_token.ids,
-            })
-        } else {
-            None
+                key_id: Some(verification_data.key_id()),
+            }),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned,
+                randomization,
+            )),
         }
     }
 }
@@ -382,6 +459,8 @@ impl<M: AsRef<[u8]> + Clone, const N: usize> TokenEngine for BatchedPairingToken
 
 #[cfg(test)]
 mod tests {
+    use core::convert::TryInto;
+
     use crate::atpm_pairing::tokens::{PairingTokenEngine, RandomizedUnsignedToken};
 
     use super::*;
@@ -400,10 +479,147 @@ mod tests {
         })
         .unwrap();
 
-        assert!(BatchedPairingTokenEngine::verify(&signed, &public_key));
+        assert!(BatchedPairingTokenEngine::verify(&signed, &public_key).is_ok());
+
+        for token in signed.iter() {
+            assert!(PairingTokenEngine::verify(&token, &public_key).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_verify_detailed() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = BatchedPairingTokenEngine::<_, 7>::generate(b"metadata");
+        let mut signed = BatchedPairingTokenEngine::sign(tokens, &public_key, |tokens| {
+            BatchedPairingTokenEngine::sign_randomized(tokens, &private_key)
+        })
+        .unwrap();
+
+        assert!(signed.verify_detailed(&public_key).is_empty());
+
+        // corrupted in the wallet
+        signed.signatures.swap(2, 5);
+        assert!(!signed.verify(&public_key));
+        assert_eq!(signed.verify_detailed(&public_key), [2, 5]);
+    }
+
+    #[test]
+    fn fail_collect() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let sign = |metadata: &'static [u8]| {
+            let tokens = BatchedPairingTokenEngine::<_, 2>::generate(metadata);
+            BatchedPairingTokenEngine::sign(tokens, &public_key, |tokens| {
+                BatchedPairingTokenEngine::sign_randomized(tokens, &private_key)
+            })
+            .unwrap()
+        };
+        let signed = sign(b"metadata");
+        let other = sign(b"other metadata");
+
+        let empty: [PairingSignedToken<&[u8]>; 0] = [];
+        assert_eq!(
+            BatchedPairingSignedToken::try_from(empty).err(),
+            Some(Error::EmptyBatch)
+        );
+
+        let mixed = [signed.iter().next().unwrap(), other.iter().next().unwrap()];
+        assert_eq!(
+            BatchedPairingSignedToken::try_from(mixed).err(),
+            Some(Error::MetadataMismatch)
+        );
+
+        let mut tokens = signed.iter();
+        let same = [tokens.next().unwrap(), tokens.next().unwrap()];
+        assert!(BatchedPairingSignedToken::try_from(same)
+            .unwrap()
+            .verify(&public_key));
+    }
+
+    #[test]
+    fn test_hidden() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = BatchedPairingTokenEngine::<_, 5>::generate_with_hidden(
+            &b"metadata"[..],
+            &b"hidden"[..],
+        );
+
+        // the hidden metadata is hashed with a different id for each token
+        for (i, id) in tokens.ids.iter().enumerate() {
+            assert!(matches!(id.hidden(), Some(hidden) if hidden == b"hidden"));
+            assert!(tokens.ids[..i].iter().all(|other| other != id));
+        }
+
+        let signed = BatchedPairingTokenEngine::sign(tokens, &public_key, |tokens| {
+            BatchedPairingTokenEngine::sign_randomized(tokens, &private_key)
+        })
+        .unwrap();
+
+        assert!(BatchedPairingTokenEngine::verify(&signed, &public_key).is_ok());
 
         for token in signed.iter() {
-            assert!(PairingTokenEngine::verify(&token, &public_key));
+            assert!(PairingTokenEngine::verify(&token, &public_key).is_ok());
+        }
+    }
+
+    /// The series of a seeded rng, after some zero bytes
+    struct ZerosFirst {
+        zeros: usize,
+        series: StdRng,
+    }
+
+    impl RngCore for ZerosFirst {
+        fn next_u32(&mut self) -> u32 {
+            if self.zeros > 0 {
+                self.zeros -= 1;
+                0
+            } else {
+                self.series.next_u32()
+            }
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            u64::from(self.next_u32()) << 32 | u64::from(self.next_u32())
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.iter_mut()
+                .for_each(|byte| *byte = self.next_u32() as u8);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for ZerosFirst {}
+
+    #[test]
+    fn test_retry_zero_r() {
+        let zeros_first = || ZerosFirst {
+            zeros: 32,
+            series: StdRng::seed_from_u64(7),
+        };
+
+        // the first r is zero, and only that one is drawn again
+        assert_eq!(random_vartime(&mut zeros_first()), Scalar::zero());
+        let r = random_vartime(&mut StdRng::seed_from_u64(7));
+        assert_eq!(next_r(&mut zeros_first()), r);
+        assert_eq!(next_r_inverse(&mut zeros_first()) * r, Scalar::one());
+
+        // the same series of r's and their inverses
+        let (mut rs, mut inverses) = (zeros_first(), zeros_first());
+        for _i in 0..4 {
+            assert_eq!(
+                next_r(&mut rs) * next_r_inverse(&mut inverses),
+                Scalar::one()
+            );
         }
     }
 
@@ -423,7 +639,7 @@ mod tests {
             BatchedPairingTokenEngine::sign(tokens, &public_key, |tokens| {
                 BatchedPairingTokenEngine::sign_randomized(tokens, &wrong_private_key)
             })
-            .is_none()
+            .is_err()
         );
     }
 
@@ -444,13 +660,43 @@ mod tests {
         let fake_private = PrivateKey::new();
         let fake_public = PublicKey::from(&fake_private);
 
-        assert!(!BatchedPairingTokenEngine::verify(&signed, &fake_public));
+        assert!(BatchedPairingTokenEngine::verify(&signed, &fake_public).is_err());
 
         for token in signed.iter() {
-            assert!(!PairingTokenEngine::verify(&token, &fake_public));
+            assert!(PairingTokenEngine::verify(&token, &fake_public).is_err());
         }
     }
 
+    #[test]
+    fn fail_tagged_response() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = BatchedPairingTokenEngine::<_, 5>::generate(b"metadata");
+
+        let (r, randomized) = BatchedPairingTokenEngine::randomize(&tokens);
+
+        let mut signed =
+            BatchedPairingTokenEngine::sign_randomized(&randomized, &private_key).unwrap();
+
+        // the signer tags the user by returning one of the points twice
+        signed.points[1] = signed.points[0].clone();
+
+        assert_eq!(
+            BatchedPairingTokenEngine::check_response(&randomized, &signed),
+            Err(BatchResponseError::DuplicatePoint)
+        );
+
+        assert!(BatchedPairingTokenEngine::verify_signature_and_unrandomize(
+            tokens,
+            randomized,
+            signed,
+            &public_key,
+            r
+        )
+        .is_err());
+    }
+
     #[test]
     fn attack_no_lincomb() {
         const N: usize = 50;
@@ -468,7 +714,7 @@ mod tests {
                 (s + point, l)
             });
 
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
 
         let r = random_biased(&mut rng);
 
@@ -498,13 +744,13 @@ mod tests {
         let tokens: [PairingSignedToken<_>; N] = [G1Affine::from(w)]
             .iter()
             .chain(a.iter())
-            .zip(l.into_iter())
+            .zip(l)
             .map(|(w, t)| t.get_signed(CurvePoint::from(w)))
             .collect::<Vec<_>>()
             .try_into()
             .unwrap();
 
-        let btoken = BatchedPairingSignedToken::<_, N>::from(tokens);
+        let btoken = BatchedPairingSignedToken::<_, N>::try_from(tokens).unwrap();
 
         assert!(!btoken.verify(&public_key));
         assert!(verify_no_lin_comb(&btoken, &public_key));
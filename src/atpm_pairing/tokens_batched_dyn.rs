@@ -0,0 +1,1008 @@
+//! Batched tokens where the size of the batch is picked at runtime
+//!
+//! This is the same protocol as [`super::tokens_batched`], but the tokens are stored in vectors,
+//! so a server may pick the batch size from a config file or from the request.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens_batched_dyn::DynBatchedPairingTokenEngine,
+//!     };
+//!
+//!     let private_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&private_key);
+//!
+//!     // the metadata and the size of the batch
+//!     let tokens = DynBatchedPairingTokenEngine::generate((&b"metadata"[..], 7));
+//!
+//!     let signed = DynBatchedPairingTokenEngine::sign(tokens, &public_key, |randomized| {
+//!         DynBatchedPairingTokenEngine::sign_randomized(randomized, &private_key)
+//!     })
+//!     .unwrap();
+//!
+//!     assert_eq!(signed.len(), 7);
+//!     assert!(DynBatchedPairingTokenEngine::verify(&signed, &public_key).is_ok());
+//! ```
+
+use core::{convert::TryFrom, future::Future, iter::repeat_with, marker::PhantomData};
+
+use alloc::{boxed::Box, vec::Vec};
+use bls12_381::{G1Affine, G1Projective, G2Affine, G2Projective, Scalar};
+use rand::{prelude::StdRng, CryptoRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    atpm_pairing::util::random_vartime,
+    chunked::{par_map, Chunking, Chunks},
+    common::{check_batch_response, check_metadata, fill_bytes, invertible},
+    BatchResponseError, Error, HasKeyId, KeyEpoch, KeyId, RandomizedSignedToken,
+    RandomizedUnsignedToken, SignedToken, TokenEngine, UnsignedToken, VerifyError,
+};
+
+use super::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{check_collect, PairingSignedToken},
+    util::{h_1, h_m, pairing_check, random_biased, CurvePoint},
+    TokenIdentifier,
+};
+
+// {{{ Unsigned token
+
+pub struct DynBatchedPairingUnsignedToken<M: AsRef<[u8]>> {
+    ids: Vec<TokenIdentifier<M>>,
+    metadata: M,
+}
+
+impl<M: AsRef<[u8]>> DynBatchedPairingUnsignedToken<M> {
+    /// The number of tokens in the batch
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> UnsignedToken for DynBatchedPairingUnsignedToken<M> {
+    type HiddenMetadata = M;
+    /// The metadata, and the number of tokens in the batch
+    type Metadata = (M, usize);
+
+    fn new_with_rng<R: CryptoRng + RngCore>((metadata, size): Self::Metadata, rng: &mut R) -> Self {
+        Self {
+            ids: repeat_with(|| TokenIdentifier::new_with_rng(rng))
+                .take(size)
+                .collect(),
+            metadata,
+        }
+    }
+
+    /// The hidden metadata is the same for all the tokens in the batch
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        (metadata, size): Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
+        Self {
+            ids: repeat_with(|| TokenIdentifier::with_hidden_with_rng(hidden.clone(), rng))
+                .take(size)
+                .collect(),
+            metadata,
+        }
+    }
+}
+
+// }}}
+
+// {{{ Randomized unsigned
+
+#[derive(Serialize, Deserialize)]
+pub struct DynBatchedRandomizedUnsignedToken<M> {
+    points: Vec<CurvePoint>,
+    metadata: Box<[u8]>,
+    _m: PhantomData<M>,
+}
+
+impl<M> DynBatchedRandomizedUnsignedToken<M> {
+    /// The number of tokens the user asks to be signed
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+impl<M: AsRef<[u8]>> RandomizedUnsignedToken for DynBatchedRandomizedUnsignedToken<M> {
+    fn metadata(&self) -> Box<[u8]> {
+        self.metadata.clone()
+    }
+}
+
+// }}}
+
+// {{{ Randomized Signed token
+
+#[derive(Serialize, Deserialize)]
+pub struct DynBatchedRandomizedSignedToken<M> {
+    points: Vec<CurvePoint>,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
+    _m: PhantomData<M>,
+}
+
+impl<M> RandomizedSignedToken for DynBatchedRandomizedSignedToken<M> {
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
+// }}}
+
+// {{{ Signed token
+
+pub struct DynBatchedPairingSignedToken<M: AsRef<[u8]>> {
+    ids: Vec<TokenIdentifier<M>>,
+    metadata: M,
+    signatures: Vec<CurvePoint>,
+    key_id: Option<KeyId>,
+}
+
+impl<M: AsRef<[u8]>> DynBatchedPairingSignedToken<M> {
+    /// The number of tokens in the batch
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The single tokens of the batch
+    pub fn iter(&self) -> impl Iterator<Item = PairingSignedToken<M>> + '_
+    where
+        M: Clone,
+    {
+        self.ids
+            .iter()
+            .zip(self.signatures.iter())
+            .map(move |(id, signature)| {
+                PairingSignedToken::create(
+                    id.clone(),
+                    signature.clone(),
+                    self.metadata.clone(),
+                    self.key_id,
+                )
+            })
+    }
+}
+
+impl<M: AsRef<[u8]>> TryFrom<Vec<PairingSignedToken<M>>> for DynBatchedPairingSignedToken<M> {
+    type Error = Error;
+
+    /// Collect single tokens into a batch
+    ///
+    /// The tokens must have the same metadata and key id, else this fails with
+    /// [`MetadataMismatch`](Error::MetadataMismatch) or [`KeyMismatch`](Error::KeyMismatch), and
+    /// there must be at least one, else it fails with [`EmptyBatch`](Error::EmptyBatch).
+    fn try_from(tokens: Vec<PairingSignedToken<M>>) -> Result<Self, Error> {
+        check_collect(&tokens)?;
+        let (ids, signatures, metadata, key_id) = tokens.into_iter().fold(
+            (Vec::new(), Vec::new(), None, None),
+            |(mut ids, mut signs, _metadata, _key_id), s| {
+                let (id, point, metadata, key_id) = s.unpack();
+                ids.push(id);
+                signs.push(point);
+                (ids, signs, Some(metadata), key_id)
+            },
+        );
+
+        Ok(Self {
+            ids,
+            signatures,
+            metadata: metadata.ok_or(Error::EmptyBatch)?,
+            key_id,
+        })
+    }
+}
+
+impl<M: AsRef<[u8]>> DynBatchedPairingSignedToken<M> {
+    /// The terms of the random linear combination that is checked when verifying
+    fn weighted(
+        metadata: &[u8],
+        t: [u8; 16],
+        w: &CurvePoint,
+        r: Scalar,
+    ) -> (G1Projective, G1Projective) {
+        (h_1(t, metadata) * r, G1Affine::from(w) * r)
+    }
+
+    fn check_combination(
+        &self,
+        terms: Vec<(G1Projective, G1Projective)>,
+        verification_key: &PublicKey,
+    ) -> bool {
+        let (t, w) = terms.into_iter().fold(
+            (G1Projective::identity(), G1Projective::identity()),
+            |(tsum, wsum), (t, w)| (tsum + t, wsum + w),
+        );
+
+        // get the public key and other useful points on the curve
+        let pk = G2Affine::from(verification_key);
+        let u = G2Affine::generator() * h_m(&self.metadata) + pk;
+
+        // Verify that the signature is from the provided public key
+        pairing_check(w, u, t)
+    }
+
+    /// [`SignedToken::verify`], yielding between the chunks of the batch
+    ///
+    /// A cancelled verification does not verify.
+    pub async fn verify_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        &self,
+        verification_key: &PublicKey,
+        chunking: &mut Chunking<Y>,
+    ) -> bool {
+        if self.ids.len() != self.signatures.len() {
+            return false;
+        }
+
+        let mut rng = crate::rng::default_rng();
+        let terms = chunking
+            .map(
+                self.ids
+                    .iter()
+                    .zip(self.signatures.iter())
+                    .zip(repeat_with(|| random_biased(&mut rng))),
+                |((id, w), r)| Self::weighted(self.metadata.as_ref(), id.into(), w, r),
+            )
+            .await;
+
+        terms.is_ok_and(|terms| self.check_combination(terms, verification_key))
+    }
+}
+
+impl<M: AsRef<[u8]>> SignedToken for DynBatchedPairingSignedToken<M> {
+    type VerificationKey = PublicKey;
+
+    fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
+        if self.ids.len() != self.signatures.len() {
+            return false;
+        }
+
+        let mut rng = crate::rng::default_rng();
+
+        let items = self
+            .ids
+            .iter()
+            .zip(self.signatures.iter())
+            .map(|(id, w)| (<[u8; 16]>::from(id), w, random_biased(&mut rng))) // may use biased, since it only needs to be unpredictable
+            .collect::<Vec<_>>();
+
+        let metadata = self.metadata.as_ref();
+        let terms = par_map(&items, |(t, w, r)| Self::weighted(metadata, *t, w, *r));
+
+        self.check_combination(terms, verification_key)
+    }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn key_id(&self) -> Option<KeyId> {
+        self.key_id
+    }
+}
+
+// }}}
+
+// {{{ Token engine
+
+pub struct DynBatchedPairingTokenEngine<M: AsRef<[u8]> + Clone> {
+    _m: PhantomData<M>,
+}
+
+impl<M: AsRef<[u8]> + Clone> DynBatchedPairingTokenEngine<M> {
+    /// Check that the signer returned one point for each point sent, that they are distinct, and
+    /// that none of them are the identity.
+    ///
+    /// This is done by `verify_signature_and_unrandomize`, but may be used to find out why a
+    /// response was rejected.
+    pub fn check_response(
+        randomized_unsigned: &DynBatchedRandomizedUnsignedToken<M>,
+        signed_token: &DynBatchedRandomizedSignedToken<M>,
+    ) -> Result<(), BatchResponseError> {
+        check_batch_response(
+            randomized_unsigned.points.len(),
+            signed_token
+                .points
+                .iter()
+                .map(|point| G1Affine::from(point).to_compressed()),
+            G1Affine::identity().to_compressed(),
+        )
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> TokenEngine for DynBatchedPairingTokenEngine<M> {
+    type UnsignedToken = DynBatchedPairingUnsignedToken<M>;
+    type RandomizedUnsignedToken = DynBatchedRandomizedUnsignedToken<M>;
+    type RandomizedSignedToken = DynBatchedRandomizedSignedToken<M>;
+    type SignedToken = DynBatchedPairingSignedToken<M>;
+    type Randomization = [u8; 32];
+
+    type UserVerification = PublicKey;
+    type SignKey = PrivateKey;
+
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        let (randomization, nums) = Self::inverses(unsigned_token.ids.len(), rng);
+
+        (
+            randomization,
+            DynBatchedRandomizedUnsignedToken {
+                points: nums
+                    .into_iter()
+                    .zip(unsigned_token.ids.iter())
+                    .map(|(r, id)| Self::blind(id, &unsigned_token.metadata, r))
+                    .collect(),
+                metadata: Box::from(unsigned_token.metadata.as_ref()),
+                _m: PhantomData {},
+            },
+        )
+    }
+
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        _rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        // This should be a constant time implementation
+        let d = h_m(&randomized_unsigned.metadata);
+        let k: Scalar = <&PrivateKey>::into(sign_key);
+        invertible((d + k).invert().map(|inverse| {
+            let points = par_map(&randomized_unsigned.points, |point| {
+                G1Affine::from(G1Affine::from(point) * inverse).into()
+            });
+
+            DynBatchedRandomizedSignedToken {
+                key_epoch: None,
+                _m: PhantomData {},
+                points,
+            }
+        }))
+    }
+
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::unrandomize(
+            &unsigned_token,
+            &randomized_unsigned,
+            &signed_token,
+            verification_data,
+            randomization,
+        ) {
+            Ok(signatures) => Ok(Self::signed(unsigned_token, signatures, verification_data)),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned,
+                randomization,
+            )),
+        }
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> DynBatchedPairingTokenEngine<M> {
+    /// The seed of the randomization, and the inverses of the series of r
+    fn inverses<R: CryptoRng + RngCore>(len: usize, rng: &mut R) -> ([u8; 32], Vec<Scalar>) {
+        loop {
+            // create random seed
+            let mut randomization = [0; 32];
+            fill_bytes(rng, &mut randomization);
+
+            // seed an rng for the series of r
+            let mut series = StdRng::from_seed(randomization);
+
+            let nums = repeat_with(|| random_vartime(&mut series)) // generate random r's
+                .take(len)
+                .map(|r| r.invert())
+                // Collect the inverses, fails if one of the random numbers is not invertible
+                .map(Option::<Scalar>::from)
+                .collect::<Option<Vec<_>>>();
+
+            if let Some(nums) = nums {
+                return (randomization, nums);
+            }
+        }
+    }
+
+    /// T' = [r]T
+    fn blind(id: &TokenIdentifier<M>, metadata: &M, r: Scalar) -> CurvePoint {
+        let t: [u8; 16] = id.into();
+        G1Affine::from(h_1(t, metadata) * r).into()
+    }
+
+    fn unblind(w_prime: &CurvePoint, r: Scalar) -> CurvePoint {
+        G1Affine::from(G1Affine::from(w_prime) * r).into()
+    }
+
+    /// The signatures without the randomization, if they verify
+    fn unrandomize(
+        unsigned_token: &DynBatchedPairingUnsignedToken<M>,
+        randomized_unsigned: &DynBatchedRandomizedUnsignedToken<M>,
+        signed_token: &DynBatchedRandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+    ) -> Result<Vec<CurvePoint>, Error> {
+        check_metadata(&unsigned_token.metadata, &randomized_unsigned.metadata)?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned, signed_token)?;
+
+        // seed an rng for the series of r
+        let mut rng = StdRng::from_seed(randomization);
+
+        // remove randomization from w, the series of r is drawn in order first
+        let items = repeat_with(|| random_vartime(&mut rng))
+            .zip(signed_token.points.iter())
+            .zip(unsigned_token.ids.iter().map(<[u8; 16]>::from))
+            .collect::<Vec<_>>();
+
+        let metadata = unsigned_token.metadata.as_ref();
+        let (signatures, t_list) = par_map(&items, |((r, w_prime), t)| {
+            (Self::unblind(w_prime, *r), h_1(t, metadata))
+        })
+        .into_iter()
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        Self::check_signatures(unsigned_token, &signatures, t_list, verification_data)?;
+        Ok(signatures)
+    }
+
+    /// Check the unrandomized signatures against the hashes of the ids
+    fn check_signatures(
+        unsigned_token: &DynBatchedPairingUnsignedToken<M>,
+        signatures: &[CurvePoint],
+        t_list: Vec<G1Affine>,
+        verification_data: &PublicKey,
+    ) -> Result<(), Error> {
+        // the public key point
+        let pk: G2Affine = <&PublicKey>::into(verification_data);
+        let u_point: G2Projective = G2Affine::generator() * h_m(&unsigned_token.metadata) + pk;
+
+        // the r's of the randomization work in addition as a random linear combination of the
+        // signatures, to make sure that the signer has not given a bad batch
+
+        // sum the w's
+        let w = signatures
+            .iter()
+            .fold(G1Projective::identity(), |s, w| s + G1Affine::from(w));
+
+        // Sum the t's
+        let t = t_list
+            .into_iter()
+            .fold(G1Projective::identity(), |s, t| s + t);
+
+        // Verify that the signature is correct
+        if pairing_check(w, u_point, t) {
+            Ok(())
+        } else {
+            Err(Error::BadSignature)
+        }
+    }
+
+    /// The signed token of the checked signatures
+    fn signed(
+        unsigned_token: DynBatchedPairingUnsignedToken<M>,
+        signatures: Vec<CurvePoint>,
+        verification_data: &PublicKey,
+    ) -> DynBatchedPairingSignedToken<M> {
+        DynBatchedPairingSignedToken {
+            signatures,
+            metadata: unsigned_token.metadata,
+            ids: unsigned_token.ids,
+            key_id: Some(verification_data.key_id()),
+        }
+    }
+
+    /// [`TokenEngine::randomize`], yielding between the chunks of the batch
+    pub async fn randomize_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        unsigned_token: &DynBatchedPairingUnsignedToken<M>,
+        chunking: &mut Chunking<Y>,
+    ) -> Result<([u8; 32], DynBatchedRandomizedUnsignedToken<M>), Error> {
+        Self::randomize_chunked_with_rng(unsigned_token, chunking, &mut crate::rng::default_rng())
+            .await
+    }
+
+    /// [`Self::randomize_chunked`], with the randomness from the given rng
+    pub async fn randomize_chunked_with_rng<
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+        R: CryptoRng + RngCore,
+    >(
+        unsigned_token: &DynBatchedPairingUnsignedToken<M>,
+        chunking: &mut Chunking<Y>,
+        rng: &mut R,
+    ) -> Result<([u8; 32], DynBatchedRandomizedUnsignedToken<M>), Error> {
+        let (randomization, nums) = Self::inverses(unsigned_token.ids.len(), rng);
+
+        let points = chunking
+            .map(
+                nums.into_iter().zip(unsigned_token.ids.iter()),
+                |(r, id)| Self::blind(id, &unsigned_token.metadata, r),
+            )
+            .await?;
+
+        Ok((
+            randomization,
+            DynBatchedRandomizedUnsignedToken {
+                points,
+                metadata: Box::from(unsigned_token.metadata.as_ref()),
+                _m: PhantomData {},
+            },
+        ))
+    }
+
+    /// [`TokenEngine::sign_randomized`], yielding between the chunks of the batch
+    pub async fn sign_randomized_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        randomized_unsigned: &DynBatchedRandomizedUnsignedToken<M>,
+        sign_key: &PrivateKey,
+        chunking: &mut Chunking<Y>,
+    ) -> Result<DynBatchedRandomizedSignedToken<M>, Error> {
+        let d = h_m(&randomized_unsigned.metadata);
+        let k: Scalar = <&PrivateKey>::into(sign_key);
+        let inverse = invertible((d + k).invert())?;
+
+        let points = chunking
+            .map(randomized_unsigned.points.iter(), |point| {
+                G1Affine::from(G1Affine::from(point) * inverse).into()
+            })
+            .await?;
+
+        Ok(DynBatchedRandomizedSignedToken {
+            key_epoch: None,
+            _m: PhantomData {},
+            points,
+        })
+    }
+
+    /// [`TokenEngine::verify_signature_and_unrandomize`], yielding between the chunks of the batch
+    pub async fn verify_signature_and_unrandomize_chunked<
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+    >(
+        unsigned_token: DynBatchedPairingUnsignedToken<M>,
+        randomized_unsigned: DynBatchedRandomizedUnsignedToken<M>,
+        signed_token: DynBatchedRandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunking: &mut Chunking<Y>,
+    ) -> Result<DynBatchedPairingSignedToken<M>, VerifyError<Self>> {
+        match Self::unrandomize_chunked(
+            &unsigned_token,
+            &randomized_unsigned,
+            &signed_token,
+            verification_data,
+            randomization,
+            chunking,
+        )
+        .await
+        {
+            Ok(signatures) => Ok(Self::signed(unsigned_token, signatures, verification_data)),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned,
+                randomization,
+            )),
+        }
+    }
+
+    /// [`TokenEngine::verify_signature_and_unrandomize`], blocking, in chunks of the batch
+    ///
+    /// With the `parallel` feature, the chunks are unrandomized in parallel.
+    pub fn verify_signature_and_unrandomize_in_chunks(
+        unsigned_token: DynBatchedPairingUnsignedToken<M>,
+        randomized_unsigned: DynBatchedRandomizedUnsignedToken<M>,
+        signed_token: DynBatchedRandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunks: &Chunks<'_>,
+    ) -> Result<DynBatchedPairingSignedToken<M>, VerifyError<Self>>
+    where
+        M: Sync,
+    {
+        match Self::unrandomize_in_chunks(
+            &unsigned_token,
+            &randomized_unsigned,
+            &signed_token,
+            verification_data,
+            randomization,
+            chunks,
+        ) {
+            Ok(signatures) => Ok(Self::signed(unsigned_token, signatures, verification_data)),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned,
+                randomization,
+            )),
+        }
+    }
+
+    /// [`Self::unrandomize`], in chunks of the batch
+    fn unrandomize_in_chunks(
+        unsigned_token: &DynBatchedPairingUnsignedToken<M>,
+        randomized_unsigned: &DynBatchedRandomizedUnsignedToken<M>,
+        signed_token: &DynBatchedRandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunks: &Chunks<'_>,
+    ) -> Result<Vec<CurvePoint>, Error>
+    where
+        M: Sync,
+    {
+        check_metadata(&unsigned_token.metadata, &randomized_unsigned.metadata)?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned, signed_token)?;
+
+        // the series of r is drawn in order, before the chunks are split up
+        let mut rng = StdRng::from_seed(randomization);
+        let items = repeat_with(|| random_vartime(&mut rng))
+            .zip(signed_token.points.iter().zip(unsigned_token.ids.iter()))
+            .collect::<Vec<_>>();
+
+        let metadata = &unsigned_token.metadata;
+        let (signatures, t_list) = chunks
+            .map(&items, |(r, (w_prime, id))| {
+                let t: [u8; 16] = (*id).into();
+                (Self::unblind(w_prime, *r), h_1(t, metadata))
+            })
+            .into_iter()
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        Self::check_signatures(unsigned_token, &signatures, t_list, verification_data)?;
+        Ok(signatures)
+    }
+
+    /// [`Self::unrandomize`], yielding between the chunks of the batch
+    async fn unrandomize_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        unsigned_token: &DynBatchedPairingUnsignedToken<M>,
+        randomized_unsigned: &DynBatchedRandomizedUnsignedToken<M>,
+        signed_token: &DynBatchedRandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunking: &mut Chunking<Y>,
+    ) -> Result<Vec<CurvePoint>, Error> {
+        check_metadata(&unsigned_token.metadata, &randomized_unsigned.metadata)?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned, signed_token)?;
+
+        let mut rng = StdRng::from_seed(randomization);
+        let signatures = chunking
+            .map(
+                repeat_with(|| random_vartime(&mut rng)).zip(signed_token.points.iter()),
+                |(r, w_prime)| Self::unblind(w_prime, r),
+            )
+            .await?;
+
+        let metadata = &unsigned_token.metadata;
+        let t_list = chunking
+            .map(unsigned_token.ids.iter(), |id| {
+                let t: [u8; 16] = id.into();
+                h_1(t, metadata)
+            })
+            .await?;
+
+        Self::check_signatures(unsigned_token, &signatures, t_list, verification_data)?;
+        Ok(signatures)
+    }
+}
+
+// }}}
+
+// {{{ Tests
+
+#[cfg(test)]
+mod tests {
+    use crate::atpm_pairing::tokens::PairingTokenEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_all() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        for size in [1, 5, 12] {
+            let tokens = DynBatchedPairingTokenEngine::generate((b"metadata", size));
+
+            let signed = DynBatchedPairingTokenEngine::sign(tokens, &public_key, |tokens| {
+                DynBatchedPairingTokenEngine::sign_randomized(tokens, &private_key)
+            })
+            .unwrap();
+
+            assert_eq!(signed.len(), size);
+            assert!(DynBatchedPairingTokenEngine::verify(&signed, &public_key).is_ok());
+
+            for token in signed.iter() {
+                assert!(PairingTokenEngine::verify(&token, &public_key).is_ok());
+                assert_eq!(token.key_id(), Some(public_key.key_id()));
+            }
+
+            // the single tokens can be collected into a batch again
+            let batch =
+                DynBatchedPairingSignedToken::try_from(signed.iter().collect::<Vec<_>>()).unwrap();
+            assert!(batch.verify(&public_key));
+            assert_eq!(batch.key_id(), signed.key_id());
+        }
+    }
+
+    #[test]
+    fn fail_collect() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let other_private_key = PrivateKey::new();
+        let other_public_key = PublicKey::from(&other_private_key);
+
+        let sign = |metadata: &'static [u8], private_key, public_key| {
+            let tokens = DynBatchedPairingTokenEngine::generate((metadata, 2));
+            DynBatchedPairingTokenEngine::sign(tokens, public_key, |tokens| {
+                DynBatchedPairingTokenEngine::sign_randomized(tokens, private_key)
+            })
+            .unwrap()
+        };
+        let signed = sign(b"metadata", &private_key, &public_key);
+        let other_metadata = sign(b"other metadata", &private_key, &public_key);
+        let other_key = sign(b"metadata", &other_private_key, &other_public_key);
+
+        let collect = |other: &DynBatchedPairingSignedToken<&[u8]>| {
+            DynBatchedPairingSignedToken::try_from(
+                signed.iter().chain(other.iter()).collect::<Vec<_>>(),
+            )
+            .err()
+        };
+
+        assert_eq!(
+            DynBatchedPairingSignedToken::<&[u8]>::try_from(Vec::new()).err(),
+            Some(Error::EmptyBatch)
+        );
+        assert_eq!(collect(&other_metadata), Some(Error::MetadataMismatch));
+        assert_eq!(collect(&other_key), Some(Error::KeyMismatch));
+        assert!(collect(&signed).is_none());
+    }
+
+    #[test]
+    fn test_serialization() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = DynBatchedPairingTokenEngine::generate((Box::from(&b"metadata"[..]), 3));
+        let (r, randomized) = DynBatchedPairingTokenEngine::randomize(&tokens);
+
+        // the signer reads the size of the batch from the request
+        let request: DynBatchedRandomizedUnsignedToken<Box<[u8]>> =
+            serde_json::from_str(&serde_json::to_string(&randomized).unwrap()).unwrap();
+        assert_eq!(request.len(), 3);
+
+        let signed = DynBatchedPairingTokenEngine::sign_randomized(&request, &private_key).unwrap();
+        let signed = serde_json::from_str(&serde_json::to_string(&signed).unwrap()).unwrap();
+
+        let signed = DynBatchedPairingTokenEngine::verify_signature_and_unrandomize(
+            tokens,
+            randomized,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap();
+        assert!(signed.verify(&public_key));
+    }
+
+    #[test]
+    fn fail_bad_signkey() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = DynBatchedPairingTokenEngine::generate((b"metadata", 5));
+
+        let wrong_private_key = PrivateKey::new();
+
+        assert!(
+            DynBatchedPairingTokenEngine::sign(tokens, &public_key, |tokens| {
+                DynBatchedPairingTokenEngine::sign_randomized(tokens, &wrong_private_key)
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_chunked() {
+        use crate::chunked::{yield_now, Chunking};
+        use futures::executor::block_on;
+
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let mut chunking = Chunking::new(4, yield_now);
+
+        for size in [1, 4, 13] {
+            let tokens = DynBatchedPairingTokenEngine::generate((b"metadata", size));
+            let (r, randomized) = block_on(DynBatchedPairingTokenEngine::randomize_chunked(
+                &tokens,
+                &mut chunking,
+            ))
+            .unwrap();
+            assert_eq!(randomized.len(), size);
+
+            let signed = block_on(DynBatchedPairingTokenEngine::sign_randomized_chunked(
+                &randomized,
+                &private_key,
+                &mut chunking,
+            ))
+            .unwrap();
+            let signed = block_on(
+                DynBatchedPairingTokenEngine::verify_signature_and_unrandomize_chunked(
+                    tokens,
+                    randomized,
+                    signed,
+                    &public_key,
+                    r,
+                    &mut chunking,
+                ),
+            )
+            .unwrap();
+
+            assert!(signed.verify(&public_key));
+            assert!(block_on(signed.verify_chunked(&public_key, &mut chunking)));
+            assert!(!block_on(signed.verify_chunked(
+                &PublicKey::from(&PrivateKey::new()),
+                &mut chunking
+            )));
+        }
+
+        // a signature from another key is rejected
+        let tokens = DynBatchedPairingTokenEngine::generate((b"metadata", 6));
+        let (r, randomized) = block_on(DynBatchedPairingTokenEngine::randomize_chunked(
+            &tokens,
+            &mut chunking,
+        ))
+        .unwrap();
+        let signed =
+            DynBatchedPairingTokenEngine::sign_randomized(&randomized, &PrivateKey::new()).unwrap();
+        assert!(block_on(
+            DynBatchedPairingTokenEngine::verify_signature_and_unrandomize_chunked(
+                tokens,
+                randomized,
+                signed,
+                &public_key,
+                r,
+                &mut chunking,
+            )
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn fail_cancelled() {
+        use crate::chunked::{yield_now, Cancel, Chunking};
+        use futures::executor::block_on;
+
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let cancel = Cancel::new();
+        let mut chunking = Chunking::new(4, yield_now).with_cancel(&cancel);
+
+        let tokens = DynBatchedPairingTokenEngine::generate((b"metadata", 13));
+        let (r, randomized) = block_on(DynBatchedPairingTokenEngine::randomize_chunked(
+            &tokens,
+            &mut chunking,
+        ))
+        .unwrap();
+        let signed =
+            DynBatchedPairingTokenEngine::sign_randomized(&randomized, &private_key).unwrap();
+
+        // the user gives up on the session, and may start over with the same tokens
+        cancel.cancel();
+        assert!(matches!(
+            block_on(DynBatchedPairingTokenEngine::randomize_chunked(
+                &tokens,
+                &mut chunking
+            )),
+            Err(Error::Cancelled)
+        ));
+        assert!(matches!(
+            block_on(
+                DynBatchedPairingTokenEngine::verify_signature_and_unrandomize_chunked(
+                    tokens,
+                    randomized,
+                    signed,
+                    &public_key,
+                    r,
+                    &mut chunking,
+                )
+            ),
+            Err(VerifyError {
+                error: Error::Cancelled,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_hidden() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = DynBatchedPairingTokenEngine::generate_with_hidden(
+            (&b"metadata"[..], 6),
+            &b"hidden"[..],
+        );
+        assert_eq!(tokens.len(), 6);
+        assert!(tokens
+            .ids
+            .iter()
+            .all(|id| matches!(id.hidden(), Some(hidden) if hidden == b"hidden")));
+
+        let signed = DynBatchedPairingTokenEngine::sign(tokens, &public_key, |randomized| {
+            DynBatchedPairingTokenEngine::sign_randomized(randomized, &private_key)
+        })
+        .unwrap();
+        assert!(DynBatchedPairingTokenEngine::verify(&signed, &public_key).is_ok());
+    }
+
+    #[test]
+    fn fail_wrong_count() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let tokens = DynBatchedPairingTokenEngine::generate((b"metadata", 5));
+
+        let (r, randomized) = DynBatchedPairingTokenEngine::randomize(&tokens);
+
+        let mut signed =
+            DynBatchedPairingTokenEngine::sign_randomized(&randomized, &private_key).unwrap();
+
+        // the signer only signs some of the tokens
+        signed.points.truncate(4);
+
+        assert_eq!(
+            DynBatchedPairingTokenEngine::check_response(&randomized, &signed),
+            Err(BatchResponseError::WrongCount {
+                expected: 5,
+                actual: 4
+            })
+        );
+
+        assert!(
+            DynBatchedPairingTokenEngine::verify_signature_and_unrandomize(
+                tokens,
+                randomized,
+                signed,
+                &public_key,
+                r
+            )
+            .is_err()
+        );
+    }
+}
+
+// }}}
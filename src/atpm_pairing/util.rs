@@ -1,16 +1,20 @@
 use bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve};
-use bls12_381::{G1Affine, G1Projective, Scalar};
+use bls12_381::{multi_miller_loop, G1Affine, G1Projective, G2Affine, G2Prepared, Gt, Scalar};
 use rand::{CryptoRng, RngCore};
 use sha2::{Digest, Sha256, Sha512};
 
 use alloc::{format, vec::Vec};
 use core::{convert::TryInto, fmt};
 
-use serde::de::MapAccess;
 use serde::de::{self, Deserialize, Visitor};
+use serde::de::{MapAccess, SeqAccess};
 use serde::ser::{Serialize, SerializeStruct};
 
+use subtle::CtOption;
+
+use super::encoding::{from_hex, point_encoding, to_hex, PointEncoding};
 use super::fill_bytes;
+use crate::group::PrimeOrderGroup;
 
 /// Generates a uniformly distributed random scalar, but with variable time
 pub fn random_vartime<R: CryptoRng + RngCore>(rng: &mut R) -> Scalar {
@@ -29,8 +33,10 @@ pub fn random_vartime<R: CryptoRng + RngCore>(rng: &mut R) -> Scalar {
     }
 }
 
-#[allow(dead_code)]
-/// Generates a radnom scalar in constant time (I believe), but it is not uniform
+/// Generates a random scalar in constant time
+///
+/// This reduces 512 random bits by the modulus, so the bias from uniform is negligible. The signer
+/// and the proofs use this, such that the timing does not depend on the random bytes.
 pub fn random_biased<R: CryptoRng + RngCore>(rng: &mut R) -> Scalar {
     // generate some random bytes
     let mut rand_bytes = [0u8; 64];
@@ -40,9 +46,8 @@ pub fn random_biased<R: CryptoRng + RngCore>(rng: &mut R) -> Scalar {
     Scalar::from_bytes_wide(&rand_bytes)
 }
 
-#[allow(dead_code)]
 /// Variable time hash to get uniformity
-fn h_m_uniform(md: impl AsRef<[u8]>) -> Scalar {
+pub(crate) fn h_m_uniform(md: impl AsRef<[u8]>) -> Scalar {
     let mut hasher = Sha256::new();
 
     // Separate the domains of the random oracles
@@ -50,22 +55,18 @@ fn h_m_uniform(md: impl AsRef<[u8]>) -> Scalar {
 
     hasher.update(md);
 
-    let bytes = &hasher.finalize()[..];
-
-    let scalar = Scalar::from_bytes(bytes.try_into().unwrap());
+    let bytes: [u8; 32] = hasher.finalize().into();
 
     // If not sucessful, try again recursivly
     // This is tail recursive, so should be compiled to replace the stack frame
-    if bool::from(scalar.is_some()) {
-        scalar.unwrap()
-    } else {
-        h_m(bytes)
+    match Option::from(Scalar::from_bytes(&bytes)) {
+        Some(scalar) => scalar,
+        None => h_m_uniform(bytes),
     }
 }
 
-#[allow(dead_code)]
 /// Constant time implementation, is not uniform
-fn h_m_reduce_modulus(md: impl AsRef<[u8]>) -> Scalar {
+pub(crate) fn h_m_reduce_modulus(md: impl AsRef<[u8]>) -> Scalar {
     let mut hasher = Sha512::new();
 
     // Separate the domains of the random oracles
@@ -73,26 +74,38 @@ fn h_m_reduce_modulus(md: impl AsRef<[u8]>) -> Scalar {
 
     hasher.update(md);
 
-    Scalar::from_bytes_wide(&hasher.finalize()[..].try_into().unwrap())
+    let mut bytes = [0u8; 64];
+    for (dst, src) in bytes.iter_mut().zip(hasher.finalize().iter()) {
+        *dst = *src;
+    }
+
+    Scalar::from_bytes_wide(&bytes)
 }
 
-/// Hash a message into a scalar
-///
-/// I am not sure if this scalar is uniformly distributed
+/// Hash a message into a scalar, with the [`HmUniform`](super::hm::HmUniform) of the default engine
 pub fn h_m(md: impl AsRef<[u8]>) -> Scalar {
-    #[cfg(feature = "uniform_hm")]
-    {
-        h_m_uniform(md)
-    }
+    h_m_uniform(md)
+}
 
-    #[cfg(not(feature = "uniform_hm"))]
-    {
-        h_m_reduce_modulus(md)
-    }
+/// Hash a public key and a label to the tweak of a derived key
+pub(crate) fn h_derive(public_key: impl AsRef<[u8]>, label: impl AsRef<[u8]>) -> Scalar {
+    let mut hasher = Sha512::new();
+
+    // Separate the domains of the random oracles
+    hasher.update(b"this is h_derive");
+
+    // the public key has a fixed length, so the label needs no length prefix
+    hasher.update(public_key);
+    hasher.update(label);
+
+    let mut bytes = [0u8; 64];
+    bytes.copy_from_slice(&hasher.finalize());
+
+    Scalar::from_bytes_wide(&bytes)
 }
 
 /// hash some bytes to a curve point in the G1 group.
-pub fn h_1<'a>(t: impl AsRef<[u8]>, md: impl AsRef<[u8]>) -> G1Affine {
+pub fn h_1(t: impl AsRef<[u8]>, md: impl AsRef<[u8]>) -> G1Affine {
     // Domain of the random oracle
     const DOMAIN: &[u8] = b"This is h_1 hash to curve thingy";
 
@@ -105,6 +118,62 @@ pub fn h_1<'a>(t: impl AsRef<[u8]>, md: impl AsRef<[u8]>) -> G1Affine {
     <G1Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(bytes, DOMAIN).into()
 }
 
+/// Whether e(w, u) = e(t, g2), with one final exponentiation instead of two
+///
+/// It checks e(w, u) * e(-t, g2) = 1, where the Miller loops of both pairings are done together
+/// and only their product is exponentiated, which is about half of the cost of a pairing.
+pub fn pairing_check(
+    w: impl Into<G1Affine>,
+    u: impl Into<G2Affine>,
+    t: impl Into<G1Affine>,
+) -> bool {
+    let u = G2Prepared::from(u.into());
+    let generator = G2Prepared::from(G2Affine::generator());
+
+    multi_miller_loop(&[(&w.into(), &u), (&-t.into(), &generator)]).final_exponentiation()
+        == Gt::identity()
+}
+
+// {{{ Group
+
+/// The G1 group of BLS12-381, where the tokens are
+pub struct Bls12G1;
+
+impl PrimeOrderGroup for Bls12G1 {
+    type Scalar = Scalar;
+    type Element = G1Projective;
+
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn generator() -> G1Projective {
+        G1Projective::generator()
+    }
+
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn identity() -> G1Projective {
+        G1Projective::identity()
+    }
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Scalar {
+        random_biased(rng)
+    }
+
+    fn invert(scalar: &Scalar) -> CtOption<Scalar> {
+        scalar.invert()
+    }
+
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn encode(element: &G1Projective) -> Vec<u8> {
+        G1Affine::from(element).to_compressed().to_vec()
+    }
+
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn challenge(transcript: &[u8]) -> Scalar {
+        h_m(transcript)
+    }
+}
+
+// }}}
+
 // {{{ Cruve Point
 
 #[derive(Clone, PartialEq, Debug)]
@@ -135,26 +204,69 @@ impl From<G1Affine> for CurvePoint {
 
 impl From<&G1Affine> for CurvePoint {
     fn from(point: &G1Affine) -> Self {
-        Self {
-            point: point.clone(),
-        }
+        Self { point: *point }
     }
 }
 
-impl Serialize for CurvePoint {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+/// A multiple of the generator, so it is always in the group
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for CurvePoint {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::from(
+            G1Affine::generator() * Scalar::from_bytes_wide(&u.arbitrary()?),
+        ))
+    }
+}
+
+impl CurvePoint {
+    pub(crate) fn to_compressed(&self) -> [u8; 48] {
+        self.point.to_compressed()
+    }
+
+    pub(crate) fn from_compressed(bytes: &[u8; 48]) -> Option<Self> {
+        Option::from(G1Affine::from_compressed(bytes)).map(|point| Self { point })
+    }
+
+    pub(crate) fn from_uncompressed(bytes: &[u8; 96]) -> Option<Self> {
+        Option::from(G1Affine::from_uncompressed(bytes)).map(|point| Self { point })
+    }
+
+    /// The point from the compressed or the uncompressed bytes, by their length
+    fn from_encoded<E: de::Error>(bytes: &[u8]) -> Result<Self, E> {
+        let point = match bytes.len() {
+            48 => Self::from_compressed(bytes.try_into().expect("48 bytes")),
+            96 => Self::from_uncompressed(bytes.try_into().expect("96 bytes")),
+            len => {
+                return Err(de::Error::custom(
+                    format!("point bytes has to be 48 or 96 bytes, not {}", len).as_str(),
+                ))
+            }
+        };
+
+        point.ok_or_else(|| de::Error::custom("Failed to decompress token point"))
+    }
+
+    fn serialize_with<S>(&self, encoding: PointEncoding, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut s = serializer.serialize_struct("CurvePoint", 1)?;
-        let bytes: &[u8] = &self.point.to_compressed();
-        s.serialize_field("point", &bytes)?;
-        s.end()
+        match encoding.in_format(serializer.is_human_readable()) {
+            PointEncoding::Compressed => serializer.serialize_bytes(&self.point.to_compressed()),
+            PointEncoding::Uncompressed => {
+                serializer.serialize_bytes(&self.point.to_uncompressed())
+            }
+            PointEncoding::Hex => serializer.serialize_str(&to_hex(&self.point.to_compressed())),
+            _ => {
+                let mut s = serializer.serialize_struct("CurvePoint", 1)?;
+                let bytes: &[u8] = &self.point.to_compressed();
+                s.serialize_field("point", &bytes)?;
+                s.end()
+            }
+        }
     }
-}
 
-impl<'de> Deserialize<'de> for CurvePoint {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    /// A human-readable format is read in any encoding, the others in the one they are written in
+    fn deserialize_with<'de, D>(encoding: PointEncoding, deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
@@ -169,7 +281,7 @@ impl<'de> Deserialize<'de> for CurvePoint {
             type Value = CurvePoint;
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("struct CurvePoint")
+                formatter.write_str("struct CurvePoint, point bytes or a hex string")
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<CurvePoint, V::Error>
@@ -197,20 +309,69 @@ impl<'de> Deserialize<'de> for CurvePoint {
                     )
                 })?;
 
-                let maybe_point = G1Affine::from_compressed(&point_bytes);
-
-                let point = if bool::from(maybe_point.is_some()) {
-                    Ok(maybe_point.unwrap())
-                } else {
-                    Err(de::Error::custom("Failed to decompress token point"))
-                }?;
+                let point = Option::from(G1Affine::from_compressed(point_bytes))
+                    .ok_or_else(|| de::Error::custom("Failed to decompress token point"))?;
 
                 Ok(CurvePoint { point })
             }
+
+            fn visit_seq<V>(self, mut seq: V) -> Result<CurvePoint, V::Error>
+            where
+                V: SeqAccess<'de>,
+            {
+                let mut bytes = Vec::with_capacity(96);
+                while let Some(byte) = seq.next_element()? {
+                    if bytes.len() == 96 {
+                        return Err(de::Error::custom("point bytes has to be 48 or 96 bytes"));
+                    }
+                    bytes.push(byte);
+                }
+
+                CurvePoint::from_encoded(&bytes)
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<CurvePoint, E> {
+                CurvePoint::from_encoded(bytes)
+            }
+
+            fn visit_str<E: de::Error>(self, hex: &str) -> Result<CurvePoint, E> {
+                let bytes =
+                    from_hex(hex).ok_or_else(|| de::Error::custom("point is not a hex string"))?;
+
+                CurvePoint::from_encoded(&bytes)
+            }
+        }
+
+        if deserializer.is_human_readable() {
+            return deserializer.deserialize_any(CurvePointVisitor);
         }
 
         const FIELDS: &[&str] = &["point"];
-        deserializer.deserialize_struct("CurvePoint", FIELDS, CurvePointVisitor)
+        match encoding.in_format(false) {
+            PointEncoding::Compressed | PointEncoding::Uncompressed => {
+                deserializer.deserialize_bytes(CurvePointVisitor)
+            }
+            PointEncoding::Hex => deserializer.deserialize_str(CurvePointVisitor),
+            _ => deserializer.deserialize_struct("CurvePoint", FIELDS, CurvePointVisitor),
+        }
+    }
+}
+
+impl Serialize for CurvePoint {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.serialize_with(point_encoding(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CurvePoint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Self::deserialize_with(point_encoding(), deserializer)
     }
 }
 
@@ -235,4 +396,61 @@ mod tests {
         // Assert that the serialization and deserialization works
         assert!(G1Affine::from(point) == deserialized.point);
     }
+
+    #[test]
+    fn test_point_encodings() {
+        let cp = CurvePoint::from(G1Affine::generator() * Scalar::from(123));
+
+        let to_json = |encoding| {
+            let mut json = Vec::new();
+            cp.serialize_with(encoding, &mut serde_json::Serializer::new(&mut json))
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap()
+        };
+
+        let hex = to_hex(&cp.to_compressed());
+        assert_eq!(to_json(PointEncoding::Hex), serde_json::json!(hex));
+        assert_eq!(
+            to_json(PointEncoding::HumanReadable),
+            serde_json::json!(hex)
+        );
+        assert_eq!(
+            to_json(PointEncoding::Compressed).as_array().unwrap().len(),
+            48
+        );
+        assert_eq!(
+            to_json(PointEncoding::Uncompressed)
+                .as_array()
+                .unwrap()
+                .len(),
+            96
+        );
+
+        // JSON is read in any encoding, with the earlier struct
+        for encoding in [
+            PointEncoding::Struct,
+            PointEncoding::Compressed,
+            PointEncoding::Uncompressed,
+            PointEncoding::Hex,
+            PointEncoding::HumanReadable,
+        ] {
+            let decoded: CurvePoint = serde_json::from_value(to_json(encoding)).unwrap();
+            assert_eq!(decoded, cp);
+        }
+        let upper: CurvePoint =
+            serde_json::from_value(serde_json::json!(hex.to_uppercase())).unwrap();
+        assert_eq!(upper, cp);
+
+        assert!(serde_json::from_value::<CurvePoint>(serde_json::json!(&hex[2..])).is_err());
+        assert!(
+            serde_json::from_value::<CurvePoint>(serde_json::json!(alloc::vec![0u8; 47])).is_err()
+        );
+        assert!(serde_json::from_value::<CurvePoint>(serde_json::json!("not hex")).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "curve25519")]
+    fn test_group() {
+        crate::group::tests::check_proofs::<Bls12G1>();
+    }
 }
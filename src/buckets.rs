@@ -0,0 +1,196 @@
+//! # Metadata buckets
+//!
+//! Every user with the same public metadata is in the same anonymity set, see
+//! [`stats`](crate::stats), so metadata with many values splits the users into small sets.
+//! A [`MetadataBucketizer`] hashes any metadata, e.g. an account or a device class, into one of k
+//! buckets, and the canonical metadata of the bucket is signed instead. There are never more than
+//! k anonymity sets.
+//!
+//! A [`CardinalityMonitor`] counts the distinct metadata that an issuer signs, and warns once there
+//! are more than the limit, before the anonymity sets are too small.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::buckets::{CardinalityMonitor, MetadataBucketizer};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let bucketizer = MetadataBucketizer::new(16);
+//!     let mut monitor = CardinalityMonitor::new(16);
+//!
+//!     let metadata = bucketizer.metadata(b"account 1234");
+//!     assert!(bucketizer.bucket(b"account 1234") < 16);
+//!
+//!     // the issuer checks the metadata it signs
+//!     assert!(monitor.observe(metadata).is_none());
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(metadata),
+//!         &public_key,
+//!         |randomized| PairingTokenEngine::sign_randomized(randomized, &secret_key),
+//!     ).unwrap();
+//!     assert!(PairingTokenEngine::verify(&signed, &public_key).is_ok());
+//! ```
+
+use alloc::collections::BTreeSet;
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+/// The length of the canonical metadata of a bucket
+pub const BUCKET_METADATA_LEN: usize = 4;
+
+/// Hash metadata into one of k buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataBucketizer {
+    k: u32,
+}
+
+impl MetadataBucketizer {
+    /// A bucketizer of k buckets, k must not be zero
+    ///
+    /// This panics for zero, which is a compile error in a `const`.
+    pub const fn new(k: u32) -> Self {
+        assert!(k > 0, "there must be at least one bucket");
+        Self { k }
+    }
+
+    /// The number of buckets
+    pub fn buckets(&self) -> u32 {
+        self.k
+    }
+
+    /// The bucket of the metadata, from 0 to k - 1
+    ///
+    /// The bucket is the SHA-256 of the metadata, reduced from 64 bits, which is close to uniform
+    /// for any k of 32 bits.
+    pub fn bucket(&self, metadata: impl AsRef<[u8]>) -> u32 {
+        let mut hasher = Sha256::new();
+        hasher.update(b"atpmd metadata bucket");
+        hasher.update(metadata.as_ref());
+
+        let mut wide = [0; 8];
+        wide.copy_from_slice(&hasher.finalize()[..8]);
+        (u64::from_be_bytes(wide) % u64::from(self.k)) as u32
+    }
+
+    /// The canonical metadata of the bucket of the metadata, to sign instead of the metadata
+    ///
+    /// This is the bucket as a big endian `u32`, like the integers of
+    /// [`metadata`](crate::metadata).
+    pub fn metadata(&self, metadata: impl AsRef<[u8]>) -> [u8; BUCKET_METADATA_LEN] {
+        self.bucket(metadata).to_be_bytes()
+    }
+}
+
+/// There are more distinct metadata values than the limit of a [`CardinalityMonitor`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardinalityWarning {
+    pub distinct: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for CardinalityWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} distinct metadata values, more than the limit of {}",
+            self.distinct, self.limit
+        )
+    }
+}
+
+/// Count the distinct metadata values an issuer signs
+///
+/// The monitor keeps the hashes of the metadata, so it stays small for long metadata.
+#[derive(Debug, Clone)]
+pub struct CardinalityMonitor {
+    limit: usize,
+    seen: BTreeSet<[u8; 32]>,
+}
+
+impl CardinalityMonitor {
+    /// A monitor of at most `limit` distinct values
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Count the metadata, a warning if there are more distinct values than the limit
+    ///
+    /// Once there are, every call warns, also for metadata that was seen before.
+    pub fn observe(&mut self, metadata: impl AsRef<[u8]>) -> Option<CardinalityWarning> {
+        self.seen.insert(Sha256::digest(metadata.as_ref()).into());
+        self.check()
+    }
+
+    /// A warning if there are more distinct values than the limit
+    pub fn check(&self) -> Option<CardinalityWarning> {
+        if self.seen.len() > self.limit {
+            Some(CardinalityWarning {
+                distinct: self.seen.len(),
+                limit: self.limit,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The number of distinct metadata values
+    pub fn distinct(&self) -> usize {
+        self.seen.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::format;
+
+    #[test]
+    fn test_buckets() {
+        let bucketizer = MetadataBucketizer::new(8);
+        assert_eq!(bucketizer.buckets(), 8);
+
+        let mut used = BTreeSet::new();
+        for i in 0..1000u32 {
+            let bucket = bucketizer.bucket(i.to_be_bytes());
+            assert!(bucket < 8);
+            assert_eq!(bucketizer.bucket(i.to_be_bytes()), bucket);
+            assert_eq!(bucketizer.metadata(i.to_be_bytes()), bucket.to_be_bytes());
+            used.insert(bucket);
+        }
+        assert_eq!(used.len(), 8);
+
+        assert_eq!(MetadataBucketizer::new(1).bucket(b"anything"), 0);
+    }
+
+    #[test]
+    fn test_cardinality() {
+        let mut monitor = CardinalityMonitor::new(2);
+        assert_eq!(monitor.observe(b"a"), None);
+        assert_eq!(monitor.observe(b"b"), None);
+        assert_eq!(monitor.observe(b"a"), None);
+        assert_eq!(monitor.distinct(), 2);
+
+        let warning = monitor.observe(b"c").unwrap();
+        assert_eq!(
+            warning,
+            CardinalityWarning {
+                distinct: 3,
+                limit: 2
+            }
+        );
+        assert_eq!(monitor.observe(b"a"), Some(warning));
+        assert_eq!(
+            format!("{}", warning),
+            "3 distinct metadata values, more than the limit of 2"
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! # Challenges against replay
+//!
+//! A verifier that wants a token to be redeemed for one fresh request gives the client a random
+//! [`Challenge`], and the client binds the token to the challenge and the request, see
+//! [`binding`]. A challenge is only valid for a window after it was issued, and only once.
+//!
+//! The [`ChallengeManager`] does not remember the challenges it has issued: they are
+//! authenticated with a key, and only the used ones are kept, in a
+//! [`RedemptionStore`](crate::redemption::RedemptionStore) until they expire. The instances of a
+//! verifier with the same key and a shared store, e.g. Redis, accept each challenge once.
+//! [`MemoryChallengeManager`] is for a single instance.
+//!
+//! ```
+//!     use atpmd::challenge::{ChallengeError, MemoryChallengeManager};
+//!
+//!     let now = 1_600_000_000;
+//!     let mut manager = MemoryChallengeManager::in_memory(60);
+//!
+//!     // sent to the client with the page
+//!     let challenge = manager.issue(now);
+//!
+//!     // and sent back with the token
+//!     assert_eq!(manager.consume(&challenge, now + 10), Ok(()));
+//!     assert_eq!(manager.consume(&challenge, now + 20), Err(ChallengeError::Replayed));
+//!     assert_eq!(
+//!         manager.consume(&manager.issue(now), now + 60),
+//!         Err(ChallengeError::Expired)
+//!     );
+//! ```
+
+use alloc::vec::Vec;
+use core::{convert::TryInto, fmt};
+
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512Trunc256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+use crate::redemption::{Fingerprint, MemoryRedemptionStore, RedemptionStore};
+
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 32;
+
+/// The length of an encoded challenge
+pub const CHALLENGE_LEN: usize = NONCE_LEN + 8 + TAG_LEN;
+
+/// The reason a challenge was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeError {
+    /// The challenge was not issued with this key, or has been changed
+    Unknown,
+    /// The window of the challenge has passed
+    Expired,
+    /// The challenge has already been used
+    Replayed,
+    /// The token is not signed, or not bound to the challenge and the request
+    InvalidToken,
+    /// The token has already been redeemed
+    DoubleSpend,
+}
+
+impl fmt::Display for ChallengeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => f.write_str("the challenge was not issued here"),
+            Self::Expired => f.write_str("the challenge has expired"),
+            Self::Replayed => f.write_str("the challenge has already been used"),
+            Self::InvalidToken => f.write_str("the token is not valid for this challenge"),
+            Self::DoubleSpend => f.write_str("the token has already been used"),
+        }
+    }
+}
+
+/// A random challenge, with the time it expires at and the tag of the verifier
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge {
+    pub nonce: [u8; NONCE_LEN],
+    /// In seconds since the unix epoch
+    pub expires_at: u64,
+    pub tag: [u8; TAG_LEN],
+}
+
+impl Challenge {
+    pub fn to_bytes(&self) -> [u8; CHALLENGE_LEN] {
+        let mut bytes = [0; CHALLENGE_LEN];
+        bytes[..NONCE_LEN].copy_from_slice(&self.nonce);
+        bytes[NONCE_LEN..NONCE_LEN + 8].copy_from_slice(&self.expires_at.to_be_bytes());
+        bytes[NONCE_LEN + 8..].copy_from_slice(&self.tag);
+        bytes
+    }
+
+    /// Decode a challenge, none if it is not [`CHALLENGE_LEN`] bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != CHALLENGE_LEN {
+            return None;
+        }
+
+        Some(Self {
+            nonce: bytes[..NONCE_LEN].try_into().ok()?,
+            expires_at: u64::from_be_bytes(bytes[NONCE_LEN..NONCE_LEN + 8].try_into().ok()?),
+            tag: bytes[NONCE_LEN + 8..].try_into().ok()?,
+        })
+    }
+
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::from_bytes(
+            Sha512Trunc256::new()
+                .chain(b"Domain of challenge fingerprints")
+                .chain(self.nonce)
+                .finalize()
+                .into(),
+        )
+    }
+}
+
+/// The data a token is bound to, for the challenge and the request
+///
+/// The challenge has a fixed length, so the request needs no length prefix.
+pub fn binding(challenge: &Challenge, request: impl AsRef<[u8]>) -> Vec<u8> {
+    let mut data = challenge.to_bytes().to_vec();
+    data.extend_from_slice(request.as_ref());
+    data
+}
+
+// {{{ Manager
+
+/// Issues challenges, and accepts each once within its window
+pub struct ChallengeManager<S> {
+    key: [u8; 32],
+    window: u64,
+    store: S,
+}
+
+/// A challenge manager for a single instance of a verifier
+pub type MemoryChallengeManager = ChallengeManager<MemoryRedemptionStore>;
+
+impl MemoryChallengeManager {
+    /// A manager with a random key, as no other instance needs it
+    pub fn in_memory(window: u64) -> Self {
+        let mut key = [0; 32];
+        crate::rng::default_rng().fill_bytes(&mut key);
+        Self::new(key, window, MemoryRedemptionStore::new())
+    }
+}
+
+impl<S: RedemptionStore> ChallengeManager<S> {
+    /// A manager with the key of the verifier, the window in seconds, and the used challenges
+    ///
+    /// The store may be the store of the redeemed tokens, the fingerprints of the challenges are
+    /// in another domain.
+    pub fn new(key: [u8; 32], window: u64, store: S) -> Self {
+        Self { key, window, store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Forget the used challenges that have expired
+    pub fn prune(&mut self, now: u64) {
+        self.store.prune(now);
+    }
+
+    /// A new challenge, valid for the window after `now`
+    pub fn issue(&self, now: u64) -> Challenge {
+        self.issue_with_rng(now, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::issue`], with the nonce from the given rng
+    pub fn issue_with_rng<R: CryptoRng + RngCore>(&self, now: u64, rng: &mut R) -> Challenge {
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let expires_at = now.saturating_add(self.window);
+
+        Challenge {
+            nonce,
+            expires_at,
+            tag: self.tag(&nonce, expires_at),
+        }
+    }
+
+    /// Accept a challenge that was issued here, has not expired at `now`, and was not used
+    pub fn consume(&mut self, challenge: &Challenge, now: u64) -> Result<(), ChallengeError> {
+        let tag = self.tag(&challenge.nonce, challenge.expires_at);
+        if !bool::from(tag.ct_eq(&challenge.tag)) {
+            return Err(ChallengeError::Unknown);
+        }
+
+        if now >= challenge.expires_at {
+            return Err(ChallengeError::Expired);
+        }
+
+        if !self
+            .store
+            .insert(challenge.fingerprint(), challenge.expires_at)
+        {
+            return Err(ChallengeError::Replayed);
+        }
+
+        Ok(())
+    }
+
+    fn tag(&self, nonce: &[u8], expires_at: u64) -> [u8; TAG_LEN] {
+        Sha512Trunc256::new()
+            .chain(b"This is the challenge tag")
+            .chain(self.key)
+            .chain(nonce)
+            .chain(expires_at.to_be_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+#[cfg(feature = "curve25519")]
+impl<S: RedemptionStore> ChallengeManager<S> {
+    /// Redeem a token that is bound to the challenge and the request, see [`binding`]
+    ///
+    /// The challenge and the token are both used up, until the challenge and the token expire.
+    pub fn redeem_bound<M: AsRef<[u8]>>(
+        &mut self,
+        redemption: &crate::nizkp_curve25519::tokens::BoundRedemption<M>,
+        challenge: &Challenge,
+        request: impl AsRef<[u8]>,
+        verification_key: &crate::nizkp_curve25519::keys::PrivateKey,
+        now: u64,
+    ) -> Result<(), ChallengeError> {
+        let metadata = redemption.metadata.as_ref();
+        let expires_at = match crate::expiry::expires_at(metadata) {
+            Some(expires_at) if now >= expires_at => return Err(ChallengeError::InvalidToken),
+            Some(expires_at) => expires_at,
+            None => u64::MAX,
+        };
+
+        if !redemption.verify(verification_key, binding(challenge, request)) {
+            return Err(ChallengeError::InvalidToken);
+        }
+
+        self.consume(challenge, now)?;
+
+        let id = crate::common::TokenIdentifier::<&[u8]>::from_id(redemption.id);
+        if !self
+            .store
+            .insert(Fingerprint::of_token(&id, metadata), expires_at)
+        {
+            return Err(ChallengeError::DoubleSpend);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> Drop for ChallengeManager<S> {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+
+    #[test]
+    fn test_consume() {
+        let mut manager = MemoryChallengeManager::in_memory(60);
+        let challenge = manager.issue(NOW);
+
+        let decoded = Challenge::from_bytes(&challenge.to_bytes()).unwrap();
+        assert_eq!(decoded, challenge);
+        assert_eq!(Challenge::from_bytes(&challenge.to_bytes()[1..]), None);
+
+        assert_eq!(manager.consume(&challenge, NOW + 59), Ok(()));
+        assert_eq!(
+            manager.consume(&challenge, NOW + 59),
+            Err(ChallengeError::Replayed)
+        );
+
+        // the used challenges are forgotten when they expire
+        manager.prune(NOW + 60);
+        assert!(manager.store().is_empty());
+        assert_eq!(
+            manager.consume(&challenge, NOW + 60),
+            Err(ChallengeError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_shared_store() {
+        let key = [7; 32];
+        let mut first = ChallengeManager::new(key, 60, MemoryRedemptionStore::new());
+        let challenge = first.issue(NOW);
+        assert_eq!(first.consume(&challenge, NOW), Ok(()));
+
+        // another instance with the same key and the store of the first
+        let store = first.store().clone();
+        let mut second = ChallengeManager::new(key, 60, store);
+        assert_eq!(
+            second.consume(&challenge, NOW),
+            Err(ChallengeError::Replayed)
+        );
+        assert_eq!(second.consume(&first.issue(NOW), NOW), Ok(()));
+    }
+
+    #[test]
+    fn fail_unknown() {
+        let mut manager = MemoryChallengeManager::in_memory(60);
+        let other = MemoryChallengeManager::in_memory(60);
+
+        assert_eq!(
+            manager.consume(&other.issue(NOW), NOW),
+            Err(ChallengeError::Unknown)
+        );
+
+        // a client can not extend the window
+        let mut extended = manager.issue(NOW);
+        extended.expires_at += 3600;
+        assert_eq!(
+            manager.consume(&extended, NOW + 120),
+            Err(ChallengeError::Unknown)
+        );
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn test_redeem_bound() {
+        use crate::expiry::Metadata;
+        use crate::nizkp_curve25519::{
+            keys::{PrivateKey, PublicKey},
+            tokens::NizkpTokenEngine,
+        };
+        use crate::TokenEngine;
+
+        let key = PrivateKey::new();
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(Metadata::new(NOW + 3600, b"search")),
+            &PublicKey::from(&key),
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &key),
+        )
+        .unwrap();
+
+        let mut manager = MemoryChallengeManager::in_memory(60);
+        let challenge = manager.issue(NOW);
+        let redemption = signed.bind(binding(&challenge, b"GET /search?q=kake"));
+
+        // bound to another request
+        assert_eq!(
+            manager.redeem_bound(&redemption, &challenge, b"GET /admin", &key, NOW),
+            Err(ChallengeError::InvalidToken)
+        );
+        assert_eq!(
+            manager.redeem_bound(&redemption, &challenge, b"GET /search?q=kake", &key, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            manager.redeem_bound(&redemption, &challenge, b"GET /search?q=kake", &key, NOW),
+            Err(ChallengeError::Replayed)
+        );
+
+        // the token can not be used again with a new challenge
+        let challenge = manager.issue(NOW);
+        let redemption = signed.bind(binding(&challenge, b"GET /search?q=kake"));
+        assert_eq!(
+            manager.redeem_bound(&redemption, &challenge, b"GET /search?q=kake", &key, NOW),
+            Err(ChallengeError::DoubleSpend)
+        );
+    }
+}
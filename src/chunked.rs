@@ -0,0 +1,432 @@
+//! # Cooperative chunking of large batches
+//!
+//! Randomizing, unrandomizing and verifying a batch of a few hundred tokens takes long enough to
+//! freeze a browser when the wasm build runs it on the main thread.
+//! The batched engines with a runtime size have `_chunked` variants of these operations, that do
+//! the work on a few tokens at a time, and await a yield future between the chunks.
+//!
+//! The yield future is injected, so it may come from whatever scheduler is used.
+//! In a browser it should give the event loop a chance to render, e.g. a future resolving on a
+//! `setTimeout(0)`, while [`yield_now`] is enough for executors that poll other tasks between the
+//! polls of a task.
+//!
+//! The operations may be cancelled between the chunks, with a [`Cancel`] signal or a deadline,
+//! such that an overloaded signer can shed load and a user does not wait forever. A cancelled
+//! operation fails with [`Error::Cancelled`](crate::Error::Cancelled), and leaves nothing behind, so
+//! it may be discarded or started over with the same unsigned token.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::chunked::{yield_now, Chunking};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens_batched_dyn::DynBatchedPairingTokenEngine,
+//!     };
+//!
+//!     let private_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&private_key);
+//!
+//!     // yield after every 16 tokens
+//!     let mut chunking = Chunking::new(16, yield_now);
+//!
+//!     futures::executor::block_on(async {
+//!         let tokens = DynBatchedPairingTokenEngine::generate((&b"metadata"[..], 40));
+//!         let (r, randomized) =
+//!             DynBatchedPairingTokenEngine::randomize_chunked(&tokens, &mut chunking)
+//!                 .await
+//!                 .unwrap();
+//!
+//!         let signed = DynBatchedPairingTokenEngine::sign_randomized(&randomized, &private_key)
+//!             .unwrap();
+//!
+//!         let signed = DynBatchedPairingTokenEngine::verify_signature_and_unrandomize_chunked(
+//!             tokens,
+//!             randomized,
+//!             signed,
+//!             &public_key,
+//!             r,
+//!             &mut chunking,
+//!         )
+//!         .await
+//!         .unwrap();
+//!
+//!         assert!(signed.verify_chunked(&public_key, &mut chunking).await);
+//!     });
+//! ```
+//!
+//! Where blocking is fine, e.g. a native wallet refilling in the background, [`Chunks`] splits the
+//! unrandomization of a batch into chunks without the async machinery, and reports the progress
+//! after each chunk. With the `parallel` feature the chunks are done on the rayon thread pool.
+
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+use core::sync::atomic::AtomicUsize;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// A chunked operation was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the operation was cancelled")
+    }
+}
+
+/// A signal to cancel chunked operations, the clones share the signal
+///
+/// ```
+///     use atpmd::chunked::{yield_now, Cancel, Chunking};
+///
+///     let cancel = Cancel::new();
+///     let mut chunking = Chunking::new(16, yield_now).with_cancel(&cancel);
+///
+///     // e.g. when the client disconnects
+///     cancel.cancel();
+///     assert!(chunking.is_cancelled());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the operations that use this signal, at their next chunk
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How many tokens to handle before yielding, and how to yield
+pub struct Chunking<Y> {
+    chunk_size: usize,
+    // only the batches of the pairing and curve25519 engines are chunked
+    #[cfg_attr(
+        not(any(feature = "pairing", feature = "curve25519")),
+        allow(dead_code)
+    )]
+    yield_now: Y,
+    cancel: Option<Cancel>,
+    /// The deadline, and the clock to compare it to
+    deadline: Option<(u64, fn() -> u64)>,
+}
+
+impl<Y: FnMut() -> F, F: Future<Output = ()>> Chunking<Y> {
+    /// Yield with `yield_now` after every `chunk_size` tokens
+    ///
+    /// A `chunk_size` of zero is handled as one.
+    pub fn new(chunk_size: usize, yield_now: Y) -> Self {
+        Self {
+            chunk_size: chunk_size.max(1),
+            yield_now,
+            cancel: None,
+            deadline: None,
+        }
+    }
+
+    /// Cancel the operations when the signal is cancelled
+    pub fn with_cancel(self, cancel: &Cancel) -> Self {
+        Self {
+            cancel: Some(cancel.clone()),
+            ..self
+        }
+    }
+
+    /// Cancel the operations once `now()` is at or past the deadline
+    ///
+    /// The deadline and the clock may be in any unit, e.g. milliseconds since the unix epoch.
+    pub fn with_deadline(self, deadline: u64, now: fn() -> u64) -> Self {
+        Self {
+            deadline: Some((deadline, now)),
+            ..self
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Has the signal been cancelled, or the deadline passed
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(Cancel::is_cancelled)
+            || self
+                .deadline
+                .is_some_and(|(deadline, now)| now() >= deadline)
+    }
+
+    /// Map the items in chunks, yielding between the chunks
+    ///
+    /// This checks for cancellation before every chunk.
+    #[cfg(any(feature = "pairing", feature = "curve25519"))]
+    pub(crate) async fn map<I: IntoIterator, U>(
+        &mut self,
+        items: I,
+        mut f: impl FnMut(I::Item) -> U,
+    ) -> Result<Vec<U>, Cancelled> {
+        let mut items = items.into_iter().peekable();
+        let mut mapped = Vec::new();
+
+        loop {
+            if self.is_cancelled() {
+                return Err(Cancelled);
+            }
+
+            mapped.extend(items.by_ref().take(self.chunk_size).map(&mut f));
+
+            if items.peek().is_none() {
+                return Ok(mapped);
+            }
+
+            (self.yield_now)().await;
+        }
+    }
+}
+
+impl Default for Chunking<fn() -> YieldNow> {
+    /// Yield with [`yield_now`] after every 32 tokens
+    fn default() -> Self {
+        Self::new(32, yield_now)
+    }
+}
+
+/// How to split a blocking batch operation into chunks, and who to tell about the progress
+///
+/// ```
+///     use core::sync::atomic::{AtomicUsize, Ordering};
+///     use atpmd::TokenEngine;
+///     use atpmd::chunked::Chunks;
+///     use atpmd::atpm_pairing::{
+///         keys::{PrivateKey, PublicKey},
+///         tokens_batched_dyn::DynBatchedPairingTokenEngine,
+///     };
+///
+///     let private_key = PrivateKey::new();
+///     let public_key = PublicKey::from(&private_key);
+///
+///     let tokens = DynBatchedPairingTokenEngine::generate((&b"metadata"[..], 40));
+///     let (r, randomized) = DynBatchedPairingTokenEngine::randomize(&tokens);
+///     let signed = DynBatchedPairingTokenEngine::sign_randomized(&randomized, &private_key).unwrap();
+///
+///     // the largest count of unrandomized tokens reported
+///     let done = AtomicUsize::new(0);
+///     let progress = |unrandomized: usize, _total: usize| {
+///         done.fetch_max(unrandomized, Ordering::Relaxed);
+///     };
+///
+///     let signed = DynBatchedPairingTokenEngine::verify_signature_and_unrandomize_in_chunks(
+///         tokens,
+///         randomized,
+///         signed,
+///         &public_key,
+///         r,
+///         &Chunks::new(16).with_progress(&progress),
+///     )
+///     .unwrap();
+///
+///     assert!(DynBatchedPairingTokenEngine::verify(&signed, &public_key).is_ok());
+///     assert_eq!(done.load(Ordering::Relaxed), 40);
+/// ```
+#[derive(Clone, Copy)]
+pub struct Chunks<'a> {
+    chunk_size: usize,
+    #[cfg_attr(
+        not(any(feature = "pairing", feature = "curve25519")),
+        allow(dead_code)
+    )]
+    progress: Option<&'a (dyn Fn(usize, usize) + Sync)>,
+}
+
+impl<'a> Chunks<'a> {
+    /// Chunks of `chunk_size` tokens, a `chunk_size` of zero is handled as one
+    pub fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size: chunk_size.max(1),
+            progress: None,
+        }
+    }
+
+    /// Call `progress(done, total)` after every chunk
+    ///
+    /// With the `parallel` feature, it is called from the threads of the pool, and the calls may
+    /// come out of order.
+    pub fn with_progress(self, progress: &'a (dyn Fn(usize, usize) + Sync)) -> Self {
+        Self {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Map the items chunk by chunk, reporting the progress after each chunk
+    #[cfg(any(feature = "pairing", feature = "curve25519"))]
+    pub(crate) fn map<T: Sync, U: Send>(&self, items: &[T], f: impl Fn(&T) -> U + Sync) -> Vec<U> {
+        let done = AtomicUsize::new(0);
+        let map_chunk = |chunk: &[T]| {
+            let mapped = chunk.iter().map(&f).collect::<Vec<_>>();
+            let done = done.fetch_add(chunk.len(), Ordering::Relaxed) + chunk.len();
+            if let Some(progress) = self.progress {
+                progress(done, items.len());
+            }
+            mapped
+        };
+
+        #[cfg(feature = "parallel")]
+        let chunks = items
+            .par_chunks(self.chunk_size)
+            .map(map_chunk)
+            .collect::<Vec<_>>();
+        #[cfg(not(feature = "parallel"))]
+        let chunks = items.chunks(self.chunk_size).map(map_chunk);
+
+        chunks.into_iter().flatten().collect()
+    }
+}
+
+impl Default for Chunks<'_> {
+    /// Chunks of 64 tokens, without progress
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+/// Map the points of a batch, on the rayon thread pool with the `parallel` feature
+///
+/// The engines use this for the scalar multiplications of signing and verifying a batch, the
+/// random scalars are drawn in order before.
+pub(crate) fn par_map<T: Sync, U: Send>(items: &[T], f: impl Fn(&T) -> U + Sync + Send) -> Vec<U> {
+    #[cfg(feature = "parallel")]
+    let mapped = items.par_iter().map(f).collect();
+    #[cfg(not(feature = "parallel"))]
+    let mapped = items.iter().map(f).collect();
+
+    mapped
+}
+
+/// A future that is pending once, so the executor may run other tasks
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// The future of [`yield_now`]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(all(test, any(feature = "pairing", feature = "curve25519")))]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test]
+    fn test_map() {
+        let yields = Cell::new(0);
+        let mut chunking = Chunking::new(3, || {
+            yields.set(yields.get() + 1);
+            yield_now()
+        });
+
+        let mapped = futures::executor::block_on(chunking.map(0..10, |i| i * 2));
+        assert_eq!(mapped, Ok((0..10).map(|i| i * 2).collect::<Vec<_>>()));
+
+        // one yield between each of the four chunks
+        assert_eq!(yields.get(), 3);
+
+        // no yield after the last chunk, when it is full
+        yields.set(0);
+        futures::executor::block_on(chunking.map(0..9, |i| i)).unwrap();
+        assert_eq!(yields.get(), 2);
+    }
+
+    #[test]
+    fn test_chunks() {
+        let calls = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        let progress = |d: usize, total: usize| {
+            assert_eq!(total, 10);
+            calls.fetch_add(1, Ordering::Relaxed);
+            done.fetch_max(d, Ordering::Relaxed);
+        };
+
+        let items = (0..10).collect::<Vec<_>>();
+        let mapped = Chunks::new(3)
+            .with_progress(&progress)
+            .map(&items, |i| i * 2);
+        assert_eq!(mapped, (0..10).map(|i| i * 2).collect::<Vec<_>>());
+
+        // one call for each of the four chunks
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+        assert_eq!(done.load(Ordering::Relaxed), 10);
+
+        assert!(Chunks::new(0).map(&items[..0], |i| *i).is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let cancel = Cancel::new();
+        let yields = Cell::new(0);
+        let mut chunking = Chunking::new(3, || {
+            yields.set(yields.get() + 1);
+            // cancel in the middle of the operation
+            if yields.get() == 2 {
+                cancel.cancel();
+            }
+            yield_now()
+        })
+        .with_cancel(&cancel);
+
+        let mapped = futures::executor::block_on(chunking.map(0..10, |i| i));
+        assert_eq!(mapped, Err(Cancelled));
+        assert_eq!(yields.get(), 2);
+
+        // the signal stays cancelled
+        assert!(futures::executor::block_on(chunking.map(0..1, |i| i)).is_err());
+    }
+
+    #[test]
+    fn test_deadline() {
+        fn now() -> u64 {
+            100
+        }
+
+        let mut chunking = Chunking::new(3, yield_now).with_deadline(101, now);
+        assert!(futures::executor::block_on(chunking.map(0..10, |i| i)).is_ok());
+
+        let mut chunking = Chunking::new(3, yield_now).with_deadline(100, now);
+        assert_eq!(
+            futures::executor::block_on(chunking.map(0..10, |i| i)),
+            Err(Cancelled)
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! # Committed metadata
+//!
+//! The signer sees the metadata of the tokens it signs. A user that does not want to show the
+//! metadata to the signer generates the token with [`CommittedMetadata`] instead: a policy class
+//! that the signer decides on, e.g. "one day of access", and a commitment to the metadata. The
+//! engines sign and verify the committed metadata as any other metadata.
+//!
+//! The user keeps the [`Opening`] of the commitment, and shows it to the verifier along with the
+//! token. The verifier verifies the token, then [opens](CommittedMetadata::open) the commitment to
+//! get the metadata.
+//!
+//! ```
+//!     use atpmd::{RandomizedUnsignedToken, SignedToken, TokenEngine};
+//!     use atpmd::commitment::CommittedMetadata;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::NizkpTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let (committed, opening) = CommittedMetadata::commit(b"day", b"room 101").unwrap();
+//!
+//!     let signed = NizkpTokenEngine::sign(
+//!         NizkpTokenEngine::generate(committed),
+//!         &public_key,
+//!         |randomized_unsigned| {
+//!             // the signer only sees the class of the metadata
+//!             let metadata = randomized_unsigned.metadata();
+//!             assert_eq!(CommittedMetadata::from(&metadata[..]).class(), Ok(&b"day"[..]));
+//!             NizkpTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!         }
+//!     ).unwrap();
+//!
+//!     assert!(signed.verify(&secret_key));
+//!     assert_eq!(signed.metadata().open(&opening), Ok(&b"room 101"[..]));
+//! ```
+
+use alloc::boxed::Box;
+use core::fmt;
+
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::common::fill_bytes;
+use crate::wire::{Reader, WireError, Writer};
+
+/// Why a commitment did not open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// The committed metadata is not a class and a commitment
+    Malformed(WireError),
+    /// The opening is not the one of the commitment
+    Mismatch,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "malformed committed metadata: {}", e),
+            Self::Mismatch => f.write_str("the opening does not match the commitment"),
+        }
+    }
+}
+
+/// The hash the metadata is committed with, the blinding hides the metadata
+fn commitment(class: &[u8], metadata: &[u8], blinding: &[u8; 32]) -> Result<[u8; 32], WireError> {
+    let mut writer = Writer::new();
+    writer.prefixed(class)?;
+    writer.fixed(blinding);
+    writer.prefixed(metadata)?;
+
+    let mut hasher = Sha256::new();
+    // domain of the oracle, to have separate oracles
+    hasher.update(b"This is metadata commitment hash");
+    hasher.update(writer.into_bytes());
+
+    Ok(hasher.finalize().into())
+}
+
+// {{{ Committed metadata
+
+/// The metadata of a token, as a policy class and a commitment to the metadata
+///
+/// The bytes are the class, prefixed with its length, and the 32 bytes of the commitment. They are
+/// parsed when a part is needed, so committed metadata decoded from a token may be malformed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct CommittedMetadata {
+    bytes: Box<[u8]>,
+}
+
+impl CommittedMetadata {
+    /// Commit to the metadata, with a class the signer sees
+    pub fn commit(
+        class: impl AsRef<[u8]>,
+        metadata: impl AsRef<[u8]>,
+    ) -> Result<(Self, Opening), WireError> {
+        Self::commit_with_rng(class, metadata, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::commit`], with the blinding from the given rng
+    pub fn commit_with_rng<R: CryptoRng + RngCore>(
+        class: impl AsRef<[u8]>,
+        metadata: impl AsRef<[u8]>,
+        rng: &mut R,
+    ) -> Result<(Self, Opening), WireError> {
+        let mut blinding = [0; 32];
+        fill_bytes(rng, &mut blinding);
+
+        let class = class.as_ref();
+        let metadata = metadata.as_ref();
+        let digest = commitment(class, metadata, &blinding)?;
+
+        let mut writer = Writer::new();
+        writer.prefixed(class)?;
+        writer.fixed(digest);
+
+        Ok((
+            Self {
+                bytes: writer.into_bytes().into_boxed_slice(),
+            },
+            Opening {
+                metadata: Box::from(metadata),
+                blinding,
+            },
+        ))
+    }
+
+    fn parts(&self) -> Result<(&[u8], [u8; 32]), WireError> {
+        let mut reader = Reader::new(&self.bytes);
+        let class = reader.prefixed()?;
+        let digest = reader.fixed()?;
+
+        if !reader.is_empty() {
+            return Err(WireError::TrailingBytes);
+        }
+
+        Ok((class, digest))
+    }
+
+    /// The policy class, which the signer decides on
+    pub fn class(&self) -> Result<&[u8], WireError> {
+        self.parts().map(|(class, _digest)| class)
+    }
+
+    /// The metadata of the opening, if it is the opening of the commitment
+    pub fn open<'a>(&self, opening: &'a Opening) -> Result<&'a [u8], OpenError> {
+        let (class, digest) = self.parts().map_err(OpenError::Malformed)?;
+        let opened = commitment(class, &opening.metadata, &opening.blinding)
+            .map_err(OpenError::Malformed)?;
+
+        if bool::from(opened.ct_eq(&digest)) {
+            Ok(&opening.metadata)
+        } else {
+            Err(OpenError::Mismatch)
+        }
+    }
+}
+
+impl AsRef<[u8]> for CommittedMetadata {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<&[u8]> for CommittedMetadata {
+    fn from(bytes: &[u8]) -> Self {
+        Self {
+            bytes: Box::from(bytes),
+        }
+    }
+}
+
+// }}}
+
+// {{{ Opening
+
+/// The metadata of a commitment and its blinding, which the user shows to the verifier
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Opening {
+    metadata: Box<[u8]>,
+    blinding: [u8; 32],
+}
+
+impl Opening {
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() {
+        let (committed, opening) = CommittedMetadata::commit(b"class", b"metadata").unwrap();
+        assert_eq!(committed.class(), Ok(&b"class"[..]));
+        assert_eq!(committed.open(&opening), Ok(&b"metadata"[..]));
+
+        // the commitment hides the metadata
+        let (again, _opening) = CommittedMetadata::commit(b"class", b"metadata").unwrap();
+        assert_ne!(committed, again);
+        assert_eq!(again.open(&opening), Err(OpenError::Mismatch));
+
+        let other = Opening {
+            metadata: Box::from(&b"other"[..]),
+            ..opening.clone()
+        };
+        assert_eq!(committed.open(&other), Err(OpenError::Mismatch));
+
+        // the class is committed to
+        let mut bytes = Writer::new();
+        bytes.prefixed(b"other").unwrap();
+        bytes.fixed(&committed.as_ref()[7..]);
+        let swapped = CommittedMetadata::from(&bytes.into_bytes()[..]);
+        assert_eq!(swapped.open(&opening), Err(OpenError::Mismatch));
+
+        assert_eq!(
+            CommittedMetadata::from(&b"\0\x05class"[..]).open(&opening),
+            Err(OpenError::Malformed(WireError::Truncated))
+        );
+    }
+
+    #[cfg(feature = "pairing")]
+    #[test]
+    fn test_token() {
+        use crate::atpm_pairing::{
+            keys::{PrivateKey, PublicKey},
+            tokens::{PairingSignedToken, PairingTokenEngine},
+        };
+        use crate::wire::WireFormat;
+        use crate::{SignedToken, TokenEngine};
+
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+        let (committed, opening) = CommittedMetadata::commit(b"class", b"metadata").unwrap();
+
+        let signed = PairingTokenEngine::sign(
+            PairingTokenEngine::generate(committed),
+            &public_key,
+            |randomized| PairingTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+
+        let decoded =
+            PairingSignedToken::<CommittedMetadata>::from_bytes(&signed.to_bytes()).unwrap();
+        assert!(decoded.verify(&public_key));
+        assert_eq!(decoded.metadata().open(&opening), Ok(&b"metadata"[..]));
+    }
+}
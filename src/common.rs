@@ -1,93 +1,235 @@
 //! Common traits and functions used in the protocols
 
-use core::{convert::TryInto, iter::repeat_with};
+use core::fmt;
 
 use alloc::{boxed::Box, vec::Vec};
 use rand::{CryptoRng, Rng, RngCore};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha512};
-use subtle::CtOption;
+use sha2::{Digest, Sha256, Sha512};
+use subtle::{Choice, ConstantTimeEq, CtOption};
+use zeroize::DefaultIsZeroes;
+#[cfg(feature = "private_key_serde")]
+use zeroize::Zeroize;
+
+use crate::array::BoxedArray;
+use crate::redemption::{self, RedeemError, Redeemable, RedemptionStore};
+use crate::wire::WireError;
 
 /// Fill some bytes with random data
 ///
-/// Uses thread_rng, wich in turn uses the chacha20 cipher as a random byte stream, seeded from the osrng
+/// Fills them from the given rng, which is [`default_rng`](crate::rng::default_rng) when the caller
+/// has none
 pub fn fill_bytes<R: CryptoRng + RngCore>(rng: &mut R, mut bytes: impl AsMut<[u8]>) {
     bytes.as_mut().iter_mut().for_each(|byte| *byte = rng.gen());
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 /// The identifier for the tokens
 ///
 /// This identifier may have two states:
 /// It may only be a random id, or it may be a random id with some additional hidden public metadata
 /// It is hidden from the signer, but not from the verifier.
-pub enum TokenIdentifier<T: AsRef<[u8]>> {
+///
+/// The bytes of an identifier with hidden metadata are hashed once, when it is created, see
+/// [`TokenIdentifier::as_bytes`]. The fields are private, so the bytes are always the ones of the
+/// random id and the hidden metadata.
+pub struct TokenIdentifier<T: AsRef<[u8]>> {
+    id: [u8; 16],
+    hidden: Option<T>,
+    bytes: [u8; 16],
+}
+
+/// The hash of the random id and the hidden metadata of a [`TokenIdentifier`]
+fn hidden_digest(t: &[u8; 16], hidden: &[u8]) -> [u8; 16] {
+    let mut hasher = Sha512::new();
+
+    // Domain separation of random oracles
+    hasher.update(b"Domain of hidden metadata");
+
+    hasher.update(hidden);
+    hasher.update(t);
+
+    let mut arr = [0u8; 16];
+    // this will take the first 16 bytes of the hash, but this is ok from the
+    // specification. See [SHS](https://doi.org/10.6028/NIST.FIPS.180-4), section 7.
+    for (dst, src) in arr.iter_mut().zip(hasher.finalize().iter()) {
+        *dst = *src;
+    }
+
+    arr
+}
+
+impl<T: AsRef<[u8]>> From<&TokenIdentifier<T>> for [u8; 16] {
+    fn from(val: &TokenIdentifier<T>) -> Self {
+        *val.as_bytes()
+    }
+}
+
+/// The serialized identifier, as it was before the digest was cached
+#[derive(Serialize)]
+#[serde(rename = "TokenIdentifier")]
+enum TokenIdentifierRef<'a, T> {
+    Id(&'a [u8; 16]),
+    WithHidden(&'a [u8; 16], &'a T),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "TokenIdentifier")]
+enum TokenIdentifierOwned<T> {
     Id([u8; 16]),
     WithHidden([u8; 16], T),
 }
 
-impl<T: AsRef<[u8]>> Into<[u8; 16]> for &TokenIdentifier<T> {
-    fn into(self) -> [u8; 16] {
-        let mut arr = [0u8; 16];
-        match self {
-            TokenIdentifier::Id(t) => {
-                for (src, dst) in t.iter().zip(arr.iter_mut()) {
-                    *dst = *src;
-                }
-            }
-            TokenIdentifier::WithHidden(t, data) => {
-                let mut hasher = Sha512::new();
-
-                // Domain separation of random oracles
-                hasher.update(b"Domain of hidden metadata");
-
-                hasher.update(data);
-                hasher.update(t);
-                // this will take the first 16 bytes of the hash, but this is ok from the
-                // specification. See [SHS](https://doi.org/10.6028/NIST.FIPS.180-4), section 7.
-                for (dst, src) in arr.iter_mut().zip(hasher.finalize().iter()) {
-                    *dst = *src;
-                }
-            }
+impl<T: AsRef<[u8]> + Serialize> Serialize for TokenIdentifier<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.hidden {
+            None => TokenIdentifierRef::<T>::Id(&self.id),
+            Some(hidden) => TokenIdentifierRef::WithHidden(&self.id, hidden),
         }
+        .serialize(serializer)
+    }
+}
 
-        arr
+impl<'de, T: AsRef<[u8]> + Deserialize<'de>> Deserialize<'de> for TokenIdentifier<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match TokenIdentifierOwned::deserialize(deserializer)? {
+            TokenIdentifierOwned::Id(t) => Self::from_id(t),
+            TokenIdentifierOwned::WithHidden(t, hidden) => Self::from_hidden(t, hidden),
+        })
     }
 }
 
 impl<T: AsRef<[u8]>> TokenIdentifier<T> {
     /// Create a new random token identifier
     pub fn new() -> Self {
+        Self::new_with_rng(&mut crate::rng::default_rng())
+    }
+
+    /// Create a new random token identifier from the given rng
+    pub fn new_with_rng<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
         let mut t = [0; 16];
-        fill_bytes(&mut rand::thread_rng(), &mut t);
+        fill_bytes(rng, &mut t);
 
-        Self::Id(t)
+        Self::from_id(t)
+    }
+
+    /// The token identifier of a random id
+    pub fn from_id(t: [u8; 16]) -> Self {
+        Self {
+            id: t,
+            hidden: None,
+            bytes: t,
+        }
     }
 
     /// Create a new random token identifier with some hidden public metadata
     pub fn with_hidden(hidden: T) -> Self {
+        Self::with_hidden_with_rng(hidden, &mut crate::rng::default_rng())
+    }
+
+    /// Create a new random token identifier with some hidden public metadata, from the given rng
+    pub fn with_hidden_with_rng<R: CryptoRng + RngCore>(hidden: T, rng: &mut R) -> Self {
         let mut t = [0; 16];
-        fill_bytes(&mut rand::thread_rng(), &mut t);
+        fill_bytes(rng, &mut t);
+
+        Self::from_hidden(t, hidden)
+    }
+
+    /// The token identifier of a random id with some hidden public metadata
+    pub fn from_hidden(t: [u8; 16], hidden: T) -> Self {
+        Self {
+            bytes: hidden_digest(&t, hidden.as_ref()),
+            id: t,
+            hidden: Some(hidden),
+        }
+    }
+
+    /// The canonical bytes of the identifier, that the token is signed with
+    ///
+    /// These are the random id, or the hash of the random id and the hidden metadata.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.bytes
+    }
+
+    /// The random id, which is the canonical bytes when there is no hidden metadata
+    pub fn id(&self) -> &[u8; 16] {
+        &self.id
+    }
 
-        Self::WithHidden(t, hidden)
+    /// The hidden metadata, if the identifier has any
+    pub fn hidden(&self) -> Option<&T> {
+        self.hidden.as_ref()
     }
 
-    pub fn generate<const N: usize>() -> [Self; N] {
-        repeat_with(|| Self::new())
-            .take(N)
-            .collect::<Vec<_>>()
-            .try_into()
-            .ok()
-            .unwrap()
+    /// Whether the identifier is the hash of the random id and the expected hidden metadata
+    ///
+    /// The hash is computed again from the expected metadata, and compared in constant time.
+    pub fn is_hidden(&self, expected: &[u8]) -> Choice {
+        match self.hidden {
+            None => Choice::from(0),
+            Some(_) => hidden_digest(&self.id, expected).ct_eq(&self.bytes),
+        }
+    }
+
+    pub fn generate<const N: usize>() -> BoxedArray<Self, N> {
+        Self::generate_with_rng(&mut crate::rng::default_rng())
+    }
+
+    pub fn generate_with_rng<R: CryptoRng + RngCore, const N: usize>(
+        rng: &mut R,
+    ) -> BoxedArray<Self, N> {
+        BoxedArray::from_fn(|_i| Self::new_with_rng(rng))
+    }
+
+    /// Create N new random token identifiers with the same hidden public metadata
+    ///
+    /// The random part differs for each identifier, so the hidden metadata does not link the
+    /// tokens of a batch to each other.
+    pub fn generate_with_hidden<const N: usize>(hidden: T) -> BoxedArray<Self, N>
+    where
+        T: Clone,
+    {
+        Self::generate_with_hidden_with_rng(hidden, &mut crate::rng::default_rng())
+    }
+
+    pub fn generate_with_hidden_with_rng<R: CryptoRng + RngCore, const N: usize>(
+        hidden: T,
+        rng: &mut R,
+    ) -> BoxedArray<Self, N>
+    where
+        T: Clone,
+    {
+        BoxedArray::from_fn(|_i| Self::with_hidden_with_rng(hidden.clone(), rng))
+    }
+}
+
+impl<T: AsRef<[u8]>> ConstantTimeEq for TokenIdentifier<T> {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        self.as_bytes().ct_eq(other.as_bytes())
     }
 }
 
 impl<T: AsRef<[u8]>> PartialEq for TokenIdentifier<T> {
     fn eq(&self, other: &Self) -> bool {
-        let lhs: [u8; 16] = self.into();
-        let rhs: [u8; 16] = other.into();
-        // i think this should be a constant time comparison thingy
-        lhs.iter().zip(rhs).fold(true, |s, (l, r)| l ^ r == 0 && s)
+        self.ct_eq(other).into()
+    }
+}
+
+impl<T: AsRef<[u8]>> Eq for TokenIdentifier<T> {}
+
+/// A random id, or a random id with hidden metadata
+#[cfg(feature = "arbitrary")]
+impl<'a, T> arbitrary::Arbitrary<'a> for TokenIdentifier<T>
+where
+    T: AsRef<[u8]> + arbitrary::Arbitrary<'a>,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let t = u.arbitrary()?;
+        Ok(if u.arbitrary()? {
+            Self::from_hidden(t, u.arbitrary()?)
+        } else {
+            Self::from_id(t)
+        })
     }
 }
 
@@ -96,7 +238,7 @@ impl<T: AsRef<[u8]>> PartialEq for TokenIdentifier<T> {
 /// SInce this contains the token identifier, this should not be shared directly (that would be
 /// loss of anonymity). If the token identifier is turned into a curve point, this curve point
 /// could be shared.
-pub trait UnsignedToken {
+pub trait UnsignedToken: Sized {
     /// The metadata connected to the token.
     type Metadata;
 
@@ -104,10 +246,25 @@ pub trait UnsignedToken {
     type HiddenMetadata;
 
     /// Create a new unsigned token
-    fn new(metadata: Self::Metadata) -> Self;
+    fn new(metadata: Self::Metadata) -> Self {
+        Self::new_with_rng(metadata, &mut crate::rng::default_rng())
+    }
 
     /// create a new unsigned token with hidden metadata
-    fn with_hidden(metadata: Self::Metadata, hidden: Self::HiddenMetadata) -> Self;
+    fn with_hidden(metadata: Self::Metadata, hidden: Self::HiddenMetadata) -> Self {
+        Self::with_hidden_with_rng(metadata, hidden, &mut crate::rng::default_rng())
+    }
+
+    /// Create a new unsigned token, with the token identifier from the given rng
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self;
+
+    /// Create a new unsigned token with hidden metadata, with the token identifier from the given
+    /// rng
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self;
 }
 
 /// This is a signed token.
@@ -116,6 +273,60 @@ pub trait SignedToken {
     type VerificationKey;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool;
+
+    /// The public metadata of the token, as bytes
+    fn metadata_bytes(&self) -> &[u8];
+
+    /// Verify the token, and that it has not expired at `now`, in seconds since the unix epoch
+    ///
+    /// The metadata must start with the expiration time, see [`Metadata`](crate::expiry::Metadata).
+    fn verify_with_time(&self, verification_key: &Self::VerificationKey, now: u64) -> bool {
+        !crate::expiry::is_expired(self.metadata_bytes(), now) && self.verify(verification_key)
+    }
+
+    /// The identifier of the key that signed this token, if it is known
+    ///
+    /// This is only a hint of which key to verify with, it is not covered by the signature.
+    fn key_id(&self) -> Option<KeyId> {
+        None
+    }
+
+    /// The hidden metadata of the token, if it has any
+    ///
+    /// Only the user and the verifier see it, the signer only signed its hash.
+    fn hidden_metadata(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Verify the token, and that its hidden metadata is the expected
+    fn verify_with_hidden(
+        &self,
+        verification_key: &Self::VerificationKey,
+        expected_hidden: &[u8],
+    ) -> bool {
+        self.hidden_metadata()
+            .is_some_and(|hidden| bool::from(hidden.ct_eq(expected_hidden)))
+            && self.verify(verification_key)
+    }
+
+    /// Verify the token, and that its metadata is `expected_metadata`, e.g. of the resource
+    ///
+    /// A token of another resource is [`Error::WrongResource`], and the metadata is compared in
+    /// constant time.
+    fn verify_for(
+        &self,
+        verification_key: &Self::VerificationKey,
+        expected_metadata: &[u8],
+    ) -> Result<(), Error> {
+        let expected = self.metadata_bytes().ct_eq(expected_metadata);
+        if !self.verify(verification_key) {
+            Err(Error::BadSignature)
+        } else if !bool::from(expected) {
+            Err(Error::WrongResource)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// A randomized unsigned token contains the blinded curve point of the token and the metadata.
@@ -124,6 +335,479 @@ pub trait RandomizedUnsignedToken {
     fn metadata(&self) -> Box<[u8]>;
 }
 
+/// The epoch of a signing key.
+///
+/// The signer gives each of its keys an epoch, which is increased when the key is rotated.
+pub type KeyEpoch = u32;
+
+/// A randomized signed token is the response of the signer.
+///
+/// The signer may fill in the epoch of the key it signed with, so that the user can pick the
+/// right public key if the signer has rotated its key since the user fetched it.
+pub trait RandomizedSignedToken {
+    /// The epoch of the key that signed this token, if the signer filled it in
+    fn key_epoch(&self) -> Option<KeyEpoch>;
+
+    /// Fill in the epoch of the key that signed this token
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self;
+}
+
+/// A set of public keys of a signer, indexed by the epoch of the key.
+///
+/// A signer that picks which key signs the tokens of each user can tell the users apart by their
+/// keys when the tokens are redeemed. So the set must only hold the keys that every user sees,
+/// from a consistent directory, e.g. a [`KeyDirectory`](crate::directory::KeyDirectory) that is
+/// found through [`discovery`](crate::discovery) and checked with
+/// [`verify_directory_consistency`](crate::directory::KeyDirectory::verify_directory_consistency).
+/// Never add a key because a response of the signer names its epoch, a response with an epoch
+/// that is not in the set is rejected.
+///
+/// ```
+///     # use atpmd::PublicKeySet;
+///     # use atpmd::atpm_pairing::keys::{PrivateKey, PublicKey};
+///     let mut keys = PublicKeySet::new();
+///     keys.insert(1, PublicKey::from(PrivateKey::new()));
+///     keys.insert(2, PublicKey::from(PrivateKey::new()));
+///
+///     assert!(keys.get(1).is_some());
+///     assert!(keys.get(3).is_none());
+///     assert_eq!(keys.latest_epoch(), Some(2));
+/// ```
+pub struct PublicKeySet<K> {
+    keys: Vec<(KeyEpoch, K)>,
+}
+
+impl<K> PublicKeySet<K> {
+    /// Create an empty key set
+    pub fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Insert a key, replacing any key with the same epoch
+    pub fn insert(&mut self, epoch: KeyEpoch, key: K) {
+        self.remove(epoch);
+        self.keys.push((epoch, key));
+    }
+
+    /// Remove the key with the given epoch
+    pub fn remove(&mut self, epoch: KeyEpoch) -> Option<K> {
+        let index = self.keys.iter().position(|(e, _)| *e == epoch)?;
+        Some(self.keys.swap_remove(index).1)
+    }
+
+    /// Get the key with the given epoch
+    pub fn get(&self, epoch: KeyEpoch) -> Option<&K> {
+        self.keys
+            .iter()
+            .find(|(e, _)| *e == epoch)
+            .map(|(_, key)| key)
+    }
+
+    /// The highest epoch in the set
+    pub fn latest_epoch(&self) -> Option<KeyEpoch> {
+        self.keys.iter().map(|(e, _)| *e).max()
+    }
+
+    /// The key with the highest epoch
+    pub fn latest(&self) -> Option<&K> {
+        self.latest_epoch().and_then(|epoch| self.get(epoch))
+    }
+
+    /// Iterate over the epochs and keys, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (KeyEpoch, &K)> {
+        self.keys.iter().map(|(e, key)| (*e, key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl<K> Default for PublicKeySet<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A stable identifier of a key, the first bytes of a hash of the public key.
+///
+/// The user fills in the identifier of the public key it unrandomized a token with, so the
+/// verifier may look up the key in a [`KeyRing`].
+/// The public key is known to all users, so this does not tell more about the token than the
+/// key epoch does.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyId([u8; 8]);
+
+impl KeyId {
+    /// The identifier of a public key, from the compressed encoding of the key
+    #[cfg(any(feature = "pairing", feature = "curve25519"))]
+    pub(crate) fn of_public_key(encoded: impl AsRef<[u8]>) -> Self {
+        let mut hasher = Sha512::new();
+
+        // Domain separation of random oracles
+        hasher.update(b"Domain of key identifiers");
+        hasher.update(encoded);
+
+        let mut id = [0u8; 8];
+        id.copy_from_slice(&hasher.finalize()[..8]);
+        Self(id)
+    }
+
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.0
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for KeyId {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self(u.arbitrary()?))
+    }
+}
+
+/// The SHA-256 of the canonical encoding of a public key, to log, index or pin the key
+///
+/// The [`KeyId`] is short, since it is in every token. The fingerprint is the whole hash, of the
+/// [wire](crate::wire) encoding of the key, so it may also be computed without this crate. It is
+/// displayed in hex. The tokens have their
+/// [`Redeemable::fingerprint`](crate::redemption::Redeemable::fingerprint) instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyFingerprint([u8; 32]);
+
+impl KeyFingerprint {
+    pub(crate) fn of_encoding(encoded: impl AsRef<[u8]>) -> Self {
+        Self(Sha256::digest(encoded.as_ref()).into())
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl fmt::Display for KeyFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// A key that has a [`KeyId`]
+///
+/// A private key has the identifier of its public key.
+pub trait HasKeyId {
+    fn key_id(&self) -> KeyId;
+}
+
+/// The verification keys of a signer, looked up by the key identifier in the tokens.
+///
+/// The verifier keeps the keys of all the epochs it still accepts, so that the signer may rotate
+/// its key without invalidating the tokens it has already signed.
+/// A token without a key identifier is checked against all the keys.
+///
+/// ```
+///     # use atpmd::{Error, KeyRing, TokenEngine};
+///     # use atpmd::atpm_pairing::{keys::{PrivateKey, PublicKey}, tokens::PairingTokenEngine};
+///     let old_key = PrivateKey::new();
+///     let new_key = PrivateKey::new();
+///
+///     let signed = PairingTokenEngine::sign(
+///         PairingTokenEngine::generate(&b"metadata"[..]),
+///         &PublicKey::from(&old_key),
+///         |randomized| PairingTokenEngine::sign_randomized(randomized, &old_key),
+///     ).unwrap();
+///
+///     let mut keys = KeyRing::new();
+///     keys.insert(1, PublicKey::from(&old_key));
+///     keys.insert(2, PublicKey::from(&new_key));
+///     assert!(PairingTokenEngine::verify_with_key_ring(&signed, &keys).is_ok());
+///
+///     keys.remove(1);
+///     assert_eq!(
+///         PairingTokenEngine::verify_with_key_ring(&signed, &keys),
+///         Err(Error::KeyMismatch)
+///     );
+/// ```
+pub struct KeyRing<K> {
+    keys: Vec<(KeyEpoch, KeyId, K)>,
+}
+
+impl<K> KeyRing<K> {
+    /// Create an empty key ring
+    pub fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    /// Remove the key with the given epoch
+    pub fn remove(&mut self, epoch: KeyEpoch) -> Option<K> {
+        let index = self.keys.iter().position(|(e, _, _)| *e == epoch)?;
+        Some(self.keys.swap_remove(index).2)
+    }
+
+    /// Get the key with the given identifier
+    pub fn get(&self, key_id: KeyId) -> Option<&K> {
+        self.keys
+            .iter()
+            .find(|(_, id, _)| *id == key_id)
+            .map(|(_, _, key)| key)
+    }
+
+    /// The epoch of the key with the given identifier
+    pub fn epoch(&self, key_id: KeyId) -> Option<KeyEpoch> {
+        self.keys
+            .iter()
+            .find(|(_, id, _)| *id == key_id)
+            .map(|(epoch, _, _)| *epoch)
+    }
+
+    /// Verify a token with the key that signed it
+    ///
+    /// Fails if the token names a key that is not in the ring, and checks all the keys if the
+    /// token does not name a key.
+    pub fn verify<T: SignedToken<VerificationKey = K>>(&self, token: &T) -> bool {
+        match token.key_id() {
+            Some(key_id) => self.get(key_id).is_some_and(|key| token.verify(key)),
+            None => self.keys.iter().any(|(_, _, key)| token.verify(key)),
+        }
+    }
+
+    /// Iterate over the epochs, identifiers and keys, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (KeyEpoch, KeyId, &K)> {
+        self.keys.iter().map(|(e, id, key)| (*e, *id, key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl<K: HasKeyId> KeyRing<K> {
+    /// Insert a key, replacing any key with the same epoch
+    pub fn insert(&mut self, epoch: KeyEpoch, key: K) {
+        self.remove(epoch);
+        self.keys.push((epoch, key.key_id(), key));
+    }
+}
+
+impl<K> Default for KeyRing<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The reason a batched response from the signer was rejected.
+///
+/// A signer could tag a user by returning some points that are equal, or equal to the identity,
+/// so the user checks this before the response is accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchResponseError {
+    /// The signer did not return the same number of points as was sent
+    WrongCount { expected: usize, actual: usize },
+    /// Two of the returned points are equal
+    DuplicatePoint,
+    /// One of the returned points is the identity
+    IdentityPoint,
+}
+
+/// Check that there are exactly `expected` returned points, that they are all distinct, and that
+/// none are the identity.
+///
+/// The points are given by their canonical encoding.
+pub(crate) fn check_batch_response<P: AsRef<[u8]>>(
+    expected: usize,
+    points: impl IntoIterator<Item = P>,
+    identity: impl AsRef<[u8]>,
+) -> Result<(), BatchResponseError> {
+    let mut points = points
+        .into_iter()
+        .map(|point| Box::<[u8]>::from(point.as_ref()))
+        .collect::<Vec<_>>();
+
+    if points.len() != expected {
+        return Err(BatchResponseError::WrongCount {
+            expected,
+            actual: points.len(),
+        });
+    }
+
+    if points.iter().any(|point| **point == *identity.as_ref()) {
+        return Err(BatchResponseError::IdentityPoint);
+    }
+
+    // equal points are next to each other after sorting
+    points.sort_unstable();
+    if points.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(BatchResponseError::DuplicatePoint);
+    }
+
+    Ok(())
+}
+
+/// Why an operation of a [`TokenEngine`] failed
+///
+/// A forged or tampered response is a [`BadProof`](Error::BadProof) or
+/// [`BadSignature`](Error::BadSignature), while the other errors point to a bug or a mixup in the
+/// transport, e.g. tokens or keys that do not belong together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The proof of the signer does not verify
+    BadProof,
+    /// The signature of a token does not verify
+    BadSignature,
+    /// The sum of the metadata scalar and the private key is zero, so the metadata can not be
+    /// signed with this key
+    NonInvertibleScalar,
+    /// The key is not the key of the token, or it is not in the key set or key ring
+    KeyMismatch,
+    /// A point could not be decoded, or is not in the group
+    MalformedPoint,
+    /// A token could not be decoded
+    Malformed(WireError),
+    /// The metadata of the randomized token is not the metadata of the unsigned token, or the
+    /// tokens of a batch have different metadata
+    MetadataMismatch,
+    /// A batch has no tokens
+    EmptyBatch,
+    /// The token verifies, but its metadata is not that of the resource, see
+    /// [`SignedToken::verify_for`]
+    WrongResource,
+    /// A batched response was rejected
+    BatchResponse(BatchResponseError),
+    /// The signer did not sign the token, e.g. it refused or could not be reached
+    NotSigned,
+    /// A chunked operation was cancelled, or ran past its deadline
+    Cancelled,
+    /// The issuer refused to sign, and said why
+    Refused(crate::refusal::Refusal),
+    /// The refusal is not signed by the issuer, or is too old
+    BadRefusal,
+    /// The metadata looks like it identifies the user, see [`guard`](crate::guard)
+    UnsafeMetadata(crate::guard::Violation),
+    /// The policy of the signer does not allow the metadata, see [`policy`](crate::policy)
+    Policy(crate::policy::PolicyError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadProof => f.write_str("the proof of the signer does not verify"),
+            Self::BadSignature => f.write_str("the signature does not verify"),
+            Self::NonInvertibleScalar => {
+                f.write_str("the metadata can not be signed with this key")
+            }
+            Self::KeyMismatch => f.write_str("the key does not belong to the token"),
+            Self::MalformedPoint => f.write_str("a point is not valid"),
+            Self::Malformed(e) => write!(f, "malformed token: {}", e),
+            Self::MetadataMismatch => f.write_str("the metadata of the tokens does not match"),
+            Self::EmptyBatch => f.write_str("the batch has no tokens"),
+            Self::WrongResource => f.write_str("the token is for another resource"),
+            Self::BatchResponse(e) => write!(f, "bad batched response: {:?}", e),
+            Self::NotSigned => f.write_str("the signer did not sign the token"),
+            Self::Cancelled => f.write_str("the operation was cancelled"),
+            Self::Refused(refusal) => write!(f, "the signer refused: {}", refusal.reason),
+            Self::BadRefusal => f.write_str("the refusal is not from the signer"),
+            Self::UnsafeMetadata(v) => write!(f, "the metadata may identify the user: {}", v),
+            Self::Policy(e) => write!(f, "the policy of the signer does not allow it: {}", e),
+        }
+    }
+}
+
+impl From<BatchResponseError> for Error {
+    fn from(e: BatchResponseError) -> Self {
+        Self::BatchResponse(e)
+    }
+}
+
+impl From<crate::chunked::Cancelled> for Error {
+    fn from(_e: crate::chunked::Cancelled) -> Self {
+        Self::Cancelled
+    }
+}
+
+impl From<WireError> for Error {
+    fn from(e: WireError) -> Self {
+        match e {
+            WireError::InvalidPoint => Self::MalformedPoint,
+            e => Self::Malformed(e),
+        }
+    }
+}
+
+/// A signature that did not verify, with the inputs of the client back
+///
+/// The client may try another response of the signer with them, e.g. from another issuer.
+pub struct VerifyError<E: TokenEngine + ?Sized> {
+    pub error: Error,
+    pub unsigned_token: E::UnsignedToken,
+    pub randomized_unsigned: E::RandomizedUnsignedToken,
+    pub randomization: E::Randomization,
+}
+
+impl<E: TokenEngine + ?Sized> VerifyError<E> {
+    pub(crate) fn new(
+        error: Error,
+        unsigned_token: E::UnsignedToken,
+        randomized_unsigned: E::RandomizedUnsignedToken,
+        randomization: E::Randomization,
+    ) -> Self {
+        Self {
+            error,
+            unsigned_token,
+            randomized_unsigned,
+            randomization,
+        }
+    }
+}
+
+// not derived, the randomization must not end up in logs
+impl<E: TokenEngine + ?Sized> fmt::Debug for VerifyError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerifyError")
+            .field("error", &self.error)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E: TokenEngine + ?Sized> fmt::Display for VerifyError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<E: TokenEngine + ?Sized> From<VerifyError<E>> for Error {
+    fn from(e: VerifyError<E>) -> Self {
+        e.error
+    }
+}
+
+/// The metadata of a randomized token must be that of the unsigned token it was made from
+pub(crate) fn check_metadata(unsigned: impl AsRef<[u8]>, randomized: &[u8]) -> Result<(), Error> {
+    if unsigned.as_ref() == randomized {
+        Ok(())
+    } else {
+        Err(Error::MetadataMismatch)
+    }
+}
+
+/// The result of signing with a scalar that may not be invertible
+pub(crate) fn invertible<T>(signed: CtOption<T>) -> Result<T, Error> {
+    Option::from(signed).ok_or(Error::NonInvertibleScalar)
+}
+
 /// The token engine is the glue of the types.
 ///
 /// Creating a signed token is split up into 4 parts; randomize, (create signature), verify
@@ -161,7 +845,7 @@ pub trait RandomizedUnsignedToken {
 ///
 ///     # let verification_key = public_key;
 ///     // verifier verifies the signature
-///     assert!(TokenEngine::verify(&signed_token, &verification_key))
+///     assert!(TokenEngine::verify(&signed_token, &verification_key).is_ok())
 /// ```
 ///
 /// See examples for usage.
@@ -173,7 +857,7 @@ pub trait TokenEngine {
     type RandomizedUnsignedToken: RandomizedUnsignedToken;
 
     /// A signed token that is anonymous
-    type RandomizedSignedToken;
+    type RandomizedSignedToken: RandomizedSignedToken;
 
     /// A signed token
     type SignedToken: SignedToken;
@@ -202,25 +886,130 @@ pub trait TokenEngine {
         UnsignedToken::with_hidden(metadata, hidden)
     }
 
+    /// [`TokenEngine::generate`], with the randomness from the given rng
+    fn generate_with_rng<R: CryptoRng + RngCore>(
+        metadata: <Self::UnsignedToken as UnsignedToken>::Metadata,
+        rng: &mut R,
+    ) -> Self::UnsignedToken {
+        UnsignedToken::new_with_rng(metadata, rng)
+    }
+
+    /// [`TokenEngine::generate_with_hidden`], with the randomness from the given rng
+    fn generate_with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: <Self::UnsignedToken as UnsignedToken>::Metadata,
+        hidden: <Self::UnsignedToken as UnsignedToken>::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self::UnsignedToken {
+        UnsignedToken::with_hidden_with_rng(metadata, hidden, rng)
+    }
+
     /// Randomize an unsigned token
     fn randomize(
         unsigned_token: &Self::UnsignedToken,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        Self::randomize_with_rng(unsigned_token, &mut crate::rng::default_rng())
+    }
+
+    /// [`TokenEngine::randomize`], with the randomness from the given rng
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken);
 
     /// Sign a randomized unsigned token
+    ///
+    /// This runs in constant time in the key, up to the check that d + k is invertible at the end,
+    /// as the error tells anyway.
     fn sign_randomized(
         randomized_unsigned: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> CtOption<Self::RandomizedSignedToken>;
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        Self::sign_randomized_with_rng(
+            randomized_unsigned,
+            sign_key,
+            &mut crate::rng::default_rng(),
+        )
+    }
+
+    /// [`TokenEngine::sign_randomized`], with the randomness of the proof from the given rng
+    ///
+    /// The engines without a proof do not use the rng.
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error>;
+
+    /// [`TokenEngine::sign_randomized`], if the policy allows the metadata in the context of the
+    /// request
+    fn sign_randomized_with_policy<P, Ctx>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        policy: &P,
+        context: &Ctx,
+    ) -> Result<Self::RandomizedSignedToken, Error>
+    where
+        P: crate::policy::SignerPolicy<Ctx>,
+        Ctx: ?Sized,
+    {
+        policy
+            .allow(&randomized_unsigned.metadata(), context)
+            .map_err(Error::Policy)?;
+        Self::sign_randomized(randomized_unsigned, sign_key)
+    }
 
     /// Verify that the signature is a valid signature, and remove the randomization
+    ///
+    /// The tokens and the randomization are only consumed on success, the [`VerifyError`] gives
+    /// them back.
     fn verify_signature_and_unrandomize(
         unsigned_token: Self::UnsignedToken,
         randomized_unsigned: Self::RandomizedUnsignedToken,
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken>;
+    ) -> Result<Self::SignedToken, VerifyError<Self>>;
+
+    /// Verify that the signature is a valid signature, and remove the randomization
+    ///
+    /// The public key is picked from the key set by the key epoch in the signed token.
+    /// If the signer did not fill in the key epoch, the latest key in the set is used.
+    /// A key epoch that is not in the set fails with [`KeyMismatch`](Error::KeyMismatch).
+    ///
+    /// The signer chooses the epoch, so it could sign each user with another key and link their
+    /// tokens by it. The key set must come from a directory that all users agree on, see
+    /// [`PublicKeySet`].
+    fn verify_signature_and_unrandomize_with_key_set(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        key_set: &PublicKeySet<Self::UserVerification>,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        let verification_data = match signed_token.key_epoch() {
+            Some(epoch) => key_set.get(epoch),
+            None => key_set.latest(),
+        };
+        let verification_data = match verification_data {
+            Some(verification_data) => verification_data,
+            None => {
+                return Err(VerifyError::new(
+                    Error::KeyMismatch,
+                    unsigned_token,
+                    randomized_unsigned,
+                    randomization,
+                ))
+            }
+        };
+
+        Self::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized_unsigned,
+            signed_token,
+            verification_data,
+            randomization,
+        )
+    }
 
     /// Sign a token
     ///
@@ -229,48 +1018,259 @@ pub trait TokenEngine {
         unsigned_token: Self::UnsignedToken,
         verification_data: &Self::UserVerification,
         sign_func: F,
-    ) -> Option<Self::SignedToken>
+    ) -> Result<Self::SignedToken, Error>
     where
-        F: Fn(&Self::RandomizedUnsignedToken) -> CtOption<Self::RandomizedSignedToken>,
+        F: Fn(&Self::RandomizedUnsignedToken) -> Result<Self::RandomizedSignedToken, Error>,
     {
         let (r, randomized_unsigned) = Self::randomize(&unsigned_token);
 
-        // use the sign_func as an oracle to get a CtOption<RandomizedSignedToken>
-        let randomized_signed = sign_func(&randomized_unsigned);
+        // use the sign_func as an oracle to get the RandomizedSignedToken
+        let randomized_signed = sign_func(&randomized_unsigned)?;
 
-        if bool::from(randomized_signed.is_none()) {
-            return None;
-        }
-
-        Self::verify_signature_and_unrandomize(
+        Ok(Self::verify_signature_and_unrandomize(
             unsigned_token,
             randomized_unsigned,
-            randomized_signed.unwrap(),
+            randomized_signed,
             verification_data,
             r,
-        )
+        )?)
     }
 
     /// Verify a token
     fn verify(
         token: &Self::SignedToken,
         verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
-    ) -> bool {
-        token.verify(verification_key)
+    ) -> Result<(), Error> {
+        if token.verify(verification_key) {
+            Ok(())
+        } else {
+            Err(Error::BadSignature)
+        }
+    }
+
+    /// Verify a token with the key in the key ring that signed it
+    ///
+    /// Fails with [`Error::KeyMismatch`] if the token names a key that is not in the ring.
+    fn verify_with_key_ring(
+        token: &Self::SignedToken,
+        key_ring: &KeyRing<<Self::SignedToken as SignedToken>::VerificationKey>,
+    ) -> Result<(), Error> {
+        if token
+            .key_id()
+            .is_some_and(|key_id| key_ring.get(key_id).is_none())
+        {
+            Err(Error::KeyMismatch)
+        } else if key_ring.verify(token) {
+            Ok(())
+        } else {
+            Err(Error::BadSignature)
+        }
+    }
+
+    /// Verify a token that has not expired at `now`, and mark it as redeemed in the store
+    fn redeem<S: RedemptionStore>(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+        store: &mut S,
+        now: u64,
+    ) -> Result<(), RedeemError>
+    where
+        Self::SignedToken: Redeemable,
+    {
+        redemption::redeem(token, verification_key, store, now)
+    }
+
+    /// Verify a token without an expiration time, and mark it as redeemed in the store
+    fn redeem_without_expiry<S: RedemptionStore>(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+        store: &mut S,
+    ) -> Result<(), RedeemError>
+    where
+        Self::SignedToken: Redeemable,
+    {
+        redemption::redeem_without_expiry(token, verification_key, store)
+    }
+}
+
+/// A secret scalar in a private key
+///
+/// The scalar types of the curves do not implement zeroize, but they are zero by default, so this
+/// wrapper can be zeroized.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Secret<S: Copy + Default>(pub(crate) S);
+
+impl<S: Copy + Default> DefaultIsZeroes for Secret<S> {}
+
+/// The serialized private key, the bytes are zeroized when this is dropped
+#[cfg(feature = "private_key_serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "PrivateKey")]
+pub(crate) struct PrivateKeyBytes {
+    pub(crate) key: Vec<u8>,
+}
+
+#[cfg(feature = "private_key_serde")]
+impl PrivateKeyBytes {
+    /// The bytes, if there are exactly len of them
+    pub(crate) fn bytes<E: serde::de::Error>(&self, len: usize) -> Result<&[u8], E> {
+        if self.key.len() == len {
+            Ok(&self.key)
+        } else {
+            Err(E::invalid_length(self.key.len(), &"the length of a scalar"))
+        }
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl Drop for PrivateKeyBytes {
+    fn drop(&mut self) {
+        self.key.zeroize();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::fill_bytes;
+    use super::{
+        check_batch_response, fill_bytes, BatchResponseError, HasKeyId, KeyId, KeyRing,
+        PublicKeySet, SignedToken, TokenIdentifier,
+    };
     #[test]
     fn fill_bytes_test() {
         let mut b1 = [0u8; 32];
         let mut b2 = [0u8; 32];
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
         fill_bytes(&mut rng, &mut b1);
         fill_bytes(&mut rng, &mut b2);
         // probability of a collision is really small (2^{-256})
         assert_ne!(b1, b2);
     }
+
+    #[test]
+    fn token_identifier_bytes() {
+        use sha2::{Digest, Sha512};
+
+        let id = TokenIdentifier::from_hidden([7; 16], &b"hidden"[..]);
+
+        let mut hasher = Sha512::new();
+        hasher.update(b"Domain of hidden metadata");
+        hasher.update(b"hidden");
+        hasher.update([7; 16]);
+        assert_eq!(id.as_bytes()[..], hasher.finalize()[..16]);
+        assert_eq!(TokenIdentifier::<&[u8]>::from_id([7; 16]).as_bytes(), &[7; 16]);
+
+        assert_eq!(id, TokenIdentifier::from_hidden([7; 16], &b"hidden"[..]));
+        assert_ne!(id, TokenIdentifier::from_hidden([7; 16], &b"other"[..]));
+        assert_ne!(id, TokenIdentifier::from_id([7; 16]));
+        assert_eq!(id.id(), &[7; 16]);
+
+        // serialized as before the digest, which is computed again
+        let (t, hidden) = ([7u8; 16], b"hidden");
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({ "WithHidden": [t, hidden] })
+        );
+        let decoded: TokenIdentifier<alloc::vec::Vec<u8>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.as_bytes(), id.as_bytes());
+    }
+
+    #[test]
+    fn token_identifier_hidden() {
+        let id = TokenIdentifier::with_hidden(&b"hidden"[..]);
+        assert_eq!(id.hidden(), Some(&&b"hidden"[..]));
+        assert!(bool::from(id.is_hidden(b"hidden")));
+        assert!(!bool::from(id.is_hidden(b"other")));
+
+        let id = TokenIdentifier::<&[u8]>::new();
+        assert_eq!(id.hidden(), None);
+        assert!(!bool::from(id.is_hidden(b"")));
+    }
+
+    #[test]
+    fn key_set_insert_replaces() {
+        let mut keys = PublicKeySet::new();
+        keys.insert(1, "old");
+        keys.insert(1, "new");
+        keys.insert(0, "older");
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.get(1), Some(&"new"));
+        assert_eq!(keys.latest(), Some(&"new"));
+
+        assert_eq!(keys.remove(1), Some("new"));
+        assert_eq!(keys.latest(), Some(&"older"));
+    }
+
+    struct Key(&'static str);
+
+    impl HasKeyId for Key {
+        fn key_id(&self) -> KeyId {
+            KeyId::of_public_key(self.0)
+        }
+    }
+
+    /// A token that is valid for one key
+    struct Token(&'static str, Option<KeyId>);
+
+    impl SignedToken for Token {
+        type VerificationKey = Key;
+
+        fn verify(&self, verification_key: &Key) -> bool {
+            self.0 == verification_key.0
+        }
+
+        fn metadata_bytes(&self) -> &[u8] {
+            &[]
+        }
+
+        fn key_id(&self) -> Option<KeyId> {
+            self.1
+        }
+    }
+
+    #[test]
+    fn key_ring_lookup() {
+        let mut keys = KeyRing::new();
+        keys.insert(1, Key("old"));
+        keys.insert(2, Key("new"));
+
+        let old = KeyId::of_public_key("old");
+        assert_ne!(old, KeyId::of_public_key("new"));
+        assert_eq!(keys.epoch(old), Some(1));
+        assert_eq!(keys.get(old).map(|key| key.0), Some("old"));
+
+        assert!(keys.verify(&Token("old", Some(old))));
+        // the token names the key, so the other keys are not tried
+        assert!(!keys.verify(&Token("new", Some(old))));
+        // without a key id all the keys are tried
+        assert!(keys.verify(&Token("new", None)));
+
+        keys.insert(1, Key("newer"));
+        assert_eq!(keys.len(), 2);
+        assert!(!keys.verify(&Token("old", Some(old))));
+        assert!(!keys.verify(&Token("old", None)));
+    }
+
+    #[test]
+    fn batch_response_check() {
+        let identity = [0u8; 2];
+
+        assert_eq!(check_batch_response(2, [[1, 2], [2, 1]], identity), Ok(()));
+        assert_eq!(
+            check_batch_response(3, [[1, 2], [2, 1]], identity),
+            Err(BatchResponseError::WrongCount {
+                expected: 3,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            check_batch_response(3, [[1, 2], [2, 1], [1, 2]], identity),
+            Err(BatchResponseError::DuplicatePoint)
+        );
+        assert_eq!(
+            check_batch_response(2, [[1, 2], [0, 0]], identity),
+            Err(BatchResponseError::IdentityPoint)
+        );
+    }
 }
@@ -0,0 +1,411 @@
+//! # Conformance suite of the engines
+//!
+//! The checks that every [`TokenEngine`] has to pass, whatever its curve: the tokens round-trip
+//! through issuance and serde, a token with any signed byte changed does not verify, a token does
+//! not verify with another key, a response for other metadata is rejected, and the hidden metadata
+//! is disclosed to the verifier. A backend implements [`Backend`] and instantiates the tests with
+//! [`conformance!`](crate::conformance!). This is built for the tests of the crate, and with the
+//! `conformance` feature for the tests of other crates.
+//!
+//! ```
+//!     use atpmd::conformance::Backend;
+//!     use atpmd::nizkp_curve25519::{keys::PrivateKey, tokens::NizkpTokenEngine};
+//!
+//!     struct Curve25519;
+//!
+//!     impl Backend for Curve25519 {
+//!         type Engine = NizkpTokenEngine<Vec<u8>>;
+//!
+//!         fn verification_key(sign_key: &PrivateKey) -> PrivateKey {
+//!             sign_key.clone()
+//!         }
+//!
+//!         fn metadata(bytes: &[u8]) -> Vec<u8> {
+//!             bytes.to_vec()
+//!         }
+//!
+//!         fn hidden_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+//!             Some(bytes.to_vec())
+//!         }
+//!     }
+//!
+//!     // in the tests of the backend, this is `atpmd::conformance!(curve25519, Curve25519);`
+//!     atpmd::conformance::tampered::<Curve25519>();
+//! ```
+
+use alloc::{format, string::String, vec, vec::Vec};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::common::{Error, SignedToken, TokenEngine, UnsignedToken};
+
+/// The sign key of the engine of a backend
+pub type SignKey<B> = <<B as Backend>::Engine as TokenEngine>::SignKey;
+/// The signed tokens of the engine of a backend
+pub type Token<B> = <<B as Backend>::Engine as TokenEngine>::SignedToken;
+/// The key the verifier verifies the tokens of a backend with
+pub type VerificationKey<B> = <Token<B> as SignedToken>::VerificationKey;
+/// The public metadata of the tokens of a backend
+pub type Metadata<B> =
+    <<<B as Backend>::Engine as TokenEngine>::UnsignedToken as UnsignedToken>::Metadata;
+/// The hidden metadata of the tokens of a backend
+pub type HiddenMetadata<B> =
+    <<<B as Backend>::Engine as TokenEngine>::UnsignedToken as UnsignedToken>::HiddenMetadata;
+
+/// An engine, and how to make its keys and metadata
+pub trait Backend {
+    type Engine: TokenEngine;
+
+    /// The fields of the serialized tokens that the signature does not cover, such as the hint
+    /// of the key id
+    const UNSIGNED_FIELDS: &'static [&'static str] = &["key_id"];
+
+    /// The verification key of a sign key
+    fn verification_key(sign_key: &SignKey<Self>) -> VerificationKey<Self>;
+
+    fn metadata(bytes: &[u8]) -> Metadata<Self>;
+
+    /// The hidden metadata of the bytes, none if the tokens have no hidden metadata
+    fn hidden_metadata(bytes: &[u8]) -> Option<HiddenMetadata<Self>>;
+}
+
+// {{{ Checks
+
+/// Issue a token with the key
+fn issue<B: Backend>(
+    sign_key: &SignKey<B>,
+    unsigned: <B::Engine as TokenEngine>::UnsignedToken,
+) -> Token<B> {
+    B::Engine::sign(unsigned, &sign_key.clone().into(), |randomized| {
+        B::Engine::sign_randomized(randomized, sign_key)
+    })
+    .unwrap_or_else(|e| panic!("a token of the key was not issued: {}", e))
+}
+
+/// A token of the key verifies, and only for the metadata it was issued with
+pub fn round_trip<B: Backend>() {
+    let sign_key = SignKey::<B>::default();
+    let key = B::verification_key(&sign_key);
+
+    let token = issue::<B>(&sign_key, B::Engine::generate(B::metadata(b"metadata")));
+    assert!(B::Engine::verify(&token, &key).is_ok());
+    assert_eq!(token.metadata_bytes(), b"metadata");
+    assert_eq!(token.verify_for(&key, b"metadata"), Ok(()));
+    assert_eq!(token.verify_for(&key, b"other"), Err(Error::WrongResource));
+}
+
+/// A token verifies after a round-trip through serde, and serializes the same
+pub fn serde<B: Backend>()
+where
+    Token<B>: Serialize + DeserializeOwned,
+{
+    let sign_key = SignKey::<B>::default();
+    let key = B::verification_key(&sign_key);
+    let token = issue::<B>(&sign_key, B::Engine::generate(B::metadata(b"metadata")));
+
+    let json = serde_json::to_value(&token).unwrap();
+    let decoded: Token<B> = serde_json::from_value(json.clone()).unwrap();
+    assert!(decoded.verify(&key));
+    assert_eq!(serde_json::to_value(&decoded).unwrap(), json);
+}
+
+/// A token with any signed leaf of its serialization changed does not verify
+///
+/// This covers the signature, the identifier and the metadata of the token.
+pub fn tampered<B: Backend>()
+where
+    Token<B>: Serialize + DeserializeOwned,
+{
+    let sign_key = SignKey::<B>::default();
+    let key = B::verification_key(&sign_key);
+    let token = issue::<B>(&sign_key, B::Engine::generate(B::metadata(b"metadata")));
+
+    let json = serde_json::to_value(&token).unwrap();
+    let signed = mutations(&json)
+        .into_iter()
+        .filter(|(path, _)| !B::UNSIGNED_FIELDS.contains(&field(path)));
+    for (path, mutated) in signed {
+        if let Ok(decoded) = serde_json::from_value::<Token<B>>(mutated) {
+            assert!(
+                !decoded.verify(&key),
+                "the token verifies with {} changed",
+                path
+            );
+        }
+    }
+}
+
+/// A token does not verify with another key, and the user rejects a response of another key
+pub fn wrong_keys<B: Backend>() {
+    let sign_key = SignKey::<B>::default();
+    let other = SignKey::<B>::default();
+
+    let token = issue::<B>(&sign_key, B::Engine::generate(B::metadata(b"metadata")));
+    assert!(!token.verify(&B::verification_key(&other)));
+    assert_eq!(
+        token.verify_for(&B::verification_key(&other), b"metadata"),
+        Err(Error::BadSignature)
+    );
+
+    let result = B::Engine::sign(
+        B::Engine::generate(B::metadata(b"metadata")),
+        &sign_key.clone().into(),
+        |randomized| B::Engine::sign_randomized(randomized, &other),
+    );
+    assert!(result.is_err(), "a response of another key is accepted");
+}
+
+/// The user rejects a response for a request with other metadata
+pub fn metadata_mutation<B: Backend>() {
+    let sign_key = SignKey::<B>::default();
+
+    let token = B::Engine::generate(B::metadata(b"metadata"));
+    let (r, randomized) = B::Engine::randomize(&token);
+    let (_, other) = B::Engine::randomize(&B::Engine::generate(B::metadata(b"other")));
+
+    let response = B::Engine::sign_randomized(&other, &sign_key).unwrap();
+    let result = B::Engine::verify_signature_and_unrandomize(
+        token,
+        randomized,
+        response,
+        &sign_key.clone().into(),
+        r,
+    );
+    assert!(result.is_err(), "a response for other metadata is accepted");
+}
+
+/// The verifier sees the hidden metadata of a token, and checks it against the expected
+pub fn hidden<B: Backend>() {
+    let hidden = match B::hidden_metadata(b"hidden") {
+        Some(hidden) => hidden,
+        None => return,
+    };
+
+    let sign_key = SignKey::<B>::default();
+    let key = B::verification_key(&sign_key);
+
+    let token = issue::<B>(
+        &sign_key,
+        B::Engine::generate_with_hidden(B::metadata(b"metadata"), hidden),
+    );
+    assert!(token.verify(&key));
+    assert_eq!(token.hidden_metadata(), Some(&b"hidden"[..]));
+    assert!(token.verify_with_hidden(&key, b"hidden"));
+    assert!(!token.verify_with_hidden(&key, b"other"));
+
+    let token = issue::<B>(&sign_key, B::Engine::generate(B::metadata(b"metadata")));
+    assert_eq!(token.hidden_metadata(), None);
+    assert!(!token.verify_with_hidden(&key, b"hidden"));
+}
+
+// }}}
+
+// {{{ Mutations
+
+/// Every copy of the value with one leaf changed, with the path of the leaf
+fn mutations(value: &Value) -> Vec<(String, Value)> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .flat_map(|(i, leaf)| {
+                mutations(leaf).into_iter().map(move |(path, mutated)| {
+                    let mut copy = values.clone();
+                    copy[i] = mutated;
+                    (format!("[{}]{}", i, path), Value::Array(copy))
+                })
+            })
+            .collect(),
+        Value::Object(fields) => fields
+            .iter()
+            .flat_map(|(name, leaf)| {
+                mutations(leaf).into_iter().map(move |(path, mutated)| {
+                    let mut copy = fields.clone();
+                    copy.insert(name.clone(), mutated);
+                    (format!(".{}{}", name, path), Value::Object(copy))
+                })
+            })
+            .collect(),
+        Value::Number(n) => n
+            .as_u64()
+            .map(|n| vec![(String::new(), Value::from(n ^ 1))])
+            .unwrap_or_default(),
+        Value::String(s) => {
+            let first = if s.starts_with('0') { "1" } else { "0" };
+            let rest: String = s.chars().skip(1).collect();
+            vec![(String::new(), Value::from(format!("{}{}", first, rest)))]
+        }
+        Value::Bool(b) => vec![(String::new(), Value::Bool(!b))],
+        Value::Null => vec![],
+    }
+}
+
+/// The top level field of a path
+fn field(path: &str) -> &str {
+    path.trim_start_matches('.')
+        .split(['.', '['])
+        .next()
+        .unwrap_or("")
+}
+
+// }}}
+
+/// Instantiate the conformance tests of a [`Backend`](crate::conformance::Backend), in a module
+///
+/// The signed tokens of the engine have to be serializable with serde.
+#[macro_export]
+macro_rules! conformance {
+    ($name:ident, $backend:ty) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            #[test]
+            fn round_trip() {
+                $crate::conformance::round_trip::<$backend>()
+            }
+
+            #[test]
+            fn serde() {
+                $crate::conformance::serde::<$backend>()
+            }
+
+            #[test]
+            fn tampered() {
+                $crate::conformance::tampered::<$backend>()
+            }
+
+            #[test]
+            fn wrong_keys() {
+                $crate::conformance::wrong_keys::<$backend>()
+            }
+
+            #[test]
+            fn metadata_mutation() {
+                $crate::conformance::metadata_mutation::<$backend>()
+            }
+
+            #[test]
+            fn hidden() {
+                $crate::conformance::hidden::<$backend>()
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mutations() {
+        let value = serde_json::json!({ "id": [1, 2], "key_id": null, "point": "ab" });
+        let paths: Vec<_> = mutations(&value)
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(paths, [".id[0]", ".id[1]", ".point"]);
+        assert_eq!(field(".id[0]"), "id");
+        assert_eq!(field(".point.x"), "point");
+
+        let (_, mutated) = &mutations(&value)[2];
+        assert_eq!(mutated["point"], "0b");
+    }
+
+    #[cfg(feature = "pairing")]
+    mod pairing {
+        use crate::atpm_pairing::{
+            hm::HmReduced,
+            keys::{PrivateKey, PublicKey},
+            tokens::{HashedPairingTokenEngine, PairingTokenEngine},
+        };
+        use alloc::vec::Vec;
+
+        pub struct Pairing;
+
+        impl super::Backend for Pairing {
+            type Engine = PairingTokenEngine<Vec<u8>>;
+
+            fn verification_key(sign_key: &PrivateKey) -> PublicKey {
+                PublicKey::from(sign_key)
+            }
+
+            fn metadata(bytes: &[u8]) -> Vec<u8> {
+                bytes.to_vec()
+            }
+
+            fn hidden_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+                Some(bytes.to_vec())
+            }
+        }
+
+        pub struct Reduced;
+
+        impl super::Backend for Reduced {
+            type Engine = HashedPairingTokenEngine<Vec<u8>, HmReduced>;
+
+            fn verification_key(sign_key: &PrivateKey) -> PublicKey {
+                PublicKey::from(sign_key)
+            }
+
+            fn metadata(bytes: &[u8]) -> Vec<u8> {
+                bytes.to_vec()
+            }
+
+            fn hidden_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+                Some(bytes.to_vec())
+            }
+        }
+
+        crate::conformance!(uniform, Pairing);
+        crate::conformance!(reduced, Reduced);
+    }
+
+    #[cfg(feature = "curve25519")]
+    mod curve25519 {
+        use crate::nizkp_curve25519::{
+            keys::PrivateKey, oprf::OprfTokenEngine, tokens::NizkpTokenEngine,
+        };
+        use alloc::vec::Vec;
+
+        pub struct Curve25519;
+
+        impl super::Backend for Curve25519 {
+            type Engine = NizkpTokenEngine<Vec<u8>>;
+
+            fn verification_key(sign_key: &PrivateKey) -> PrivateKey {
+                sign_key.clone()
+            }
+
+            fn metadata(bytes: &[u8]) -> Vec<u8> {
+                bytes.to_vec()
+            }
+
+            fn hidden_metadata(bytes: &[u8]) -> Option<Vec<u8>> {
+                Some(bytes.to_vec())
+            }
+        }
+
+        pub struct Oprf;
+
+        impl super::Backend for Oprf {
+            type Engine = OprfTokenEngine<Vec<u8>>;
+
+            fn verification_key(sign_key: &PrivateKey) -> PrivateKey {
+                sign_key.clone()
+            }
+
+            fn metadata(bytes: &[u8]) -> Vec<u8> {
+                bytes.to_vec()
+            }
+
+            fn hidden_metadata(_bytes: &[u8]) -> Option<core::convert::Infallible> {
+                None
+            }
+        }
+
+        crate::conformance!(nizkp, Curve25519);
+        crate::conformance!(oprf, Oprf);
+    }
+}
@@ -0,0 +1,328 @@
+//! # Key directories
+//!
+//! An issuer that signs the tokens of some users with another key can tell those users apart
+//! when the tokens are redeemed. The issuer publishes a [`KeyDirectory`] of its public keys, as
+//! JWKs with validity windows, and the clients check that they all see the same one.
+//!
+//! The directory is pinned by its [`DirectoryDigest`], e.g. in the app or from a consistency
+//! service, which compares the digests that the clients fetched from different places.
+//! [`KeyDirectory::verify_directory_consistency`] checks the digest, and that there are never more
+//! than [`MAX_CONCURRENT_KEYS`] keys of an engine to choose from, and
+//! [`KeyDirectory::select_current_key`] gives the key to use now.
+//!
+//! ```
+//!     use atpmd::directory::KeyDirectory;
+//!     use atpmd::atpm_pairing::keys::{PrivateKey, PublicKey};
+//!
+//!     let old = PublicKey::from(&PrivateKey::new());
+//!     let new = PublicKey::from(&PrivateKey::new());
+//!
+//!     // The issuer serves this
+//!     let mut directory = KeyDirectory::new();
+//!     directory.insert(&old, 1000, 3000);
+//!     directory.insert(&new, 2000, 4000);
+//!     let json = serde_json::to_string(&directory).unwrap();
+//!
+//!     // The client fetches it, and checks it against the pinned digest
+//!     let pinned = directory.digest();
+//!     let directory: KeyDirectory = serde_json::from_str(&json).unwrap();
+//!     assert!(directory.verify_directory_consistency(&[pinned]).is_ok());
+//!
+//!     let current: PublicKey = directory.select_current_key(2500).unwrap();
+//!     assert_eq!(current.fingerprint(), new.fingerprint());
+//! ```
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::jwk::{Jwk, JwkPublicKey};
+
+/// The version of the directory format
+pub const DIRECTORY_VERSION: u32 = 1;
+
+/// The most keys of an engine that may be valid at the same time, the old and the new key of a
+/// rotation
+pub const MAX_CONCURRENT_KEYS: usize = 2;
+
+/// The reason a directory is not consistent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirectoryError {
+    /// The directory has another version
+    Version(u32),
+    /// The key at the index ends before it starts
+    Window(usize),
+    /// The key at the index is in the directory twice
+    DuplicateKey(usize),
+    /// More than [`MAX_CONCURRENT_KEYS`] keys of the curve are valid at the time
+    TooManyKeys { curve: String, at: u64 },
+    /// The digest is not the pinned one
+    Inconsistent,
+}
+
+impl fmt::Display for DirectoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Version(version) => write!(f, "directory version {} is not supported", version),
+            Self::Window(index) => write!(f, "key {} ends before it starts", index),
+            Self::DuplicateKey(index) => write!(f, "key {} is in the directory twice", index),
+            Self::TooManyKeys { curve, at } => write!(
+                f,
+                "more than {} keys of curve {} are valid at {}",
+                MAX_CONCURRENT_KEYS, curve, at
+            ),
+            Self::Inconsistent => f.write_str("the directory is not the pinned one"),
+        }
+    }
+}
+
+/// The digest of a directory, to pin it and to compare it with other clients
+///
+/// It is displayed in hex.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DirectoryDigest([u8; 32]);
+
+impl DirectoryDigest {
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl fmt::Display for DirectoryDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// A public key of the directory, valid from `not_before` until `not_after`, in seconds
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub key: Jwk,
+    pub not_before: u64,
+    pub not_after: u64,
+}
+
+impl DirectoryEntry {
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.not_before <= now && now < self.not_after
+    }
+}
+
+/// The public keys of an issuer, with their validity windows
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyDirectory {
+    pub version: u32,
+    pub keys: Vec<DirectoryEntry>,
+}
+
+impl Default for KeyDirectory {
+    fn default() -> Self {
+        Self {
+            version: DIRECTORY_VERSION,
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl KeyDirectory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a key, valid from `not_before` until `not_after`
+    pub fn insert<K: JwkPublicKey>(&mut self, key: &K, not_before: u64, not_after: u64) {
+        self.keys.push(DirectoryEntry {
+            key: key.to_jwk(),
+            not_before,
+            not_after,
+        });
+    }
+
+    /// The SHA-256 of the directory, of the members in order, each prefixed with its length
+    pub fn digest(&self) -> DirectoryDigest {
+        let mut hasher = Sha256::new();
+        hasher.update(b"atpmd key directory");
+        hasher.update(self.version.to_be_bytes());
+
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
+        };
+        for entry in &self.keys {
+            field(entry.key.kty.as_bytes());
+            field(entry.key.crv.as_bytes());
+            field(entry.key.x.as_bytes());
+            field(entry.key.kid.as_deref().unwrap_or("").as_bytes());
+            field(&entry.key.epoch.map_or([0; 9], |epoch| {
+                let mut bytes = [1; 9];
+                bytes[1..].copy_from_slice(&u64::from(epoch).to_be_bytes());
+                bytes
+            }));
+            field(&entry.not_before.to_be_bytes());
+            field(&entry.not_after.to_be_bytes());
+        }
+
+        DirectoryDigest(hasher.finalize().into())
+    }
+
+    /// The newest key of the engine of `K` that is valid at `now`
+    ///
+    /// Keys that do not import are skipped, call
+    /// [`verify_directory_consistency`](Self::verify_directory_consistency) first.
+    pub fn select_current_key<K: JwkPublicKey>(&self, now: u64) -> Option<K> {
+        self.keys
+            .iter()
+            .filter(|entry| entry.key.crv == K::CURVE && entry.is_valid_at(now))
+            .filter_map(|entry| Some((entry.not_before, K::from_jwk(&entry.key).ok()?)))
+            .max_by_key(|(not_before, _key)| *not_before)
+            .map(|(_not_before, key)| key)
+    }
+
+    /// Check the directory, and that its digest is the same as all of `pinned`
+    ///
+    /// The digests are e.g. the one in the app, or those other clients saw. Without any, only the
+    /// directory itself is checked.
+    pub fn verify_directory_consistency(
+        &self,
+        pinned: &[DirectoryDigest],
+    ) -> Result<(), DirectoryError> {
+        if self.version != DIRECTORY_VERSION {
+            return Err(DirectoryError::Version(self.version));
+        }
+
+        for (i, entry) in self.keys.iter().enumerate() {
+            if entry.not_after <= entry.not_before {
+                return Err(DirectoryError::Window(i));
+            }
+            if self.keys[..i]
+                .iter()
+                .any(|other| other.key.crv == entry.key.crv && other.key.x == entry.key.x)
+            {
+                return Err(DirectoryError::DuplicateKey(i));
+            }
+
+            // the most keys are valid at the start of a window
+            let concurrent = self
+                .keys
+                .iter()
+                .filter(|other| {
+                    other.key.crv == entry.key.crv && other.is_valid_at(entry.not_before)
+                })
+                .count();
+            if concurrent > MAX_CONCURRENT_KEYS {
+                return Err(DirectoryError::TooManyKeys {
+                    curve: entry.key.crv.clone(),
+                    at: entry.not_before,
+                });
+            }
+        }
+
+        let digest = self.digest();
+        if pinned.iter().all(|pinned| *pinned == digest) {
+            Ok(())
+        } else {
+            Err(DirectoryError::Inconsistent)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    use crate::atpm_pairing::keys as pairing;
+    use crate::nizkp_curve25519::keys as curve25519;
+
+    fn key() -> pairing::PublicKey {
+        pairing::PublicKey::from(&pairing::PrivateKey::new())
+    }
+
+    #[test]
+    fn test_directory() {
+        let (first, second) = (key(), key());
+        let ristretto = curve25519::PublicKey::from(&curve25519::PrivateKey::new());
+
+        let mut directory = KeyDirectory::new();
+        directory.insert(&first, 0, 200);
+        directory.insert(&second, 100, 300);
+        directory.insert(&ristretto, 0, 300);
+        assert!(directory.verify_directory_consistency(&[]).is_ok());
+
+        let current = |now| {
+            directory
+                .select_current_key::<pairing::PublicKey>(now)
+                .map(|key| key.fingerprint())
+        };
+        assert_eq!(current(50), Some(first.fingerprint()));
+        assert_eq!(current(150), Some(second.fingerprint()));
+        assert_eq!(current(300), None);
+        assert_eq!(
+            directory
+                .select_current_key::<curve25519::PublicKey>(50)
+                .map(|key| key.fingerprint()),
+            Some(ristretto.fingerprint())
+        );
+
+        let json = serde_json::to_string(&directory).unwrap();
+        let fetched: KeyDirectory = serde_json::from_str(&json).unwrap();
+        assert_eq!(fetched, directory);
+        assert_eq!(fetched.digest(), directory.digest());
+        assert_eq!(directory.digest().to_string().len(), 64);
+
+        let mut epoch = directory.clone();
+        epoch.keys[0].key.epoch = Some(1);
+        assert_ne!(epoch.digest(), directory.digest());
+    }
+
+    #[test]
+    fn fail_directory() {
+        let mut directory = KeyDirectory::new();
+        directory.insert(&key(), 0, 200);
+        let pinned = directory.digest();
+
+        // a key for some of the users
+        let mut targeted = directory.clone();
+        targeted.insert(&key(), 100, 300);
+        assert!(targeted.verify_directory_consistency(&[]).is_ok());
+        assert_eq!(
+            targeted.verify_directory_consistency(&[pinned]),
+            Err(DirectoryError::Inconsistent)
+        );
+
+        targeted.insert(&key(), 150, 300);
+        assert_eq!(
+            targeted.verify_directory_consistency(&[]),
+            Err(DirectoryError::TooManyKeys {
+                curve: "BLS12381G2".to_string(),
+                at: 150
+            })
+        );
+
+        let mut twice = directory.clone();
+        twice.keys.push(directory.keys[0].clone());
+        assert_eq!(
+            twice.verify_directory_consistency(&[]),
+            Err(DirectoryError::DuplicateKey(1))
+        );
+
+        let mut window = directory.clone();
+        window.keys[0].not_after = 0;
+        assert_eq!(
+            window.verify_directory_consistency(&[]),
+            Err(DirectoryError::Window(0))
+        );
+
+        let mut version = directory;
+        version.version = 2;
+        assert_eq!(
+            version.verify_directory_consistency(&[]),
+            Err(DirectoryError::Version(2))
+        );
+    }
+}
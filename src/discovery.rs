@@ -0,0 +1,273 @@
+//! # Discovery of issuers
+//!
+//! An issuer serves an [`IssuerDocument`] at `/.well-known/anon-token-issuer`, with the engines it
+//! signs with, where its keys and its issuance endpoint are, and how many tokens it signs in one
+//! request. A client then only needs the base URL of the issuer, and no hard-coded paths.
+//!
+//! The crate has no HTTP client, so [`discover`] is given a function that fetches a URL, like the
+//! engines are given a function that sends the randomized tokens to the signer.
+//!
+//! ```
+//!     use atpmd::discovery::{discover, BatchLimits, IssuerDocument, WELL_KNOWN_PATH};
+//!
+//!     // The issuer serves the document, the URLs may be relative to its base URL
+//!     let document = IssuerDocument {
+//!         engines: vec!["pairing".into()],
+//!         key_bundle: "/.well-known/jwks.json".into(),
+//!         issuance: "/sign/batch".into(),
+//!         batch_limits: BatchLimits::new(1, 256),
+//!         ..IssuerDocument::default()
+//!     };
+//!     let json = document.to_json();
+//!
+//!     // The client fetches it, e.g. with reqwest
+//!     let discovered = discover("https://issuer.example/", |url| {
+//!         assert_eq!(url, format!("https://issuer.example{}", WELL_KNOWN_PATH));
+//!         Ok::<_, ()>(json.clone())
+//!     })
+//!     .unwrap();
+//!
+//!     assert!(discovered.supports("pairing"));
+//!     assert_eq!(discovered.issuance, "https://issuer.example/sign/batch");
+//!     assert_eq!(discovered.batch_limits.clamp(1000), 256);
+//! ```
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+/// The path of the document, relative to the base URL of the issuer
+pub const WELL_KNOWN_PATH: &str = "/.well-known/anon-token-issuer";
+
+/// The version of the document format
+pub const DOCUMENT_VERSION: u32 = 1;
+
+/// The reason an issuer could not be discovered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryError<E> {
+    /// The document could not be fetched
+    Fetch(E),
+    /// The document is not valid JSON, or it is missing fields
+    Json,
+    /// The document has an unknown version
+    Version(u32),
+    /// The batch limits are empty, or the fewest tokens is zero
+    BatchLimits,
+}
+
+impl<E: fmt::Display> fmt::Display for DiscoveryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fetch(e) => write!(f, "the issuer document could not be fetched: {}", e),
+            Self::Json => f.write_str("the issuer document is not valid"),
+            Self::Version(version) => write!(f, "issuer document version {} is unknown", version),
+            Self::BatchLimits => f.write_str("the batch limits of the issuer are empty"),
+        }
+    }
+}
+
+/// The fewest and the most tokens the issuer signs in one request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchLimits {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl BatchLimits {
+    pub fn new(min: usize, max: usize) -> Self {
+        Self { min, max }
+    }
+
+    /// Whether the issuer signs a batch of `count` tokens
+    pub fn allows(&self, count: usize) -> bool {
+        (self.min..=self.max).contains(&count)
+    }
+
+    /// The size of the batch to ask for, when a client wants `count` tokens
+    pub fn clamp(&self, count: usize) -> usize {
+        count.max(self.min).min(self.max)
+    }
+
+    fn is_valid(&self) -> bool {
+        self.min > 0 && self.min <= self.max
+    }
+}
+
+impl Default for BatchLimits {
+    /// One token at a time
+    fn default() -> Self {
+        Self::new(1, 1)
+    }
+}
+
+/// The document an issuer serves at [`WELL_KNOWN_PATH`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IssuerDocument {
+    /// The version of the document format, [`DOCUMENT_VERSION`]
+    pub version: u32,
+    /// The engines the issuer signs with, as the `ENGINE_ID` of their tokens, see
+    /// [`HeaderToken`](crate::http::HeaderToken)
+    pub engines: Vec<String>,
+    /// The URL of the public keys, a JWK set (see [`jwk`](crate::jwk))
+    pub key_bundle: String,
+    /// The URL the randomized tokens are posted to
+    pub issuance: String,
+    pub batch_limits: BatchLimits,
+    /// The version of the schema of the public metadata, which is up to the application
+    pub metadata_version: u32,
+}
+
+impl Default for IssuerDocument {
+    fn default() -> Self {
+        Self {
+            version: DOCUMENT_VERSION,
+            engines: Vec::new(),
+            key_bundle: String::new(),
+            issuance: String::new(),
+            batch_limits: BatchLimits::default(),
+            metadata_version: 0,
+        }
+    }
+}
+
+impl IssuerDocument {
+    /// Whether the issuer signs with the engine
+    pub fn supports(&self, engine_id: &str) -> bool {
+        self.engines.iter().any(|engine| engine == engine_id)
+    }
+
+    /// The document with the relative URLs resolved against the base URL of the issuer
+    pub fn resolve(self, base_url: &str) -> Self {
+        Self {
+            key_bundle: resolve(base_url, &self.key_bundle),
+            issuance: resolve(base_url, &self.issuance),
+            ..self
+        }
+    }
+
+    /// Check the version and the batch limits of a document from an issuer
+    pub fn check<E>(&self) -> Result<(), DiscoveryError<E>> {
+        if self.version != DOCUMENT_VERSION {
+            return Err(DiscoveryError::Version(self.version));
+        }
+
+        if !self.batch_limits.is_valid() {
+            return Err(DiscoveryError::BatchLimits);
+        }
+
+        Ok(())
+    }
+
+    /// The JSON of the document, to be served at [`WELL_KNOWN_PATH`]
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        // serializing a document can not fail
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Parse and check the JSON of a document
+    #[cfg(feature = "json")]
+    pub fn from_json<E>(json: &str) -> Result<Self, DiscoveryError<E>> {
+        let document: Self = serde_json::from_str(json).map_err(|_e| DiscoveryError::Json)?;
+        document.check()?;
+        Ok(document)
+    }
+}
+
+/// The URL of the document of an issuer
+pub fn well_known_url(base_url: &str) -> String {
+    resolve(base_url, WELL_KNOWN_PATH)
+}
+
+/// A URL of the document, which is either absolute or a path relative to the base URL
+fn resolve(base_url: &str, url: &str) -> String {
+    if url.contains("://") {
+        return url.to_string();
+    }
+
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        url.trim_start_matches('/')
+    )
+}
+
+/// Fetch and check the document of an issuer, with the URLs resolved against the base URL
+///
+/// `fetch` gets the body of a URL, e.g. with a blocking HTTP client.
+#[cfg(feature = "json")]
+pub fn discover<E>(
+    base_url: &str,
+    fetch: impl FnOnce(&str) -> Result<String, E>,
+) -> Result<IssuerDocument, DiscoveryError<E>> {
+    let json = fetch(&well_known_url(base_url)).map_err(DiscoveryError::Fetch)?;
+    Ok(IssuerDocument::from_json(&json)?.resolve(base_url))
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn document() -> IssuerDocument {
+        IssuerDocument {
+            engines: vec!["pairing".into(), "curve25519".into()],
+            key_bundle: "https://keys.example/jwks.json".into(),
+            issuance: "sign".into(),
+            batch_limits: BatchLimits::new(16, 256),
+            metadata_version: 2,
+            ..IssuerDocument::default()
+        }
+    }
+
+    #[test]
+    fn test_discover() {
+        let json = document().to_json();
+        let discovered = discover("http://127.0.0.1:8000", |url| {
+            assert_eq!(url, "http://127.0.0.1:8000/.well-known/anon-token-issuer");
+            Ok::<_, ()>(json)
+        })
+        .unwrap();
+
+        assert!(discovered.supports("curve25519"));
+        assert!(!discovered.supports("curve25519-bound"));
+        assert_eq!(discovered.key_bundle, "https://keys.example/jwks.json");
+        assert_eq!(discovered.issuance, "http://127.0.0.1:8000/sign");
+        assert_eq!(discovered.metadata_version, 2);
+
+        let limits = discovered.batch_limits;
+        assert!(limits.allows(16) && limits.allows(256) && !limits.allows(257));
+        assert_eq!((limits.clamp(1), limits.clamp(100)), (16, 100));
+    }
+
+    #[test]
+    fn fail_discover() {
+        let fetch = |json: String| move |_url: &str| Ok::<_, ()>(json);
+
+        assert_eq!(
+            discover("http://issuer", |_url| Err("offline")),
+            Err(DiscoveryError::Fetch("offline"))
+        );
+        assert_eq!(
+            discover("http://issuer", fetch("{}".into())),
+            Err(DiscoveryError::Json)
+        );
+
+        let mut future = document();
+        future.version = 2;
+        assert_eq!(
+            discover("http://issuer", fetch(future.to_json())),
+            Err(DiscoveryError::Version(2))
+        );
+
+        let mut empty = document();
+        empty.batch_limits = BatchLimits::new(8, 4);
+        assert_eq!(
+            discover("http://issuer", fetch(empty.to_json())),
+            Err(DiscoveryError::BatchLimits)
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! # Tokens that expire
+//!
+//! The public metadata of a token may start with the time it expires, such that the verifier can
+//! reject old tokens with [`SignedToken::verify_with_time`] instead of decoding the metadata on its
+//! own.
+//! [`Metadata`] is this layout: the expiration time as seconds since the unix epoch, as 8 big endian
+//! bytes, followed by the metadata of the application.
+//! A token expires at the expiration time, i.e. it is valid as long as `now` is before it.
+//!
+//! ```
+//!     use atpmd::{SignedToken, TokenEngine};
+//!     use atpmd::expiry::Metadata;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let now = 1_600_000_000;
+//!     let metadata = Metadata::new(now + 3600, b"resource");
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(metadata),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     assert_eq!(signed.metadata().data(), b"resource");
+//!     assert!(signed.verify_with_time(&public_key, now));
+//!     assert!(!signed.verify_with_time(&public_key, now + 3600));
+//! ```
+//!
+//! [`SignedToken::verify_with_time`]: crate::SignedToken::verify_with_time
+
+use alloc::{boxed::Box, vec::Vec};
+use core::convert::TryInto;
+
+/// The length of the expiration time at the start of the metadata
+const EXPIRY_LEN: usize = 8;
+
+/// Public metadata with an expiration time
+///
+/// Metadata decoded from a token may be too short to contain an expiration time, such a token
+/// never verifies with a time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Metadata(Box<[u8]>);
+
+impl Metadata {
+    /// Metadata that expires at `expires_at`, in seconds since the unix epoch
+    pub fn new(expires_at: u64, data: impl AsRef<[u8]>) -> Self {
+        let mut bytes = Vec::with_capacity(EXPIRY_LEN + data.as_ref().len());
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes.extend_from_slice(data.as_ref());
+
+        Self(bytes.into_boxed_slice())
+    }
+
+    /// The expiration time, in seconds since the unix epoch
+    pub fn expires_at(&self) -> Option<u64> {
+        expires_at(&self.0)
+    }
+
+    /// Has the metadata expired at `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        is_expired(&self.0, now)
+    }
+
+    /// The metadata of the application, after the expiration time
+    pub fn data(&self) -> &[u8] {
+        self.0.get(EXPIRY_LEN..).unwrap_or(&[])
+    }
+}
+
+impl AsRef<[u8]> for Metadata {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for Metadata {
+    fn from(bytes: &[u8]) -> Self {
+        Self(Box::from(bytes))
+    }
+}
+
+/// The expiration time at the start of some encoded metadata
+pub fn expires_at(metadata: &[u8]) -> Option<u64> {
+    metadata
+        .get(..EXPIRY_LEN)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u64::from_be_bytes)
+}
+
+/// Has some encoded metadata expired at `now`
+///
+/// Metadata that is too short to contain an expiration time counts as expired.
+pub fn is_expired(metadata: &[u8], now: u64) -> bool {
+    expires_at(metadata).is_none_or(|expires_at| now >= expires_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let metadata = Metadata::new(0x0102030405060708, b"data");
+
+        assert_eq!(metadata.as_ref(), b"\x01\x02\x03\x04\x05\x06\x07\x08data");
+        assert_eq!(metadata.expires_at(), Some(0x0102030405060708));
+        assert_eq!(metadata.data(), b"data");
+        assert_eq!(Metadata::from(metadata.as_ref()), metadata);
+    }
+
+    #[test]
+    fn test_expired() {
+        let metadata = Metadata::new(100, b"");
+
+        assert!(!metadata.is_expired(0));
+        assert!(!metadata.is_expired(99));
+        assert!(metadata.is_expired(100));
+        assert!(metadata.is_expired(u64::MAX));
+    }
+
+    #[test]
+    fn fail_short() {
+        let metadata = Metadata::from(&b"1234567"[..]);
+
+        assert_eq!(metadata.expires_at(), None);
+        assert_eq!(metadata.data(), b"");
+        assert!(metadata.is_expired(0));
+    }
+}
@@ -0,0 +1,559 @@
+//! A prime order group, and the proofs that are the same for all the groups
+//!
+//! The backends implement [`PrimeOrderGroup`] for their curve, and use the generic proofs here
+//! instead of having a copy each.
+
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+use alloc::vec::Vec;
+#[cfg(feature = "curve25519")]
+use core::future::Future;
+use core::ops::{Add, Mul, Sub};
+
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+use rand::prelude::StdRng;
+use rand::{CryptoRng, RngCore};
+use subtle::{ConditionallySelectable, CtOption};
+
+#[cfg(feature = "curve25519")]
+use crate::chunked::{Cancelled, Chunking};
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+use crate::{chunked::par_map, hash_suite::DomainParams, transcript::Transcript};
+
+/// A group of prime order, with a generator
+pub(crate) trait PrimeOrderGroup {
+    type Scalar: Copy
+        + Send
+        + Sync
+        + PartialEq
+        + Default
+        + ConditionallySelectable
+        + Add<Output = Self::Scalar>
+        + Sub<Output = Self::Scalar>
+        + Mul<Output = Self::Scalar>;
+
+    type Element: Copy
+        + Send
+        + Sync
+        + PartialEq
+        + Add<Output = Self::Element>
+        + Mul<Self::Scalar, Output = Self::Element>;
+
+    // the elements are only used by the proofs, the pairing engine has none
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn generator() -> Self::Element;
+
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn identity() -> Self::Element;
+
+    /// Multiply the generator by a scalar, a group may use a precomputed table for this
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn mul_generator(scalar: &Self::Scalar) -> Self::Element {
+        Self::generator() * *scalar
+    }
+
+    /// A uniformly random scalar
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Self::Scalar;
+
+    /// The inverse of a scalar, none for zero
+    fn invert(scalar: &Self::Scalar) -> CtOption<Self::Scalar>;
+
+    /// The canonical encoding of an element, used in the hashes of the proofs
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn encode(element: &Self::Element) -> Vec<u8>;
+
+    /// Hash the transcript of a proof uniformly to a scalar
+    #[cfg(any(feature = "curve25519", feature = "nizkp"))]
+    fn challenge(transcript: &[u8]) -> Self::Scalar;
+}
+
+/// A random scalar r with its inverse, to blind a point as r^{-1} t
+pub(crate) fn blinding<G: PrimeOrderGroup, R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> (G::Scalar, G::Scalar) {
+    loop {
+        // Pick random stuff until it is invertible (should be the first)
+        let r = G::random_scalar(rng);
+        let inverse = G::invert(&r);
+
+        if bool::from(inverse.is_some()) {
+            return (r, inverse.unwrap());
+        }
+    }
+}
+
+/// Sign a point with the key k, for the metadata scalar d, as w = (d+k)^{-1} t
+///
+/// This is none if d + k is not invertible
+pub(crate) fn sign_point<G: PrimeOrderGroup>(
+    t: G::Element,
+    d: G::Scalar,
+    k: G::Scalar,
+) -> CtOption<G::Element> {
+    G::invert(&(d + k)).map(|e| t * e)
+}
+
+/// Recompute t' = (d + k) w for a signature w, with a proof that the key k was used
+///
+/// The signature is valid if t' is the hash of the token, so this proves the outcome of verifying
+/// to anyone with the public key.
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+pub(crate) fn prove_verification<G: PrimeOrderGroup>(
+    w: G::Element,
+    d: G::Scalar,
+    k: G::Scalar,
+    domain: &DomainParams,
+) -> (G::Element, DleqProof<G::Scalar>) {
+    let t = w * (d + k);
+    let proof =
+        DleqProof::create_with_domain::<G, _>(t, w, d + k, domain, &mut crate::rng::default_rng());
+    (t, proof)
+}
+
+// {{{ DLEQProof
+
+/// A proof that two pairs of elements have the same discrete logarithm
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DleqProof<S> {
+    pub(crate) c: S,
+    pub(crate) z: S,
+}
+
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+impl<S: Copy + PartialEq + Sub<Output = S> + Mul<Output = S>> DleqProof<S> {
+    fn hash_data<G: PrimeOrderGroup<Scalar = S>>(
+        domain: &DomainParams,
+        u: &G::Element,
+        t: &G::Element,
+        w: &G::Element,
+        a: &G::Element,
+        b: &G::Element,
+    ) -> S {
+        let mut transcript = Transcript::new(domain.dleq_proof);
+        transcript.append_element::<G>(b"g", &G::generator());
+        transcript.append_element::<G>(b"u", u);
+        transcript.append_element::<G>(b"t", t);
+        transcript.append_element::<G>(b"w", w);
+        transcript.append_element::<G>(b"a", a);
+        transcript.append_element::<G>(b"b", b);
+
+        transcript.challenge_scalar::<G>(b"c")
+    }
+
+    /// Create a proof of the fact that log_w t = k
+    ///
+    /// If you create w=(d+k)^{-1} t, then create this proof with create(t, w, d + k)
+    #[cfg(test)]
+    pub fn create<G: PrimeOrderGroup<Scalar = S>>(t: G::Element, w: G::Element, k: S) -> Self {
+        Self::create_with_rng::<G, _>(t, w, k, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::create`], with the nonce of the proof from the given rng
+    pub fn create_with_rng<G: PrimeOrderGroup<Scalar = S>, R: RngCore + CryptoRng>(
+        t: G::Element,
+        w: G::Element,
+        k: S,
+        rng: &mut R,
+    ) -> Self {
+        Self::create_with_domain::<G, _>(t, w, k, &DomainParams::DEFAULT, rng)
+    }
+
+    /// [`Self::create_with_rng`], with the tag of the transcript of the domain
+    pub fn create_with_domain<G: PrimeOrderGroup<Scalar = S>, R: RngCore + CryptoRng>(
+        t: G::Element,
+        w: G::Element,
+        k: S,
+        domain: &DomainParams,
+        rng: &mut R,
+    ) -> Self {
+        let r = G::random_scalar(rng);
+        let a = G::mul_generator(&r);
+        let b = w * r;
+
+        let c = Self::hash_data::<G>(domain, &G::mul_generator(&k), &t, &w, &a, &b);
+
+        let z = r - k * c;
+
+        Self { c, z }
+    }
+
+    /// Verify the proof that log_w t = k
+    ///
+    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
+    pub fn verify<G: PrimeOrderGroup<Scalar = S>>(
+        &self,
+        t: G::Element,
+        w: G::Element,
+        public_key: G::Element,
+    ) -> bool {
+        self.verify_with_domain::<G>(t, w, public_key, &DomainParams::DEFAULT)
+    }
+
+    /// [`Self::verify`], with the tag of the transcript of the domain
+    pub fn verify_with_domain<G: PrimeOrderGroup<Scalar = S>>(
+        &self,
+        t: G::Element,
+        w: G::Element,
+        public_key: G::Element,
+        domain: &DomainParams,
+    ) -> bool {
+        let a = G::mul_generator(&self.z) + public_key * self.c;
+        let b = w * self.z + t * self.c;
+        let c = Self::hash_data::<G>(domain, &public_key, &t, &w, &a, &b);
+
+        c == self.c
+    }
+}
+
+/// A single proof for a whole batch, on a random linear combination of the elements
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DleqProofBatched<S> {
+    pub(crate) proof: DleqProof<S>,
+}
+
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+impl<S: Copy + Sync + PartialEq + Sub<Output = S> + Mul<Output = S>> DleqProofBatched<S> {
+    /// Seed an rng with the transcript of the encoded batch
+    fn hash_data<G: PrimeOrderGroup<Scalar = S>>(
+        t_encoded: &[Vec<u8>],
+        w_encoded: &[Vec<u8>],
+        public_key: &G::Element,
+    ) -> StdRng {
+        let mut transcript = Transcript::new(b"DLEQ batch");
+        transcript.append_element::<G>(b"g", &G::generator());
+        transcript.append_element::<G>(b"u", public_key);
+        transcript.append_u64(b"n", t_encoded.len() as u64);
+        t_encoded
+            .iter()
+            .for_each(|bytes| transcript.append_message(b"t", bytes));
+        w_encoded
+            .iter()
+            .for_each(|bytes| transcript.append_message(b"w", bytes));
+
+        // seedable determinizstic rng
+        transcript.challenge_rng(b"coefficients")
+    }
+
+    #[cfg(feature = "curve25519")]
+    fn weighted<G: PrimeOrderGroup<Scalar = S>, R: RngCore + CryptoRng>(
+        rng: &mut R,
+        t: &G::Element,
+        w: &G::Element,
+    ) -> (G::Element, G::Element) {
+        let c = G::random_scalar(rng);
+        (*t * c, *w * c)
+    }
+
+    fn sum<G: PrimeOrderGroup<Scalar = S>>(
+        terms: impl Iterator<Item = (G::Element, G::Element)>,
+    ) -> (G::Element, G::Element) {
+        terms.fold((G::identity(), G::identity()), |(tsum, wsum), (t, w)| {
+            (tsum + t, wsum + w)
+        })
+    }
+
+    /// Creates a random linear combination of the batch, with coefficients from an rng seeded by
+    /// the hash of the batch
+    fn hash_random_linear_combination<G: PrimeOrderGroup<Scalar = S>>(
+        t_list: &[G::Element],
+        w_list: &[G::Element],
+        public_key: &G::Element,
+    ) -> (G::Element, G::Element) {
+        let mut rng = Self::hash_data::<G>(
+            &par_map(t_list, G::encode),
+            &par_map(w_list, G::encode),
+            public_key,
+        );
+
+        // the coefficients are drawn in order, the products may then be done in parallel
+        let terms = t_list
+            .iter()
+            .zip(w_list.iter())
+            .map(|(t, w)| (t, w, G::random_scalar(&mut rng)))
+            .collect::<Vec<_>>();
+
+        Self::sum::<G>(par_map(&terms, |(t, w, c)| (**t * *c, **w * *c)).into_iter())
+    }
+
+    /// The proof for the batch, with the nonce of the proof from the given rng
+    pub fn create_with_rng<G: PrimeOrderGroup<Scalar = S>, R: RngCore + CryptoRng>(
+        t_list: &[G::Element],
+        w_list: &[G::Element],
+        k: S,
+        rng: &mut R,
+    ) -> Self {
+        let (m, z) =
+            Self::hash_random_linear_combination::<G>(t_list, w_list, &G::mul_generator(&k));
+
+        Self {
+            proof: DleqProof::create_with_rng::<G, _>(m, z, k, rng),
+        }
+    }
+
+    /// Verifies the proof for the linear combination of the tokens in the batch
+    ///
+    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
+    pub fn verify<G: PrimeOrderGroup<Scalar = S>>(
+        &self,
+        t_list: &[G::Element],
+        w_list: &[G::Element],
+        public_key: G::Element,
+    ) -> bool {
+        let (m, z) = Self::hash_random_linear_combination::<G>(t_list, w_list, &public_key);
+
+        self.proof.verify::<G>(m, z, public_key)
+    }
+
+    /// [`DleqProofBatched::create_with_rng`], yielding between the chunks of the batch
+    #[cfg(feature = "curve25519")]
+    pub async fn create_chunked_with_rng<
+        G: PrimeOrderGroup<Scalar = S>,
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+        R: RngCore + CryptoRng,
+    >(
+        t_list: &[G::Element],
+        w_list: &[G::Element],
+        k: S,
+        chunking: &mut Chunking<Y>,
+        rng: &mut R,
+    ) -> Result<Self, Cancelled> {
+        let encoded = chunking
+            .map(t_list.iter().chain(w_list.iter()), G::encode)
+            .await?;
+        let (t_encoded, w_encoded) = encoded.split_at(t_list.len());
+        let mut seeded = Self::hash_data::<G>(t_encoded, w_encoded, &G::mul_generator(&k));
+
+        let terms = chunking
+            .map(t_list.iter().zip(w_list.iter()), |(t, w)| {
+                Self::weighted::<G, _>(&mut seeded, t, w)
+            })
+            .await?;
+        let (m, z) = Self::sum::<G>(terms.into_iter());
+
+        Ok(Self {
+            proof: DleqProof::create_with_rng::<G, _>(m, z, k, rng),
+        })
+    }
+
+    /// [`DleqProofBatched::verify`], yielding between the chunks of the batch
+    #[cfg(feature = "curve25519")]
+    pub async fn verify_chunked<
+        G: PrimeOrderGroup<Scalar = S>,
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+    >(
+        &self,
+        t_list: &[G::Element],
+        w_list: &[G::Element],
+        public_key: G::Element,
+        chunking: &mut Chunking<Y>,
+    ) -> Result<bool, Cancelled> {
+        let encoded = chunking
+            .map(t_list.iter().chain(w_list.iter()), G::encode)
+            .await?;
+        let (t_encoded, w_encoded) = encoded.split_at(t_list.len());
+        let mut rng = Self::hash_data::<G>(t_encoded, w_encoded, &public_key);
+
+        let terms = chunking
+            .map(t_list.iter().zip(w_list.iter()), |(t, w)| {
+                Self::weighted::<G, _>(&mut rng, t, w)
+            })
+            .await?;
+        let (m, z) = Self::sum::<G>(terms.into_iter());
+
+        Ok(self.proof.verify::<G>(m, z, public_key))
+    }
+}
+
+/// A batched proof that is accumulated over a stream of pairs, in bounded memory
+///
+/// The coefficient of each pair is drawn from the transcript of the stream up to and including
+/// the pair, so the proof does not depend on how the stream is split into chunks. It is not the same
+/// proof as [`DleqProofBatched::create_with_rng`] of the whole batch.
+#[cfg(feature = "curve25519")]
+#[derive(Clone)]
+pub(crate) struct DleqProofStream<G: PrimeOrderGroup> {
+    transcript: Transcript,
+    m: G::Element,
+    z: G::Element,
+    len: usize,
+}
+
+#[cfg(feature = "curve25519")]
+impl<G: PrimeOrderGroup> DleqProofStream<G> {
+    pub fn new(public_key: &G::Element) -> Self {
+        let mut transcript = Transcript::new(b"DLEQ stream");
+        transcript.append_element::<G>(b"g", &G::generator());
+        transcript.append_element::<G>(b"u", public_key);
+
+        Self {
+            transcript,
+            m: G::identity(),
+            z: G::identity(),
+            len: 0,
+        }
+    }
+
+    /// Add the pair w = (d+k)^{-1} t to the linear combination
+    pub fn push(&mut self, t: &G::Element, w: &G::Element) {
+        self.transcript.append_element::<G>(b"t", t);
+        self.transcript.append_element::<G>(b"w", w);
+
+        let mut rng = self.transcript.challenge_rng(b"coefficient");
+        let (t, w) = DleqProofBatched::weighted::<G, _>(&mut rng, t, w);
+        self.m = self.m + t;
+        self.z = self.z + w;
+        self.len += 1;
+    }
+
+    /// The number of pairs so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The proof for the pairs so far, with the nonce of the proof from the given rng
+    pub fn create_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        k: G::Scalar,
+        rng: &mut R,
+    ) -> DleqProofBatched<G::Scalar> {
+        DleqProofBatched {
+            proof: DleqProof::create_with_rng::<G, _>(self.m, self.z, k, rng),
+        }
+    }
+
+    /// Verify the proof for the pairs so far
+    pub fn verify(&self, proof: &DleqProofBatched<G::Scalar>, public_key: G::Element) -> bool {
+        proof.proof.verify::<G>(self.m, self.z, public_key)
+    }
+}
+
+// }}}
+
+// {{{ Schnorr signature
+
+/// A Schnorr signature of a message, with the key x of the public key X = xG
+#[cfg(feature = "curve25519")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SchnorrSignature<S> {
+    pub(crate) c: S,
+    pub(crate) z: S,
+}
+
+#[cfg(feature = "curve25519")]
+impl<S: Copy + PartialEq + Sub<Output = S> + Mul<Output = S>> SchnorrSignature<S> {
+    fn hash_data<G: PrimeOrderGroup<Scalar = S>>(
+        public_key: &G::Element,
+        a: &G::Element,
+        message: &[u8],
+    ) -> S {
+        let mut transcript = Transcript::new(b"Schnorr signature");
+        transcript.append_element::<G>(b"g", &G::generator());
+        transcript.append_element::<G>(b"public key", public_key);
+        transcript.append_element::<G>(b"a", a);
+        transcript.append_message(b"message", message);
+
+        transcript.challenge_scalar::<G>(b"c")
+    }
+
+    /// Sign the message with the key x, with the nonce from the given rng
+    pub fn create_with_rng<G: PrimeOrderGroup<Scalar = S>, R: RngCore + CryptoRng>(
+        message: &[u8],
+        x: S,
+        rng: &mut R,
+    ) -> Self {
+        let r = G::random_scalar(rng);
+        let a = G::mul_generator(&r);
+
+        let c = Self::hash_data::<G>(&G::mul_generator(&x), &a, message);
+
+        let z = r - x * c;
+
+        Self { c, z }
+    }
+
+    pub fn verify<G: PrimeOrderGroup<Scalar = S>>(
+        &self,
+        message: &[u8],
+        public_key: G::Element,
+    ) -> bool {
+        let a = G::mul_generator(&self.z) + public_key * self.c;
+
+        Self::hash_data::<G>(&public_key, &a, message) == self.c
+    }
+}
+
+// }}}
+
+#[cfg(all(test, feature = "curve25519"))]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Check the proofs in a group, the backends call this in their tests
+    pub(crate) fn check_proofs<G: PrimeOrderGroup>() {
+        let mut rng = crate::rng::default_rng();
+
+        let k = G::random_scalar(&mut rng);
+        let d = G::random_scalar(&mut rng);
+        let u = G::mul_generator(&(d + k));
+
+        let t = G::mul_generator(&G::random_scalar(&mut rng));
+        let w = sign_point::<G>(t, d, k).unwrap();
+
+        let (r, inverse) = blinding::<G, _>(&mut rng);
+        assert!(t * inverse * r == t);
+
+        let proof = DleqProof::create::<G>(t, w, d + k);
+        assert!(proof.verify::<G>(t, w, u));
+        assert!(!proof.verify::<G>(w, t, u));
+        assert!(!proof.verify::<G>(t, w, G::mul_generator(&k)));
+
+        let t_list = [t, G::mul_generator(&G::random_scalar(&mut rng))];
+        let w_list = [w, sign_point::<G>(t_list[1], d, k).unwrap()];
+
+        let proof = DleqProofBatched::create_with_rng::<G, _>(&t_list, &w_list, d + k, &mut rng);
+        assert!(proof.verify::<G>(&t_list, &w_list, u));
+        assert!(!proof.verify::<G>(&t_list, &[w_list[1], w_list[0]], u));
+
+        let signature = SchnorrSignature::create_with_rng::<G, _>(b"message", k, &mut rng);
+        assert!(signature.verify::<G>(b"message", G::mul_generator(&k)));
+        assert!(!signature.verify::<G>(b"other message", G::mul_generator(&k)));
+        assert!(!signature.verify::<G>(b"message", u));
+
+        let mut chunking = Chunking::new(1, crate::chunked::yield_now);
+        assert_eq!(
+            futures::executor::block_on(proof.verify_chunked::<G, _, _>(
+                &t_list,
+                &w_list,
+                u,
+                &mut chunking
+            )),
+            Ok(true)
+        );
+
+        let proof = futures::executor::block_on(DleqProofBatched::create_chunked_with_rng::<
+            G,
+            _,
+            _,
+            _,
+        >(
+            &t_list, &w_list, d + k, &mut chunking, &mut rng
+        ))
+        .unwrap();
+        assert!(proof.verify::<G>(&t_list, &w_list, u));
+
+        let mut stream = DleqProofStream::<G>::new(&u);
+        stream.push(&t_list[0], &w_list[0]);
+        stream.push(&t_list[1], &w_list[1]);
+        let proof = stream.create_with_rng(d + k, &mut rng);
+        assert!(stream.verify(&proof, u));
+        assert_eq!(stream.len(), 2);
+
+        let mut swapped = DleqProofStream::<G>::new(&u);
+        swapped.push(&t_list[1], &w_list[1]);
+        swapped.push(&t_list[0], &w_list[0]);
+        assert!(!swapped.verify(&proof, u));
+    }
+}
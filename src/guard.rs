@@ -0,0 +1,306 @@
+//! # Guarding the metadata against user identifiers
+//!
+//! The public metadata is shared by all the tokens of an anonymity set, so metadata with a value
+//! of one user, like an email address or an account id, links the tokens to the user, and they
+//! are not anonymous anymore. The engines sign any bytes, so they do not stop this.
+//!
+//! A [`MetadataGuard`] looks at the text in the metadata, and rejects the words that look like
+//! identifiers: email addresses, UUIDs, and long or random looking strings. The client checks the
+//! metadata before it generates a token, and the issuer checks the metadata it signs.
+//! It is a heuristic: it does not find identifiers in binary metadata, and words that are not
+//! identifiers, e.g. the name of a resource, are allowed with patterns.
+//!
+//! ```
+//!     use atpmd::guard::{Identifier, MetadataGuard};
+//!
+//!     let guard = MetadataGuard {
+//!         allowed: vec!["release-*".into()],
+//!         ..MetadataGuard::default()
+//!     };
+//!
+//!     assert!(guard.check(b"2021-06-01 /api/articles").is_ok());
+//!     assert!(guard.check(b"release-2021x06x01x54a3b").is_ok());
+//!
+//!     let violation = guard.check(b"user=alice@example.com").unwrap_err();
+//!     assert_eq!((violation.kind, violation.offset), (Identifier::Email, 5));
+//! ```
+
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+use crate::common::{Error, RandomizedUnsignedToken, TokenEngine, UnsignedToken};
+
+/// What a word of the metadata looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identifier {
+    /// An email address, `name@host.tld`
+    Email,
+    /// A UUID, 32 hex digits in groups of 8, 4, 4, 4 and 12
+    Uuid,
+    /// A run of letters and digits of at least [`MetadataGuard::max_run`]
+    LongString,
+    /// A run of letters and digits of at least [`MetadataGuard::mixed_run`] which mixes them, like
+    /// a random id
+    HighEntropy,
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Email => "an email address",
+            Self::Uuid => "a UUID",
+            Self::LongString => "a long string",
+            Self::HighEntropy => "a random string",
+        })
+    }
+}
+
+/// A word of the metadata that looks like an identifier
+///
+/// It does not have the word, so it can be logged without the identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    pub kind: Identifier,
+    /// The byte of the metadata the word starts at
+    pub offset: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.kind, self.offset)
+    }
+}
+
+/// The checks of the metadata, see the [module](self)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataGuard {
+    /// The words that are allowed anyway, where `*` matches any run of characters
+    pub allowed: Vec<String>,
+    /// The length of the runs of letters and digits that are too long
+    pub max_run: usize,
+    /// The length of the runs that are too long, if they have two or more letters and digits
+    pub mixed_run: usize,
+}
+
+impl Default for MetadataGuard {
+    fn default() -> Self {
+        Self {
+            allowed: Vec::new(),
+            max_run: 24,
+            mixed_run: 16,
+        }
+    }
+}
+
+impl MetadataGuard {
+    /// Check that no word of the metadata looks like an identifier
+    pub fn check(&self, metadata: impl AsRef<[u8]>) -> Result<(), Violation> {
+        let metadata = metadata.as_ref();
+        let mut start = 0;
+
+        for word in metadata.split(|&c| !is_word(c)) {
+            let offset = start;
+            start += word.len() + 1;
+
+            if word.is_empty() || self.is_allowed(word) {
+                continue;
+            }
+
+            if let Some(kind) = self.identifier(word) {
+                return Err(Violation { kind, offset });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// [`TokenEngine::generate`], if the metadata passes the checks
+    pub fn generate<E: TokenEngine>(
+        &self,
+        metadata: <E::UnsignedToken as UnsignedToken>::Metadata,
+    ) -> Result<E::UnsignedToken, Violation>
+    where
+        <E::UnsignedToken as UnsignedToken>::Metadata: AsRef<[u8]>,
+    {
+        self.check(&metadata)?;
+        Ok(E::generate(metadata))
+    }
+
+    /// [`TokenEngine::sign_randomized`], if the metadata passes the checks
+    ///
+    /// A rejected token is [`Error::UnsafeMetadata`].
+    pub fn sign_randomized<E: TokenEngine>(
+        &self,
+        randomized_unsigned: &E::RandomizedUnsignedToken,
+        sign_key: &E::SignKey,
+    ) -> Result<E::RandomizedSignedToken, Error> {
+        self.check(randomized_unsigned.metadata())
+            .map_err(Error::UnsafeMetadata)?;
+        E::sign_randomized(randomized_unsigned, sign_key)
+    }
+
+    fn is_allowed(&self, word: &[u8]) -> bool {
+        self.allowed
+            .iter()
+            .any(|pattern| matches(pattern.as_bytes(), word))
+    }
+
+    fn identifier(&self, word: &[u8]) -> Option<Identifier> {
+        if is_email(word) {
+            return Some(Identifier::Email);
+        }
+
+        if word.windows(36).any(is_uuid) {
+            return Some(Identifier::Uuid);
+        }
+
+        for run in word.split(|c| !c.is_ascii_alphanumeric()) {
+            if run.len() >= self.max_run {
+                return Some(Identifier::LongString);
+            }
+
+            let digits = run.iter().filter(|c| c.is_ascii_digit()).count();
+            if run.len() >= self.mixed_run && digits >= 2 && run.len() - digits >= 2 {
+                return Some(Identifier::HighEntropy);
+            }
+        }
+
+        None
+    }
+}
+
+/// The bytes that words are made of, the rest separates them
+fn is_word(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"-_.+@".contains(&c)
+}
+
+/// A name, an `@`, and a host with a dot that is not at either end
+fn is_email(word: &[u8]) -> bool {
+    match word.iter().position(|&c| c == b'@') {
+        Some(at) if at > 0 => {
+            let host = &word[at + 1..];
+            host.len() > 2 && host[1..host.len() - 1].contains(&b'.')
+        }
+        _ => false,
+    }
+}
+
+fn is_uuid(bytes: &[u8]) -> bool {
+    bytes.iter().enumerate().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => *c == b'-',
+        _ => c.is_ascii_hexdigit(),
+    })
+}
+
+/// Whether the pattern matches all of the word, where `*` matches any run of bytes
+fn matches(pattern: &[u8], word: &[u8]) -> bool {
+    match (pattern.split_first(), word.split_first()) {
+        (None, _) => word.is_empty(),
+        (Some((b'*', rest)), _) => (0..=word.len()).any(|skip| matches(rest, &word[skip..])),
+        (Some((c, rest)), Some((w, word))) => c == w && matches(rest, word),
+        (Some(_), None) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn kind(guard: &MetadataGuard, metadata: &[u8]) -> Option<Identifier> {
+        guard.check(metadata).err().map(|violation| violation.kind)
+    }
+
+    #[test]
+    fn test_check() {
+        let guard = MetadataGuard::default();
+
+        // what metadata should look like
+        for metadata in &[
+            &b"2021-06-01"[..],
+            b"resource=/api/v1/articles&scope=read",
+            b"region:eu-west-1 tier:premium",
+            b"\x00\x00\x00\x00\x5f\x5e\x10\x00\x00\x04/api\x02",
+            b"@ a@b a@.b",
+            b"",
+        ] {
+            assert_eq!(guard.check(metadata), Ok(()));
+        }
+
+        assert_eq!(
+            guard.check(b"for alice.smith@example.com"),
+            Err(Violation {
+                kind: Identifier::Email,
+                offset: 4
+            })
+        );
+        assert_eq!(
+            kind(&guard, b"id=123e4567-e89b-12d3-a456-426614174000"),
+            Some(Identifier::Uuid)
+        );
+        assert_eq!(
+            kind(&guard, b"sha=9f86d081884c7d659a2feaa0c55ad015"),
+            Some(Identifier::LongString)
+        );
+        assert_eq!(
+            kind(&guard, b"session:dGhpc2lzYXNlY3JldA"),
+            Some(Identifier::HighEntropy)
+        );
+        assert_eq!(kind(&guard, b"transactionhistory"), None);
+    }
+
+    #[test]
+    fn test_allowed() {
+        let guard = MetadataGuard {
+            allowed: vec!["*@issuer.example".into(), "build-*-release".into()],
+            ..MetadataGuard::default()
+        };
+
+        assert_eq!(guard.check(b"support@issuer.example"), Ok(()));
+        assert_eq!(guard.check(b"build-9f86d081884c7d659a2f-release"), Ok(()));
+        assert_eq!(
+            kind(&guard, b"build-9f86d081884c7d659a2f-debug"),
+            Some(Identifier::HighEntropy)
+        );
+        assert_eq!(
+            kind(&guard, b"support@issuer.example.org"),
+            Some(Identifier::Email)
+        );
+
+        assert!(matches(b"a*b*c", b"abc") && matches(b"a*b*c", b"axxbyyc"));
+        assert!(!matches(b"a*b", b"ab c") && !matches(b"ab", b"abc"));
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn test_engine() {
+        use crate::nizkp_curve25519::{keys::PrivateKey, tokens::NizkpTokenEngine};
+
+        type Engine = NizkpTokenEngine<&'static [u8]>;
+
+        let guard = MetadataGuard::default();
+        let key = PrivateKey::new();
+
+        assert!(guard.generate::<Engine>(&b"2021-06-01"[..]).is_ok());
+        assert_eq!(
+            guard.generate::<Engine>(&b"bob@example.net"[..]).err(),
+            Some(Violation {
+                kind: Identifier::Email,
+                offset: 0
+            })
+        );
+
+        // a client without the guard, the issuer still refuses to sign
+        let (_r, randomized) = Engine::randomize(&Engine::generate(&b"bob@example.net"[..]));
+        assert_eq!(
+            guard.sign_randomized::<Engine>(&randomized, &key).err(),
+            Some(Error::UnsafeMetadata(Violation {
+                kind: Identifier::Email,
+                offset: 0
+            }))
+        );
+
+        let (_r, randomized) = Engine::randomize(&Engine::generate(&b"2021-06-01"[..]));
+        assert!(guard.sign_randomized::<Engine>(&randomized, &key).is_ok());
+    }
+}
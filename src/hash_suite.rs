@@ -0,0 +1,119 @@
+//! # The hash functions of the engines
+//!
+//! The engines hash the tokens to the curve and the metadata to a scalar with the digests of a
+//! [`HashSuite`]. The curve25519 engine uses the wide hash of 64 bytes and the generic engine the
+//! narrow hash of 32 bytes, both are [`Sha2`] by default, which is
+//! [`NizkpTokenEngine`](crate::nizkp_curve25519::tokens::NizkpTokenEngine) of curve25519 and
+//! [`NizkpTokenEngine`](crate::atpm_nizkp::tokens::NizkpTokenEngine) of `atpm_nizkp`. A deployment
+//! that has to use SHA-3 or BLAKE2 implements the suite with their digests and uses the
+//! `HashedNizkpTokenEngine` of the suite.
+//!
+//! A token only verifies with the suite it was created with, and the signer and the clients
+//! must agree on the suite, as on the ciphersuite of the engine. The integrity tags and the MACs
+//! of the tokens stay SHA-2.
+//!
+//! The suite also has the [`DomainParams`] of the random oracles. Two deployments with the same
+//! tags share the oracles, so the proofs and the hashes of one are those of the other. A
+//! deployment sets tags of its own in its suite, e.g. with the name of the application.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::hash_suite::HashSuite;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::HashedNizkpTokenEngine,
+//!     };
+//!
+//!     /// SHA-512 for curve25519 as in the default suite, and the SHA-512/256 of FIPS 180-4 for
+//!     /// the curves of the generic engine
+//!     struct Truncated;
+//!
+//!     impl HashSuite for Truncated {
+//!         const NAME: &'static str = "SHA512-SHA512/256";
+//!         type Wide = sha2::Sha512;
+//!         type Narrow = sha2::Sha512Trunc256;
+//!     }
+//!
+//!     type Engine = HashedNizkpTokenEngine<Vec<u8>, Truncated>;
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let signed = Engine::sign(
+//!         Engine::generate(b"metadata".to_vec()),
+//!         &PublicKey::from(&secret_key),
+//!         |randomized| Engine::sign_randomized(randomized, &secret_key),
+//!     ).unwrap();
+//!
+//!     assert!(Engine::verify(&signed, &secret_key).is_ok());
+//! ```
+
+use sha2::digest::{
+    consts::{U32, U64},
+    BlockInput, Digest,
+};
+use sha2::{Sha256, Sha512};
+
+/// The domain separation tags of the random oracles of an engine with a [`HashSuite`]
+///
+/// These are the hashes of the single token engines of curve25519 and of `atpm_nizkp`, and the
+/// proofs of their signers. The batched engines and the other oracles keep the default tags.
+///
+/// ```
+///     use atpmd::hash_suite::DomainParams;
+///
+///     const DOMAIN: DomainParams = DomainParams {
+///         dleq_proof: b"example.com DLEQ proof",
+///         ..DomainParams::DEFAULT
+///     };
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DomainParams {
+    /// The hash of the metadata to a scalar
+    pub hash_to_scalar: &'static [u8],
+    /// The hash of a token to the curve
+    pub hash_to_curve: &'static [u8],
+    /// The protocol of the transcript of the proof of the signer
+    pub dleq_proof: &'static [u8],
+}
+
+impl DomainParams {
+    /// The tags the engines have always used
+    pub const DEFAULT: Self = Self {
+        hash_to_scalar: b"This is hash_to_scalar hash",
+        hash_to_curve: b"This is h_t hash",
+        dleq_proof: b"DLEQ proof",
+    };
+}
+
+impl Default for DomainParams {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The digests an engine hashes with
+pub trait HashSuite {
+    /// The name of the suite, e.g. for the logs
+    const NAME: &'static str;
+
+    /// The tags of the oracles, see [`DomainParams`]
+    const DOMAIN: DomainParams = DomainParams::DEFAULT;
+
+    /// A hash of 64 bytes, reduced to the scalars and points of curve25519
+    type Wide: Digest<OutputSize = U64> + Default;
+
+    /// A hash of 32 bytes, for the scalars and points of the curves of the generic engine
+    ///
+    /// The hash to the curve is `expand_message_xmd` of RFC 9380, which needs the block size.
+    type Narrow: Digest<OutputSize = U32> + BlockInput + Default;
+}
+
+/// SHA-512 and SHA-256, which the engines have always used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sha2;
+
+impl HashSuite for Sha2 {
+    const NAME: &'static str = "SHA2";
+
+    type Wide = Sha512;
+    type Narrow = Sha256;
+}
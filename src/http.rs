@@ -0,0 +1,253 @@
+//! # Tokens in HTTP headers
+//!
+//! A signed token may be sent to the verifier in the `Authorization` header of an ordinary HTTP
+//! request, as `Authorization: AnonToken v1 <base64>`.
+//! The base64 (url safe, no padding) encodes the token in the [`wire`](crate::wire) format, which
+//! starts with the byte of the ciphersuite, such that the verifier can reject tokens from another
+//! engine before trying to verify them.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::http::HeaderToken;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::{PairingSignedToken, PairingTokenEngine},
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Box::from(&b"resource"[..])),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     // The user puts the token in the header
+//!     let header = signed.to_authorization_header();
+//!     assert!(header.starts_with("AnonToken v1 "));
+//!
+//!     // The verifier gets the token out of the header
+//!     let token = PairingSignedToken::<Box<[u8]>>::parse_authorization_header(&header).unwrap();
+//!     assert!(PairingTokenEngine::verify(&token, &public_key).is_ok());
+//! ```
+
+use alloc::{format, string::String};
+use core::fmt;
+
+use crate::wire::{Ciphersuite, WireError, WireFormat};
+
+/// The authorization scheme of the header
+pub const AUTHORIZATION_SCHEME: &str = "AnonToken";
+
+/// The version of the header format
+pub const AUTHORIZATION_VERSION: &str = "v1";
+
+/// The reason an authorization header could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizationHeaderError {
+    /// The header does not use the `AnonToken` scheme
+    Scheme,
+    /// The header has an unknown version
+    Version,
+    /// The credential is not valid base64
+    Base64,
+    /// The credential does not contain a token
+    Token,
+    /// The token is from another engine
+    Engine(String),
+    /// The token is not a valid wire encoding
+    Wire(WireError),
+}
+
+impl fmt::Display for AuthorizationHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scheme => write!(f, "authorization scheme is not {}", AUTHORIZATION_SCHEME),
+            Self::Version => write!(f, "authorization version is not {}", AUTHORIZATION_VERSION),
+            Self::Base64 => f.write_str("credential is not valid base64"),
+            Self::Token => f.write_str("credential does not contain a token"),
+            Self::Engine(engine) => write!(f, "token is from the engine {}", engine),
+            Self::Wire(e) => write!(f, "token is not a valid encoding: {}", e),
+        }
+    }
+}
+
+/// A signed token that can be sent in an HTTP `Authorization` header
+pub trait HeaderToken: WireFormat {
+    /// The identifier of the engine the token belongs to
+    const ENGINE_ID: &'static str;
+
+    /// The value of the `Authorization` header, without the header name
+    ///
+    /// Panics if the metadata is longer than 65535 bytes, see
+    /// [`HeaderToken::try_to_authorization_header`]
+    fn to_authorization_header(&self) -> String {
+        self.try_to_authorization_header()
+            .expect("metadata is too long")
+    }
+
+    /// The value of the `Authorization` header, without the header name
+    fn try_to_authorization_header(&self) -> Result<String, WireError> {
+        Ok(format!(
+            "{} {} {}",
+            AUTHORIZATION_SCHEME,
+            AUTHORIZATION_VERSION,
+            base64::encode_config(self.try_to_bytes()?, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// Parse the value of an `Authorization` header
+    fn parse_authorization_header(value: &str) -> Result<Self, AuthorizationHeaderError> {
+        let mut parts = value.trim().split_ascii_whitespace();
+
+        if parts.next() != Some(AUTHORIZATION_SCHEME) {
+            return Err(AuthorizationHeaderError::Scheme);
+        }
+
+        if parts.next() != Some(AUTHORIZATION_VERSION) {
+            return Err(AuthorizationHeaderError::Version);
+        }
+
+        let encoded = parts.next().ok_or(AuthorizationHeaderError::Token)?;
+        if parts.next().is_some() {
+            return Err(AuthorizationHeaderError::Token);
+        }
+
+        let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .map_err(|_e| AuthorizationHeaderError::Base64)?;
+
+        Self::from_bytes(&bytes).map_err(|e| match (e, bytes.first()) {
+            (WireError::Ciphersuite, Some(&byte)) => AuthorizationHeaderError::Engine(
+                Ciphersuite::from_byte(byte)
+                    .map(|suite| suite.name().into())
+                    .unwrap_or_else(|| format!("{:#04x}", byte)),
+            ),
+            (e, _) => AuthorizationHeaderError::Wire(e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{Reader, Writer};
+
+    #[derive(PartialEq, Debug)]
+    struct Dummy(u8);
+
+    impl WireFormat for Dummy {
+        const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+        const TYPE: u8 = 0xff;
+
+        fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+            writer.fixed([self.0]);
+            Ok(())
+        }
+
+        fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+            let [byte] = reader.fixed()?;
+            Ok(Self(byte))
+        }
+    }
+
+    impl HeaderToken for Dummy {
+        const ENGINE_ID: &'static str = "dummy";
+    }
+
+    #[derive(PartialEq, Debug)]
+    struct Other(u8);
+
+    impl WireFormat for Other {
+        const SUITE: Ciphersuite = Ciphersuite::PairingV1;
+        const TYPE: u8 = 0xff;
+
+        fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+            writer.fixed([self.0]);
+            Ok(())
+        }
+
+        fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+            let [byte] = reader.fixed()?;
+            Ok(Self(byte))
+        }
+    }
+
+    impl HeaderToken for Other {
+        const ENGINE_ID: &'static str = "other";
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let header = Dummy(7).to_authorization_header();
+
+        // the credential is the wire encoding, ciphersuite, type and the byte
+        assert_eq!(header, "AnonToken v1 ov8H");
+        assert_eq!(Dummy::parse_authorization_header(&header), Ok(Dummy(7)));
+    }
+
+    #[test]
+    fn fail_long_metadata() {
+        struct Long;
+
+        impl WireFormat for Long {
+            const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+            const TYPE: u8 = 0xff;
+
+            fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+                writer.prefixed([0; 0x10000])
+            }
+
+            fn decode(_reader: &mut Reader<'_>) -> Result<Self, WireError> {
+                Ok(Self)
+            }
+        }
+
+        impl HeaderToken for Long {
+            const ENGINE_ID: &'static str = "long";
+        }
+
+        assert_eq!(
+            Long.try_to_authorization_header(),
+            Err(WireError::MetadataLength)
+        );
+        assert_eq!(
+            Dummy(7).try_to_authorization_header().as_deref(),
+            Ok("AnonToken v1 ov8H")
+        );
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        let header = Dummy(7).to_authorization_header();
+
+        assert_eq!(
+            Other::parse_authorization_header(&header),
+            Err(AuthorizationHeaderError::Engine("ristretto-v1".into()))
+        );
+        assert_eq!(
+            Dummy::parse_authorization_header("AnonToken v1 EP8H"),
+            Err(AuthorizationHeaderError::Engine("0x10".into()))
+        );
+        assert_eq!(
+            Dummy::parse_authorization_header("AnonToken v1 ov8HBw"),
+            Err(AuthorizationHeaderError::Wire(WireError::TrailingBytes))
+        );
+        assert_eq!(
+            Dummy::parse_authorization_header(&header.replacen("AnonToken", "Bearer", 1)),
+            Err(AuthorizationHeaderError::Scheme)
+        );
+        assert_eq!(
+            Dummy::parse_authorization_header(&header.replacen("v1", "v2", 1)),
+            Err(AuthorizationHeaderError::Version)
+        );
+        assert_eq!(
+            Dummy::parse_authorization_header("AnonToken v1 !!!"),
+            Err(AuthorizationHeaderError::Base64)
+        );
+        assert_eq!(
+            Dummy::parse_authorization_header("AnonToken v1"),
+            Err(AuthorizationHeaderError::Token)
+        );
+    }
+}
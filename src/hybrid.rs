@@ -0,0 +1,378 @@
+//! # Hybrid metadata, for tokens that outlive the curves
+//!
+//! The tokens are only as strong as their curve, and a quantum computer would let anyone forge
+//! them. In hybrid mode the issuer also signs the public metadata with a stateless hash-based
+//! signature, e.g. SLH-DSA, and the signature is carried in the metadata of the token.
+//! The verifier checks both, so if the curve is broken, the tokens can still only have metadata
+//! that the issuer has signed, e.g. a resource and an expiration time.
+//! The unlinkability of the tokens does not survive a broken curve.
+//!
+//! The metadata is the data, followed by the signature and the length of the signature as 2 big
+//! endian bytes. The data comes first, so it may be [`expiry::Metadata`](crate::expiry::Metadata).
+//!
+//! The signature scheme is given by the deployment, through [`MetadataSigner`] and
+//! [`MetadataVerifier`]. With the `slh_dsa` feature, they are implemented by the SLH-DSA keys of
+//! the [`slh_dsa`](::slh_dsa) crate, and [`slh_dsa_key`] makes a signing key from our rng.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::hybrid::{self, HybridMetadata, MetadataSigner, MetadataVerifier};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     # struct Slh;
+//!     # impl MetadataSigner for Slh {
+//!     #     fn sign(&self, message: &[u8]) -> Option<Vec<u8>> { Some(message.to_vec()) }
+//!     # }
+//!     # impl MetadataVerifier for Slh {
+//!     #     fn verify(&self, message: &[u8], signature: &[u8]) -> bool { message == signature }
+//!     # }
+//!     # let slh_dsa = Slh;
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     // The issuer signs the metadata once, and hands it out to the users
+//!     let metadata = HybridMetadata::sign(b"resource", &slh_dsa).unwrap();
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(metadata),
+//!         &public_key,
+//!         |randomized_unsigned| {
+//!             assert!(hybrid::verify_request(randomized_unsigned, &slh_dsa));
+//!             PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!         },
+//!     ).unwrap();
+//!
+//!     assert!(hybrid::verify(&signed, &public_key, &slh_dsa));
+//! ```
+
+use alloc::{boxed::Box, vec::Vec};
+use core::convert::TryInto;
+use core::fmt;
+
+use sha2::{Digest, Sha512Trunc256};
+
+use crate::common::{RandomizedUnsignedToken, SignedToken};
+
+/// The length of the length of the signature at the end of the metadata
+const LENGTH_LEN: usize = 2;
+
+/// The issuer side of the hash-based signature
+pub trait MetadataSigner {
+    /// The signature of the message, none if the signer failed
+    fn sign(&self, message: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// The verifier side of the hash-based signature, the signer uses it to check requests
+pub trait MetadataVerifier {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool;
+}
+
+/// Why some metadata could not be signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HybridError {
+    /// The signer failed
+    Signer,
+    /// The signature is longer than 65535 bytes, so its length does not fit in the metadata
+    TooLong,
+}
+
+impl fmt::Display for HybridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Signer => f.write_str("the metadata signer failed"),
+            Self::TooLong => f.write_str("the signature of the metadata is too long"),
+        }
+    }
+}
+
+/// The message that is signed, a commitment to the data
+fn commitment(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha512Trunc256::new();
+
+    // domain of the commitment, the signature key may sign other things
+    hasher.update(b"Domain of hybrid metadata");
+    hasher.update(data);
+
+    hasher.finalize().into()
+}
+
+/// Split encoded metadata into the data and the signature
+fn split(metadata: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (rest, len) = metadata.split_at(metadata.len().checked_sub(LENGTH_LEN)?);
+    let len = u16::from_be_bytes(len.try_into().ok()?) as usize;
+    let (data, signature) = rest.split_at(rest.len().checked_sub(len)?);
+    Some((data, signature))
+}
+
+/// Public metadata with a hash-based signature
+///
+/// Metadata decoded from a token may not have a signature, such metadata never verifies.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct HybridMetadata(Box<[u8]>);
+
+impl HybridMetadata {
+    /// Sign the data, this is done by the issuer
+    ///
+    /// Fails if the signer fails, or if the signature is longer than 65535 bytes, which SLH-DSA
+    /// signatures are not.
+    pub fn sign<S: MetadataSigner>(
+        data: impl AsRef<[u8]>,
+        signer: &S,
+    ) -> Result<Self, HybridError> {
+        let data = data.as_ref();
+        let signature = signer.sign(&commitment(data)).ok_or(HybridError::Signer)?;
+        let len: u16 = signature
+            .len()
+            .try_into()
+            .map_err(|_| HybridError::TooLong)?;
+
+        let mut bytes = Vec::with_capacity(data.len() + signature.len() + LENGTH_LEN);
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&signature);
+        bytes.extend_from_slice(&len.to_be_bytes());
+
+        Ok(Self(bytes.into_boxed_slice()))
+    }
+
+    /// The data of the metadata, before the signature
+    pub fn data(&self) -> &[u8] {
+        split(&self.0).map_or(&[], |(data, _signature)| data)
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        split(&self.0).map_or(&[], |(_data, signature)| signature)
+    }
+
+    pub fn verify<V: MetadataVerifier>(&self, verifier: &V) -> bool {
+        verify_metadata(&self.0, verifier)
+    }
+}
+
+impl AsRef<[u8]> for HybridMetadata {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for HybridMetadata {
+    fn from(bytes: &[u8]) -> Self {
+        Self(Box::from(bytes))
+    }
+}
+
+/// Verify the hash-based signature of some encoded metadata
+pub fn verify_metadata<V: MetadataVerifier>(metadata: &[u8], verifier: &V) -> bool {
+    split(metadata).is_some_and(|(data, signature)| verifier.verify(&commitment(data), signature))
+}
+
+/// Verify a token, and the hash-based signature of its metadata
+pub fn verify<T: SignedToken, V: MetadataVerifier>(
+    token: &T,
+    verification_key: &T::VerificationKey,
+    verifier: &V,
+) -> bool {
+    verify_metadata(token.metadata_bytes(), verifier) && token.verify(verification_key)
+}
+
+/// Check the metadata of a request before signing it, the issuer should only sign tokens with
+/// metadata it has signed
+pub fn verify_request<R: RandomizedUnsignedToken, V: MetadataVerifier>(
+    request: &R,
+    verifier: &V,
+) -> bool {
+    verify_metadata(&request.metadata(), verifier)
+}
+
+// {{{ SLH-DSA
+
+#[cfg(feature = "slh_dsa")]
+pub use self::slh::slh_dsa_key;
+
+#[cfg(feature = "slh_dsa")]
+mod slh {
+    use alloc::vec::Vec;
+    use core::convert::TryFrom;
+
+    use rand::{CryptoRng, RngCore};
+    use slh_dsa::signature::{rand_core, Signer, Verifier};
+    use slh_dsa::{ParameterSet, Signature, SigningKey, VerifyingKey};
+
+    use super::{MetadataSigner, MetadataVerifier};
+
+    /// Our rng, for the newer `rand_core` of `slh_dsa`
+    struct Rng<'a, R>(&'a mut R);
+
+    impl<R: RngCore> rand_core::RngCore for Rng<'_, R> {
+        fn next_u32(&mut self) -> u32 {
+            self.0.next_u32()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0.next_u64()
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            self.0.fill_bytes(dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.0.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl<R: CryptoRng> rand_core::CryptoRng for Rng<'_, R> {}
+
+    /// A new SLH-DSA signing key of the parameter set `P`, e.g. `slh_dsa::Sha2_128s`
+    pub fn slh_dsa_key<P: ParameterSet, R: CryptoRng + RngCore>(rng: &mut R) -> SigningKey<P> {
+        SigningKey::new(&mut Rng(rng))
+    }
+
+    /// The deterministic SLH-DSA signature, the message is already a commitment with a domain
+    impl<P: ParameterSet> MetadataSigner for SigningKey<P> {
+        fn sign(&self, message: &[u8]) -> Option<Vec<u8>> {
+            let signature: Signature<P> = self.try_sign(message).ok()?;
+            Some(signature.to_bytes().to_vec())
+        }
+    }
+
+    impl<P: ParameterSet> MetadataVerifier for VerifyingKey<P> {
+        fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+            Signature::<P>::try_from(signature)
+                .and_then(|signature| Verifier::verify(self, message, &signature))
+                .is_ok()
+        }
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::expiry::{self, Metadata};
+
+    /// A stand-in for the hash-based signature, a keyed hash
+    struct Keyed(u8);
+
+    impl MetadataSigner for Keyed {
+        fn sign(&self, message: &[u8]) -> Option<Vec<u8>> {
+            Some(
+                Sha512Trunc256::new()
+                    .chain([self.0])
+                    .chain(message)
+                    .finalize()
+                    .to_vec(),
+            )
+        }
+    }
+
+    impl MetadataVerifier for Keyed {
+        fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+            self.sign(message).as_deref() == Some(signature)
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let metadata = HybridMetadata::sign(Metadata::new(100, b"data"), &Keyed(1)).unwrap();
+
+        assert_eq!(metadata.signature().len(), 32);
+        assert_eq!(metadata.as_ref()[metadata.as_ref().len() - 2..], [0, 32]);
+        assert_eq!(metadata.data(), Metadata::new(100, b"data").as_ref());
+        assert_eq!(expiry::expires_at(metadata.as_ref()), Some(100));
+
+        assert!(metadata.verify(&Keyed(1)));
+        assert!(!metadata.verify(&Keyed(2)));
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn test_token() {
+        use crate::nizkp_curve25519::{
+            keys::{PrivateKey, PublicKey},
+            tokens::NizkpTokenEngine,
+        };
+        use crate::TokenEngine;
+
+        let private = PrivateKey::new();
+        let metadata = HybridMetadata::sign(b"data", &Keyed(1)).unwrap();
+
+        let unsigned = NizkpTokenEngine::generate(metadata);
+        let (r, randomized) = NizkpTokenEngine::randomize(&unsigned);
+        assert!(verify_request(&randomized, &Keyed(1)));
+        assert!(!verify_request(&randomized, &Keyed(2)));
+
+        let response = NizkpTokenEngine::sign_randomized(&randomized, &private).unwrap();
+        let signed = NizkpTokenEngine::verify_signature_and_unrandomize(
+            unsigned,
+            randomized,
+            response,
+            &PublicKey::from(&private),
+            r,
+        )
+        .unwrap();
+
+        assert!(verify(&signed, &private, &Keyed(1)));
+        assert!(!verify(&signed, &private, &Keyed(2)));
+        assert!(!verify(&signed, &PrivateKey::new(), &Keyed(1)));
+    }
+
+    #[test]
+    fn fail_sign() {
+        struct Failing(usize);
+
+        impl MetadataSigner for Failing {
+            fn sign(&self, _message: &[u8]) -> Option<Vec<u8>> {
+                Some(alloc::vec![0; self.0]).filter(|_| self.0 > 0)
+            }
+        }
+
+        assert_eq!(
+            HybridMetadata::sign(b"data", &Failing(0)),
+            Err(HybridError::Signer)
+        );
+        assert_eq!(
+            HybridMetadata::sign(b"data", &Failing(1 << 16)),
+            Err(HybridError::TooLong)
+        );
+        assert!(HybridMetadata::sign(b"data", &Failing(0xffff)).is_ok());
+    }
+
+    #[cfg(feature = "slh_dsa")]
+    #[test]
+    fn test_slh_dsa() {
+        use slh_dsa::signature::Keypair;
+
+        let signing = slh_dsa_key::<slh_dsa::Shake128f, _>(&mut crate::rng::default_rng());
+        let verifying = signing.verifying_key();
+        let metadata = HybridMetadata::sign(Metadata::new(100, b"data"), &signing).unwrap();
+
+        assert_eq!(metadata.data(), Metadata::new(100, b"data").as_ref());
+        assert!(metadata.verify(&verifying));
+
+        let other = slh_dsa_key::<slh_dsa::Shake128f, _>(&mut crate::rng::default_rng());
+        assert!(!metadata.verify(&other.verifying_key()));
+        assert!(!verify_metadata(b"data\x00\x00", &verifying));
+    }
+
+    #[test]
+    fn fail_unsigned() {
+        for bytes in [&b""[..], b"\x00", b"data\x00\x10"] {
+            let metadata = HybridMetadata::from(bytes);
+            assert_eq!(metadata.signature(), b"");
+            assert!(!metadata.verify(&Keyed(1)));
+        }
+
+        // the signature of other data
+        let signed = HybridMetadata::sign(b"data", &Keyed(1)).unwrap();
+        let mut bytes = signed.as_ref().to_vec();
+        bytes[0] ^= 1;
+        assert!(!HybridMetadata::from(&bytes[..]).verify(&Keyed(1)));
+    }
+}
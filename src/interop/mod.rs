@@ -0,0 +1,7 @@
+//! # Formats of other token protocols
+//!
+//! The messages of other protocols, mapped onto the engines of this crate, such that the engines
+//! can be used where the other protocols are spoken.
+
+#[cfg(feature = "curve25519")]
+pub mod privacypass;
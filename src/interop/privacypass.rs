@@ -0,0 +1,433 @@
+//! # Privacy Pass messages
+//!
+//! The messages of the Privacy Pass issuance protocol ([RFC 9578]) for the curve25519 engine. The
+//! [`TokenRequest`], [`TokenResponse`] and [`Token`] have the byte layouts of the privately
+//! verifiable token type, with `Ne = Ns = Nk = 32` for ristretto255, so the clients and issuers of
+//! Privacy Pass can carry them.
+//!
+//! The engine is not the VOPRF of the token type 0x0001: the group is ristretto255, and the token
+//! is signed as (d + k)^{-1} t, for the hash d of the empty public metadata. The messages therefore
+//! have their own token type, [`TOKEN_TYPE`], which is not registered, and a client or issuer from
+//! elsewhere must be configured with it. The nonce, the challenge digest and the key id of a token
+//! are its hidden metadata, so the issuer never sees them.
+//!
+//! ```
+//!     use atpmd::interop::privacypass::{Token, TokenRequest, TokenResponse};
+//!     use atpmd::nizkp_curve25519::keys::{PrivateKey, PublicKey};
+//!
+//!     let private_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&private_key);
+//!
+//!     // the digest of the token challenge of the origin
+//!     let challenge_digest = [7u8; 32];
+//!
+//!     // the client asks the issuer
+//!     let (pending, request) = TokenRequest::new(challenge_digest, &public_key);
+//!     let request = TokenRequest::from_bytes(&request.to_bytes()).unwrap();
+//!
+//!     let response = request.issue(&private_key).unwrap();
+//!     let response = TokenResponse::from_bytes(&response.to_bytes()).unwrap();
+//!
+//!     // and redeems the token at the origin
+//!     let token = pending.finalize(&response, &public_key).unwrap();
+//!     let token = Token::from_bytes(&token.to_bytes()).unwrap();
+//!     assert_eq!(token.challenge_digest, challenge_digest);
+//!     assert!(token.verify(&private_key).is_ok());
+//! ```
+//!
+//! [RFC 9578]: https://www.rfc-editor.org/rfc/rfc9578
+
+use alloc::{boxed::Box, vec::Vec};
+
+use curve25519_dalek::{ristretto::CompressedRistretto, scalar::Scalar};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::common::{fill_bytes, Error, TokenEngine, TokenIdentifier};
+use crate::group::DleqProof;
+use crate::nizkp_curve25519::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{
+        read_point, NizkpSignedToken, NizkpTokenEngine, NizkpUnsignedToken, RandomizedSignedToken,
+        RandomizedUnsignedToken,
+    },
+};
+use crate::wire::{Reader, WireError, Writer};
+
+/// The token type of the messages, not registered with IANA
+pub const TOKEN_TYPE: u16 = 0xA7D1;
+
+/// The length of an encoded element
+pub const NE: usize = 32;
+
+/// The length of an encoded scalar
+pub const NS: usize = 32;
+
+/// The length of the authenticator of a token
+pub const NK: usize = 32;
+
+/// The length of a token key id
+pub const NID: usize = 32;
+
+type Engine = NizkpTokenEngine<Box<[u8]>>;
+
+/// The key id of a public key, the SHA-256 of its encoding
+pub fn token_key_id(public_key: &PublicKey) -> [u8; NID] {
+    Sha256::digest(public_key.to_affine().compress().as_bytes()).into()
+}
+
+/// The last byte of the key id, which is all the issuer gets in a request
+fn truncated(token_key_id: &[u8; NID]) -> u8 {
+    token_key_id[NID - 1]
+}
+
+/// The identifier of a token, with the fields of the token as the hidden metadata
+fn identifier(
+    nonce: &[u8; 32],
+    challenge_digest: &[u8; 32],
+    token_key_id: &[u8; NID],
+) -> TokenIdentifier<Box<[u8]>> {
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&nonce[..16]);
+
+    let mut hidden = Vec::with_capacity(2 + 16 + 32 + NID);
+    hidden.extend_from_slice(&TOKEN_TYPE.to_be_bytes());
+    hidden.extend_from_slice(&nonce[16..]);
+    hidden.extend_from_slice(challenge_digest);
+    hidden.extend_from_slice(token_key_id);
+
+    TokenIdentifier::from_hidden(id, hidden.into_boxed_slice())
+}
+
+/// The public metadata of the tokens, empty as Privacy Pass has none
+fn metadata() -> Box<[u8]> {
+    Box::from(&[][..])
+}
+
+/// Read the token type, and check that it is ours
+fn read_token_type(reader: &mut Reader<'_>) -> Result<(), WireError> {
+    if u16::from_be_bytes(reader.fixed()?) == TOKEN_TYPE {
+        Ok(())
+    } else {
+        Err(WireError::Type)
+    }
+}
+
+/// Check that a message has been read to the end
+fn finish<T>(reader: Reader<'_>, message: T) -> Result<T, WireError> {
+    if reader.is_empty() {
+        Ok(message)
+    } else {
+        Err(WireError::TrailingBytes)
+    }
+}
+
+// {{{ Request
+
+/// The request of the client, `TokenRequest` in the RFC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenRequest {
+    pub truncated_token_key_id: u8,
+    pub blinded_msg: [u8; NE],
+}
+
+impl TokenRequest {
+    /// A request for a token for the challenge, and what the client keeps to finalize the token
+    pub fn new(challenge_digest: [u8; 32], public_key: &PublicKey) -> (PendingToken, Self) {
+        Self::new_with_rng(challenge_digest, public_key, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::new`], with the nonce and the blinding from the given rng
+    pub fn new_with_rng<R: CryptoRng + RngCore>(
+        challenge_digest: [u8; 32],
+        public_key: &PublicKey,
+        rng: &mut R,
+    ) -> (PendingToken, Self) {
+        let mut nonce = [0u8; 32];
+        fill_bytes(rng, &mut nonce);
+        let token_key_id = token_key_id(public_key);
+
+        let unsigned = NizkpUnsignedToken::from_parts(
+            identifier(&nonce, &challenge_digest, &token_key_id),
+            metadata(),
+        );
+        let (r, randomized) = Engine::randomize_with_rng(&unsigned, rng);
+
+        let request = Self {
+            truncated_token_key_id: truncated(&token_key_id),
+            blinded_msg: randomized.point().compress().to_bytes(),
+        };
+
+        let pending = PendingToken {
+            unsigned,
+            randomized,
+            r,
+            nonce,
+            challenge_digest,
+            token_key_id,
+        };
+
+        (pending, request)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.fixed(TOKEN_TYPE.to_be_bytes());
+        writer.fixed([self.truncated_token_key_id]);
+        writer.fixed(self.blinded_msg);
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let mut reader = Reader::new(bytes);
+        read_token_type(&mut reader)?;
+        let [truncated_token_key_id] = reader.fixed()?;
+        let request = Self {
+            truncated_token_key_id,
+            blinded_msg: reader.fixed()?,
+        };
+
+        finish(reader, request)
+    }
+
+    /// Sign the request, by the issuer
+    ///
+    /// Fails with [`Error::KeyMismatch`] if the request is for another key.
+    pub fn issue(&self, private_key: &PrivateKey) -> Result<TokenResponse, Error> {
+        self.issue_with_rng(private_key, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::issue`], with the nonce of the proof from the given rng
+    pub fn issue_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        private_key: &PrivateKey,
+        rng: &mut R,
+    ) -> Result<TokenResponse, Error> {
+        if self.truncated_token_key_id != truncated(&token_key_id(&PublicKey::from(private_key))) {
+            return Err(Error::KeyMismatch);
+        }
+
+        let point = read_point(&mut Reader::new(&self.blinded_msg))?;
+        let randomized = RandomizedUnsignedToken::from_parts(point, metadata());
+        let signed = Engine::sign_randomized_with_rng(&randomized, private_key, rng)?;
+
+        let mut proof = Writer::new();
+        signed.proof().encode(&mut proof);
+
+        let mut evaluate_proof = [0u8; 2 * NS];
+        evaluate_proof.copy_from_slice(&proof.into_bytes());
+
+        Ok(TokenResponse {
+            evaluate_msg: signed.point().compress().to_bytes(),
+            evaluate_proof,
+        })
+    }
+}
+
+// }}}
+
+// {{{ Response
+
+/// The response of the issuer, `TokenResponse` in the RFC
+///
+/// The proof is the DLEQ proof of the engine, the challenge and the response scalars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenResponse {
+    pub evaluate_msg: [u8; NE],
+    pub evaluate_proof: [u8; 2 * NS],
+}
+
+impl TokenResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.fixed(self.evaluate_msg);
+        writer.fixed(self.evaluate_proof);
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let mut reader = Reader::new(bytes);
+        let response = Self {
+            evaluate_msg: reader.fixed()?,
+            evaluate_proof: reader.fixed()?,
+        };
+
+        finish(reader, response)
+    }
+}
+
+/// What the client keeps between the request and the response
+pub struct PendingToken {
+    unsigned: NizkpUnsignedToken<Box<[u8]>>,
+    randomized: RandomizedUnsignedToken<Box<[u8]>>,
+    r: Scalar,
+    nonce: [u8; 32],
+    challenge_digest: [u8; 32],
+    token_key_id: [u8; NID],
+}
+
+impl PendingToken {
+    /// Check the proof of the response, and make the token
+    pub fn finalize(
+        self,
+        response: &TokenResponse,
+        public_key: &PublicKey,
+    ) -> Result<Token, Error> {
+        let point = read_point(&mut Reader::new(&response.evaluate_msg))?;
+        let proof = DleqProof::decode(&mut Reader::new(&response.evaluate_proof))?;
+
+        let signed = Engine::verify_signature_and_unrandomize(
+            self.unsigned,
+            self.randomized,
+            RandomizedSignedToken::from_parts(point, proof),
+            public_key,
+            self.r,
+        )?;
+
+        Ok(Token {
+            nonce: self.nonce,
+            challenge_digest: self.challenge_digest,
+            token_key_id: self.token_key_id,
+            authenticator: signed.point().compress().to_bytes(),
+        })
+    }
+}
+
+// }}}
+
+// {{{ Token
+
+/// The token the client redeems at the origin, `Token` in the RFC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+    pub nonce: [u8; 32],
+    pub challenge_digest: [u8; 32],
+    pub token_key_id: [u8; NID],
+    pub authenticator: [u8; NK],
+}
+
+impl Token {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new();
+        writer.fixed(TOKEN_TYPE.to_be_bytes());
+        writer.fixed(self.nonce);
+        writer.fixed(self.challenge_digest);
+        writer.fixed(self.token_key_id);
+        writer.fixed(self.authenticator);
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let mut reader = Reader::new(bytes);
+        read_token_type(&mut reader)?;
+        let token = Self {
+            nonce: reader.fixed()?,
+            challenge_digest: reader.fixed()?,
+            token_key_id: reader.fixed()?,
+            authenticator: reader.fixed()?,
+        };
+
+        finish(reader, token)
+    }
+
+    /// The token of the engine, e.g. to redeem it with a redemption store
+    pub fn to_signed(&self) -> Result<NizkpSignedToken<Box<[u8]>>, Error> {
+        let point = CompressedRistretto(self.authenticator)
+            .decompress()
+            .ok_or(Error::MalformedPoint)?;
+
+        Ok(NizkpSignedToken::from_parts(
+            identifier(&self.nonce, &self.challenge_digest, &self.token_key_id),
+            metadata(),
+            point,
+            None,
+        ))
+    }
+
+    /// Verify the token, by the origin
+    ///
+    /// The origin must also check that the challenge digest is of a challenge it made.
+    pub fn verify(&self, private_key: &PrivateKey) -> Result<(), Error> {
+        if self.token_key_id != token_key_id(&PublicKey::from(private_key)) {
+            return Err(Error::KeyMismatch);
+        }
+
+        Engine::verify(&self.to_signed()?, private_key)
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(private_key: &PrivateKey) -> Token {
+        let public_key = PublicKey::from(private_key);
+        let (pending, request) = TokenRequest::new([1; 32], &public_key);
+        let response = request.issue(private_key).unwrap();
+        pending.finalize(&response, &public_key).unwrap()
+    }
+
+    #[test]
+    fn test_layout() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let (pending, request) = TokenRequest::new([1; 32], &public_key);
+        let response = request.issue(&private_key).unwrap();
+
+        let bytes = request.to_bytes();
+        assert_eq!(bytes.len(), 2 + 1 + NE);
+        assert_eq!(bytes[..2], TOKEN_TYPE.to_be_bytes());
+        assert_eq!(bytes[2], token_key_id(&public_key)[NID - 1]);
+        assert_eq!(response.to_bytes().len(), NE + 2 * NS);
+
+        let token = pending.finalize(&response, &public_key).unwrap();
+        assert_eq!(token.to_bytes().len(), 2 + 32 + 32 + NID + NK);
+        assert_eq!(Token::from_bytes(&token.to_bytes()), Ok(token));
+
+        let mut other_type = token.to_bytes();
+        other_type[1] = 0x01;
+        assert_eq!(Token::from_bytes(&other_type), Err(WireError::Type));
+        assert_eq!(
+            TokenRequest::from_bytes(&[&bytes[..], &[0]].concat()),
+            Err(WireError::TrailingBytes)
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let private_key = PrivateKey::new();
+        let token = token(&private_key);
+        assert!(token.verify(&private_key).is_ok());
+
+        // the challenge, the nonce and the key are signed
+        let mut changed = token;
+        changed.challenge_digest[0] ^= 1;
+        assert_eq!(changed.verify(&private_key), Err(Error::BadSignature));
+
+        let mut changed = token;
+        changed.nonce[31] ^= 1;
+        assert_eq!(changed.verify(&private_key), Err(Error::BadSignature));
+
+        assert_eq!(token.verify(&PrivateKey::new()), Err(Error::KeyMismatch));
+    }
+
+    #[test]
+    fn fail_issue() {
+        let private_key = PrivateKey::new();
+        let (pending, mut request) = TokenRequest::new([1; 32], &PublicKey::from(&private_key));
+
+        let mut wrong_key = request;
+        wrong_key.truncated_token_key_id ^= 1;
+        assert_eq!(wrong_key.issue(&private_key), Err(Error::KeyMismatch));
+
+        // the response of another key does not verify
+        let other_key = PrivateKey::new();
+        request.truncated_token_key_id = truncated(&token_key_id(&PublicKey::from(&other_key)));
+        let response = request.issue(&other_key).unwrap();
+        assert!(pending
+            .finalize(&response, &PublicKey::from(&private_key))
+            .is_err());
+    }
+}
@@ -0,0 +1,474 @@
+//! # Messages of the issuance protocol
+//!
+//! The client posts an [`IssuanceRequestMsg`] to the issuance endpoint of the issuer, see
+//! [`discovery`](crate::discovery), and the issuer answers with an [`IssuanceResponseMsg`], for a
+//! single token or a dyn batch of any engine. Both sides use these types, so they agree on the
+//! shape of the messages.
+//!
+//! A message is tagged with the version of the messages and the engine, e.g.
+//! `{"version":1,"engine":"pairing-batch","message":{...}}`. The engine is the `ENGINE_ID` of the
+//! tokens (see [`HeaderToken`](crate::http::HeaderToken)), with `-batch` for the batches. A message
+//! of another version, or for an engine that is not built, is not decoded.
+//!
+//! ```
+//!     use std::convert::TryFrom;
+//!     use atpmd::TokenEngine;
+//!     use atpmd::issuance::{IssuanceRequestMsg, IssuanceResponseMsg};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         refusal::PairingSignedRefusal,
+//!         tokens::{PairingTokenEngine, RandomizedSignedToken, RandomizedUnsignedToken},
+//!     };
+//!     use atpmd::refusal::SignResponse;
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Box::from(&b"resource"[..])),
+//!         &public_key,
+//!         |randomized_unsigned| {
+//!             // the client sends the request
+//!             let request = IssuanceRequestMsg::from(randomized_unsigned.clone());
+//!             let json = serde_json::to_string(&request).unwrap();
+//!
+//!             // the issuer only signs the engine it has a key for
+//!             let request: IssuanceRequestMsg<Box<[u8]>> = serde_json::from_str(&json).unwrap();
+//!             assert_eq!(request.engine(), "pairing");
+//!             let randomized = RandomizedUnsignedToken::try_from(request).unwrap();
+//!             let response = IssuanceResponseMsg::from(SignResponse::Signed(
+//!                 PairingTokenEngine::sign_randomized(&randomized, &secret_key)?,
+//!             ));
+//!             let json = serde_json::to_string(&response).unwrap();
+//!
+//!             // the client gets the signed token back, or why the issuer refused
+//!             let response: IssuanceResponseMsg<Box<[u8]>> = serde_json::from_str(&json).unwrap();
+//!             SignResponse::<RandomizedSignedToken<_>, PairingSignedRefusal>::try_from(response)
+//!                 .map_err(|_e| atpmd::Error::NotSigned)?
+//!                 .into_result(&b"resource"[..], &public_key, 1_600_000_000)
+//!         }
+//!     ).unwrap();
+//!
+//!     assert!(PairingTokenEngine::verify(&signed, &public_key).is_ok());
+//! ```
+
+use alloc::boxed::Box;
+use core::{convert::TryFrom, fmt};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(feature = "pairing")]
+use crate::atpm_pairing::{
+    refusal::PairingSignedRefusal, tokens as pairing, tokens_batched_dyn as pairing_batched,
+};
+use crate::common::RandomizedUnsignedToken;
+#[cfg(feature = "curve25519")]
+use crate::nizkp_curve25519::{
+    refusal::NizkpSignedRefusal, tokens as curve25519, tokens_batched_dyn as curve25519_batched,
+};
+use crate::refusal::SignResponse;
+
+/// The version of the messages
+pub const ISSUANCE_VERSION: u32 = 1;
+
+/// A message is for another engine than the one it was taken as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EngineMismatch {
+    /// The engine of the message
+    pub engine: &'static str,
+}
+
+impl fmt::Display for EngineMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the message is for the engine {}", self.engine)
+    }
+}
+
+// {{{ Request
+
+/// The randomized tokens the client asks the issuer to sign
+#[derive(Serialize, Deserialize)]
+#[serde(
+    remote = "Self",
+    tag = "engine",
+    content = "message",
+    bound = "M: AsRef<[u8]>"
+)]
+pub enum IssuanceRequestMsg<M: AsRef<[u8]>> {
+    #[cfg(feature = "pairing")]
+    #[serde(rename = "pairing")]
+    Pairing(pairing::RandomizedUnsignedToken<M>),
+    #[cfg(feature = "pairing")]
+    #[serde(rename = "pairing-batch")]
+    PairingBatch(pairing_batched::DynBatchedRandomizedUnsignedToken<M>),
+    #[cfg(feature = "curve25519")]
+    #[serde(rename = "curve25519")]
+    Curve25519(curve25519::RandomizedUnsignedToken<M>),
+    #[cfg(feature = "curve25519")]
+    #[serde(rename = "curve25519-batch")]
+    Curve25519Batch(curve25519_batched::DynRandomizedUnsignedTokenBatched<M>),
+}
+
+impl<M: AsRef<[u8]>> IssuanceRequestMsg<M> {
+    /// The engine tag of the message
+    pub fn engine(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(_) => "pairing",
+            #[cfg(feature = "pairing")]
+            Self::PairingBatch(_) => "pairing-batch",
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(_) => "curve25519",
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519Batch(_) => "curve25519-batch",
+        }
+    }
+
+    /// The number of tokens the client asks for
+    pub fn len(&self) -> usize {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(_) => 1,
+            #[cfg(feature = "pairing")]
+            Self::PairingBatch(batch) => batch.len(),
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(_) => 1,
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519Batch(batch) => batch.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The public metadata the tokens are signed with
+    pub fn metadata(&self) -> Box<[u8]> {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(token) => token.metadata(),
+            #[cfg(feature = "pairing")]
+            Self::PairingBatch(batch) => batch.metadata(),
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(token) => token.metadata(),
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519Batch(batch) => batch.metadata(),
+        }
+    }
+}
+
+// }}}
+
+// {{{ Response
+
+/// The answer of the issuer, the signed tokens or a signed refusal
+#[derive(Serialize, Deserialize)]
+#[serde(
+    remote = "Self",
+    tag = "engine",
+    content = "message",
+    bound = "M: AsRef<[u8]>"
+)]
+pub enum IssuanceResponseMsg<M: AsRef<[u8]>> {
+    #[cfg(feature = "pairing")]
+    #[serde(rename = "pairing")]
+    Pairing(SignResponse<pairing::RandomizedSignedToken<M>, PairingSignedRefusal>),
+    #[cfg(feature = "pairing")]
+    #[serde(rename = "pairing-batch")]
+    PairingBatch(
+        SignResponse<pairing_batched::DynBatchedRandomizedSignedToken<M>, PairingSignedRefusal>,
+    ),
+    #[cfg(feature = "curve25519")]
+    #[serde(rename = "curve25519")]
+    Curve25519(SignResponse<curve25519::RandomizedSignedToken<M>, NizkpSignedRefusal>),
+    #[cfg(feature = "curve25519")]
+    #[serde(rename = "curve25519-batch")]
+    Curve25519Batch(
+        SignResponse<curve25519_batched::DynRandomizedSignedTokenBatched<M>, NizkpSignedRefusal>,
+    ),
+}
+
+impl<M: AsRef<[u8]>> IssuanceResponseMsg<M> {
+    /// The engine tag of the message
+    pub fn engine(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(_) => "pairing",
+            #[cfg(feature = "pairing")]
+            Self::PairingBatch(_) => "pairing-batch",
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(_) => "curve25519",
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519Batch(_) => "curve25519-batch",
+        }
+    }
+}
+
+// }}}
+
+// {{{ Conversions
+
+macro_rules! message_conversions {
+    ($msg:ident { $($(#[$cfg:meta])* $variant:ident($ty:ty),)* }) => {
+        $(
+            $(#[$cfg])*
+            impl<M: AsRef<[u8]>> From<$ty> for $msg<M> {
+                fn from(message: $ty) -> Self {
+                    Self::$variant(message)
+                }
+            }
+
+            $(#[$cfg])*
+            impl<M: AsRef<[u8]>> TryFrom<$msg<M>> for $ty {
+                type Error = EngineMismatch;
+
+                fn try_from(message: $msg<M>) -> Result<Self, Self::Error> {
+                    match message {
+                        $msg::$variant(message) => Ok(message),
+                        #[allow(unreachable_patterns)]
+                        other => Err(EngineMismatch {
+                            engine: other.engine(),
+                        }),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+message_conversions!(IssuanceRequestMsg {
+    #[cfg(feature = "pairing")]
+    Pairing(pairing::RandomizedUnsignedToken<M>),
+    #[cfg(feature = "pairing")]
+    PairingBatch(pairing_batched::DynBatchedRandomizedUnsignedToken<M>),
+    #[cfg(feature = "curve25519")]
+    Curve25519(curve25519::RandomizedUnsignedToken<M>),
+    #[cfg(feature = "curve25519")]
+    Curve25519Batch(curve25519_batched::DynRandomizedUnsignedTokenBatched<M>),
+});
+
+message_conversions!(IssuanceResponseMsg {
+    #[cfg(feature = "pairing")]
+    Pairing(SignResponse<pairing::RandomizedSignedToken<M>, PairingSignedRefusal>),
+    #[cfg(feature = "pairing")]
+    PairingBatch(
+        SignResponse<pairing_batched::DynBatchedRandomizedSignedToken<M>, PairingSignedRefusal>
+    ),
+    #[cfg(feature = "curve25519")]
+    Curve25519(SignResponse<curve25519::RandomizedSignedToken<M>, NizkpSignedRefusal>),
+    #[cfg(feature = "curve25519")]
+    Curve25519Batch(
+        SignResponse<curve25519_batched::DynRandomizedSignedTokenBatched<M>, NizkpSignedRefusal>
+    ),
+});
+
+// }}}
+
+// {{{ Serde
+
+#[derive(Serialize)]
+#[serde(bound = "")]
+struct VersionedRef<'a, T: Tagged> {
+    version: u32,
+    #[serde(flatten, serialize_with = "T::serialize_tagged")]
+    message: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(bound = "")]
+struct Versioned<T: Tagged> {
+    version: u32,
+    #[serde(flatten, deserialize_with = "T::deserialize_tagged")]
+    message: T,
+}
+
+/// A message that is serialized with its engine tag, and then versioned
+trait Tagged: Sized {
+    fn serialize_tagged<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>;
+
+    fn deserialize_tagged<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+}
+
+fn serialize_versioned<T: Tagged, S: Serializer>(
+    message: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    VersionedRef {
+        version: ISSUANCE_VERSION,
+        message,
+    }
+    .serialize(serializer)
+}
+
+fn deserialize_versioned<'de, T: Tagged, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<T, D::Error> {
+    let versioned = Versioned::<T>::deserialize(deserializer)?;
+    if versioned.version != ISSUANCE_VERSION {
+        return Err(de::Error::custom(
+            "unknown version of the issuance messages",
+        ));
+    }
+
+    Ok(versioned.message)
+}
+
+macro_rules! versioned_serde {
+    ($msg:ident) => {
+        impl<M: AsRef<[u8]>> Tagged for $msg<M> {
+            fn serialize_tagged<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                Self::serialize(self, serializer)
+            }
+
+            fn deserialize_tagged<'de, D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> Result<Self, D::Error> {
+                Self::deserialize(deserializer)
+            }
+        }
+
+        impl<M: AsRef<[u8]>> Serialize for $msg<M> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serialize_versioned(self, serializer)
+            }
+        }
+
+        impl<'de, M: AsRef<[u8]>> Deserialize<'de> for $msg<M> {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserialize_versioned(deserializer)
+            }
+        }
+    };
+}
+
+versioned_serde!(IssuanceRequestMsg);
+versioned_serde!(IssuanceResponseMsg);
+
+// }}}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use crate::atpm_pairing::{
+        keys::{PrivateKey, PublicKey},
+        tokens::PairingTokenEngine,
+        tokens_batched_dyn::DynBatchedPairingTokenEngine,
+    };
+    use crate::nizkp_curve25519::{
+        keys::PrivateKey as NizkpPrivateKey, tokens::NizkpTokenEngine,
+        tokens_batched_dyn::DynBatchedNizkpTokenEngine,
+    };
+    use crate::refusal::{Refusal, RefusalReason};
+    use crate::TokenEngine;
+
+    type Request = IssuanceRequestMsg<Box<[u8]>>;
+    type Response = IssuanceResponseMsg<Box<[u8]>>;
+
+    fn metadata() -> Box<[u8]> {
+        Box::from(&b"resource"[..])
+    }
+
+    fn roundtrip<T: Serialize + serde::de::DeserializeOwned>(message: &T) -> T {
+        serde_json::from_str(&serde_json::to_string(message).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_requests() {
+        let (_, pairing) = PairingTokenEngine::randomize(&PairingTokenEngine::generate(metadata()));
+        let (_, pairing_batch) = DynBatchedPairingTokenEngine::randomize(
+            &DynBatchedPairingTokenEngine::generate((metadata(), 3)),
+        );
+        let (_, curve25519) = NizkpTokenEngine::randomize(&NizkpTokenEngine::generate(metadata()));
+        let (_, curve25519_batch) = DynBatchedNizkpTokenEngine::randomize(
+            &DynBatchedNizkpTokenEngine::generate((metadata(), 4)),
+        );
+
+        let requests = [
+            (Request::from(pairing), "pairing", 1),
+            (Request::from(pairing_batch), "pairing-batch", 3),
+            (Request::from(curve25519), "curve25519", 1),
+            (Request::from(curve25519_batch), "curve25519-batch", 4),
+        ];
+
+        for (request, engine, len) in &requests {
+            let json = serde_json::to_value(request).unwrap();
+            assert_eq!(json["version"], ISSUANCE_VERSION);
+            assert_eq!(json["engine"], *engine);
+
+            let decoded = roundtrip(request);
+            assert_eq!(decoded.engine(), *engine);
+            assert_eq!(decoded.len(), *len);
+            assert_eq!(decoded.metadata(), metadata());
+        }
+    }
+
+    #[test]
+    fn test_engine_mismatch() {
+        let (_, curve25519) = NizkpTokenEngine::randomize(&NizkpTokenEngine::generate(metadata()));
+        let request = Request::from(curve25519);
+
+        assert_eq!(
+            pairing::RandomizedUnsignedToken::try_from(request).err(),
+            Some(EngineMismatch {
+                engine: "curve25519"
+            })
+        );
+    }
+
+    #[test]
+    fn test_responses() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let unsigned = PairingTokenEngine::generate(metadata());
+        let (r, randomized) = PairingTokenEngine::randomize(&unsigned);
+        let signed = PairingTokenEngine::sign_randomized(&randomized, &private_key).unwrap();
+
+        let response = roundtrip(&Response::from(SignResponse::Signed(signed)));
+        assert_eq!(response.engine(), "pairing");
+        let signed = SignResponse::<pairing::RandomizedSignedToken<_>, _>::try_from(response)
+            .unwrap()
+            .into_result(metadata(), &public_key, 1000)
+            .unwrap();
+        assert!(PairingTokenEngine::verify_signature_and_unrandomize(
+            unsigned,
+            randomized,
+            signed,
+            &public_key,
+            r
+        )
+        .is_ok());
+
+        // a refusal of the batch issuance of the other engine
+        let nizkp_key = NizkpPrivateKey::new();
+        let refusal = NizkpSignedRefusal::sign(
+            Refusal::new(RefusalReason::Overloaded, Some(1), 1000),
+            metadata(),
+            &nizkp_key,
+        );
+        let response = roundtrip(&Response::from(SignResponse::<
+            curve25519_batched::DynRandomizedSignedTokenBatched<_>,
+            _,
+        >::Refused(refusal)));
+        assert_eq!(response.engine(), "curve25519-batch");
+        assert!(SignResponse::<pairing::RandomizedSignedToken<_>, _>::try_from(response).is_err());
+    }
+
+    #[test]
+    fn test_bad_messages() {
+        let (_, pairing) = PairingTokenEngine::randomize(&PairingTokenEngine::generate(metadata()));
+        let mut json = serde_json::to_value(Request::from(pairing)).unwrap();
+
+        json["version"] = (ISSUANCE_VERSION + 1).into();
+        assert!(serde_json::from_value::<Request>(json.clone()).is_err());
+
+        json["version"] = ISSUANCE_VERSION.into();
+        assert!(serde_json::from_value::<Request>(json.clone()).is_ok());
+
+        json["engine"] = "curve25519".into();
+        assert!(serde_json::from_value::<Request>(json.clone()).is_err());
+
+        json["engine"] = "p256".into();
+        assert!(serde_json::from_value::<Request>(json).is_err());
+    }
+}
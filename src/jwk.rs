@@ -0,0 +1,280 @@
+//! # Public keys as JSON Web Keys
+//!
+//! The public keys of a signer may be distributed as a JWK set (RFC 7517), e.g. from
+//! `/.well-known/jwks.json`, to use the caching and rotation of existing JOSE infrastructure.
+//!
+//! The keys are octet key pairs with curves that are not registered with IANA:
+//!
+//! | engine     | `kty` | `crv`          | `x`                                  |
+//! |------------|-------|----------------|--------------------------------------|
+//! | pairing    | `OKP` | `BLS12381G2`   | the compressed point in G2, 96 bytes |
+//! | curve25519 | `OKP` | `ristretto255` | the compressed ristretto point       |
+//!
+//! `x` and `kid` are base64url without padding, `kid` is the [`KeyId`] of the key.
+//! The non-standard member `epoch` is the [`KeyEpoch`] of the key, if it has one.
+//!
+//! ```
+//!     use atpmd::PublicKeySet;
+//!     use atpmd::jwk::JwkSet;
+//!     use atpmd::atpm_pairing::keys::{PrivateKey, PublicKey};
+//!
+//!     let mut keys = PublicKeySet::new();
+//!     keys.insert(1, PublicKey::from(&PrivateKey::new()));
+//!     keys.insert(2, PublicKey::from(&PrivateKey::new()));
+//!
+//!     // The signer serves this
+//!     let jwks = serde_json::to_string(&JwkSet::from_key_set(&keys)).unwrap();
+//!
+//!     // The user fetches it
+//!     let jwks: JwkSet = serde_json::from_str(&jwks).unwrap();
+//!     let fetched: PublicKeySet<PublicKey> = jwks.to_key_set().unwrap();
+//!     assert_eq!(fetched.latest_epoch(), Some(2));
+//! ```
+
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
+use core::{convert::TryInto, fmt};
+
+use crate::common::{HasKeyId, KeyEpoch, KeyId, PublicKeySet};
+use crate::wire::WireFormat;
+
+/// The key type of all the keys
+pub const KEY_TYPE: &str = "OKP";
+
+/// The reason a JWK could not be imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JwkError {
+    /// The key type is not `OKP`
+    KeyType(String),
+    /// The curve is not the curve of the key
+    Curve(String),
+    /// `x` or `kid` is not valid base64url
+    Base64,
+    /// `x` is not a valid point
+    Key,
+    /// `kid` is not the identifier of the key
+    KeyId,
+    /// The key does not have an epoch, needed for a key set
+    MissingEpoch,
+}
+
+impl fmt::Display for JwkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyType(kty) => write!(f, "key type is {}, not {}", kty, KEY_TYPE),
+            Self::Curve(crv) => write!(f, "curve {} is not the curve of the key", crv),
+            Self::Base64 => f.write_str("x or kid is not valid base64url"),
+            Self::Key => f.write_str("x is not a valid public key"),
+            Self::KeyId => f.write_str("kid is not the identifier of the key"),
+            Self::MissingEpoch => f.write_str("the key does not have an epoch"),
+        }
+    }
+}
+
+/// A JSON Web Key, with only the members used here
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub epoch: Option<KeyEpoch>,
+}
+
+impl Jwk {
+    /// The JSON of the key, e.g. to put it in a configuration file
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        // serializing a key can not fail
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// A JWK set, the document served at the JWKS endpoint
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+fn encode(bytes: impl AsRef<[u8]>) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, JwkError> {
+    base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_e| JwkError::Base64)
+}
+
+/// A public key that can be exported as a JWK
+///
+/// The `x` of the key is its wire format, without the header.
+pub trait JwkPublicKey: WireFormat + HasKeyId {
+    /// The `crv` of the key
+    const CURVE: &'static str;
+
+    fn to_jwk(&self) -> Jwk {
+        Jwk {
+            kty: KEY_TYPE.to_owned(),
+            crv: Self::CURVE.to_owned(),
+            x: encode(&self.to_bytes()[Self::header().len()..]),
+            kid: Some(encode(self.key_id().to_bytes())),
+            epoch: None,
+        }
+    }
+
+    /// Import a key, this checks `kid` if it is there
+    fn from_jwk(jwk: &Jwk) -> Result<Self, JwkError> {
+        if jwk.kty != KEY_TYPE {
+            return Err(JwkError::KeyType(jwk.kty.clone()));
+        }
+
+        if jwk.crv != Self::CURVE {
+            return Err(JwkError::Curve(jwk.crv.clone()));
+        }
+
+        let mut bytes = Vec::from(Self::header());
+        bytes.extend(decode(&jwk.x)?);
+        let key = Self::from_bytes(&bytes).map_err(|_e| JwkError::Key)?;
+
+        if let Some(kid) = &jwk.kid {
+            let kid: [u8; 8] = decode(kid)?.try_into().map_err(|_e| JwkError::KeyId)?;
+            if KeyId::from_bytes(kid) != key.key_id() {
+                return Err(JwkError::KeyId);
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+impl JwkSet {
+    /// The keys of a key set, with their epochs
+    pub fn from_key_set<K: JwkPublicKey>(keys: &PublicKeySet<K>) -> Self {
+        Self {
+            keys: keys
+                .iter()
+                .map(|(epoch, key)| Jwk {
+                    epoch: Some(epoch),
+                    ..key.to_jwk()
+                })
+                .collect(),
+        }
+    }
+
+    /// Import the keys with the curve of `K` into a key set
+    ///
+    /// The keys of other curves are skipped, such that one JWK set may have the keys of several
+    /// engines.
+    pub fn to_key_set<K: JwkPublicKey>(&self) -> Result<PublicKeySet<K>, JwkError> {
+        let mut keys = PublicKeySet::new();
+
+        for jwk in self.keys.iter().filter(|jwk| jwk.crv == K::CURVE) {
+            let epoch = jwk.epoch.ok_or(JwkError::MissingEpoch)?;
+            keys.insert(epoch, K::from_jwk(jwk)?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Find a key by its identifier
+    pub fn find<K: JwkPublicKey>(&self, key_id: KeyId) -> Option<K> {
+        let kid = encode(key_id.to_bytes());
+
+        self.keys
+            .iter()
+            .filter(|jwk| jwk.crv == K::CURVE && jwk.kid.as_ref() == Some(&kid))
+            .find_map(|jwk| K::from_jwk(jwk).ok())
+    }
+}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    use crate::atpm_pairing::keys as pairing;
+    use crate::nizkp_curve25519::keys as curve25519;
+
+    #[test]
+    fn test_roundtrip() {
+        let key = pairing::PublicKey::from(&pairing::PrivateKey::new());
+        let jwk = key.to_jwk();
+        assert_eq!(jwk.kty, "OKP");
+        assert_eq!(jwk.crv, "BLS12381G2");
+        assert_eq!(decode(&jwk.x).unwrap().len(), 96);
+
+        let json = serde_json::to_string(&jwk).unwrap();
+        let imported = pairing::PublicKey::from_jwk(&serde_json::from_str(&json).unwrap());
+        assert_eq!(imported.map(|key| key.key_id()), Ok(key.key_id()));
+
+        let key = curve25519::PublicKey::from(&curve25519::PrivateKey::new());
+        let jwk = key.to_jwk();
+        assert_eq!(jwk.crv, "ristretto255");
+        assert_eq!(
+            curve25519::PublicKey::from_jwk(&jwk).map(|key| key.key_id()),
+            Ok(key.key_id())
+        );
+    }
+
+    #[test]
+    fn test_mixed_set() {
+        let mut pairing_keys = PublicKeySet::new();
+        pairing_keys.insert(3, pairing::PublicKey::from(&pairing::PrivateKey::new()));
+        let mut curve25519_keys = PublicKeySet::new();
+        let key = curve25519::PublicKey::from(&curve25519::PrivateKey::new());
+        curve25519_keys.insert(7, key.clone());
+
+        let mut jwks = JwkSet::from_key_set(&pairing_keys);
+        jwks.keys
+            .extend(JwkSet::from_key_set(&curve25519_keys).keys);
+
+        let imported = jwks.to_key_set::<curve25519::PublicKey>().unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported.get(7).map(|key| key.key_id()), Some(key.key_id()));
+
+        assert_eq!(
+            jwks.find::<curve25519::PublicKey>(key.key_id())
+                .map(|key| key.key_id()),
+            Some(key.key_id())
+        );
+        assert!(jwks.find::<pairing::PublicKey>(key.key_id()).is_none());
+    }
+
+    #[test]
+    fn fail_bad_keys() {
+        let key = pairing::PublicKey::from(&pairing::PrivateKey::new());
+        let jwk = key.to_jwk();
+
+        assert_eq!(
+            curve25519::PublicKey::from_jwk(&jwk).map(|_| ()),
+            Err(JwkError::Curve("BLS12381G2".to_owned()))
+        );
+
+        let other = pairing::PublicKey::from(&pairing::PrivateKey::new()).to_jwk();
+        let swapped = Jwk {
+            kid: other.kid,
+            ..jwk.clone()
+        };
+        assert_eq!(
+            pairing::PublicKey::from_jwk(&swapped).map(|_| ()),
+            Err(JwkError::KeyId)
+        );
+
+        let truncated = Jwk {
+            x: jwk.x[..jwk.x.len() - 4].to_owned(),
+            ..jwk.clone()
+        };
+        assert_eq!(
+            pairing::PublicKey::from_jwk(&truncated).map(|_| ()),
+            Err(JwkError::Key)
+        );
+
+        let without_epoch = JwkSet { keys: vec![jwk] };
+        assert_eq!(
+            without_epoch
+                .to_key_set::<pairing::PublicKey>()
+                .map(|keys| keys.len()),
+            Err(JwkError::MissingEpoch)
+        );
+    }
+}
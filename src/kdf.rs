@@ -0,0 +1,58 @@
+//! HKDF with SHA-256 (RFC 5869), to derive keys from a seed
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Fill `okm` with the key material of the seed for `info`, without a salt
+///
+/// At most 255 * 32 bytes may be derived. The pseudorandom key and the blocks are zeroized, the key
+/// material only stays in `okm`.
+pub(crate) fn hkdf_sha256(seed: &[u8], info: &[u8], okm: &mut [u8]) {
+    assert!(okm.len() <= 255 * 32, "HKDF derives at most 8160 bytes");
+
+    // extract, the salt is the zero block when there is none
+    let mut extract = HmacSha256::new_from_slice(&[0; 32]).expect("HMAC takes any key length");
+    extract.update(seed);
+    let mut prk = extract.finalize().into_bytes();
+
+    // expand
+    let mut block = [0; 32];
+    for (i, chunk) in okm.chunks_mut(32).enumerate() {
+        let mut expand = HmacSha256::new_from_slice(&prk).expect("HMAC takes any key length");
+        if i > 0 {
+            expand.update(&block);
+        }
+        expand.update(info);
+        expand.update(&[i as u8 + 1]);
+        block.copy_from_slice(&expand.finalize().into_bytes());
+
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+
+    prk[..].zeroize();
+    block.zeroize();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test case 3 of RFC 5869, which has no salt
+    #[test]
+    fn test_rfc5869() {
+        let mut okm = [0; 42];
+        hkdf_sha256(&[0x0b; 22], &[], &mut okm);
+
+        assert_eq!(
+            okm[..],
+            [
+                0x8d, 0xa4, 0xe7, 0x75, 0xa5, 0x63, 0xc1, 0x8f, 0x71, 0x5f, 0x80, 0x2a, 0x06, 0x3c,
+                0x5a, 0x31, 0xb8, 0xa1, 0x1f, 0x5c, 0x5e, 0xe1, 0x87, 0x9e, 0xc3, 0x45, 0x4e, 0x5f,
+                0x3c, 0x73, 0x8d, 0x2d, 0x9d, 0x20, 0x13, 0x95, 0xfa, 0xa4, 0xb6, 0x1a, 0x96, 0xc8,
+            ][..]
+        );
+    }
+}
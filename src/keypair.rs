@@ -0,0 +1,150 @@
+//! # Key pairs of any engine
+//!
+//! The private keys of the engines are made with `PrivateKey::new` and their public keys with
+//! `PublicKey::from`, but the scalar is `to_scalar` of one and `Into<Scalar>` of another. The
+//! [`Keys`] trait is the same for all of them, so the code of a server that makes and keeps its
+//! keys is written once, and a [`KeyPair`] keeps the private key with its public key, such that it
+//! is not computed again for each response.
+//!
+//! ```
+//!     use atpmd::keypair::{KeyPair, Keys};
+//!     use atpmd::atpm_pairing::keys::PrivateKey as PairingKey;
+//!     use atpmd::nizkp_curve25519::keys::PrivateKey as Curve25519Key;
+//!     use atpmd::KeyFingerprint;
+//!
+//!     // the code of the server, for any engine
+//!     fn announce<K: Keys>(keys: &KeyPair<K>) -> KeyFingerprint {
+//!         keys.fingerprint()
+//!     }
+//!
+//!     let mut rng = rand::thread_rng();
+//!     let pairing = KeyPair::<PairingKey>::generate(&mut rng);
+//!     let curve25519 = KeyPair::<Curve25519Key>::generate(&mut rng);
+//!     assert_eq!(announce(&pairing), pairing.public().fingerprint());
+//!     assert_eq!(announce(&curve25519), curve25519.secret().fingerprint());
+//! ```
+
+use rand::{CryptoRng, RngCore};
+
+use crate::KeyFingerprint;
+
+/// The private key of an engine, with its public key and its scalar
+pub trait Keys: Clone {
+    /// The public key of the private key
+    type Public: Clone;
+    /// The scalar of the private key, which is as secret as the key
+    type Scalar;
+
+    /// A new private key from the given rng
+    fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self;
+
+    fn public(&self) -> Self::Public;
+
+    fn to_scalar(&self) -> Self::Scalar;
+
+    /// The fingerprint of the public key
+    fn fingerprint(&self) -> KeyFingerprint;
+}
+
+/// A private key, and its public key
+#[derive(Clone)]
+pub struct KeyPair<K: Keys> {
+    secret: K,
+    public: K::Public,
+}
+
+impl<K: Keys> KeyPair<K> {
+    /// A new key pair from the given rng
+    pub fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::from_secret(K::generate(rng))
+    }
+
+    /// The key pair of a private key, e.g. of a seed
+    pub fn from_secret(secret: K) -> Self {
+        Self {
+            public: secret.public(),
+            secret,
+        }
+    }
+
+    pub fn public(&self) -> &K::Public {
+        &self.public
+    }
+
+    pub fn secret(&self) -> &K {
+        &self.secret
+    }
+
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        self.secret.fingerprint()
+    }
+
+    pub fn into_secret(self) -> K {
+        self.secret
+    }
+}
+
+/// A new key pair from [`default_rng`](crate::rng::default_rng)
+impl<K: Keys> Default for KeyPair<K> {
+    fn default() -> Self {
+        Self::generate(&mut crate::rng::default_rng())
+    }
+}
+
+impl<K: Keys> From<K> for KeyPair<K> {
+    fn from(secret: K) -> Self {
+        Self::from_secret(secret)
+    }
+}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    use crate::atpm_pairing::{keys as pairing, tokens::PairingTokenEngine};
+    use crate::nizkp_curve25519::{keys as curve25519, tokens::NizkpTokenEngine};
+    use crate::TokenEngine;
+
+    /// Sign one token with the keys of any engine, and check the signature with the public key
+    fn sign<E>(keys: &KeyPair<E::SignKey>) -> Result<E::SignedToken, crate::Error>
+    where
+        E: TokenEngine,
+        E::SignKey: Keys<Public = E::UserVerification>,
+        E::UnsignedToken: crate::UnsignedToken<Metadata = Box<[u8]>>,
+    {
+        E::sign(
+            E::generate(Box::from(&b"resource"[..])),
+            keys.public(),
+            |randomized| E::sign_randomized(randomized, keys.secret()),
+        )
+    }
+
+    #[test]
+    fn test_key_pair() {
+        let pairing = KeyPair::<pairing::PrivateKey>::default();
+        assert_eq!(
+            pairing.public().fingerprint(),
+            pairing::PublicKey::from(pairing.secret()).fingerprint()
+        );
+        assert_eq!(pairing.fingerprint(), pairing.secret().fingerprint());
+        assert!(sign::<PairingTokenEngine<_>>(&pairing).is_ok());
+
+        let seeded = KeyPair::from(curve25519::PrivateKey::from_seed(&[7; 32]));
+        assert_eq!(
+            seeded.public().to_affine(),
+            curve25519::PublicKey::from(&curve25519::PrivateKey::from_seed(&[7; 32])).to_affine()
+        );
+        assert_eq!(
+            seeded.secret().to_scalar(),
+            Keys::to_scalar(&seeded.clone().into_secret())
+        );
+        assert!(sign::<NizkpTokenEngine<_>>(&seeded).is_ok());
+
+        let mut rng = crate::rng::default_rng();
+        assert_ne!(
+            KeyPair::<pairing::PrivateKey>::generate(&mut rng).fingerprint(),
+            KeyPair::<pairing::PrivateKey>::generate(&mut rng).fingerprint()
+        );
+    }
+}
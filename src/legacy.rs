@@ -0,0 +1,207 @@
+//! # The `CtOption` and `Option` API of the engines
+//!
+//! [`TokenEngine`] returns an [`Error`](crate::Error) that says why an operation failed, e.g. that
+//! the signer refused, that its proof does not verify, or that the metadata can not be signed with
+//! the key. Before that, the engines returned a `CtOption`, an `Option` or a `bool`, which is kept
+//! here for code that has not moved yet.
+//!
+//! Code that imported `atpmd::TokenEngine` imports [`LegacyTokenEngine`] instead, and keeps
+//! compiling with deprecation warnings until it is moved to [`TokenEngine`]. The two traits have
+//! methods with the same names, so only one of them should be in scope.
+//!
+//! ```
+//!     #![allow(deprecated)]
+//!     use atpmd::legacy::LegacyTokenEngine;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(b"metadata"),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     assert!(PairingTokenEngine::verify(&signed, &public_key));
+//! ```
+
+use subtle::{Choice, CtOption};
+
+use crate::common::{Error, SignedToken, TokenEngine, UnsignedToken};
+
+/// The API of [`TokenEngine`] before it returned [`Error`]
+///
+/// It is implemented for every engine.
+pub trait LegacyTokenEngine: TokenEngine {
+    /// [`TokenEngine::generate`]
+    #[deprecated(since = "0.5.0", note = "use atpmd::TokenEngine")]
+    fn generate(metadata: <Self::UnsignedToken as UnsignedToken>::Metadata) -> Self::UnsignedToken {
+        <Self as TokenEngine>::generate(metadata)
+    }
+
+    /// [`TokenEngine::generate_with_hidden`]
+    #[deprecated(since = "0.5.0", note = "use atpmd::TokenEngine")]
+    fn generate_with_hidden(
+        metadata: <Self::UnsignedToken as UnsignedToken>::Metadata,
+        hidden: <Self::UnsignedToken as UnsignedToken>::HiddenMetadata,
+    ) -> Self::UnsignedToken {
+        <Self as TokenEngine>::generate_with_hidden(metadata, hidden)
+    }
+
+    /// [`TokenEngine::randomize`]
+    #[deprecated(since = "0.5.0", note = "use atpmd::TokenEngine")]
+    fn randomize(
+        unsigned_token: &Self::UnsignedToken,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        <Self as TokenEngine>::randomize(unsigned_token)
+    }
+
+    /// [`TokenEngine::sign_randomized`], none if it fails for any reason
+    #[deprecated(
+        since = "0.5.0",
+        note = "use atpmd::TokenEngine::sign_randomized, which says why it failed"
+    )]
+    fn sign_randomized(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+    ) -> CtOption<Self::RandomizedSignedToken>
+    where
+        Self::RandomizedSignedToken: Default,
+    {
+        match <Self as TokenEngine>::sign_randomized(randomized_unsigned, sign_key) {
+            Ok(signed) => CtOption::new(signed, Choice::from(1)),
+            Err(_e) => CtOption::new(Default::default(), Choice::from(0)),
+        }
+    }
+
+    /// [`TokenEngine::verify_signature_and_unrandomize`], none if it fails for any reason
+    #[deprecated(
+        since = "0.5.0",
+        note = "use atpmd::TokenEngine::verify_signature_and_unrandomize, which says why it failed"
+    )]
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Option<Self::SignedToken> {
+        <Self as TokenEngine>::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized_unsigned,
+            signed_token,
+            verification_data,
+            randomization,
+        )
+        .ok()
+    }
+
+    /// [`TokenEngine::sign`], none if the signer did not sign or the signature does not verify
+    #[deprecated(
+        since = "0.5.0",
+        note = "use atpmd::TokenEngine::sign, which says why it failed"
+    )]
+    fn sign<F>(
+        unsigned_token: Self::UnsignedToken,
+        verification_data: &Self::UserVerification,
+        sign_func: F,
+    ) -> Option<Self::SignedToken>
+    where
+        F: Fn(&Self::RandomizedUnsignedToken) -> CtOption<Self::RandomizedSignedToken>,
+    {
+        <Self as TokenEngine>::sign(unsigned_token, verification_data, |randomized_unsigned| {
+            Option::from(sign_func(randomized_unsigned)).ok_or(Error::NotSigned)
+        })
+        .ok()
+    }
+
+    /// [`TokenEngine::verify`], whether the token is signed with the key
+    #[deprecated(
+        since = "0.5.0",
+        note = "use atpmd::TokenEngine::verify, which says why it failed"
+    )]
+    fn verify(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+    ) -> bool {
+        <Self as TokenEngine>::verify(token, verification_key).is_ok()
+    }
+}
+
+impl<E: TokenEngine> LegacyTokenEngine for E {}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+#[allow(deprecated)]
+mod tests {
+    use super::*;
+    use crate::atpm_pairing::{
+        keys::{PrivateKey, PublicKey},
+        tokens::PairingTokenEngine,
+    };
+    use crate::nizkp_curve25519::{
+        keys::{PrivateKey as NizkpPrivateKey, PublicKey as NizkpPublicKey},
+        tokens::NizkpTokenEngine,
+    };
+
+    type Engine = PairingTokenEngine<&'static [u8]>;
+
+    #[test]
+    fn test_legacy_api() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let unsigned = <Engine as LegacyTokenEngine>::generate(b"metadata");
+        let (r, randomized) = <Engine as LegacyTokenEngine>::randomize(&unsigned);
+
+        let signed = <Engine as LegacyTokenEngine>::sign_randomized(&randomized, &private_key);
+        assert!(bool::from(signed.is_some()));
+
+        let signed = <Engine as LegacyTokenEngine>::verify_signature_and_unrandomize(
+            unsigned,
+            randomized,
+            signed.unwrap(),
+            &public_key,
+            r,
+        )
+        .unwrap();
+        assert!(<Engine as LegacyTokenEngine>::verify(&signed, &public_key));
+        assert!(!<Engine as LegacyTokenEngine>::verify(
+            &signed,
+            &PublicKey::from(&PrivateKey::new())
+        ));
+
+        // a signer that refuses
+        assert!(<Engine as LegacyTokenEngine>::sign(
+            <Engine as LegacyTokenEngine>::generate(b"metadata"),
+            &public_key,
+            |_randomized| CtOption::new(Default::default(), Choice::from(0))
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_legacy_unrandomize_fails() {
+        let private_key = NizkpPrivateKey::new();
+        let other = NizkpPrivateKey::new();
+
+        let unsigned = <NizkpTokenEngine<_> as LegacyTokenEngine>::generate(&b"metadata"[..]);
+        let (r, randomized) = <NizkpTokenEngine<_> as LegacyTokenEngine>::randomize(&unsigned);
+        let signed =
+            <NizkpTokenEngine<_> as TokenEngine>::sign_randomized(&randomized, &other).unwrap();
+
+        assert!(
+            <NizkpTokenEngine<_> as LegacyTokenEngine>::verify_signature_and_unrandomize(
+                unsigned,
+                randomized,
+                signed,
+                &NizkpPublicKey::from(&private_key),
+                r,
+            )
+            .is_none()
+        );
+    }
+}
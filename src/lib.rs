@@ -35,7 +35,7 @@
 //!     ).unwrap();
 //!
 //!     // The verifier may verify that the token is signed
-//!     let is_properly_signed = PairingTokenEngine::verify(&signed, &public_key);
+//!     let is_properly_signed = PairingTokenEngine::verify(&signed, &public_key).is_ok();
 //!     assert!(is_properly_signed);
 //! ```
 //!
@@ -74,22 +74,33 @@
 //!     ).unwrap();
 //!
 //!     // The verifier may verify that the token is signed
-//!     let is_properly_signed = NizkpTokenEngine::verify(&signed, &secret_key);
+//!     let is_properly_signed = NizkpTokenEngine::verify(&signed, &secret_key).is_ok();
 //!     assert!(is_properly_signed);
 //! ```
 
 #![no_std]
 
+#[cfg(feature = "pairings")]
 extern crate bls12_381;
+#[cfg(feature = "pairings")]
 extern crate pairing;
 extern crate rand;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 #[macro_use]
 extern crate serde;
 extern crate alloc;
+extern crate base64;
 extern crate core;
+#[cfg(feature = "std")]
+extern crate std;
+#[cfg(feature = "json")]
 extern crate serde_json;
 extern crate sha2;
 extern crate subtle;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+extern crate zeroize;
 
 #[cfg(feature = "nizkp")]
 pub mod atpm_nizkp;
@@ -102,4 +113,106 @@ pub mod nizkp_curve25519;
 
 pub(crate) mod common;
 
-pub use common::{RandomizedUnsignedToken, SignedToken, TokenEngine, UnsignedToken};
+pub(crate) mod group;
+
+#[cfg(any(feature = "curve25519", feature = "nizkp"))]
+pub(crate) mod transcript;
+
+pub(crate) mod kdf;
+
+pub mod array;
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+
+pub mod buckets;
+
+pub mod challenge;
+
+pub mod chunked;
+
+pub mod commitment;
+
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+
+pub mod directory;
+
+pub mod discovery;
+
+pub mod expiry;
+
+pub mod guard;
+
+pub mod hash_suite;
+
+pub mod http;
+
+pub mod hybrid;
+
+pub mod interop;
+
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+pub mod issuance;
+
+pub mod jwk;
+
+pub mod keypair;
+
+pub mod legacy;
+
+pub mod metadata;
+
+pub mod migration;
+
+#[cfg(test)]
+mod no_panic;
+
+pub mod pem;
+
+pub mod policy;
+
+pub mod presentation;
+
+pub mod presets;
+
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+pub mod profile;
+
+pub mod receipts;
+
+pub mod redemption;
+
+pub mod refusal;
+
+pub mod rng;
+
+pub mod roles;
+
+pub mod session;
+
+pub mod stats;
+
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+pub mod trust;
+
+pub mod unlinkability;
+
+pub mod verifier;
+
+pub mod wallet;
+
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub mod wire;
+
+pub use common::{
+    BatchResponseError, Error, HasKeyId, KeyEpoch, KeyFingerprint, KeyId, KeyRing, PublicKeySet,
+    RandomizedSignedToken, RandomizedUnsignedToken, SignedToken, TokenEngine, UnsignedToken,
+    VerifyError,
+};
+
+pub use roles::{TokenClient, TokenSigner, TokenVerifier};
+
+pub use zeroize::Zeroize;
@@ -0,0 +1,335 @@
+//! # Typed public metadata
+//!
+//! The engines sign the metadata as bytes, so the signer and the verifier must agree on the bytes
+//! of structured metadata, e.g. a resource, a scope and an expiration time.
+//! A [`MetadataCodec`] is a canonical encoding of a type, and [`Encoded`] is the metadata of a
+//! token with a typed value, so the metadata is hashed the same everywhere.
+//!
+//! The encoding uses the fields of the [wire format](crate::wire): integers are big endian, and
+//! strings and bytes are prefixed with their length.
+//! A type that starts with its expiration time as a `u64` works with [`expiry`](crate::expiry).
+//!
+//! ```
+//!     use atpmd::{SignedToken, TokenEngine};
+//!     use atpmd::metadata::{Encoded, MetadataCodec};
+//!     use atpmd::wire::{Reader, WireError, Writer};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     #[derive(Debug, PartialEq)]
+//!     struct Access {
+//!         expires_at: u64,
+//!         resource: String,
+//!         scope: u8,
+//!     }
+//!
+//!     impl MetadataCodec for Access {
+//!         fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+//!             (self.expires_at, self.resource.clone(), self.scope).encode(writer)
+//!         }
+//!
+//!         fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+//!             let (expires_at, resource, scope) = MetadataCodec::decode(reader)?;
+//!             Ok(Self { expires_at, resource, scope })
+//!         }
+//!     }
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let access = Access { expires_at: 1_600_000_000, resource: "/api".into(), scope: 2 };
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Encoded::new(&access).unwrap()),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     assert!(signed.verify_with_time(&public_key, 1_500_000_000));
+//!     assert_eq!(signed.metadata().value(), Ok(access));
+//! ```
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{fmt, marker::PhantomData};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::wire::{Reader, WireError, Writer};
+
+/// A canonical encoding of public metadata
+///
+/// Equal values must have equal encodings, and decoding must accept only the encoding of a value.
+/// [`Encoded::value`] checks the latter by encoding the decoded value again.
+pub trait MetadataCodec: Sized {
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError>;
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError>;
+}
+
+// {{{ Encoded
+
+/// The metadata of a token, as the canonical encoding of a `T`
+///
+/// The bytes are decoded when the value is needed, so metadata decoded from a token may not be a
+/// valid `T`.
+pub struct Encoded<T> {
+    bytes: Box<[u8]>,
+    _t: PhantomData<T>,
+}
+
+impl<T: MetadataCodec> Encoded<T> {
+    /// Encode a value, this fails if a field is too long
+    pub fn new(value: &T) -> Result<Self, WireError> {
+        encode(value).map(|bytes| Self {
+            bytes: bytes.into_boxed_slice(),
+            _t: PhantomData,
+        })
+    }
+
+    /// Decode the value, and check that the bytes are its canonical encoding
+    pub fn value(&self) -> Result<T, WireError> {
+        let mut reader = Reader::new(&self.bytes);
+        let value = T::decode(&mut reader)?;
+
+        if !reader.is_empty() {
+            return Err(WireError::TrailingBytes);
+        }
+
+        if encode(&value)? != *self.bytes {
+            return Err(WireError::NonCanonical);
+        }
+
+        Ok(value)
+    }
+}
+
+fn encode<T: MetadataCodec>(value: &T) -> Result<Vec<u8>, WireError> {
+    let mut writer = Writer::new();
+    value.encode(&mut writer)?;
+    Ok(writer.into_bytes())
+}
+
+impl<T> AsRef<[u8]> for Encoded<T> {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<T> From<&[u8]> for Encoded<T> {
+    fn from(bytes: &[u8]) -> Self {
+        Self {
+            bytes: Box::from(bytes),
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Encoded<T> {
+    fn clone(&self) -> Self {
+        Self::from(self.as_ref())
+    }
+}
+
+impl<T> PartialEq for Encoded<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl<T> Eq for Encoded<T> {}
+
+impl<T> fmt::Debug for Encoded<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Encoded").field(&self.bytes).finish()
+    }
+}
+
+impl<T> Serialize for Encoded<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.bytes.serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Encoded<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Box::<[u8]>::deserialize(deserializer).map(|bytes| Self {
+            bytes,
+            _t: PhantomData,
+        })
+    }
+}
+
+// }}}
+
+// {{{ Codecs
+
+macro_rules! int_codec {
+    ($($int:ty),*) => {
+        $(
+            impl MetadataCodec for $int {
+                fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+                    writer.fixed(self.to_be_bytes());
+                    Ok(())
+                }
+
+                fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+                    Ok(Self::from_be_bytes(reader.fixed()?))
+                }
+            }
+        )*
+    };
+}
+
+int_codec!(u8, u16, u32, u64);
+
+impl MetadataCodec for bool {
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed([*self as u8]);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        match reader.fixed::<1>()? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(WireError::Tag),
+        }
+    }
+}
+
+impl MetadataCodec for Box<[u8]> {
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.prefixed(self)
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        reader.prefixed().map(Box::from)
+    }
+}
+
+impl MetadataCodec for String {
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.prefixed(self)
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        core::str::from_utf8(reader.prefixed()?)
+            .map(String::from)
+            .map_err(|_e| WireError::NonCanonical)
+    }
+}
+
+impl<T: MetadataCodec> MetadataCodec for Option<T> {
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        match self {
+            None => writer.fixed([0]),
+            Some(value) => {
+                writer.fixed([1]);
+                value.encode(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        match reader.fixed::<1>()? {
+            [0] => Ok(None),
+            [1] => T::decode(reader).map(Some),
+            _ => Err(WireError::Tag),
+        }
+    }
+}
+
+macro_rules! tuple_codec {
+    ($($name:ident),*) => {
+        impl<$($name: MetadataCodec),*> MetadataCodec for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+                let ($($name,)*) = self;
+                $($name.encode(writer)?;)*
+                Ok(())
+            }
+
+            fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+                Ok(($($name::decode(reader)?,)*))
+            }
+        }
+    };
+}
+
+tuple_codec!(A, B);
+tuple_codec!(A, B, C);
+tuple_codec!(A, B, C, D);
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Scope = (u64, String, Option<u8>);
+
+    #[test]
+    fn test_roundtrip() {
+        let scope: Scope = (100, String::from("resource"), Some(3));
+        let encoded = Encoded::new(&scope).unwrap();
+
+        assert_eq!(
+            encoded.as_ref(),
+            b"\0\0\0\0\0\0\0\x64\0\x08resource\x01\x03"
+        );
+        assert_eq!(encoded.value(), Ok(scope));
+        assert_eq!(crate::expiry::expires_at(encoded.as_ref()), Some(100));
+
+        let json = serde_json::to_string(&encoded).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Encoded<Scope>>(&json).unwrap(),
+            encoded
+        );
+    }
+
+    #[test]
+    fn fail_not_canonical() {
+        let decode = |bytes: &[u8]| Encoded::<Scope>::from(bytes).value();
+
+        assert_eq!(
+            decode(b"\0\0\0\0\0\0\0\x64\0\x08resource\x02"),
+            Err(WireError::Tag)
+        );
+        assert_eq!(
+            decode(b"\0\0\0\0\0\0\0\x64\0\x08resource\x00\x00"),
+            Err(WireError::TrailingBytes)
+        );
+        assert_eq!(
+            decode(b"\0\0\0\0\0\0\0\x64\0\x02\xff\xfe\x00"),
+            Err(WireError::NonCanonical)
+        );
+        assert_eq!(decode(b"\0\0\0"), Err(WireError::Truncated));
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn test_token() {
+        use crate::nizkp_curve25519::{
+            keys::{PrivateKey, PublicKey},
+            tokens::{NizkpSignedToken, NizkpTokenEngine},
+        };
+        use crate::wire::WireFormat;
+        use crate::TokenEngine;
+
+        let private = PrivateKey::new();
+        let scope: Scope = (100, String::from("resource"), None);
+
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(Encoded::new(&scope).unwrap()),
+            &PublicKey::from(&private),
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+
+        let decoded = NizkpSignedToken::<Encoded<Scope>>::from_bytes(&signed.to_bytes()).unwrap();
+        assert!(NizkpTokenEngine::verify(&decoded, &private).is_ok());
+        assert_eq!(decoded.metadata().value(), Ok(scope));
+    }
+}
@@ -0,0 +1,228 @@
+//! # Key migration
+//!
+//! When the signer rotates its key, users may still hold a stock of tokens signed under the old
+//! key.
+//! Instead of making them authenticate again, the signer may exchange each old token for a new
+//! one: the user redeems a valid old token together with a fresh randomized request, and gets the
+//! request signed under the new key.
+//! The old token is marked as used, so it can not be exchanged or redeemed twice.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::migration::{KeyMigrator, RateLimit};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     type Engine = PairingTokenEngine<&'static [u8]>;
+//!
+//!     let old_key = PrivateKey::new();
+//!     let old_public = PublicKey::from(&old_key);
+//!     let new_key = PrivateKey::new();
+//!     let new_public = PublicKey::from(&new_key);
+//!
+//!     // a token the user got before the rotation
+//!     let old_token = Engine::sign(Engine::generate(b"resource"), &old_public, |r| {
+//!         Engine::sign_randomized(r, &old_key)
+//!     })
+//!     .unwrap();
+//!
+//!     let mut migrator = KeyMigrator::<Engine>::new(old_public, new_key, RateLimit::new(10, 60));
+//!
+//!     // the user sends the old token along with a new request
+//!     let unsigned = Engine::generate(b"resource");
+//!     let (randomization, request) = Engine::randomize(&unsigned);
+//!     let response = migrator.migrate(0, old_token, &request).unwrap();
+//!
+//!     let new_token = Engine::verify_signature_and_unrandomize(
+//!         unsigned,
+//!         request,
+//!         response,
+//!         &new_public,
+//!         randomization,
+//!     )
+//!     .unwrap();
+//!     assert!(Engine::verify(&new_token, &new_public).is_ok());
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::common::{KeyEpoch, RandomizedSignedToken, SignedToken, TokenEngine};
+
+/// At most `max` migrations per `window` time units.
+///
+/// The unit of time is up to the caller, see [`KeyMigrator::migrate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max: u32,
+    pub window: u64,
+}
+
+impl RateLimit {
+    pub fn new(max: u32, window: u64) -> Self {
+        Self { max, window }
+    }
+}
+
+/// The reason a token could not be migrated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationError {
+    /// Too many migrations in the current window
+    RateLimited,
+    /// The old token is not signed with the old key
+    InvalidToken,
+    /// The old token has already been migrated
+    AlreadyMigrated,
+    /// The new key could not sign the request
+    Signing,
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited => f.write_str("too many migrations, try again later"),
+            Self::InvalidToken => f.write_str("the token is not signed with the old key"),
+            Self::AlreadyMigrated => f.write_str("the token has already been migrated"),
+            Self::Signing => f.write_str("the request could not be signed"),
+        }
+    }
+}
+
+/// Exchanges tokens signed with an old key for tokens signed with a new key
+pub struct KeyMigrator<E: TokenEngine> {
+    old_key: <E::SignedToken as SignedToken>::VerificationKey,
+    new_key: E::SignKey,
+    new_epoch: Option<KeyEpoch>,
+    limit: RateLimit,
+    window_start: u64,
+    in_window: u32,
+    migrated: Vec<E::SignedToken>,
+}
+
+impl<E: TokenEngine> KeyMigrator<E>
+where
+    E::SignedToken: PartialEq,
+{
+    pub fn new(
+        old_key: <E::SignedToken as SignedToken>::VerificationKey,
+        new_key: E::SignKey,
+        limit: RateLimit,
+    ) -> Self {
+        Self {
+            old_key,
+            new_key,
+            new_epoch: None,
+            limit,
+            window_start: 0,
+            in_window: 0,
+            migrated: Vec::new(),
+        }
+    }
+
+    /// Tag the new tokens with the epoch of the new key
+    pub fn with_key_epoch(mut self, epoch: KeyEpoch) -> Self {
+        self.new_epoch = Some(epoch);
+        self
+    }
+
+    /// Exchange a token signed with the old key for a signature on `request` with the new key
+    ///
+    /// `now` is the current time, in the same unit as the window of the rate limit.
+    pub fn migrate(
+        &mut self,
+        now: u64,
+        old_token: E::SignedToken,
+        request: &E::RandomizedUnsignedToken,
+    ) -> Result<E::RandomizedSignedToken, MigrationError> {
+        if now.saturating_sub(self.window_start) >= self.limit.window {
+            self.window_start = now;
+            self.in_window = 0;
+        }
+        if self.in_window >= self.limit.max {
+            return Err(MigrationError::RateLimited);
+        }
+
+        if E::verify(&old_token, &self.old_key).is_err() {
+            return Err(MigrationError::InvalidToken);
+        }
+        if self.migrated.contains(&old_token) {
+            return Err(MigrationError::AlreadyMigrated);
+        }
+
+        let signed =
+            E::sign_randomized(request, &self.new_key).map_err(|_e| MigrationError::Signing)?;
+
+        self.in_window += 1;
+        self.migrated.push(old_token);
+
+        Ok(match self.new_epoch {
+            Some(epoch) => signed.with_key_epoch(epoch),
+            None => signed,
+        })
+    }
+
+    /// Tokens that have been migrated, and must not be redeemed under the old key anymore
+    pub fn migrated(&self) -> &[E::SignedToken] {
+        &self.migrated
+    }
+}
+
+#[cfg(all(test, feature = "pairing"))]
+mod tests {
+    use super::*;
+    use crate::atpm_pairing::{
+        keys::{PrivateKey, PublicKey},
+        tokens::PairingTokenEngine,
+    };
+
+    type Engine = PairingTokenEngine<&'static [u8]>;
+
+    fn token(key: &PrivateKey) -> <Engine as TokenEngine>::SignedToken {
+        Engine::sign(Engine::generate(b"resource"), &PublicKey::from(key), |r| {
+            Engine::sign_randomized(r, key)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_migration() {
+        let old_key = PrivateKey::new();
+        let new_key = PrivateKey::new();
+        let mut migrator = KeyMigrator::<Engine>::new(
+            PublicKey::from(&old_key),
+            new_key.clone(),
+            RateLimit::new(2, 10),
+        )
+        .with_key_epoch(2);
+
+        let (_, request) = Engine::randomize(&Engine::generate(b"resource"));
+
+        let old_token = token(&old_key);
+        let response = migrator.migrate(0, old_token, &request).unwrap();
+        assert_eq!(response.key_epoch(), Some(2));
+
+        // the old token may only be migrated once
+        let old_token = migrator.migrated()[0].clone();
+        assert_eq!(
+            migrator.migrate(1, old_token, &request).err(),
+            Some(MigrationError::AlreadyMigrated)
+        );
+
+        // only tokens from the old key
+        assert_eq!(
+            migrator.migrate(2, token(&new_key), &request).err(),
+            Some(MigrationError::InvalidToken)
+        );
+
+        assert!(migrator.migrate(3, token(&old_key), &request).is_ok());
+        assert_eq!(
+            migrator.migrate(4, token(&old_key), &request).err(),
+            Some(MigrationError::RateLimited)
+        );
+
+        // a new window
+        assert!(migrator.migrate(10, token(&old_key), &request).is_ok());
+    }
+}
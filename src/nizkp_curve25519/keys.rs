@@ -8,28 +8,89 @@
 //!     let public_key = PublicKey::from(&private_key);
 //! ```
 
+use alloc::string::String;
+use core::{fmt, str::FromStr};
+
 use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
-use curve25519_dalek::ristretto::RistrettoPoint;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
 use curve25519_dalek::scalar::Scalar;
+use rand::{CryptoRng, RngCore};
+#[cfg(feature = "private_key_serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+use super::util::h_derive;
+#[cfg(feature = "private_key_serde")]
+use super::PrivateKeyBytes;
+use super::{HasKeyId, KeyId, Secret};
+use crate::kdf::hkdf_sha256;
+use crate::pem::{self, PemError};
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
+use crate::KeyFingerprint;
 
 #[derive(Debug, Clone)]
 /// The private key for the nizkp protocol
+///
+/// The key is zeroized when it is dropped
 pub struct PrivateKey {
-    scalar: Scalar,
+    scalar: Secret<Scalar>,
 }
 
 impl PrivateKey {
     pub fn to_scalar(&self) -> Scalar {
-        self.scalar
+        self.scalar.0
+    }
+
+    /// A key with a chosen scalar, e.g. of [`derive_key_pair`](super::rfc9497::derive_key_pair), or
+    /// to test the edge cases of signing
+    pub(crate) fn from_scalar(scalar: Scalar) -> Self {
+        Self {
+            scalar: Secret(scalar),
+        }
     }
 }
 
 impl PrivateKey {
     pub fn new() -> Self {
+        Self::new_with_rng(&mut crate::rng::default_rng())
+    }
+
+    /// A private key from the given rng, e.g. for reproducible tests
+    pub fn new_with_rng<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self {
+            scalar: Secret(Scalar::random(rng)),
+        }
+    }
+
+    /// The private key of a seed, e.g. from a secrets manager, to derive the same key again
+    ///
+    /// The scalar is the HKDF-SHA256 of the seed, reduced from 64 bytes.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let mut okm = [0; 64];
+        hkdf_sha256(seed, b"atpmd curve25519 private key", &mut okm);
+        let scalar = Scalar::from_bytes_mod_order_wide(&okm);
+        okm.zeroize();
+
+        Self {
+            scalar: Secret(scalar),
+        }
+    }
+
+    /// The key for a label, e.g. an origin, derived from this master key
+    ///
+    /// The verifier of the label only needs the derived key, see [`PublicKey::derive`] for the
+    /// public key.
+    pub fn derive(&self, label: impl AsRef<[u8]>) -> Self {
+        let tweak = h_derive(PublicKey::from(self).point.compress().as_bytes(), label);
         Self {
-            scalar: Scalar::random(&mut rand::thread_rng()),
+            scalar: Secret(self.scalar.0 + tweak),
         }
     }
+
+    /// The [`PublicKey::fingerprint`] of the public key
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        PublicKey::from(self).fingerprint()
+    }
 }
 
 impl Default for PrivateKey {
@@ -38,8 +99,50 @@ impl Default for PrivateKey {
     }
 }
 
+impl Zeroize for PrivateKey {
+    fn zeroize(&mut self) {
+        self.scalar.zeroize();
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl Serialize for PrivateKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PrivateKeyBytes {
+            key: self.scalar.0.to_bytes().to_vec(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl<'de> Deserialize<'de> for PrivateKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = PrivateKeyBytes::deserialize(deserializer)?;
+        let mut scalar_bytes = [0u8; 32];
+        scalar_bytes.copy_from_slice(bytes.bytes(32)?);
+
+        let scalar = Scalar::from_canonical_bytes(scalar_bytes);
+        scalar_bytes.zeroize();
+
+        scalar
+            .map(|scalar| PrivateKey {
+                scalar: Secret(scalar),
+            })
+            .ok_or_else(|| de::Error::custom("Scalar is not canonical"))
+    }
+}
+
 /// The public key for the nizkp protocol
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PublicKey {
+    #[serde(with = "super::util::point")]
     point: RistrettoPoint,
 }
 
@@ -47,8 +150,170 @@ impl PublicKey {
     pub fn to_affine(&self) -> RistrettoPoint {
         self.point
     }
+
+    /// The public key of [`PrivateKey::derive`] with the same label
+    pub fn derive(&self, label: impl AsRef<[u8]>) -> Self {
+        let tweak = h_derive(self.point.compress().as_bytes(), label);
+        Self {
+            point: self.point + &tweak * &RISTRETTO_BASEPOINT_TABLE,
+        }
+    }
+
+    /// The SHA-256 of the wire encoding of the key
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        KeyFingerprint::of_encoding(self.to_bytes())
+    }
 }
 
+/// A point of arbitrary uniform bytes, so it is always in the group
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for PublicKey {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            point: RistrettoPoint::from_uniform_bytes(&u.arbitrary()?),
+        })
+    }
+}
+
+impl HasKeyId for PublicKey {
+    fn key_id(&self) -> KeyId {
+        KeyId::of_public_key(self.point.compress().as_bytes())
+    }
+}
+
+impl HasKeyId for PrivateKey {
+    /// The identifier of the public key, so the verifier may look up its private key
+    fn key_id(&self) -> KeyId {
+        PublicKey::from(self).key_id()
+    }
+}
+
+impl crate::keypair::Keys for PrivateKey {
+    type Public = PublicKey;
+    type Scalar = Scalar;
+
+    fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        Self::new_with_rng(rng)
+    }
+
+    fn public(&self) -> PublicKey {
+        PublicKey::from(self)
+    }
+
+    fn to_scalar(&self) -> Scalar {
+        PrivateKey::to_scalar(self)
+    }
+
+    fn fingerprint(&self) -> KeyFingerprint {
+        PrivateKey::fingerprint(self)
+    }
+}
+
+impl WireFormat for PublicKey {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x14;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.point.compress().as_bytes());
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        CompressedRistretto(reader.fixed()?)
+            .decompress()
+            .map(|point| PublicKey { point })
+            .ok_or(WireError::InvalidPoint)
+    }
+}
+
+impl crate::jwk::JwkPublicKey for PublicKey {
+    const CURVE: &'static str = "ristretto255";
+}
+
+// {{{ PEM and base64url
+
+impl PublicKey {
+    /// The label of the PEM of the key
+    pub const PEM_LABEL: &'static str = "ATPMD CURVE25519 PUBLIC KEY";
+
+    /// The wire format of the key as PEM, e.g. for a configuration file
+    pub fn to_pem(&self) -> String {
+        pem::encode(Self::PEM_LABEL, &self.to_bytes())
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, PemError> {
+        Self::from_bytes(&pem::decode(Self::PEM_LABEL, pem)?).map_err(PemError::Malformed)
+    }
+}
+
+/// The wire format of the key as base64url, e.g. for an HTTP header
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&pem::encode_url(&self.to_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = PemError;
+
+    fn from_str(encoded: &str) -> Result<Self, PemError> {
+        Self::from_bytes(&pem::decode_url(encoded)?).map_err(PemError::Malformed)
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl PrivateKey {
+    /// The label of the PEM of the key
+    pub const PEM_LABEL: &'static str = "ATPMD CURVE25519 PRIVATE KEY";
+
+    /// The scalar of the key as PEM, the caller has to keep it secret
+    pub fn to_pem(&self) -> String {
+        let mut bytes = self.scalar.0.to_bytes();
+        let pem = pem::encode(Self::PEM_LABEL, &bytes);
+        bytes.zeroize();
+        pem
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, PemError> {
+        Self::from_scalar_bytes(pem::scalar_bytes(pem::decode(Self::PEM_LABEL, pem)?)?)
+    }
+
+    fn from_scalar_bytes(mut bytes: [u8; 32]) -> Result<Self, PemError> {
+        let scalar = Scalar::from_canonical_bytes(bytes);
+        bytes.zeroize();
+
+        scalar
+            .map(|scalar| PrivateKey {
+                scalar: Secret(scalar),
+            })
+            .ok_or(PemError::Malformed(WireError::InvalidPoint))
+    }
+}
+
+/// The scalar of the key as base64url, which is as secret as the key, so it is not to be logged
+#[cfg(feature = "private_key_serde")]
+impl fmt::Display for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = self.scalar.0.to_bytes();
+        let mut encoded = pem::encode_url(&bytes);
+        let result = f.write_str(&encoded);
+        bytes.zeroize();
+        encoded.zeroize();
+        result
+    }
+}
+
+#[cfg(feature = "private_key_serde")]
+impl FromStr for PrivateKey {
+    type Err = PemError;
+
+    fn from_str(encoded: &str) -> Result<Self, PemError> {
+        Self::from_scalar_bytes(pem::scalar_bytes(pem::decode_url(encoded)?)?)
+    }
+}
+
+// }}}
+
 impl From<&PrivateKey> for PublicKey {
     fn from(key: &PrivateKey) -> Self {
         Self {
@@ -62,3 +327,122 @@ impl From<PrivateKey> for PublicKey {
         Self::from(&key)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_id() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        assert_eq!(private_key.key_id(), public_key.key_id());
+        assert_ne!(PrivateKey::new().key_id(), public_key.key_id());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        use sha2::{Digest, Sha256};
+
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        assert_eq!(private_key.fingerprint(), public_key.fingerprint());
+        assert_eq!(
+            public_key.fingerprint().to_bytes(),
+            <[u8; 32]>::from(Sha256::digest(&public_key.to_bytes()))
+        );
+        assert_ne!(PrivateKey::new().fingerprint(), public_key.fingerprint());
+    }
+
+    #[test]
+    fn test_pem() {
+        use alloc::string::ToString;
+
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let pem = public_key.to_pem();
+        assert_eq!(
+            PublicKey::from_pem(&pem).unwrap().fingerprint(),
+            public_key.fingerprint()
+        );
+        let parsed: PublicKey = public_key.to_string().parse().unwrap();
+        assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+
+        assert_eq!(
+            PublicKey::from_pem(&pem.replace("PUBLIC", "PRIVATE")).err(),
+            Some(PemError::Label)
+        );
+        assert_eq!(
+            "AAAA".parse::<PublicKey>().err(),
+            Some(PemError::Malformed(WireError::Ciphersuite))
+        );
+
+        #[cfg(feature = "private_key_serde")]
+        {
+            let parsed = PrivateKey::from_pem(&private_key.to_pem()).unwrap();
+            assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+            let parsed: PrivateKey = private_key.to_string().parse().unwrap();
+            assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+
+            assert_eq!(
+                PrivateKey::from_str(&pem::encode_url(&[0xff; 32])).err(),
+                Some(PemError::Malformed(WireError::InvalidPoint))
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_seed() {
+        let seed = [7; 32];
+
+        // the same seed is the same key
+        assert_eq!(
+            PrivateKey::from_seed(&seed).fingerprint(),
+            PrivateKey::from_seed(&seed).fingerprint()
+        );
+        assert_ne!(
+            PrivateKey::from_seed(&seed).fingerprint(),
+            PrivateKey::from_seed(&[8; 32]).fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_derive() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+
+        let derived = PublicKey::from(private_key.derive(b"example.com"));
+        assert_eq!(derived.key_id(), public_key.derive(b"example.com").key_id());
+        assert_ne!(derived.key_id(), public_key.key_id());
+        assert_ne!(derived.key_id(), public_key.derive(b"example.org").key_id());
+    }
+
+    #[test]
+    fn test_zeroize() {
+        let mut private_key = PrivateKey::new();
+        private_key.zeroize();
+
+        assert_eq!(private_key.to_scalar(), Scalar::zero());
+    }
+
+    #[cfg(feature = "private_key_serde")]
+    #[test]
+    fn test_private_key_serde() {
+        let private_key = PrivateKey::new();
+
+        let serialized = serde_json::to_string(&private_key).unwrap();
+        let deserialized: PrivateKey = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.to_scalar(), private_key.to_scalar());
+
+        // not canonical
+        let deserialized: Result<PrivateKey, _> =
+            serde_json::from_str(&alloc::format!("{{\"key\":{:?}}}", [255u8; 32]));
+        assert!(deserialized.is_err());
+
+        let deserialized: Result<PrivateKey, _> = serde_json::from_str(r#"{"key":[1,2,3]}"#);
+        assert!(deserialized.is_err());
+    }
+}
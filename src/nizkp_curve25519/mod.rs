@@ -33,13 +33,22 @@
 //!     ).unwrap();
 //!
 //!     // The verifier may verify that the token is signed
-//!     let is_properly_signed = NizkpTokenEngine::verify(&signed, &secret_key);
+//!     let is_properly_signed = NizkpTokenEngine::verify(&signed, &secret_key).is_ok();
 //!     assert!(is_properly_signed);
 //! ```
 
 pub (crate) use super::common::*;
 
+/// The group and the hash of the engine, see [`trust`](crate::trust)
+pub const CIPHERSUITE: &str = "ristretto255-SHA512";
+
 mod util;
 pub mod tokens;
 pub mod keys;
+pub mod operators;
+pub mod oprf;
+pub mod ratelimit;
+pub mod refusal;
+pub mod rfc9497;
 pub mod tokens_batched;
+pub mod tokens_batched_dyn;
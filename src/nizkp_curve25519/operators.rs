@@ -0,0 +1,403 @@
+//! # Responses by several operators
+//!
+//! Several operators may run the sign endpoint of one issuer, with the private key of the issuer.
+//! To tell which operator made a response, e.g. to find the operator that signs for abusers, each
+//! operator also gets an operator key, with a certificate made with the private key of the issuer.
+//! The operator tags its responses with the operator key, and the user checks that the
+//! certificate is for the advertised public key of the issuer, and that the tag is for the
+//! response.
+//!
+//! The tag covers the randomized token and the response, which are never shown to the verifier,
+//! so a tag does not link a token to the user.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         operators::OperatorKey,
+//!         tokens::NizkpTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let operator = OperatorKey::issue(7, &secret_key);
+//!
+//!     let token = NizkpTokenEngine::generate(&b"metadata"[..]);
+//!     let (r, randomized) = NizkpTokenEngine::randomize(&token);
+//!
+//!     // The operator signs and tags the response
+//!     let (response, tag) =
+//!         NizkpTokenEngine::sign_randomized_by_operator(&randomized, &secret_key, &operator)
+//!             .unwrap();
+//!
+//!     // The user checks who made the response before unrandomizing it
+//!     assert_eq!(tag.verify(&randomized, &response, &public_key), Ok(7));
+//!     let signed = NizkpTokenEngine::verify_signature_and_unrandomize(
+//!         token,
+//!         randomized,
+//!         response,
+//!         &public_key,
+//!         r,
+//!     );
+//!     assert!(signed.is_ok());
+//! ```
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_TABLE, ristretto::RistrettoPoint, scalar::Scalar,
+};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use super::keys::{PrivateKey, PublicKey};
+use super::tokens::{
+    read_point, read_scalar, NizkpTokenEngine, RandomizedSignedToken, RandomizedUnsignedToken,
+};
+use super::util::{point, signature, Ristretto};
+use super::{Error, RandomizedUnsignedToken as _, Secret, TokenEngine};
+use crate::group::SchnorrSignature;
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
+
+/// The identifier of an operator, chosen by the issuer
+pub type OperatorId = u32;
+
+impl SchnorrSignature<Scalar> {
+    fn encode(&self, writer: &mut Writer) {
+        writer.fixed(self.c.as_bytes());
+        writer.fixed(self.z.as_bytes());
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            c: read_scalar(reader)?,
+            z: read_scalar(reader)?,
+        })
+    }
+}
+
+/// Why a tag does not tell the operator of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperatorError {
+    /// The certificate is not made with the key of the issuer
+    Certificate,
+    /// The tag is not for the response
+    Tag,
+}
+
+impl fmt::Display for OperatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Certificate => f.write_str("the operator is not certified by the issuer"),
+            Self::Tag => f.write_str("the tag is not for the response"),
+        }
+    }
+}
+
+// {{{ Certificate
+
+/// The operator key of an operator, signed with the private key of the issuer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OperatorCertificate {
+    operator: OperatorId,
+    #[serde(with = "point")]
+    point: RistrettoPoint,
+    #[serde(with = "signature")]
+    signature: SchnorrSignature<Scalar>,
+}
+
+impl OperatorCertificate {
+    fn message(operator: OperatorId, point: &RistrettoPoint) -> Vec<u8> {
+        let mut message = Vec::new();
+
+        // domain of the signature, the issuer key also signs tokens
+        message.extend_from_slice(b"This is an operator certificate");
+        message.extend_from_slice(&operator.to_be_bytes());
+        message.extend_from_slice(point.compress().as_bytes());
+
+        message
+    }
+
+    pub fn operator(&self) -> OperatorId {
+        self.operator
+    }
+
+    /// Is this certificate made with the private key of the public key
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        self.signature.verify::<Ristretto>(
+            &Self::message(self.operator, &self.point),
+            public_key.to_affine(),
+        )
+    }
+}
+
+impl WireFormat for OperatorCertificate {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x16;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.operator.to_be_bytes());
+        writer.fixed(self.point.compress().as_bytes());
+        self.signature.encode(writer);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            operator: OperatorId::from_be_bytes(reader.fixed()?),
+            point: read_point(reader)?,
+            signature: SchnorrSignature::decode(reader)?,
+        })
+    }
+}
+
+// }}}
+
+// {{{ Operator key
+
+/// The key an operator tags its responses with
+///
+/// The key is zeroized when it is dropped
+pub struct OperatorKey {
+    scalar: Secret<Scalar>,
+    certificate: OperatorCertificate,
+}
+
+impl OperatorKey {
+    /// Issue a new operator key, this needs the private key of the issuer
+    pub fn issue(operator: OperatorId, issuer: &PrivateKey) -> Self {
+        Self::issue_with_rng(operator, issuer, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::issue`], with the randomness from the given rng
+    pub fn issue_with_rng<R: CryptoRng + RngCore>(
+        operator: OperatorId,
+        issuer: &PrivateKey,
+        rng: &mut R,
+    ) -> Self {
+        let scalar = Scalar::random(rng);
+        let point = &RISTRETTO_BASEPOINT_TABLE * &scalar;
+
+        let signature = SchnorrSignature::create_with_rng::<Ristretto, _>(
+            &OperatorCertificate::message(operator, &point),
+            issuer.to_scalar(),
+            rng,
+        );
+
+        Self {
+            scalar: Secret(scalar),
+            certificate: OperatorCertificate {
+                operator,
+                point,
+                signature,
+            },
+        }
+    }
+
+    pub fn certificate(&self) -> &OperatorCertificate {
+        &self.certificate
+    }
+
+    /// Tag a response to the randomized token
+    pub fn tag<M: AsRef<[u8]>>(
+        &self,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        randomized_signed_token: &RandomizedSignedToken<M>,
+    ) -> OperatorTag {
+        self.tag_with_rng(
+            randomized_unsigned_token,
+            randomized_signed_token,
+            &mut crate::rng::default_rng(),
+        )
+    }
+
+    /// [`Self::tag`], with the nonce of the signature from the given rng
+    pub fn tag_with_rng<M: AsRef<[u8]>, R: CryptoRng + RngCore>(
+        &self,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        randomized_signed_token: &RandomizedSignedToken<M>,
+        rng: &mut R,
+    ) -> OperatorTag {
+        OperatorTag {
+            certificate: self.certificate,
+            signature: SchnorrSignature::create_with_rng::<Ristretto, _>(
+                &OperatorTag::message(randomized_unsigned_token, randomized_signed_token),
+                self.scalar.0,
+                rng,
+            ),
+        }
+    }
+}
+
+impl Zeroize for OperatorKey {
+    fn zeroize(&mut self) {
+        self.scalar.zeroize();
+    }
+}
+
+impl Drop for OperatorKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+// }}}
+
+// {{{ Tag
+
+/// Which operator made a response, sent to the user along with the response
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OperatorTag {
+    certificate: OperatorCertificate,
+    #[serde(with = "signature")]
+    signature: SchnorrSignature<Scalar>,
+}
+
+impl OperatorTag {
+    fn message<M: AsRef<[u8]>>(
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        randomized_signed_token: &RandomizedSignedToken<M>,
+    ) -> Vec<u8> {
+        let metadata = randomized_unsigned_token.metadata();
+        let mut message = Vec::new();
+
+        // domain of the signature
+        message.extend_from_slice(b"This is an operator tag");
+        message.extend_from_slice(randomized_unsigned_token.point().compress().as_bytes());
+        message.extend_from_slice(&(metadata.len() as u64).to_be_bytes());
+        message.extend_from_slice(&metadata);
+        message.extend_from_slice(randomized_signed_token.point().compress().as_bytes());
+
+        message
+    }
+
+    pub fn certificate(&self) -> &OperatorCertificate {
+        &self.certificate
+    }
+
+    /// The operator that made the response, if the operator is certified with the public key
+    pub fn verify<M: AsRef<[u8]>>(
+        &self,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        randomized_signed_token: &RandomizedSignedToken<M>,
+        public_key: &PublicKey,
+    ) -> Result<OperatorId, OperatorError> {
+        if !self.certificate.verify(public_key) {
+            return Err(OperatorError::Certificate);
+        }
+
+        if !self.signature.verify::<Ristretto>(
+            &Self::message(randomized_unsigned_token, randomized_signed_token),
+            self.certificate.point,
+        ) {
+            return Err(OperatorError::Tag);
+        }
+
+        Ok(self.certificate.operator)
+    }
+}
+
+impl WireFormat for OperatorTag {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x17;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        self.certificate.encode(writer)?;
+        self.signature.encode(writer);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            certificate: OperatorCertificate::decode(reader)?,
+            signature: SchnorrSignature::decode(reader)?,
+        })
+    }
+}
+
+// }}}
+
+impl<M: AsRef<[u8]>> NizkpTokenEngine<M> {
+    /// Sign a randomized token, and tag the response with the operator key
+    pub fn sign_randomized_by_operator(
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        sign_key: &PrivateKey,
+        operator: &OperatorKey,
+    ) -> Result<(RandomizedSignedToken<M>, OperatorTag), Error> {
+        let response = Self::sign_randomized(randomized_unsigned_token, sign_key)?;
+        let tag = operator.tag(randomized_unsigned_token, &response);
+
+        Ok((response, tag))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> RandomizedUnsignedToken<&'static [u8]> {
+        NizkpTokenEngine::randomize(&NizkpTokenEngine::generate(&b"metadata"[..])).1
+    }
+
+    #[test]
+    fn test_tag() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+        let first = OperatorKey::issue(1, &private);
+        let second = OperatorKey::issue(2, &private);
+
+        let randomized = request();
+        let (response, tag) =
+            NizkpTokenEngine::sign_randomized_by_operator(&randomized, &private, &first).unwrap();
+        assert_eq!(tag.verify(&randomized, &response, &public_key), Ok(1));
+
+        let other = second.tag(&randomized, &response);
+        assert_eq!(other.verify(&randomized, &response, &public_key), Ok(2));
+
+        let decoded = OperatorTag::from_bytes(&tag.to_bytes()).unwrap();
+        assert_eq!(decoded, tag);
+        let json = serde_json::to_string(&tag).unwrap();
+        assert_eq!(serde_json::from_str::<OperatorTag>(&json).unwrap(), tag);
+    }
+
+    #[test]
+    fn fail_other_response() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+        let operator = OperatorKey::issue(1, &private);
+
+        let randomized = request();
+        let (response, tag) =
+            NizkpTokenEngine::sign_randomized_by_operator(&randomized, &private, &operator)
+                .unwrap();
+
+        // the tag of one response can not be moved to another
+        let other = request();
+        let other_response = NizkpTokenEngine::sign_randomized(&other, &private).unwrap();
+        assert_eq!(
+            tag.verify(&other, &other_response, &public_key),
+            Err(OperatorError::Tag)
+        );
+
+        // the operator must be certified by the advertised key
+        assert_eq!(
+            tag.verify(&randomized, &response, &PublicKey::from(&PrivateKey::new())),
+            Err(OperatorError::Certificate)
+        );
+
+        // nor can the certificate be claimed by another operator
+        let forged = OperatorTag {
+            certificate: OperatorCertificate {
+                operator: 2,
+                ..tag.certificate
+            },
+            ..tag
+        };
+        assert_eq!(
+            forged.verify(&randomized, &response, &public_key),
+            Err(OperatorError::Certificate)
+        );
+    }
+}
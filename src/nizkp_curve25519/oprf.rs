@@ -0,0 +1,299 @@
+//! # Tokens without identifiers
+//!
+//! A deployment that only needs to know that a token was issued by its key does not need the
+//! [`TokenIdentifier`](super::TokenIdentifier) of the tokens. The point of these tokens is the
+//! hash of 32 random bytes of the client, as in an OPRF, so a signed token is the nonce, the
+//! metadata and the point, without hidden metadata, the integrity tag or the key identifier.
+//!
+//! The signer signs them as any other token of the engine, with
+//! [`NizkpTokenEngine::sign_randomized`](super::tokens::NizkpTokenEngine), and can not tell them
+//! apart.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         oprf::OprfTokenEngine,
+//!         tokens::NizkpTokenEngine,
+//!     };
+//!     use atpmd::wire::WireFormat;
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = OprfTokenEngine::sign(
+//!         OprfTokenEngine::generate(b"metadata".to_vec()),
+//!         &public_key,
+//!         |randomized| NizkpTokenEngine::sign_randomized(randomized, &secret_key),
+//!     ).unwrap();
+//!
+//!     assert!(OprfTokenEngine::verify(&signed, &secret_key).is_ok());
+//!     assert_eq!(signed.to_bytes().len(), 2 + 32 + 2 + 8 + 32);
+//! ```
+
+use alloc::boxed::Box;
+use core::{convert::Infallible, marker::PhantomData};
+
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_TABLE, ristretto::RistrettoPoint, scalar::Scalar,
+};
+use rand::{CryptoRng, RngCore};
+
+use super::keys::{PrivateKey, PublicKey};
+use super::tokens::{read_point, NizkpTokenEngine, RandomizedSignedToken, RandomizedUnsignedToken};
+use super::util::{h_nonce, hash_to_scalar, point, Ristretto};
+use super::{
+    check_metadata, fill_bytes, Error, SignedToken, TokenEngine, UnsignedToken, VerifyError,
+};
+use crate::group::blinding;
+use crate::redemption::{Fingerprint, Nullifier, Redeemable};
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
+
+// {{{ Unsigned token
+
+/// A token of a random nonce and the metadata
+pub struct OprfUnsignedToken<M: AsRef<[u8]>> {
+    nonce: [u8; 32],
+    metadata: M,
+}
+
+impl<M: AsRef<[u8]>> OprfUnsignedToken<M> {
+    pub fn get_point(&self) -> RistrettoPoint {
+        h_nonce(&self.nonce)
+    }
+}
+
+impl<M: AsRef<[u8]>> UnsignedToken for OprfUnsignedToken<M> {
+    type Metadata = M;
+    /// These tokens have no hidden metadata
+    type HiddenMetadata = Infallible;
+
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
+        let mut nonce = [0; 32];
+        fill_bytes(rng, &mut nonce);
+
+        Self { nonce, metadata }
+    }
+
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        _metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        _rng: &mut R,
+    ) -> Self {
+        match hidden {}
+    }
+}
+
+// }}}
+
+// {{{ Signed token
+
+#[derive(Serialize, Deserialize)]
+pub struct OprfSignedToken<M: AsRef<[u8]>> {
+    nonce: [u8; 32],
+    metadata: M,
+    #[serde(with = "point")]
+    point: RistrettoPoint,
+}
+
+impl<M: AsRef<[u8]>> OprfSignedToken<M> {
+    pub fn metadata(&self) -> &M {
+        &self.metadata
+    }
+
+    pub fn nonce(&self) -> &[u8; 32] {
+        &self.nonce
+    }
+}
+
+impl<M: AsRef<[u8]>> SignedToken for OprfSignedToken<M> {
+    type VerificationKey = PrivateKey;
+
+    fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
+        // w == e * t is the same as e^-1 w == t
+        let e_inverse = hash_to_scalar(&self.metadata) + verification_key.to_scalar();
+
+        self.point * e_inverse == h_nonce(&self.nonce)
+    }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+}
+
+impl<M: AsRef<[u8]>> Redeemable for OprfSignedToken<M> {
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_nonce(&self.nonce, self.metadata.as_ref())
+    }
+
+    fn nullifier(&self) -> Nullifier {
+        Nullifier::of_nonce(
+            &self.nonce,
+            self.point.compress().as_bytes(),
+            self.metadata.as_ref(),
+        )
+    }
+}
+
+impl<M: AsRef<[u8]> + for<'a> From<&'a [u8]>> WireFormat for OprfSignedToken<M> {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x19;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.nonce);
+        writer.prefixed(&self.metadata)?;
+        writer.fixed(self.point.compress().as_bytes());
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            nonce: reader.fixed()?,
+            metadata: M::from(reader.prefixed()?),
+            point: read_point(reader)?,
+        })
+    }
+}
+
+// }}}
+
+// {{{ Token engine
+
+/// The engine of the tokens without identifiers
+///
+/// The randomized tokens are those of [`NizkpTokenEngine`], which signs them.
+pub struct OprfTokenEngine<M: AsRef<[u8]>> {
+    _m: PhantomData<M>,
+}
+
+impl<M: AsRef<[u8]>> OprfTokenEngine<M> {
+    /// Check the proof of the signer against the randomized token
+    fn check_proof(
+        unsigned_token: &OprfUnsignedToken<M>,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        signed_token: &RandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+    ) -> Result<(), Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &crate::common::RandomizedUnsignedToken::metadata(randomized_unsigned_token),
+        )?;
+
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        if signed_token.proof().verify::<Ristretto>(
+            randomized_unsigned_token.point(),
+            signed_token.point(),
+            u,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::BadProof)
+        }
+    }
+}
+
+impl<M: AsRef<[u8]>> TokenEngine for OprfTokenEngine<M> {
+    type UnsignedToken = OprfUnsignedToken<M>;
+    type RandomizedUnsignedToken = RandomizedUnsignedToken<M>;
+    type RandomizedSignedToken = RandomizedSignedToken<M>;
+    type SignedToken = OprfSignedToken<M>;
+    type Randomization = Scalar;
+    type UserVerification = PublicKey;
+    type SignKey = PrivateKey;
+
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        let (r, inverse) = blinding::<Ristretto, _>(rng);
+        (
+            r,
+            RandomizedUnsignedToken::from_parts(
+                unsigned_token.get_point() * inverse,
+                Box::from(unsigned_token.metadata.as_ref()),
+            ),
+        )
+    }
+
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned_token: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::check_proof(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+        ) {
+            // Remove randomization
+            Ok(()) => Ok(OprfSignedToken {
+                nonce: unsigned_token.nonce,
+                metadata: unsigned_token.metadata,
+                point: signed_token.point() * randomization,
+            }),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
+        }
+    }
+
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        t_prime: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        NizkpTokenEngine::sign_randomized_with_rng(t_prime, sign_key, rng)
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nizkp_curve25519::tokens::NizkpSignedToken;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_oprf() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let signed = OprfTokenEngine::sign(
+            OprfTokenEngine::generate(b"metadata".to_vec()),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+        assert!(signed.verify(&private));
+        assert!(!signed.verify(&PrivateKey::new()));
+
+        let decoded = OprfSignedToken::<Vec<u8>>::from_bytes(&signed.to_bytes()).unwrap();
+        assert!(decoded.verify(&private));
+        assert_eq!(decoded.nullifier(), signed.nullifier());
+        // smaller than a token with an identifier
+        let token = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(b"metadata".to_vec()),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+        assert!(signed.to_bytes().len() < NizkpSignedToken::to_bytes(&token).len());
+
+        // a response of another key is rejected with the token
+        let result = OprfTokenEngine::sign(
+            OprfTokenEngine::generate(b"metadata".to_vec()),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &PrivateKey::new()),
+        );
+        assert_eq!(result.err(), Some(Error::BadProof));
+    }
+}
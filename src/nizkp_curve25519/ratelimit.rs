@@ -0,0 +1,306 @@
+//! # Tokens counted per user and epoch
+//!
+//! An issuer that gives each user N tokens per epoch, e.g. 10 requests an hour, counts the tokens
+//! it signs for the user, who is authenticated at issuance. The tokens are blinded, so the
+//! verifier still does not learn which user redeems a token, only that some user got it within
+//! the limit.
+//!
+//! The metadata of a token is the end of its epoch, as [`expiry::Metadata`](crate::expiry::Metadata),
+//! and it is signed with a key of the epoch derived from the key of the issuer. A token is then
+//! only valid in its epoch, and the tokens of an epoch can not be saved up for the next. The user
+//! derives the public key of the epoch from the public key of the issuer, and checks the proof of
+//! the issuer against it, so the issuer can not sign some users with other keys to tell them
+//! apart at redemption.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::redemption::MemoryRedemptionStore;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         ratelimit::{RateLimit, RateLimitedIssuer},
+//!         tokens::NizkpTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     // one token per user an hour
+//!     let rate = RateLimit { limit: 1, epoch_length: 3600 };
+//!     let mut issuer = RateLimitedIssuer::new(secret_key.clone(), rate);
+//!
+//!     let now = 1_600_000_000;
+//!     let token = NizkpTokenEngine::generate(rate.metadata(now));
+//!     let (r, randomized) = NizkpTokenEngine::randomize(&token);
+//!
+//!     // the issuer counts the token for the authenticated user
+//!     let response = issuer.sign("alice", &randomized, now).unwrap();
+//!
+//!     let signed = NizkpTokenEngine::verify_signature_and_unrandomize(
+//!         token,
+//!         randomized,
+//!         response,
+//!         &rate.public_key(&public_key, now),
+//!         r,
+//!     ).ok().unwrap();
+//!
+//!     // alice has used up the epoch
+//!     let token = NizkpTokenEngine::generate(rate.metadata(now));
+//!     let (_r, randomized) = NizkpTokenEngine::randomize(&token);
+//!     assert!(issuer.sign("alice", &randomized, now).is_err());
+//!
+//!     let mut store = MemoryRedemptionStore::new();
+//!     assert!(rate.redeem(&signed, &secret_key, &mut store, now).is_ok());
+//! ```
+
+use alloc::{collections::BTreeMap, string::String};
+
+use super::keys::{PrivateKey, PublicKey};
+use super::tokens::{NizkpSignedToken, NizkpTokenEngine, RandomizedSignedToken};
+use super::{Error, TokenEngine};
+use crate::common::RandomizedUnsignedToken as _;
+use crate::expiry::{self, Metadata};
+use crate::policy::PolicyError;
+use crate::redemption::{RedeemError, RedemptionStore};
+
+/// The number of tokens a user gets in each epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub limit: u32,
+    /// The length of the epochs in seconds, the epochs start at multiples of it
+    pub epoch_length: u64,
+}
+
+impl RateLimit {
+    /// The epoch at `now`, in seconds since the unix epoch
+    pub fn epoch(&self, now: u64) -> u64 {
+        now / self.epoch_length
+    }
+
+    /// The seconds until the next epoch
+    pub fn retry_after(&self, now: u64) -> u64 {
+        self.epoch_length - now % self.epoch_length
+    }
+
+    /// The metadata of the tokens of the epoch at `now`
+    pub fn metadata(&self, now: u64) -> Metadata {
+        let end = (self.epoch(now) + 1) * self.epoch_length;
+        Metadata::new(end, [])
+    }
+
+    /// The epoch of the metadata of a token, if it is the metadata of an epoch
+    fn epoch_of(&self, metadata: &[u8]) -> Option<u64> {
+        let end = expiry::expires_at(metadata)?;
+        let is_epoch = metadata.len() == 8 && end > 0 && end % self.epoch_length == 0;
+
+        if is_epoch {
+            Some(end / self.epoch_length - 1)
+        } else {
+            None
+        }
+    }
+
+    /// The public key the tokens of the epoch at `now` are signed with
+    pub fn public_key(&self, public_key: &PublicKey, now: u64) -> PublicKey {
+        public_key.derive(epoch_label(self.epoch(now)))
+    }
+
+    /// Verify a token in its epoch, and mark it as redeemed in the store
+    ///
+    /// The verifier has the key of the issuer, the key of the epoch of the token is derived from it.
+    pub fn redeem<M: AsRef<[u8]>, S: RedemptionStore>(
+        &self,
+        token: &NizkpSignedToken<M>,
+        private_key: &PrivateKey,
+        store: &mut S,
+        now: u64,
+    ) -> Result<(), RedeemError> {
+        let epoch = self
+            .epoch_of(token.metadata().as_ref())
+            .ok_or(RedeemError::Invalid)?;
+        let epoch_key = private_key.derive(epoch_label(epoch));
+
+        NizkpTokenEngine::redeem(token, &epoch_key, store, now)
+    }
+}
+
+/// The label the keys of an epoch are derived with
+fn epoch_label(epoch: u64) -> [u8; 24] {
+    let mut label = [0; 24];
+    label[..16].copy_from_slice(b"rate limit epoch");
+    label[16..].copy_from_slice(&epoch.to_be_bytes());
+    label
+}
+
+/// An issuer that signs [`RateLimit::limit`] tokens for each user in an epoch
+pub struct RateLimitedIssuer {
+    private_key: PrivateKey,
+    rate: RateLimit,
+    /// The tokens signed for each user in the current epoch
+    counts: BTreeMap<String, u32>,
+    epoch: u64,
+}
+
+impl RateLimitedIssuer {
+    pub fn new(private_key: PrivateKey, rate: RateLimit) -> Self {
+        Self {
+            private_key,
+            rate,
+            counts: BTreeMap::new(),
+            epoch: 0,
+        }
+    }
+
+    /// Forget the counts of the past epochs
+    fn enter_epoch(&mut self, now: u64) {
+        let epoch = self.rate.epoch(now);
+        if self.epoch != epoch {
+            self.epoch = epoch;
+            self.counts.clear();
+        }
+    }
+
+    /// The tokens the user may still get in the epoch at `now`
+    pub fn remaining(&mut self, user: &str, now: u64) -> u32 {
+        self.enter_epoch(now);
+        let used = self.counts.get(user).copied().unwrap_or(0);
+        self.rate.limit.saturating_sub(used)
+    }
+
+    /// Sign a token of the epoch at `now` for the user, if the user has tokens left
+    ///
+    /// A token of another epoch is [`PolicyError::denied`], and a user that has used up the epoch
+    /// is [`PolicyError::rate_limited`] until the next.
+    pub fn sign<M: AsRef<[u8]>>(
+        &mut self,
+        user: &str,
+        randomized_unsigned: &super::tokens::RandomizedUnsignedToken<M>,
+        now: u64,
+    ) -> Result<RandomizedSignedToken<M>, Error> {
+        if *randomized_unsigned.metadata() != *self.rate.metadata(now).as_ref() {
+            return Err(Error::Policy(PolicyError::denied()));
+        }
+
+        if self.remaining(user, now) == 0 {
+            let retry_after = self.rate.retry_after(now);
+            return Err(Error::Policy(PolicyError::rate_limited(retry_after)));
+        }
+
+        let epoch_key = self.private_key.derive(epoch_label(self.rate.epoch(now)));
+        let signed = NizkpTokenEngine::sign_randomized(randomized_unsigned, &epoch_key)?;
+
+        *self.counts.entry(String::from(user)).or_insert(0) += 1;
+        Ok(signed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redemption::MemoryRedemptionStore;
+
+    const RATE: RateLimit = RateLimit {
+        limit: 2,
+        epoch_length: 3600,
+    };
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn issue(
+        issuer: &mut RateLimitedIssuer,
+        public_key: &PublicKey,
+        user: &str,
+        now: u64,
+    ) -> Result<NizkpSignedToken<Metadata>, Error> {
+        let token = NizkpTokenEngine::generate(RATE.metadata(now));
+        let (r, randomized) = NizkpTokenEngine::randomize(&token);
+        let response = issuer.sign(user, &randomized, now)?;
+
+        NizkpTokenEngine::verify_signature_and_unrandomize(
+            token,
+            randomized,
+            response,
+            &RATE.public_key(public_key, now),
+            r,
+        )
+        .map_err(|e| e.error)
+    }
+
+    #[test]
+    fn test_limit_per_epoch() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+        let mut issuer = RateLimitedIssuer::new(private.clone(), RATE);
+
+        assert!(issue(&mut issuer, &public_key, "alice", NOW).is_ok());
+        assert!(issue(&mut issuer, &public_key, "alice", NOW).is_ok());
+        assert_eq!(
+            issue(&mut issuer, &public_key, "alice", NOW).err(),
+            Some(Error::Policy(PolicyError::rate_limited(
+                RATE.retry_after(NOW)
+            )))
+        );
+        assert_eq!(issuer.remaining("bob", NOW), 2);
+
+        // the next epoch
+        let later = NOW + RATE.retry_after(NOW);
+        assert_eq!(issuer.remaining("alice", later), 2);
+        assert!(issue(&mut issuer, &public_key, "alice", later).is_ok());
+    }
+
+    #[test]
+    fn test_redeem_in_epoch() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+        let mut issuer = RateLimitedIssuer::new(private.clone(), RATE);
+        let mut store = MemoryRedemptionStore::new();
+
+        let token = issue(&mut issuer, &public_key, "alice", NOW).unwrap();
+        assert_eq!(RATE.redeem(&token, &private, &mut store, NOW), Ok(()));
+        assert_eq!(
+            RATE.redeem(&token, &private, &mut store, NOW),
+            Err(RedeemError::DoubleSpend)
+        );
+
+        // the token is not saved for the next epoch
+        let token = issue(&mut issuer, &public_key, "alice", NOW).unwrap();
+        let later = NOW + RATE.retry_after(NOW);
+        assert_eq!(
+            RATE.redeem(&token, &private, &mut store, later),
+            Err(RedeemError::Expired)
+        );
+
+        // the key of the issuer does not sign the tokens of an epoch
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(RATE.metadata(NOW)),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+        assert_eq!(
+            RATE.redeem(&signed, &private, &mut store, NOW),
+            Err(RedeemError::Invalid)
+        );
+    }
+
+    #[test]
+    fn fail_other_metadata() {
+        let private = PrivateKey::new();
+        let mut issuer = RateLimitedIssuer::new(private, RATE);
+
+        for metadata in [
+            Metadata::new(NOW + 10, []),
+            RATE.metadata(NOW + RATE.epoch_length),
+            Metadata::new(RATE.metadata(NOW).expires_at().unwrap(), b"more"),
+        ] {
+            let token = NizkpTokenEngine::generate(metadata);
+            let (_r, randomized) = NizkpTokenEngine::randomize(&token);
+            assert_eq!(
+                issuer.sign("alice", &randomized, NOW).err(),
+                Some(Error::Policy(PolicyError::denied()))
+            );
+        }
+
+        assert_eq!(RATE.epoch_of(&[]), None);
+        assert_eq!(RATE.epoch_of(&[0; 8]), None);
+    }
+}
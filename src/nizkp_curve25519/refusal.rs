@@ -0,0 +1,121 @@
+//! # Signed refusals
+//!
+//! A refusal is a Schnorr signature with the private key of the issuer, so the client checks it
+//! with the public key it already has for the proofs. The nonce of the signature is a hash of the
+//! private key and the refusal, so a refusal is the same every time it is signed.
+//!
+//! ```
+//!     use atpmd::Error;
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         refusal::NizkpSignedRefusal,
+//!         tokens::RandomizedSignedToken,
+//!     };
+//!     use atpmd::refusal::{Refusal, RefusalReason, SignResponse};
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let now = 1_600_000_000;
+//!
+//!     // the client may not have tokens for the resource
+//!     let refusal = Refusal::new(RefusalReason::PolicyDenied, None, now);
+//!     let response: SignResponse<RandomizedSignedToken<&[u8]>, _> =
+//!         SignResponse::Refused(NizkpSignedRefusal::sign(refusal, b"resource", &secret_key));
+//!
+//!     assert_eq!(
+//!         response.into_result(b"resource", &public_key, now).err(),
+//!         Some(Error::Refused(refusal))
+//!     );
+//! ```
+
+use curve25519_dalek::scalar::Scalar;
+use rand::{prelude::StdRng, SeedableRng};
+use sha2::{Digest, Sha256};
+
+use super::keys::{PrivateKey, PublicKey};
+use super::util::{signature, Ristretto};
+use crate::group::SchnorrSignature;
+use crate::refusal::{Refusal, SignedRefusal};
+
+/// A refusal with the signature of the issuer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct NizkpSignedRefusal {
+    refusal: Refusal,
+    #[serde(with = "signature")]
+    signature: SchnorrSignature<Scalar>,
+}
+
+impl NizkpSignedRefusal {
+    /// Sign a refusal of a request for the metadata
+    pub fn sign(refusal: Refusal, metadata: impl AsRef<[u8]>, private_key: &PrivateKey) -> Self {
+        let message = refusal.message(metadata.as_ref());
+
+        // deterministic nonce, the key keeps it secret
+        let mut hasher = Sha256::new();
+        hasher.update(b"This is a refusal nonce");
+        hasher.update(private_key.to_scalar().as_bytes());
+        hasher.update(&message);
+        let mut rng = StdRng::from_seed(hasher.finalize().into());
+
+        Self {
+            refusal,
+            signature: SchnorrSignature::create_with_rng::<Ristretto, _>(
+                &message,
+                private_key.to_scalar(),
+                &mut rng,
+            ),
+        }
+    }
+
+    /// The refusal, without checking the signature
+    pub fn refusal(&self) -> &Refusal {
+        &self.refusal
+    }
+}
+
+impl SignedRefusal for NizkpSignedRefusal {
+    type PublicKey = PublicKey;
+
+    fn verify(&self, metadata: &[u8], public_key: &PublicKey) -> Option<Refusal> {
+        if self
+            .signature
+            .verify::<Ristretto>(&self.refusal.message(metadata), public_key.to_affine())
+        {
+            Some(self.refusal)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::refusal::RefusalReason;
+
+    #[test]
+    fn test_refusal() {
+        let private_key = PrivateKey::new();
+        let public_key = PublicKey::from(&private_key);
+        let refusal = Refusal::new(RefusalReason::Overloaded, Some(5), 1000);
+
+        let signed = NizkpSignedRefusal::sign(refusal, b"a", &private_key);
+        assert_eq!(signed.verify(b"a", &public_key), Some(refusal));
+        assert_eq!(signed.verify(b"b", &public_key), None);
+        assert_eq!(
+            signed.verify(b"a", &PublicKey::from(PrivateKey::new())),
+            None
+        );
+
+        // deterministic
+        assert_eq!(
+            NizkpSignedRefusal::sign(refusal, b"a", &private_key),
+            signed
+        );
+
+        let mut changed = signed;
+        changed.refusal.reason = RefusalReason::PolicyDenied;
+        assert_eq!(changed.verify(b"a", &public_key), None);
+    }
+}
@@ -0,0 +1,755 @@
+//! # Tokens of RFC 9497
+//!
+//! The hashes and the proof of [`NizkpTokenEngine`](super::tokens::NizkpTokenEngine) are those of
+//! this crate, so only this crate verifies its tokens. The tokens of the [`PoprfTokenEngine`] are
+//! those of the POPRF mode of the OPRF(ristretto255, SHA-512) suite of [RFC 9497], with the
+//! metadata as the public info: the blinded and evaluated elements and the proof have the encodings
+//! of the RFC, and the output of a token is that of the RFC, so a token of this crate is verified
+//! by the other implementations of the RFC, and the other way around.
+//!
+//! The signer signs as the other engines, with (m + k)^{-1} T' for the hash m of the info, but the
+//! hashes are those of the RFC, so the signed tokens of the engines are not the same. A key may be
+//! used with both, or derived with [`derive_key_pair`] of the RFC.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::nizkp_curve25519::keys::{PrivateKey, PublicKey};
+//!     use atpmd::nizkp_curve25519::rfc9497::{evaluate, BlindedElement, PoprfTokenEngine};
+//!
+//!     type Engine = PoprfTokenEngine<Vec<u8>>;
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let unsigned = Engine::generate(b"info".to_vec());
+//!     let (blind, blinded) = Engine::randomize(&unsigned);
+//!
+//!     // the signer gets the 32 bytes of the blinded element, and the info
+//!     let request = BlindedElement::from_bytes(&blinded.to_bytes(), b"info").unwrap();
+//!     let evaluated = Engine::sign_randomized(&request, &secret_key).unwrap();
+//!
+//!     let signed = Engine::verify_signature_and_unrandomize(
+//!         unsigned, blinded, evaluated, &public_key, blind,
+//!     ).ok().unwrap();
+//!
+//!     // the output of the RFC, which the verifier computes with the key
+//!     let output = evaluate(&secret_key, signed.input(), b"info").unwrap();
+//!     assert_eq!(signed.output().unwrap(), output);
+//!     assert!(Engine::verify(&signed, &secret_key).is_ok());
+//! ```
+//!
+//! [RFC 9497]: https://www.rfc-editor.org/rfc/rfc9497
+
+use alloc::boxed::Box;
+use core::convert::{Infallible, TryFrom};
+use core::marker::PhantomData;
+
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_TABLE, ristretto::RistrettoPoint, scalar::Scalar,
+    traits::Identity,
+};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512};
+use subtle::ConstantTimeEq;
+
+use super::keys::{PrivateKey, PublicKey};
+use super::tokens::{read_point, read_scalar};
+use super::util::{point, scalar, Ristretto};
+use super::{
+    check_metadata, fill_bytes, Error, KeyEpoch, SignedToken, TokenEngine, UnsignedToken,
+    VerifyError,
+};
+use crate::group::blinding;
+use crate::wire::{Reader, WireError};
+
+// {{{ Suite
+
+/// The context string of the POPRF mode of the ristretto255-SHA512 suite
+const CONTEXT: &[u8] = b"OPRFV1-\x02-ristretto255-SHA512";
+
+/// The length of an encoded element
+pub const NE: usize = 32;
+
+/// The length of an encoded scalar
+pub const NS: usize = 32;
+
+/// The length of an output
+pub const NH: usize = 64;
+
+/// expand_message_xmd of RFC 9380 with SHA-512, to 64 bytes, of the parts of the message
+fn expand_message_xmd(msg: &[&[u8]], dst: &[&[u8]]) -> [u8; 64] {
+    // the domains are shorter than 255 bytes
+    let dst_len = dst.iter().map(|part| part.len()).sum::<usize>() as u8;
+    let with_dst = |hasher: Sha512| {
+        dst.iter()
+            .fold(hasher, |hasher, part| hasher.chain(part))
+            .chain([dst_len])
+    };
+
+    // Z_pad || msg || l_i_b_str || 0 || DST_prime
+    let padded = Sha512::new().chain([0; 128]);
+    let b_0 = with_dst(
+        msg.iter()
+            .fold(padded, |hasher, part| hasher.chain(part))
+            .chain([0, 64, 0]),
+    )
+    .finalize();
+
+    // 64 bytes are one block of SHA-512
+    let mut uniform = [0; 64];
+    uniform.copy_from_slice(&with_dst(Sha512::new().chain(b_0).chain([1])).finalize());
+    uniform
+}
+
+fn hash_to_group(input: &[u8]) -> RistrettoPoint {
+    RistrettoPoint::from_uniform_bytes(&expand_message_xmd(&[input], &[b"HashToGroup-", CONTEXT]))
+}
+
+fn hash_to_scalar(msg: &[&[u8]], dst: &[u8]) -> Scalar {
+    Scalar::from_bytes_mod_order_wide(&expand_message_xmd(msg, &[dst, CONTEXT]))
+}
+
+/// The length prefix of the RFC, which only has inputs and infos of up to 2^16 - 1 bytes
+fn prefix(bytes: &[u8]) -> Result<[u8; 2], Error> {
+    u16::try_from(bytes.len())
+        .map(u16::to_be_bytes)
+        .map_err(|_e| Error::Malformed(WireError::MetadataLength))
+}
+
+/// The tweak m of the key for the info
+fn tweak(info: &[u8]) -> Result<Scalar, Error> {
+    Ok(hash_to_scalar(
+        &[b"Info", &prefix(info)?, info],
+        b"HashToScalar-",
+    ))
+}
+
+/// The tweaked key t = k + m, which the signer signs with
+fn tweaked_secret(key: &PrivateKey, info: &[u8]) -> Result<Scalar, Error> {
+    let t = key.to_scalar() + tweak(info)?;
+    if t == Scalar::zero() {
+        Err(Error::NonInvertibleScalar)
+    } else {
+        Ok(t)
+    }
+}
+
+/// The output of an input, with the unblinded element
+fn finalize(input: &[u8], info: &[u8], element: &RistrettoPoint) -> Result<[u8; NH], Error> {
+    let hasher = Sha512::new()
+        .chain(prefix(input)?)
+        .chain(input)
+        .chain(prefix(info)?)
+        .chain(info)
+        .chain((NE as u16).to_be_bytes())
+        .chain(element.compress().as_bytes())
+        .chain(b"Finalize");
+
+    let mut output = [0; NH];
+    output.copy_from_slice(&hasher.finalize());
+    Ok(output)
+}
+
+/// The element of the bytes, which may not be the identity
+fn read_element(reader: &mut Reader<'_>) -> Result<RistrettoPoint, WireError> {
+    let element = read_point(reader)?;
+    if element == RistrettoPoint::identity() {
+        Err(WireError::InvalidPoint)
+    } else {
+        Ok(element)
+    }
+}
+
+/// Decode all of the bytes
+fn decode<T>(
+    bytes: &[u8],
+    f: impl FnOnce(&mut Reader<'_>) -> Result<T, WireError>,
+) -> Result<T, WireError> {
+    let mut reader = Reader::new(bytes);
+    let value = f(&mut reader)?;
+    if reader.is_empty() {
+        Ok(value)
+    } else {
+        Err(WireError::TrailingBytes)
+    }
+}
+
+/// The key pair of a seed and an info, as DeriveKeyPair of the RFC
+pub fn derive_key_pair(seed: &[u8; 32], info: &[u8]) -> Result<PrivateKey, Error> {
+    let info_len = prefix(info)?;
+    (0..=255)
+        .map(|counter: u8| hash_to_scalar(&[seed, &info_len, info, &[counter]], b"DeriveKeyPair"))
+        .find(|scalar| *scalar != Scalar::zero())
+        .map(PrivateKey::from_scalar)
+        .ok_or(Error::NonInvertibleScalar)
+}
+
+/// The output of the input and the info, as Evaluate of the RFC
+///
+/// This is what the verifier computes to check a token of another implementation.
+pub fn evaluate(key: &PrivateKey, input: &[u8], info: &[u8]) -> Result<[u8; NH], Error> {
+    let t = tweaked_secret(key, info)?;
+    finalize(input, info, &(hash_to_group(input) * t.invert()))
+}
+
+// }}}
+
+// {{{ Proof
+
+/// The proof of the RFC, that the evaluated elements are the blinded elements signed with the
+/// tweaked key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Proof {
+    #[serde(with = "scalar")]
+    c: Scalar,
+    #[serde(with = "scalar")]
+    s: Scalar,
+}
+
+/// The composite elements of the batch, with the weights di of the hash of the tweaked key
+///
+/// The signer knows the tweaked secret t, with which z = t m.
+fn composites(
+    tweaked_key: &RistrettoPoint,
+    evaluated: &[RistrettoPoint],
+    blinded: &[RistrettoPoint],
+    tweaked_secret: Option<Scalar>,
+) -> (RistrettoPoint, RistrettoPoint) {
+    let b = tweaked_key.compress();
+    let seed_dst = [&b"Seed-"[..], CONTEXT].concat();
+    let seed = Sha512::new()
+        .chain((NE as u16).to_be_bytes())
+        .chain(b.as_bytes())
+        .chain((seed_dst.len() as u16).to_be_bytes())
+        .chain(&seed_dst)
+        .finalize();
+
+    let (m, z) = evaluated.iter().zip(blinded.iter()).enumerate().fold(
+        (RistrettoPoint::identity(), RistrettoPoint::identity()),
+        |(m, z), (i, (c, d))| {
+            let di = hash_to_scalar(
+                &[
+                    &(seed.len() as u16).to_be_bytes(),
+                    &seed,
+                    &(i as u16).to_be_bytes(),
+                    &(NE as u16).to_be_bytes(),
+                    c.compress().as_bytes(),
+                    &(NE as u16).to_be_bytes(),
+                    d.compress().as_bytes(),
+                    b"Composite",
+                ],
+                b"HashToScalar-",
+            );
+            (m + c * di, z + d * di)
+        },
+    );
+
+    match tweaked_secret {
+        Some(t) => (m, m * t),
+        None => (m, z),
+    }
+}
+
+fn challenge(points: [&RistrettoPoint; 5]) -> Scalar {
+    let encoded = points.map(|point| point.compress());
+    let len = (NE as u16).to_be_bytes();
+    hash_to_scalar(
+        &[
+            &len,
+            encoded[0].as_bytes(),
+            &len,
+            encoded[1].as_bytes(),
+            &len,
+            encoded[2].as_bytes(),
+            &len,
+            encoded[3].as_bytes(),
+            &len,
+            encoded[4].as_bytes(),
+            b"Challenge",
+        ],
+        b"HashToScalar-",
+    )
+}
+
+impl Proof {
+    /// GenerateProof of the RFC, with the random scalar r
+    fn create(
+        t: Scalar,
+        tweaked_key: &RistrettoPoint,
+        evaluated: &[RistrettoPoint],
+        blinded: &[RistrettoPoint],
+        r: Scalar,
+    ) -> Self {
+        let (m, z) = composites(tweaked_key, evaluated, blinded, Some(t));
+        let c = challenge([
+            tweaked_key,
+            &m,
+            &z,
+            &(&RISTRETTO_BASEPOINT_TABLE * &r),
+            &(m * r),
+        ]);
+
+        Self { c, s: r - c * t }
+    }
+
+    /// VerifyProof of the RFC
+    fn verify(
+        &self,
+        tweaked_key: &RistrettoPoint,
+        evaluated: &[RistrettoPoint],
+        blinded: &[RistrettoPoint],
+    ) -> bool {
+        let (m, z) = composites(tweaked_key, evaluated, blinded, None);
+        let t2 = &RISTRETTO_BASEPOINT_TABLE * &self.s + tweaked_key * self.c;
+        let t3 = m * self.s + z * self.c;
+
+        bool::from(challenge([tweaked_key, &m, &z, &t2, &t3]).ct_eq(&self.c))
+    }
+
+    /// The encoding of the RFC, c and s
+    pub fn to_bytes(&self) -> [u8; 2 * NS] {
+        let mut bytes = [0; 2 * NS];
+        bytes[..NS].copy_from_slice(self.c.as_bytes());
+        bytes[NS..].copy_from_slice(self.s.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        decode(bytes, |reader| {
+            Ok(Self {
+                c: read_scalar(reader)?,
+                s: read_scalar(reader)?,
+            })
+        })
+    }
+}
+
+// }}}
+
+// {{{ Unsigned token
+
+/// A token of a random input and the info
+pub struct PoprfUnsignedToken<M: AsRef<[u8]>> {
+    input: [u8; 32],
+    metadata: M,
+}
+
+impl<M: AsRef<[u8]>> UnsignedToken for PoprfUnsignedToken<M> {
+    type Metadata = M;
+    /// These tokens have no hidden metadata
+    type HiddenMetadata = Infallible;
+
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
+        let mut input = [0; 32];
+        fill_bytes(rng, &mut input);
+
+        Self { input, metadata }
+    }
+
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        _metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        _rng: &mut R,
+    ) -> Self {
+        match hidden {}
+    }
+}
+
+// }}}
+
+// {{{ Blinded and evaluated elements
+
+/// The request of the client, the blinded element and the info
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlindedElement {
+    #[serde(with = "point")]
+    element: RistrettoPoint,
+    metadata: Box<[u8]>,
+}
+
+impl BlindedElement {
+    /// The encoding of the RFC, the info is sent along with it
+    pub fn to_bytes(&self) -> [u8; NE] {
+        self.element.compress().to_bytes()
+    }
+
+    pub fn from_bytes(element: &[u8], info: &[u8]) -> Result<Self, WireError> {
+        Ok(Self {
+            element: decode(element, read_element)?,
+            metadata: Box::from(info),
+        })
+    }
+}
+
+impl crate::common::RandomizedUnsignedToken for BlindedElement {
+    fn metadata(&self) -> Box<[u8]> {
+        self.metadata.clone()
+    }
+}
+
+/// The response of the signer, the evaluated element and the proof
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EvaluatedElement {
+    #[serde(with = "point")]
+    element: RistrettoPoint,
+    proof: Proof,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
+}
+
+impl EvaluatedElement {
+    /// The encoding of the RFC, the proof is sent along with it
+    pub fn to_bytes(&self) -> [u8; NE] {
+        self.element.compress().to_bytes()
+    }
+
+    pub fn from_bytes(element: &[u8], proof: &[u8]) -> Result<Self, WireError> {
+        Ok(Self {
+            element: decode(element, read_element)?,
+            proof: Proof::from_bytes(proof)?,
+            key_epoch: None,
+        })
+    }
+
+    pub fn proof(&self) -> &Proof {
+        &self.proof
+    }
+}
+
+impl crate::common::RandomizedSignedToken for EvaluatedElement {
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
+// }}}
+
+// {{{ Signed token
+
+/// The input, the info and the unblinded element, of which the output is the hash
+#[derive(Serialize, Deserialize)]
+pub struct PoprfSignedToken<M: AsRef<[u8]>> {
+    input: [u8; 32],
+    metadata: M,
+    #[serde(with = "point")]
+    element: RistrettoPoint,
+}
+
+impl<M: AsRef<[u8]>> PoprfSignedToken<M> {
+    pub fn input(&self) -> &[u8; 32] {
+        &self.input
+    }
+
+    pub fn metadata(&self) -> &M {
+        &self.metadata
+    }
+
+    /// The output of the RFC, Finalize of the client
+    pub fn output(&self) -> Result<[u8; NH], Error> {
+        finalize(&self.input, self.metadata.as_ref(), &self.element)
+    }
+}
+
+impl<M: AsRef<[u8]>> SignedToken for PoprfSignedToken<M> {
+    type VerificationKey = PrivateKey;
+
+    fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
+        // the same as evaluate, without the hash of the output
+        tweaked_secret(verification_key, self.metadata.as_ref())
+            .is_ok_and(|t| self.element * t == hash_to_group(&self.input))
+    }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+}
+
+// }}}
+
+// {{{ Token engine
+
+/// The engine of the POPRF of RFC 9497
+pub struct PoprfTokenEngine<M: AsRef<[u8]>> {
+    _m: PhantomData<M>,
+}
+
+impl<M: AsRef<[u8]>> TokenEngine for PoprfTokenEngine<M> {
+    type UnsignedToken = PoprfUnsignedToken<M>;
+    type RandomizedUnsignedToken = BlindedElement;
+    type RandomizedSignedToken = EvaluatedElement;
+    type SignedToken = PoprfSignedToken<M>;
+    /// The inverse of the blind of the RFC
+    type Randomization = Scalar;
+    type UserVerification = PublicKey;
+    type SignKey = PrivateKey;
+
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        let (r, blind) = blinding::<Ristretto, _>(rng);
+        (
+            r,
+            BlindedElement {
+                element: hash_to_group(&unsigned_token.input) * blind,
+                metadata: Box::from(unsigned_token.metadata.as_ref()),
+            },
+        )
+    }
+
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned_token: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        let checked = check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )
+        .and_then(|()| tweak(unsigned_token.metadata.as_ref()))
+        .and_then(|m| {
+            let tweaked_key = &RISTRETTO_BASEPOINT_TABLE * &m + verification_data.to_affine();
+            if signed_token.proof.verify(
+                &tweaked_key,
+                &[signed_token.element],
+                &[randomized_unsigned_token.element],
+            ) {
+                Ok(())
+            } else {
+                Err(Error::BadProof)
+            }
+        });
+
+        match checked {
+            // Remove randomization
+            Ok(()) => Ok(PoprfSignedToken {
+                input: unsigned_token.input,
+                metadata: unsigned_token.metadata,
+                element: signed_token.element * randomization,
+            }),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
+        }
+    }
+
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        t_prime: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        let t = tweaked_secret(sign_key, &t_prime.metadata)?;
+        let element = t_prime.element * t.invert();
+        let proof = Proof::create(
+            t,
+            &(&RISTRETTO_BASEPOINT_TABLE * &t),
+            &[element],
+            &[t_prime.element],
+            Scalar::random(rng),
+        );
+
+        Ok(EvaluatedElement {
+            element,
+            proof,
+            key_epoch: None,
+        })
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    type Engine = PoprfTokenEngine<Vec<u8>>;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn parse_scalar(hex_scalar: &str) -> Scalar {
+        read_scalar(&mut Reader::new(&hex(hex_scalar))).unwrap()
+    }
+
+    fn parse_element(hex_element: &str) -> RistrettoPoint {
+        read_point(&mut Reader::new(&hex(hex_element))).unwrap()
+    }
+
+    /// The POPRF vectors of the ristretto255-SHA512 suite, A.1.3 of the RFC
+    #[test]
+    fn test_vectors() {
+        let seed: [u8; 32] = [0xa3; 32];
+        let key = derive_key_pair(&seed, b"test key").unwrap();
+        assert_eq!(
+            key.to_scalar(),
+            parse_scalar("145c79c108538421ac164ecbe131942136d5570b16d8bf41a24d4337da981e07")
+        );
+        assert_eq!(
+            PublicKey::from(&key).to_affine(),
+            parse_element("c647bef38497bc6ec077c22af65b696efa43bff3b4a1975a3e8e0a1c5a79d631")
+        );
+
+        let info = hex("7465737420696e666f");
+        let blind =
+            parse_scalar("64d37aed22a27f5191de1c1d69fadb899d8862b58eb4220029e036ec4c1f6706");
+        let proof_r =
+            parse_scalar("222a5e897cf59db8145db8d16e597e8facb80ae7d4e26d9881aa6f61d645fc0e");
+        let vectors = [
+            (
+                "00",
+                "c8713aa89241d6989ac142f22dba30596db635c772cbf25021fdd8f3d461f715",
+                "1a4b860d808ff19624731e67b5eff20ceb2df3c3c03b906f5693e2078450d874",
+                "41ad1a291aa02c80b0915fbfbb0c0afa15a57e2970067a602ddb9e8fd6b7100d\
+                 e32e1ecff943a36f0b10e3dae6bd266cdeb8adf825d86ef27dbc6c0e30c52206",
+                "ca688351e88afb1d841fde4401c79efebb2eb75e7998fa9737bd5a82a152406d\
+                 38bd29f680504e54fd4587eddcf2f37a2617ac2fbd2993f7bdf45442ace7d221",
+            ),
+            (
+                "5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a",
+                "f0f0b209dd4d5f1844dac679acc7761b91a2e704879656cb7c201e82a99ab07d",
+                "8c3c9d064c334c6991e99f286ea2301d1bde170b54003fb9c44c6d7bd6fc1540",
+                "4c39992d55ffba38232cdac88fe583af8a85441fefd7d1d4a8d0394cd1de7701\
+                 8bf135c174f20281b3341ab1f453fe72b0293a7398703384bed822bfdeec8908",
+                "7c6557b276a137922a0bcfc2aa2b35dd78322bd500235eb6d6b6f91bc5b56a52\
+                 de2d65612d503236b321f5d0bebcbc52b64b92e426f29c9b8b69f52de98ae507",
+            ),
+        ];
+
+        let t = tweaked_secret(&key, &info).unwrap();
+        let tweaked_key = &RISTRETTO_BASEPOINT_TABLE * &t;
+        for (input, blinded, evaluated, proof, output) in vectors.iter() {
+            let input = hex(input);
+            let blinded_element = hash_to_group(&input) * blind;
+            assert_eq!(blinded_element, parse_element(blinded));
+
+            let evaluated_element = blinded_element * t.invert();
+            assert_eq!(evaluated_element, parse_element(evaluated));
+
+            let created = Proof::create(
+                t,
+                &tweaked_key,
+                &[evaluated_element],
+                &[blinded_element],
+                proof_r,
+            );
+            assert_eq!(&created.to_bytes()[..], &hex(proof)[..]);
+            assert!(created.verify(&tweaked_key, &[evaluated_element], &[blinded_element]));
+
+            let unblinded = evaluated_element * blind.invert();
+            assert_eq!(
+                &finalize(&input, &info, &unblinded).unwrap()[..],
+                &hex(output)[..]
+            );
+            assert_eq!(
+                &evaluate(&key, &input, &info).unwrap()[..],
+                &hex(output)[..]
+            );
+        }
+
+        // the batch of both, with one proof
+        let inputs = [hex("00"), hex("5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a5a")];
+        let blinds = [blind, proof_r];
+        let blinded: Vec<_> = inputs
+            .iter()
+            .zip(blinds.iter())
+            .map(|(input, blind)| hash_to_group(input) * blind)
+            .collect();
+        let evaluated: Vec<_> = blinded.iter().map(|point| point * t.invert()).collect();
+        let proof = Proof::create(
+            t,
+            &tweaked_key,
+            &evaluated,
+            &blinded,
+            parse_scalar("419c4f4f5052c53c45f3da494d2b67b220d02118e0857cdbcf037f9ea84bbe0c"),
+        );
+        assert_eq!(
+            &proof.to_bytes()[..],
+            &hex(
+                "43fdb53be399cbd3561186ae480320caa2b9f36cca0e5b160c4a677b8bbf4301\
+                  b28f12c36aa8e11e5a7ef551da0781e863a6dc8c0b2bf5a149c9e00621f02006"
+            )[..]
+        );
+        assert!(proof.verify(&tweaked_key, &evaluated, &blinded));
+    }
+
+    #[test]
+    fn test_poprf() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let signed = Engine::sign(Engine::generate(b"info".to_vec()), &public_key, |blinded| {
+            let request = BlindedElement::from_bytes(&blinded.to_bytes(), b"info")?;
+            let evaluated = Engine::sign_randomized(&request, &private)?;
+            let response =
+                EvaluatedElement::from_bytes(&evaluated.to_bytes(), &evaluated.proof().to_bytes())?;
+            Ok(response)
+        })
+        .unwrap();
+        assert!(signed.verify(&private));
+        assert!(!signed.verify(&PrivateKey::new()));
+        assert_eq!(signed.output(), evaluate(&private, signed.input(), b"info"));
+
+        let json = serde_json::to_string(&signed).unwrap();
+        let decoded: PoprfSignedToken<Vec<u8>> = serde_json::from_str(&json).unwrap();
+        assert!(decoded.verify(&private));
+    }
+
+    #[test]
+    fn fail_poprf() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        // a response of another key
+        let result = Engine::sign(Engine::generate(b"info".to_vec()), &public_key, |blinded| {
+            Engine::sign_randomized(blinded, &PrivateKey::new())
+        });
+        assert_eq!(result.err(), Some(Error::BadProof));
+
+        // signed for another info
+        let result = Engine::sign(Engine::generate(b"info".to_vec()), &public_key, |blinded| {
+            let request = BlindedElement::from_bytes(&blinded.to_bytes(), b"other")?;
+            Engine::sign_randomized(&request, &private)
+        });
+        assert_eq!(result.err(), Some(Error::BadProof));
+
+        let unsigned = Engine::generate(b"info".to_vec());
+        let (_r, blinded) = Engine::randomize(&unsigned);
+        let bytes = blinded.to_bytes();
+        assert_eq!(
+            BlindedElement::from_bytes(&bytes[1..], b"info"),
+            Err(WireError::Truncated)
+        );
+        assert_eq!(
+            BlindedElement::from_bytes(&[&bytes[..], &[0]].concat(), b"info"),
+            Err(WireError::TrailingBytes)
+        );
+        assert_eq!(
+            BlindedElement::from_bytes(&[0; NE], b"info"),
+            Err(WireError::InvalidPoint)
+        );
+        assert_eq!(
+            Proof::from_bytes(&[0xff; 2 * NS]),
+            Err(WireError::InvalidPoint)
+        );
+        assert_eq!(
+            evaluate(&private, &[0; 0x10000], b"info"),
+            Err(Error::Malformed(WireError::MetadataLength))
+        );
+    }
+}
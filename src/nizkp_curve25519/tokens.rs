@@ -1,100 +1,89 @@
 use alloc::boxed::Box;
-use core::marker::PhantomData;
+use core::{convert::TryFrom, fmt, marker::PhantomData};
 
 use super::{
+    check_metadata, invertible,
     keys::{PrivateKey, PublicKey},
-    SignedToken, TokenEngine, TokenIdentifier, UnsignedToken,
+    Error, HasKeyId, KeyEpoch, KeyId, SignedToken, TokenEngine, TokenIdentifier, UnsignedToken,
+    VerifyError,
 };
 
-use sha2::{Digest, Sha512};
-use subtle::{Choice, CtOption};
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512Trunc256};
+use subtle::ConstantTimeEq;
 
-use super::util::{h_t, hash_to_scalar};
+use super::util::{h_t, h_t_with, hash_to_scalar, hash_to_scalar_with, point, proof, scalar, Ristretto};
+use crate::group::{blinding, prove_verification, sign_point, DleqProof};
+use crate::hash_suite::{HashSuite, Sha2};
+use crate::redemption::{Fingerprint, Nullifier, Redeemable};
+use crate::wire::{Ciphersuite, Reader, WireError, WireFormat, Writer};
 
 use curve25519_dalek::{
-    constants::{RISTRETTO_BASEPOINT_POINT, RISTRETTO_BASEPOINT_TABLE},
-    ristretto::RistrettoPoint,
+    constants::RISTRETTO_BASEPOINT_TABLE,
+    ristretto::{CompressedRistretto, RistrettoPoint},
     scalar::Scalar,
 };
 
 // {{{ DLEQProof
 
-#[derive(Clone)]
-struct DLEQProof {
-    c: Scalar,
-    z: Scalar,
-}
-
-impl DLEQProof {
-    fn hash_data(
-        u: &RistrettoPoint,
-        t: &RistrettoPoint,
-        w: &RistrettoPoint,
-        a: &RistrettoPoint,
-        b: &RistrettoPoint,
-    ) -> Scalar {
-        let mut hasher = Sha512::new();
-
-        // domain of the oracle, to have separate oracles
-        hasher.update(b"This is DLEQ_PROOF hash");
-
-        hasher.update(RISTRETTO_BASEPOINT_POINT.compress().as_bytes());
-        hasher.update(u.compress().as_bytes());
-        hasher.update(t.compress().as_bytes());
-        hasher.update(w.compress().as_bytes());
-        hasher.update(a.compress().as_bytes());
-        hasher.update(b.compress().as_bytes());
-
-        // Turn the bytes uniformly and deterministically into a scalar
-        Scalar::from_hash(hasher)
+impl DleqProof<Scalar> {
+    pub(crate) fn encode(&self, writer: &mut Writer) {
+        writer.fixed(self.c.as_bytes());
+        writer.fixed(self.z.as_bytes());
     }
 
-    /// Create a proof of the fact that log_w t = k
-    ///
-    /// If you create w=(d+k)^{-1} t, then create this proof with create(t, w, d + k)
-    pub fn create(t: RistrettoPoint, w: RistrettoPoint, k: Scalar) -> Self {
-        let r = Scalar::random(&mut rand::thread_rng());
-        let a = &RISTRETTO_BASEPOINT_TABLE * &r;
-        let b = w * r;
-
-        let c = DLEQProof::hash_data(&(&RISTRETTO_BASEPOINT_TABLE * &k), &t, &w, &a, &b);
-
-        let z = r - k * c;
+    pub(crate) fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            c: read_scalar(reader)?,
+            z: read_scalar(reader)?,
+        })
+    }
+}
 
-        Self { c, z }
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for DleqProof<Scalar> {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            c: Scalar::from_bytes_mod_order_wide(&u.arbitrary()?),
+            z: Scalar::from_bytes_mod_order_wide(&u.arbitrary()?),
+        })
     }
+}
 
-    /// Verify the proof that log_w t = k
-    ///
-    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
-    pub fn verify(
-        &self,
-        t: RistrettoPoint,
-        w: RistrettoPoint,
-        public_key: RistrettoPoint,
-    ) -> bool {
-        let a = &RISTRETTO_BASEPOINT_TABLE * &self.z + public_key * self.c;
-        let b = w * self.z + t * self.c;
-        let c = DLEQProof::hash_data(&public_key, &t, &w, &a, &b);
+pub(crate) fn read_point(reader: &mut Reader<'_>) -> Result<RistrettoPoint, WireError> {
+    CompressedRistretto(reader.fixed()?)
+        .decompress()
+        .ok_or(WireError::InvalidPoint)
+}
 
-        c == self.c
-    }
+pub(crate) fn read_scalar(reader: &mut Reader<'_>) -> Result<Scalar, WireError> {
+    Scalar::from_canonical_bytes(reader.fixed()?).ok_or(WireError::InvalidPoint)
 }
 
 // }}}
 
 // {{{ UnsignedToken
 
+#[derive(Serialize, Deserialize)]
 pub struct NizkpUnsignedToken<M: AsRef<[u8]>> {
     id: TokenIdentifier<M>,
     metadata: M,
 }
 
 impl<M: AsRef<[u8]>> NizkpUnsignedToken<M> {
+    /// A token with a chosen identifier, for formats that make the identifier themselves
+    pub(crate) fn from_parts(id: TokenIdentifier<M>, metadata: M) -> Self {
+        Self { id, metadata }
+    }
+
     pub fn get_point(&self) -> RistrettoPoint {
-        let t: [u8; 16] = (&self.id).into();
+        self.point_with::<Sha2>()
+    }
 
-        h_t(t, &self.metadata)
+    /// The point of the token, hashed with the suite
+    pub(crate) fn point_with<H: HashSuite>(&self) -> RistrettoPoint {
+        h_t_with::<H>(self.id.as_bytes(), &self.metadata)
     }
 }
 
@@ -102,16 +91,20 @@ impl<M: AsRef<[u8]>> UnsignedToken for NizkpUnsignedToken<M> {
     type Metadata = M;
     type HiddenMetadata = M;
 
-    fn new(metadata: Self::Metadata) -> Self {
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
         Self {
-            id: TokenIdentifier::new(),
+            id: TokenIdentifier::new_with_rng(rng),
             metadata,
         }
     }
 
-    fn with_hidden(metadata: Self::Metadata, hidden: Self::HiddenMetadata) -> Self {
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
         Self {
-            id: TokenIdentifier::with_hidden(hidden),
+            id: TokenIdentifier::with_hidden_with_rng(hidden, rng),
             metadata,
         }
     }
@@ -121,84 +114,689 @@ impl<M: AsRef<[u8]>> UnsignedToken for NizkpUnsignedToken<M> {
 
 // {{{   Randomized signed
 
+#[derive(Serialize, Deserialize)]
 pub struct RandomizedSignedToken<M: AsRef<[u8]>> {
+    #[serde(with = "point")]
     point: RistrettoPoint,
-    proof: DLEQProof,
+    #[serde(with = "proof")]
+    proof: DleqProof<Scalar>,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
     _m: PhantomData<M>,
 }
 
+impl<M: AsRef<[u8]>> RandomizedSignedToken<M> {
+    pub(crate) fn from_parts(point: RistrettoPoint, proof: DleqProof<Scalar>) -> Self {
+        Self {
+            point,
+            proof,
+            key_epoch: None,
+            _m: PhantomData {},
+        }
+    }
+
+    pub(crate) fn point(&self) -> RistrettoPoint {
+        self.point
+    }
+
+    pub(crate) fn proof(&self) -> &DleqProof<Scalar> {
+        &self.proof
+    }
+}
+
+impl<M: AsRef<[u8]>> crate::common::RandomizedSignedToken for RandomizedSignedToken<M> {
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
+impl<M: AsRef<[u8]>> WireFormat for RandomizedSignedToken<M> {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x13;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.point.compress().as_bytes());
+        self.proof.encode(writer);
+        writer.key_epoch(self.key_epoch);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            point: read_point(reader)?,
+            proof: DleqProof::decode(reader)?,
+            key_epoch: reader.key_epoch()?,
+            _m: PhantomData {},
+        })
+    }
+}
+
 // }}}
 
 // {{{ randomized unsigned
 
+#[derive(Serialize, Deserialize)]
 pub struct RandomizedUnsignedToken<M: AsRef<[u8]>> {
+    #[serde(with = "point")]
     point: RistrettoPoint,
     metadata: Box<[u8]>,
     _m: PhantomData<M>,
 }
 
+impl<M: AsRef<[u8]>> RandomizedUnsignedToken<M> {
+    pub(crate) fn from_parts(point: RistrettoPoint, metadata: Box<[u8]>) -> Self {
+        Self {
+            point,
+            metadata,
+            _m: PhantomData {},
+        }
+    }
+
+    pub(crate) fn point(&self) -> RistrettoPoint {
+        self.point
+    }
+}
+
 impl<M: AsRef<[u8]>> crate::common::RandomizedUnsignedToken for RandomizedUnsignedToken<M> {
     fn metadata(&self) -> Box<[u8]> {
         self.metadata.clone()
     }
 }
 
+impl<M: AsRef<[u8]>> WireFormat for RandomizedUnsignedToken<M> {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x12;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.point.compress().as_bytes());
+        writer.prefixed(&self.metadata)
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            point: read_point(reader)?,
+            metadata: Box::from(reader.prefixed()?),
+            _m: PhantomData {},
+        })
+    }
+}
+
 // }}}
 
 // {{{ Signed token
 
-pub struct NizkpSignedToken<M: AsRef<[u8]>> {
+/// The integrity tag of a signed token does not match the token
+///
+/// The tag is an unkeyed hash of the id, the metadata and the point, so it catches tokens that
+/// were corrupted in storage or transit, or put together from the parts of other tokens by
+/// mistake. Anyone can compute the tag of any fields, so it does not protect against an attacker:
+/// the signature does, as the metadata is part of what [`SignedToken::verify`] checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityError;
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the token is corrupted, its integrity tag does not match its fields")
+    }
+}
+
+/// The fields of a signed token, the integrity tag is checked once, before this becomes a token
+#[derive(Deserialize)]
+struct NizkpSignedTokenFields<M: AsRef<[u8]>> {
+    id: TokenIdentifier<M>,
+    metadata: M,
+    #[serde(with = "point")]
+    point: RistrettoPoint,
+    tag: [u8; 32],
+    #[serde(default)]
+    key_id: Option<KeyId>,
+}
+
+impl<M: AsRef<[u8]>, H> TryFrom<NizkpSignedTokenFields<M>> for NizkpSignedToken<M, H> {
+    type Error = IntegrityError;
+
+    fn try_from(fields: NizkpSignedTokenFields<M>) -> Result<Self, IntegrityError> {
+        let token = Self {
+            id: fields.id,
+            metadata: fields.metadata,
+            point: fields.point,
+            tag: fields.tag,
+            key_id: fields.key_id,
+            _h: PhantomData,
+        };
+
+        token.check_integrity().map(|_| token)
+    }
+}
+
+/// A signed token, whose points are hashed with the suite H, see [`hash_suite`](crate::hash_suite)
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "NizkpSignedTokenFields<M>")]
+pub struct NizkpSignedToken<M: AsRef<[u8]>, H = Sha2> {
     id: TokenIdentifier<M>,
     metadata: M,
+    #[serde(with = "point")]
     point: RistrettoPoint,
+    tag: [u8; 32],
+    /// The key the user unrandomized the token with, this is not covered by the integrity tag
+    key_id: Option<KeyId>,
+    #[serde(skip)]
+    _h: PhantomData<H>,
 }
 
-impl<M: AsRef<[u8]>> SignedToken for NizkpSignedToken<M> {
+impl<M: AsRef<[u8]>, H: HashSuite> SignedToken for NizkpSignedToken<M, H> {
     type VerificationKey = PrivateKey;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
-        let t: [u8; 16] = (&self.id).into();
-        let t = h_t(t, &self.metadata);
+        // the integrity tag was checked when the token was decoded, and the metadata is bound by
+        // the signature itself
+        let t = h_t_with::<H>(self.id.as_bytes(), &self.metadata);
 
         // We may do this, since
         // w == e * t is the same as e^-1 w == t
         // We then do not need to do the inversion step, and maybe it could be easier to build
         // batch verification
-        let e_inverse = hash_to_scalar(&self.metadata) + verification_key.to_scalar();
+        let e_inverse = hash_to_scalar_with::<H>(&self.metadata) + verification_key.to_scalar();
 
         let signed = self.point * e_inverse;
 
         signed == t
     }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn hidden_metadata(&self) -> Option<&[u8]> {
+        self.id.hidden().map(AsRef::as_ref)
+    }
+
+    fn verify_with_hidden(
+        &self,
+        verification_key: &Self::VerificationKey,
+        expected_hidden: &[u8],
+    ) -> bool {
+        bool::from(self.id.is_hidden(expected_hidden)) && self.verify(verification_key)
+    }
+
+    fn key_id(&self) -> Option<KeyId> {
+        self.key_id
+    }
+}
+
+/// A token of arbitrary fields, with the integrity tag so it decodes, that does not verify
+#[cfg(feature = "arbitrary")]
+impl<'a, M, H> arbitrary::Arbitrary<'a> for NizkpSignedToken<M, H>
+where
+    M: AsRef<[u8]> + arbitrary::Arbitrary<'a>,
+{
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let id = u.arbitrary()?;
+        let metadata = u.arbitrary()?;
+        let point = RistrettoPoint::from_uniform_bytes(&u.arbitrary()?);
+        Ok(Self {
+            tag: integrity_tag(&id, &metadata, &point),
+            id,
+            metadata,
+            point,
+            key_id: u.arbitrary()?,
+            _h: PhantomData,
+        })
+    }
+}
+
+impl<M: AsRef<[u8]>, H: HashSuite> Redeemable for NizkpSignedToken<M, H> {
+    fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of_token(&self.id, self.metadata.as_ref())
+    }
+
+    fn nullifier(&self) -> Nullifier {
+        Nullifier::of_token(
+            &self.id,
+            self.point.compress().as_bytes(),
+            self.metadata.as_ref(),
+        )
+    }
+}
+
+impl<M: AsRef<[u8]> + for<'a> From<&'a [u8]>, H> WireFormat for NizkpSignedToken<M, H> {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x11;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.id(&self.id)?;
+        writer.prefixed(&self.metadata)?;
+        writer.fixed(self.point.compress().as_bytes());
+        writer.fixed(self.tag);
+        writer.key_id(self.key_id);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        let token = Self {
+            id: reader.id()?,
+            metadata: M::from(reader.prefixed()?),
+            point: read_point(reader)?,
+            tag: reader.fixed()?,
+            key_id: reader.key_id()?,
+            _h: PhantomData,
+        };
+
+        token
+            .check_integrity()
+            .map(|_| token)
+            .map_err(|_e| WireError::Integrity)
+    }
+}
+
+impl<M: AsRef<[u8]> + for<'a> From<&'a [u8]>> crate::http::HeaderToken for NizkpSignedToken<M> {
+    const ENGINE_ID: &'static str = "curve25519";
+}
+
+impl<M: AsRef<[u8]>, H> NizkpSignedToken<M, H> {
+    pub(crate) fn from_parts(
+        id: TokenIdentifier<M>,
+        metadata: M,
+        point: RistrettoPoint,
+        key_id: Option<KeyId>,
+    ) -> Self {
+        let tag = integrity_tag(&id, &metadata, &point);
+        Self {
+            id,
+            metadata,
+            point,
+            tag,
+            key_id,
+            _h: PhantomData,
+        }
+    }
+
+    /// Check that the id, metadata and point are the ones the token was created with
+    ///
+    /// This catches accidental corruption only, see [`IntegrityError`]. It is checked when a token
+    /// is decoded, so a token that exists has a matching tag.
+    pub fn check_integrity(&self) -> Result<(), IntegrityError> {
+        let tag = integrity_tag(&self.id, &self.metadata, &self.point);
+
+        if bool::from(tag.ct_eq(&self.tag)) {
+            Ok(())
+        } else {
+            Err(IntegrityError)
+        }
+    }
+
+    pub fn metadata(&self) -> &M {
+        &self.metadata
+    }
+
+    pub(crate) fn point(&self) -> RistrettoPoint {
+        self.point
+    }
+
+    /// The identifier, with the hidden metadata if there is some
+    pub fn id(&self) -> &TokenIdentifier<M> {
+        &self.id
+    }
+}
+
+impl<M: AsRef<[u8]>> NizkpSignedToken<M> {
+    /// Redeem the token for some data, e.g. a report
+    ///
+    /// The signature is not sent, but used as the key of a MAC over the data.
+    /// Anyone who sees the redemption can therefore not redeem the token for other data.
+    pub fn bind(&self, data: impl AsRef<[u8]>) -> BoundRedemption<M>
+    where
+        M: Clone,
+    {
+        BoundRedemption {
+            id: (&self.id).into(),
+            metadata: self.metadata.clone(),
+            mac: redemption_mac(&self.point, data),
+        }
+    }
+}
+
+fn integrity_tag<M: AsRef<[u8]>>(
+    id: &TokenIdentifier<M>,
+    metadata: &M,
+    point: &RistrettoPoint,
+) -> [u8; 32] {
+    let t: [u8; 16] = id.into();
+    let metadata = metadata.as_ref();
+
+    let mut hasher = Sha512Trunc256::new();
+    hasher.update(b"This is integrity_tag hash");
+    hasher.update(t);
+    // the length separates the metadata from the point
+    hasher.update((metadata.len() as u64).to_be_bytes());
+    hasher.update(metadata);
+    hasher.update(point.compress().as_bytes());
+
+    hasher.finalize().into()
+}
+
+// }}}
+
+// {{{ Bound redemption
+
+/// A token redeemed for some data, see [`NizkpSignedToken::bind`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BoundRedemption<M: AsRef<[u8]>> {
+    pub id: [u8; 16],
+    pub metadata: M,
+    pub mac: [u8; 32],
+}
+
+impl<M: AsRef<[u8]> + for<'a> TryFrom<&'a [u8]>> WireFormat for BoundRedemption<M> {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x1a;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.id);
+        writer.prefixed(&self.metadata)?;
+        writer.fixed(self.mac);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            id: reader.fixed()?,
+            // e.g. the metadata of the anti-abuse preset is exactly 4 bytes
+            metadata: M::try_from(reader.prefixed()?).map_err(|_e| WireError::NonCanonical)?,
+            mac: reader.fixed()?,
+        })
+    }
+}
+
+impl<M: AsRef<[u8]> + for<'a> TryFrom<&'a [u8]>> crate::http::HeaderToken for BoundRedemption<M> {
+    const ENGINE_ID: &'static str = "curve25519-bound";
+}
+
+impl<M: AsRef<[u8]>> BoundRedemption<M> {
+    /// Verify that the token is signed, and that it was redeemed for this data
+    pub fn verify(&self, verification_key: &PrivateKey, data: impl AsRef<[u8]>) -> bool {
+        // recreate the signature, w = (d + k)^{-1} t
+        let e = (hash_to_scalar(&self.metadata) + verification_key.to_scalar()).invert();
+        let w = h_t(self.id, &self.metadata) * e;
+
+        bool::from(redemption_mac(&w, data).ct_eq(&self.mac))
+    }
+}
+
+fn redemption_mac(w: &RistrettoPoint, data: impl AsRef<[u8]>) -> [u8; 32] {
+    // truncated sha512 is not vulnerable to length extension
+    let mut hasher = Sha512Trunc256::new();
+    hasher.update(b"This is redemption_mac hash");
+    hasher.update(w.compress().as_bytes());
+    hasher.update(data);
+
+    hasher.finalize().into()
+}
+
+// }}}
+
+// {{{ Verification proof
+
+/// A proof of the outcome of verifying a token, that anyone with the public key can check
+///
+/// The verifier needs the private key to verify a token, so the user can not tell whether a token
+/// was rejected honestly. The verifier shows the point t' = (d + k) w that it compares to the hash
+/// of the token, with a proof that it was computed with the key of the public key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct VerificationProof {
+    #[serde(with = "point")]
+    point: RistrettoPoint,
+    #[serde(with = "proof")]
+    proof: DleqProof<Scalar>,
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for VerificationProof {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            point: RistrettoPoint::from_uniform_bytes(&u.arbitrary()?),
+            proof: u.arbitrary()?,
+        })
+    }
+}
+
+impl VerificationProof {
+    /// Whether the token verified, or none if this is not a proof for the token and public key
+    pub fn check<M: AsRef<[u8]>, H: HashSuite>(
+        &self,
+        token: &NizkpSignedToken<M, H>,
+        public_key: &PublicKey,
+    ) -> Option<bool> {
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar_with::<H>(&token.metadata)
+            + public_key.to_affine();
+
+        if !self
+            .proof
+            .verify_with_domain::<Ristretto>(self.point, token.point, u, &H::DOMAIN)
+        {
+            return None;
+        }
+
+        Some(self.point == h_t_with::<H>(token.id.as_bytes(), &token.metadata))
+    }
+}
+
+impl WireFormat for VerificationProof {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x15;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.point.compress().as_bytes());
+        self.proof.encode(writer);
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            point: read_point(reader)?,
+            proof: DleqProof::decode(reader)?,
+        })
+    }
+}
+
+impl<M: AsRef<[u8]>, H: HashSuite> NizkpSignedToken<M, H> {
+    /// Verify the token, and prove the outcome to anyone with the public key
+    pub fn verify_with_proof(&self, verification_key: &PrivateKey) -> (bool, VerificationProof) {
+        let (point, proof) = prove_verification::<Ristretto>(
+            self.point,
+            hash_to_scalar_with::<H>(&self.metadata),
+            verification_key.to_scalar(),
+            &H::DOMAIN,
+        );
+
+        let valid = point == h_t_with::<H>(self.id.as_bytes(), &self.metadata);
+
+        (valid, VerificationProof { point, proof })
+    }
+}
+
+// }}}
+
+// {{{ Issuance proof
+
+/// The proof of the signer from the issuance of a token, that anyone with the public key can check
+///
+/// The verifier needs the private key to verify a token, but this shows an auditor with only the
+/// public key that the token was signed with the key of the public key. It has the randomized
+/// points and the randomization of the token, so it links the token to the request it was signed
+/// for: it is for the auditor, and is not sent to the verifier along with the token.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct IssuanceProof {
+    #[serde(with = "point")]
+    randomized: RistrettoPoint,
+    #[serde(with = "point")]
+    signed: RistrettoPoint,
+    #[serde(with = "proof")]
+    proof: DleqProof<Scalar>,
+    #[serde(with = "scalar")]
+    randomization: Scalar,
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for IssuanceProof {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self {
+            randomized: RistrettoPoint::from_uniform_bytes(&u.arbitrary()?),
+            signed: RistrettoPoint::from_uniform_bytes(&u.arbitrary()?),
+            proof: u.arbitrary()?,
+            randomization: Scalar::from_bytes_mod_order_wide(&u.arbitrary()?),
+        })
+    }
+}
+
+impl IssuanceProof {
+    /// Whether the signer proved that it signed the token with the key of the public key
+    pub fn check<M: AsRef<[u8]>, H: HashSuite>(
+        &self,
+        token: &NizkpSignedToken<M, H>,
+        public_key: &PublicKey,
+    ) -> bool {
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar_with::<H>(&token.metadata)
+            + public_key.to_affine();
+
+        // the randomized points are the points of the token
+        let is_token = self.randomized * self.randomization
+                == h_t_with::<H>(token.id.as_bytes(), &token.metadata)
+            && self.signed * self.randomization == token.point;
+
+        is_token
+            && self.proof.verify_with_domain::<Ristretto>(
+                self.randomized,
+                self.signed,
+                u,
+                &H::DOMAIN,
+            )
+    }
+}
+
+impl WireFormat for IssuanceProof {
+    const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+    const TYPE: u8 = 0x18;
+
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+        writer.fixed(self.randomized.compress().as_bytes());
+        writer.fixed(self.signed.compress().as_bytes());
+        self.proof.encode(writer);
+        writer.fixed(self.randomization.as_bytes());
+        Ok(())
+    }
+
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+        Ok(Self {
+            randomized: read_point(reader)?,
+            signed: read_point(reader)?,
+            proof: DleqProof::decode(reader)?,
+            randomization: read_scalar(reader)?,
+        })
+    }
+}
+
+impl<M: AsRef<[u8]>, H: HashSuite> HashedNizkpTokenEngine<M, H> {
+    /// [`TokenEngine::verify_signature_and_unrandomize`], keeping the proof of the signer for an
+    /// auditor, see [`IssuanceProof`]
+    // the error gives back the token, as the one of the trait
+    #[allow(clippy::result_large_err)]
+    pub fn verify_signature_and_unrandomize_with_proof(
+        unsigned_token: NizkpUnsignedToken<M>,
+        randomized_unsigned_token: RandomizedUnsignedToken<M>,
+        signed_token: RandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+        randomization: Scalar,
+    ) -> Result<(NizkpSignedToken<M, H>, IssuanceProof), VerifyError<Self>> {
+        let proof = IssuanceProof {
+            randomized: randomized_unsigned_token.point,
+            signed: signed_token.point,
+            proof: signed_token.proof,
+            randomization,
+        };
+
+        Self::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized_unsigned_token,
+            signed_token,
+            verification_data,
+            randomization,
+        )
+        .map(|token| (token, proof))
+    }
 }
 
 // }}}
 
 // {{{ Token engine
 
-pub struct NizkpTokenEngine<M: AsRef<[u8]>> {
+/// The engine of the tokens, with the points hashed with the suite H, see
+/// [`hash_suite`](crate::hash_suite)
+pub struct HashedNizkpTokenEngine<M: AsRef<[u8]>, H> {
     _m: PhantomData<M>,
+    _h: PhantomData<H>,
+}
+
+/// The engine of the tokens, with the points hashed with SHA-512
+pub type NizkpTokenEngine<M> = HashedNizkpTokenEngine<M, Sha2>;
+
+impl<M: AsRef<[u8]>, H: HashSuite> HashedNizkpTokenEngine<M, H> {
+    /// Check the proof of the signer against the randomized token
+    fn check_proof(
+        unsigned_token: &NizkpUnsignedToken<M>,
+        randomized_unsigned_token: &RandomizedUnsignedToken<M>,
+        signed_token: &RandomizedSignedToken<M>,
+        verification_data: &PublicKey,
+    ) -> Result<(), Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // get the public key
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar_with::<H>(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        // verify proof
+        if signed_token.proof.verify_with_domain::<Ristretto>(
+            randomized_unsigned_token.point,
+            signed_token.point,
+            u,
+            &H::DOMAIN,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::BadProof)
+        }
+    }
 }
 
-impl<M: AsRef<[u8]>> TokenEngine for NizkpTokenEngine<M> {
+impl<M: AsRef<[u8]>, H: HashSuite> TokenEngine for HashedNizkpTokenEngine<M, H> {
     type UnsignedToken = NizkpUnsignedToken<M>;
     type RandomizedUnsignedToken = RandomizedUnsignedToken<M>;
     type RandomizedSignedToken = RandomizedSignedToken<M>;
-    type SignedToken = NizkpSignedToken<M>;
+    type SignedToken = NizkpSignedToken<M, H>;
     type Randomization = Scalar;
     type UserVerification = PublicKey;
     type SignKey = PrivateKey;
 
     //For batched tokens we generate a seed for an rng to reduce memory usage. It had to be verified that all scalars are invertible
-    fn randomize(
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
         unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
-        let r = Scalar::random(&mut rand::thread_rng());
-        let inverse = r.invert();
+        let (r, inverse) = blinding::<Ristretto, _>(rng);
         (
             r,
             Self::RandomizedUnsignedToken {
-                point: unsigned_token.get_point() * inverse,
+                point: unsigned_token.point_with::<H>() * inverse,
                 metadata: Box::from(unsigned_token.metadata.as_ref()),
                 _m: PhantomData {},
             },
@@ -211,44 +809,52 @@ impl<M: AsRef<[u8]>> TokenEngine for NizkpTokenEngine<M> {
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken> {
-        // get the public key
-        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(&unsigned_token.metadata)
-            + verification_data.to_affine();
-
-        // verify proof
-        if signed_token
-            .proof
-            .verify(randomized_unsigned_token.point, signed_token.point, u)
-        {
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::check_proof(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+        ) {
             // Remove randomization
-            Some(Self::SignedToken {
-                point: signed_token.point * randomization,
-                metadata: unsigned_token.metadata,
-                id: unsigned_token.id,
-            })
-        } else {
-            None
+            Ok(()) => Ok(Self::SignedToken::from_parts(
+                unsigned_token.id,
+                unsigned_token.metadata,
+                signed_token.point * randomization,
+                Some(verification_data.key_id()),
+            )),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
         }
     }
 
-    fn sign_randomized(
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
         t_prime: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> CtOption<Self::RandomizedSignedToken> {
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
         // This should be a constant time implementation
-        let d = hash_to_scalar(&t_prime.metadata);
-        let e = (d + sign_key.to_scalar()).invert();
-
-        let w = t_prime.point * e;
-
-        CtOption::new(
-            Self::RandomizedSignedToken {
-                point: w,
-                proof: DLEQProof::create(t_prime.point, w, d + sign_key.to_scalar()),
-                _m: PhantomData {},
-            },
-            Choice::from(1),
+        let d = hash_to_scalar_with::<H>(&t_prime.metadata);
+
+        invertible(
+            sign_point::<Ristretto>(t_prime.point, d, sign_key.to_scalar()).map(|w| {
+                Self::RandomizedSignedToken {
+                    point: w,
+                    proof: DleqProof::create_with_domain::<Ristretto, _>(
+                        t_prime.point,
+                        w,
+                        d + sign_key.to_scalar(),
+                        &H::DOMAIN,
+                        rng,
+                    ),
+                    key_epoch: None,
+                    _m: PhantomData {},
+                }
+            }),
         )
     }
 }
@@ -260,12 +866,17 @@ impl<M: AsRef<[u8]>> TokenEngine for NizkpTokenEngine<M> {
 #[cfg(test)]
 mod tests {
     use super::super::keys::{PrivateKey, PublicKey};
+    use super::super::{KeyRing, PublicKeySet, RandomizedSignedToken as _};
     use super::*;
+    use crate::expiry::Metadata;
+    use crate::hash_suite::DomainParams;
+    use alloc::{string::ToString, vec::Vec};
+    use sha2::digest::{consts::U64, FixedOutput, Output, Reset, Update};
 
     #[test]
     fn test_proof() {
         // setup
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
 
         // create keys
         let private_key = Scalar::random(&mut rng);
@@ -286,10 +897,10 @@ mod tests {
         let w = t * e;
 
         // create proof
-        let proof = DLEQProof::create(t, w, private_key + d);
+        let proof = DleqProof::create::<Ristretto>(t, w, private_key + d);
 
         // verify
-        assert!(proof.verify(t, w, u));
+        assert!(proof.verify::<Ristretto>(t, w, u));
     }
 
     #[test]
@@ -316,12 +927,46 @@ mod tests {
             &public_key,
             r,
         );
-        assert!(signed.is_some());
+        assert!(signed.is_ok());
 
         // verify personalized token
         assert!(signed.unwrap().verify(&private));
     }
 
+    #[test]
+    fn test_issuance_proof() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let issue = |metadata: &'static [u8]| {
+            let token = NizkpTokenEngine::generate(metadata);
+            let (r, randomized) = NizkpTokenEngine::randomize(&token);
+            let signed = NizkpTokenEngine::sign_randomized(&randomized, &private).unwrap();
+            NizkpTokenEngine::verify_signature_and_unrandomize_with_proof(
+                token,
+                randomized,
+                signed,
+                &public_key,
+                r,
+            )
+            .ok()
+            .unwrap()
+        };
+
+        let (token, proof) = issue(b"metadata");
+        assert!(token.verify(&private));
+        assert!(proof.check(&token, &public_key));
+        assert!(IssuanceProof::from_bytes(&proof.to_bytes())
+            .unwrap()
+            .check(&token, &public_key));
+
+        // the proof is for the key and the token
+        assert!(!proof.check(&token, &PublicKey::from(&PrivateKey::new())));
+        let (other, other_proof) = issue(b"metadata");
+        assert!(!proof.check(&other, &public_key));
+        assert!(other_proof.check(&other, &public_key));
+    }
+
     #[test]
     fn test_hidden() {
         // generate keys
@@ -348,10 +993,46 @@ mod tests {
             r,
         );
 
-        assert!(signed.is_some());
+        assert!(signed.is_ok());
 
         // verify personalized token
-        assert!(signed.unwrap().verify(&private));
+        let signed = signed.unwrap();
+        assert!(signed.verify(&private));
+
+        // the verifier sees the hidden metadata, and checks that it is the expected
+        assert_eq!(signed.hidden_metadata(), Some(&hidden_metadata[..]));
+        assert!(signed.verify_with_hidden(&private, hidden_metadata));
+        assert!(!signed.verify_with_hidden(&private, b"other hidden metadata"));
+    }
+
+    #[test]
+    fn test_deterministic() {
+        use rand::{prelude::StdRng, SeedableRng};
+
+        let run = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let private = PrivateKey::new_with_rng(&mut rng);
+            let public_key = PublicKey::from(&private);
+
+            let token = NizkpTokenEngine::generate_with_rng(Box::from(&b"metadata"[..]), &mut rng);
+            let (r, anon_token) = NizkpTokenEngine::randomize_with_rng(&token, &mut rng);
+            let signed =
+                NizkpTokenEngine::sign_randomized_with_rng(&anon_token, &private, &mut rng)
+                    .unwrap();
+            NizkpTokenEngine::verify_signature_and_unrandomize(
+                token,
+                anon_token,
+                signed,
+                &public_key,
+                r,
+            )
+            .unwrap()
+            .to_bytes()
+        };
+
+        // the same seed gives the same token, down to the nonce of the proof
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
     }
 
     #[test]
@@ -370,7 +1051,34 @@ mod tests {
             NizkpTokenEngine::sign_randomized(randomized, &bad)
         });
 
-        assert!(signed.is_none());
+        assert!(matches!(signed, Err(Error::BadProof)));
+    }
+
+    #[test]
+    fn fail_metadata_mismatch() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        // the user mixes up two of its tokens
+        let token = NizkpTokenEngine::generate(&b"one"[..]);
+        let other = NizkpTokenEngine::generate(&b"other"[..]);
+        let (r, request) = NizkpTokenEngine::randomize(&other);
+        let response = NizkpTokenEngine::sign_randomized(&request, &private).unwrap();
+
+        let signed = NizkpTokenEngine::verify_signature_and_unrandomize(
+            token,
+            request,
+            response,
+            &public_key,
+            r,
+        );
+        assert!(matches!(
+            signed,
+            Err(VerifyError {
+                error: Error::MetadataMismatch,
+                ..
+            })
+        ));
     }
 
     #[test]
@@ -392,6 +1100,457 @@ mod tests {
 
         assert!(!signed.verify(&bad));
     }
+
+    #[test]
+    fn test_verification_proof() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(&b"This is my metadata"[..]),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+
+        let (valid, proof) = signed.verify_with_proof(&private);
+        assert!(valid);
+        assert_eq!(proof.check(&signed, &public_key), Some(true));
+
+        let decoded = VerificationProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert_eq!(decoded, proof);
+
+        // a verifier with another key honestly rejects the token
+        let other = PrivateKey::new();
+        let (valid, rejected) = signed.verify_with_proof(&other);
+        assert!(!valid);
+        assert_eq!(
+            rejected.check(&signed, &PublicKey::from(&other)),
+            Some(false)
+        );
+
+        // but can not claim to have used the right key
+        assert_eq!(rejected.check(&signed, &public_key), None);
+    }
+
+    #[test]
+    fn fail_verification_proof_other_token() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let sign = || {
+            NizkpTokenEngine::sign(
+                NizkpTokenEngine::generate(&b"This is my metadata"[..]),
+                &public_key,
+                |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+            )
+            .unwrap()
+        };
+        let (first, second) = (sign(), sign());
+
+        let (_, proof) = first.verify_with_proof(&private);
+        assert_eq!(proof.check(&second, &public_key), None);
+    }
+
+    #[test]
+    fn test_verify_with_time() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let metadata = Metadata::new(1000, b"This is my metadata");
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(metadata),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+
+        assert!(signed.verify_with_time(&private, 999));
+        assert!(!signed.verify_with_time(&private, 1000));
+        assert!(!signed.verify_with_time(&PrivateKey::new(), 999));
+
+        // metadata without an expiration time never verifies with a time
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(&b"short"[..]),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+        assert!(signed.verify(&private));
+        assert!(!signed.verify_with_time(&private, 0));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let private = PrivateKey::new();
+        let public_key: PublicKey =
+            serde_json::from_str(&serde_json::to_string(&PublicKey::from(&private)).unwrap())
+                .unwrap();
+
+        // the user sends the randomized token to the signer
+        let token = NizkpTokenEngine::generate(Box::from(&b"This is my metadata"[..]));
+        let (r, anon_token) = NizkpTokenEngine::randomize(&token);
+        let request = serde_json::to_string(&anon_token).unwrap();
+
+        // the signer sends back the signature
+        let request: RandomizedUnsignedToken<Box<[u8]>> = serde_json::from_str(&request).unwrap();
+        let signed = NizkpTokenEngine::sign_randomized(&request, &private).unwrap();
+        let response = serde_json::to_string(&signed).unwrap();
+
+        let signed = serde_json::from_str(&response).unwrap();
+        let signed = NizkpTokenEngine::verify_signature_and_unrandomize(
+            token,
+            anon_token,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap();
+
+        // the user sends the signed token to the verifier
+        let signed: NizkpSignedToken<Box<[u8]>> =
+            serde_json::from_str(&serde_json::to_string(&signed).unwrap()).unwrap();
+        assert!(signed.verify(&private));
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from_bytes(&PublicKey::from(&private).to_bytes()).unwrap();
+
+        let token = NizkpTokenEngine::generate(Box::from(&b"This is my metadata"[..]));
+        let (r, anon_token) = NizkpTokenEngine::randomize(&token);
+
+        // the signer only sees the bytes
+        let request: RandomizedUnsignedToken<Box<[u8]>> =
+            RandomizedUnsignedToken::from_bytes(&anon_token.to_bytes()).unwrap();
+        let signed = NizkpTokenEngine::sign_randomized(&request, &private)
+            .unwrap()
+            .with_key_epoch(4);
+        let signed = RandomizedSignedToken::from_bytes(&signed.to_bytes()).unwrap();
+        assert_eq!(signed.key_epoch(), Some(4));
+
+        let signed = NizkpTokenEngine::verify_signature_and_unrandomize(
+            token,
+            anon_token,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap();
+
+        let bytes = signed.to_bytes();
+        let decoded = NizkpSignedToken::<Box<[u8]>>::from_bytes(&bytes).unwrap();
+        assert!(decoded.verify(&private));
+        assert_eq!(decoded.key_id(), Some(public_key.key_id()));
+
+        // a token is not a public key
+        assert_eq!(PublicKey::from_bytes(&bytes).err(), Some(WireError::Type));
+    }
+
+    #[test]
+    fn fail_malformed() {
+        let private = PrivateKey::new();
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(Box::from(&b"metadata"[..])),
+            &PublicKey::from(&private),
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+        let bytes = signed.to_bytes();
+        let decode = NizkpSignedToken::<Box<[u8]>>::from_bytes;
+
+        for len in 0..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "decoded {} bytes", len);
+        }
+
+        // only the key id is not signed
+        for i in 0..bytes.len() {
+            let mut bad = bytes.clone();
+            bad[i] ^= 0xff;
+            if let Ok(token) = decode(&bad) {
+                assert!(
+                    !token.verify(&private) || token.key_id() != signed.key_id(),
+                    "byte {} is not signed",
+                    i
+                );
+            }
+        }
+
+        let (_valid, proof) = signed.verify_with_proof(&private);
+        let proof = proof.to_bytes();
+        for len in 0..proof.len() {
+            assert!(VerificationProof::from_bytes(&proof[..len]).is_err());
+        }
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn test_arbitrary() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let private = PrivateKey::new();
+        for _ in 0..32 {
+            let mut entropy = [0; 256];
+            crate::rng::default_rng().fill_bytes(&mut entropy);
+            let mut u = Unstructured::new(&entropy);
+
+            let token = NizkpSignedToken::<Box<[u8]>>::arbitrary(&mut u).unwrap();
+            assert!(token.check_integrity().is_ok());
+            let decoded = NizkpSignedToken::<Box<[u8]>>::from_bytes(&token.to_bytes()).unwrap();
+            assert_eq!(decoded.to_bytes(), token.to_bytes());
+            assert!(!token.verify(&private));
+
+            let json = serde_json::to_string(&token).unwrap();
+            let decoded: NizkpSignedToken<Box<[u8]>> = serde_json::from_str(&json).unwrap();
+            assert_eq!(decoded.to_bytes(), token.to_bytes());
+
+            let proof = VerificationProof::arbitrary(&mut u).unwrap();
+            assert_eq!(VerificationProof::from_bytes(&proof.to_bytes()), Ok(proof));
+            assert_eq!(proof.check(&token, &PublicKey::from(&private)), None);
+
+            let proof = IssuanceProof::arbitrary(&mut u).unwrap();
+            assert_eq!(IssuanceProof::from_bytes(&proof.to_bytes()), Ok(proof));
+            assert!(!proof.check(&token, &PublicKey::from(&private)));
+
+            let key = PublicKey::arbitrary(&mut u).unwrap();
+            assert_eq!(
+                PublicKey::from_bytes(&key.to_bytes()).unwrap().to_bytes(),
+                key.to_bytes()
+            );
+
+            // the decoders take any bytes
+            let _ = NizkpSignedToken::<Box<[u8]>>::from_bytes(&entropy);
+            let _ = VerificationProof::from_bytes(&entropy);
+            let _ = IssuanceProof::from_bytes(&entropy);
+            let _ = PublicKey::from_bytes(&entropy);
+        }
+    }
+
+    #[test]
+    fn test_integrity() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let sign = |metadata: &'static [u8]| {
+            NizkpTokenEngine::sign(
+                NizkpTokenEngine::generate(Box::from(metadata)),
+                &public_key,
+                |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+            )
+            .unwrap()
+        };
+        let signed = sign(b"metadata");
+        let other = sign(b"other metadata");
+        assert!(signed.check_integrity().is_ok());
+
+        // the metadata of the other token, put into the token by mistake
+        let mut spliced = serde_json::to_value(&signed).unwrap();
+        spliced["metadata"] = serde_json::to_value(&other).unwrap()["metadata"].clone();
+        let error = serde_json::from_value::<NizkpSignedToken<Box<[u8]>>>(spliced)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("integrity tag"));
+
+        // the last byte of the metadata
+        let mut bytes = signed.to_bytes();
+        bytes[1 + 1 + 16 + 2 + 7] ^= 1;
+        assert_eq!(
+            NizkpSignedToken::<Box<[u8]>>::from_bytes(&bytes).err(),
+            Some(WireError::Integrity)
+        );
+
+        let spliced = NizkpSignedToken {
+            metadata: other.metadata.clone(),
+            ..signed
+        };
+        assert_eq!(spliced.check_integrity(), Err(IntegrityError));
+        assert!(!spliced.verify(&private));
+    }
+
+    #[test]
+    fn test_bound_redemption() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = NizkpTokenEngine::generate(&b"This is my metadata"[..]);
+        let signed = NizkpTokenEngine::sign(token, &public_key, |randomized| {
+            NizkpTokenEngine::sign_randomized(randomized, &private)
+        })
+        .unwrap();
+
+        let redemption = signed.bind(b"report");
+        assert!(redemption.verify(&private, b"report"));
+        assert!(!redemption.verify(&private, b"another report"));
+        assert!(!redemption.verify(&PrivateKey::new(), b"report"));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        // generate keys
+        let old_private = PrivateKey::new();
+        let new_private = PrivateKey::new();
+
+        let mut keys = PublicKeySet::new();
+        keys.insert(1, PublicKey::from(&old_private));
+        keys.insert(2, PublicKey::from(&new_private));
+
+        // generate a new token
+        let metadata = b"This is my metadata";
+        let token = NizkpTokenEngine::generate(metadata);
+
+        // randomize token
+        let (r, anon_token) = NizkpTokenEngine::randomize(&token);
+
+        // sign randomized token with the old key, while the new key is the latest
+        let signed = NizkpTokenEngine::sign_randomized(&anon_token, &old_private)
+            .unwrap()
+            .with_key_epoch(1);
+
+        let signed = NizkpTokenEngine::verify_signature_and_unrandomize_with_key_set(
+            token, anon_token, signed, &keys, r,
+        );
+
+        let signed = signed.unwrap();
+        assert!(signed.verify(&old_private));
+
+        // the verifier only has the private keys, and finds the one the token names
+        let mut key_ring = KeyRing::new();
+        key_ring.insert(1, old_private.clone());
+        key_ring.insert(2, new_private);
+        assert_eq!(signed.key_id(), Some(old_private.key_id()));
+        assert_eq!(key_ring.epoch(signed.key_id().unwrap()), Some(1));
+        assert!(NizkpTokenEngine::verify_with_key_ring(&signed, &key_ring).is_ok());
+
+        key_ring.remove(1);
+        assert!(!key_ring.verify(&signed));
+    }
+
+    /// SHA-512 of a tag and the data
+    #[derive(Clone)]
+    struct Tagged(sha2::Sha512);
+
+    impl Default for Tagged {
+        fn default() -> Self {
+            Self(Update::chain(sha2::Sha512::default(), b"tag"))
+        }
+    }
+
+    impl Update for Tagged {
+        fn update(&mut self, data: impl AsRef<[u8]>) {
+            Update::update(&mut self.0, data)
+        }
+    }
+
+    impl FixedOutput for Tagged {
+        type OutputSize = U64;
+
+        fn finalize_into(self, out: &mut Output<sha2::Sha512>) {
+            self.0.finalize_into(out)
+        }
+
+        fn finalize_into_reset(&mut self, out: &mut Output<sha2::Sha512>) {
+            self.0.finalize_into_reset(out);
+            Reset::reset(self)
+        }
+    }
+
+    impl Reset for Tagged {
+        fn reset(&mut self) {
+            *self = Self::default()
+        }
+    }
+
+    struct TaggedSuite;
+
+    impl HashSuite for TaggedSuite {
+        const NAME: &'static str = "SHA2-tagged";
+        type Wide = Tagged;
+        type Narrow = sha2::Sha256;
+    }
+
+    #[test]
+    fn test_hash_suite() {
+        type Engine = HashedNizkpTokenEngine<Vec<u8>, TaggedSuite>;
+
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let signed = Engine::sign(Engine::generate(b"metadata".to_vec()), &public_key, |randomized| {
+            Engine::sign_randomized(randomized, &private)
+        })
+        .unwrap();
+        assert!(Engine::verify(&signed, &private).is_ok());
+
+        // the token does not verify with the hashes of the default suite
+        let bytes = signed.to_bytes();
+        let default = NizkpSignedToken::<Vec<u8>>::from_bytes(&bytes).unwrap();
+        assert!(!default.verify(&private));
+
+        // nor does a signature of the default signer
+        let result = Engine::sign(Engine::generate(b"metadata".to_vec()), &public_key, |randomized| {
+            NizkpTokenEngine::sign_randomized(randomized, &private)
+        });
+        assert_eq!(result.err(), Some(Error::BadProof));
+    }
+
+    /// The suite of a deployment with tags of its own
+    struct AppSuite;
+
+    impl HashSuite for AppSuite {
+        const NAME: &'static str = "SHA2-app";
+        const DOMAIN: DomainParams = DomainParams {
+            hash_to_scalar: b"app hash_to_scalar",
+            hash_to_curve: b"app h_t",
+            dleq_proof: b"app DLEQ proof",
+        };
+        type Wide = sha2::Sha512;
+        type Narrow = sha2::Sha256;
+    }
+
+    #[test]
+    fn test_domain_params() {
+        type Engine = HashedNizkpTokenEngine<Vec<u8>, AppSuite>;
+
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let signed = Engine::sign(Engine::generate(b"metadata".to_vec()), &public_key, |randomized| {
+            Engine::sign_randomized(randomized, &private)
+        })
+        .unwrap();
+        assert!(Engine::verify(&signed, &private).is_ok());
+
+        let (valid, proof) = signed.verify_with_proof(&private);
+        assert!(valid);
+        assert_eq!(proof.check(&signed, &public_key), Some(true));
+
+        // the oracles of the default tags are others
+        let default = NizkpSignedToken::<Vec<u8>>::from_bytes(&signed.to_bytes()).unwrap();
+        assert!(!default.verify(&private));
+        assert_eq!(proof.check(&default, &public_key), None);
+
+        // the proof of a signer with the default tags does not verify, even with the same hashes
+        struct ProofSuite;
+
+        impl HashSuite for ProofSuite {
+            const NAME: &'static str = "SHA2-proof";
+            const DOMAIN: DomainParams = DomainParams {
+                dleq_proof: b"app DLEQ proof",
+                ..DomainParams::DEFAULT
+            };
+            type Wide = sha2::Sha512;
+            type Narrow = sha2::Sha256;
+        }
+
+        type ProofOnly = HashedNizkpTokenEngine<Vec<u8>, ProofSuite>;
+        let result = ProofOnly::sign(
+            ProofOnly::generate(b"metadata".to_vec()),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private),
+        );
+        assert_eq!(result.err(), Some(Error::BadProof));
+    }
 }
 
 // }}}
@@ -1,202 +1,64 @@
 use alloc::{boxed::Box, vec::Vec};
-use core::{convert::TryInto, iter::repeat_with, marker::PhantomData};
+use core::marker::PhantomData;
 use curve25519_dalek::{
-    constants::{RISTRETTO_BASEPOINT_POINT, RISTRETTO_BASEPOINT_TABLE},
-    ristretto::RistrettoPoint,
-    scalar::Scalar,
+    constants::RISTRETTO_BASEPOINT_TABLE, ristretto::RistrettoPoint, scalar::Scalar,
     traits::Identity,
 };
-use rand::{prelude::StdRng, SeedableRng};
-// use serde::{Deserialize, Serialize};
+use rand::{prelude::StdRng, CryptoRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use subtle::{ConstantTimeEq, CtOption};
 
-use crate::common::fill_bytes;
+use crate::array::BoxedArray;
+use crate::common::{check_batch_response, check_metadata, fill_bytes, invertible};
+use crate::group::DleqProofBatched;
 
 use super::{
     keys::{PrivateKey, PublicKey},
-    SignedToken, TokenEngine, TokenIdentifier, UnsignedToken,
+    BatchResponseError, Error, HasKeyId, KeyEpoch, KeyId, SignedToken, TokenEngine,
+    TokenIdentifier, UnsignedToken, VerifyError,
 };
 
-use sha2::{Digest, Sha256, Sha512};
-use subtle::{Choice, CtOption};
-
-use super::util::{h_t, hash_to_scalar};
-
-// {{{ DLEQProof
-
-#[derive(Clone)]
-struct DLEQProof {
-    c: Scalar,
-    z: Scalar,
-}
-
-impl DLEQProof {
-    fn hash_data(
-        u: &RistrettoPoint,
-        t: &RistrettoPoint,
-        w: &RistrettoPoint,
-        a: &RistrettoPoint,
-        b: &RistrettoPoint,
-    ) -> Scalar {
-        let mut hasher = Sha512::new();
-        hasher.update(b"This is DLEQ_PROOF hash");
-        hasher.update(RISTRETTO_BASEPOINT_POINT.compress().as_bytes());
-        hasher.update(u.compress().as_bytes());
-        hasher.update(t.compress().as_bytes());
-        hasher.update(w.compress().as_bytes());
-        hasher.update(a.compress().as_bytes());
-        hasher.update(b.compress().as_bytes());
-
-        Scalar::from_hash(hasher)
-    }
-
-    /// Create a proof of the fact that log_w t = k
-    ///
-    /// If you create w=(d+k)^{-1} t, then create this proof with create(t, w, d + k)
-    pub fn create(t: RistrettoPoint, w: RistrettoPoint, k: Scalar) -> Self {
-        let r = Scalar::random(&mut rand::thread_rng());
-        let a = &RISTRETTO_BASEPOINT_TABLE * &r;
-        let b = w * r;
-
-        let c = DLEQProof::hash_data(&(&RISTRETTO_BASEPOINT_TABLE * &k), &t, &w, &a, &b);
-
-        let z = r - k * c;
-
-        Self { c, z }
-    }
-
-    /// Verify the proof that log_w t = k
-    ///
-    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
-    pub fn verify(
-        &self,
-        t: RistrettoPoint,
-        w: RistrettoPoint,
-        public_key: RistrettoPoint,
-    ) -> bool {
-        let a = &RISTRETTO_BASEPOINT_TABLE * &self.z + public_key * self.c;
-        let b = w * self.z + t * self.c;
-        let c = DLEQProof::hash_data(&public_key, &t, &w, &a, &b);
-
-        c == self.c
-    }
-}
-
-struct DLEQProofBatched {
-    proof: DLEQProof,
-}
-
-impl DLEQProofBatched {
-    fn hash_data(
-        unsignedvec: impl AsRef<[RistrettoPoint]>,
-        signedvec: impl AsRef<[RistrettoPoint]>,
-        public_key: RistrettoPoint,
-    ) -> StdRng {
-        let mut hasher = Sha256::new();
-        hasher.update(b"This is DLEQ_PROOF hash");
-        hasher.update(RISTRETTO_BASEPOINT_POINT.compress().as_bytes());
-        hasher.update(public_key.compress().as_bytes());
-        unsignedvec.as_ref().iter().for_each(|thing| {
-            hasher.update(thing.compress().as_bytes());
-        });
-
-        signedvec.as_ref().iter().for_each(|item| {
-            hasher.update(item.compress().as_bytes());
-        });
-
-        // seedable determinizstic rng
-        StdRng::from_seed(hasher.finalize().into())
-    }
-
-    ///For use in batched verification
-    /// Creates a random linear combination of the batch of tokens given trough use of hash function which seeds an rng
-    fn hash_random_linear_combination(
-        t_list: impl AsRef<[RistrettoPoint]>,
-        w_list: impl AsRef<[RistrettoPoint]>,
-        public_key: RistrettoPoint,
-    ) -> (RistrettoPoint, RistrettoPoint) {
-        let mut c = DLEQProofBatched::hash_data(&t_list, &w_list, public_key);
-        let (newt, neww) = t_list
-            .as_ref()
-            .iter()
-            .zip(w_list.as_ref().iter())
-            .map(|(t, w)| {
-                let c = Scalar::random(&mut c);
-                (t * c, w * c)
-            })
-            .fold(
-                (RistrettoPoint::identity(), RistrettoPoint::identity()),
-                |(tsum, wsum), (t, w)| (tsum + t, wsum + w),
-            );
-        (newt, neww)
-    }
-
-    pub fn create(
-        t_list: impl AsRef<[RistrettoPoint]>,
-        w_list: impl AsRef<[RistrettoPoint]>,
-        k: Scalar,
-    ) -> Self {
-        let (m, z) = DLEQProofBatched::hash_random_linear_combination(
-            t_list,
-            w_list,
-            &RISTRETTO_BASEPOINT_TABLE * &k,
-        );
-        let proof = DLEQProof::create(m, z, k);
-        Self { proof }
-    }
-    /// Verifies the proof for the linear combination of the tokens in the batch
-    /// If w was created as w=(d+k)^{-1} t, and have U=(d+k)G, then call as verify(t, w, u)
-    pub fn verify<const N: usize>(
-        &self,
-        unsignedvec: [RistrettoPoint; N],
-        signedvec: [RistrettoPoint; N],
-        public_key: RistrettoPoint,
-    ) -> bool {
-        let (m, z) =
-            DLEQProofBatched::hash_random_linear_combination(unsignedvec, signedvec, public_key);
-        self.proof.verify(m, z, public_key)
-    }
-}
-
-// }}}
+use super::tokens::NizkpSignedToken;
+use super::util::{batched_proof, h_t, hash_to_scalar, points, Ristretto};
 
 // {{{ UnsignedToken
 
 pub struct NizkpUnsignedTokenBatched<M: AsRef<[u8]>, const N: usize> {
-    ids: [TokenIdentifier<M>; N],
+    ids: BoxedArray<TokenIdentifier<M>, N>,
     metadata: M,
 }
 impl<M: AsRef<[u8]>, const N: usize> From<&NizkpUnsignedTokenBatched<M, N>>
-    for [RistrettoPoint; N]
+    for BoxedArray<RistrettoPoint, N>
 {
     fn from(token: &NizkpUnsignedTokenBatched<M, N>) -> Self {
-        let points: [RistrettoPoint; N] = (&token.ids)
-            .iter()
-            .map(|id| {
-                let t: [u8; 16] = id.into();
-                h_t(t, &token.metadata)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .ok()
-            .unwrap();
-        points
+        token.ids.map(|id| {
+            let t: [u8; 16] = id.into();
+            h_t(t, &token.metadata)
+        })
     }
 }
 
-impl<M: AsRef<[u8]>, const N: usize> UnsignedToken for NizkpUnsignedTokenBatched<M, N> {
+impl<M: AsRef<[u8]> + Clone, const N: usize> UnsignedToken for NizkpUnsignedTokenBatched<M, N> {
     type Metadata = M;
     type HiddenMetadata = M;
 
-    fn new(metadata: Self::Metadata) -> Self {
+    fn new_with_rng<R: CryptoRng + RngCore>(metadata: Self::Metadata, rng: &mut R) -> Self {
         Self {
-            ids: TokenIdentifier::generate(),
+            ids: TokenIdentifier::generate_with_rng(rng),
             metadata,
         }
     }
 
-    // needs thinking
-    fn with_hidden(_metadata: Self::Metadata, _hidden: Self::HiddenMetadata) -> Self {
-        todo!()
+    /// The hidden metadata is the same for all the tokens in the batch
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        metadata: Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
+        Self {
+            ids: TokenIdentifier::generate_with_hidden_with_rng(hidden, rng),
+            metadata,
+        }
     }
 }
 
@@ -204,14 +66,36 @@ impl<M: AsRef<[u8]>, const N: usize> UnsignedToken for NizkpUnsignedTokenBatched
 
 // {{{   Randomized signed
 
+#[derive(Serialize, Deserialize)]
 pub struct RandomizedSignedTokenBatched<M: AsRef<[u8]>, const N: usize> {
-    points: [RistrettoPoint; N],
-    proof: DLEQProofBatched,
+    #[serde(with = "points")]
+    points: BoxedArray<RistrettoPoint, N>,
+    #[serde(with = "batched_proof")]
+    proof: DleqProofBatched<Scalar>,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
     _m: PhantomData<M>,
 }
 
+impl<M: AsRef<[u8]>, const N: usize> crate::common::RandomizedSignedToken
+    for RandomizedSignedTokenBatched<M, N>
+{
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct RandomizedUnsignedTokenBatched<M: AsRef<[u8]>, const N: usize> {
-    points: [RistrettoPoint; N],
+    #[serde(with = "points")]
+    points: BoxedArray<RistrettoPoint, N>,
     metadata: Box<[u8]>,
     _m: PhantomData<M>,
 }
@@ -229,25 +113,21 @@ impl<M: AsRef<[u8]>, const N: usize> crate::common::RandomizedUnsignedToken
 // {{{ Signed token
 
 pub struct NizkpSignedTokenBatched<M: AsRef<[u8]>, const N: usize> {
-    ids: [TokenIdentifier<M>; N],
+    ids: BoxedArray<TokenIdentifier<M>, N>,
     metadata: M,
-    points: [RistrettoPoint; N],
+    points: BoxedArray<RistrettoPoint, N>,
+    key_id: KeyId,
 }
 
 impl<M: AsRef<[u8]>, const N: usize> SignedToken for NizkpSignedTokenBatched<M, N> {
     type VerificationKey = PrivateKey;
 
     fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
-        let tpoints: [RistrettoPoint; N] = (&self.ids)
-            .iter()
-            .map(|id| {
-                let t: [u8; 16] = id.into();
-                h_t(t, &self.metadata)
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .ok()
-            .unwrap();
+        let metadata = self.metadata.as_ref();
+        let tpoints = self
+            .ids
+            .map(|id| <[u8; 16]>::from(id))
+            .par_map(|t| h_t(t, metadata));
         // We may do this, since
         // w == e * t is the same as e^-1 w == t
         // We then do not need to do the inversion step, and maybe it could be easier to build
@@ -263,17 +143,209 @@ impl<M: AsRef<[u8]>, const N: usize> SignedToken for NizkpSignedTokenBatched<M,
                 .iter()
                 .fold(RistrettoPoint::identity(), |sum, point| sum + point)
     }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn key_id(&self) -> Option<KeyId> {
+        Some(self.key_id)
+    }
+}
+
+impl<M: AsRef<[u8]>, const N: usize> NizkpSignedTokenBatched<M, N> {
+    /// Find the tokens of the batch that do not verify, such that only those are thrown away
+    ///
+    /// Returns the indices of the bad tokens, which is empty if the batch verifies. The verifier
+    /// has the private key, so every token is checked with a multiplication.
+    pub fn verify_detailed(&self, verification_key: &PrivateKey) -> Vec<usize> {
+        let e_inverse = hash_to_scalar(&self.metadata) + verification_key.to_scalar();
+
+        self.ids
+            .iter()
+            .zip(self.points.iter())
+            .enumerate()
+            .filter(|(_i, (id, point))| {
+                let t: [u8; 16] = (*id).into();
+                *point * e_inverse != h_t(t, &self.metadata)
+            })
+            .map(|(i, _token)| i)
+            .collect()
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone, const N: usize> NizkpSignedTokenBatched<M, N> {
+    /// Split the batch into single tokens, that may be redeemed one by one
+    pub fn into_tokens(self) -> Vec<NizkpSignedToken<M>> {
+        let metadata = self.metadata;
+        let key_id = Some(self.key_id);
+        IntoIterator::into_iter(self.ids)
+            .zip(IntoIterator::into_iter(self.points))
+            .map(|(id, point)| NizkpSignedToken::from_parts(id, metadata.clone(), point, key_id))
+            .collect()
+    }
+}
+
+// }}}
+
+// {{{ Issuance proof
+
+/// The proof of the signer from the issuance of a batch, that anyone with the public key can check
+///
+/// The verifier needs the private key to verify the tokens, but this shows an auditor with only
+/// the public key that the points of the request were signed with the key of the public key and
+/// the metadata, e.g. for a public log of the issuance. Unlike the
+/// [`IssuanceProof`](super::tokens::IssuanceProof) of a single token, it does not have the
+/// randomization, so it does not link the request to the tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IssuanceProofBatched<const N: usize> {
+    #[serde(with = "points")]
+    randomized: BoxedArray<RistrettoPoint, N>,
+    #[serde(with = "points")]
+    signed: BoxedArray<RistrettoPoint, N>,
+    #[serde(with = "batched_proof")]
+    proof: DleqProofBatched<Scalar>,
+    metadata: Box<[u8]>,
+}
+
+impl<const N: usize> IssuanceProofBatched<N> {
+    /// The proof of a request and the response of the signer to it
+    pub fn new<M: AsRef<[u8]>>(
+        randomized_unsigned: &RandomizedUnsignedTokenBatched<M, N>,
+        signed: &RandomizedSignedTokenBatched<M, N>,
+    ) -> Self {
+        Self {
+            randomized: randomized_unsigned.points.clone(),
+            signed: signed.points.clone(),
+            proof: signed.proof,
+            metadata: randomized_unsigned.metadata.clone(),
+        }
+    }
+
+    pub fn metadata(&self) -> &[u8] {
+        &self.metadata
+    }
+
+    /// Whether the signer proved that it signed the points with the key of the public key
+    pub fn check(&self, public_key: &PublicKey) -> bool {
+        // the signer may tag the users with equal or identity points
+        let response = check_batch_response(
+            self.randomized.len(),
+            self.signed.iter().map(|point| point.compress().to_bytes()),
+            RistrettoPoint::identity().compress().to_bytes(),
+        );
+
+        response.is_ok()
+            && proof_holds(
+                &self.metadata,
+                &self.randomized,
+                &self.signed,
+                &self.proof,
+                public_key,
+            )
+    }
 }
 
 // }}}
 
 // {{{ Token engine
 
+/// Whether the proof shows that the signed points are the randomized points signed with the key
+/// of the public key and the metadata
+fn proof_holds(
+    metadata: &[u8],
+    randomized: &[RistrettoPoint],
+    signed: &[RistrettoPoint],
+    proof: &DleqProofBatched<Scalar>,
+    public_key: &PublicKey,
+) -> bool {
+    // get the public key
+    let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(metadata) + public_key.to_affine();
+
+    proof.verify::<Ristretto>(randomized, signed, u)
+}
+
 pub struct BatchedNizkpTokenEngine<M: AsRef<[u8]>, const N: usize> {
     _m: PhantomData<M>,
 }
 
-impl<M: AsRef<[u8]>, const N: usize> TokenEngine for BatchedNizkpTokenEngine<M, N> {
+impl<M: AsRef<[u8]>, const N: usize> BatchedNizkpTokenEngine<M, N> {
+    /// Check that the signer returned one point for each point sent, that they are distinct, and
+    /// that none of them are the identity.
+    ///
+    /// This is done by `verify_signature_and_unrandomize`, but may be used to find out why a
+    /// response was rejected.
+    pub fn check_response(
+        randomized_unsigned: &RandomizedUnsignedTokenBatched<M, N>,
+        signed_token: &RandomizedSignedTokenBatched<M, N>,
+    ) -> Result<(), BatchResponseError> {
+        check_batch_response(
+            randomized_unsigned.points.len(),
+            signed_token
+                .points
+                .iter()
+                .map(|point| point.compress().to_bytes()),
+            RistrettoPoint::identity().compress().to_bytes(),
+        )
+    }
+
+    /// Check the response and the proof of the signer against the randomized tokens
+    fn check_proof(
+        unsigned_token: &NizkpUnsignedTokenBatched<M, N>,
+        randomized_unsigned_token: &RandomizedUnsignedTokenBatched<M, N>,
+        signed_token: &RandomizedSignedTokenBatched<M, N>,
+        verification_data: &PublicKey,
+    ) -> Result<(), Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned_token, signed_token)?;
+
+        // verify proof
+        if proof_holds(
+            unsigned_token.metadata.as_ref(),
+            &randomized_unsigned_token.points,
+            &signed_token.points,
+            &signed_token.proof,
+            verification_data,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::BadProof)
+        }
+    }
+
+    /// [`TokenEngine::verify_signature_and_unrandomize`], keeping the proof of the signer for an
+    /// auditor, see [`IssuanceProofBatched`]
+    // the error gives back the tokens, as the one of the trait
+    #[allow(clippy::result_large_err)]
+    pub fn verify_signature_and_unrandomize_with_proof(
+        unsigned_token: NizkpUnsignedTokenBatched<M, N>,
+        randomized_unsigned_token: RandomizedUnsignedTokenBatched<M, N>,
+        signed_token: RandomizedSignedTokenBatched<M, N>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+    ) -> Result<(NizkpSignedTokenBatched<M, N>, IssuanceProofBatched<N>), VerifyError<Self>>
+    where
+        M: Clone,
+    {
+        let proof = IssuanceProofBatched::new(&randomized_unsigned_token, &signed_token);
+
+        Self::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized_unsigned_token,
+            signed_token,
+            verification_data,
+            randomization,
+        )
+        .map(|tokens| (tokens, proof))
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone, const N: usize> TokenEngine for BatchedNizkpTokenEngine<M, N> {
     type UnsignedToken = NizkpUnsignedTokenBatched<M, N>;
     type RandomizedUnsignedToken = RandomizedUnsignedTokenBatched<M, N>;
     type RandomizedSignedToken = RandomizedSignedTokenBatched<M, N>;
@@ -283,12 +355,13 @@ impl<M: AsRef<[u8]>, const N: usize> TokenEngine for BatchedNizkpTokenEngine<M,
     type SignKey = PrivateKey;
 
     //For batched tokens we generate a seed for an rng to reduce memory usage. It had to be verified that all scalars are invertible
-    fn randomize(
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
         unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
     ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
         // create random seed
         let mut randomization = [0; 32];
-        fill_bytes(&mut rand::thread_rng(), &mut randomization);
+        fill_bytes(rng, &mut randomization);
 
         // seed an rng for the series of r
         let mut rng = StdRng::from_seed(randomization);
@@ -296,19 +369,13 @@ impl<M: AsRef<[u8]>, const N: usize> TokenEngine for BatchedNizkpTokenEngine<M,
         (
             randomization,
             Self::RandomizedUnsignedToken {
-                points: repeat_with(|| Scalar::random(&mut rng)) // generate random r's
-                    .take(N)
-                    .map(|r| r.invert())
-                    .zip(unsigned_token.ids.iter())
-                    .map(|(r, id)| {
-                        let t: [u8; 16] = id.into();
-                        // T' = [r]T
-                        h_t(t, &unsigned_token.metadata) * r
-                    })
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .ok()
-                    .unwrap(),
+                points: unsigned_token.ids.map(|id| {
+                    // generate a random r
+                    let r = Scalar::random(&mut rng).invert();
+                    let t: [u8; 16] = id.into();
+                    // T' = [r]T
+                    h_t(t, &unsigned_token.metadata) * r
+                }),
                 metadata: Box::from(unsigned_token.metadata.as_ref()),
                 _m: PhantomData {},
             },
@@ -321,69 +388,65 @@ impl<M: AsRef<[u8]>, const N: usize> TokenEngine for BatchedNizkpTokenEngine<M,
         signed_token: Self::RandomizedSignedToken,
         verification_data: &Self::UserVerification,
         randomization: Self::Randomization,
-    ) -> Option<Self::SignedToken> {
-        // get the public key
-        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(&unsigned_token.metadata)
-            + verification_data.to_affine();
-
-        // verify proof
-        if signed_token
-            .proof
-            .verify(randomized_unsigned_token.points, signed_token.points, u)
-        {
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::check_proof(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+        ) {
             // needs fix
             // Remove randomization
-
-            let mut rng = StdRng::from_seed(randomization);
-            let rlist = repeat_with(|| Scalar::random(&mut rng)).take(N);
-            Some(Self::SignedToken {
-                points: (signed_token
+            Ok(()) => {
+                let mut rng = StdRng::from_seed(randomization);
+                let items = signed_token
                     .points
-                    .iter()
-                    .zip(rlist)
-                    .map(|(point, r)| point * r)
-                    .collect::<Vec<_>>()
-                    .try_into()
-                    .ok()
-                    .unwrap()),
-                metadata: unsigned_token.metadata,
-                ids: unsigned_token.ids,
-            })
-        } else {
-            None
+                    .map(|point| (*point, Scalar::random(&mut rng)));
+                Ok(Self::SignedToken {
+                    points: items.par_map(|(point, r)| point * r),
+                    metadata: unsigned_token.metadata,
+                    ids: unsigned_token.ids,
+                    key_id: verification_data.key_id(),
+                })
+            }
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
         }
     }
 
-    fn sign_randomized(
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
         t_prime: &Self::RandomizedUnsignedToken,
         sign_key: &Self::SignKey,
-    ) -> CtOption<Self::RandomizedSignedToken> {
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
         // This should be a constant time implementation
         let d = hash_to_scalar(&t_prime.metadata);
-        let e = (d + sign_key.to_scalar()).invert();
+        let k = d + sign_key.to_scalar();
+        let e = k.invert();
         // list of W'
-        let w_prime_list = t_prime
-            .points
-            .iter()
-            .map(|t_prime| t_prime * e)
-            .collect::<Vec<_>>()
-            .try_into()
-            .ok()
-            .unwrap();
-
-        //
+        let w_prime_list = t_prime.points.par_map(|t_prime| t_prime * e);
 
-        let proof =
-            DLEQProofBatched::create(&t_prime.points, &w_prime_list, d + sign_key.to_scalar());
+        let proof = DleqProofBatched::create_with_rng::<Ristretto, _>(
+            &t_prime.points,
+            &w_prime_list,
+            k,
+            rng,
+        );
 
-        CtOption::new(
+        // the inverse of zero is zero, which would sign every point as the identity
+        invertible(CtOption::new(
             RandomizedSignedTokenBatched {
                 points: w_prime_list,
                 proof,
+                key_epoch: None,
                 _m: PhantomData {},
             },
-            Choice::from(1),
-        )
+            !k.ct_eq(&Scalar::zero()),
+        ))
     }
 }
 
@@ -395,11 +458,12 @@ impl<M: AsRef<[u8]>, const N: usize> TokenEngine for BatchedNizkpTokenEngine<M,
 mod tests {
     use super::super::keys::{PrivateKey, PublicKey};
     use super::*;
+    use crate::group::DleqProof;
 
     #[test]
     fn test_proof() {
         // setup
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::default_rng();
 
         // create keys
         let private_key = Scalar::random(&mut rng);
@@ -420,10 +484,10 @@ mod tests {
         let w = t * e;
 
         // create proof
-        let proof = DLEQProof::create(t, w, private_key + d);
+        let proof = DleqProof::create::<Ristretto>(t, w, private_key + d);
 
         // verify
-        assert!(proof.verify(t, w, u));
+        assert!(proof.verify::<Ristretto>(t, w, u));
     }
 
     #[test]
@@ -450,10 +514,184 @@ mod tests {
             &public_key,
             r,
         );
-        assert!(signed.is_some());
+        assert!(signed.is_ok());
 
         // verify personalized token
-        assert!(signed.unwrap().verify(&private));
+        let signed = signed.unwrap();
+        assert!(signed.verify(&private));
+
+        // every token in the batch is valid on its own
+        let tokens = signed.into_tokens();
+        assert_eq!(tokens.len(), 5);
+        assert!(tokens.iter().all(|token| token.verify(&private)));
+    }
+
+    #[test]
+    fn test_verify_detailed() {
+        let private = PrivateKey::new();
+
+        let mut signed = BatchedNizkpTokenEngine::<_, 5>::sign(
+            BatchedNizkpTokenEngine::generate(b"metadata"),
+            &PublicKey::from(&private),
+            |randomized| BatchedNizkpTokenEngine::sign_randomized(randomized, &private),
+        )
+        .unwrap();
+
+        assert!(signed.verify_detailed(&private).is_empty());
+
+        // every token is checked, even if the errors cancel out in the sum
+        let delta = RistrettoPoint::random(&mut crate::rng::default_rng());
+        signed.points[1] += delta;
+        signed.points[3] -= delta;
+        assert_eq!(signed.verify_detailed(&private), [1, 3]);
+    }
+
+    #[test]
+    fn test_hidden() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = BatchedNizkpTokenEngine::<_, 5>::generate_with_hidden(
+            &b"This is my metadata"[..],
+            &b"hidden"[..],
+        );
+
+        let signed = BatchedNizkpTokenEngine::sign(token, &public_key, |randomized| {
+            BatchedNizkpTokenEngine::sign_randomized(randomized, &private)
+        })
+        .unwrap();
+        assert!(signed.verify(&private));
+
+        // the hidden metadata follows every token of the batch
+        for token in signed.into_tokens() {
+            assert!(token.verify(&private));
+            assert!(
+                matches!(token.id().hidden(), Some(hidden) if hidden == b"hidden")
+            );
+        }
+    }
+
+    #[test]
+    fn test_serialization() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = BatchedNizkpTokenEngine::<_, 5>::generate(&b"This is my metadata"[..]);
+        let (r, anon_token) = BatchedNizkpTokenEngine::randomize(&token);
+
+        // the signer gets the request over the network
+        let request = serde_json::to_string(&anon_token).unwrap();
+        let request: RandomizedUnsignedTokenBatched<&[u8], 5> =
+            serde_json::from_str(&request).unwrap();
+
+        // and returns the signed points with the proof
+        let response = BatchedNizkpTokenEngine::sign_randomized(&request, &private).unwrap();
+        let response = serde_json::to_string(&response).unwrap();
+        let signed = serde_json::from_str(&response).unwrap();
+
+        assert!(BatchedNizkpTokenEngine::verify_signature_and_unrandomize(
+            token,
+            anon_token,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap()
+        .verify(&private));
+
+        // a batch of another size is rejected
+        assert!(serde_json::from_str::<RandomizedSignedTokenBatched<&[u8], 4>>(&response).is_err());
+    }
+
+    #[test]
+    fn test_issuance_proof() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = BatchedNizkpTokenEngine::<_, 5>::generate(&b"metadata"[..]);
+        let (r, anon_token) = BatchedNizkpTokenEngine::randomize(&token);
+        let signed = BatchedNizkpTokenEngine::sign_randomized(&anon_token, &private).unwrap();
+
+        let (tokens, proof) = BatchedNizkpTokenEngine::verify_signature_and_unrandomize_with_proof(
+            token,
+            anon_token,
+            signed,
+            &public_key,
+            r,
+        )
+        .unwrap();
+        assert!(tokens.verify(&private));
+
+        // the auditor gets the proof later, with only the public key
+        let json = serde_json::to_string(&proof).unwrap();
+        let logged: IssuanceProofBatched<5> = serde_json::from_str(&json).unwrap();
+        assert_eq!(logged, proof);
+        assert_eq!(logged.metadata(), b"metadata");
+        assert!(logged.check(&public_key));
+        assert!(serde_json::from_str::<IssuanceProofBatched<4>>(&json).is_err());
+    }
+
+    #[test]
+    fn fail_issuance_proof() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = BatchedNizkpTokenEngine::<_, 5>::generate(&b"metadata"[..]);
+        let (_r, anon_token) = BatchedNizkpTokenEngine::randomize(&token);
+        let signed = BatchedNizkpTokenEngine::sign_randomized(&anon_token, &private).unwrap();
+        let proof = IssuanceProofBatched::new(&anon_token, &signed);
+        assert!(proof.check(&public_key));
+
+        // another key
+        assert!(!proof.check(&PublicKey::from(&PrivateKey::new())));
+
+        // other metadata
+        let mut other = proof.clone();
+        other.metadata = Box::from(&b"other"[..]);
+        assert!(!other.check(&public_key));
+
+        // the response to another request
+        let (_r, another) = BatchedNizkpTokenEngine::randomize(&token);
+        let mut other = proof.clone();
+        other.randomized = another.points;
+        assert!(!other.check(&public_key));
+
+        // the signer tags a user with the identity
+        let mut other = proof;
+        other.signed[0] = RistrettoPoint::identity();
+        assert!(!other.check(&public_key));
+    }
+
+    #[test]
+    fn fail_identity_response() {
+        // generate keys
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        // generate a new token
+        let metadata = b"This is my metadata";
+        let token = BatchedNizkpTokenEngine::<_, 5>::generate(metadata);
+
+        // randomize token
+        let (r, anon_token) = BatchedNizkpTokenEngine::randomize(&token);
+
+        // the signer tags the user with an identity point
+        let mut signed = BatchedNizkpTokenEngine::sign_randomized(&anon_token, &private).unwrap();
+        signed.points[3] = RistrettoPoint::identity();
+
+        assert_eq!(
+            BatchedNizkpTokenEngine::check_response(&anon_token, &signed),
+            Err(BatchResponseError::IdentityPoint)
+        );
+
+        let signed = BatchedNizkpTokenEngine::verify_signature_and_unrandomize(
+            token,
+            anon_token,
+            signed,
+            &public_key,
+            r,
+        );
+        assert!(signed.is_err());
     }
 
     #[test]
@@ -473,7 +711,7 @@ mod tests {
             BatchedNizkpTokenEngine::sign_randomized(randomized, &bad)
         });
 
-        assert!(signed.is_none());
+        assert!(signed.is_err());
     }
 
     #[test]
@@ -495,6 +733,21 @@ mod tests {
 
         assert!(!signed.verify(&bad));
     }
+
+    #[test]
+    fn fail_non_invertible() {
+        let metadata = b"This is my metadata";
+        let token = BatchedNizkpTokenEngine::<_, 5>::generate(metadata);
+        let (_r, randomized) = BatchedNizkpTokenEngine::randomize(&token);
+
+        // d + k is zero
+        let private = PrivateKey::from_scalar(-hash_to_scalar(metadata));
+
+        assert!(matches!(
+            BatchedNizkpTokenEngine::sign_randomized(&randomized, &private),
+            Err(Error::NonInvertibleScalar)
+        ));
+    }
 }
 
 // }}}
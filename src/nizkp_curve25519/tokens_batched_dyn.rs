@@ -0,0 +1,1343 @@
+//! Batched tokens where the size of the batch is picked at runtime
+//!
+//! This is the same protocol as [`super::tokens_batched`], but the tokens are stored in vectors,
+//! so a server may pick the batch size from a config file or from the request.
+//!
+//! A batch of thousands of tokens may also be streamed in chunks, with
+//! [`DynBatchedNizkpTokenEngine::issue_stream`], such that the signer only holds a chunk at a time.
+//!
+//! ```
+//!     use atpmd::{SignedToken, TokenEngine};
+//!     use atpmd::nizkp_curve25519::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens_batched_dyn::DynBatchedNizkpTokenEngine,
+//!     };
+//!
+//!     let private_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&private_key);
+//!
+//!     // the metadata and the size of the batch
+//!     let tokens = DynBatchedNizkpTokenEngine::generate((&b"metadata"[..], 7));
+//!
+//!     let signed = DynBatchedNizkpTokenEngine::sign(tokens, &public_key, |randomized| {
+//!         DynBatchedNizkpTokenEngine::sign_randomized(randomized, &private_key)
+//!     })
+//!     .unwrap();
+//!
+//!     assert!(signed.verify(&private_key));
+//!     assert_eq!(signed.into_tokens().len(), 7);
+//! ```
+
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
+use core::{future::Future, iter::repeat_with, marker::PhantomData, slice};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_TABLE, ristretto::RistrettoPoint, scalar::Scalar,
+    traits::Identity,
+};
+use rand::{prelude::StdRng, CryptoRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use subtle::{ConstantTimeEq, CtOption};
+use zeroize::Zeroize;
+
+use crate::chunked::{par_map, Chunking, Chunks};
+use crate::common::{check_batch_response, check_metadata, fill_bytes, invertible};
+use crate::group::{DleqProofBatched, DleqProofStream};
+
+use super::{
+    keys::{PrivateKey, PublicKey},
+    BatchResponseError, Error, HasKeyId, KeyEpoch, KeyId, Secret, SignedToken, TokenEngine,
+    TokenIdentifier, UnsignedToken, VerifyError,
+};
+
+use super::tokens::NizkpSignedToken;
+use super::util::{h_t, hash_to_scalar, Ristretto};
+
+fn points<M: AsRef<[u8]>>(ids: &[TokenIdentifier<M>], metadata: &M) -> Vec<RistrettoPoint> {
+    let metadata = metadata.as_ref();
+    par_map(&ids.iter().map(<[u8; 16]>::from).collect::<Vec<_>>(), |t| {
+        h_t(t, metadata)
+    })
+}
+
+// {{{ UnsignedToken
+
+pub struct DynNizkpUnsignedTokenBatched<M: AsRef<[u8]>> {
+    ids: Vec<TokenIdentifier<M>>,
+    metadata: M,
+}
+
+impl<M: AsRef<[u8]>> DynNizkpUnsignedTokenBatched<M> {
+    /// The number of tokens in the batch
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> UnsignedToken for DynNizkpUnsignedTokenBatched<M> {
+    /// The metadata, and the number of tokens in the batch
+    type Metadata = (M, usize);
+    type HiddenMetadata = M;
+
+    fn new_with_rng<R: CryptoRng + RngCore>((metadata, size): Self::Metadata, rng: &mut R) -> Self {
+        Self {
+            ids: repeat_with(|| TokenIdentifier::new_with_rng(rng))
+                .take(size)
+                .collect(),
+            metadata,
+        }
+    }
+
+    /// The hidden metadata is the same for all the tokens in the batch
+    fn with_hidden_with_rng<R: CryptoRng + RngCore>(
+        (metadata, size): Self::Metadata,
+        hidden: Self::HiddenMetadata,
+        rng: &mut R,
+    ) -> Self {
+        Self {
+            ids: repeat_with(|| TokenIdentifier::with_hidden_with_rng(hidden.clone(), rng))
+                .take(size)
+                .collect(),
+            metadata,
+        }
+    }
+}
+
+// }}}
+
+// {{{   Randomized signed
+
+#[derive(Serialize, Deserialize)]
+pub struct DynRandomizedSignedTokenBatched<M: AsRef<[u8]>> {
+    #[serde(with = "super::util::points")]
+    points: Vec<RistrettoPoint>,
+    #[serde(with = "super::util::batched_proof")]
+    proof: DleqProofBatched<Scalar>,
+    #[serde(default)]
+    key_epoch: Option<KeyEpoch>,
+    _m: PhantomData<M>,
+}
+
+impl<M: AsRef<[u8]>> crate::common::RandomizedSignedToken for DynRandomizedSignedTokenBatched<M> {
+    fn key_epoch(&self) -> Option<KeyEpoch> {
+        self.key_epoch
+    }
+
+    fn with_key_epoch(self, epoch: KeyEpoch) -> Self {
+        Self {
+            key_epoch: Some(epoch),
+            ..self
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DynRandomizedUnsignedTokenBatched<M: AsRef<[u8]>> {
+    #[serde(with = "super::util::points")]
+    points: Vec<RistrettoPoint>,
+    metadata: Box<[u8]>,
+    _m: PhantomData<M>,
+}
+
+impl<M: AsRef<[u8]>> DynRandomizedUnsignedTokenBatched<M> {
+    /// The number of tokens the user asks to be signed
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+impl<M: AsRef<[u8]>> crate::common::RandomizedUnsignedToken
+    for DynRandomizedUnsignedTokenBatched<M>
+{
+    fn metadata(&self) -> Box<[u8]> {
+        self.metadata.clone()
+    }
+}
+
+// }}}
+
+// {{{ Signed token
+
+pub struct DynNizkpSignedTokenBatched<M: AsRef<[u8]>> {
+    ids: Vec<TokenIdentifier<M>>,
+    metadata: M,
+    points: Vec<RistrettoPoint>,
+    key_id: KeyId,
+}
+
+impl<M: AsRef<[u8]>> SignedToken for DynNizkpSignedTokenBatched<M> {
+    type VerificationKey = PrivateKey;
+
+    fn verify(&self, verification_key: &Self::VerificationKey) -> bool {
+        if self.ids.len() != self.points.len() {
+            return false;
+        }
+
+        self.check_sum(points(&self.ids, &self.metadata), verification_key)
+    }
+
+    fn metadata_bytes(&self) -> &[u8] {
+        self.metadata.as_ref()
+    }
+
+    fn key_id(&self) -> Option<KeyId> {
+        Some(self.key_id)
+    }
+}
+
+impl<M: AsRef<[u8]>> DynNizkpSignedTokenBatched<M> {
+    fn check_sum(&self, t_list: Vec<RistrettoPoint>, verification_key: &PrivateKey) -> bool {
+        // w == e * t is the same as e^-1 w == t, see the batches with a fixed size
+        let e_inverse = hash_to_scalar(&self.metadata) + verification_key.to_scalar();
+
+        (self
+            .points
+            .iter()
+            .fold(RistrettoPoint::identity(), |sum, point| sum + point)
+            * e_inverse)
+            == t_list
+                .iter()
+                .fold(RistrettoPoint::identity(), |sum, point| sum + point)
+    }
+
+    /// [`SignedToken::verify`], yielding between the chunks of the batch
+    ///
+    /// A cancelled verification does not verify.
+    pub async fn verify_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        &self,
+        verification_key: &PrivateKey,
+        chunking: &mut Chunking<Y>,
+    ) -> bool {
+        if self.ids.len() != self.points.len() {
+            return false;
+        }
+
+        let t_list = chunking
+            .map(self.ids.iter(), |id| {
+                let t: [u8; 16] = id.into();
+                h_t(t, &self.metadata)
+            })
+            .await;
+
+        t_list.is_ok_and(|t_list| self.check_sum(t_list, verification_key))
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> DynNizkpSignedTokenBatched<M> {
+    /// Split the batch into single tokens, that may be redeemed one by one
+    pub fn into_tokens(self) -> Vec<NizkpSignedToken<M>> {
+        let metadata = self.metadata;
+        let key_id = Some(self.key_id);
+        self.ids
+            .into_iter()
+            .zip(self.points)
+            .map(|(id, point)| NizkpSignedToken::from_parts(id, metadata.clone(), point, key_id))
+            .collect()
+    }
+}
+
+// }}}
+
+// {{{ Token engine
+
+pub struct DynBatchedNizkpTokenEngine<M: AsRef<[u8]>> {
+    _m: PhantomData<M>,
+}
+
+impl<M: AsRef<[u8]>> DynBatchedNizkpTokenEngine<M> {
+    /// Check that the signer returned one point for each point sent, that they are distinct, and
+    /// that none of them are the identity.
+    ///
+    /// This is done by `verify_signature_and_unrandomize`, but may be used to find out why a
+    /// response was rejected.
+    pub fn check_response(
+        randomized_unsigned: &DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: &DynRandomizedSignedTokenBatched<M>,
+    ) -> Result<(), BatchResponseError> {
+        check_batch_response(
+            randomized_unsigned.points.len(),
+            signed_token
+                .points
+                .iter()
+                .map(|point| point.compress().to_bytes()),
+            RistrettoPoint::identity().compress().to_bytes(),
+        )
+    }
+
+    /// The points without the randomization, if the proof verifies
+    fn unrandomize(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        randomized_unsigned_token: &DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: &DynRandomizedSignedTokenBatched<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+    ) -> Result<Vec<RistrettoPoint>, Error> {
+        Self::check_proof(
+            unsigned_token,
+            randomized_unsigned_token,
+            signed_token,
+            verification_data,
+        )?;
+
+        // Remove randomization, the series of r is drawn in order first
+        let mut rng = StdRng::from_seed(randomization);
+        let items = repeat_with(|| Scalar::random(&mut rng))
+            .zip(signed_token.points.iter())
+            .collect::<Vec<_>>();
+
+        Ok(par_map(&items, |(r, point)| *point * r))
+    }
+
+    /// [`Self::unrandomize`], in chunks of the batch
+    fn unrandomize_in_chunks(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        randomized_unsigned_token: &DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: &DynRandomizedSignedTokenBatched<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunks: &Chunks<'_>,
+    ) -> Result<Vec<RistrettoPoint>, Error> {
+        Self::check_proof(
+            unsigned_token,
+            randomized_unsigned_token,
+            signed_token,
+            verification_data,
+        )?;
+
+        // the series of r is drawn in order, before the chunks are split up
+        let mut rng = StdRng::from_seed(randomization);
+        let items = repeat_with(|| Scalar::random(&mut rng))
+            .zip(signed_token.points.iter())
+            .collect::<Vec<_>>();
+
+        Ok(chunks.map(&items, |(r, point)| *point * r))
+    }
+
+    /// Check the response and the proof of the signer against the randomized tokens
+    fn check_proof(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        randomized_unsigned_token: &DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: &DynRandomizedSignedTokenBatched<M>,
+        verification_data: &PublicKey,
+    ) -> Result<(), Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned_token, signed_token)?;
+
+        // get the public key
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        // verify proof
+        if signed_token.proof.verify::<Ristretto>(
+            &randomized_unsigned_token.points,
+            &signed_token.points,
+            u,
+        ) {
+            Ok(())
+        } else {
+            Err(Error::BadProof)
+        }
+    }
+
+    /// The signed token of the unrandomized points
+    fn signed(
+        unsigned_token: DynNizkpUnsignedTokenBatched<M>,
+        points: Vec<RistrettoPoint>,
+        verification_data: &PublicKey,
+    ) -> DynNizkpSignedTokenBatched<M> {
+        DynNizkpSignedTokenBatched {
+            points,
+            metadata: unsigned_token.metadata,
+            ids: unsigned_token.ids,
+            key_id: verification_data.key_id(),
+        }
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> TokenEngine for DynBatchedNizkpTokenEngine<M> {
+    type UnsignedToken = DynNizkpUnsignedTokenBatched<M>;
+    type RandomizedUnsignedToken = DynRandomizedUnsignedTokenBatched<M>;
+    type RandomizedSignedToken = DynRandomizedSignedTokenBatched<M>;
+    type SignedToken = DynNizkpSignedTokenBatched<M>;
+    type Randomization = [u8; 32];
+    type UserVerification = PublicKey;
+    type SignKey = PrivateKey;
+
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        // create random seed
+        let mut randomization = [0; 32];
+        fill_bytes(rng, &mut randomization);
+
+        // seed an rng for the series of r
+        let mut rng = StdRng::from_seed(randomization);
+
+        (
+            randomization,
+            Self::RandomizedUnsignedToken {
+                points: repeat_with(|| Scalar::random(&mut rng)) // generate random r's
+                    .map(|r| r.invert())
+                    .zip(points(&unsigned_token.ids, &unsigned_token.metadata))
+                    // T' = [r]T
+                    .map(|(r, t)| t * r)
+                    .collect(),
+                metadata: Box::from(unsigned_token.metadata.as_ref()),
+                _m: PhantomData {},
+            },
+        )
+    }
+
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned_token: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self>> {
+        match Self::unrandomize(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+            randomization,
+        ) {
+            Ok(points) => Ok(Self::signed(unsigned_token, points, verification_data)),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
+        }
+    }
+
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        t_prime: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        // This should be a constant time implementation
+        let d = hash_to_scalar(&t_prime.metadata);
+        let k = d + sign_key.to_scalar();
+        let e = k.invert();
+
+        // list of W'
+        let w_prime_list = par_map(&t_prime.points, |t_prime| t_prime * e);
+
+        let proof = DleqProofBatched::create_with_rng::<Ristretto, _>(
+            &t_prime.points,
+            &w_prime_list,
+            k,
+            rng,
+        );
+
+        invertible(CtOption::new(
+            DynRandomizedSignedTokenBatched {
+                points: w_prime_list,
+                proof,
+                key_epoch: None,
+                _m: PhantomData {},
+            },
+            !k.ct_eq(&Scalar::zero()),
+        ))
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> DynBatchedNizkpTokenEngine<M> {
+    /// [`TokenEngine::randomize`], yielding between the chunks of the batch
+    pub async fn randomize_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        chunking: &mut Chunking<Y>,
+    ) -> Result<([u8; 32], DynRandomizedUnsignedTokenBatched<M>), Error> {
+        Self::randomize_chunked_with_rng(unsigned_token, chunking, &mut crate::rng::default_rng())
+            .await
+    }
+
+    /// [`Self::randomize_chunked`], with the randomness from the given rng
+    pub async fn randomize_chunked_with_rng<
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+        R: CryptoRng + RngCore,
+    >(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        chunking: &mut Chunking<Y>,
+        rng: &mut R,
+    ) -> Result<([u8; 32], DynRandomizedUnsignedTokenBatched<M>), Error> {
+        let mut randomization = [0; 32];
+        fill_bytes(rng, &mut randomization);
+
+        let mut rng = StdRng::from_seed(randomization);
+        let metadata = &unsigned_token.metadata;
+
+        let points = chunking
+            .map(
+                repeat_with(|| Scalar::random(&mut rng)).zip(unsigned_token.ids.iter()),
+                |(r, id)| {
+                    let t: [u8; 16] = id.into();
+                    // T' = [r]T
+                    h_t(t, metadata) * r.invert()
+                },
+            )
+            .await?;
+
+        Ok((
+            randomization,
+            DynRandomizedUnsignedTokenBatched {
+                points,
+                metadata: Box::from(metadata.as_ref()),
+                _m: PhantomData {},
+            },
+        ))
+    }
+
+    /// [`TokenEngine::sign_randomized`], yielding between the chunks of the batch
+    pub async fn sign_randomized_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        t_prime: &DynRandomizedUnsignedTokenBatched<M>,
+        sign_key: &PrivateKey,
+        chunking: &mut Chunking<Y>,
+    ) -> Result<DynRandomizedSignedTokenBatched<M>, Error> {
+        Self::sign_randomized_chunked_with_rng(
+            t_prime,
+            sign_key,
+            chunking,
+            &mut crate::rng::default_rng(),
+        )
+        .await
+    }
+
+    /// [`Self::sign_randomized_chunked`], with the randomness of the proof from the given rng
+    pub async fn sign_randomized_chunked_with_rng<
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+        R: CryptoRng + RngCore,
+    >(
+        t_prime: &DynRandomizedUnsignedTokenBatched<M>,
+        sign_key: &PrivateKey,
+        chunking: &mut Chunking<Y>,
+        rng: &mut R,
+    ) -> Result<DynRandomizedSignedTokenBatched<M>, Error> {
+        let d = hash_to_scalar(&t_prime.metadata);
+        let k = d + sign_key.to_scalar();
+        let e = invertible(CtOption::new(k.invert(), !k.ct_eq(&Scalar::zero())))?;
+
+        let w_prime_list = chunking
+            .map(t_prime.points.iter(), |t_prime| t_prime * e)
+            .await?;
+
+        let proof = DleqProofBatched::create_chunked_with_rng::<Ristretto, _, _, _>(
+            &t_prime.points,
+            &w_prime_list,
+            k,
+            chunking,
+            rng,
+        )
+        .await?;
+
+        Ok(DynRandomizedSignedTokenBatched {
+            points: w_prime_list,
+            proof,
+            key_epoch: None,
+            _m: PhantomData {},
+        })
+    }
+
+    /// [`TokenEngine::verify_signature_and_unrandomize`], yielding between the chunks of the batch
+    pub async fn verify_signature_and_unrandomize_chunked<
+        Y: FnMut() -> F,
+        F: Future<Output = ()>,
+    >(
+        unsigned_token: DynNizkpUnsignedTokenBatched<M>,
+        randomized_unsigned_token: DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: DynRandomizedSignedTokenBatched<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunking: &mut Chunking<Y>,
+    ) -> Result<DynNizkpSignedTokenBatched<M>, VerifyError<Self>> {
+        match Self::unrandomize_chunked(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+            randomization,
+            chunking,
+        )
+        .await
+        {
+            Ok(points) => Ok(Self::signed(unsigned_token, points, verification_data)),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
+        }
+    }
+
+    /// [`TokenEngine::verify_signature_and_unrandomize`], blocking, in chunks of the batch
+    ///
+    /// With the `parallel` feature, the chunks are unrandomized in parallel.
+    pub fn verify_signature_and_unrandomize_in_chunks(
+        unsigned_token: DynNizkpUnsignedTokenBatched<M>,
+        randomized_unsigned_token: DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: DynRandomizedSignedTokenBatched<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunks: &Chunks<'_>,
+    ) -> Result<DynNizkpSignedTokenBatched<M>, VerifyError<Self>> {
+        match Self::unrandomize_in_chunks(
+            &unsigned_token,
+            &randomized_unsigned_token,
+            &signed_token,
+            verification_data,
+            randomization,
+            chunks,
+        ) {
+            Ok(points) => Ok(Self::signed(unsigned_token, points, verification_data)),
+            Err(error) => Err(VerifyError::new(
+                error,
+                unsigned_token,
+                randomized_unsigned_token,
+                randomization,
+            )),
+        }
+    }
+
+    /// [`Self::unrandomize`], yielding between the chunks of the batch
+    async fn unrandomize_chunked<Y: FnMut() -> F, F: Future<Output = ()>>(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        randomized_unsigned_token: &DynRandomizedUnsignedTokenBatched<M>,
+        signed_token: &DynRandomizedSignedTokenBatched<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+        chunking: &mut Chunking<Y>,
+    ) -> Result<Vec<RistrettoPoint>, Error> {
+        check_metadata(
+            &unsigned_token.metadata,
+            &randomized_unsigned_token.metadata,
+        )?;
+
+        // the signer may tag the user with equal or identity points
+        Self::check_response(randomized_unsigned_token, signed_token)?;
+
+        // get the public key
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        if !signed_token
+            .proof
+            .verify_chunked::<Ristretto, _, _>(
+                &randomized_unsigned_token.points,
+                &signed_token.points,
+                u,
+                chunking,
+            )
+            .await?
+        {
+            return Err(Error::BadProof);
+        }
+
+        // Remove randomization
+        let mut rng = StdRng::from_seed(randomization);
+        Ok(chunking
+            .map(
+                signed_token
+                    .points
+                    .iter()
+                    .zip(repeat_with(|| Scalar::random(&mut rng))),
+                |(point, r)| point * r,
+            )
+            .await?)
+    }
+}
+
+// }}}
+
+// {{{ Streaming
+
+/// A chunk of the randomized points of a streamed batch, see
+/// [`DynBatchedNizkpTokenEngine::issue_stream`]
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RandomizedChunk {
+    #[serde(with = "super::util::points")]
+    points: Vec<RistrettoPoint>,
+}
+
+/// A chunk of the signed points of a streamed batch
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignedChunk {
+    #[serde(with = "super::util::points")]
+    points: Vec<RistrettoPoint>,
+}
+
+/// The proof of the signer for all the chunks of a stream, sent after the last chunk
+#[derive(Serialize, Deserialize)]
+pub struct StreamProof {
+    #[serde(with = "super::util::batched_proof")]
+    proof: DleqProofBatched<Scalar>,
+}
+
+/// The randomized chunks of a batch, see [`DynBatchedNizkpTokenEngine::randomize_stream`]
+pub struct RandomizeStream<'a, M: AsRef<[u8]>> {
+    ids: slice::Chunks<'a, TokenIdentifier<M>>,
+    metadata: &'a M,
+    rng: StdRng,
+}
+
+impl<M: AsRef<[u8]>> Iterator for RandomizeStream<'_, M> {
+    type Item = RandomizedChunk;
+
+    fn next(&mut self) -> Option<RandomizedChunk> {
+        let ids = self.ids.next()?;
+        let (metadata, rng) = (self.metadata, &mut self.rng);
+
+        Some(RandomizedChunk {
+            points: ids
+                .iter()
+                .map(|id| {
+                    let t: [u8; 16] = id.into();
+                    // T' = [r]T
+                    h_t(t, metadata) * Scalar::random(rng).invert()
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Signs the randomized chunks as they come in, see [`DynBatchedNizkpTokenEngine::issue_stream`]
+///
+/// The key is zeroized when this is dropped.
+pub struct IssueStream<I> {
+    chunks: I,
+    k: Secret<Scalar>,
+    e: Secret<Scalar>,
+    proof: DleqProofStream<Ristretto>,
+}
+
+impl<I: Iterator<Item = RandomizedChunk>> Iterator for IssueStream<I> {
+    type Item = SignedChunk;
+
+    fn next(&mut self) -> Option<SignedChunk> {
+        let chunk = self.chunks.next()?;
+        let points = chunk
+            .points
+            .iter()
+            .map(|t_prime| t_prime * self.e.0)
+            .collect::<Vec<_>>();
+
+        for (t_prime, w_prime) in chunk.points.iter().zip(points.iter()) {
+            self.proof.push(t_prime, w_prime);
+        }
+        Some(SignedChunk { points })
+    }
+}
+
+impl<I> IssueStream<I> {
+    /// The number of points signed so far, e.g. to stop a client that sends too many
+    pub fn signed(&self) -> usize {
+        self.proof.len()
+    }
+
+    /// The proof for the chunks signed so far
+    pub fn finish(self) -> StreamProof {
+        self.finish_with_rng(&mut crate::rng::default_rng())
+    }
+
+    /// [`Self::finish`], with the nonce of the proof from the given rng
+    pub fn finish_with_rng<R: CryptoRng + RngCore>(self, rng: &mut R) -> StreamProof {
+        StreamProof {
+            proof: self.proof.create_with_rng(self.k.0, rng),
+        }
+    }
+}
+
+impl<I> Drop for IssueStream<I> {
+    fn drop(&mut self) {
+        self.k.zeroize();
+        self.e.zeroize();
+    }
+}
+
+/// Checks and unrandomizes the signed chunks as they come in, see
+/// [`DynBatchedNizkpTokenEngine::unrandomize_stream`]
+pub struct UnrandomizeStream<M: AsRef<[u8]>> {
+    unsigned_token: DynNizkpUnsignedTokenBatched<M>,
+    verification_data: PublicKey,
+    /// The public key for the metadata
+    u: RistrettoPoint,
+    rng: StdRng,
+    proof: DleqProofStream<Ristretto>,
+    /// The signed points so far, the signer may tag the user with equal points
+    seen: BTreeSet<[u8; 32]>,
+    points: Vec<RistrettoPoint>,
+}
+
+impl<M: AsRef<[u8]>> UnrandomizeStream<M> {
+    /// Check the points of a signed chunk, and unrandomize them
+    ///
+    /// The tokens are not signed before [`Self::finish`] has checked the proof, and the stream is
+    /// of no use after an error.
+    pub fn push(&mut self, chunk: &SignedChunk) -> Result<(), Error> {
+        let expected = self.unsigned_token.ids.len();
+        let actual = self.points.len() + chunk.points.len();
+        if actual > expected {
+            return Err(BatchResponseError::WrongCount { expected, actual }.into());
+        }
+
+        let identity = RistrettoPoint::identity().compress().to_bytes();
+        for w_prime in &chunk.points {
+            let encoded = w_prime.compress().to_bytes();
+            if encoded == identity {
+                return Err(BatchResponseError::IdentityPoint.into());
+            }
+            if !self.seen.insert(encoded) {
+                return Err(BatchResponseError::DuplicatePoint.into());
+            }
+
+            // the same series of r as the randomized chunks
+            let t: [u8; 16] = (&self.unsigned_token.ids[self.points.len()]).into();
+            let r = Scalar::random(&mut self.rng);
+            let t_prime = h_t(t, &self.unsigned_token.metadata) * r.invert();
+
+            self.proof.push(&t_prime, w_prime);
+            self.points.push(w_prime * r);
+        }
+        Ok(())
+    }
+
+    /// The number of points that have been unrandomized so far
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Check the proof of the signer for all the chunks, and return the signed tokens
+    pub fn finish(self, proof: &StreamProof) -> Result<DynNizkpSignedTokenBatched<M>, Error> {
+        let expected = self.unsigned_token.ids.len();
+        if self.points.len() != expected {
+            return Err(BatchResponseError::WrongCount {
+                expected,
+                actual: self.points.len(),
+            }
+            .into());
+        }
+
+        if !self.proof.verify(&proof.proof, self.u) {
+            return Err(Error::BadProof);
+        }
+
+        Ok(DynBatchedNizkpTokenEngine::signed(
+            self.unsigned_token,
+            self.points,
+            &self.verification_data,
+        ))
+    }
+}
+
+impl<M: AsRef<[u8]> + Clone> DynBatchedNizkpTokenEngine<M> {
+    /// Randomize a batch in chunks of `chunk_size` points, for a signer that streams the batch
+    ///
+    /// The chunks are randomized as they are taken, and the randomization is for
+    /// [`Self::unrandomize_stream`].
+    pub fn randomize_stream(
+        unsigned_token: &DynNizkpUnsignedTokenBatched<M>,
+        chunk_size: usize,
+    ) -> ([u8; 32], RandomizeStream<'_, M>) {
+        Self::randomize_stream_with_rng(unsigned_token, chunk_size, &mut crate::rng::default_rng())
+    }
+
+    /// [`Self::randomize_stream`], with the randomness from the given rng
+    pub fn randomize_stream_with_rng<'a, R: CryptoRng + RngCore>(
+        unsigned_token: &'a DynNizkpUnsignedTokenBatched<M>,
+        chunk_size: usize,
+        rng: &mut R,
+    ) -> ([u8; 32], RandomizeStream<'a, M>) {
+        let mut randomization = [0; 32];
+        fill_bytes(rng, &mut randomization);
+
+        (
+            randomization,
+            RandomizeStream {
+                ids: unsigned_token.ids.chunks(chunk_size.max(1)),
+                metadata: &unsigned_token.metadata,
+                rng: StdRng::from_seed(randomization),
+            },
+        )
+    }
+
+    /// Sign a batch that is streamed in chunks, in bounded memory
+    ///
+    /// A signed chunk is yielded for each randomized chunk as it comes in, and
+    /// [`IssueStream::finish`] makes the proof for all of them after the last one. The proof is
+    /// accumulated over the stream, so the whole batch is never in memory, unlike with
+    /// [`TokenEngine::sign_randomized`]. It is not the proof of [`TokenEngine::sign_randomized`]
+    /// though, the client checks it with [`Self::unrandomize_stream`].
+    ///
+    /// ```
+    ///     use atpmd::TokenEngine;
+    ///     use atpmd::nizkp_curve25519::{
+    ///         keys::{PrivateKey, PublicKey},
+    ///         tokens_batched_dyn::DynBatchedNizkpTokenEngine,
+    ///     };
+    ///
+    ///     let private_key = PrivateKey::new();
+    ///     let public_key = PublicKey::from(&private_key);
+    ///
+    ///     let tokens = DynBatchedNizkpTokenEngine::generate((&b"metadata"[..], 100));
+    ///     let (randomization, chunks) = DynBatchedNizkpTokenEngine::randomize_stream(&tokens, 16);
+    ///
+    ///     // the signer only holds a chunk at a time, and sends each one when it is signed
+    ///     let mut issued =
+    ///         DynBatchedNizkpTokenEngine::issue_stream(&b"metadata"[..], chunks, &private_key)
+    ///             .unwrap();
+    ///     let sent = issued.by_ref().collect::<Vec<_>>();
+    ///     let proof = issued.finish();
+    ///
+    ///     let mut stream =
+    ///         DynBatchedNizkpTokenEngine::unrandomize_stream(tokens, &public_key, randomization);
+    ///     for chunk in &sent {
+    ///         stream.push(chunk).unwrap();
+    ///     }
+    ///     let signed = stream.finish(&proof).unwrap();
+    ///     assert_eq!(signed.into_tokens().len(), 100);
+    /// ```
+    pub fn issue_stream<I: IntoIterator<Item = RandomizedChunk>>(
+        metadata: M,
+        chunks: I,
+        sign_key: &PrivateKey,
+    ) -> Result<IssueStream<I::IntoIter>, Error> {
+        let d = hash_to_scalar(&metadata);
+        let k = d + sign_key.to_scalar();
+        let e = invertible(CtOption::new(k.invert(), !k.ct_eq(&Scalar::zero())))?;
+
+        Ok(IssueStream {
+            chunks: chunks.into_iter(),
+            k: Secret(k),
+            e: Secret(e),
+            proof: DleqProofStream::new(&(&RISTRETTO_BASEPOINT_TABLE * &k)),
+        })
+    }
+
+    /// Check the signed chunks of [`Self::issue_stream`] as they come in, and unrandomize them
+    pub fn unrandomize_stream(
+        unsigned_token: DynNizkpUnsignedTokenBatched<M>,
+        verification_data: &PublicKey,
+        randomization: [u8; 32],
+    ) -> UnrandomizeStream<M> {
+        // get the public key
+        let u = &RISTRETTO_BASEPOINT_TABLE * &hash_to_scalar(&unsigned_token.metadata)
+            + verification_data.to_affine();
+
+        UnrandomizeStream {
+            unsigned_token,
+            verification_data: verification_data.clone(),
+            u,
+            rng: StdRng::from_seed(randomization),
+            proof: DleqProofStream::new(&u),
+            seen: BTreeSet::new(),
+            points: Vec::new(),
+        }
+    }
+}
+
+// }}}
+
+// {{{ tests
+
+#[cfg(test)]
+mod tests {
+    use super::super::keys::{PrivateKey, PublicKey};
+    use super::*;
+
+    #[test]
+    fn test_all() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        for size in [1, 5, 12] {
+            let token = DynBatchedNizkpTokenEngine::generate((b"This is my metadata", size));
+            assert_eq!(token.len(), size);
+
+            let (r, anon_token) = DynBatchedNizkpTokenEngine::randomize(&token);
+            assert_eq!(anon_token.len(), size);
+
+            let signed =
+                DynBatchedNizkpTokenEngine::sign_randomized(&anon_token, &private).unwrap();
+
+            let signed = DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize(
+                token,
+                anon_token,
+                signed,
+                &public_key,
+                r,
+            )
+            .unwrap();
+            assert!(signed.verify(&private));
+
+            // every token in the batch is valid on its own, and names the key
+            let tokens = signed.into_tokens();
+            assert_eq!(tokens.len(), size);
+            assert!(tokens.iter().all(|token| token.verify(&private)));
+            assert!(tokens
+                .iter()
+                .all(|token| token.key_id() == Some(public_key.key_id())));
+        }
+    }
+
+    #[test]
+    fn fail_bad_signkey() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = DynBatchedNizkpTokenEngine::generate((b"This is my metadata", 5));
+
+        let bad = PrivateKey::new();
+        assert!(
+            DynBatchedNizkpTokenEngine::sign(token, &public_key, |randomized| {
+                DynBatchedNizkpTokenEngine::sign_randomized(randomized, &bad)
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_chunked() {
+        use crate::chunked::{yield_now, Chunking};
+        use futures::executor::block_on;
+
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+        let mut chunking = Chunking::new(4, yield_now);
+
+        for size in [1, 4, 13] {
+            let tokens = DynBatchedNizkpTokenEngine::generate((b"metadata", size));
+            let (r, randomized) = block_on(DynBatchedNizkpTokenEngine::randomize_chunked(
+                &tokens,
+                &mut chunking,
+            ))
+            .unwrap();
+            assert_eq!(randomized.len(), size);
+
+            let signed = block_on(DynBatchedNizkpTokenEngine::sign_randomized_chunked(
+                &randomized,
+                &private,
+                &mut chunking,
+            ))
+            .unwrap();
+            let signed = block_on(
+                DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize_chunked(
+                    tokens,
+                    randomized,
+                    signed,
+                    &public_key,
+                    r,
+                    &mut chunking,
+                ),
+            )
+            .unwrap();
+
+            assert!(signed.verify(&private));
+            assert!(block_on(signed.verify_chunked(&private, &mut chunking)));
+            assert!(!block_on(
+                signed.verify_chunked(&PrivateKey::new(), &mut chunking)
+            ));
+        }
+
+        // a signature from another key is rejected
+        let tokens = DynBatchedNizkpTokenEngine::generate((b"metadata", 6));
+        let (r, randomized) = block_on(DynBatchedNizkpTokenEngine::randomize_chunked(
+            &tokens,
+            &mut chunking,
+        ))
+        .unwrap();
+        let signed =
+            DynBatchedNizkpTokenEngine::sign_randomized(&randomized, &PrivateKey::new()).unwrap();
+        assert!(block_on(
+            DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize_chunked(
+                tokens,
+                randomized,
+                signed,
+                &public_key,
+                r,
+                &mut chunking,
+            )
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_in_chunks() {
+        use crate::chunked::Chunks;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let calls = AtomicUsize::new(0);
+        let progress = |_: usize, _: usize| {
+            calls.fetch_add(1, Ordering::Relaxed);
+        };
+        let chunks = Chunks::new(4).with_progress(&progress);
+
+        let tokens = DynBatchedNizkpTokenEngine::generate((b"metadata", 13));
+        let (r, randomized) = DynBatchedNizkpTokenEngine::randomize(&tokens);
+        let signed = DynBatchedNizkpTokenEngine::sign_randomized(&randomized, &private).unwrap();
+
+        // a response of another key is rejected before the chunks
+        let other =
+            DynBatchedNizkpTokenEngine::sign_randomized(&randomized, &PrivateKey::new()).unwrap();
+        let e = DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize_in_chunks(
+            tokens,
+            randomized,
+            other,
+            &public_key,
+            r,
+            &chunks,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(e.error, Error::BadProof);
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+
+        let signed = DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize_in_chunks(
+            e.unsigned_token,
+            e.randomized_unsigned,
+            signed,
+            &public_key,
+            e.randomization,
+            &chunks,
+        )
+        .unwrap();
+        assert!(signed.verify(&private));
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn fail_cancelled() {
+        use crate::chunked::{yield_now, Cancel, Chunking};
+        use futures::executor::block_on;
+
+        let private = PrivateKey::new();
+        let tokens = DynBatchedNizkpTokenEngine::generate((b"metadata", 13));
+        let (_r, randomized) = DynBatchedNizkpTokenEngine::randomize(&tokens);
+
+        // an issuer past the deadline sheds the request
+        fn now() -> u64 {
+            1000
+        }
+        let mut late = Chunking::new(4, yield_now).with_deadline(1000, now);
+        assert!(matches!(
+            block_on(DynBatchedNizkpTokenEngine::sign_randomized_chunked(
+                &randomized,
+                &private,
+                &mut late
+            )),
+            Err(Error::Cancelled)
+        ));
+
+        let cancel = Cancel::new();
+        let mut chunking = Chunking::new(4, yield_now).with_cancel(&cancel);
+        let signed = DynBatchedNizkpTokenEngine::sign(tokens, &PublicKey::from(&private), |r| {
+            DynBatchedNizkpTokenEngine::sign_randomized(r, &private)
+        })
+        .unwrap();
+        assert!(block_on(signed.verify_chunked(&private, &mut chunking)));
+
+        cancel.cancel();
+        assert!(!block_on(signed.verify_chunked(&private, &mut chunking)));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = DynBatchedNizkpTokenEngine::generate((&b"This is my metadata"[..], 3));
+        let (r, anon_token) = DynBatchedNizkpTokenEngine::randomize(&token);
+
+        // the signer reads the size of the batch from the request
+        let request: DynRandomizedUnsignedTokenBatched<&[u8]> =
+            serde_json::from_str(&serde_json::to_string(&anon_token).unwrap()).unwrap();
+        assert_eq!(request.len(), 3);
+
+        let signed = DynBatchedNizkpTokenEngine::sign_randomized(&request, &private).unwrap();
+        let signed = serde_json::from_str(&serde_json::to_string(&signed).unwrap()).unwrap();
+
+        assert!(
+            DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize(
+                token,
+                anon_token,
+                signed,
+                &public_key,
+                r,
+            )
+            .unwrap()
+            .verify(&private)
+        );
+    }
+
+    #[test]
+    fn test_hidden() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let tokens =
+            DynBatchedNizkpTokenEngine::generate_with_hidden((&b"metadata"[..], 6), &b"hidden"[..]);
+        assert_eq!(tokens.len(), 6);
+        assert!(tokens
+            .ids
+            .iter()
+            .all(|id| matches!(id.hidden(), Some(hidden) if hidden == b"hidden")));
+
+        let signed = DynBatchedNizkpTokenEngine::sign(tokens, &public_key, |randomized| {
+            DynBatchedNizkpTokenEngine::sign_randomized(randomized, &private)
+        })
+        .unwrap();
+        assert!(signed.verify(&private));
+    }
+
+    #[test]
+    fn fail_wrong_count() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = DynBatchedNizkpTokenEngine::generate((b"This is my metadata", 5));
+
+        let (r, anon_token) = DynBatchedNizkpTokenEngine::randomize(&token);
+
+        let mut signed =
+            DynBatchedNizkpTokenEngine::sign_randomized(&anon_token, &private).unwrap();
+
+        // the signer drops one of the tokens
+        signed.points.pop();
+
+        assert_eq!(
+            DynBatchedNizkpTokenEngine::check_response(&anon_token, &signed),
+            Err(BatchResponseError::WrongCount {
+                expected: 5,
+                actual: 4
+            })
+        );
+
+        assert!(
+            DynBatchedNizkpTokenEngine::verify_signature_and_unrandomize(
+                token,
+                anon_token,
+                signed,
+                &public_key,
+                r,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_stream() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let token = DynBatchedNizkpTokenEngine::generate((b"This is my metadata", 10));
+        let (r, chunks) = DynBatchedNizkpTokenEngine::randomize_stream(&token, 3);
+        let mut issued =
+            DynBatchedNizkpTokenEngine::issue_stream(b"This is my metadata", chunks, &private)
+                .unwrap();
+
+        // the proof does not depend on how the chunks are split up in transport
+        let points = issued
+            .by_ref()
+            .flat_map(|chunk| chunk.points)
+            .collect::<Vec<_>>();
+        assert_eq!(issued.signed(), 10);
+        let proof = issued.finish();
+
+        let mut stream = DynBatchedNizkpTokenEngine::unrandomize_stream(token, &public_key, r);
+        for points in points.chunks(4) {
+            stream
+                .push(&SignedChunk {
+                    points: points.to_vec(),
+                })
+                .unwrap();
+        }
+        assert_eq!(stream.len(), 10);
+
+        let signed = stream.finish(&proof).unwrap();
+        assert!(signed.verify(&private));
+        for token in signed.into_tokens() {
+            assert!(token.verify(&private));
+        }
+    }
+
+    #[test]
+    fn fail_stream() {
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        // the stream of the user, and the signed chunks and the proof of a batch of 4
+        let issue = |key: &PrivateKey| {
+            let token = DynBatchedNizkpTokenEngine::generate((&b"metadata"[..], 4));
+            let (r, chunks) = DynBatchedNizkpTokenEngine::randomize_stream(&token, 2);
+            let mut issued =
+                DynBatchedNizkpTokenEngine::issue_stream(&b"metadata"[..], chunks, key).unwrap();
+            let chunks = issued.by_ref().collect::<Vec<_>>();
+            let proof = issued.finish();
+            (
+                DynBatchedNizkpTokenEngine::unrandomize_stream(token, &public_key, r),
+                chunks,
+                proof,
+            )
+        };
+
+        let (mut stream, chunks, proof) = issue(&PrivateKey::new());
+        for chunk in &chunks {
+            stream.push(chunk).unwrap();
+        }
+        assert_eq!(stream.finish(&proof).err(), Some(Error::BadProof));
+
+        let (mut stream, chunks, _proof) = issue(&private);
+        stream.push(&chunks[0]).unwrap();
+        assert_eq!(
+            stream.push(&chunks[0]),
+            Err(Error::BatchResponse(BatchResponseError::DuplicatePoint))
+        );
+
+        // the signer sends a chunk too many, or too few
+        let (mut stream, chunks, _proof) = issue(&private);
+        stream.push(&chunks[0]).unwrap();
+        stream.push(&chunks[1]).unwrap();
+        assert_eq!(
+            stream.push(&chunks[1]),
+            Err(Error::BatchResponse(BatchResponseError::WrongCount {
+                expected: 4,
+                actual: 6
+            }))
+        );
+
+        let (mut stream, chunks, proof) = issue(&private);
+        stream.push(&chunks[0]).unwrap();
+        assert_eq!(
+            stream.finish(&proof).err(),
+            Some(Error::BatchResponse(BatchResponseError::WrongCount {
+                expected: 4,
+                actual: 2
+            }))
+        );
+    }
+}
+
+// }}}
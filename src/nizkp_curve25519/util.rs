@@ -1,13 +1,29 @@
-use curve25519_dalek::{ristretto::RistrettoPoint, scalar::Scalar};
+use alloc::vec::Vec;
+use curve25519_dalek::{
+    constants::{RISTRETTO_BASEPOINT_POINT, RISTRETTO_BASEPOINT_TABLE},
+    ristretto::RistrettoPoint,
+    scalar::Scalar,
+    traits::Identity,
+};
+use rand::{CryptoRng, RngCore};
 use sha2::{Digest, Sha512};
+use subtle::{ConstantTimeEq, CtOption};
+
+use crate::group::PrimeOrderGroup;
+use crate::hash_suite::{HashSuite, Sha2};
 
 /// hash the input bytes uniformly to a scalar
 ///
 /// This is a variable time implementation, to get uniform randomness by rejection sampling
 pub fn hash_to_scalar(data: impl AsRef<[u8]>) -> Scalar {
-    let mut hasher = Sha512::new();
+    hash_to_scalar_with::<Sha2>(data)
+}
+
+/// [`hash_to_scalar`] with the wide hash of the suite
+pub fn hash_to_scalar_with<H: HashSuite>(data: impl AsRef<[u8]>) -> Scalar {
+    let mut hasher = H::Wide::new();
     // domain of the oracle, to have separate oracles
-    hasher.update(b"This is hash_to_scalar hash");
+    hasher.update(H::DOMAIN.hash_to_scalar);
 
     // input data
     hasher.update(data);
@@ -15,14 +31,32 @@ pub fn hash_to_scalar(data: impl AsRef<[u8]>) -> Scalar {
     Scalar::from_hash(hasher)
 }
 
+/// Hash a public key and a label to the tweak of a derived key
+pub(crate) fn h_derive(public_key: impl AsRef<[u8]>, label: impl AsRef<[u8]>) -> Scalar {
+    let mut hasher = Sha512::new();
+    // domain of the oracle, to have separate oracles
+    hasher.update(b"This is h_derive hash");
+
+    // the public key has a fixed length, so the label needs no length prefix
+    hasher.update(public_key);
+    hasher.update(label);
+
+    Scalar::from_hash(hasher)
+}
+
 /// hash to the curve
 ///
 /// This uses a variable time hash to scalar, and multiplies the generator by this scalar to get a
 /// curve point
 pub fn h_t(t: impl AsRef<[u8]>, m: impl AsRef<[u8]>) -> RistrettoPoint {
-    let mut hasher = Sha512::new();
+    h_t_with::<Sha2>(t, m)
+}
+
+/// [`h_t`] with the wide hash of the suite
+pub fn h_t_with<H: HashSuite>(t: impl AsRef<[u8]>, m: impl AsRef<[u8]>) -> RistrettoPoint {
+    let mut hasher = H::Wide::new();
     // domain of the oracle, to have separate oracles
-    hasher.update(b"This is h_t hash");
+    hasher.update(H::DOMAIN.hash_to_curve);
 
     // Input the data to the oracle
     hasher.update(t);
@@ -30,3 +64,309 @@ pub fn h_t(t: impl AsRef<[u8]>, m: impl AsRef<[u8]>) -> RistrettoPoint {
 
     RistrettoPoint::from_hash(hasher)
 }
+
+/// hash the nonce of a token without an identifier to the curve, see [`oprf`](super::oprf)
+pub(crate) fn h_nonce(nonce: &[u8; 32]) -> RistrettoPoint {
+    let mut hasher = Sha512::new();
+    // domain of the oracle, to have separate oracles
+    hasher.update(b"This is h_nonce hash");
+
+    hasher.update(nonce);
+
+    RistrettoPoint::from_hash(hasher)
+}
+
+// {{{ Group
+
+/// The ristretto group
+pub struct Ristretto;
+
+impl PrimeOrderGroup for Ristretto {
+    type Scalar = Scalar;
+    type Element = RistrettoPoint;
+
+    fn generator() -> RistrettoPoint {
+        RISTRETTO_BASEPOINT_POINT
+    }
+
+    fn identity() -> RistrettoPoint {
+        RistrettoPoint::identity()
+    }
+
+    fn mul_generator(scalar: &Scalar) -> RistrettoPoint {
+        &RISTRETTO_BASEPOINT_TABLE * scalar
+    }
+
+    fn random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Scalar {
+        Scalar::random(rng)
+    }
+
+    fn invert(scalar: &Scalar) -> CtOption<Scalar> {
+        CtOption::new(scalar.invert(), !scalar.ct_eq(&Scalar::zero()))
+    }
+
+    fn encode(element: &RistrettoPoint) -> Vec<u8> {
+        element.compress().as_bytes().to_vec()
+    }
+
+    fn challenge(transcript: &[u8]) -> Scalar {
+        let mut hasher = Sha512::new();
+        hasher.update(transcript);
+
+        Scalar::from_hash(hasher)
+    }
+}
+
+// }}}
+
+// {{{ serialization
+
+/// Serialize a point as its compressed encoding, and check that it decompresses when deserializing
+///
+/// Use with `#[serde(with = "point")]`
+pub mod point {
+    use alloc::{format, vec::Vec};
+    use core::convert::TryInto;
+
+    use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        point: &RistrettoPoint,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(point.compress().as_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<RistrettoPoint, D::Error> {
+        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        from_bytes(&bytes)
+    }
+
+    pub(crate) fn from_bytes<E: de::Error>(bytes: &[u8]) -> Result<RistrettoPoint, E> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_e| {
+            E::custom(format!("point bytes has to be 32 bytes, not {}", bytes.len()).as_str())
+        })?;
+
+        CompressedRistretto(bytes)
+            .decompress()
+            .ok_or_else(|| E::custom("Failed to decompress token point"))
+    }
+}
+
+/// Serialize a scalar as its canonical encoding, and reject non canonical encodings
+///
+/// Use with `#[serde(with = "scalar")]`
+pub mod scalar {
+    use alloc::{format, vec::Vec};
+    use core::convert::TryInto;
+
+    use curve25519_dalek::scalar::Scalar;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(scalar: &Scalar, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(scalar.as_bytes())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Scalar, D::Error> {
+        let bytes: Vec<u8> = Deserialize::deserialize(deserializer)?;
+        let bytes: [u8; 32] = (&bytes as &[u8]).try_into().map_err(|_e| {
+            de::Error::custom(
+                format!("scalar bytes has to be 32 bytes, not {}", bytes.len()).as_str(),
+            )
+        })?;
+
+        Scalar::from_canonical_bytes(bytes)
+            .ok_or_else(|| de::Error::custom("Scalar is not canonical"))
+    }
+}
+
+/// Serialize a proof as its two scalars
+pub mod proof {
+    use curve25519_dalek::scalar::Scalar;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::group::DleqProof;
+
+    #[derive(Serialize, Deserialize)]
+    struct Proof {
+        #[serde(with = "super::scalar")]
+        c: Scalar,
+        #[serde(with = "super::scalar")]
+        z: Scalar,
+    }
+
+    pub fn serialize<S: Serializer>(
+        proof: &DleqProof<Scalar>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Proof {
+            c: proof.c,
+            z: proof.z,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DleqProof<Scalar>, D::Error> {
+        let Proof { c, z } = Deserialize::deserialize(deserializer)?;
+        Ok(DleqProof { c, z })
+    }
+}
+
+/// Serialize a Schnorr signature as its two scalars
+pub mod signature {
+    use curve25519_dalek::scalar::Scalar;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::group::SchnorrSignature;
+
+    #[derive(Serialize, Deserialize)]
+    struct Signature {
+        #[serde(with = "super::scalar")]
+        c: Scalar,
+        #[serde(with = "super::scalar")]
+        z: Scalar,
+    }
+
+    pub fn serialize<S: Serializer>(
+        signature: &SchnorrSignature<Scalar>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Signature {
+            c: signature.c,
+            z: signature.z,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<SchnorrSignature<Scalar>, D::Error> {
+        let Signature { c, z } = Deserialize::deserialize(deserializer)?;
+        Ok(SchnorrSignature { c, z })
+    }
+}
+
+/// Serialize a batched proof as the proof of the linear combination
+pub mod batched_proof {
+    use curve25519_dalek::scalar::Scalar;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::group::{DleqProof, DleqProofBatched};
+
+    #[derive(Serialize, Deserialize)]
+    struct Proof {
+        #[serde(with = "super::proof")]
+        proof: DleqProof<Scalar>,
+    }
+
+    pub fn serialize<S: Serializer>(
+        proof: &DleqProofBatched<Scalar>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Proof { proof: proof.proof }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DleqProofBatched<Scalar>, D::Error> {
+        let Proof { proof } = Deserialize::deserialize(deserializer)?;
+        Ok(DleqProofBatched { proof })
+    }
+}
+
+/// Serialize a list of points, as an array or a vector, as their compressed encodings
+///
+/// Use with `#[serde(with = "points")]`, deserializing fails if the number of points is wrong
+pub mod points {
+    use alloc::{format, vec::Vec};
+    use core::convert::TryFrom;
+
+    use curve25519_dalek::ristretto::RistrettoPoint;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    struct Point<'a>(&'a RistrettoPoint);
+
+    impl Serialize for Point<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            super::point::serialize(self.0, serializer)
+        }
+    }
+
+    pub fn serialize<S: Serializer, P: AsRef<[RistrettoPoint]>>(
+        points: &P,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(points.as_ref().iter().map(Point))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, P: TryFrom<Vec<RistrettoPoint>>>(
+        deserializer: D,
+    ) -> Result<P, D::Error> {
+        let encoded: Vec<Vec<u8>> = Deserialize::deserialize(deserializer)?;
+        let len = encoded.len();
+
+        let points = encoded
+            .iter()
+            .map(|bytes| super::point::from_bytes(bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        P::try_from(points)
+            .map_err(|_e| de::Error::custom(format!("wrong number of points: {}", len).as_str()))
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use curve25519_dalek::{
+        constants::RISTRETTO_BASEPOINT_POINT, ristretto::RistrettoPoint, scalar::Scalar,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Data {
+        #[serde(with = "super::point")]
+        point: RistrettoPoint,
+        #[serde(with = "super::scalar")]
+        scalar: Scalar,
+    }
+
+    #[test]
+    fn test_serialization() {
+        let data = Data {
+            point: RISTRETTO_BASEPOINT_POINT * Scalar::from(123u64),
+            scalar: Scalar::from(321u64),
+        };
+
+        let serialized = serde_json::to_string(&data).unwrap();
+        let deserialized: Data = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(data, deserialized);
+    }
+
+    #[test]
+    fn fail_invalid_encodings() {
+        // not a valid ristretto encoding
+        let bad_point = r#"{"point":[255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,127],"scalar":[1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}"#;
+        assert!(serde_json::from_str::<Data>(bad_point).is_err());
+
+        // not reduced modulo the group order
+        let bad_scalar = r#"{"point":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"scalar":[255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255,255]}"#;
+        assert!(serde_json::from_str::<Data>(bad_scalar).is_err());
+
+        // wrong length
+        let short = r#"{"point":[0,0],"scalar":[0]}"#;
+        assert!(serde_json::from_str::<Data>(short).is_err());
+    }
+
+    #[test]
+    fn test_group() {
+        crate::group::tests::check_proofs::<super::Ristretto>();
+    }
+}
@@ -0,0 +1,424 @@
+//! # No panics on crafted input
+//!
+//! Everything that comes over the network is decoded and checked by code that must not panic,
+//! whatever the bytes are: the verifier decodes and verifies signed tokens, the signer decodes
+//! requests and the user decodes and unrandomizes responses.
+//!
+//! The corpus is made from valid encodings, truncated, with flipped and overwritten bits, with
+//! trailing bytes and replaced by random bytes, and the same for the JSON of the serde types.
+//! It comes from a seeded rng, so a failure can be reproduced.
+//! Every input is run under `catch_unwind`, a panic fails the test with the input that caused it.
+//!
+//! The generic engine of `atpm_nizkp` is here on secp256k1, with the `nizkp` feature. Only its
+//! public keys and the requests and responses of its batches are serialized, so they are its
+//! inputs from the network.
+
+extern crate std;
+
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use rand::{prelude::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::common::{SignedToken, TokenEngine};
+use crate::wire::WireFormat;
+
+/// The number of flipped and of overwritten bits for each valid encoding
+const MUTATIONS: usize = 48;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Run `f` on the input, and fail with the input if it panics
+fn no_panic<F: FnOnce()>(what: &str, input: &[u8], f: F) {
+    if catch_unwind(AssertUnwindSafe(f)).is_err() {
+        panic!("{} panicked on {}", what, hex(input));
+    }
+}
+
+// {{{ Corpus
+
+/// Mutations of a valid encoding
+fn corpus(valid: &[u8], rng: &mut StdRng) -> Vec<Vec<u8>> {
+    let mut corpus = vec![valid.to_vec()];
+
+    for len in 0..valid.len() {
+        corpus.push(valid[..len].to_vec());
+    }
+
+    for _ in 0..MUTATIONS {
+        let mut bytes = valid.to_vec();
+        let i = rng.gen_range(0, bytes.len());
+        bytes[i] ^= 1 << rng.gen_range(0, 8);
+        corpus.push(bytes);
+
+        let mut bytes = valid.to_vec();
+        let i = rng.gen_range(0, bytes.len());
+        bytes[i] = rng.gen();
+        corpus.push(bytes);
+    }
+
+    let mut bytes = valid.to_vec();
+    bytes.extend_from_slice(&[0, 1, 0xff]);
+    corpus.push(bytes);
+
+    // keep the header, to get past the first checks
+    for len in [2, valid.len() / 2, valid.len(), 2 * valid.len()] {
+        let mut bytes = vec![0; len];
+        rng.fill(&mut bytes[..]);
+        bytes[..2].copy_from_slice(&valid[..2]);
+        corpus.push(bytes);
+    }
+
+    corpus
+}
+
+/// Mutate a random leaf of a JSON value, or change the length of an array
+fn mutate(value: &mut Value, rng: &mut StdRng) {
+    match value {
+        Value::Array(values) if values.is_empty() || rng.gen_bool(0.1) => {
+            match rng.gen_range(0, 3) {
+                0 => {
+                    values.pop();
+                }
+                1 => values.push(Value::from(rng.gen::<u8>())),
+                _ => values.clear(),
+            }
+        }
+        Value::Array(values) => {
+            let i = rng.gen_range(0, values.len());
+            mutate(&mut values[i], rng);
+        }
+        Value::Object(fields) if !fields.is_empty() => {
+            let keys: Vec<String> = fields.keys().cloned().collect();
+            let key = &keys[rng.gen_range(0, keys.len())];
+            if rng.gen_bool(0.1) {
+                fields.remove(key);
+            } else if let Some(field) = fields.get_mut(key) {
+                mutate(field, rng);
+            }
+        }
+        _ => {
+            *value = match rng.gen_range(0, 5) {
+                0 => Value::from(rng.gen::<u8>()),
+                1 => Value::from(256),
+                2 => Value::from(-1),
+                3 => Value::from(u64::MAX),
+                _ => Value::Null,
+            }
+        }
+    }
+}
+
+/// Mutations of the JSON of a valid value
+fn json_corpus<T: Serialize>(valid: &T, rng: &mut StdRng) -> Vec<Vec<u8>> {
+    let valid = serde_json::to_value(valid).unwrap();
+
+    (0..2 * MUTATIONS)
+        .map(|_| {
+            let mut value = valid.clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                mutate(&mut value, rng);
+            }
+            serde_json::to_vec(&value).unwrap()
+        })
+        .collect()
+}
+
+// }}}
+
+/// Decode every input of the corpus, and run `f` on what could be decoded
+fn decode_wire<T: WireFormat, F: FnMut(T)>(what: &str, corpus: &[Vec<u8>], mut f: F) {
+    for input in corpus {
+        no_panic(what, input, || {
+            if let Ok(decoded) = T::from_bytes(input) {
+                f(decoded);
+            }
+        });
+    }
+}
+
+fn decode_json<T: DeserializeOwned, F: FnMut(T)>(what: &str, corpus: &[Vec<u8>], mut f: F) {
+    for input in corpus {
+        no_panic(what, input, || {
+            if let Ok(decoded) = serde_json::from_slice(input) {
+                f(decoded);
+            }
+        });
+    }
+}
+
+/// The inputs of the signer, the user and the verifier of an engine
+macro_rules! no_panic_tests {
+    (
+        $name:ident,
+        $backend:ident,
+        $engine:ident,
+        $batched:ident,
+        verification_key = |$key:ident| $verification_key:expr $(,)?
+    ) => {
+        mod $name {
+            use super::*;
+
+            use crate::$backend::{
+                keys::{PrivateKey, PublicKey},
+                tokens::{
+                    $engine, RandomizedSignedToken as Response, RandomizedUnsignedToken as Request,
+                },
+                tokens_batched_dyn::$batched,
+            };
+
+            type Engine = $engine<Box<[u8]>>;
+            type Batched = $batched<Box<[u8]>>;
+            type Token = <Engine as TokenEngine>::SignedToken;
+            type Unsigned = <Engine as TokenEngine>::UnsignedToken;
+
+            fn metadata() -> Box<[u8]> {
+                Box::from(&b"metadata"[..])
+            }
+
+            #[test]
+            fn test_verifier() {
+                let mut rng = StdRng::seed_from_u64(1);
+                let $key = PrivateKey::new();
+                let verification_key = $verification_key;
+                let public_key = PublicKey::from(&$key);
+
+                let token = Engine::sign(Engine::generate(metadata()), &public_key, |request| {
+                    Engine::sign_randomized(request, &$key)
+                })
+                .unwrap();
+                assert!(token.verify(&verification_key));
+
+                decode_wire::<Token, _>("token", &corpus(&token.to_bytes(), &mut rng), |token| {
+                    token.verify(&verification_key);
+                });
+                decode_json::<Token, _>("token json", &json_corpus(&token, &mut rng), |token| {
+                    token.verify(&verification_key);
+                });
+
+                decode_wire::<PublicKey, _>(
+                    "public key",
+                    &corpus(&public_key.to_bytes(), &mut rng),
+                    |_key| (),
+                );
+            }
+
+            #[test]
+            fn test_signer() {
+                let mut rng = StdRng::seed_from_u64(2);
+                let key = PrivateKey::new();
+
+                let (_r, request) = Engine::randomize(&Engine::generate(metadata()));
+
+                decode_wire::<Request<Box<[u8]>>, _>(
+                    "request",
+                    &corpus(&request.to_bytes(), &mut rng),
+                    |request| {
+                        let _ = Engine::sign_randomized(&request, &key);
+                    },
+                );
+                decode_json::<Request<Box<[u8]>>, _>(
+                    "request json",
+                    &json_corpus(&request, &mut rng),
+                    |request| {
+                        let _ = Engine::sign_randomized(&request, &key);
+                    },
+                );
+            }
+
+            #[test]
+            fn test_user() {
+                let mut rng = StdRng::seed_from_u64(3);
+                let key = PrivateKey::new();
+                let public_key = PublicKey::from(&key);
+
+                let unsigned = serde_json::to_vec(&Engine::generate(metadata())).unwrap();
+                let unsigned = || serde_json::from_slice::<Unsigned>(&unsigned).unwrap();
+                let (r, request) = Engine::randomize(&unsigned());
+                let response = Engine::sign_randomized(&request, &key).unwrap();
+
+                let mut unrandomize = |response| {
+                    let request = Request::from_bytes(&request.to_bytes()).unwrap();
+                    let _ = Engine::verify_signature_and_unrandomize(
+                        unsigned(),
+                        request,
+                        response,
+                        &public_key,
+                        r,
+                    );
+                };
+                decode_wire::<Response<Box<[u8]>>, _>(
+                    "response",
+                    &corpus(&response.to_bytes(), &mut rng),
+                    &mut unrandomize,
+                );
+                decode_json::<Response<Box<[u8]>>, _>(
+                    "response json",
+                    &json_corpus(&response, &mut rng),
+                    &mut unrandomize,
+                );
+            }
+
+            #[test]
+            fn test_batched_user() {
+                let mut rng = StdRng::seed_from_u64(4);
+                let key = PrivateKey::new();
+                let public_key = PublicKey::from(&key);
+
+                let (r, request) = Batched::randomize(&Batched::generate((metadata(), 3)));
+                let response = Batched::sign_randomized(&request, &key).unwrap();
+                let request = serde_json::to_vec(&request).unwrap();
+
+                decode_json::<<Batched as TokenEngine>::RandomizedSignedToken, _>(
+                    "batched response json",
+                    &json_corpus(&response, &mut rng),
+                    |response| {
+                        let _ = Batched::verify_signature_and_unrandomize(
+                            Batched::generate((metadata(), 3)),
+                            serde_json::from_slice(&request).unwrap(),
+                            response,
+                            &public_key,
+                            r,
+                        );
+                    },
+                );
+                decode_json::<<Batched as TokenEngine>::RandomizedUnsignedToken, _>(
+                    "batched request json",
+                    &json_corpus(
+                        &serde_json::from_slice::<Value>(&request).unwrap(),
+                        &mut rng,
+                    ),
+                    |request| {
+                        let _ = Batched::sign_randomized(&request, &key);
+                    },
+                );
+            }
+        }
+    };
+}
+
+#[cfg(feature = "pairing")]
+no_panic_tests!(
+    pairing,
+    atpm_pairing,
+    PairingTokenEngine,
+    DynBatchedPairingTokenEngine,
+    verification_key = |key| PublicKey::from(&key),
+);
+
+#[cfg(feature = "curve25519")]
+no_panic_tests!(
+    curve25519,
+    nizkp_curve25519,
+    NizkpTokenEngine,
+    DynBatchedNizkpTokenEngine,
+    verification_key = |key| key.clone(),
+);
+
+#[cfg(feature = "curve25519")]
+mod curve25519_batched {
+    use super::*;
+
+    use crate::nizkp_curve25519::{
+        keys::{PrivateKey, PublicKey},
+        tokens_batched::{BatchedNizkpTokenEngine, RandomizedSignedTokenBatched},
+    };
+
+    type Engine = BatchedNizkpTokenEngine<Box<[u8]>, 3>;
+
+    #[test]
+    fn test_user() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let key = PrivateKey::new();
+        let public_key = PublicKey::from(&key);
+        let metadata: Box<[u8]> = Box::from(&b"metadata"[..]);
+
+        let (r, request) = Engine::randomize(&Engine::generate(metadata.clone()));
+        let response = Engine::sign_randomized(&request, &key).unwrap();
+        let request = serde_json::to_vec(&request).unwrap();
+
+        decode_json::<RandomizedSignedTokenBatched<Box<[u8]>, 3>, _>(
+            "batched response json",
+            &json_corpus(&response, &mut rng),
+            |response| {
+                let _ = Engine::verify_signature_and_unrandomize(
+                    Engine::generate(metadata.clone()),
+                    serde_json::from_slice(&request).unwrap(),
+                    response,
+                    &public_key,
+                    r,
+                );
+            },
+        );
+    }
+}
+
+#[cfg(feature = "nizkp")]
+mod nizkp_batched {
+    use super::*;
+
+    use k256::Secp256k1;
+
+    use crate::atpm_nizkp::{
+        keys::{PrivateKey, PublicKey},
+        tokens_batched::{
+            BatchedNizkpTokenEngine, RandomizedSignedTokenBatched, RandomizedUnsignedTokenBatched,
+        },
+    };
+
+    type Engine = BatchedNizkpTokenEngine<Box<[u8]>, Secp256k1, 3>;
+
+    fn metadata() -> Box<[u8]> {
+        Box::from(&b"metadata"[..])
+    }
+
+    #[test]
+    fn test_signer() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let key = PrivateKey::<Secp256k1>::new();
+
+        let (_r, request) = Engine::randomize(&Engine::generate(metadata()));
+
+        decode_json::<RandomizedUnsignedTokenBatched<Box<[u8]>, Secp256k1, 3>, _>(
+            "nizkp batched request json",
+            &json_corpus(&request, &mut rng),
+            |request| {
+                let _ = Engine::sign_randomized(&request, &key);
+            },
+        );
+        decode_json::<PublicKey<Secp256k1>, _>(
+            "nizkp public key json",
+            &json_corpus(&PublicKey::from(&key), &mut rng),
+            |_key| (),
+        );
+    }
+
+    #[test]
+    fn test_user() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let key = PrivateKey::<Secp256k1>::new();
+        let public_key = PublicKey::from(&key);
+
+        let (r, request) = Engine::randomize(&Engine::generate(metadata()));
+        let response = Engine::sign_randomized(&request, &key).unwrap();
+        let request = serde_json::to_vec(&request).unwrap();
+
+        decode_json::<RandomizedSignedTokenBatched<Box<[u8]>, Secp256k1, 3>, _>(
+            "nizkp batched response json",
+            &json_corpus(&response, &mut rng),
+            |response| {
+                let _ = Engine::verify_signature_and_unrandomize(
+                    Engine::generate(metadata()),
+                    serde_json::from_slice(&request).unwrap(),
+                    response,
+                    &public_key,
+                    r,
+                );
+            },
+        );
+    }
+}
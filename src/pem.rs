@@ -0,0 +1,144 @@
+//! # Keys as PEM and base64url
+//!
+//! A signer ships its public key in a configuration file as PEM, or in an HTTP header as a string.
+//! The keys of [`atpm_pairing`](crate::atpm_pairing) and
+//! [`nizkp_curve25519`](crate::nizkp_curve25519) have `to_pem` and `from_pem`, and
+//! [`Display`](core::fmt::Display) and [`FromStr`](core::str::FromStr) as URL-safe base64 without
+//! padding. The bytes of a public key are its [wire format](crate::wire), with the type byte, and
+//! the bytes of a private key are its scalar, with the `private_key_serde` feature.
+//!
+//! ```
+//!     use atpmd::atpm_pairing::keys::{PrivateKey, PublicKey};
+//!
+//!     let public_key = PublicKey::from(&PrivateKey::new());
+//!
+//!     let pem = public_key.to_pem();
+//!     assert!(pem.starts_with("-----BEGIN ATPMD PAIRING PUBLIC KEY-----\n"));
+//!     assert_eq!(PublicKey::from_pem(&pem).unwrap().fingerprint(), public_key.fingerprint());
+//!
+//!     let header = public_key.to_string();
+//!     let parsed: PublicKey = header.parse().unwrap();
+//!     assert_eq!(parsed.fingerprint(), public_key.fingerprint());
+//! ```
+
+use alloc::{format, string::String, vec::Vec};
+use core::fmt;
+
+use zeroize::Zeroize;
+
+use crate::wire::WireError;
+
+/// The reason a key could not be imported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PemError {
+    /// The PEM does not begin and end with the label of the key
+    Label,
+    /// The key is not valid base64
+    Base64,
+    /// The bytes are not a key
+    Malformed(WireError),
+}
+
+impl fmt::Display for PemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Label => f.write_str("the PEM is not labeled as the key"),
+            Self::Base64 => f.write_str("the key is not valid base64"),
+            Self::Malformed(e) => write!(f, "malformed key: {}", e),
+        }
+    }
+}
+
+/// The bytes as PEM with the label, in lines of 64 characters
+pub fn encode(label: &str, bytes: &[u8]) -> String {
+    let mut encoded = base64::encode_config(bytes, base64::STANDARD);
+
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(core::str::from_utf8(line).expect("base64 is ascii"));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+
+    encoded.zeroize();
+    pem
+}
+
+/// The bytes of PEM with the label
+///
+/// The whitespace around and inside the base64 is ignored.
+pub fn decode(label: &str, pem: &str) -> Result<Vec<u8>, PemError> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+
+    let body = pem
+        .trim()
+        .strip_prefix(begin.as_str())
+        .and_then(|rest| rest.strip_suffix(end.as_str()))
+        .ok_or(PemError::Label)?;
+
+    let mut encoded = body
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+    let bytes = base64::decode_config(&encoded, base64::STANDARD).map_err(|_e| PemError::Base64);
+
+    encoded.zeroize();
+    bytes
+}
+
+/// The bytes as URL-safe base64 without padding
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+pub(crate) fn encode_url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+pub(crate) fn decode_url(encoded: &str) -> Result<Vec<u8>, PemError> {
+    base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_e| PemError::Base64)
+}
+
+/// The 32 bytes of a scalar, the decoded bytes are zeroized
+#[cfg(feature = "private_key_serde")]
+pub(crate) fn scalar_bytes(mut bytes: Vec<u8>) -> Result<[u8; 32], PemError> {
+    let scalar = match bytes.len() {
+        32 => {
+            let mut scalar = [0; 32];
+            scalar.copy_from_slice(&bytes);
+            Ok(scalar)
+        }
+        len if len < 32 => Err(PemError::Malformed(WireError::Truncated)),
+        _ => Err(PemError::Malformed(WireError::TrailingBytes)),
+    };
+
+    bytes.zeroize();
+    scalar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pem() {
+        let bytes = (0..100).collect::<Vec<u8>>();
+        let pem = encode("TEST KEY", &bytes);
+
+        let lines = pem.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "-----BEGIN TEST KEY-----");
+        assert_eq!(lines[1].len(), 64);
+        assert_eq!(lines[4], "-----END TEST KEY-----");
+
+        assert_eq!(decode("TEST KEY", &pem), Ok(bytes.clone()));
+        // as indented in a configuration file
+        let indented = pem.replace('\n', "\n    ");
+        assert_eq!(decode("TEST KEY", &indented), Ok(bytes));
+
+        assert_eq!(decode("OTHER KEY", &pem), Err(PemError::Label));
+        assert_eq!(
+            decode("TEST KEY", &pem.replace('A', "*")),
+            Err(PemError::Base64)
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! # The issuance policy of a signer
+//!
+//! The engines sign any metadata. A signer decides which metadata it signs for whom, e.g. that a
+//! user has access to the resource in the metadata. A [`SignerPolicy`] is that decision, from the
+//! metadata and a context of the request, such as the authenticated user, and
+//! [`TokenEngine::sign_randomized_with_policy`] only signs what the policy allows.
+//!
+//! A closure is a policy, and so is a [`MetadataGuard`] for any context. Two policies in a tuple
+//! both have to allow the metadata.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::guard::MetadataGuard;
+//!     use atpmd::policy::PolicyError;
+//!     use atpmd::atpm_pairing::{
+//!         keys::PrivateKey,
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     // the users only get tokens of their own team
+//!     let same_team = |metadata: &[u8], team: &str| {
+//!         if metadata.starts_with(team.as_bytes()) {
+//!             Ok(())
+//!         } else {
+//!             Err(PolicyError::denied())
+//!         }
+//!     };
+//!     let policy = (MetadataGuard::default(), same_team);
+//!
+//!     let secret_key = PrivateKey::new();
+//!
+//!     let unsigned = PairingTokenEngine::generate(b"red/dashboard");
+//!     let (_r, randomized) = PairingTokenEngine::randomize(&unsigned);
+//!
+//!     assert!(PairingTokenEngine::sign_randomized_with_policy(&randomized, &secret_key, &policy, "red").is_ok());
+//!     assert_eq!(
+//!         PairingTokenEngine::sign_randomized_with_policy(&randomized, &secret_key, &policy, "blue").err(),
+//!         Some(atpmd::Error::Policy(PolicyError::denied()))
+//!     );
+//! ```
+//!
+//! [`TokenEngine::sign_randomized_with_policy`]: crate::TokenEngine::sign_randomized_with_policy
+
+use core::fmt;
+
+use crate::guard::MetadataGuard;
+use crate::refusal::RefusalReason;
+
+/// Why a policy does not allow the metadata, as the signer would refuse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyError {
+    pub reason: RefusalReason,
+    /// The seconds after which the client may ask again
+    pub retry_after: Option<u64>,
+}
+
+impl PolicyError {
+    /// The signer does not sign the metadata for this client
+    pub fn denied() -> Self {
+        Self {
+            reason: RefusalReason::PolicyDenied,
+            retry_after: None,
+        }
+    }
+
+    /// The client asked for too many tokens, and may ask again after some seconds
+    pub fn rate_limited(retry_after: u64) -> Self {
+        Self {
+            reason: RefusalReason::RateLimited,
+            retry_after: Some(retry_after),
+        }
+    }
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.retry_after {
+            None => write!(f, "{}", self.reason),
+            Some(seconds) => write!(f, "{}, retry after {} s", self.reason, seconds),
+        }
+    }
+}
+
+/// Which metadata a signer signs, for the context of a request
+pub trait SignerPolicy<Ctx: ?Sized> {
+    fn allow(&self, metadata: &[u8], context: &Ctx) -> Result<(), PolicyError>;
+}
+
+impl<Ctx: ?Sized, F: Fn(&[u8], &Ctx) -> Result<(), PolicyError>> SignerPolicy<Ctx> for F {
+    fn allow(&self, metadata: &[u8], context: &Ctx) -> Result<(), PolicyError> {
+        self(metadata, context)
+    }
+}
+
+/// Metadata that looks like it identifies the user is denied
+impl<Ctx: ?Sized> SignerPolicy<Ctx> for MetadataGuard {
+    fn allow(&self, metadata: &[u8], _context: &Ctx) -> Result<(), PolicyError> {
+        self.check(metadata)
+            .map_err(|_violation| PolicyError::denied())
+    }
+}
+
+impl<Ctx: ?Sized, A: SignerPolicy<Ctx>, B: SignerPolicy<Ctx>> SignerPolicy<Ctx> for (A, B) {
+    fn allow(&self, metadata: &[u8], context: &Ctx) -> Result<(), PolicyError> {
+        self.0.allow(metadata, context)?;
+        self.1.allow(metadata, context)
+    }
+}
+
+#[cfg(all(test, feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use crate::common::{Error, TokenEngine};
+    use crate::nizkp_curve25519::{
+        keys::PrivateKey, tokens_batched_dyn::DynBatchedNizkpTokenEngine,
+    };
+    use alloc::boxed::Box;
+
+    /// The number of tokens a user may still get
+    struct Quota;
+
+    impl SignerPolicy<u32> for Quota {
+        fn allow(&self, _metadata: &[u8], left: &u32) -> Result<(), PolicyError> {
+            if *left > 0 {
+                Ok(())
+            } else {
+                Err(PolicyError::rate_limited(60))
+            }
+        }
+    }
+
+    #[test]
+    fn test_policy_on_batches() {
+        type Engine = DynBatchedNizkpTokenEngine<Box<[u8]>>;
+
+        let private = PrivateKey::new();
+        let unsigned = Engine::generate((Box::from(&b"resource"[..]), 4));
+        let (_r, randomized) = Engine::randomize(&unsigned);
+
+        assert!(Engine::sign_randomized_with_policy(&randomized, &private, &Quota, &1).is_ok());
+        assert_eq!(
+            Engine::sign_randomized_with_policy(&randomized, &private, &Quota, &0).err(),
+            Some(Error::Policy(PolicyError::rate_limited(60)))
+        );
+
+        let guarded = (Quota, MetadataGuard::default());
+        let unsigned = Engine::generate((Box::from(&b"alice@example.com"[..]), 4));
+        let (_r, randomized) = Engine::randomize(&unsigned);
+        assert_eq!(
+            Engine::sign_randomized_with_policy(&randomized, &private, &guarded, &1).err(),
+            Some(Error::Policy(PolicyError::denied()))
+        );
+    }
+}
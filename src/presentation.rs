@@ -0,0 +1,176 @@
+//! # Presentations bound to a nonce of the verifier
+//!
+//! A token that is shown to a verifier may be shown again to another verifier, by anyone who saw
+//! it, until the redemption stores catch it. A presentation instead proves that the client has the
+//! signature of the token, for the nonce the verifier gave it, without showing the signature, so
+//! it is worthless to any other verifier or for another nonce. The nonce may also cover the origin
+//! of the verifier or the channel, e.g. a [`challenge::binding`](crate::challenge::binding) of a
+//! challenge and the request.
+//!
+//! The pairing tokens are presented with a proof of knowledge of the signature, which anyone with
+//! the public key can verify. The curve25519 tokens are presented as a
+//! [`BoundRedemption`](crate::nizkp_curve25519::tokens::BoundRedemption) of the nonce.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::presentation::{present, verify_presentation};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::{PairingSignedToken, PairingTokenEngine},
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(&b"resource"[..]),
+//!         &public_key,
+//!         |randomized| PairingTokenEngine::sign_randomized(randomized, &secret_key),
+//!     )
+//!     .unwrap();
+//!
+//!     // the verifier sends a fresh nonce, for which the client presents the token
+//!     let presentation = present(&signed, b"nonce of the verifier");
+//!
+//!     assert!(verify_presentation::<PairingSignedToken<_>>(
+//!         &presentation,
+//!         &public_key,
+//!         b"nonce of the verifier"
+//!     ));
+//!     assert!(!verify_presentation::<PairingSignedToken<_>>(
+//!         &presentation,
+//!         &public_key,
+//!         b"nonce of another verifier"
+//!     ));
+//! ```
+
+use rand::{CryptoRng, RngCore};
+
+use crate::SignedToken;
+
+/// A signed token that may be presented for a nonce
+pub trait Presentable: SignedToken {
+    /// What the client sends instead of the token
+    type Presentation;
+
+    /// Present the token for the nonce, with the randomness of the proof from the rng
+    fn present_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        nonce: &[u8],
+        rng: &mut R,
+    ) -> Self::Presentation;
+
+    /// Verify that the presentation is of a signed token, for this nonce
+    fn verify_presentation(
+        presentation: &Self::Presentation,
+        verification_key: &Self::VerificationKey,
+        nonce: &[u8],
+    ) -> bool;
+}
+
+/// Present the token for the nonce of the verifier
+pub fn present<T: Presentable>(token: &T, nonce: impl AsRef<[u8]>) -> T::Presentation {
+    token.present_with_rng(nonce.as_ref(), &mut crate::rng::default_rng())
+}
+
+/// Verify a presentation for the nonce the verifier gave the client
+///
+/// The verifier should still redeem the token, by the fingerprint of the presentation, so it is
+/// not presented twice to the same verifier.
+pub fn verify_presentation<T: Presentable>(
+    presentation: &T::Presentation,
+    verification_key: &T::VerificationKey,
+    nonce: impl AsRef<[u8]>,
+) -> bool {
+    T::verify_presentation(presentation, verification_key, nonce.as_ref())
+}
+
+#[cfg(feature = "curve25519")]
+mod curve25519 {
+    use rand::{CryptoRng, RngCore};
+    use sha2::{Digest, Sha512Trunc256};
+
+    use super::Presentable;
+    use crate::nizkp_curve25519::{
+        keys::PrivateKey,
+        tokens::{BoundRedemption, NizkpSignedToken},
+    };
+
+    /// The data the token is bound to, separate from the data of other bound redemptions
+    fn data(nonce: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha512Trunc256::new();
+        hasher.update(b"This is presentation nonce hash");
+        hasher.update(nonce);
+        hasher.finalize().into()
+    }
+
+    impl<M: AsRef<[u8]> + Clone> Presentable for NizkpSignedToken<M> {
+        type Presentation = BoundRedemption<M>;
+
+        // the MAC needs no randomness
+        fn present_with_rng<R: CryptoRng + RngCore>(
+            &self,
+            nonce: &[u8],
+            _rng: &mut R,
+        ) -> BoundRedemption<M> {
+            self.bind(data(nonce))
+        }
+
+        fn verify_presentation(
+            presentation: &BoundRedemption<M>,
+            verification_key: &PrivateKey,
+            nonce: &[u8],
+        ) -> bool {
+            presentation.verify(verification_key, data(nonce))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "curve25519"))]
+mod tests {
+    use super::*;
+
+    use crate::nizkp_curve25519::{
+        keys::{PrivateKey, PublicKey},
+        tokens::{NizkpSignedToken, NizkpTokenEngine},
+    };
+    use crate::TokenEngine;
+
+    type Token = NizkpSignedToken<&'static [u8]>;
+
+    #[test]
+    fn test_curve25519() {
+        let private_key = PrivateKey::new();
+        let signed = NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(&b"resource"[..]),
+            &PublicKey::from(&private_key),
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, &private_key),
+        )
+        .unwrap();
+
+        let presentation = present(&signed, b"nonce");
+        assert!(verify_presentation::<Token>(
+            &presentation,
+            &private_key,
+            b"nonce"
+        ));
+        assert!(!verify_presentation::<Token>(
+            &presentation,
+            &private_key,
+            b"other"
+        ));
+        assert!(!verify_presentation::<Token>(
+            &presentation,
+            &PrivateKey::new(),
+            b"nonce"
+        ));
+
+        // a presentation is not a redemption bound to the nonce itself
+        assert!(!presentation.verify(&private_key, b"nonce"));
+        assert!(!verify_presentation::<Token>(
+            &signed.bind(b"nonce"),
+            &private_key,
+            b"nonce"
+        ));
+    }
+}
@@ -0,0 +1,456 @@
+//! # Door access
+//!
+//! The issuer gives users tokens for a door and a validity window.
+//! The door reader is offline: it gets the public keys of the issuer in a [`VerifierBundle`]
+//! when it is synced, and verifies tokens it reads over serial or NFC without contacting the
+//! issuer, so the issuer never learns who opened which door.
+//!
+//! Tokens are sent to the reader as frames, the JSON of the token followed by `\r\n\r\n`, the same
+//! format as the QR code and serial examples.
+//!
+//! ```
+//!     use atpmd::atpm_pairing::keys::{PrivateKey, PublicKey};
+//!     use atpmd::presets::access_control::{
+//!         AccessRequest, DoorIssuer, DoorMetadata, DoorVerifier, FrameDecoder, VerifierBundle,
+//!     };
+//!     use atpmd::PublicKeySet;
+//!
+//!     let issuer = DoorIssuer::new(PrivateKey::new(), 1);
+//!     let mut keys = PublicKeySet::new();
+//!     keys.insert(1, PublicKey::from(issuer.private_key()));
+//!
+//!     // the reader at door 7 is synced
+//!     let mut reader = DoorVerifier::new(7);
+//!     assert!(reader.sync(VerifierBundle::new(7, &keys)));
+//!
+//!     // the user gets a token for door 7
+//!     let metadata = DoorMetadata { door: 7, not_before: 1000, not_after: 2000 };
+//!     let request = AccessRequest::new(metadata);
+//!     let response = issuer.issue(request.request()).unwrap();
+//!     let token = request.finish(response, &keys).unwrap();
+//!
+//!     // and shows it to the reader, byte by byte
+//!     let mut decoder = FrameDecoder::new();
+//!     let frame = DoorVerifier::encode_frame(&token);
+//!     let received = frame.iter().find_map(|byte| decoder.push(*byte)).unwrap();
+//!
+//!     assert_eq!(reader.check_frame(&received, 1500), Ok(metadata));
+//!     // a token opens the door only once
+//!     assert!(reader.check_frame(&received, 1500).is_err());
+//! ```
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{convert::TryInto, fmt};
+
+use crate::atpm_pairing::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{PairingSignedToken, PairingTokenEngine, PairingUnsignedToken, RandomizedSignedToken},
+};
+use crate::common::{
+    KeyEpoch, PublicKeySet, RandomizedSignedToken as _, RandomizedUnsignedToken as _, TokenEngine,
+    UnsignedToken as _,
+};
+
+/// The engine used for door tokens
+pub type DoorEngine = PairingTokenEngine<Box<[u8]>>;
+
+/// A token for a door
+pub type DoorToken = PairingSignedToken<Box<[u8]>>;
+
+/// The end of a frame
+pub const FRAME_END: &[u8] = b"\r\n\r\n";
+
+/// The encoded length of [`DoorMetadata`]
+pub const METADATA_LEN: usize = 20;
+
+// {{{ Metadata
+
+/// The metadata of a door token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoorMetadata {
+    pub door: u32,
+    /// The token is valid from this time, in seconds
+    pub not_before: u64,
+    /// The token is valid until this time, in seconds
+    pub not_after: u64,
+}
+
+impl DoorMetadata {
+    pub fn to_bytes(&self) -> [u8; METADATA_LEN] {
+        let mut bytes = [0u8; METADATA_LEN];
+        bytes[..4].copy_from_slice(&self.door.to_be_bytes());
+        bytes[4..12].copy_from_slice(&self.not_before.to_be_bytes());
+        bytes[12..].copy_from_slice(&self.not_after.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != METADATA_LEN {
+            return None;
+        }
+
+        Some(Self {
+            door: u32::from_be_bytes(bytes[..4].try_into().ok()?),
+            not_before: u64::from_be_bytes(bytes[4..12].try_into().ok()?),
+            not_after: u64::from_be_bytes(bytes[12..].try_into().ok()?),
+        })
+    }
+}
+
+// }}}
+
+// {{{ Issuer
+
+/// Signs door tokens
+///
+/// The issuer has to check that the user has access to the door before signing.
+pub struct DoorIssuer {
+    private_key: PrivateKey,
+    epoch: KeyEpoch,
+}
+
+impl DoorIssuer {
+    pub fn new(private_key: PrivateKey, epoch: KeyEpoch) -> Self {
+        Self { private_key, epoch }
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    /// Sign a request, if the metadata is well formed
+    pub fn issue(
+        &self,
+        request: &<DoorEngine as TokenEngine>::RandomizedUnsignedToken,
+    ) -> Option<RandomizedSignedToken<Box<[u8]>>> {
+        let metadata = DoorMetadata::from_bytes(&request.metadata())?;
+        if metadata.not_before > metadata.not_after {
+            return None;
+        }
+
+        DoorEngine::sign_randomized(request, &self.private_key)
+            .ok()
+            .map(|signed| signed.with_key_epoch(self.epoch))
+    }
+}
+
+// }}}
+
+// {{{ User
+
+/// A request for a door token, waiting for the response of the issuer
+pub struct AccessRequest {
+    unsigned: PairingUnsignedToken<Box<[u8]>>,
+    randomization: <DoorEngine as TokenEngine>::Randomization,
+    request: <DoorEngine as TokenEngine>::RandomizedUnsignedToken,
+}
+
+impl AccessRequest {
+    pub fn new(metadata: DoorMetadata) -> Self {
+        let unsigned = PairingUnsignedToken::new(Box::from(&metadata.to_bytes()[..]));
+        let (randomization, request) = DoorEngine::randomize(&unsigned);
+
+        Self {
+            unsigned,
+            randomization,
+            request,
+        }
+    }
+
+    /// The request to send to the issuer
+    pub fn request(&self) -> &<DoorEngine as TokenEngine>::RandomizedUnsignedToken {
+        &self.request
+    }
+
+    /// Verify the response of the issuer, and get the token
+    pub fn finish(
+        self,
+        response: RandomizedSignedToken<Box<[u8]>>,
+        keys: &PublicKeySet<PublicKey>,
+    ) -> Option<DoorToken> {
+        DoorEngine::verify_signature_and_unrandomize_with_key_set(
+            self.unsigned,
+            self.request,
+            response,
+            keys,
+            self.randomization,
+        )
+        .ok()
+    }
+}
+
+// }}}
+
+// {{{ Verifier
+
+/// Everything an offline door reader needs to verify tokens
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VerifierBundle {
+    pub door: u32,
+    pub keys: Vec<(KeyEpoch, PublicKey)>,
+}
+
+impl VerifierBundle {
+    pub fn new(door: u32, keys: &PublicKeySet<PublicKey>) -> Self {
+        Self {
+            door,
+            keys: keys
+                .iter()
+                .map(|(epoch, key)| (epoch, key.clone()))
+                .collect(),
+        }
+    }
+}
+
+/// The reason the door did not open
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessError {
+    /// The frame does not contain a token
+    Frame,
+    /// The token is not signed by any of the keys in the bundle
+    InvalidToken,
+    /// The token is for another door
+    WrongDoor,
+    /// The validity window of the token has not started
+    NotYetValid,
+    /// The validity window of the token has ended
+    Expired,
+    /// The token has already been used
+    AlreadyUsed,
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Frame => f.write_str("the frame does not contain a token"),
+            Self::InvalidToken => f.write_str("the token is not signed"),
+            Self::WrongDoor => f.write_str("the token is for another door"),
+            Self::NotYetValid => f.write_str("the token is not valid yet"),
+            Self::Expired => f.write_str("the token has expired"),
+            Self::AlreadyUsed => f.write_str("the token has already been used"),
+        }
+    }
+}
+
+/// An offline door reader
+pub struct DoorVerifier {
+    door: u32,
+    keys: PublicKeySet<PublicKey>,
+    used: Vec<(u64, DoorToken)>,
+}
+
+impl DoorVerifier {
+    pub fn new(door: u32) -> Self {
+        Self {
+            door,
+            keys: PublicKeySet::new(),
+            used: Vec::new(),
+        }
+    }
+
+    /// Replace the keys with those of the bundle, if the bundle is for this door
+    pub fn sync(&mut self, bundle: VerifierBundle) -> bool {
+        if bundle.door != self.door {
+            return false;
+        }
+
+        self.keys = PublicKeySet::new();
+        for (epoch, key) in bundle.keys {
+            self.keys.insert(epoch, key);
+        }
+
+        true
+    }
+
+    /// Encode a token as a frame
+    #[cfg(feature = "json")]
+    pub fn encode_frame(token: &DoorToken) -> Vec<u8> {
+        // serializing a token can not fail
+        let mut frame = serde_json::to_vec(token).unwrap();
+        frame.extend_from_slice(FRAME_END);
+        frame
+    }
+
+    /// Check the token in a frame, see [`DoorVerifier::check`]
+    #[cfg(feature = "json")]
+    pub fn check_frame(&mut self, frame: &[u8], now: u64) -> Result<DoorMetadata, AccessError> {
+        let frame = frame.strip_suffix(FRAME_END).unwrap_or(frame);
+        let token = serde_json::from_slice(frame).map_err(|_e| AccessError::Frame)?;
+
+        self.check(token, now)
+    }
+
+    /// Check that the token opens this door at the time `now`, and mark it as used
+    pub fn check(&mut self, token: DoorToken, now: u64) -> Result<DoorMetadata, AccessError> {
+        // the token does not say which key signed it, so try them all
+        if !self
+            .keys
+            .iter()
+            .any(|(_, key)| DoorEngine::verify(&token, key).is_ok())
+        {
+            return Err(AccessError::InvalidToken);
+        }
+
+        let metadata =
+            DoorMetadata::from_bytes(token.metadata()).ok_or(AccessError::InvalidToken)?;
+
+        if metadata.door != self.door {
+            return Err(AccessError::WrongDoor);
+        }
+        if now < metadata.not_before {
+            return Err(AccessError::NotYetValid);
+        }
+        if now > metadata.not_after {
+            return Err(AccessError::Expired);
+        }
+
+        // expired tokens will be rejected anyway
+        self.used.retain(|(not_after, _)| *not_after >= now);
+        if self.used.iter().any(|(_, used)| *used == token) {
+            return Err(AccessError::AlreadyUsed);
+        }
+        self.used.push((metadata.not_after, token));
+
+        Ok(metadata)
+    }
+}
+
+/// Collects bytes from a serial port or NFC reader until a whole frame has been read
+#[derive(Debug, Clone, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a byte, and get the frame if it is complete
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        self.buffer.push(byte);
+
+        if self.buffer.ends_with(FRAME_END) {
+            Some(core::mem::take(&mut self.buffer))
+        } else {
+            None
+        }
+    }
+}
+
+// }}}
+
+// {{{ tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(
+        issuer: &DoorIssuer,
+        keys: &PublicKeySet<PublicKey>,
+        metadata: DoorMetadata,
+    ) -> DoorToken {
+        let request = AccessRequest::new(metadata);
+        let response = issuer.issue(request.request()).unwrap();
+        request.finish(response, keys).unwrap()
+    }
+
+    #[test]
+    fn test_access() {
+        let old = DoorIssuer::new(PrivateKey::new(), 1);
+        let new = DoorIssuer::new(PrivateKey::new(), 2);
+        let mut keys = PublicKeySet::new();
+        keys.insert(1, PublicKey::from(old.private_key()));
+        keys.insert(2, PublicKey::from(new.private_key()));
+
+        let mut reader = DoorVerifier::new(7);
+        assert!(!reader.sync(VerifierBundle::new(8, &keys)));
+        assert!(reader.sync(VerifierBundle::new(7, &keys)));
+
+        let metadata = DoorMetadata {
+            door: 7,
+            not_before: 10,
+            not_after: 20,
+        };
+
+        // tokens from both keys are accepted
+        assert_eq!(reader.check(token(&old, &keys, metadata), 15), Ok(metadata));
+        assert_eq!(reader.check(token(&new, &keys, metadata), 15), Ok(metadata));
+
+        assert_eq!(
+            reader.check(token(&new, &keys, metadata), 9),
+            Err(AccessError::NotYetValid)
+        );
+        assert_eq!(
+            reader.check(token(&new, &keys, metadata), 21),
+            Err(AccessError::Expired)
+        );
+
+        let other_door = DoorMetadata {
+            door: 8,
+            ..metadata
+        };
+        assert_eq!(
+            reader.check(token(&new, &keys, other_door), 15),
+            Err(AccessError::WrongDoor)
+        );
+
+        // after the old key is removed, its tokens are rejected
+        keys.remove(1);
+        assert!(reader.sync(VerifierBundle::new(7, &keys)));
+        assert_eq!(
+            reader.check(token(&old, &keys_with(&old), metadata), 15),
+            Err(AccessError::InvalidToken)
+        );
+    }
+
+    fn keys_with(issuer: &DoorIssuer) -> PublicKeySet<PublicKey> {
+        let mut keys = PublicKeySet::new();
+        keys.insert(1, PublicKey::from(issuer.private_key()));
+        keys
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_frames() {
+        let issuer = DoorIssuer::new(PrivateKey::new(), 1);
+        let keys = keys_with(&issuer);
+        let mut reader = DoorVerifier::new(1);
+        reader.sync(VerifierBundle::new(1, &keys));
+
+        let metadata = DoorMetadata {
+            door: 1,
+            not_before: 0,
+            not_after: 100,
+        };
+        let mut stream = DoorVerifier::encode_frame(&token(&issuer, &keys, metadata));
+        stream.extend(DoorVerifier::encode_frame(&token(&issuer, &keys, metadata)));
+
+        // two frames in one stream
+        let mut decoder = FrameDecoder::new();
+        let frames: Vec<_> = stream.iter().filter_map(|b| decoder.push(*b)).collect();
+        assert_eq!(frames.len(), 2);
+
+        for frame in frames {
+            assert_eq!(reader.check_frame(&frame, 50), Ok(metadata));
+        }
+        assert_eq!(reader.check_frame(b"garbage", 50), Err(AccessError::Frame));
+    }
+
+    #[test]
+    fn test_bundle_serde() {
+        let mut keys = PublicKeySet::new();
+        keys.insert(3, PublicKey::from(PrivateKey::new()));
+
+        let bundle = VerifierBundle::new(4, &keys);
+        let bundle: VerifierBundle =
+            serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+
+        assert_eq!(bundle.door, 4);
+        assert_eq!(bundle.keys.len(), 1);
+        assert_eq!(bundle.keys[0].0, 3);
+    }
+}
+
+// }}}
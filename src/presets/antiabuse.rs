@@ -0,0 +1,721 @@
+//! # Anti-abuse tokens
+//!
+//! Privacy Pass style tokens for web services: a client that passes a challenge (e.g. a CAPTCHA)
+//! at the issuer gets a batch of tokens, and spends one token per request at the origin instead of
+//! solving another challenge.
+//!
+//! - The metadata is only the epoch, so every client refilling in the same epoch is in the same
+//!   anonymity set, and old tokens expire.
+//! - The issuer limits the number of refills per client and epoch.
+//! - The token is sent in the `Authorization` header, bound to the request it pays for, and the
+//!   origin keeps the spent tokens in a [`SpentStore`].
+//!
+//! With the `redis_store` feature, the instances of the origin share the spent tokens in Redis
+//! with a [`RedisSpentStore`], and with the `axum_middleware` feature, [`require_token`] checks
+//! the tokens of the requests to an axum router.
+//!
+//! Both the issuer and the origin need the private key, as the curve25519 tokens can only be
+//! verified with it.
+//!
+//! ```
+//!     use atpmd::nizkp_curve25519::keys::{PrivateKey, PublicKey};
+//!     use atpmd::presets::antiabuse::{
+//!         AntiAbuseIssuer, AntiAbuseOrigin, AntiAbuseWallet, MemorySpentStore,
+//!     };
+//!
+//!     let key = PrivateKey::new();
+//!     let mut issuer = AntiAbuseIssuer::new(key.clone(), 2);
+//!     let mut origin = AntiAbuseOrigin::new(key.clone(), MemorySpentStore::new());
+//!     let mut wallet = AntiAbuseWallet::new(PublicKey::from(&key));
+//!
+//!     let now = 1_622_548_800;
+//!
+//!     // after solving a challenge, the client refills its wallet
+//!     let pending = AntiAbuseWallet::request(now);
+//!     let response = issuer.refill(b"client ip", pending.request(), now).unwrap();
+//!     assert!(wallet.add(pending, response));
+//!
+//!     // and uses a token for a request
+//!     let header = wallet.authorization(now, b"GET /search?q=kake").unwrap();
+//!     assert!(origin.check(Some(&header), b"GET /search?q=kake", now).is_ok());
+//!     assert!(origin.check(Some(&header), b"GET /search?q=kake", now).is_err());
+//! ```
+
+#[cfg(any(feature = "redis_store", feature = "axum_middleware"))]
+use alloc::string::ToString;
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    vec::Vec,
+};
+use core::{convert::TryInto, fmt};
+
+use crate::common::{RandomizedUnsignedToken as _, TokenEngine, UnsignedToken as _};
+use crate::http::{AuthorizationHeaderError, HeaderToken};
+use crate::nizkp_curve25519::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{BoundRedemption, NizkpSignedToken},
+    tokens_batched::{
+        BatchedNizkpTokenEngine, NizkpUnsignedTokenBatched, RandomizedSignedTokenBatched,
+        RandomizedUnsignedTokenBatched,
+    },
+};
+
+/// The number of tokens issued at once
+pub const BATCH_SIZE: usize = 32;
+
+/// The length of an epoch, in seconds
+pub const EPOCH_SECONDS: u64 = 60 * 60;
+
+/// The number of epochs a token may be used after the epoch it was issued in
+pub const MAX_AGE_EPOCHS: u32 = 1;
+
+/// The engine used for anti-abuse tokens
+pub type AntiAbuseEngine = BatchedNizkpTokenEngine<[u8; 4], BATCH_SIZE>;
+
+/// A token as sent in the `Authorization` header
+pub type AntiAbuseRedemption = BoundRedemption<[u8; 4]>;
+
+/// What a token is bound to for a request, the method and the path with the query, e.g.
+/// `GET /search?q=kake`
+pub fn binding(method: &str, path_and_query: &str) -> Vec<u8> {
+    [method.as_bytes(), b" ", path_and_query.as_bytes()].concat()
+}
+
+/// The epoch of a unix timestamp
+pub fn epoch(unix_seconds: u64) -> u32 {
+    (unix_seconds / EPOCH_SECONDS) as u32
+}
+
+fn is_fresh(token_epoch: u32, now: u64) -> bool {
+    let current = epoch(now);
+    token_epoch <= current && current - token_epoch <= MAX_AGE_EPOCHS
+}
+
+/// The reason a refill or a request was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AntiAbuseError {
+    /// The client has refilled too many times this epoch
+    RateLimited,
+    /// The token is not for the current epoch
+    Expired,
+    /// The request did not contain a token
+    MissingToken,
+    /// The header could not be parsed
+    Header(AuthorizationHeaderError),
+    /// The token is not signed, or not bound to this request
+    InvalidToken,
+    /// The token has already been used
+    DoubleSpend,
+    /// The spent store failed, so the token is not accepted
+    Store(String),
+}
+
+impl fmt::Display for AntiAbuseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited => f.write_str("too many refills, try again later"),
+            Self::Expired => f.write_str("the token has expired"),
+            Self::MissingToken => f.write_str("the request does not contain a token"),
+            Self::Header(e) => write!(f, "bad authorization header: {}", e),
+            Self::InvalidToken => f.write_str("the token is not valid for this request"),
+            Self::DoubleSpend => f.write_str("the token has already been used"),
+            Self::Store(e) => write!(f, "the spent store failed: {}", e),
+        }
+    }
+}
+
+// {{{ Spent store
+
+/// Where the origin keeps the spent tokens
+///
+/// This is shared between all instances of the origin, e.g. a Redis set per epoch, where
+/// `insert` is `SADD` and the keys expire after [`MAX_AGE_EPOCHS`].
+pub trait SpentStore {
+    /// Mark the token as spent, returns false if it already was, and
+    /// [`AntiAbuseError::Store`] if the store failed
+    fn insert(&mut self, epoch: u32, id: [u8; 16]) -> Result<bool, AntiAbuseError>;
+
+    /// Forget the tokens of the epochs before `oldest`
+    fn prune(&mut self, oldest: u32);
+}
+
+/// A spent store in memory, for a single instance of the origin
+#[derive(Debug, Clone, Default)]
+pub struct MemorySpentStore {
+    spent: BTreeSet<(u32, [u8; 16])>,
+}
+
+impl MemorySpentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.spent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spent.is_empty()
+    }
+}
+
+impl SpentStore for MemorySpentStore {
+    fn insert(&mut self, epoch: u32, id: [u8; 16]) -> Result<bool, AntiAbuseError> {
+        Ok(self.spent.insert((epoch, id)))
+    }
+
+    fn prune(&mut self, oldest: u32) {
+        self.spent = self.spent.split_off(&(oldest, [0; 16]));
+    }
+}
+
+/// A spent store in Redis, shared by the instances of the origin
+///
+/// The tokens of an epoch are a set under `{prefix}:{epoch}`, which expires when the tokens of
+/// the epoch do, so `prune` does nothing.
+#[cfg(feature = "redis_store")]
+pub struct RedisSpentStore<C: redis::ConnectionLike> {
+    connection: C,
+    prefix: String,
+}
+
+#[cfg(feature = "redis_store")]
+impl<C: redis::ConnectionLike> RedisSpentStore<C> {
+    pub fn new(connection: C, prefix: impl Into<String>) -> Self {
+        Self {
+            connection,
+            prefix: prefix.into(),
+        }
+    }
+
+    fn key(&self, epoch: u32) -> String {
+        alloc::format!("{}:{}", self.prefix, epoch)
+    }
+}
+
+#[cfg(feature = "redis_store")]
+impl<C: redis::ConnectionLike> SpentStore for RedisSpentStore<C> {
+    fn insert(&mut self, epoch: u32, id: [u8; 16]) -> Result<bool, AntiAbuseError> {
+        let key = self.key(epoch);
+        // the epoch of the token, and the epochs it may still be used in
+        let ttl = (u64::from(MAX_AGE_EPOCHS) + 1) * EPOCH_SECONDS;
+
+        let (added,): (usize,) = redis::pipe()
+            .sadd(&key, &id[..])
+            .expire(&key, ttl as i64)
+            .ignore()
+            .query(&mut self.connection)
+            .map_err(|e| AntiAbuseError::Store(e.to_string()))?;
+        Ok(added == 1)
+    }
+
+    fn prune(&mut self, _oldest: u32) {}
+}
+
+// }}}
+
+// {{{ Issuer
+
+/// Signs batches of tokens, at most `max_refills` per client and epoch
+pub struct AntiAbuseIssuer {
+    private_key: PrivateKey,
+    max_refills: u32,
+    refills: BTreeMap<Vec<u8>, (u32, u32)>,
+}
+
+impl AntiAbuseIssuer {
+    pub fn new(private_key: PrivateKey, max_refills: u32) -> Self {
+        Self {
+            private_key,
+            max_refills,
+            refills: BTreeMap::new(),
+        }
+    }
+
+    /// Sign a batch for a client that has passed the challenge
+    ///
+    /// The client is whatever the rate limit is applied to, e.g. the ip address.
+    pub fn refill(
+        &mut self,
+        client: impl AsRef<[u8]>,
+        request: &RandomizedUnsignedTokenBatched<[u8; 4], BATCH_SIZE>,
+        now: u64,
+    ) -> Result<RandomizedSignedTokenBatched<[u8; 4], BATCH_SIZE>, AntiAbuseError> {
+        let current = epoch(now);
+        let metadata: [u8; 4] = request
+            .metadata()
+            .as_ref()
+            .try_into()
+            .map_err(|_e| AntiAbuseError::Expired)?;
+        if u32::from_be_bytes(metadata) != current {
+            return Err(AntiAbuseError::Expired);
+        }
+
+        // forget the clients of the previous epochs
+        self.refills.retain(|_, (epoch, _)| *epoch == current);
+
+        let (_, count) = self
+            .refills
+            .entry(client.as_ref().to_vec())
+            .or_insert((current, 0));
+        if *count >= self.max_refills {
+            return Err(AntiAbuseError::RateLimited);
+        }
+
+        let signed = AntiAbuseEngine::sign_randomized(request, &self.private_key)
+            .map_err(|_e| AntiAbuseError::InvalidToken)?;
+
+        *count += 1;
+        Ok(signed)
+    }
+}
+
+// }}}
+
+// {{{ Wallet
+
+/// A refill that is waiting for the response of the issuer
+pub struct PendingRefill {
+    unsigned: NizkpUnsignedTokenBatched<[u8; 4], BATCH_SIZE>,
+    randomization: [u8; 32],
+    request: RandomizedUnsignedTokenBatched<[u8; 4], BATCH_SIZE>,
+}
+
+impl PendingRefill {
+    /// The request to send to the issuer
+    pub fn request(&self) -> &RandomizedUnsignedTokenBatched<[u8; 4], BATCH_SIZE> {
+        &self.request
+    }
+}
+
+/// The tokens of a client
+pub struct AntiAbuseWallet {
+    public_key: PublicKey,
+    tokens: Vec<NizkpSignedToken<[u8; 4]>>,
+}
+
+impl AntiAbuseWallet {
+    pub fn new(public_key: PublicKey) -> Self {
+        Self {
+            public_key,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Create a request for a batch of tokens for the current epoch
+    pub fn request(now: u64) -> PendingRefill {
+        let unsigned = NizkpUnsignedTokenBatched::new(epoch(now).to_be_bytes());
+        let (randomization, request) = AntiAbuseEngine::randomize(&unsigned);
+
+        PendingRefill {
+            unsigned,
+            randomization,
+            request,
+        }
+    }
+
+    /// Add the tokens of a batch to the wallet, if the response is valid
+    pub fn add(
+        &mut self,
+        pending: PendingRefill,
+        response: RandomizedSignedTokenBatched<[u8; 4], BATCH_SIZE>,
+    ) -> bool {
+        match AntiAbuseEngine::verify_signature_and_unrandomize(
+            pending.unsigned,
+            pending.request,
+            response,
+            &self.public_key,
+            pending.randomization,
+        ) {
+            Ok(signed) => {
+                self.tokens.extend(signed.into_tokens());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The number of tokens the origin will still accept
+    pub fn remaining(&self, now: u64) -> usize {
+        self.tokens
+            .iter()
+            .filter(|token| is_fresh(u32::from_be_bytes(*token.metadata()), now))
+            .count()
+    }
+
+    /// Spend a token on a request, and get the value of the `Authorization` header
+    ///
+    /// Expired tokens are thrown away. Returns `None` when the wallet needs a refill.
+    pub fn authorization(&mut self, now: u64, request: impl AsRef<[u8]>) -> Option<String> {
+        self.tokens
+            .retain(|token| is_fresh(u32::from_be_bytes(*token.metadata()), now));
+
+        // a token is never used twice
+        let token = self.tokens.pop()?;
+
+        Some(token.bind(request).to_authorization_header())
+    }
+}
+
+// }}}
+
+// {{{ Origin
+
+/// Checks the tokens in the requests to a web service
+///
+/// Call [`AntiAbuseOrigin::check`] from the middleware of the web framework, before the handler.
+pub struct AntiAbuseOrigin<S: SpentStore> {
+    private_key: PrivateKey,
+    store: S,
+}
+
+impl<S: SpentStore> AntiAbuseOrigin<S> {
+    pub fn new(private_key: PrivateKey, store: S) -> Self {
+        Self { private_key, store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Check the `Authorization` header of a request, and mark the token as spent
+    ///
+    /// `request` is what the client bound the token to, e.g. the method and the path.
+    pub fn check(
+        &mut self,
+        header: Option<&str>,
+        request: impl AsRef<[u8]>,
+        now: u64,
+    ) -> Result<(), AntiAbuseError> {
+        let header = header.ok_or(AntiAbuseError::MissingToken)?;
+        let redemption = AntiAbuseRedemption::parse_authorization_header(header)
+            .map_err(AntiAbuseError::Header)?;
+
+        let token_epoch = u32::from_be_bytes(redemption.metadata);
+        if !is_fresh(token_epoch, now) {
+            return Err(AntiAbuseError::Expired);
+        }
+
+        if !redemption.verify(&self.private_key, request) {
+            return Err(AntiAbuseError::InvalidToken);
+        }
+
+        self.store.prune(epoch(now).saturating_sub(MAX_AGE_EPOCHS));
+        if !self.store.insert(token_epoch, redemption.id)? {
+            return Err(AntiAbuseError::DoubleSpend);
+        }
+
+        Ok(())
+    }
+}
+
+// }}}
+
+// {{{ axum
+
+/// The origin of the requests to an axum router, shared by its handlers
+#[cfg(feature = "axum_middleware")]
+pub type SharedOrigin<S> = std::sync::Arc<std::sync::Mutex<AntiAbuseOrigin<S>>>;
+
+/// axum middleware that checks the token of a request before the handler, and answers
+/// `401 Unauthorized` without one, or `503 Service Unavailable` if the spent store failed
+///
+/// The token is bound to the [`binding`] of the method and the path with the query.
+///
+/// ```
+///     use std::sync::{Arc, Mutex};
+///
+///     use atpmd::nizkp_curve25519::keys::PrivateKey;
+///     use atpmd::presets::antiabuse::{AntiAbuseOrigin, MemorySpentStore, require_token};
+///     use axum::{middleware::from_fn_with_state, routing::get, Router};
+///
+///     let origin = AntiAbuseOrigin::new(PrivateKey::new(), MemorySpentStore::new());
+///     let app: Router = Router::new()
+///         .route("/search", get(|| async { "search results" }))
+///         .layer(from_fn_with_state(
+///             Arc::new(Mutex::new(origin)),
+///             require_token::<MemorySpentStore>,
+///         ));
+/// ```
+#[cfg(feature = "axum_middleware")]
+pub async fn require_token<S: SpentStore>(
+    axum::extract::State(origin): axum::extract::State<SharedOrigin<S>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::http::{header::AUTHORIZATION, StatusCode};
+    use axum::response::IntoResponse;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or(request.uri().path(), |path| path.as_str());
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok());
+
+    let checked = match origin.lock() {
+        Ok(mut origin) => origin.check(header, binding(request.method().as_str(), path), now),
+        Err(_) => Err(AntiAbuseError::Store("the origin is poisoned".into())),
+    };
+
+    match checked {
+        Ok(()) => next.run(request).await,
+        Err(e @ AntiAbuseError::Store(_)) => {
+            (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response()
+        }
+        Err(e) => (StatusCode::UNAUTHORIZED, e.to_string()).into_response(),
+    }
+}
+
+// }}}
+
+// {{{ tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_622_548_800;
+
+    /// The issuer service, that only talks to the client
+    struct IssuerService {
+        issuer: AntiAbuseIssuer,
+    }
+
+    /// The origin service, that only sees the headers of the requests
+    struct OriginService {
+        origin: AntiAbuseOrigin<MemorySpentStore>,
+    }
+
+    impl OriginService {
+        fn handle(
+            &mut self,
+            header: Option<String>,
+            path: &str,
+            now: u64,
+        ) -> Result<&'static str, AntiAbuseError> {
+            self.origin
+                .check(header.as_deref(), path, now)
+                .map(|_| "search results")
+        }
+    }
+
+    fn services() -> (IssuerService, OriginService, PublicKey) {
+        let key = PrivateKey::new();
+        let public_key = PublicKey::from(&key);
+
+        (
+            IssuerService {
+                issuer: AntiAbuseIssuer::new(key.clone(), 1),
+            },
+            OriginService {
+                origin: AntiAbuseOrigin::new(key, MemorySpentStore::new()),
+            },
+            public_key,
+        )
+    }
+
+    fn refill(
+        issuer: &mut IssuerService,
+        wallet: &mut AntiAbuseWallet,
+        client: &[u8],
+        now: u64,
+    ) -> Result<(), AntiAbuseError> {
+        let pending = AntiAbuseWallet::request(now);
+        let response = issuer.issuer.refill(client, pending.request(), now)?;
+        assert!(wallet.add(pending, response));
+        Ok(())
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        let (mut issuer, mut origin, public_key) = services();
+        let mut wallet = AntiAbuseWallet::new(public_key);
+
+        assert_eq!(
+            origin.handle(None, "/search", NOW),
+            Err(AntiAbuseError::MissingToken)
+        );
+
+        refill(&mut issuer, &mut wallet, b"client", NOW).unwrap();
+        assert_eq!(wallet.remaining(NOW), BATCH_SIZE);
+
+        // every token pays for one request
+        for _ in 0..BATCH_SIZE {
+            let header = wallet.authorization(NOW, "/search");
+            assert_eq!(origin.handle(header, "/search", NOW), Ok("search results"));
+        }
+        assert!(wallet.authorization(NOW, "/search").is_none());
+        assert_eq!(origin.origin.store().len(), BATCH_SIZE);
+
+        // the client is rate limited in this epoch, but not the next
+        assert_eq!(
+            refill(&mut issuer, &mut wallet, b"client", NOW),
+            Err(AntiAbuseError::RateLimited)
+        );
+        assert!(refill(&mut issuer, &mut wallet, b"other client", NOW).is_ok());
+        assert!(refill(&mut issuer, &mut wallet, b"client", NOW + EPOCH_SECONDS).is_ok());
+    }
+
+    #[test]
+    fn fail_replay_and_rebinding() {
+        let (mut issuer, mut origin, public_key) = services();
+        let mut wallet = AntiAbuseWallet::new(public_key);
+        refill(&mut issuer, &mut wallet, b"client", NOW).unwrap();
+
+        let header = wallet.authorization(NOW, "/search").unwrap();
+
+        // the token is bound to the request
+        assert_eq!(
+            origin.handle(Some(header.clone()), "/admin", NOW),
+            Err(AntiAbuseError::InvalidToken)
+        );
+        assert!(origin.handle(Some(header.clone()), "/search", NOW).is_ok());
+        assert_eq!(
+            origin.handle(Some(header), "/search", NOW),
+            Err(AntiAbuseError::DoubleSpend)
+        );
+
+        assert!(matches!(
+            origin.handle(Some("Bearer kake".into()), "/search", NOW),
+            Err(AntiAbuseError::Header(_))
+        ));
+    }
+
+    #[test]
+    fn fail_expired() {
+        let (mut issuer, mut origin, public_key) = services();
+        let mut wallet = AntiAbuseWallet::new(public_key);
+        refill(&mut issuer, &mut wallet, b"client", NOW).unwrap();
+
+        let header = wallet.authorization(NOW, "/search");
+        assert_eq!(
+            origin.handle(header, "/search", NOW + 2 * EPOCH_SECONDS),
+            Err(AntiAbuseError::Expired)
+        );
+
+        // the wallet throws away the tokens the origin will not accept
+        assert_eq!(wallet.remaining(NOW + 2 * EPOCH_SECONDS), 0);
+        assert!(wallet
+            .authorization(NOW + 2 * EPOCH_SECONDS, "/search")
+            .is_none());
+
+        // and the issuer does not sign for another epoch
+        let pending = AntiAbuseWallet::request(NOW);
+        assert_eq!(
+            issuer
+                .issuer
+                .refill(b"client", pending.request(), NOW + EPOCH_SECONDS)
+                .err(),
+            Some(AntiAbuseError::Expired)
+        );
+    }
+
+    #[cfg(feature = "redis_store")]
+    #[test]
+    fn test_redis_store() {
+        use redis_test::{MockCmd, MockRedisConnection};
+
+        let (mut issuer, _, public_key) = services();
+        let mut wallet = AntiAbuseWallet::new(public_key);
+        refill(&mut issuer, &mut wallet, b"client", NOW).unwrap();
+        let header = wallet.authorization(NOW, "/search").unwrap();
+        let id = AntiAbuseRedemption::parse_authorization_header(&header)
+            .unwrap()
+            .id;
+
+        let key = alloc::format!("spent:{}", epoch(NOW));
+        let spend = || {
+            redis::pipe()
+                .sadd(&key, &id[..])
+                .expire(&key, (2 * EPOCH_SECONDS) as i64)
+                .ignore()
+                .clone()
+        };
+        let connection = MockRedisConnection::new([
+            MockCmd::with_values(spend(), Ok(alloc::vec![1, 1])),
+            MockCmd::with_values(spend(), Ok(alloc::vec![0, 1])),
+        ]);
+
+        let mut origin = AntiAbuseOrigin::new(
+            issuer.issuer.private_key.clone(),
+            RedisSpentStore::new(connection, "spent"),
+        );
+        assert!(origin.check(Some(&header), "/search", NOW).is_ok());
+        assert_eq!(
+            origin.check(Some(&header), "/search", NOW),
+            Err(AntiAbuseError::DoubleSpend)
+        );
+
+        // no more answers from the mock, as if Redis is down
+        assert!(matches!(
+            origin.check(Some(&header), "/search", NOW),
+            Err(AntiAbuseError::Store(_))
+        ));
+    }
+
+    #[cfg(feature = "axum_middleware")]
+    #[tokio::test]
+    async fn test_axum_middleware() {
+        use std::sync::{Arc, Mutex};
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        use axum::{body::Body, http::Request, http::StatusCode, routing::get, Router};
+        use tower::ServiceExt;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let (mut issuer, origin, public_key) = services();
+        let mut wallet = AntiAbuseWallet::new(public_key);
+        refill(&mut issuer, &mut wallet, b"client", now).unwrap();
+
+        let origin: SharedOrigin<_> = Arc::new(Mutex::new(origin.origin));
+        let app = Router::new()
+            .route("/search", get(|| async { "search results" }))
+            .layer(axum::middleware::from_fn_with_state(
+                origin.clone(),
+                require_token::<MemorySpentStore>,
+            ));
+        let send = |header: Option<&str>, path: &str| {
+            let mut request = Request::get(path);
+            if let Some(header) = header {
+                request = request.header("Authorization", header);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let header = wallet
+            .authorization(now, binding("GET", "/search?q=kake"))
+            .unwrap();
+        let status = |response: axum::response::Response| response.status();
+
+        assert_eq!(
+            status(send(None, "/search?q=kake").await.unwrap()),
+            StatusCode::UNAUTHORIZED
+        );
+        // the token is bound to the query
+        assert_eq!(
+            status(send(Some(&header), "/search?q=other").await.unwrap()),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(send(Some(&header), "/search?q=kake").await.unwrap()),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(send(Some(&header), "/search?q=kake").await.unwrap()),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(origin.lock().unwrap().store().len(), 1);
+    }
+}
+
+// }}}
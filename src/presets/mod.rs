@@ -0,0 +1,15 @@
+//! # Presets
+//!
+//! Ready made combinations of an engine, a metadata schema and a policy for common use cases.
+
+#[cfg(feature = "pairing")]
+pub mod access_control;
+
+#[cfg(feature = "curve25519")]
+pub mod antiabuse;
+
+#[cfg(feature = "pairing")]
+pub mod quota;
+
+#[cfg(feature = "curve25519")]
+pub mod telemetry;
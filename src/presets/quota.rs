@@ -0,0 +1,587 @@
+//! # Quota tokens
+//!
+//! Two tiers of tokens, such that the number of tokens per account can be limited without the
+//! redemptions being linked to the account:
+//!
+//! 1. The account service authenticates the user as usual, and signs a few blinded
+//!    [quota tokens](QuotaIssuer) per account and epoch.
+//! 2. The user spends a quota token at the [exchange](AccessIssuer) for a batch of blinded access
+//!    tokens. The exchange only sees an unblinded quota token, so it does not learn the account.
+//! 3. The user spends the access tokens at the [origin](AccessVerifier), one per request.
+//!
+//! Neither the account service nor the exchange can link what they saw to the redemptions, even if
+//! they work together, as both issuances are blind. All tokens of an epoch have the same metadata,
+//! so the anonymity set is everyone that got tokens in the epoch. The pairing engine is used, so
+//! the exchange and the origin only need the public keys.
+//!
+//! ```
+//!     use atpmd::atpm_pairing::keys::{PrivateKey, PublicKey};
+//!     use atpmd::presets::quota::{AccessIssuer, AccessVerifier, QuotaIssuer, QuotaWallet};
+//!     use atpmd::redemption::MemoryRedemptionStore;
+//!
+//!     let quota_key = PrivateKey::new();
+//!     let access_key = PrivateKey::new();
+//!     let mut accounts = QuotaIssuer::new(quota_key.clone(), 1);
+//!     let mut exchange = AccessIssuer::new(
+//!         PublicKey::from(&quota_key),
+//!         access_key.clone(),
+//!         10,
+//!         MemoryRedemptionStore::new(),
+//!     );
+//!     let mut origin = AccessVerifier::new(PublicKey::from(&access_key), MemoryRedemptionStore::new());
+//!     let mut wallet = QuotaWallet::new(PublicKey::from(&quota_key), PublicKey::from(&access_key));
+//!
+//!     let now = 1_622_548_800;
+//!
+//!     // the user logs in, and gets a quota token for the account
+//!     let pending = QuotaWallet::request_quota(now);
+//!     let response = accounts.issue(b"alice", pending.request(), now).unwrap();
+//!     assert!(wallet.add_quota(pending, response));
+//!     assert!(accounts.issue(b"alice", QuotaWallet::request_quota(now).request(), now).is_err());
+//!
+//!     // then trades it for access tokens, without logging in
+//!     let pending = wallet.exchange(10, now).unwrap();
+//!     let response = exchange.exchange(pending.request(), now).unwrap();
+//!     assert!(wallet.add_access(pending, response));
+//!
+//!     // and spends them one at a time
+//!     let token = wallet.access_token(now).unwrap();
+//!     assert!(origin.check(&token, now).is_ok());
+//!     assert!(origin.check(&token, now).is_err());
+//!     assert_eq!(wallet.remaining(now), 9);
+//! ```
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::fmt;
+
+use crate::atpm_pairing::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{
+        PairingSignedToken, PairingTokenEngine, PairingUnsignedToken, RandomizedSignedToken,
+        RandomizedUnsignedToken,
+    },
+    tokens_batched_dyn::{
+        DynBatchedPairingTokenEngine, DynBatchedPairingUnsignedToken,
+        DynBatchedRandomizedSignedToken, DynBatchedRandomizedUnsignedToken,
+    },
+};
+use crate::common::{RandomizedUnsignedToken as _, TokenEngine};
+use crate::expiry::Metadata;
+use crate::redemption::{RedeemError, RedemptionStore};
+
+/// The length of an epoch, in seconds
+pub const EPOCH_SECONDS: u64 = 24 * 60 * 60;
+
+/// The application data of the metadata of the quota tokens
+pub const QUOTA_DATA: &[u8] = b"quota";
+
+/// The application data of the metadata of the access tokens
+pub const ACCESS_DATA: &[u8] = b"access";
+
+/// The engine of the quota tokens
+pub type QuotaEngine = PairingTokenEngine<Metadata>;
+
+/// The engine of the batches of access tokens
+pub type AccessEngine = DynBatchedPairingTokenEngine<Metadata>;
+
+/// A quota token or an access token
+pub type QuotaToken = PairingSignedToken<Metadata>;
+
+/// The metadata of the tokens issued at `now`, which expire at the end of the epoch
+pub fn metadata(data: &[u8], now: u64) -> Metadata {
+    Metadata::new((now / EPOCH_SECONDS + 1) * EPOCH_SECONDS, data)
+}
+
+/// The reason a quota token or access tokens were not issued, or an access token was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaError {
+    /// The account has got all of its quota tokens for the epoch
+    RateLimited,
+    /// The tokens are not for the current epoch
+    Expired,
+    /// The batch has more access tokens than one quota token pays for
+    TooMany,
+    /// The token is not validly signed
+    InvalidToken,
+    /// The token has already been spent
+    DoubleSpend,
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimited => {
+                f.write_str("the quota of the account is used up, try again later")
+            }
+            Self::Expired => f.write_str("the tokens are not for the current epoch"),
+            Self::TooMany => f.write_str("too many access tokens for one quota token"),
+            Self::InvalidToken => f.write_str("the token is not validly signed"),
+            Self::DoubleSpend => f.write_str("the token has already been used"),
+        }
+    }
+}
+
+impl From<RedeemError> for QuotaError {
+    fn from(e: RedeemError) -> Self {
+        match e {
+            RedeemError::Expired => Self::Expired,
+            RedeemError::Invalid => Self::InvalidToken,
+            RedeemError::DoubleSpend => Self::DoubleSpend,
+        }
+    }
+}
+
+// {{{ Account service
+
+/// Signs quota tokens, at most `per_account` per account and epoch
+///
+/// The account service sees who the user is, but only blinded quota tokens.
+pub struct QuotaIssuer {
+    private_key: PrivateKey,
+    per_account: u32,
+    issued: BTreeMap<Vec<u8>, (u64, u32)>,
+}
+
+impl QuotaIssuer {
+    pub fn new(private_key: PrivateKey, per_account: u32) -> Self {
+        Self {
+            private_key,
+            per_account,
+            issued: BTreeMap::new(),
+        }
+    }
+
+    /// Sign a quota token for an account that the caller has authenticated
+    pub fn issue(
+        &mut self,
+        account: impl AsRef<[u8]>,
+        request: &RandomizedUnsignedToken<Metadata>,
+        now: u64,
+    ) -> Result<RandomizedSignedToken<Metadata>, QuotaError> {
+        if *request.metadata() != *metadata(QUOTA_DATA, now).as_ref() {
+            return Err(QuotaError::Expired);
+        }
+
+        // forget the accounts of the previous epochs
+        let current = now / EPOCH_SECONDS;
+        self.issued.retain(|_, (epoch, _)| *epoch == current);
+
+        let (_, count) = self
+            .issued
+            .entry(account.as_ref().to_vec())
+            .or_insert((current, 0));
+        if *count >= self.per_account {
+            return Err(QuotaError::RateLimited);
+        }
+
+        let signed = QuotaEngine::sign_randomized(request, &self.private_key)
+            .map_err(|_e| QuotaError::InvalidToken)?;
+
+        *count += 1;
+        Ok(signed)
+    }
+}
+
+// }}}
+
+// {{{ Exchange
+
+/// A quota token, and the blinded batch of access tokens it pays for
+#[derive(Serialize, Deserialize)]
+pub struct QuotaExchange {
+    pub quota: QuotaToken,
+    pub batch: DynBatchedRandomizedUnsignedToken<Metadata>,
+}
+
+/// Trades quota tokens for batches of up to `per_quota` access tokens
+///
+/// The spent quota tokens are kept in a redemption store until they expire.
+pub struct AccessIssuer<S: RedemptionStore> {
+    quota_key: PublicKey,
+    private_key: PrivateKey,
+    per_quota: usize,
+    spent: S,
+}
+
+impl<S: RedemptionStore> AccessIssuer<S> {
+    pub fn new(quota_key: PublicKey, private_key: PrivateKey, per_quota: usize, spent: S) -> Self {
+        Self {
+            quota_key,
+            private_key,
+            per_quota,
+            spent,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.spent
+    }
+
+    /// Spend the quota token, and sign the batch of access tokens
+    pub fn exchange(
+        &mut self,
+        exchange: &QuotaExchange,
+        now: u64,
+    ) -> Result<DynBatchedRandomizedSignedToken<Metadata>, QuotaError> {
+        if exchange.batch.len() > self.per_quota {
+            return Err(QuotaError::TooMany);
+        }
+
+        if *exchange.batch.metadata() != *metadata(ACCESS_DATA, now).as_ref() {
+            return Err(QuotaError::Expired);
+        }
+
+        if exchange.quota.metadata().data() != QUOTA_DATA {
+            return Err(QuotaError::InvalidToken);
+        }
+
+        // the batch is checked first, so a quota token is not spent on a batch that is refused
+        self.spent.prune(now);
+        QuotaEngine::redeem(&exchange.quota, &self.quota_key, &mut self.spent, now)?;
+
+        AccessEngine::sign_randomized(&exchange.batch, &self.private_key)
+            .map_err(|_e| QuotaError::InvalidToken)
+    }
+}
+
+// }}}
+
+// {{{ Wallet
+
+/// A quota token that is waiting for the signature of the account service
+pub struct PendingQuota {
+    unsigned: PairingUnsignedToken<Metadata>,
+    randomization: <QuotaEngine as TokenEngine>::Randomization,
+    request: RandomizedUnsignedToken<Metadata>,
+}
+
+impl PendingQuota {
+    /// The request to send to the account service
+    pub fn request(&self) -> &RandomizedUnsignedToken<Metadata> {
+        &self.request
+    }
+}
+
+/// A batch of access tokens that is waiting for the signature of the exchange
+pub struct PendingExchange {
+    unsigned: DynBatchedPairingUnsignedToken<Metadata>,
+    randomization: [u8; 32],
+    request: QuotaExchange,
+}
+
+impl PendingExchange {
+    /// The request to send to the exchange
+    pub fn request(&self) -> &QuotaExchange {
+        &self.request
+    }
+}
+
+/// The quota tokens and the access tokens of a user
+pub struct QuotaWallet {
+    quota_key: PublicKey,
+    access_key: PublicKey,
+    quota: Vec<QuotaToken>,
+    access: Vec<QuotaToken>,
+}
+
+impl QuotaWallet {
+    pub fn new(quota_key: PublicKey, access_key: PublicKey) -> Self {
+        Self {
+            quota_key,
+            access_key,
+            quota: Vec::new(),
+            access: Vec::new(),
+        }
+    }
+
+    /// Create a request for a quota token for the current epoch
+    pub fn request_quota(now: u64) -> PendingQuota {
+        let unsigned = QuotaEngine::generate(metadata(QUOTA_DATA, now));
+        let (randomization, request) = QuotaEngine::randomize(&unsigned);
+
+        PendingQuota {
+            unsigned,
+            randomization,
+            request,
+        }
+    }
+
+    /// Add a quota token to the wallet, if the response is valid
+    pub fn add_quota(
+        &mut self,
+        pending: PendingQuota,
+        response: RandomizedSignedToken<Metadata>,
+    ) -> bool {
+        match QuotaEngine::verify_signature_and_unrandomize(
+            pending.unsigned,
+            pending.request,
+            response,
+            &self.quota_key,
+            pending.randomization,
+        ) {
+            Ok(token) => {
+                self.quota.push(token);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Take a quota token, and create a request for `count` access tokens
+    ///
+    /// Returns `None` when the wallet has no quota token for the current epoch. The quota token is
+    /// gone once it is sent, also if the exchange fails.
+    pub fn exchange(&mut self, count: usize, now: u64) -> Option<PendingExchange> {
+        self.quota.retain(|token| !token.metadata().is_expired(now));
+        let quota = self.quota.pop()?;
+
+        let unsigned = AccessEngine::generate((metadata(ACCESS_DATA, now), count));
+        let (randomization, batch) = AccessEngine::randomize(&unsigned);
+
+        Some(PendingExchange {
+            unsigned,
+            randomization,
+            request: QuotaExchange { quota, batch },
+        })
+    }
+
+    /// Add the access tokens of a batch to the wallet, if the response is valid
+    pub fn add_access(
+        &mut self,
+        pending: PendingExchange,
+        response: DynBatchedRandomizedSignedToken<Metadata>,
+    ) -> bool {
+        match AccessEngine::verify_signature_and_unrandomize(
+            pending.unsigned,
+            pending.request.batch,
+            response,
+            &self.access_key,
+            pending.randomization,
+        ) {
+            Ok(signed) => {
+                self.access.extend(signed.iter());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The number of access tokens that have not expired
+    pub fn remaining(&self, now: u64) -> usize {
+        self.access
+            .iter()
+            .filter(|token| !token.metadata().is_expired(now))
+            .count()
+    }
+
+    /// The number of quota tokens that have not expired
+    pub fn quota(&self, now: u64) -> usize {
+        self.quota
+            .iter()
+            .filter(|token| !token.metadata().is_expired(now))
+            .count()
+    }
+
+    /// Take an access token to spend, expired tokens are thrown away
+    pub fn access_token(&mut self, now: u64) -> Option<QuotaToken> {
+        self.access
+            .retain(|token| !token.metadata().is_expired(now));
+        self.access.pop()
+    }
+}
+
+// }}}
+
+// {{{ Origin
+
+/// Checks the access tokens of the requests, and remembers them until they expire
+pub struct AccessVerifier<S: RedemptionStore> {
+    access_key: PublicKey,
+    store: S,
+}
+
+impl<S: RedemptionStore> AccessVerifier<S> {
+    pub fn new(access_key: PublicKey, store: S) -> Self {
+        Self { access_key, store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Redeem an access token
+    pub fn check(&mut self, token: &QuotaToken, now: u64) -> Result<(), QuotaError> {
+        if token.metadata().data() != ACCESS_DATA {
+            return Err(QuotaError::InvalidToken);
+        }
+
+        self.store.prune(now);
+        Ok(QuotaEngine::redeem(
+            token,
+            &self.access_key,
+            &mut self.store,
+            now,
+        )?)
+    }
+}
+
+// }}}
+
+// {{{ tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::redemption::MemoryRedemptionStore;
+
+    const NOW: u64 = 1_622_548_800;
+
+    struct Services {
+        accounts: QuotaIssuer,
+        exchange: AccessIssuer<MemoryRedemptionStore>,
+        origin: AccessVerifier<MemoryRedemptionStore>,
+        wallet: QuotaWallet,
+    }
+
+    fn services(per_account: u32, per_quota: usize) -> Services {
+        let quota_key = PrivateKey::new();
+        let access_key = PrivateKey::new();
+
+        Services {
+            accounts: QuotaIssuer::new(quota_key.clone(), per_account),
+            exchange: AccessIssuer::new(
+                PublicKey::from(&quota_key),
+                access_key.clone(),
+                per_quota,
+                MemoryRedemptionStore::new(),
+            ),
+            origin: AccessVerifier::new(PublicKey::from(&access_key), MemoryRedemptionStore::new()),
+            wallet: QuotaWallet::new(PublicKey::from(&quota_key), PublicKey::from(&access_key)),
+        }
+    }
+
+    impl Services {
+        fn quota(&mut self, account: &[u8], now: u64) -> Result<(), QuotaError> {
+            let pending = QuotaWallet::request_quota(now);
+            let response = self.accounts.issue(account, pending.request(), now)?;
+            assert!(self.wallet.add_quota(pending, response));
+            Ok(())
+        }
+
+        fn exchange(&mut self, count: usize, now: u64) -> Result<(), QuotaError> {
+            let pending = self.wallet.exchange(count, now).unwrap();
+            let response = self.exchange.exchange(pending.request(), now)?;
+            assert!(self.wallet.add_access(pending, response));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        let mut services = services(2, 4);
+
+        // each account gets its own quota
+        services.quota(b"alice", NOW).unwrap();
+        services.quota(b"alice", NOW).unwrap();
+        assert_eq!(services.quota(b"alice", NOW), Err(QuotaError::RateLimited));
+        services.quota(b"bob", NOW).unwrap();
+        assert_eq!(services.wallet.quota(NOW), 3);
+
+        services.exchange(4, NOW).unwrap();
+        services.exchange(2, NOW).unwrap();
+        assert_eq!(services.wallet.remaining(NOW), 6);
+
+        for _ in 0..6 {
+            let token = services.wallet.access_token(NOW).unwrap();
+            assert_eq!(services.origin.check(&token, NOW), Ok(()));
+            assert_eq!(
+                services.origin.check(&token, NOW),
+                Err(QuotaError::DoubleSpend)
+            );
+        }
+        assert!(services.wallet.access_token(NOW).is_none());
+
+        // the quota is refilled in the next epoch, and the old tokens expire
+        let tomorrow = NOW + EPOCH_SECONDS;
+        assert_eq!(services.wallet.quota(tomorrow), 0);
+        services.quota(b"alice", tomorrow).unwrap();
+        services.exchange(1, tomorrow).unwrap();
+    }
+
+    #[test]
+    fn fail_exchange() {
+        let mut services = services(4, 4);
+        for _ in 0..3 {
+            services.quota(b"alice", NOW).unwrap();
+        }
+
+        // the batch is refused before the quota token is spent
+        let pending = services.wallet.exchange(5, NOW).unwrap();
+        let request = pending.request();
+        assert_eq!(
+            services.exchange.exchange(request, NOW).err(),
+            Some(QuotaError::TooMany)
+        );
+        assert!(services.exchange.store().is_empty());
+
+        // a quota token is only spent once
+        let pending = services.wallet.exchange(4, NOW).unwrap();
+        let response = services.exchange.exchange(pending.request(), NOW).unwrap();
+        assert_eq!(
+            services.exchange.exchange(pending.request(), NOW).err(),
+            Some(QuotaError::DoubleSpend)
+        );
+        assert!(services.wallet.add_access(pending, response));
+
+        // an access token does not pay for access tokens, and a quota token is not an access token
+        let access = services.wallet.access_token(NOW).unwrap();
+        let mut pending = services.wallet.exchange(1, NOW).unwrap();
+        let quota = core::mem::replace(&mut pending.request.quota, access);
+        assert_eq!(
+            services.exchange.exchange(pending.request(), NOW).err(),
+            Some(QuotaError::InvalidToken)
+        );
+        assert_eq!(
+            services.origin.check(&quota, NOW),
+            Err(QuotaError::InvalidToken)
+        );
+
+        // a quota token from another account service does not verify
+        let other_key = PrivateKey::new();
+        let mut other = QuotaIssuer::new(other_key.clone(), 1);
+        let mut wallet = QuotaWallet::new(
+            PublicKey::from(&other_key),
+            services.wallet.access_key.clone(),
+        );
+        let pending = QuotaWallet::request_quota(NOW);
+        let response = other.issue(b"mallory", pending.request(), NOW).unwrap();
+        assert!(wallet.add_quota(pending, response));
+        let pending = wallet.exchange(1, NOW).unwrap();
+        assert_eq!(
+            services.exchange.exchange(pending.request(), NOW).err(),
+            Some(QuotaError::InvalidToken)
+        );
+
+        // the tokens are for the current epoch
+        services.quota(b"alice", NOW).unwrap();
+        let pending = services.wallet.exchange(1, NOW).unwrap();
+        assert_eq!(
+            services
+                .exchange
+                .exchange(pending.request(), NOW + EPOCH_SECONDS)
+                .err(),
+            Some(QuotaError::Expired)
+        );
+        let pending = QuotaWallet::request_quota(NOW);
+        assert_eq!(
+            services
+                .accounts
+                .issue(b"alice", pending.request(), NOW + EPOCH_SECONDS)
+                .err(),
+            Some(QuotaError::Expired)
+        );
+    }
+}
+
+// }}}
@@ -0,0 +1,409 @@
+//! # Anonymous telemetry
+//!
+//! Clients get a batch of tokens for a report type each day, and spend one token per report.
+//! The collector learns that a report comes from a client it issued tokens to, but not which one.
+//!
+//! - The metadata is only the report type and the day, so that every client reporting the same
+//!   type on the same day is in the same anonymity set.
+//! - Tokens are issued in batches of [`BATCH_SIZE`], and expire after [`MAX_AGE_DAYS`].
+//! - A token is bound to its report by a MAC, so it can not be used for another report, and each
+//!   token can only be redeemed once.
+//!
+//! ```
+//!     use atpmd::nizkp_curve25519::keys::{PrivateKey, PublicKey};
+//!     use atpmd::presets::telemetry::{day, TelemetryCollector, TelemetryMetadata, TelemetryWallet};
+//!
+//!     let today = day(1_622_548_800);
+//!     let metadata = TelemetryMetadata { report_type: 1, day: today };
+//!
+//!     let mut collector = TelemetryCollector::new(PrivateKey::new());
+//!     let mut wallet = TelemetryWallet::new(PublicKey::from(collector.private_key()));
+//!
+//!     // refill the wallet
+//!     let pending = TelemetryWallet::request(metadata);
+//!     let response = collector.issue(pending.request(), today).unwrap();
+//!     assert!(wallet.add(pending, response));
+//!
+//!     // send a report
+//!     let report = wallet.report(metadata, b"crash in module 3").unwrap();
+//!     assert_eq!(collector.redeem(&report, today), Ok(metadata));
+//!
+//!     // the same token can not be used twice
+//!     assert!(collector.redeem(&report, today).is_err());
+//! ```
+
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
+use core::{convert::TryInto, fmt};
+
+use crate::common::{RandomizedUnsignedToken as _, TokenEngine, UnsignedToken as _};
+use crate::nizkp_curve25519::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{BoundRedemption, NizkpSignedToken},
+    tokens_batched::{
+        BatchedNizkpTokenEngine, NizkpUnsignedTokenBatched, RandomizedSignedTokenBatched,
+        RandomizedUnsignedTokenBatched,
+    },
+};
+
+/// The number of tokens issued at once
+pub const BATCH_SIZE: usize = 16;
+
+/// The number of days a token may be used after the day it was issued for
+pub const MAX_AGE_DAYS: u32 = 1;
+
+/// The encoded length of [`TelemetryMetadata`]
+pub const METADATA_LEN: usize = 6;
+
+/// The engine used for telemetry tokens
+pub type TelemetryEngine = BatchedNizkpTokenEngine<[u8; METADATA_LEN], BATCH_SIZE>;
+
+/// The day bucket of a unix timestamp, as days since 1970-01-01
+pub fn day(unix_seconds: u64) -> u32 {
+    (unix_seconds / (24 * 60 * 60)) as u32
+}
+
+// {{{ Metadata
+
+/// The metadata of a telemetry token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TelemetryMetadata {
+    /// What kind of report the token may be spent on
+    pub report_type: u16,
+    /// The day the token was issued for, see [`day`]
+    pub day: u32,
+}
+
+impl TelemetryMetadata {
+    pub fn to_bytes(&self) -> [u8; METADATA_LEN] {
+        let mut bytes = [0u8; METADATA_LEN];
+        bytes[..2].copy_from_slice(&self.report_type.to_be_bytes());
+        bytes[2..].copy_from_slice(&self.day.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != METADATA_LEN {
+            return None;
+        }
+
+        Some(Self {
+            report_type: u16::from_be_bytes(bytes[..2].try_into().ok()?),
+            day: u32::from_be_bytes(bytes[2..].try_into().ok()?),
+        })
+    }
+
+    fn is_fresh(&self, today: u32) -> bool {
+        self.day <= today && today - self.day <= MAX_AGE_DAYS
+    }
+}
+
+// }}}
+
+// {{{ Report
+
+/// A report, with the token that pays for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryReport {
+    pub redemption: BoundRedemption<[u8; METADATA_LEN]>,
+    pub report: Box<[u8]>,
+}
+
+/// The reason a report was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TelemetryError {
+    /// The token is for another day than today or yesterday
+    Expired,
+    /// The token is not signed, or not bound to this report
+    InvalidToken,
+    /// The token has already been used
+    DoubleSpend,
+}
+
+impl fmt::Display for TelemetryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => f.write_str("the token has expired"),
+            Self::InvalidToken => f.write_str("the token is not valid for this report"),
+            Self::DoubleSpend => f.write_str("the token has already been used"),
+        }
+    }
+}
+
+// }}}
+
+// {{{ Wallet
+
+/// A batch request that is waiting for the response of the collector
+pub struct PendingRefill {
+    unsigned: NizkpUnsignedTokenBatched<[u8; METADATA_LEN], BATCH_SIZE>,
+    randomization: [u8; 32],
+    request: RandomizedUnsignedTokenBatched<[u8; METADATA_LEN], BATCH_SIZE>,
+}
+
+impl PendingRefill {
+    /// The request to send to the collector
+    pub fn request(&self) -> &RandomizedUnsignedTokenBatched<[u8; METADATA_LEN], BATCH_SIZE> {
+        &self.request
+    }
+}
+
+/// The tokens of a client
+pub struct TelemetryWallet {
+    public_key: PublicKey,
+    tokens: Vec<NizkpSignedToken<[u8; METADATA_LEN]>>,
+}
+
+impl TelemetryWallet {
+    pub fn new(public_key: PublicKey) -> Self {
+        Self {
+            public_key,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// Create a request for a new batch of tokens
+    pub fn request(metadata: TelemetryMetadata) -> PendingRefill {
+        let unsigned = NizkpUnsignedTokenBatched::new(metadata.to_bytes());
+        let (randomization, request) = TelemetryEngine::randomize(&unsigned);
+
+        PendingRefill {
+            unsigned,
+            randomization,
+            request,
+        }
+    }
+
+    /// Add the tokens of a batch to the wallet, if the response is valid
+    pub fn add(
+        &mut self,
+        pending: PendingRefill,
+        response: RandomizedSignedTokenBatched<[u8; METADATA_LEN], BATCH_SIZE>,
+    ) -> bool {
+        match TelemetryEngine::verify_signature_and_unrandomize(
+            pending.unsigned,
+            pending.request,
+            response,
+            &self.public_key,
+            pending.randomization,
+        ) {
+            Ok(signed) => {
+                self.tokens.extend(signed.into_tokens());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The number of unused tokens with the given metadata
+    pub fn remaining(&self, metadata: TelemetryMetadata) -> usize {
+        let metadata = metadata.to_bytes();
+        self.tokens
+            .iter()
+            .filter(|token| *token.metadata() == metadata)
+            .count()
+    }
+
+    /// Spend a token on a report
+    pub fn report(
+        &mut self,
+        metadata: TelemetryMetadata,
+        report: impl AsRef<[u8]>,
+    ) -> Option<TelemetryReport> {
+        let metadata = metadata.to_bytes();
+        let index = self
+            .tokens
+            .iter()
+            .position(|token| *token.metadata() == metadata)?;
+
+        // a token is never used twice
+        let token = self.tokens.swap_remove(index);
+
+        Some(TelemetryReport {
+            redemption: token.bind(&report),
+            report: Box::from(report.as_ref()),
+        })
+    }
+
+    /// Remove the tokens the collector will no longer accept
+    pub fn prune(&mut self, today: u32) {
+        self.tokens.retain(|token| {
+            TelemetryMetadata::from_bytes(token.metadata())
+                .is_some_and(|metadata| metadata.is_fresh(today))
+        });
+    }
+}
+
+// }}}
+
+// {{{ Collector
+
+/// Issues tokens, and accepts reports
+pub struct TelemetryCollector {
+    private_key: PrivateKey,
+    spent: BTreeSet<(u32, [u8; 16])>,
+}
+
+impl TelemetryCollector {
+    pub fn new(private_key: PrivateKey) -> Self {
+        Self {
+            private_key,
+            spent: BTreeSet::new(),
+        }
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    /// Sign a batch of tokens, if it is for today
+    ///
+    /// The caller should limit the number of batches per client.
+    pub fn issue(
+        &self,
+        request: &RandomizedUnsignedTokenBatched<[u8; METADATA_LEN], BATCH_SIZE>,
+        today: u32,
+    ) -> Option<RandomizedSignedTokenBatched<[u8; METADATA_LEN], BATCH_SIZE>> {
+        let metadata = TelemetryMetadata::from_bytes(&request.metadata())?;
+        if metadata.day != today {
+            return None;
+        }
+
+        TelemetryEngine::sign_randomized(request, &self.private_key).ok()
+    }
+
+    /// Accept a report, and return the metadata of the token it was paid with
+    pub fn redeem(
+        &mut self,
+        report: &TelemetryReport,
+        today: u32,
+    ) -> Result<TelemetryMetadata, TelemetryError> {
+        let redemption = &report.redemption;
+        let metadata = TelemetryMetadata::from_bytes(&redemption.metadata)
+            .ok_or(TelemetryError::InvalidToken)?;
+
+        if !metadata.is_fresh(today) {
+            return Err(TelemetryError::Expired);
+        }
+
+        if !redemption.verify(&self.private_key, &report.report) {
+            return Err(TelemetryError::InvalidToken);
+        }
+
+        if !self.spent.insert((metadata.day, redemption.id)) {
+            return Err(TelemetryError::DoubleSpend);
+        }
+
+        Ok(metadata)
+    }
+
+    /// Forget the spent tokens that have expired, they will be rejected anyway
+    pub fn prune(&mut self, today: u32) {
+        let oldest = today.saturating_sub(MAX_AGE_DAYS);
+        self.spent = self.spent.split_off(&(oldest, [0; 16]));
+    }
+}
+
+// }}}
+
+// {{{ tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(metadata: TelemetryMetadata) -> (TelemetryCollector, TelemetryWallet) {
+        let collector = TelemetryCollector::new(PrivateKey::new());
+        let mut wallet = TelemetryWallet::new(PublicKey::from(collector.private_key()));
+
+        let pending = TelemetryWallet::request(metadata);
+        let response = collector.issue(pending.request(), metadata.day).unwrap();
+        assert!(wallet.add(pending, response));
+
+        (collector, wallet)
+    }
+
+    #[test]
+    fn test_metadata_bytes() {
+        let metadata = TelemetryMetadata {
+            report_type: 513,
+            day: 18779,
+        };
+
+        assert_eq!(
+            TelemetryMetadata::from_bytes(&metadata.to_bytes()),
+            Some(metadata)
+        );
+        assert_eq!(TelemetryMetadata::from_bytes(&[0; 5]), None);
+    }
+
+    #[test]
+    fn test_reports() {
+        let metadata = TelemetryMetadata {
+            report_type: 1,
+            day: 100,
+        };
+        let (mut collector, mut wallet) = setup(metadata);
+        assert_eq!(wallet.remaining(metadata), BATCH_SIZE);
+
+        // no tokens for another report type
+        let other = TelemetryMetadata {
+            report_type: 2,
+            ..metadata
+        };
+        assert!(wallet.report(other, b"report").is_none());
+
+        let report = wallet.report(metadata, b"report").unwrap();
+        assert_eq!(wallet.remaining(metadata), BATCH_SIZE - 1);
+
+        // the token is bound to the report
+        let mut forged = report.clone();
+        forged.report = Box::from(&b"another report"[..]);
+        assert_eq!(
+            collector.redeem(&forged, 100),
+            Err(TelemetryError::InvalidToken)
+        );
+
+        assert_eq!(collector.redeem(&report, 101), Ok(metadata));
+        assert_eq!(
+            collector.redeem(&report, 101),
+            Err(TelemetryError::DoubleSpend)
+        );
+
+        // too old
+        let report = wallet.report(metadata, b"report").unwrap();
+        assert_eq!(collector.redeem(&report, 102), Err(TelemetryError::Expired));
+
+        wallet.prune(102);
+        assert_eq!(wallet.remaining(metadata), 0);
+    }
+
+    #[test]
+    fn fail_issue_other_day() {
+        let collector = TelemetryCollector::new(PrivateKey::new());
+        let pending = TelemetryWallet::request(TelemetryMetadata {
+            report_type: 1,
+            day: 99,
+        });
+
+        assert!(collector.issue(pending.request(), 100).is_none());
+    }
+
+    #[test]
+    fn test_prune_spent() {
+        let metadata = TelemetryMetadata {
+            report_type: 1,
+            day: 100,
+        };
+        let (mut collector, mut wallet) = setup(metadata);
+
+        let report = wallet.report(metadata, b"report").unwrap();
+        assert!(collector.redeem(&report, 100).is_ok());
+
+        collector.prune(101);
+        assert_eq!(collector.spent.len(), 1);
+        collector.prune(102);
+        assert!(collector.spent.is_empty());
+    }
+}
+
+// }}}
@@ -0,0 +1,315 @@
+//! # Comparing the engines
+//!
+//! The pairing and the NIZKP engines trade the cost of issuance and verification, the size of the
+//! tokens and keys, and who may verify the tokens. [`compare`] measures the engines for a
+//! deployment, i.e. the length of the metadata and the size of the batches, on the machine it runs
+//! on, so an integrator may pick an engine in code instead of reading the output of the benches.
+//!
+//! The crate has no clock, so the caller passes one which returns nanoseconds.
+//!
+//! ```
+//!     use std::time::Instant;
+//!     use atpmd::profile::{compare, Engine, Verifiability};
+//!
+//!     let start = Instant::now();
+//!     let estimates = compare(&[Engine::Pairing, Engine::Curve25519], 16, 4, || {
+//!         start.elapsed().as_nanos() as u64
+//!     });
+//!
+//!     // pick the cheapest engine where the verifier does not have the signing key
+//!     let public = estimates
+//!         .iter()
+//!         .filter(|estimate| estimate.verifiability == Verifiability::Public)
+//!         .min_by_key(|estimate| estimate.verification_nanos)
+//!         .unwrap();
+//!     assert_eq!(public.engine, Engine::Pairing);
+//! ```
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+use crate::wire::WireFormat;
+use crate::{SignedToken, TokenEngine};
+
+/// How many times the measurements are repeated, the fastest round is used
+const ROUNDS: usize = 3;
+
+/// An engine that may be compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// [`crate::atpm_pairing`]
+    #[cfg(feature = "pairing")]
+    Pairing,
+    /// [`crate::nizkp_curve25519`]
+    #[cfg(feature = "curve25519")]
+    Curve25519,
+}
+
+impl Engine {
+    /// The engines of this build
+    pub fn all() -> Vec<Self> {
+        vec![
+            #[cfg(feature = "pairing")]
+            Self::Pairing,
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519,
+        ]
+    }
+
+    /// Who may verify the tokens of the engine
+    pub fn verifiability(self) -> Verifiability {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing => Verifiability::Public,
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519 => Verifiability::PrivateKey,
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing => "pairing",
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519 => "curve25519",
+        })
+    }
+}
+
+/// Who may verify a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verifiability {
+    /// Anyone with the public key
+    Public,
+    /// Only the holders of the private key, i.e. the signer or a verifier it trusts with the key
+    PrivateKey,
+}
+
+/// The cost of an engine for a deployment
+///
+/// The times are in the nanoseconds of the clock given to [`compare`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Estimate {
+    pub engine: Engine,
+    pub metadata_len: usize,
+    pub batch_size: usize,
+    /// The signer signing a batch, with the proof
+    pub issuance_nanos: u64,
+    /// The client generating, randomizing and unrandomizing a batch, and checking the proof
+    pub client_nanos: u64,
+    /// Verifying one token of the batch
+    pub verification_nanos: u64,
+    /// The bytes of one signed token in the wire format, or `None` if the metadata is longer than
+    /// the wire format allows
+    pub token_size: Option<usize>,
+    /// The bytes of the public key in the wire format
+    pub key_size: usize,
+    pub verifiability: Verifiability,
+}
+
+/// Measure the engines for metadata of `metadata_len` bytes and batches of `batch_size` tokens
+///
+/// `clock` returns a time in nanoseconds which does not go backwards, e.g. from
+/// `std::time::Instant`. Every engine is measured a few times and the fastest round is used.
+/// A batch size of 0 is measured as 1.
+pub fn compare(
+    engines: &[Engine],
+    metadata_len: usize,
+    batch_size: usize,
+    mut clock: impl FnMut() -> u64,
+) -> Vec<Estimate> {
+    let batch_size = batch_size.max(1);
+
+    engines
+        .iter()
+        .map(|&engine| {
+            let measurement = measure_engine(engine, metadata_len, batch_size, &mut clock);
+
+            Estimate {
+                engine,
+                metadata_len,
+                batch_size,
+                issuance_nanos: measurement.issuance,
+                client_nanos: measurement.client,
+                verification_nanos: measurement.verification,
+                token_size: measurement.token_size,
+                key_size: measurement.key_size,
+                verifiability: engine.verifiability(),
+            }
+        })
+        .collect()
+}
+
+/// The fastest times of the rounds, and the sizes
+struct Measurement {
+    issuance: u64,
+    client: u64,
+    verification: u64,
+    token_size: Option<usize>,
+    key_size: usize,
+}
+
+fn measure_engine(
+    engine: Engine,
+    metadata_len: usize,
+    batch_size: usize,
+    clock: &mut dyn FnMut() -> u64,
+) -> Measurement {
+    let metadata = vec![0x2a; metadata_len];
+
+    match engine {
+        #[cfg(feature = "pairing")]
+        Engine::Pairing => {
+            use crate::atpm_pairing::keys::{PrivateKey, PublicKey};
+            use crate::atpm_pairing::tokens_batched_dyn::DynBatchedPairingTokenEngine;
+
+            let private_key = PrivateKey::new();
+            let public_key = PublicKey::from(&private_key);
+
+            measure::<DynBatchedPairingTokenEngine<Vec<u8>>, _>(
+                (metadata, batch_size),
+                &private_key,
+                &public_key,
+                &public_key,
+                |batch| batch.iter().collect(),
+                public_key.to_bytes().len(),
+                clock,
+            )
+        }
+        #[cfg(feature = "curve25519")]
+        Engine::Curve25519 => {
+            use crate::nizkp_curve25519::keys::{PrivateKey, PublicKey};
+            use crate::nizkp_curve25519::tokens_batched_dyn::DynBatchedNizkpTokenEngine;
+
+            let private_key = PrivateKey::new();
+            let public_key = PublicKey::from(&private_key);
+
+            measure::<DynBatchedNizkpTokenEngine<Vec<u8>>, _>(
+                (metadata, batch_size),
+                &private_key,
+                &public_key,
+                &private_key,
+                |batch| batch.into_tokens(),
+                public_key.to_bytes().len(),
+                clock,
+            )
+        }
+    }
+}
+
+/// Issue a batch with the engine, and verify the single tokens `T` of the batch
+fn measure<E, T>(
+    metadata: <E::UnsignedToken as crate::UnsignedToken>::Metadata,
+    sign_key: &E::SignKey,
+    user_verification: &E::UserVerification,
+    verification_key: &T::VerificationKey,
+    split: impl Fn(E::SignedToken) -> Vec<T>,
+    key_size: usize,
+    clock: &mut dyn FnMut() -> u64,
+) -> Measurement
+where
+    E: TokenEngine,
+    <E::UnsignedToken as crate::UnsignedToken>::Metadata: Clone,
+    T: SignedToken + WireFormat,
+{
+    let mut fastest = Measurement {
+        issuance: u64::MAX,
+        client: u64::MAX,
+        verification: u64::MAX,
+        token_size: None,
+        key_size,
+    };
+
+    for _ in 0..ROUNDS {
+        let start = clock();
+        let unsigned = E::generate(metadata.clone());
+        let (randomization, randomized) = E::randomize(&unsigned);
+        let randomized_done = clock();
+
+        let signed = E::sign_randomized(&randomized, sign_key).expect("the engine signs");
+        let signed_done = clock();
+
+        let batch = E::verify_signature_and_unrandomize(
+            unsigned,
+            randomized,
+            signed,
+            user_verification,
+            randomization,
+        )
+        .expect("the signature verifies");
+        let unrandomized_done = clock();
+
+        let tokens = split(batch);
+        let verify_start = clock();
+        let verified = tokens
+            .iter()
+            .filter(|token| token.verify(verification_key))
+            .count();
+        let verified_done = clock();
+        assert_eq!(verified, tokens.len(), "the tokens verify");
+
+        let client = (randomized_done - start) + (unrandomized_done - signed_done);
+        fastest.issuance = fastest.issuance.min(signed_done - randomized_done);
+        fastest.client = fastest.client.min(client);
+        fastest.verification = fastest
+            .verification
+            .min((verified_done - verify_start) / tokens.len().max(1) as u64);
+        fastest.token_size = tokens
+            .first()
+            .and_then(|token| token.try_to_bytes().ok())
+            .map(|bytes| bytes.len());
+    }
+
+    fastest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock that ticks once every time it is read
+    fn ticks() -> impl FnMut() -> u64 {
+        let mut now = 0;
+        move || {
+            now += 1;
+            now
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let estimates = compare(&Engine::all(), 10, 3, ticks());
+        assert_eq!(estimates.len(), Engine::all().len());
+
+        for estimate in &estimates {
+            assert_eq!((estimate.metadata_len, estimate.batch_size), (10, 3));
+            // every phase reads the clock once before and once after
+            assert_eq!(estimate.issuance_nanos, 1);
+            assert_eq!(estimate.client_nanos, 2);
+            assert_eq!(estimate.verification_nanos, 0);
+            assert!(estimate.key_size > 0);
+            assert!(estimate.token_size.unwrap() > 10);
+            assert_eq!(estimate.verifiability, estimate.engine.verifiability());
+        }
+    }
+
+    #[cfg(all(feature = "pairing", feature = "curve25519"))]
+    #[test]
+    fn test_sizes() {
+        let estimates = compare(&[Engine::Pairing, Engine::Curve25519], 0, 0, ticks());
+        let (pairing, nizkp) = (&estimates[0], &estimates[1]);
+
+        assert_eq!(pairing.batch_size, 1);
+        assert_eq!(pairing.verifiability, Verifiability::Public);
+        assert_eq!(nizkp.verifiability, Verifiability::PrivateKey);
+
+        // a G2 key is larger than a ristretto point
+        assert!(pairing.key_size > nizkp.key_size);
+        assert!(pairing.token_size.is_some() && nizkp.token_size.is_some());
+
+        let estimates = compare(&[Engine::Curve25519], 70000, 1, ticks());
+        assert_eq!(estimates[0].token_size, None);
+    }
+}
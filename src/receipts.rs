@@ -0,0 +1,426 @@
+//! # Encrypted receipts of redeemed tokens
+//!
+//! An app may show the user where their tokens were spent, without keeping a plain usage history
+//! on the device. A [`Receipt`] of every redeemed token is encrypted under a key that only the
+//! user has, and a [`ReceiptLog`] of the encrypted receipts may be backed up anywhere.
+//! Neither the issuer nor the verifiers ever see the receipts.
+//!
+//! Every encrypted receipt is [`ENCRYPTED_LEN`] bytes, so the log only tells how many tokens were
+//! spent, not what they were spent on.
+//!
+//! A receipt is encrypted with XChaCha20-Poly1305, with a random nonce in front of it. The nonce
+//! is long enough to be random for all the receipts of a key.
+//!
+//! ```
+//!     use atpmd::receipts::{Receipt, ReceiptKey, ReceiptLog};
+//!
+//!     let key = ReceiptKey::new();
+//!     let mut log = ReceiptLog::new();
+//!
+//!     // after spending a token
+//!     log.record(&key, &Receipt::new("search", 1_600_000_000, "origin.example")).unwrap();
+//!
+//!     // the log may be stored by the app, the history is only readable with the key
+//!     let stored = serde_json::to_string(&log).unwrap();
+//!     let log: ReceiptLog = serde_json::from_str(&stored).unwrap();
+//!
+//!     let history = log.history(&key);
+//!     assert_eq!(history[0].verifier, "origin.example");
+//!     assert!(log.history(&ReceiptKey::new()).is_empty());
+//! ```
+
+use alloc::{string::String, vec::Vec};
+use core::{convert::TryInto, fmt};
+
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use rand::{CryptoRng, RngCore};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+/// The longest metadata bucket and verifier identifier, in bytes
+pub const LABEL_LEN: usize = 16;
+
+/// The length of an encoded receipt, the two labels and the time
+const RECEIPT_LEN: usize = 2 * LABEL_LEN + 8;
+
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// The associated data of the receipts, so they are not taken for other ciphertexts of the key
+const RECEIPT_AD: &[u8] = b"atpmd receipt";
+
+/// The length of every encrypted receipt
+pub const ENCRYPTED_LEN: usize = NONCE_LEN + RECEIPT_LEN + TAG_LEN;
+
+/// The reason a receipt could not be encrypted or decrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptError {
+    /// The bucket or the verifier is longer than [`LABEL_LEN`] bytes, or ends with a zero byte
+    Label,
+    /// The receipt was not encrypted with this key, or has been changed
+    Decryption,
+}
+
+impl fmt::Display for ReceiptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Label => write!(f, "labels may have at most {} bytes", LABEL_LEN),
+            Self::Decryption => f.write_str("the receipt could not be decrypted"),
+        }
+    }
+}
+
+/// What the user sees of a redeemed token
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    /// A coarse description of the metadata, e.g. the kind of resource
+    pub bucket: String,
+    /// When the token was redeemed, e.g. in seconds since the unix epoch
+    pub time: u64,
+    /// The verifier the token was redeemed at
+    pub verifier: String,
+}
+
+impl Receipt {
+    pub fn new(bucket: impl Into<String>, time: u64, verifier: impl Into<String>) -> Self {
+        Self {
+            bucket: bucket.into(),
+            time,
+            verifier: verifier.into(),
+        }
+    }
+
+    fn encode(&self) -> Result<[u8; RECEIPT_LEN], ReceiptError> {
+        let mut bytes = [0; RECEIPT_LEN];
+        let (bucket, rest) = bytes.split_at_mut(LABEL_LEN);
+        let (verifier, time) = rest.split_at_mut(LABEL_LEN);
+
+        pad(bucket, &self.bucket)?;
+        pad(verifier, &self.verifier)?;
+        time.copy_from_slice(&self.time.to_be_bytes());
+
+        Ok(bytes)
+    }
+
+    fn decode(bytes: &[u8; RECEIPT_LEN]) -> Result<Self, ReceiptError> {
+        let (bucket, rest) = bytes.split_at(LABEL_LEN);
+        let (verifier, rest) = rest.split_at(LABEL_LEN);
+        let mut time = [0; 8];
+        time.copy_from_slice(rest);
+
+        Ok(Self {
+            bucket: unpad(bucket)?,
+            time: u64::from_be_bytes(time),
+            verifier: unpad(verifier)?,
+        })
+    }
+}
+
+/// Write a label padded with zeros, the padding is the only trailing zeros
+fn pad(out: &mut [u8], label: &str) -> Result<(), ReceiptError> {
+    let label = label.as_bytes();
+    if label.len() > out.len() || label.last() == Some(&0) {
+        return Err(ReceiptError::Label);
+    }
+
+    out[..label.len()].copy_from_slice(label);
+    Ok(())
+}
+
+fn unpad(padded: &[u8]) -> Result<String, ReceiptError> {
+    let len = padded.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    core::str::from_utf8(&padded[..len])
+        .map(String::from)
+        .map_err(|_e| ReceiptError::Decryption)
+}
+
+// {{{ Key
+
+/// The key of the receipts, it stays with the user
+///
+/// The key is zeroized when it is dropped
+#[derive(Clone)]
+pub struct ReceiptKey([u8; 32]);
+
+impl ReceiptKey {
+    pub fn new() -> Self {
+        Self::new_with_rng(&mut crate::rng::default_rng())
+    }
+
+    pub fn new_with_rng<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        let mut key = [0; 32];
+        rng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    /// Load a key that was exported with [`ReceiptKey::to_bytes`]
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+
+    /// Encrypt a receipt
+    pub fn seal(&self, receipt: &Receipt) -> Result<EncryptedReceipt, ReceiptError> {
+        self.seal_with_rng(receipt, &mut crate::rng::default_rng())
+    }
+
+    pub fn seal_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        receipt: &Receipt,
+        rng: &mut R,
+    ) -> Result<EncryptedReceipt, ReceiptError> {
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        // the receipt is encrypted in place, and the cipher only refuses messages of more than
+        // 256 GiB
+        let mut receipt = receipt.encode()?;
+        let sealed = self
+            .cipher()
+            .encrypt_in_place_detached(&XNonce::from(nonce), RECEIPT_AD, &mut receipt)
+            .map_err(|_e| ReceiptError::Decryption)?;
+
+        let mut bytes = [0; ENCRYPTED_LEN];
+        let (prefix, rest) = bytes.split_at_mut(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at_mut(RECEIPT_LEN);
+        prefix.copy_from_slice(&nonce);
+        ciphertext.copy_from_slice(&receipt);
+        tag.copy_from_slice(&sealed);
+
+        Ok(EncryptedReceipt(bytes))
+    }
+
+    /// Decrypt a receipt, this fails if it was encrypted with another key
+    pub fn open(&self, encrypted: &EncryptedReceipt) -> Result<Receipt, ReceiptError> {
+        let (nonce, rest) = encrypted.0.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(RECEIPT_LEN);
+
+        let mut nonce_bytes = [0; NONCE_LEN];
+        nonce_bytes.copy_from_slice(nonce);
+        let mut tag_bytes = [0; TAG_LEN];
+        tag_bytes.copy_from_slice(tag);
+        let mut receipt = [0; RECEIPT_LEN];
+        receipt.copy_from_slice(ciphertext);
+
+        let decoded = self
+            .cipher()
+            .decrypt_in_place_detached(
+                &XNonce::from(nonce_bytes),
+                RECEIPT_AD,
+                &mut receipt,
+                &Tag::from(tag_bytes),
+            )
+            .map_err(|_e| ReceiptError::Decryption)
+            .and_then(|()| Receipt::decode(&receipt));
+        receipt.zeroize();
+
+        decoded
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        // the cipher zeroizes its copy of the key when it is dropped
+        let mut key = Key::from(self.0);
+        let cipher = XChaCha20Poly1305::new(&key);
+        key[..].zeroize();
+        cipher
+    }
+}
+
+impl Default for ReceiptKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Zeroize for ReceiptKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for ReceiptKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+// }}}
+
+// {{{ Encrypted receipt
+
+/// An encrypted receipt, serialized as base64url without padding
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptedReceipt([u8; ENCRYPTED_LEN]);
+
+impl EncryptedReceipt {
+    pub fn from_bytes(bytes: [u8; ENCRYPTED_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; ENCRYPTED_LEN] {
+        self.0
+    }
+}
+
+impl fmt::Debug for EncryptedReceipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptedReceipt")
+    }
+}
+
+impl Serialize for EncryptedReceipt {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode_config(self.0, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+impl<'de> Deserialize<'de> for EncryptedReceipt {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .map_err(|_e| de::Error::custom("receipt is not valid base64url"))?;
+
+        bytes
+            .try_into()
+            .map(Self)
+            .map_err(|_e| de::Error::custom("receipt has the wrong length"))
+    }
+}
+
+// }}}
+
+// {{{ Log
+
+/// The encrypted receipts, in the order the tokens were redeemed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct ReceiptLog {
+    receipts: Vec<EncryptedReceipt>,
+}
+
+impl ReceiptLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encrypt and add the receipt of a redeemed token
+    pub fn record(&mut self, key: &ReceiptKey, receipt: &Receipt) -> Result<(), ReceiptError> {
+        self.receipts.push(key.seal(receipt)?);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.receipts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receipts.is_empty()
+    }
+
+    /// The receipts that decrypt with the key, the others are skipped
+    pub fn history(&self, key: &ReceiptKey) -> Vec<Receipt> {
+        self.receipts
+            .iter()
+            .filter_map(|receipt| key.open(receipt).ok())
+            .collect()
+    }
+
+    /// The history as JSON, for the user to take with them
+    #[cfg(feature = "json")]
+    pub fn export(&self, key: &ReceiptKey) -> String {
+        // serializing receipts can not fail
+        serde_json::to_string(&self.history(key)).unwrap()
+    }
+
+    /// Forget the receipts from before `time`
+    pub fn prune(&mut self, key: &ReceiptKey, time: u64) {
+        self.receipts.retain(|receipt| {
+            key.open(receipt)
+                .map_or(true, |receipt| receipt.time >= time)
+        });
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let key = ReceiptKey::new();
+        let receipt = Receipt::new("search", 1_600_000_000, "origin.example");
+
+        let short = key.seal(&Receipt::new("", 0, "")).unwrap();
+        let long = key
+            .seal(&Receipt::new("a".repeat(16), 1, "ø".repeat(8)))
+            .unwrap();
+        assert_eq!(short.to_bytes().len(), long.to_bytes().len());
+
+        let encrypted = key.seal(&receipt).unwrap();
+        assert_eq!(key.open(&encrypted), Ok(receipt.clone()));
+        assert_ne!(key.seal(&receipt).unwrap(), encrypted);
+
+        let json = serde_json::to_string(&encrypted).unwrap();
+        let decoded: EncryptedReceipt = serde_json::from_str(&json).unwrap();
+        assert_eq!(key.open(&decoded), Ok(receipt));
+
+        let key = ReceiptKey::from_bytes(key.to_bytes());
+        assert!(key.open(&decoded).is_ok());
+    }
+
+    #[test]
+    fn test_log() {
+        let key = ReceiptKey::new();
+        let mut log = ReceiptLog::new();
+        log.record(&key, &Receipt::new("a", 10, "first")).unwrap();
+        log.record(&key, &Receipt::new("b", 20, "second")).unwrap();
+
+        #[cfg(feature = "json")]
+        assert_eq!(
+            log.export(&key),
+            r#"[{"bucket":"a","time":10,"verifier":"first"},{"bucket":"b","time":20,"verifier":"second"}]"#
+        );
+
+        log.prune(&key, 15);
+        assert_eq!(log.len(), 1);
+        assert_eq!(log.history(&key)[0].bucket, "b");
+    }
+
+    #[test]
+    fn fail_bad_receipts() {
+        let key = ReceiptKey::new();
+
+        assert_eq!(
+            key.seal(&Receipt::new("a".repeat(17), 0, "")),
+            Err(ReceiptError::Label)
+        );
+        assert_eq!(
+            key.seal(&Receipt::new("a\0", 0, "")),
+            Err(ReceiptError::Label)
+        );
+
+        let encrypted = key.seal(&Receipt::new("a", 0, "b")).unwrap();
+        assert_eq!(
+            ReceiptKey::new().open(&encrypted),
+            Err(ReceiptError::Decryption)
+        );
+
+        // a changed nonce, ciphertext or tag
+        for i in [0, NONCE_LEN, ENCRYPTED_LEN - 1] {
+            let mut bytes = encrypted.to_bytes();
+            bytes[i] ^= 1;
+            assert_eq!(
+                key.open(&EncryptedReceipt::from_bytes(bytes)),
+                Err(ReceiptError::Decryption)
+            );
+        }
+
+        assert!(serde_json::from_str::<EncryptedReceipt>(r#""AAAA""#).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+//! # One-time use of tokens
+//!
+//! A verifier must remember the tokens it has accepted, or a token could be used again.
+//! The tokens are remembered in a [`RedemptionStore`] by their [`Fingerprint`], which is made from
+//! the parts of the token that are covered by the signature, so a token can not get a new
+//! fingerprint by changing e.g. its key identifier.
+//!
+//! The store only needs to remember a token until it expires, see [`expiry`](crate::expiry), and
+//! [`RedemptionStore::prune`] forgets the tokens that have.
+//! Tokens without an expiration time may be redeemed with [`redeem_without_expiry`], but they are
+//! never forgotten.
+//!
+//! A database that indexes the redeemed tokens itself uses their [`Nullifier`] as the key, which
+//! also covers the signature, so only the holder of a validly signed token has it.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::expiry::Metadata;
+//!     use atpmd::redemption::{MemoryRedemptionStore, RedeemError, RedemptionStore};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let mut store = MemoryRedemptionStore::new();
+//!
+//!     let now = 1_600_000_000;
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Metadata::new(now + 3600, b"resource")),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     assert_eq!(PairingTokenEngine::redeem(&signed, &public_key, &mut store, now), Ok(()));
+//!     assert_eq!(
+//!         PairingTokenEngine::redeem(&signed, &public_key, &mut store, now),
+//!         Err(RedeemError::DoubleSpend)
+//!     );
+//!
+//!     // once the token has expired, it does not need to be remembered
+//!     store.prune(now + 3600);
+//!     assert!(store.is_empty());
+//! ```
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::fmt;
+
+use sha2::{Digest, Sha512};
+
+use crate::common::{SignedToken, TokenIdentifier};
+use crate::expiry;
+
+/// The canonical fingerprint of a token
+///
+/// This is the hash of the token identifier and the metadata, so it is the same for all encodings
+/// of a token.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    pub(crate) fn of_token<M: AsRef<[u8]>>(id: &TokenIdentifier<M>, metadata: &[u8]) -> Self {
+        let t: [u8; 16] = id.into();
+
+        let mut hasher = Sha512::new();
+
+        // Domain separation of random oracles
+        hasher.update(b"Domain of token fingerprints");
+        hasher.update(t);
+        hasher.update(metadata);
+
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(&hasher.finalize()[..32]);
+        Self(fingerprint)
+    }
+
+    /// The fingerprint of a token without an identifier, by its nonce
+    #[cfg(feature = "curve25519")]
+    pub(crate) fn of_nonce(nonce: &[u8; 32], metadata: &[u8]) -> Self {
+        let mut hasher = Sha512::new();
+
+        // Domain separation of random oracles
+        hasher.update(b"Domain of nonce token fingerprints");
+        hasher.update(nonce);
+        hasher.update(metadata);
+
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(&hasher.finalize()[..32]);
+        Self(fingerprint)
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+/// The fingerprint in hex, e.g. for the logs
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// The nullifier of a signed token, to index the redeemed tokens by
+///
+/// This is the hash of the token identifier, the signature and the metadata. The signature is
+/// determined by the key, the identifier and the metadata, so a validly signed token has one
+/// nullifier under a key, in all encodings, and two tokens have the same nullifier with a
+/// probability of 2^-128 from their random identifiers. It can not be computed without the
+/// signature, so it is only unforgeable for tokens that have been verified.
+///
+/// The same token signed by two keys has two nullifiers, a store that accepts several keys, see
+/// [`KeyRing`](crate::KeyRing), uses the [`Fingerprint`] instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Nullifier([u8; 32]);
+
+impl Nullifier {
+    pub(crate) fn of_token<M: AsRef<[u8]>>(
+        id: &TokenIdentifier<M>,
+        signature: &[u8],
+        metadata: &[u8],
+    ) -> Self {
+        let mut hasher = Sha512::new();
+
+        // Domain separation of random oracles
+        hasher.update(b"Domain of token nullifiers");
+        hasher.update(id.as_bytes());
+        // the signature has the same length for all tokens of an engine
+        hasher.update(signature);
+        hasher.update(metadata);
+
+        let mut nullifier = [0u8; 32];
+        nullifier.copy_from_slice(&hasher.finalize()[..32]);
+        Self(nullifier)
+    }
+
+    /// The nullifier of a token without an identifier, by its nonce
+    #[cfg(feature = "curve25519")]
+    pub(crate) fn of_nonce(nonce: &[u8; 32], signature: &[u8], metadata: &[u8]) -> Self {
+        let mut hasher = Sha512::new();
+
+        // Domain separation of random oracles
+        hasher.update(b"Domain of nonce token nullifiers");
+        hasher.update(nonce);
+        hasher.update(signature);
+        hasher.update(metadata);
+
+        let mut nullifier = [0u8; 32];
+        nullifier.copy_from_slice(&hasher.finalize()[..32]);
+        Self(nullifier)
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+/// The nullifier in hex, e.g. for the logs
+impl fmt::Display for Nullifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+/// A signed token that can be redeemed once
+///
+/// The batched tokens are split into single tokens before they are redeemed.
+pub trait Redeemable: SignedToken {
+    fn fingerprint(&self) -> Fingerprint;
+
+    /// The nullifier of the token, see [`Nullifier`]
+    fn nullifier(&self) -> Nullifier;
+}
+
+// {{{ Store
+
+/// Where the verifier keeps the redeemed tokens
+///
+/// This may be shared between the instances of a verifier, e.g. Redis where `insert` is
+/// `SET fingerprint 1 NX EXAT expires_at`, and `prune` is left to Redis.
+pub trait RedemptionStore {
+    /// Mark the token as redeemed, returns false if it already was
+    ///
+    /// The token may be forgotten at `expires_at`, in seconds since the unix epoch.
+    fn insert(&mut self, fingerprint: Fingerprint, expires_at: u64) -> bool;
+
+    /// Forget the tokens that have expired at `now`, they will be rejected anyway
+    fn prune(&mut self, now: u64);
+}
+
+/// A redemption store in memory, for a single instance of the verifier
+#[derive(Debug, Clone, Default)]
+pub struct MemoryRedemptionStore {
+    redeemed: BTreeSet<Fingerprint>,
+    by_expiry: BTreeMap<u64, BTreeSet<Fingerprint>>,
+}
+
+impl MemoryRedemptionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, fingerprint: &Fingerprint) -> bool {
+        self.redeemed.contains(fingerprint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.redeemed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.redeemed.is_empty()
+    }
+}
+
+impl RedemptionStore for MemoryRedemptionStore {
+    fn insert(&mut self, fingerprint: Fingerprint, expires_at: u64) -> bool {
+        if !self.redeemed.insert(fingerprint) {
+            return false;
+        }
+
+        self.by_expiry
+            .entry(expires_at)
+            .or_default()
+            .insert(fingerprint);
+        true
+    }
+
+    fn prune(&mut self, now: u64) {
+        let live = match now.checked_add(1) {
+            Some(next) => self.by_expiry.split_off(&next),
+            // nothing expires at the end of time
+            None => return,
+        };
+
+        for fingerprint in core::mem::replace(&mut self.by_expiry, live)
+            .values()
+            .flatten()
+        {
+            self.redeemed.remove(fingerprint);
+        }
+    }
+}
+
+// }}}
+
+// {{{ Redeem
+
+/// Why a token could not be redeemed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedeemError {
+    /// The token has expired, or has no expiration time
+    Expired,
+    /// The signature of the token is not valid
+    Invalid,
+    /// The token has already been redeemed
+    DoubleSpend,
+}
+
+impl fmt::Display for RedeemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => f.write_str("the token has expired"),
+            Self::Invalid => f.write_str("the token is not validly signed"),
+            Self::DoubleSpend => f.write_str("the token has already been used"),
+        }
+    }
+}
+
+/// Verify a token that has not expired at `now`, and mark it as redeemed until it expires
+pub fn redeem<T: Redeemable, S: RedemptionStore>(
+    token: &T,
+    verification_key: &T::VerificationKey,
+    store: &mut S,
+    now: u64,
+) -> Result<(), RedeemError> {
+    let expires_at = expiry::expires_at(token.metadata_bytes())
+        .filter(|expires_at| now < *expires_at)
+        .ok_or(RedeemError::Expired)?;
+
+    if !token.verify(verification_key) {
+        return Err(RedeemError::Invalid);
+    }
+
+    if !store.insert(token.fingerprint(), expires_at) {
+        return Err(RedeemError::DoubleSpend);
+    }
+
+    Ok(())
+}
+
+/// Verify a token and mark it as redeemed, for tokens without an expiration time
+///
+/// The token is never pruned from the store.
+pub fn redeem_without_expiry<T: Redeemable, S: RedemptionStore>(
+    token: &T,
+    verification_key: &T::VerificationKey,
+    store: &mut S,
+) -> Result<(), RedeemError> {
+    if !token.verify(verification_key) {
+        return Err(RedeemError::Invalid);
+    }
+
+    if !store.insert(token.fingerprint(), u64::MAX) {
+        return Err(RedeemError::DoubleSpend);
+    }
+
+    Ok(())
+}
+
+// }}}
+
+#[cfg(all(test, feature = "curve25519"))]
+mod tests {
+    use super::*;
+
+    use crate::expiry::Metadata;
+    use crate::nizkp_curve25519::{
+        keys::{PrivateKey, PublicKey},
+        tokens::{NizkpSignedToken, NizkpTokenEngine},
+    };
+    use crate::wire::WireFormat;
+    use crate::TokenEngine;
+
+    fn token(private: &PrivateKey, metadata: Metadata) -> NizkpSignedToken<Metadata> {
+        NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(metadata),
+            &PublicKey::from(private),
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, private),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_redeem_once() {
+        let private = PrivateKey::new();
+        let mut store = MemoryRedemptionStore::new();
+
+        let first = token(&private, Metadata::new(100, b""));
+        let second = token(&private, Metadata::new(100, b""));
+        assert_ne!(first.fingerprint(), second.fingerprint());
+
+        assert_eq!(redeem(&first, &private, &mut store, 0), Ok(()));
+        assert_eq!(
+            redeem(&first, &private, &mut store, 0),
+            Err(RedeemError::DoubleSpend)
+        );
+        assert_eq!(redeem(&second, &private, &mut store, 0), Ok(()));
+
+        // another encoding of the same token
+        let decoded = NizkpSignedToken::<Metadata>::from_bytes(&first.to_bytes()).unwrap();
+        assert_eq!(decoded.fingerprint(), first.fingerprint());
+        assert_eq!(decoded.nullifier(), first.nullifier());
+        assert_ne!(first.nullifier(), second.nullifier());
+        assert_eq!(
+            NizkpTokenEngine::redeem(&decoded, &private, &mut store, 0),
+            Err(RedeemError::DoubleSpend)
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let private = PrivateKey::new();
+        let mut store = MemoryRedemptionStore::new();
+
+        let old = token(&private, Metadata::new(100, b""));
+        let new = token(&private, Metadata::new(200, b""));
+        let forever = token(&private, Metadata::new(0, b""));
+        assert_eq!(redeem(&old, &private, &mut store, 50), Ok(()));
+        assert_eq!(redeem(&new, &private, &mut store, 50), Ok(()));
+        assert_eq!(
+            redeem_without_expiry(&forever, &private, &mut store),
+            Ok(())
+        );
+
+        store.prune(99);
+        assert_eq!(store.len(), 3);
+
+        store.prune(100);
+        assert_eq!(store.len(), 2);
+        assert!(!store.contains(&old.fingerprint()));
+
+        // the pruned token is rejected as expired instead
+        assert_eq!(
+            redeem(&old, &private, &mut store, 100),
+            Err(RedeemError::Expired)
+        );
+
+        store.prune(u64::MAX - 1);
+        assert_eq!(store.len(), 1);
+        assert!(store.contains(&forever.fingerprint()));
+    }
+
+    #[test]
+    fn fail_invalid() {
+        let private = PrivateKey::new();
+        let mut store = MemoryRedemptionStore::new();
+
+        let signed = token(&private, Metadata::new(100, b""));
+        assert_eq!(
+            redeem(&signed, &PrivateKey::new(), &mut store, 0),
+            Err(RedeemError::Invalid)
+        );
+        assert!(store.is_empty());
+
+        let short = token(&private, Metadata::from(&b"short"[..]));
+        assert_eq!(
+            redeem(&short, &private, &mut store, 0),
+            Err(RedeemError::Expired)
+        );
+        assert!(store.is_empty());
+    }
+}
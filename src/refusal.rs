@@ -0,0 +1,180 @@
+//! # Refusals of the issuer
+//!
+//! An issuer that is overloaded, or that will not sign a token by its policy, answers with a
+//! signed [`Refusal`] instead of a signature. The client checks the refusal with the public key of
+//! the issuer, so it can tell throttling from an attacker in the middle or an incompatible issuer,
+//! and knows when to try again.
+//!
+//! The refusal covers the metadata of the request and the time it was made, and the signature is
+//! deterministic, so an overloaded issuer may answer every request for the same metadata in the
+//! same second with the same refusal.
+//!
+//! The signed refusals are in the backends, see
+//! [`PairingSignedRefusal`](crate::atpm_pairing::refusal::PairingSignedRefusal) and
+//! [`NizkpSignedRefusal`](crate::nizkp_curve25519::refusal::NizkpSignedRefusal), and the issuer
+//! sends them in a [`SignResponse`].
+
+#[cfg(any(feature = "pairing", feature = "curve25519"))]
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::common::Error;
+
+/// How old a refusal may be when the client gets it, in seconds
+///
+/// An older refusal may be replayed by an attacker, to keep the client from asking again.
+pub const MAX_REFUSAL_AGE: u64 = 300;
+
+/// Why the issuer did not sign
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefusalReason {
+    /// The issuer has too many requests, and sheds load
+    Overloaded,
+    /// The client has asked for too many tokens
+    RateLimited,
+    /// The issuer does not sign the metadata for this client
+    PolicyDenied,
+}
+
+impl RefusalReason {
+    #[cfg(any(feature = "pairing", feature = "curve25519"))]
+    fn code(self) -> u8 {
+        match self {
+            Self::Overloaded => 1,
+            Self::RateLimited => 2,
+            Self::PolicyDenied => 3,
+        }
+    }
+}
+
+impl fmt::Display for RefusalReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overloaded => f.write_str("the issuer is overloaded"),
+            Self::RateLimited => f.write_str("too many tokens were asked for"),
+            Self::PolicyDenied => f.write_str("the issuer does not sign the metadata"),
+        }
+    }
+}
+
+/// The statement of a refusal, before it is signed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Refusal {
+    pub reason: RefusalReason,
+    /// The number of seconds to wait before asking again, none if asking again will not help
+    pub retry_after: Option<u64>,
+    /// The time of the issuer when it refused
+    pub issued_at: u64,
+}
+
+impl Refusal {
+    pub fn new(reason: RefusalReason, retry_after: Option<u64>, issued_at: u64) -> Self {
+        Self {
+            reason,
+            retry_after,
+            issued_at,
+        }
+    }
+
+    /// The time to ask again at, none if asking again will not help
+    pub fn retry_at(&self) -> Option<u64> {
+        self.retry_after
+            .map(|retry_after| self.issued_at.saturating_add(retry_after))
+    }
+
+    /// Is the refusal recent at the time `now` of the client, allowing for some clock skew
+    pub fn is_fresh(&self, now: u64) -> bool {
+        self.issued_at <= now.saturating_add(MAX_REFUSAL_AGE)
+            && now.saturating_sub(self.issued_at) <= MAX_REFUSAL_AGE
+    }
+
+    /// The bytes the backends sign, for the metadata of the refused request
+    #[cfg(any(feature = "pairing", feature = "curve25519"))]
+    pub(crate) fn message(&self, metadata: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+
+        // domain of the signature, the issuer key also signs tokens
+        message.extend_from_slice(b"This is a refusal");
+        message.push(self.reason.code());
+        match self.retry_after {
+            Some(retry_after) => {
+                message.push(1);
+                message.extend_from_slice(&retry_after.to_be_bytes());
+            }
+            None => message.push(0),
+        }
+        message.extend_from_slice(&self.issued_at.to_be_bytes());
+        message.extend_from_slice(metadata);
+
+        message
+    }
+}
+
+/// A refusal signed by the issuer
+pub trait SignedRefusal {
+    type PublicKey;
+
+    /// The refusal, if it is signed with the key and is for the metadata
+    fn verify(&self, metadata: &[u8], public_key: &Self::PublicKey) -> Option<Refusal>;
+}
+
+/// The response of the sign endpoint of an issuer
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum SignResponse<T, R> {
+    Signed(T),
+    Refused(R),
+}
+
+impl<T, R: SignedRefusal> SignResponse<T, R> {
+    /// The signed token, or why the issuer refused
+    ///
+    /// A refusal that does not verify, or is stale at `now`, is [`Error::BadRefusal`], as it is
+    /// not from the issuer.
+    pub fn into_result(
+        self,
+        metadata: impl AsRef<[u8]>,
+        public_key: &R::PublicKey,
+        now: u64,
+    ) -> Result<T, Error> {
+        match self {
+            Self::Signed(signed) => Ok(signed),
+            Self::Refused(refusal) => match refusal.verify(metadata.as_ref(), public_key) {
+                Some(refusal) if refusal.is_fresh(now) => Err(Error::Refused(refusal)),
+                _ => Err(Error::BadRefusal),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let refusal = Refusal::new(RefusalReason::Overloaded, Some(30), 1000);
+        assert_eq!(refusal.retry_at(), Some(1030));
+
+        // every field is signed
+        let other = [
+            Refusal::new(RefusalReason::RateLimited, Some(30), 1000),
+            Refusal::new(RefusalReason::Overloaded, None, 1000),
+            Refusal::new(RefusalReason::Overloaded, Some(30), 1001),
+        ];
+        for other in &other {
+            assert_ne!(other.message(b"a"), refusal.message(b"a"));
+        }
+        assert_ne!(refusal.message(b"b"), refusal.message(b"a"));
+    }
+
+    #[test]
+    fn test_fresh() {
+        let refusal = Refusal::new(RefusalReason::PolicyDenied, None, 1000);
+        assert!(refusal.is_fresh(1000));
+        assert!(refusal.is_fresh(1000 + MAX_REFUSAL_AGE));
+        assert!(!refusal.is_fresh(1001 + MAX_REFUSAL_AGE));
+        assert!(refusal.is_fresh(1000 - MAX_REFUSAL_AGE));
+        assert!(!refusal.is_fresh(999 - MAX_REFUSAL_AGE));
+    }
+}
@@ -0,0 +1,125 @@
+//! # The randomness of the engines
+//!
+//! The methods that draw random scalars, e.g. [`TokenEngine::generate`](crate::TokenEngine) and
+//! `PrivateKey::new`, draw them from [`default_rng`]. Every one of them has a `_with_rng` variant
+//! that takes the RNG instead.
+//!
+//! [`default_rng`] fills the bytes with, in this order
+//! 1. the RNG that was set with [`set_rng`], if any
+//! 2. `rand::thread_rng()`, with the default `std` feature
+//! 3. the RNG of the OS, with the `os_rng` feature, by `getrandom`
+//!
+//! On a target without std, e.g. the microcontroller of a serial reader, the crate is built with
+//! `--no-default-features`, and the firmware sets its hardware RNG once as it starts:
+//!
+//! ```
+//!     fn hardware_rng(bytes: &mut [u8]) -> Result<(), rand::Error> {
+//!         // read the bytes from the TRNG of the board
+//!         # bytes.iter_mut().for_each(|byte| *byte = 4);
+//!         Ok(())
+//!     }
+//!
+//!     atpmd::rng::set_rng(hardware_rng);
+//! ```
+//!
+//! If there is no RNG, [`default_rng`] panics when it is used.
+
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use rand::{CryptoRng, Error, RngCore};
+
+/// Fill the bytes with cryptographically secure random bytes
+pub type FillBytes = fn(&mut [u8]) -> Result<(), Error>;
+
+/// The error code of [`DefaultRng`], when no RNG is set and the crate is built without one
+pub const NO_RNG: u32 = Error::CUSTOM_START + 0x7a70;
+
+static USER_RNG: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Set the RNG of [`default_rng`], for every thread
+///
+/// It is used instead of the RNG of std or the OS, if the crate is built with one.
+pub fn set_rng(fill_bytes: FillBytes) {
+    USER_RNG.store(fill_bytes as *mut (), Ordering::Release);
+}
+
+/// Use the RNG the crate is built with again
+pub fn unset_rng() {
+    USER_RNG.store(ptr::null_mut(), Ordering::Release);
+}
+
+fn user_rng() -> Option<FillBytes> {
+    let fill_bytes = USER_RNG.load(Ordering::Acquire);
+    if fill_bytes.is_null() {
+        None
+    } else {
+        // SAFETY: the only pointers that are stored are the `FillBytes` of `set_rng`
+        Some(unsafe { mem::transmute::<*mut (), FillBytes>(fill_bytes) })
+    }
+}
+
+/// The RNG of the methods without `_with_rng`, see the [module](self)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultRng;
+
+/// The RNG of the methods without `_with_rng`
+pub fn default_rng() -> DefaultRng {
+    DefaultRng
+}
+
+impl RngCore for DefaultRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if let Err(e) = self.try_fill_bytes(dest) {
+            panic!("atpmd has no RNG: {}", e);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        if let Some(fill_bytes) = user_rng() {
+            return fill_bytes(dest);
+        }
+
+        #[cfg(feature = "std")]
+        return rand::thread_rng().try_fill_bytes(dest);
+
+        #[cfg(all(not(feature = "std"), feature = "os_rng"))]
+        return rand::rngs::OsRng.try_fill_bytes(dest);
+
+        #[cfg(not(any(feature = "std", feature = "os_rng")))]
+        Err(Error::from(core::num::NonZeroU32::new(NO_RNG).unwrap()))
+    }
+}
+
+impl CryptoRng for DefaultRng {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_rng() {
+        let mut first = [0u8; 32];
+        let mut second = [0u8; 32];
+        default_rng().fill_bytes(&mut first);
+        default_rng().fill_bytes(&mut second);
+        assert_ne!(first, second);
+
+        // an RNG that a test sets would be used by the other tests too, see the doctest instead
+        assert!(user_rng().is_none());
+    }
+}
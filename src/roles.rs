@@ -0,0 +1,426 @@
+//! # The roles of an engine
+//!
+//! A [`TokenEngine`] has the types and methods of the user, the signer and the verifier. Code of
+//! one role is written against the trait of the role instead, [`TokenClient`], [`TokenSigner`] or
+//! [`TokenVerifier`], so a signer does not depend on the tokens of the user, and a verifier only
+//! on the signed tokens. Every engine has the roles, by the blanket implementations here.
+//!
+//! The methods of the roles have the names of the methods of the engine, so code imports the trait
+//! of its role or [`TokenEngine`], not both.
+//!
+//! ```
+//!     use atpmd::{Error, TokenSigner, TokenVerifier};
+//!     use atpmd::nizkp_curve25519::{keys::PrivateKey, tokens::NizkpTokenEngine};
+//!
+//!     // a signer that works with any engine
+//!     fn issue<S: TokenSigner>(
+//!         request: &S::RandomizedUnsignedToken,
+//!         key: &S::SignKey,
+//!     ) -> Result<S::RandomizedSignedToken, Error> {
+//!         S::sign_randomized(request, key)
+//!     }
+//!
+//!     // and a verifier
+//!     fn admit<V: TokenVerifier>(
+//!         token: &V::SignedToken,
+//!         key: &<V::SignedToken as atpmd::SignedToken>::VerificationKey,
+//!     ) -> bool {
+//!         V::verify(token, key).is_ok()
+//!     }
+//!
+//!     # use atpmd::TokenClient;
+//!     # let key = PrivateKey::new();
+//!     # let public_key = atpmd::nizkp_curve25519::keys::PublicKey::from(&key);
+//!     let signed = NizkpTokenEngine::sign(
+//!         NizkpTokenEngine::generate(&b"metadata"[..]),
+//!         &public_key,
+//!         |request| issue::<NizkpTokenEngine<_>>(request, &key),
+//!     ).unwrap();
+//!     assert!(admit::<NizkpTokenEngine<_>>(&signed, &key));
+//! ```
+
+use rand::{CryptoRng, RngCore};
+
+use crate::common::{
+    Error, KeyRing, PublicKeySet, RandomizedSignedToken, RandomizedUnsignedToken, SignedToken,
+    TokenEngine, UnsignedToken, VerifyError,
+};
+use crate::redemption::{RedeemError, Redeemable, RedemptionStore};
+
+// {{{ Signer
+
+/// The part of an engine that a signer needs: the randomized tokens and the key
+pub trait TokenSigner {
+    /// An anonymous unsigned token
+    type RandomizedUnsignedToken: RandomizedUnsignedToken;
+
+    /// A signed token that is anonymous
+    type RandomizedSignedToken: RandomizedSignedToken;
+
+    /// The key the signer uses to sign a token
+    type SignKey: Default + Clone;
+
+    /// See [`TokenEngine::sign_randomized`]
+    fn sign_randomized(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+    ) -> Result<Self::RandomizedSignedToken, Error>;
+
+    /// See [`TokenEngine::sign_randomized_with_rng`]
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error>;
+
+    /// See [`TokenEngine::sign_randomized_with_policy`]
+    fn sign_randomized_with_policy<P, Ctx>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        policy: &P,
+        context: &Ctx,
+    ) -> Result<Self::RandomizedSignedToken, Error>
+    where
+        P: crate::policy::SignerPolicy<Ctx>,
+        Ctx: ?Sized;
+}
+
+impl<E: TokenEngine + ?Sized> TokenSigner for E {
+    type RandomizedUnsignedToken = <E as TokenEngine>::RandomizedUnsignedToken;
+    type RandomizedSignedToken = <E as TokenEngine>::RandomizedSignedToken;
+    type SignKey = <E as TokenEngine>::SignKey;
+
+    fn sign_randomized(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        <E as TokenEngine>::sign_randomized(randomized_unsigned, sign_key)
+    }
+
+    fn sign_randomized_with_rng<R: CryptoRng + RngCore>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        rng: &mut R,
+    ) -> Result<Self::RandomizedSignedToken, Error> {
+        <E as TokenEngine>::sign_randomized_with_rng(randomized_unsigned, sign_key, rng)
+    }
+
+    fn sign_randomized_with_policy<P, Ctx>(
+        randomized_unsigned: &Self::RandomizedUnsignedToken,
+        sign_key: &Self::SignKey,
+        policy: &P,
+        context: &Ctx,
+    ) -> Result<Self::RandomizedSignedToken, Error>
+    where
+        P: crate::policy::SignerPolicy<Ctx>,
+        Ctx: ?Sized,
+    {
+        <E as TokenEngine>::sign_randomized_with_policy(
+            randomized_unsigned,
+            sign_key,
+            policy,
+            context,
+        )
+    }
+}
+
+// }}}
+
+// {{{ Verifier
+
+/// The part of an engine that a verifier needs: the signed tokens
+pub trait TokenVerifier {
+    /// A signed token
+    type SignedToken: SignedToken;
+
+    /// See [`TokenEngine::verify`]
+    fn verify(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+    ) -> Result<(), Error>;
+
+    /// See [`TokenEngine::verify_with_key_ring`]
+    fn verify_with_key_ring(
+        token: &Self::SignedToken,
+        key_ring: &KeyRing<<Self::SignedToken as SignedToken>::VerificationKey>,
+    ) -> Result<(), Error>;
+
+    /// See [`TokenEngine::redeem`]
+    fn redeem<S: RedemptionStore>(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+        store: &mut S,
+        now: u64,
+    ) -> Result<(), RedeemError>
+    where
+        Self::SignedToken: Redeemable;
+
+    /// See [`TokenEngine::redeem_without_expiry`]
+    fn redeem_without_expiry<S: RedemptionStore>(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+        store: &mut S,
+    ) -> Result<(), RedeemError>
+    where
+        Self::SignedToken: Redeemable;
+}
+
+impl<E: TokenEngine + ?Sized> TokenVerifier for E {
+    type SignedToken = <E as TokenEngine>::SignedToken;
+
+    fn verify(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+    ) -> Result<(), Error> {
+        <E as TokenEngine>::verify(token, verification_key)
+    }
+
+    fn verify_with_key_ring(
+        token: &Self::SignedToken,
+        key_ring: &KeyRing<<Self::SignedToken as SignedToken>::VerificationKey>,
+    ) -> Result<(), Error> {
+        <E as TokenEngine>::verify_with_key_ring(token, key_ring)
+    }
+
+    fn redeem<S: RedemptionStore>(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+        store: &mut S,
+        now: u64,
+    ) -> Result<(), RedeemError>
+    where
+        Self::SignedToken: Redeemable,
+    {
+        <E as TokenEngine>::redeem(token, verification_key, store, now)
+    }
+
+    fn redeem_without_expiry<S: RedemptionStore>(
+        token: &Self::SignedToken,
+        verification_key: &<Self::SignedToken as SignedToken>::VerificationKey,
+        store: &mut S,
+    ) -> Result<(), RedeemError>
+    where
+        Self::SignedToken: Redeemable,
+    {
+        <E as TokenEngine>::redeem_without_expiry(token, verification_key, store)
+    }
+}
+
+// }}}
+
+// {{{ Client
+
+/// The part of an engine that a user needs: the tokens it generates, randomizes and unrandomizes
+///
+/// The user sends the randomized tokens to the signer and the signed tokens to the verifier, so
+/// it has the types of the other roles too.
+pub trait TokenClient {
+    /// An unsigned token
+    type UnsignedToken: UnsignedToken;
+
+    /// An anonymous unsigned token
+    type RandomizedUnsignedToken: RandomizedUnsignedToken;
+
+    /// A signed token that is anonymous
+    type RandomizedSignedToken: RandomizedSignedToken;
+
+    /// A signed token
+    type SignedToken: SignedToken;
+
+    /// The randomization data, which the signer must not get
+    type Randomization;
+
+    /// The key the user uses to verify the validity of a signed token
+    type UserVerification;
+
+    /// The engine, which the [`VerifyError`] gives back the inputs of
+    type Engine: TokenEngine<
+            UnsignedToken = Self::UnsignedToken,
+            RandomizedUnsignedToken = Self::RandomizedUnsignedToken,
+            Randomization = Self::Randomization,
+        > + ?Sized;
+
+    /// See [`TokenEngine::generate`]
+    fn generate(metadata: <Self::UnsignedToken as UnsignedToken>::Metadata) -> Self::UnsignedToken;
+
+    /// See [`TokenEngine::generate_with_hidden`]
+    fn generate_with_hidden(
+        metadata: <Self::UnsignedToken as UnsignedToken>::Metadata,
+        hidden: <Self::UnsignedToken as UnsignedToken>::HiddenMetadata,
+    ) -> Self::UnsignedToken;
+
+    /// See [`TokenEngine::randomize`]
+    fn randomize(
+        unsigned_token: &Self::UnsignedToken,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken);
+
+    /// See [`TokenEngine::randomize_with_rng`]
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken);
+
+    /// See [`TokenEngine::verify_signature_and_unrandomize`]
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self::Engine>>;
+
+    /// See [`TokenEngine::verify_signature_and_unrandomize_with_key_set`]
+    fn verify_signature_and_unrandomize_with_key_set(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        key_set: &PublicKeySet<Self::UserVerification>,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<Self::Engine>>;
+
+    /// See [`TokenEngine::sign`]
+    fn sign<F>(
+        unsigned_token: Self::UnsignedToken,
+        verification_data: &Self::UserVerification,
+        sign_func: F,
+    ) -> Result<Self::SignedToken, Error>
+    where
+        F: Fn(&Self::RandomizedUnsignedToken) -> Result<Self::RandomizedSignedToken, Error>;
+}
+
+impl<E: TokenEngine + ?Sized> TokenClient for E {
+    type UnsignedToken = <E as TokenEngine>::UnsignedToken;
+    type RandomizedUnsignedToken = <E as TokenEngine>::RandomizedUnsignedToken;
+    type RandomizedSignedToken = <E as TokenEngine>::RandomizedSignedToken;
+    type SignedToken = <E as TokenEngine>::SignedToken;
+    type Randomization = <E as TokenEngine>::Randomization;
+    type UserVerification = <E as TokenEngine>::UserVerification;
+    type Engine = E;
+
+    fn generate(metadata: <Self::UnsignedToken as UnsignedToken>::Metadata) -> Self::UnsignedToken {
+        <E as TokenEngine>::generate(metadata)
+    }
+
+    fn generate_with_hidden(
+        metadata: <Self::UnsignedToken as UnsignedToken>::Metadata,
+        hidden: <Self::UnsignedToken as UnsignedToken>::HiddenMetadata,
+    ) -> Self::UnsignedToken {
+        <E as TokenEngine>::generate_with_hidden(metadata, hidden)
+    }
+
+    fn randomize(
+        unsigned_token: &Self::UnsignedToken,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        <E as TokenEngine>::randomize(unsigned_token)
+    }
+
+    fn randomize_with_rng<R: CryptoRng + RngCore>(
+        unsigned_token: &Self::UnsignedToken,
+        rng: &mut R,
+    ) -> (Self::Randomization, Self::RandomizedUnsignedToken) {
+        <E as TokenEngine>::randomize_with_rng(unsigned_token, rng)
+    }
+
+    fn verify_signature_and_unrandomize(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        verification_data: &Self::UserVerification,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<E>> {
+        <E as TokenEngine>::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized_unsigned,
+            signed_token,
+            verification_data,
+            randomization,
+        )
+    }
+
+    fn verify_signature_and_unrandomize_with_key_set(
+        unsigned_token: Self::UnsignedToken,
+        randomized_unsigned: Self::RandomizedUnsignedToken,
+        signed_token: Self::RandomizedSignedToken,
+        key_set: &PublicKeySet<Self::UserVerification>,
+        randomization: Self::Randomization,
+    ) -> Result<Self::SignedToken, VerifyError<E>> {
+        <E as TokenEngine>::verify_signature_and_unrandomize_with_key_set(
+            unsigned_token,
+            randomized_unsigned,
+            signed_token,
+            key_set,
+            randomization,
+        )
+    }
+
+    fn sign<F>(
+        unsigned_token: Self::UnsignedToken,
+        verification_data: &Self::UserVerification,
+        sign_func: F,
+    ) -> Result<Self::SignedToken, Error>
+    where
+        F: Fn(&Self::RandomizedUnsignedToken) -> Result<Self::RandomizedSignedToken, Error>,
+    {
+        <E as TokenEngine>::sign(unsigned_token, verification_data, sign_func)
+    }
+}
+
+// }}}
+
+#[cfg(all(test, feature = "pairing"))]
+mod tests {
+    use super::*;
+    use crate::atpm_pairing::{
+        keys::{PrivateKey, PublicKey},
+        tokens::PairingTokenEngine,
+    };
+
+    /// A client that only knows its role
+    fn request<C: TokenClient>(
+        metadata: <C::UnsignedToken as UnsignedToken>::Metadata,
+    ) -> (
+        C::UnsignedToken,
+        C::Randomization,
+        C::RandomizedUnsignedToken,
+    ) {
+        let unsigned = C::generate(metadata);
+        let (r, randomized) = C::randomize(&unsigned);
+        (unsigned, r, randomized)
+    }
+
+    #[test]
+    fn test_roles() {
+        type Engine = PairingTokenEngine<&'static [u8]>;
+
+        let private = PrivateKey::new();
+        let public_key = PublicKey::from(&private);
+
+        let (unsigned, r, randomized) = request::<Engine>(b"metadata");
+        let response = <Engine as TokenSigner>::sign_randomized(&randomized, &private).unwrap();
+
+        // the response of another key gives the request back
+        let other = PublicKey::from(&PrivateKey::new());
+        let error = <Engine as TokenClient>::verify_signature_and_unrandomize(
+            unsigned, randomized, response, &other, r,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.error, Error::BadSignature);
+
+        let response =
+            <Engine as TokenSigner>::sign_randomized(&error.randomized_unsigned, &private).unwrap();
+        let signed = <Engine as TokenClient>::verify_signature_and_unrandomize(
+            error.unsigned_token,
+            error.randomized_unsigned,
+            response,
+            &public_key,
+            error.randomization,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            <Engine as TokenVerifier>::verify(&signed, &public_key),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! # Issuance sessions
+//!
+//! Issuing a token is randomize, send, receive and unrandomize, and the client keeps the unsigned
+//! token, the randomized token and the randomization between them. [`TokenEngine::sign`] does it
+//! in a closure, which does not fit a client that sends the request and gets the response in
+//! another place. An [`IssuanceSession`] keeps the pieces instead, and its state only has the
+//! methods of the next step: a [`Randomized`] session gives the request, and a [`Sent`] session
+//! takes the response.
+//!
+//! The signer gets an [`IssuanceResponder`] of its key, which gives a [`ReceivedRequest`] for each
+//! request, to check the metadata before it signs.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::session::{IssuanceResponder, IssuanceSession};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     type Engine = PairingTokenEngine<&'static [u8]>;
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let responder = IssuanceResponder::<Engine>::new(&secret_key);
+//!
+//!     // the client
+//!     let session = IssuanceSession::<Engine>::new(Engine::generate(b"resource"));
+//!     let request = session.request().clone();
+//!     let session = session.send();
+//!
+//!     // the signer
+//!     let received = responder.receive(request);
+//!     assert_eq!(&*received.metadata(), b"resource");
+//!     let response = received.sign().unwrap();
+//!
+//!     // the client
+//!     let signed = session.receive(response, &public_key).unwrap();
+//!     assert!(Engine::verify(&signed, &public_key).is_ok());
+//! ```
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+
+use crate::common::{Error, RandomizedUnsignedToken, TokenEngine, VerifyError};
+
+// {{{ Client
+
+/// The state of a session that has a request to send
+pub struct Randomized;
+
+/// The state of a session that waits for the response of the signer
+pub struct Sent;
+
+/// The issuance of one token, on the client, in the state `S`
+pub struct IssuanceSession<E: TokenEngine, S = Randomized> {
+    unsigned_token: E::UnsignedToken,
+    randomized: E::RandomizedUnsignedToken,
+    randomization: E::Randomization,
+    _s: PhantomData<S>,
+}
+
+impl<E: TokenEngine, S> IssuanceSession<E, S> {
+    /// The request to the signer
+    pub fn request(&self) -> &E::RandomizedUnsignedToken {
+        &self.randomized
+    }
+
+    fn into_state<T>(self) -> IssuanceSession<E, T> {
+        IssuanceSession {
+            unsigned_token: self.unsigned_token,
+            randomized: self.randomized,
+            randomization: self.randomization,
+            _s: PhantomData {},
+        }
+    }
+}
+
+impl<E: TokenEngine> IssuanceSession<E, Randomized> {
+    /// Randomize the unsigned token
+    pub fn new(unsigned_token: E::UnsignedToken) -> Self {
+        let (randomization, randomized) = E::randomize(&unsigned_token);
+        Self {
+            unsigned_token,
+            randomized,
+            randomization,
+            _s: PhantomData {},
+        }
+    }
+
+    /// The request has been sent, the session waits for the response
+    pub fn send(self) -> IssuanceSession<E, Sent> {
+        self.into_state()
+    }
+}
+
+impl<E: TokenEngine> IssuanceSession<E, Sent> {
+    /// Check the signature of the response and remove the randomization
+    ///
+    /// If it does not verify, the session is back in the error, see [`IssuanceSession::from`], to
+    /// try another response.
+    pub fn receive(
+        self,
+        signed: E::RandomizedSignedToken,
+        verification_data: &E::UserVerification,
+    ) -> Result<E::SignedToken, VerifyError<E>> {
+        E::verify_signature_and_unrandomize(
+            self.unsigned_token,
+            self.randomized,
+            signed,
+            verification_data,
+            self.randomization,
+        )
+    }
+}
+
+impl<E: TokenEngine> From<VerifyError<E>> for IssuanceSession<E, Sent> {
+    fn from(e: VerifyError<E>) -> Self {
+        Self {
+            unsigned_token: e.unsigned_token,
+            randomized: e.randomized_unsigned,
+            randomization: e.randomization,
+            _s: PhantomData {},
+        }
+    }
+}
+
+// }}}
+
+// {{{ Signer
+
+/// The issuance of the tokens of a key, on the signer
+pub struct IssuanceResponder<'k, E: TokenEngine> {
+    key: &'k E::SignKey,
+}
+
+impl<'k, E: TokenEngine> IssuanceResponder<'k, E> {
+    pub fn new(key: &'k E::SignKey) -> Self {
+        Self { key }
+    }
+
+    /// A request from a client, to check before it is signed
+    pub fn receive(&self, request: E::RandomizedUnsignedToken) -> ReceivedRequest<'k, E> {
+        ReceivedRequest {
+            key: self.key,
+            request,
+        }
+    }
+}
+
+/// A request that the signer has received, and not signed
+pub struct ReceivedRequest<'k, E: TokenEngine> {
+    key: &'k E::SignKey,
+    request: E::RandomizedUnsignedToken,
+}
+
+impl<'k, E: TokenEngine> ReceivedRequest<'k, E> {
+    /// The public metadata of the request, e.g. for the policy of the signer
+    pub fn metadata(&self) -> Box<[u8]> {
+        self.request.metadata()
+    }
+
+    pub fn request(&self) -> &E::RandomizedUnsignedToken {
+        &self.request
+    }
+
+    /// Sign the request, this is the response to the client
+    pub fn sign(self) -> Result<E::RandomizedSignedToken, Error> {
+        E::sign_randomized(&self.request, self.key)
+    }
+
+    /// Refuse the request, and get it back
+    pub fn refuse(self) -> E::RandomizedUnsignedToken {
+        self.request
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::atpm_pairing::{
+        keys::{PrivateKey, PublicKey},
+        tokens::PairingTokenEngine,
+    };
+    use crate::nizkp_curve25519::tokens_batched::BatchedNizkpTokenEngine;
+
+    type Engine = PairingTokenEngine<&'static [u8]>;
+
+    #[test]
+    fn test_session() {
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+        let responder = IssuanceResponder::<Engine>::new(&secret_key);
+
+        let session = IssuanceSession::<Engine>::new(Engine::generate(b"a")).send();
+        let response = responder
+            .receive(Engine::randomize(&Engine::generate(b"a")).1)
+            .sign()
+            .unwrap();
+
+        // the response to another request, the session may take the right one
+        let e = session.receive(response, &public_key).err().unwrap();
+        assert_eq!(e.error, Error::BadSignature);
+        let session = IssuanceSession::from(e);
+
+        let received = responder.receive(session.request().clone());
+        assert_eq!(&*received.metadata(), b"a");
+        let signed = session
+            .receive(received.sign().unwrap(), &public_key)
+            .unwrap();
+        assert!(Engine::verify(&signed, &public_key).is_ok());
+    }
+
+    #[test]
+    fn test_session_batched() {
+        use crate::nizkp_curve25519::keys::{
+            PrivateKey as NizkpPrivateKey, PublicKey as NizkpPublicKey,
+        };
+
+        type Batched = BatchedNizkpTokenEngine<&'static [u8], 4>;
+        let secret_key = NizkpPrivateKey::new();
+        let public_key = NizkpPublicKey::from(&secret_key);
+
+        // sent as JSON, the batches are not cloned
+        let session = IssuanceSession::<Batched>::new(Batched::generate(b"a"));
+        let json = serde_json::to_string(session.request()).unwrap();
+        let session = session.send();
+
+        let received = IssuanceResponder::<Batched>::new(&secret_key)
+            .receive(serde_json::from_str(&json).unwrap());
+        assert_eq!(&*received.metadata(), b"a");
+
+        let signed = session
+            .receive(received.sign().unwrap(), &public_key)
+            .unwrap();
+        assert!(Batched::verify(&signed, &secret_key).is_ok());
+    }
+}
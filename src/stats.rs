@@ -0,0 +1,129 @@
+//! # Issuance statistics
+//!
+//! The public metadata is visible to the signer, so every user with the same metadata is in the
+//! same anonymity set.
+//! If very few tokens are issued with some metadata, the metadata alone may identify the user.
+//! This module counts the issued tokens per metadata, and estimates the size of the anonymity sets
+//! offline, so that the operator may choose coarser metadata before it is a problem.
+//!
+//! ```
+//!     use atpmd::stats::IssuerStats;
+//!
+//!     let mut stats = IssuerStats::new();
+//!     for _ in 0..100 {
+//!         stats.record(b"2021-06-01");
+//!     }
+//!     stats.record(b"2021-06-01 12:03");
+//!
+//!     let report = stats.anonymity_sets(10);
+//!     assert_eq!(report.smallest(), Some(1));
+//!     assert_eq!(report.flagged().count(), 1);
+//! ```
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+/// The number of tokens issued per public metadata
+#[derive(Debug, Clone, Default)]
+pub struct IssuerStats {
+    counts: BTreeMap<Vec<u8>, u64>,
+}
+
+impl IssuerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one issued token with the given metadata
+    pub fn record(&mut self, metadata: impl AsRef<[u8]>) {
+        self.record_many(metadata, 1);
+    }
+
+    /// Count several issued tokens with the given metadata, e.g. a batch
+    pub fn record_many(&mut self, metadata: impl AsRef<[u8]>, count: u64) {
+        *self.counts.entry(metadata.as_ref().to_vec()).or_insert(0) += count;
+    }
+
+    /// The number of tokens issued with the given metadata
+    pub fn count(&self, metadata: impl AsRef<[u8]>) -> u64 {
+        self.counts.get(metadata.as_ref()).copied().unwrap_or(0)
+    }
+
+    /// The total number of tokens issued
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// All metadata and their counts, sorted by metadata
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.counts.iter().map(|(m, c)| (&m[..], *c))
+    }
+
+    /// Estimate the anonymity sets, flagging every metadata with fewer than `threshold` tokens
+    pub fn anonymity_sets(&self, threshold: u64) -> AnonymityReport<'_> {
+        let mut sets: Vec<_> = self
+            .iter()
+            .map(|(metadata, size)| AnonymitySet {
+                metadata,
+                size,
+                flagged: size < threshold,
+            })
+            .collect();
+        sets.sort_by_key(|set| set.size);
+
+        AnonymityReport { threshold, sets }
+    }
+}
+
+/// The anonymity set of a single metadata value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnonymitySet<'a> {
+    pub metadata: &'a [u8],
+    /// The number of tokens that may not be told apart by the signer
+    pub size: u64,
+    /// The set is smaller than the threshold
+    pub flagged: bool,
+}
+
+/// The anonymity sets of all metadata, from smallest to largest
+#[derive(Debug, Clone)]
+pub struct AnonymityReport<'a> {
+    pub threshold: u64,
+    pub sets: Vec<AnonymitySet<'a>>,
+}
+
+impl<'a> AnonymityReport<'a> {
+    /// The sets that are smaller than the threshold
+    pub fn flagged(&self) -> impl Iterator<Item = &AnonymitySet<'a>> {
+        self.sets.iter().filter(|set| set.flagged)
+    }
+
+    /// The size of the smallest anonymity set
+    pub fn smallest(&self) -> Option<u64> {
+        self.sets.first().map(|set| set.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anonymity_sets() {
+        let mut stats = IssuerStats::new();
+        stats.record_many(b"a", 50);
+        stats.record_many(b"b", 3);
+        stats.record(b"c");
+        stats.record(b"c");
+
+        assert_eq!(stats.count(b"c"), 2);
+        assert_eq!(stats.count(b"d"), 0);
+        assert_eq!(stats.total(), 55);
+
+        let report = stats.anonymity_sets(3);
+        let sizes: Vec<_> = report.sets.iter().map(|set| set.size).collect();
+        assert_eq!(sizes, [2, 3, 50]);
+
+        let flagged: Vec<_> = report.flagged().map(|set| set.metadata).collect();
+        assert_eq!(flagged, [&b"c"[..]]);
+    }
+}
@@ -0,0 +1,107 @@
+//! The Fiat–Shamir transcripts of the proofs
+//!
+//! A proof absorbs what it proves into a [`Transcript`], each part with a label, and draws its
+//! challenges from it. The protocol of the proof and the labels are hashed along with the lengths
+//! of the parts, so the transcripts of two proofs, or of two parts of one proof, can not be
+//! confused, and a new proof only needs a new protocol name to have its own oracle.
+
+use rand::{prelude::StdRng, SeedableRng};
+use sha2::{Digest, Sha512};
+
+use crate::group::PrimeOrderGroup;
+
+/// A transcript of a proof, hashed with SHA-512
+#[derive(Clone)]
+pub(crate) struct Transcript {
+    hasher: Sha512,
+}
+
+impl Transcript {
+    /// The transcript of a proof of the protocol
+    pub fn new(protocol: &'static [u8]) -> Self {
+        let mut transcript = Self {
+            hasher: Sha512::new(),
+        };
+        transcript.append_message(b"atpmd transcript", protocol);
+        transcript
+    }
+
+    /// Absorb a message, with its label and length
+    pub fn append_message(&mut self, label: &'static [u8], message: &[u8]) {
+        self.hasher.update((label.len() as u64).to_be_bytes());
+        self.hasher.update(label);
+        self.hasher.update((message.len() as u64).to_be_bytes());
+        self.hasher.update(message);
+    }
+
+    /// Absorb a number, e.g. the length of a batch
+    pub fn append_u64(&mut self, label: &'static [u8], value: u64) {
+        self.append_message(label, &value.to_be_bytes());
+    }
+
+    /// Absorb an element of a group, in its canonical encoding
+    pub fn append_element<G: PrimeOrderGroup>(
+        &mut self,
+        label: &'static [u8],
+        element: &G::Element,
+    ) {
+        self.append_message(label, &G::encode(element));
+    }
+
+    /// Draw challenge bytes, which are absorbed so the next challenge depends on them
+    pub fn challenge_bytes(&mut self, label: &'static [u8]) -> [u8; 64] {
+        self.append_message(b"challenge", label);
+
+        let mut challenge = [0; 64];
+        challenge.copy_from_slice(&self.hasher.clone().finalize());
+        self.append_message(b"challenge bytes", &challenge);
+
+        challenge
+    }
+
+    /// Draw a challenge scalar of the group
+    pub fn challenge_scalar<G: PrimeOrderGroup>(&mut self, label: &'static [u8]) -> G::Scalar {
+        G::challenge(&self.challenge_bytes(label))
+    }
+
+    /// Draw a seeded rng, for many challenges, e.g. the coefficients of a batch
+    pub fn challenge_rng(&mut self, label: &'static [u8]) -> StdRng {
+        let mut seed = [0; 32];
+        seed.copy_from_slice(&self.challenge_bytes(label)[..32]);
+        StdRng::from_seed(seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(build: impl Fn(&mut Transcript)) -> [u8; 64] {
+        let mut transcript = Transcript::new(b"test");
+        build(&mut transcript);
+        transcript.challenge_bytes(b"c")
+    }
+
+    #[test]
+    fn test_domain_separation() {
+        let ab = challenge(|t| t.append_message(b"m", b"ab"));
+
+        // the boundaries of the messages are in the transcript
+        assert_ne!(
+            ab,
+            challenge(|t| {
+                t.append_message(b"m", b"a");
+                t.append_message(b"m", b"b");
+            })
+        );
+        // and the labels
+        assert_ne!(ab, challenge(|t| t.append_message(b"n", b"ab")));
+        assert_ne!(ab, Transcript::new(b"other").challenge_bytes(b"c"));
+        assert_eq!(ab, challenge(|t| t.append_message(b"m", b"ab")));
+
+        // the challenges follow each other
+        let mut transcript = Transcript::new(b"test");
+        let first = transcript.challenge_bytes(b"c");
+        assert_ne!(first, transcript.challenge_bytes(b"c"));
+    }
+}
@@ -0,0 +1,532 @@
+//! # Pinning the issuers of a verifier
+//!
+//! A verifier that takes tokens in the [wire format](crate::wire) of any engine could be made to
+//! accept a token of another engine, or of another parameter set, than the issuer signs with, e.g.
+//! to downgrade it to a weaker engine. The [`TrustStore`] records for each issuer the engines,
+//! ciphersuites, wire versions and key fingerprints the verifier accepts, and
+//! [`verify_token_bytes`] only verifies tokens that match all of them.
+//!
+//! The pins are empty to begin with, so an issuer accepts nothing that is not pinned.
+//!
+//! ```
+//!     use atpmd::{TokenEngine, wire::WireFormat};
+//!     use atpmd::trust::{verify_token_bytes, IssuerPins, TrustError, TrustStore, VerificationKey};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let mut store = TrustStore::new();
+//!     store.insert("issuer.example", IssuerPins::pairing(public_key.fingerprint()));
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Box::from(&b"resource"[..])),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     let keys = [VerificationKey::Pairing(&public_key)];
+//!     let token = verify_token_bytes::<Box<[u8]>>(&store, "issuer.example", &signed.to_bytes(), &keys);
+//!     assert!(token.is_ok());
+//!
+//!     // the issuer is not known
+//!     assert_eq!(
+//!         verify_token_bytes::<Box<[u8]>>(&store, "other.example", &signed.to_bytes(), &keys).err(),
+//!         Some(TrustError::UnknownIssuer)
+//!     );
+//! ```
+
+use alloc::{collections::BTreeMap, collections::BTreeSet, string::String};
+use core::fmt;
+
+#[cfg(feature = "pairing")]
+use crate::atpm_pairing::{self, keys::PublicKey as PairingPublicKey, tokens::PairingSignedToken};
+use crate::common::{HasKeyId, KeyFingerprint, SignedToken};
+#[cfg(feature = "curve25519")]
+use crate::nizkp_curve25519::{
+    self, keys::PrivateKey as NizkpPrivateKey, tokens::NizkpSignedToken,
+};
+use crate::wire::{Ciphersuite, WireError, WireFormat, WIRE_VERSION};
+
+/// Why a token of an issuer was not accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustError {
+    /// The issuer is not in the trust store
+    UnknownIssuer,
+    /// The token could not be decoded
+    Malformed(WireError),
+    /// The issuer is not pinned to the engine of the token
+    Engine(&'static str),
+    /// The issuer is not pinned to the ciphersuite of the token
+    Ciphersuite(&'static str),
+    /// The issuer is not pinned to the wire version of the token
+    WireVersion(u8),
+    /// None of the keys is pinned for the issuer and may have signed the token
+    Key,
+    /// The signature of the token does not verify
+    BadSignature,
+}
+
+impl fmt::Display for TrustError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownIssuer => f.write_str("the issuer is not trusted"),
+            Self::Malformed(e) => write!(f, "malformed token: {}", e),
+            Self::Engine(engine) => write!(f, "the engine {} is not pinned", engine),
+            Self::Ciphersuite(suite) => write!(f, "the ciphersuite {} is not pinned", suite),
+            Self::WireVersion(version) => write!(f, "wire version {} is not pinned", version),
+            Self::Key => f.write_str("no pinned key of the issuer signed the token"),
+            Self::BadSignature => f.write_str("the signature does not verify"),
+        }
+    }
+}
+
+impl From<WireError> for TrustError {
+    fn from(e: WireError) -> Self {
+        Self::Malformed(e)
+    }
+}
+
+// {{{ Tokens
+
+/// The engine and the parameter set of a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parameters {
+    /// The `ENGINE_ID` of the tokens, see [`HeaderToken`](crate::http::HeaderToken)
+    pub engine: &'static str,
+    pub ciphersuite: &'static str,
+    pub wire_version: u8,
+}
+
+/// A signed token of any engine, as it was decoded from the wire format
+pub enum AnyToken<M: AsRef<[u8]>> {
+    #[cfg(feature = "pairing")]
+    Pairing(PairingSignedToken<M>),
+    #[cfg(feature = "curve25519")]
+    Curve25519(NizkpSignedToken<M>),
+}
+
+impl<M: AsRef<[u8]>> AnyToken<M> {
+    /// The engine and the parameters the token was made with
+    pub fn parameters(&self) -> Parameters {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(_) => Parameters {
+                engine: "pairing",
+                ciphersuite: atpm_pairing::CIPHERSUITE,
+                wire_version: WIRE_VERSION,
+            },
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(_) => Parameters {
+                engine: "curve25519",
+                ciphersuite: nizkp_curve25519::CIPHERSUITE,
+                wire_version: WIRE_VERSION,
+            },
+        }
+    }
+
+    pub fn metadata_bytes(&self) -> &[u8] {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(token) => token.metadata_bytes(),
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(token) => token.metadata_bytes(),
+        }
+    }
+
+    /// Whether the token is signed with the key, which has to be of the same engine
+    fn verify(&self, key: &VerificationKey<'_>) -> bool {
+        match (self, key) {
+            #[cfg(feature = "pairing")]
+            (Self::Pairing(token), VerificationKey::Pairing(key)) => {
+                may_have_signed(token, *key) && token.verify(key)
+            }
+            #[cfg(feature = "curve25519")]
+            (Self::Curve25519(token), VerificationKey::Curve25519(key)) => {
+                may_have_signed(token, *key) && token.verify(key)
+            }
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+/// The key identifier of the token, if it has one, is that of the key
+fn may_have_signed<T: SignedToken, K: HasKeyId>(token: &T, key: &K) -> bool {
+    match token.key_id() {
+        Some(key_id) => key_id == key.key_id(),
+        None => true,
+    }
+}
+
+/// Decode a signed token of any engine, by the ciphersuite byte of the encoding
+///
+/// The tokens of an engine that is not in the build are of an unknown ciphersuite.
+pub fn decode_any<M>(bytes: &[u8]) -> Result<AnyToken<M>, WireError>
+where
+    M: AsRef<[u8]> + for<'a> From<&'a [u8]>,
+{
+    let suite = bytes.first().ok_or(WireError::Truncated)?;
+    match Ciphersuite::from_byte(*suite) {
+        #[cfg(feature = "pairing")]
+        Some(Ciphersuite::PairingV1) => {
+            PairingSignedToken::from_bytes(bytes).map(AnyToken::Pairing)
+        }
+        #[cfg(feature = "curve25519")]
+        Some(Ciphersuite::RistrettoV1) => {
+            NizkpSignedToken::from_bytes(bytes).map(AnyToken::Curve25519)
+        }
+        #[cfg(feature = "pairing")]
+        None if *suite == PairingSignedToken::<M>::TYPE => Err(WireError::Version),
+        #[cfg(feature = "curve25519")]
+        None if *suite == NizkpSignedToken::<M>::TYPE => Err(WireError::Version),
+        _ => Err(WireError::Ciphersuite),
+    }
+}
+
+// }}}
+
+// {{{ Store
+
+/// The key a verifier verifies the tokens of an engine with
+#[derive(Clone, Copy)]
+pub enum VerificationKey<'a> {
+    #[cfg(feature = "pairing")]
+    Pairing(&'a PairingPublicKey),
+    /// The tokens of the engine are verified with the private key
+    #[cfg(feature = "curve25519")]
+    Curve25519(&'a NizkpPrivateKey),
+}
+
+impl VerificationKey<'_> {
+    /// The fingerprint of the public key
+    pub fn fingerprint(&self) -> KeyFingerprint {
+        match self {
+            #[cfg(feature = "pairing")]
+            Self::Pairing(key) => key.fingerprint(),
+            #[cfg(feature = "curve25519")]
+            Self::Curve25519(key) => key.fingerprint(),
+        }
+    }
+}
+
+/// What a verifier accepts from an issuer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IssuerPins {
+    pub engines: BTreeSet<String>,
+    pub ciphersuites: BTreeSet<String>,
+    pub wire_versions: BTreeSet<u8>,
+    /// The fingerprints of the public keys of the issuer
+    pub keys: BTreeSet<KeyFingerprint>,
+}
+
+impl IssuerPins {
+    /// Nothing is accepted
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The pins of an issuer that signs with the pairing engine of this build, with the key
+    #[cfg(feature = "pairing")]
+    pub fn pairing(key: KeyFingerprint) -> Self {
+        Self::new()
+            .engine("pairing")
+            .ciphersuite(atpm_pairing::CIPHERSUITE)
+            .wire_version(WIRE_VERSION)
+            .key(key)
+    }
+
+    /// The pins of an issuer that signs with the curve25519 engine of this build, with the key
+    #[cfg(feature = "curve25519")]
+    pub fn curve25519(key: KeyFingerprint) -> Self {
+        Self::new()
+            .engine("curve25519")
+            .ciphersuite(nizkp_curve25519::CIPHERSUITE)
+            .wire_version(WIRE_VERSION)
+            .key(key)
+    }
+
+    pub fn engine(mut self, engine: impl Into<String>) -> Self {
+        self.engines.insert(engine.into());
+        self
+    }
+
+    pub fn ciphersuite(mut self, ciphersuite: impl Into<String>) -> Self {
+        self.ciphersuites.insert(ciphersuite.into());
+        self
+    }
+
+    pub fn wire_version(mut self, version: u8) -> Self {
+        self.wire_versions.insert(version);
+        self
+    }
+
+    pub fn key(mut self, fingerprint: KeyFingerprint) -> Self {
+        self.keys.insert(fingerprint);
+        self
+    }
+
+    /// Check the parameters of a token against the pins
+    pub fn check(&self, parameters: &Parameters) -> Result<(), TrustError> {
+        if !self.engines.contains(parameters.engine) {
+            return Err(TrustError::Engine(parameters.engine));
+        }
+
+        if !self.ciphersuites.contains(parameters.ciphersuite) {
+            return Err(TrustError::Ciphersuite(parameters.ciphersuite));
+        }
+
+        if !self.wire_versions.contains(&parameters.wire_version) {
+            return Err(TrustError::WireVersion(parameters.wire_version));
+        }
+
+        Ok(())
+    }
+}
+
+/// The pins of the issuers of a verifier, by the name of the issuer
+#[derive(Debug, Clone, Default)]
+pub struct TrustStore {
+    issuers: BTreeMap<String, IssuerPins>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust an issuer, replacing its old pins
+    pub fn insert(&mut self, issuer: impl Into<String>, pins: IssuerPins) {
+        self.issuers.insert(issuer.into(), pins);
+    }
+
+    pub fn remove(&mut self, issuer: &str) -> Option<IssuerPins> {
+        self.issuers.remove(issuer)
+    }
+
+    pub fn get(&self, issuer: &str) -> Option<&IssuerPins> {
+        self.issuers.get(issuer)
+    }
+
+    pub fn len(&self) -> usize {
+        self.issuers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issuers.is_empty()
+    }
+}
+
+/// Decode a token of the issuer, and verify it with one of the keys, if the trust store pins the
+/// engine, the parameters and the key for the issuer
+///
+/// The keys that are not pinned for the issuer are not tried.
+pub fn verify_token_bytes<M>(
+    store: &TrustStore,
+    issuer: &str,
+    bytes: &[u8],
+    keys: &[VerificationKey<'_>],
+) -> Result<AnyToken<M>, TrustError>
+where
+    M: AsRef<[u8]> + for<'a> From<&'a [u8]>,
+{
+    let pins = store.get(issuer).ok_or(TrustError::UnknownIssuer)?;
+    let token = decode_any::<M>(bytes)?;
+
+    pins.check(&token.parameters())?;
+
+    let mut pinned = keys
+        .iter()
+        .filter(|key| pins.keys.contains(&key.fingerprint()))
+        .peekable();
+    if pinned.peek().is_none() {
+        return Err(TrustError::Key);
+    }
+
+    if pinned.any(|key| token.verify(key)) {
+        Ok(token)
+    } else {
+        Err(TrustError::BadSignature)
+    }
+}
+
+// }}}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+mod tests {
+    use super::*;
+    use crate::atpm_pairing::{keys::PrivateKey as PairingPrivateKey, tokens::PairingTokenEngine};
+    use crate::nizkp_curve25519::{keys::PublicKey as NizkpPublicKey, tokens::NizkpTokenEngine};
+    use crate::TokenEngine;
+    use alloc::{boxed::Box, vec::Vec};
+
+    type Metadata = Box<[u8]>;
+
+    fn pairing_token(key: &PairingPrivateKey) -> Vec<u8> {
+        let public_key = PairingPublicKey::from(key);
+        PairingTokenEngine::sign(
+            PairingTokenEngine::generate(Box::from(&b"resource"[..])),
+            &public_key,
+            |randomized| PairingTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+        .to_bytes()
+    }
+
+    fn curve25519_token(key: &NizkpPrivateKey) -> Vec<u8> {
+        let public_key = NizkpPublicKey::from(key);
+        NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(Box::from(&b"resource"[..])),
+            &public_key,
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_decode_any() {
+        let pairing = pairing_token(&PairingPrivateKey::new());
+        let curve25519 = curve25519_token(&NizkpPrivateKey::new());
+
+        let token = decode_any::<Metadata>(&pairing).unwrap();
+        assert_eq!(token.parameters().engine, "pairing");
+        assert_eq!(token.metadata_bytes(), b"resource");
+        let token = decode_any::<Metadata>(&curve25519).unwrap();
+        assert_eq!(token.parameters().engine, "curve25519");
+        assert_eq!(token.parameters().wire_version, WIRE_VERSION);
+
+        assert_eq!(
+            decode_any::<Metadata>(&[]).err(),
+            Some(WireError::Truncated)
+        );
+        assert_eq!(
+            decode_any::<Metadata>(&[0x04]).err(),
+            Some(WireError::Ciphersuite)
+        );
+        assert_eq!(
+            decode_any::<Metadata>(&[Ciphersuite::Secp256k1V1.to_byte()]).err(),
+            Some(WireError::Ciphersuite)
+        );
+        // a public key of the engine
+        let public_key = PairingPublicKey::from(&PairingPrivateKey::new()).to_bytes();
+        assert_eq!(
+            decode_any::<Metadata>(&public_key).err(),
+            Some(WireError::Type)
+        );
+        // a token of wire version 1, without the ciphersuite
+        assert_eq!(
+            decode_any::<Metadata>(&pairing[1..]).err(),
+            Some(WireError::Version)
+        );
+    }
+
+    #[test]
+    fn test_pinned_engines() {
+        let pairing_key = PairingPrivateKey::new();
+        let pairing_public = PairingPublicKey::from(&pairing_key);
+        let nizkp_key = NizkpPrivateKey::new();
+
+        let mut store = TrustStore::new();
+        store.insert("issuer", IssuerPins::pairing(pairing_public.fingerprint()));
+
+        let keys = [
+            VerificationKey::Pairing(&pairing_public),
+            VerificationKey::Curve25519(&nizkp_key),
+        ];
+
+        assert!(verify_token_bytes::<Metadata>(
+            &store,
+            "issuer",
+            &pairing_token(&pairing_key),
+            &keys
+        )
+        .is_ok());
+
+        // a verifier of both engines is not downgraded to the other engine
+        assert_eq!(
+            verify_token_bytes::<Metadata>(&store, "issuer", &curve25519_token(&nizkp_key), &keys)
+                .err(),
+            Some(TrustError::Engine("curve25519"))
+        );
+
+        // the engine is pinned, but with the ciphersuite of another build
+        let pins = IssuerPins::new()
+            .engine("pairing")
+            .ciphersuite("BLS12-381-other")
+            .wire_version(WIRE_VERSION)
+            .key(pairing_public.fingerprint());
+        store.insert("issuer", pins.clone());
+        assert_eq!(
+            verify_token_bytes::<Metadata>(&store, "issuer", &pairing_token(&pairing_key), &keys)
+                .err(),
+            Some(TrustError::Ciphersuite(atpm_pairing::CIPHERSUITE))
+        );
+
+        let pins = IssuerPins {
+            wire_versions: BTreeSet::new(),
+            ..IssuerPins::pairing(pairing_public.fingerprint())
+        };
+        store.insert("issuer", pins);
+        assert_eq!(
+            verify_token_bytes::<Metadata>(&store, "issuer", &pairing_token(&pairing_key), &keys)
+                .err(),
+            Some(TrustError::WireVersion(WIRE_VERSION))
+        );
+    }
+
+    #[test]
+    fn test_pinned_keys() {
+        let pairing_key = PairingPrivateKey::new();
+        let pairing_public = PairingPublicKey::from(&pairing_key);
+        let other_key = PairingPrivateKey::new();
+        let other_public = PairingPublicKey::from(&other_key);
+
+        let mut store = TrustStore::new();
+        store.insert("issuer", IssuerPins::pairing(pairing_public.fingerprint()));
+
+        // the key of the token is given, but it is not pinned
+        assert_eq!(
+            verify_token_bytes::<Metadata>(
+                &store,
+                "issuer",
+                &pairing_token(&other_key),
+                &[VerificationKey::Pairing(&other_public)]
+            )
+            .err(),
+            Some(TrustError::Key)
+        );
+
+        // the pinned key did not sign the token
+        assert_eq!(
+            verify_token_bytes::<Metadata>(
+                &store,
+                "issuer",
+                &pairing_token(&other_key),
+                &[
+                    VerificationKey::Pairing(&other_public),
+                    VerificationKey::Pairing(&pairing_public)
+                ]
+            )
+            .err(),
+            Some(TrustError::BadSignature)
+        );
+
+        // a curve25519 key is checked by the fingerprint of its public part
+        let nizkp_key = NizkpPrivateKey::new();
+        store.insert(
+            "other",
+            IssuerPins::curve25519(NizkpPublicKey::from(&nizkp_key).fingerprint()),
+        );
+        assert!(verify_token_bytes::<Metadata>(
+            &store,
+            "other",
+            &curve25519_token(&nizkp_key),
+            &[VerificationKey::Curve25519(&nizkp_key)]
+        )
+        .is_ok());
+    }
+}
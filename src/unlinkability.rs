@@ -0,0 +1,369 @@
+//! # Unlinkability self-test
+//!
+//! The signer must not be able to link a redeemed token to the issuance it came from.
+//! This is a statistical test for auditors, and for the CI of deployments that embed the crate:
+//! it issues many tokens with the same metadata, keeps what the issuer sees (the request and the
+//! response) and what the verifier sees (the signed token), and looks for correlations between
+//! the two that are beyond chance.
+//!
+//! Two things are checked:
+//!
+//! - No [`WINDOW`] bytes of a redemption are found in an issuance, skipping the bytes that are the
+//!   same in every token, like the metadata. This catches values that are carried over, e.g. a
+//!   point that is not randomized.
+//! - Every bit of the issuances is independent of every bit of the redemptions. The chi-squared
+//!   statistic of each pair of bits is computed, and the largest has to be below a threshold.
+//!
+//! A test like this can only find leaks, it can not prove that there are none.
+//!
+//! ```
+//!     use atpmd::unlinkability::unlinkability_check;
+//!     use atpmd::nizkp_curve25519::{keys::PrivateKey, tokens::NizkpTokenEngine};
+//!
+//!     let report = unlinkability_check::<NizkpTokenEngine<Box<[u8]>>>(
+//!         Box::from(&b"2021-06-01"[..]),
+//!         &PrivateKey::new(),
+//!         64,
+//!     )
+//!     .unwrap();
+//!
+//!     assert!(report.is_unlinkable());
+//! ```
+
+use alloc::{collections::BTreeSet, vec::Vec};
+use core::fmt;
+
+use crate::common::{TokenEngine, UnsignedToken};
+use crate::wire::{WireError, WireFormat};
+
+/// The number of bytes of a redemption that are looked for in the issuances
+pub const WINDOW: usize = 8;
+
+/// The default threshold of the chi-squared statistic of a pair of bits
+///
+/// A pair of independent bits exceeds this with a probability of about `1e-9`, so a check of a
+/// million pairs fails by chance about once in a thousand runs.
+pub const DEFAULT_THRESHOLD: f64 = 37.3;
+
+/// What the issuer and the verifier see of one token
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    /// The request and the response, as sent to and from the issuer
+    pub issuance: Vec<u8>,
+    /// The signed token, as sent to the verifier
+    pub redemption: Vec<u8>,
+}
+
+/// The reason the tokens could not be issued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckError {
+    /// The signer did not sign a token
+    Signing,
+    /// The signature was not valid
+    Unrandomize,
+    /// A token could not be encoded
+    Encoding(WireError),
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Signing => f.write_str("the signer did not sign the token"),
+            Self::Unrandomize => f.write_str("the signature is not valid"),
+            Self::Encoding(error) => write!(f, "the token could not be encoded: {}", error),
+        }
+    }
+}
+
+/// The result of the check
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnlinkabilityReport {
+    /// The number of tokens
+    pub samples: usize,
+    /// The number of redemptions with bytes from an issuance
+    pub shared_windows: usize,
+    /// The number of pairs of bits that were compared
+    ///
+    /// Bits that are set, or not set, in fewer than an eighth of the tokens are skipped.
+    pub bit_pairs: usize,
+    /// The largest chi-squared statistic of a pair of bits
+    pub max_statistic: f64,
+}
+
+impl UnlinkabilityReport {
+    /// There are no shared bytes, and no pair of bits is above the threshold
+    pub fn passed(&self, threshold: f64) -> bool {
+        self.shared_windows == 0 && self.max_statistic <= threshold
+    }
+
+    /// [`UnlinkabilityReport::passed`] with the [`DEFAULT_THRESHOLD`]
+    pub fn is_unlinkable(&self) -> bool {
+        self.passed(DEFAULT_THRESHOLD)
+    }
+}
+
+/// Issue `samples` tokens with the metadata, and check that they are unlinkable
+///
+/// A few hundred samples are enough to find most leaks.
+pub fn unlinkability_check<E>(
+    metadata: <E::UnsignedToken as UnsignedToken>::Metadata,
+    sign_key: &E::SignKey,
+    samples: usize,
+) -> Result<UnlinkabilityReport, CheckError>
+where
+    E: TokenEngine,
+    <E::UnsignedToken as UnsignedToken>::Metadata: Clone,
+    E::RandomizedUnsignedToken: WireFormat,
+    E::RandomizedSignedToken: WireFormat,
+    E::SignedToken: WireFormat,
+{
+    Ok(analyze(&transcripts::<E>(metadata, sign_key, samples)?))
+}
+
+/// Issue `samples` tokens with the metadata, and keep what the issuer and the verifier see
+pub fn transcripts<E>(
+    metadata: <E::UnsignedToken as UnsignedToken>::Metadata,
+    sign_key: &E::SignKey,
+    samples: usize,
+) -> Result<Vec<Transcript>, CheckError>
+where
+    E: TokenEngine,
+    <E::UnsignedToken as UnsignedToken>::Metadata: Clone,
+    E::RandomizedUnsignedToken: WireFormat,
+    E::RandomizedSignedToken: WireFormat,
+    E::SignedToken: WireFormat,
+{
+    let verification = E::UserVerification::from(sign_key.clone());
+
+    (0..samples)
+        .map(|_| {
+            let unsigned = E::generate(metadata.clone());
+            let (r, request) = E::randomize(&unsigned);
+            let response =
+                E::sign_randomized(&request, sign_key).map_err(|_e| CheckError::Signing)?;
+
+            let mut issuance = request.try_to_bytes().map_err(CheckError::Encoding)?;
+            issuance.extend(response.try_to_bytes().map_err(CheckError::Encoding)?);
+
+            let signed =
+                E::verify_signature_and_unrandomize(unsigned, request, response, &verification, r)
+                    .map_err(|_e| CheckError::Unrandomize)?;
+
+            Ok(Transcript {
+                issuance,
+                redemption: signed.try_to_bytes().map_err(CheckError::Encoding)?,
+            })
+        })
+        .collect()
+}
+
+/// Look for correlations between the issuances and the redemptions
+///
+/// The transcripts may come from [`transcripts`], or be captured from a deployment.
+/// The tokens should have the same metadata, and there should be many of them, as the bytes that
+/// are the same in all the tokens are not compared.
+pub fn analyze(transcripts: &[Transcript]) -> UnlinkabilityReport {
+    let issuances = columns(transcripts.iter().map(|t| &t.issuance[..]));
+    let redemptions = columns(transcripts.iter().map(|t| &t.redemption[..]));
+
+    let n = transcripts.len() as f64;
+    let max_statistic = issuances
+        .iter()
+        .flat_map(|a| redemptions.iter().map(move |b| chi_squared(n, a, b)))
+        .fold(0.0, f64::max);
+
+    UnlinkabilityReport {
+        samples: transcripts.len(),
+        shared_windows: shared_windows(transcripts),
+        bit_pairs: issuances.len() * redemptions.len(),
+        max_statistic,
+    }
+}
+
+/// The byte positions that are the same in all the encodings, like the metadata
+fn constant_bytes<'a>(encodings: impl Iterator<Item = &'a [u8]> + Clone) -> Vec<bool> {
+    let len = encodings.clone().map(<[u8]>::len).max().unwrap_or(0);
+
+    (0..len)
+        .map(|position| {
+            let mut bytes = encodings
+                .clone()
+                .filter_map(|encoding| encoding.get(position));
+            let first = bytes.next();
+            bytes.all(|byte| Some(byte) == first)
+        })
+        .collect()
+}
+
+/// The windows of an encoding without constant bytes
+///
+/// A window with only a few bytes that vary is likely to be found somewhere by chance.
+fn varying_windows<'a>(
+    encoding: &'a [u8],
+    constant: &'a [bool],
+) -> impl Iterator<Item = &'a [u8]> + 'a {
+    encoding
+        .windows(WINDOW)
+        .enumerate()
+        .filter(move |(start, _)| !constant[*start..*start + WINDOW].contains(&true))
+        .map(|(_, window)| window)
+}
+
+/// The number of redemptions that have a window of bytes that is in an issuance
+fn shared_windows(transcripts: &[Transcript]) -> usize {
+    let issuances = transcripts.iter().map(|t| &t.issuance[..]);
+    let constant = constant_bytes(issuances.clone());
+    let windows: BTreeSet<_> = issuances
+        .flat_map(|issuance| varying_windows(issuance, &constant))
+        .collect();
+
+    let redemptions = transcripts.iter().map(|t| &t.redemption[..]);
+    let constant = constant_bytes(redemptions.clone());
+    redemptions
+        .filter(|redemption| {
+            varying_windows(redemption, &constant).any(|window| windows.contains(window))
+        })
+        .count()
+}
+
+/// A bit position of the encodings, as a bit set over the tokens
+struct Column {
+    bits: Vec<u64>,
+    ones: u32,
+}
+
+/// The bit positions of the encodings that vary enough between the tokens to be compared
+fn columns<'a>(encodings: impl Iterator<Item = &'a [u8]> + Clone) -> Vec<Column> {
+    let samples = encodings.clone().count();
+    let len = encodings.clone().map(<[u8]>::len).min().unwrap_or(0);
+
+    // a pair of rare bits that are set in the same token has a large statistic by chance
+    let min_count = (samples / 8).max(1) as u32;
+
+    (0..len * 8)
+        .map(|position| {
+            let mut bits = alloc::vec![0u64; samples.div_ceil(64)];
+            for (i, encoding) in encodings.clone().enumerate() {
+                if (encoding[position / 8] >> (position % 8)) & 1 == 1 {
+                    bits[i / 64] |= 1 << (i % 64);
+                }
+            }
+            let ones = bits.iter().map(|word| word.count_ones()).sum();
+
+            Column { bits, ones }
+        })
+        .filter(|column| column.ones.min(samples as u32 - column.ones) >= min_count)
+        .collect()
+}
+
+/// The chi-squared statistic of the 2x2 table of a pair of bits
+fn chi_squared(n: f64, a: &Column, b: &Column) -> f64 {
+    let both: u32 = a
+        .bits
+        .iter()
+        .zip(b.bits.iter())
+        .map(|(a, b)| (a & b).count_ones())
+        .sum();
+
+    let (both, a_ones, b_ones) = (both as f64, a.ones as f64, b.ones as f64);
+    let deviation = both * n - a_ones * b_ones;
+
+    n * deviation * deviation / (a_ones * (n - a_ones) * b_ones * (n - b_ones))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::fill_bytes;
+
+    fn random_transcripts(samples: usize) -> Vec<Transcript> {
+        let mut rng = crate::rng::default_rng();
+        (0..samples)
+            .map(|_| {
+                let mut issuance = alloc::vec![0u8; 64];
+                let mut redemption = alloc::vec![0u8; 48];
+                fill_bytes(&mut rng, &mut issuance);
+                fill_bytes(&mut rng, &mut redemption);
+
+                // the same metadata in both
+                issuance[..4].copy_from_slice(b"meta");
+                redemption[..4].copy_from_slice(b"meta");
+
+                Transcript {
+                    issuance,
+                    redemption,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_independent() {
+        let report = analyze(&random_transcripts(256));
+
+        assert_eq!(report.samples, 256);
+        assert_eq!(report.shared_windows, 0);
+        // the metadata bits are constant
+        assert_eq!(report.bit_pairs, 60 * 8 * 44 * 8);
+        assert!(report.is_unlinkable());
+    }
+
+    #[test]
+    fn fail_shared_bytes() {
+        let mut transcripts = random_transcripts(256);
+        for t in transcripts.iter_mut() {
+            // the issuer sees a part of the token
+            let carried = t.redemption[20..30].to_vec();
+            t.issuance[40..50].copy_from_slice(&carried);
+        }
+
+        let report = analyze(&transcripts);
+        assert_eq!(report.shared_windows, 256);
+        assert!(!report.is_unlinkable());
+    }
+
+    #[test]
+    fn fail_correlated_bit() {
+        let mut transcripts = random_transcripts(256);
+        for t in transcripts.iter_mut() {
+            // a single bit is leaked
+            t.issuance[10] = (t.issuance[10] & !1) | (t.redemption[30] >> 7);
+        }
+
+        let report = analyze(&transcripts);
+        assert_eq!(report.shared_windows, 0);
+        assert!(report.max_statistic > 200.0);
+        assert!(!report.is_unlinkable());
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn test_curve25519() {
+        use crate::nizkp_curve25519::{keys::PrivateKey, tokens::NizkpTokenEngine};
+
+        let report = unlinkability_check::<NizkpTokenEngine<alloc::boxed::Box<[u8]>>>(
+            alloc::boxed::Box::from(&b"metadata"[..]),
+            &PrivateKey::new(),
+            256,
+        )
+        .unwrap();
+
+        assert!(report.bit_pairs > 0);
+        assert!(report.is_unlinkable(), "{:?}", report);
+    }
+
+    #[cfg(feature = "pairing")]
+    #[test]
+    fn test_pairing() {
+        use crate::atpm_pairing::{keys::PrivateKey, tokens::PairingTokenEngine};
+
+        let report = unlinkability_check::<PairingTokenEngine<alloc::boxed::Box<[u8]>>>(
+            alloc::boxed::Box::from(&b"metadata"[..]),
+            &PrivateKey::new(),
+            64,
+        )
+        .unwrap();
+
+        assert!(report.is_unlinkable(), "{:?}", report);
+    }
+}
@@ -0,0 +1,242 @@
+//! # Verifiers of any engine
+//!
+//! The types of a [`TokenEngine`](crate::TokenEngine) are associated types, so the verifiers of
+//! different engines do not have a common type. An [`AnyVerifier`] verifies the wire format of
+//! the tokens of one engine and key behind a trait object, and gives the [`VerifiedClaims`] of the
+//! token. A gateway keeps the verifiers of all its engines and keys in a [`VerifierRegistry`],
+//! which picks them by the [`Ciphersuite`] byte of the token.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::verifier::{Curve25519Verifier, PairingVerifier, VerifierRegistry};
+//!     use atpmd::atpm_pairing::{keys as pairing, tokens::PairingTokenEngine};
+//!     use atpmd::nizkp_curve25519::keys as curve25519;
+//!     use atpmd::wire::WireFormat;
+//!
+//!     let secret_key = pairing::PrivateKey::new();
+//!     let public_key = pairing::PublicKey::from(&secret_key);
+//!
+//!     let mut registry = VerifierRegistry::new();
+//!     registry.insert(PairingVerifier::new(public_key.clone()));
+//!     registry.insert(Curve25519Verifier::new(curve25519::PrivateKey::new()));
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Box::from(&b"resource"[..])),
+//!         &public_key,
+//!         |randomized| PairingTokenEngine::sign_randomized(randomized, &secret_key),
+//!     ).unwrap();
+//!
+//!     let claims = registry.verify_bytes(&signed.to_bytes()).unwrap();
+//!     assert_eq!(&*claims.metadata, b"resource");
+//! ```
+
+use alloc::{boxed::Box, vec::Vec};
+use core::marker::PhantomData;
+
+use crate::common::{Error, KeyId, SignedToken};
+use crate::redemption::{Nullifier, Redeemable};
+use crate::wire::{Ciphersuite, WireError, WireFormat};
+
+/// What a verifier knows of a token that verifies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedClaims {
+    /// The engine and the parameters of the token
+    pub suite: Ciphersuite,
+    pub metadata: Box<[u8]>,
+    /// The hidden metadata, if the token has it, see [`SignedToken::hidden_metadata`]
+    pub hidden_metadata: Option<Box<[u8]>>,
+    pub key_id: Option<KeyId>,
+    /// To spend the token once, see [`redemption`](crate::redemption)
+    pub nullifier: Nullifier,
+}
+
+/// A verifier of the wire format of the tokens of one engine, that may be a trait object
+pub trait AnyVerifier: Send + Sync {
+    /// The ciphersuite of the tokens it verifies
+    fn suite(&self) -> Ciphersuite;
+
+    /// Decode and verify a token
+    fn verify_bytes(&self, token: &[u8]) -> Result<VerifiedClaims, Error>;
+}
+
+/// A verifier of the tokens `T` with a key
+pub struct KeyedVerifier<T: SignedToken> {
+    key: T::VerificationKey,
+    _t: PhantomData<fn() -> T>,
+}
+
+impl<T: SignedToken> KeyedVerifier<T> {
+    pub fn new(key: T::VerificationKey) -> Self {
+        Self {
+            key,
+            _t: PhantomData {},
+        }
+    }
+
+    pub fn key(&self) -> &T::VerificationKey {
+        &self.key
+    }
+}
+
+impl<T> AnyVerifier for KeyedVerifier<T>
+where
+    T: SignedToken + WireFormat + Redeemable,
+    T::VerificationKey: Send + Sync,
+{
+    fn suite(&self) -> Ciphersuite {
+        T::SUITE
+    }
+
+    fn verify_bytes(&self, token: &[u8]) -> Result<VerifiedClaims, Error> {
+        let token = T::from_bytes(token)?;
+        if !token.verify(&self.key) {
+            return Err(Error::BadSignature);
+        }
+
+        Ok(VerifiedClaims {
+            suite: T::SUITE,
+            metadata: Box::from(token.metadata_bytes()),
+            hidden_metadata: token.hidden_metadata().map(Box::from),
+            key_id: token.key_id(),
+            nullifier: token.nullifier(),
+        })
+    }
+}
+
+/// A verifier of the pairing tokens, with the public key
+#[cfg(feature = "pairing")]
+pub type PairingVerifier =
+    KeyedVerifier<crate::atpm_pairing::tokens::PairingSignedToken<Box<[u8]>>>;
+
+/// A verifier of the curve25519 tokens, with the private key
+#[cfg(feature = "curve25519")]
+pub type Curve25519Verifier =
+    KeyedVerifier<crate::nizkp_curve25519::tokens::NizkpSignedToken<Box<[u8]>>>;
+
+/// The verifiers of a gateway, of any engines and keys
+#[derive(Default)]
+pub struct VerifierRegistry {
+    verifiers: Vec<Box<dyn AnyVerifier>>,
+}
+
+impl VerifierRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, verifier: impl AnyVerifier + 'static) {
+        self.verifiers.push(Box::new(verifier));
+    }
+
+    pub fn len(&self) -> usize {
+        self.verifiers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.verifiers.is_empty()
+    }
+
+    /// Verify a token with the verifiers of its ciphersuite, one key at a time
+    ///
+    /// A token of a ciphersuite without a verifier is [`WireError::Ciphersuite`].
+    pub fn verify_bytes(&self, token: &[u8]) -> Result<VerifiedClaims, Error> {
+        let suite = token
+            .first()
+            .and_then(|byte| Ciphersuite::from_byte(*byte))
+            .ok_or(Error::Malformed(WireError::Ciphersuite))?;
+
+        let mut result = Err(Error::Malformed(WireError::Ciphersuite));
+        for verifier in self.verifiers.iter().filter(|v| v.suite() == suite) {
+            result = verifier.verify_bytes(token);
+            if result.is_ok() {
+                break;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(all(test, feature = "pairing", feature = "curve25519"))]
+mod tests {
+    use super::*;
+
+    use crate::atpm_pairing::{keys as pairing, tokens::PairingTokenEngine};
+    use crate::nizkp_curve25519::{keys as curve25519, tokens::NizkpTokenEngine};
+    use crate::TokenEngine;
+
+    fn pairing_token(key: &pairing::PrivateKey, metadata: &[u8]) -> Vec<u8> {
+        PairingTokenEngine::sign(
+            PairingTokenEngine::generate(Box::from(metadata)),
+            &pairing::PublicKey::from(key),
+            |randomized| PairingTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+        .to_bytes()
+    }
+
+    fn curve25519_token(key: &curve25519::PrivateKey, metadata: &[u8]) -> Vec<u8> {
+        NizkpTokenEngine::sign(
+            NizkpTokenEngine::generate(Box::from(metadata)),
+            &curve25519::PublicKey::from(key),
+            |randomized| NizkpTokenEngine::sign_randomized(randomized, key),
+        )
+        .unwrap()
+        .to_bytes()
+    }
+
+    #[test]
+    fn test_registry() {
+        let (old, new) = (pairing::PrivateKey::new(), pairing::PrivateKey::new());
+        let ristretto = curve25519::PrivateKey::new();
+
+        let mut registry = VerifierRegistry::new();
+        registry.insert(PairingVerifier::new(pairing::PublicKey::from(&old)));
+        registry.insert(PairingVerifier::new(pairing::PublicKey::from(&new)));
+        registry.insert(Curve25519Verifier::new(ristretto.clone()));
+        assert_eq!(registry.len(), 3);
+
+        let claims = registry.verify_bytes(&pairing_token(&new, b"a")).unwrap();
+        assert_eq!(claims.suite, Ciphersuite::PairingV1);
+        assert_eq!(&*claims.metadata, b"a");
+        assert_eq!(claims.hidden_metadata, None);
+
+        let claims = registry
+            .verify_bytes(&curve25519_token(&ristretto, b"b"))
+            .unwrap();
+        assert_eq!(claims.suite, Ciphersuite::RistrettoV1);
+        assert_eq!(&*claims.metadata, b"b");
+
+        // the same token has the same nullifier
+        let token = pairing_token(&old, b"a");
+        assert_eq!(
+            registry.verify_bytes(&token).unwrap().nullifier,
+            registry.verify_bytes(&token).unwrap().nullifier
+        );
+    }
+
+    #[test]
+    fn fail_registry() {
+        let key = pairing::PrivateKey::new();
+        let mut registry = VerifierRegistry::new();
+        registry.insert(PairingVerifier::new(pairing::PublicKey::from(&key)));
+
+        let other = pairing_token(&pairing::PrivateKey::new(), b"a");
+        assert_eq!(registry.verify_bytes(&other), Err(Error::BadSignature));
+
+        let ristretto = curve25519_token(&curve25519::PrivateKey::new(), b"a");
+        assert_eq!(
+            registry.verify_bytes(&ristretto),
+            Err(Error::Malformed(WireError::Ciphersuite))
+        );
+
+        let token = pairing_token(&key, b"a");
+        assert_eq!(
+            registry.verify_bytes(&token[..token.len() - 1]),
+            Err(Error::Malformed(WireError::Truncated))
+        );
+        assert_eq!(
+            registry.verify_bytes(&[]),
+            Err(Error::Malformed(WireError::Ciphersuite))
+        );
+    }
+}
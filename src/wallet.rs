@@ -0,0 +1,868 @@
+//! # A wallet of signed tokens
+//!
+//! A client holding hundreds of tokens should spend the ones that expire first, or they go to
+//! waste, and should spend the tokens of a key before the signer retires it.
+//! The [`Wallet`] keeps the tokens with the epoch of their key, and [`Wallet::select`] takes out a
+//! token by a [`SelectionPolicy`].
+//!
+//! The metadata of the tokens starts with the expiration time, see [`expiry`](crate::expiry), and
+//! the tokens are selected by the data of the application after it. Tokens without an expiration
+//! time are never selected.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::expiry::Metadata;
+//!     use atpmd::wallet::{SelectionPolicy, Wallet};
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!     let now = 1_600_000_000;
+//!
+//!     let mut wallet = Wallet::new();
+//!     for expires_at in [now + 7200, now + 3600] {
+//!         let signed = PairingTokenEngine::sign(
+//!             PairingTokenEngine::generate(Metadata::new(expires_at, b"resource")),
+//!             &public_key,
+//!             |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!         ).unwrap();
+//!         wallet.insert(signed, Some(1));
+//!     }
+//!
+//!     let token = wallet.select(b"resource", now, SelectionPolicy::ClosestToExpiry).unwrap();
+//!     assert_eq!(token.metadata().expires_at(), Some(now + 3600));
+//!     assert_eq!(wallet.count(b"resource", now), 1);
+//! ```
+//!
+//! A client of several verifiers keeps the tokens of each origin apart in a [`ScopedWallet`], such
+//! that a bug in the application can not redeem the tokens of one origin at another and link the
+//! user across them. The origins that trust each other may share tokens with
+//! [`Isolation::Grouped`].
+//!
+//! Before that, a client keeps the tokens it requested in a [`TokenWallet`], with their
+//! randomizations, until the signer answers. The wallet may be serialized while it waits.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::wallet::TokenWallet;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::PairingTokenEngine,
+//!     };
+//!
+//!     type Engine = PairingTokenEngine<Vec<u8>>;
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let mut wallet = TokenWallet::<Engine>::new();
+//!     let id = wallet.insert(Engine::generate(b"resource".to_vec()));
+//!     let request = wallet.request(id).unwrap().clone();
+//!
+//!     // the client restarts while it waits for the signer
+//!     let json = serde_json::to_string(&wallet).unwrap();
+//!     let mut wallet: TokenWallet<Engine> = serde_json::from_str(&json).unwrap();
+//!
+//!     let response = Engine::sign_randomized(&request, &secret_key).unwrap();
+//!     wallet.complete(id, response, &public_key).unwrap();
+//!     assert_eq!(wallet.redeemable().count(), 1);
+//!
+//!     let token = wallet.take(id).unwrap();
+//!     assert!(Engine::verify(&token, &public_key).is_ok());
+//! ```
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{convert::TryInto, fmt};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::common::{Error, KeyEpoch, SignedToken, TokenEngine};
+use crate::expiry;
+
+/// The length of the expiration time at the start of the metadata
+const EXPIRY_LEN: usize = 8;
+
+/// Which of the matching tokens to take out of the wallet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    /// The token that expires first
+    ClosestToExpiry,
+    /// A token of the oldest key epoch, and the one that expires first of those
+    ///
+    /// This empties the wallet of a key before the signer retires it. Tokens of an unknown epoch
+    /// count as the oldest.
+    OldestEpoch,
+    /// A token of the newest key epoch, and the one that expires first of those
+    ///
+    /// For verifiers that have already retired the older keys.
+    NewestEpoch,
+}
+
+struct Entry<T> {
+    token: T,
+    epoch: Option<KeyEpoch>,
+}
+
+impl<T: SignedToken> Entry<T> {
+    fn expires_at(&self) -> u64 {
+        // the entries without one are never selected
+        expiry::expires_at(self.token.metadata_bytes()).unwrap_or(0)
+    }
+
+    fn is_usable(&self, data: &[u8], now: u64) -> bool {
+        let metadata = self.token.metadata_bytes();
+        !expiry::is_expired(metadata, now) && metadata.get(EXPIRY_LEN..) == Some(data)
+    }
+}
+
+/// The tokens of a client, with the epochs of the keys that signed them
+pub struct Wallet<T> {
+    entries: Vec<Entry<T>>,
+}
+
+impl<T: SignedToken> Wallet<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add a token, with the epoch of the key from the response of the signer
+    pub fn insert(&mut self, token: T, epoch: Option<KeyEpoch>) {
+        self.entries.push(Entry { token, epoch });
+    }
+
+    /// Add the tokens of a batch, signed by the same key
+    pub fn extend(&mut self, tokens: impl IntoIterator<Item = T>, epoch: Option<KeyEpoch>) {
+        self.entries
+            .extend(tokens.into_iter().map(|token| Entry { token, epoch }));
+    }
+
+    /// The number of tokens, including expired ones
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of tokens for `data` that have not expired at `now`
+    pub fn count(&self, data: impl AsRef<[u8]>, now: u64) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.is_usable(data.as_ref(), now))
+            .count()
+    }
+
+    /// The number of tokens of each key epoch, e.g. to refill before a key is retired
+    pub fn count_by_epoch(&self) -> BTreeMap<Option<KeyEpoch>, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in &self.entries {
+            *counts.entry(entry.epoch).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Take out a token for `data` that has not expired at `now`
+    ///
+    /// Returns `None` when the wallet needs a refill.
+    pub fn select(
+        &mut self,
+        data: impl AsRef<[u8]>,
+        now: u64,
+        policy: SelectionPolicy,
+    ) -> Option<T> {
+        let usable = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_i, entry)| entry.is_usable(data.as_ref(), now));
+
+        let (index, _entry) = match policy {
+            SelectionPolicy::ClosestToExpiry => usable.min_by_key(|(_i, entry)| entry.expires_at()),
+            SelectionPolicy::OldestEpoch => {
+                usable.min_by_key(|(_i, entry)| (entry.epoch, entry.expires_at()))
+            }
+            SelectionPolicy::NewestEpoch => usable
+                .min_by_key(|(_i, entry)| (core::cmp::Reverse(entry.epoch), entry.expires_at())),
+        }?;
+
+        // a token is never used twice
+        Some(self.entries.swap_remove(index).token)
+    }
+
+    /// Throw away the tokens that have expired at `now`, and return how many there were
+    pub fn prune(&mut self, now: u64) -> usize {
+        let len = self.entries.len();
+        self.entries
+            .retain(|entry| !expiry::is_expired(entry.token.metadata_bytes(), now));
+        len - self.entries.len()
+    }
+
+    /// Throw away the tokens of a retired key, and return how many there were
+    pub fn retire_epoch(&mut self, epoch: KeyEpoch) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|entry| entry.epoch != Some(epoch));
+        len - self.entries.len()
+    }
+}
+
+impl<T: SignedToken> Default for Wallet<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which origins a [`ScopedWallet`] offers the tokens of an origin to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isolation {
+    /// Only the origin the tokens were issued for
+    Strict,
+    /// Also the origins that are joined to it with [`ScopedWallet::join`]
+    Grouped,
+}
+
+/// A wallet for each verifier origin
+///
+/// There is no way to select a token without naming the origin, so the tokens of one origin are
+/// never offered to another unless the origins are joined.
+pub struct ScopedWallet<T> {
+    isolation: Isolation,
+    /// The origins that use the wallet of another origin
+    joined: BTreeMap<Box<[u8]>, Box<[u8]>>,
+    wallets: BTreeMap<Box<[u8]>, Wallet<T>>,
+}
+
+impl<T: SignedToken> ScopedWallet<T> {
+    pub fn new(isolation: Isolation) -> Self {
+        Self {
+            isolation,
+            joined: BTreeMap::new(),
+            wallets: BTreeMap::new(),
+        }
+    }
+
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    /// The origin whose wallet `origin` uses
+    fn scope<'a>(&'a self, origin: &'a [u8]) -> &'a [u8] {
+        self.joined.get(origin).map_or(origin, |scope| scope)
+    }
+
+    /// Let `origin` use the tokens of `scope`, and put its tokens there
+    ///
+    /// Returns false with [`Isolation::Strict`], or if `origin` already has tokens or other
+    /// origins joined to it.
+    pub fn join(&mut self, origin: impl AsRef<[u8]>, scope: impl AsRef<[u8]>) -> bool {
+        let origin = origin.as_ref();
+        let scope = Box::from(self.scope(scope.as_ref()));
+
+        if self.isolation == Isolation::Strict
+            || self.wallets.contains_key(origin)
+            || self.joined.values().any(|joined| &**joined == origin)
+        {
+            return false;
+        }
+        if *scope != *origin {
+            self.joined.insert(Box::from(origin), scope);
+        }
+        true
+    }
+
+    /// Add a token that was issued for `origin`
+    pub fn insert(&mut self, origin: impl AsRef<[u8]>, token: T, epoch: Option<KeyEpoch>) {
+        self.wallet_mut(origin.as_ref()).insert(token, epoch);
+    }
+
+    /// Add the tokens of a batch for `origin`, signed by the same key
+    pub fn extend(
+        &mut self,
+        origin: impl AsRef<[u8]>,
+        tokens: impl IntoIterator<Item = T>,
+        epoch: Option<KeyEpoch>,
+    ) {
+        self.wallet_mut(origin.as_ref()).extend(tokens, epoch);
+    }
+
+    fn wallet_mut(&mut self, origin: &[u8]) -> &mut Wallet<T> {
+        let scope = Box::from(self.scope(origin));
+        self.wallets.entry(scope).or_default()
+    }
+
+    /// The tokens `origin` may use, e.g. for [`Wallet::count_by_epoch`]
+    pub fn wallet(&self, origin: impl AsRef<[u8]>) -> Option<&Wallet<T>> {
+        self.wallets.get(self.scope(origin.as_ref()))
+    }
+
+    /// The number of tokens in all the wallets, including expired ones
+    pub fn len(&self) -> usize {
+        self.wallets.values().map(Wallet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// [`Wallet::count`] of the tokens `origin` may use
+    pub fn count(&self, origin: impl AsRef<[u8]>, data: impl AsRef<[u8]>, now: u64) -> usize {
+        self.wallet(origin)
+            .map_or(0, |wallet| wallet.count(data, now))
+    }
+
+    /// [`Wallet::select`] of the tokens `origin` may use
+    pub fn select(
+        &mut self,
+        origin: impl AsRef<[u8]>,
+        data: impl AsRef<[u8]>,
+        now: u64,
+        policy: SelectionPolicy,
+    ) -> Option<T> {
+        let scope: Box<[u8]> = Box::from(self.scope(origin.as_ref()));
+        self.wallets.get_mut(&scope)?.select(data, now, policy)
+    }
+
+    /// [`Wallet::prune`] of every wallet
+    pub fn prune(&mut self, now: u64) -> usize {
+        self.wallets
+            .values_mut()
+            .map(|wallet| wallet.prune(now))
+            .sum()
+    }
+
+    /// [`Wallet::retire_epoch`] of every wallet
+    pub fn retire_epoch(&mut self, epoch: KeyEpoch) -> usize {
+        self.wallets
+            .values_mut()
+            .map(|wallet| wallet.retire_epoch(epoch))
+            .sum()
+    }
+}
+
+/// A randomization that a [`TokenWallet`] can store
+///
+/// The randomization unlinks the request from the token, so the stored bytes are as secret as
+/// the token.
+pub trait StoredRandomization: Sized {
+    fn to_stored(&self) -> Vec<u8>;
+
+    /// The randomization of the bytes, none if they are not one
+    fn from_stored(bytes: &[u8]) -> Option<Self>;
+}
+
+/// The seeds of the batches
+impl StoredRandomization for [u8; 32] {
+    fn to_stored(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn from_stored(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+#[cfg(feature = "pairing")]
+impl StoredRandomization for bls12_381::Scalar {
+    fn to_stored(&self) -> Vec<u8> {
+        self.to_bytes().to_vec()
+    }
+
+    fn from_stored(bytes: &[u8]) -> Option<Self> {
+        Option::from(Self::from_bytes(&bytes.try_into().ok()?))
+    }
+}
+
+#[cfg(feature = "curve25519")]
+impl StoredRandomization for curve25519_dalek::scalar::Scalar {
+    fn to_stored(&self) -> Vec<u8> {
+        self.to_bytes().to_vec()
+    }
+
+    fn from_stored(bytes: &[u8]) -> Option<Self> {
+        Self::from_canonical_bytes(bytes.try_into().ok()?)
+    }
+}
+
+#[cfg(feature = "nizkp_p256")]
+impl StoredRandomization for p256::Scalar {
+    fn to_stored(&self) -> Vec<u8> {
+        use elliptic_curve::group::ff::PrimeField;
+        self.to_repr().to_vec()
+    }
+
+    fn from_stored(bytes: &[u8]) -> Option<Self> {
+        use elliptic_curve::group::ff::PrimeField;
+        if bytes.len() != 32 {
+            return None;
+        }
+        let mut repr = p256::FieldBytes::default();
+        repr.copy_from_slice(bytes);
+        Self::from_repr(repr)
+    }
+}
+
+mod stored {
+    use super::StoredRandomization;
+    use alloc::vec::Vec;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use zeroize::Zeroize;
+
+    pub(super) fn serialize<R: StoredRandomization, S: Serializer>(
+        randomization: &R,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut bytes = randomization.to_stored();
+        let result = bytes.serialize(serializer);
+        bytes.zeroize();
+        result
+    }
+
+    pub(super) fn deserialize<'de, R: StoredRandomization, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<R, D::Error> {
+        let mut bytes = Vec::<u8>::deserialize(deserializer)?;
+        let randomization = R::from_stored(&bytes);
+        bytes.zeroize();
+        randomization.ok_or_else(|| de::Error::custom("invalid randomization"))
+    }
+}
+
+/// An entry of a [`TokenWallet`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct EntryId(u64);
+
+/// Why an entry of a [`TokenWallet`] was not signed or taken out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletError {
+    /// There is no entry with the id, or it has been taken out
+    Unknown,
+    /// The randomization of the entry has already been used
+    Consumed,
+    /// The entry is waiting for the signature
+    NotSigned,
+    /// The signature does not verify, the entry is still waiting for the signature
+    Verify(Error),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalletError::Unknown => write!(f, "There is no such entry in the wallet"),
+            WalletError::Consumed => write!(f, "The randomization has already been used"),
+            WalletError::NotSigned => write!(f, "The token has not been signed"),
+            WalletError::Verify(error) => write!(f, "The signature does not verify: {}", error),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "E::UnsignedToken: Serialize, E::RandomizedUnsignedToken: Serialize, \
+                 E::SignedToken: Serialize, E::Randomization: StoredRandomization",
+    deserialize = "E::UnsignedToken: DeserializeOwned, E::RandomizedUnsignedToken: DeserializeOwned, \
+                   E::SignedToken: DeserializeOwned, E::Randomization: StoredRandomization"
+))]
+enum TokenState<E: TokenEngine> {
+    Pending {
+        unsigned_token: E::UnsignedToken,
+        randomized: E::RandomizedUnsignedToken,
+        #[serde(with = "stored")]
+        randomization: E::Randomization,
+    },
+    Signed(E::SignedToken),
+}
+
+/// The tokens of a client of the engine `E`, from the request to the redemption
+///
+/// An entry holds the unsigned token with its randomization until the response of the signer,
+/// and then the signed token. The randomization is taken out when the signature verifies, so it
+/// is never used twice. The wallet is serialized between the request and the response, e.g. when
+/// the client waits for the signer across restarts.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "TokenState<E>: Serialize",
+    deserialize = "TokenState<E>: DeserializeOwned"
+))]
+pub struct TokenWallet<E: TokenEngine> {
+    next: u64,
+    entries: BTreeMap<u64, TokenState<E>>,
+}
+
+impl<E: TokenEngine> TokenWallet<E> {
+    pub fn new() -> Self {
+        Self {
+            next: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Randomize an unsigned token, the request is [`TokenWallet::request`] of the entry
+    pub fn insert(&mut self, unsigned_token: E::UnsignedToken) -> EntryId {
+        let (randomization, randomized) = E::randomize(&unsigned_token);
+        let id = self.next;
+        self.next += 1;
+
+        self.entries.insert(
+            id,
+            TokenState::Pending {
+                unsigned_token,
+                randomized,
+                randomization,
+            },
+        );
+        EntryId(id)
+    }
+
+    /// The request to send to the signer, none if the entry is not waiting for the signature
+    pub fn request(&self, id: EntryId) -> Option<&E::RandomizedUnsignedToken> {
+        match self.entries.get(&id.0)? {
+            TokenState::Pending { randomized, .. } => Some(randomized),
+            TokenState::Signed(_) => None,
+        }
+    }
+
+    /// Check the signature of the entry and remove the randomization
+    ///
+    /// The entry keeps waiting for the signature if it does not verify.
+    pub fn complete(
+        &mut self,
+        id: EntryId,
+        signed: E::RandomizedSignedToken,
+        verification_data: &E::UserVerification,
+    ) -> Result<(), WalletError> {
+        let (unsigned_token, randomized, randomization) =
+            match self.entries.remove(&id.0).ok_or(WalletError::Unknown)? {
+                TokenState::Pending {
+                    unsigned_token,
+                    randomized,
+                    randomization,
+                } => (unsigned_token, randomized, randomization),
+                state => {
+                    self.entries.insert(id.0, state);
+                    return Err(WalletError::Consumed);
+                }
+            };
+
+        let (state, result) = match E::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized,
+            signed,
+            verification_data,
+            randomization,
+        ) {
+            Ok(token) => (TokenState::Signed(token), Ok(())),
+            Err(e) => (
+                TokenState::Pending {
+                    unsigned_token: e.unsigned_token,
+                    randomized: e.randomized_unsigned,
+                    randomization: e.randomization,
+                },
+                Err(WalletError::Verify(e.error)),
+            ),
+        };
+        self.entries.insert(id.0, state);
+        result
+    }
+
+    /// The entries waiting for the signature, with the requests to send again
+    pub fn pending(&self) -> impl Iterator<Item = (EntryId, &E::RandomizedUnsignedToken)> + '_ {
+        self.entries.iter().filter_map(|(id, state)| match state {
+            TokenState::Pending { randomized, .. } => Some((EntryId(*id), randomized)),
+            TokenState::Signed(_) => None,
+        })
+    }
+
+    /// The signed tokens, to pick one to take out and redeem
+    pub fn redeemable(&self) -> impl Iterator<Item = (EntryId, &E::SignedToken)> + '_ {
+        self.entries.iter().filter_map(|(id, state)| match state {
+            TokenState::Signed(token) => Some((EntryId(*id), token)),
+            TokenState::Pending { .. } => None,
+        })
+    }
+
+    /// Take out a signed token to redeem it, so it is not redeemed twice
+    pub fn take(&mut self, id: EntryId) -> Result<E::SignedToken, WalletError> {
+        match self.entries.remove(&id.0).ok_or(WalletError::Unknown)? {
+            TokenState::Signed(token) => Ok(token),
+            state => {
+                self.entries.insert(id.0, state);
+                Err(WalletError::NotSigned)
+            }
+        }
+    }
+
+    /// Throw away an entry, e.g. a request the signer refused
+    pub fn remove(&mut self, id: EntryId) -> bool {
+        self.entries.remove(&id.0).is_some()
+    }
+
+    /// The number of entries, pending and signed
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<E: TokenEngine> Default for TokenWallet<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::atpm_pairing::{
+        keys::{PrivateKey, PublicKey},
+        tokens::PairingTokenEngine,
+    };
+    use crate::expiry::Metadata;
+
+    /// A token without a signature, the wallet does not verify
+    struct Dummy(Metadata);
+
+    impl SignedToken for Dummy {
+        type VerificationKey = ();
+
+        fn verify(&self, _verification_key: &()) -> bool {
+            true
+        }
+
+        fn metadata_bytes(&self) -> &[u8] {
+            self.0.as_ref()
+        }
+    }
+
+    fn filled() -> Wallet<Dummy> {
+        let mut wallet = Wallet::new();
+        wallet.insert(Dummy(Metadata::new(300, b"a")), Some(2));
+        wallet.insert(Dummy(Metadata::new(200, b"a")), Some(2));
+        wallet.insert(Dummy(Metadata::new(400, b"a")), Some(1));
+        wallet.insert(Dummy(Metadata::new(100, b"a")), Some(3));
+        wallet.insert(Dummy(Metadata::new(50, b"b")), Some(1));
+        wallet
+    }
+
+    fn expiry(token: Option<Dummy>) -> Option<u64> {
+        token.and_then(|token| token.0.expires_at())
+    }
+
+    #[test]
+    fn test_policies() {
+        let mut wallet = filled();
+        let closest = wallet.select(b"a", 0, SelectionPolicy::ClosestToExpiry);
+        assert_eq!(expiry(closest), Some(100));
+
+        let mut wallet = filled();
+        let oldest = wallet.select(b"a", 0, SelectionPolicy::OldestEpoch);
+        assert_eq!(expiry(oldest), Some(400));
+
+        let mut wallet = filled();
+        let newest = wallet.select(b"a", 0, SelectionPolicy::NewestEpoch);
+        assert_eq!(expiry(newest), Some(100));
+        let newest = wallet.select(b"a", 0, SelectionPolicy::NewestEpoch);
+        assert_eq!(expiry(newest), Some(200));
+    }
+
+    #[test]
+    fn test_expired() {
+        let mut wallet = filled();
+
+        assert_eq!(wallet.count(b"a", 150), 3);
+        assert_eq!(wallet.count(b"b", 150), 0);
+        assert_eq!(
+            expiry(wallet.select(b"a", 150, SelectionPolicy::ClosestToExpiry)),
+            Some(200)
+        );
+        assert!(wallet
+            .select(b"b", 150, SelectionPolicy::ClosestToExpiry)
+            .is_none());
+
+        assert_eq!(wallet.prune(150), 2);
+        assert_eq!(wallet.len(), 2);
+    }
+
+    #[test]
+    fn test_epochs() {
+        let mut wallet = filled();
+        wallet.insert(Dummy(Metadata::from(&b"short"[..])), None);
+
+        let counts = wallet.count_by_epoch();
+        assert_eq!(counts.get(&Some(2)), Some(&2));
+        assert_eq!(counts.get(&None), Some(&1));
+
+        // tokens without an expiration time are never selected
+        assert_eq!(wallet.count(b"", 0), 0);
+
+        assert_eq!(wallet.retire_epoch(1), 2);
+        assert_eq!(wallet.len(), 4);
+        assert_eq!(
+            expiry(wallet.select(b"a", 0, SelectionPolicy::OldestEpoch)),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn test_scoped() {
+        let mut wallet = ScopedWallet::new(Isolation::Strict);
+        wallet.insert(b"org-a", Dummy(Metadata::new(100, b"a")), Some(1));
+        wallet.extend(
+            b"org-b",
+            filled().entries.into_iter().map(|e| e.token),
+            Some(1),
+        );
+        assert!(!wallet.join(b"org-c", b"org-a"));
+
+        // the tokens of org-b are never offered to org-a
+        assert_eq!(wallet.count(b"org-a", b"a", 0), 1);
+        assert!(wallet
+            .select(b"org-a", b"a", 0, SelectionPolicy::ClosestToExpiry)
+            .is_some());
+        assert!(wallet
+            .select(b"org-a", b"a", 0, SelectionPolicy::ClosestToExpiry)
+            .is_none());
+        assert!(wallet
+            .select(b"org-c", b"a", 0, SelectionPolicy::ClosestToExpiry)
+            .is_none());
+        assert_eq!(wallet.count(b"org-b", b"a", 0), 4);
+
+        assert_eq!(wallet.len(), 5);
+        assert_eq!(wallet.prune(150), 2);
+        assert_eq!(wallet.retire_epoch(1), 3);
+        assert!(wallet.is_empty());
+    }
+
+    #[test]
+    fn test_grouped() {
+        let mut wallet = ScopedWallet::new(Isolation::Grouped);
+        wallet.insert(b"www.org", Dummy(Metadata::new(100, b"a")), None);
+        wallet.insert(b"other.org", Dummy(Metadata::new(100, b"a")), None);
+
+        assert!(wallet.join(b"api.org", b"www.org"));
+        assert!(wallet.join(b"cdn.org", b"api.org"));
+        // the origins that have tokens, or others joined to them, are kept
+        assert!(!wallet.join(b"other.org", b"www.org"));
+        assert!(!wallet.join(b"www.org", b"other.org"));
+
+        wallet.insert(b"cdn.org", Dummy(Metadata::new(200, b"a")), None);
+        assert_eq!(wallet.count(b"api.org", b"a", 0), 2);
+        assert_eq!(wallet.count(b"other.org", b"a", 0), 1);
+        assert_eq!(
+            expiry(wallet.select(b"cdn.org", b"a", 0, SelectionPolicy::ClosestToExpiry)),
+            Some(100)
+        );
+        assert_eq!(wallet.wallet(b"www.org").map(Wallet::len), Some(1));
+    }
+
+    #[test]
+    fn test_token_wallet() {
+        type Engine = PairingTokenEngine<Vec<u8>>;
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let mut wallet = TokenWallet::<Engine>::new();
+        let first = wallet.insert(Engine::generate(b"a".to_vec()));
+        let second = wallet.insert(Engine::generate(b"b".to_vec()));
+        assert_eq!(wallet.pending().count(), 2);
+        assert_eq!(wallet.take(first).err(), Some(WalletError::NotSigned));
+
+        let requests: Vec<_> = wallet
+            .pending()
+            .map(|(id, request)| (id, request.clone()))
+            .collect();
+        let json = serde_json::to_string(&wallet).unwrap();
+        let mut wallet: TokenWallet<Engine> = serde_json::from_str(&json).unwrap();
+
+        for (id, request) in requests {
+            let response = Engine::sign_randomized(&request, &secret_key).unwrap();
+            wallet.complete(id, response, &public_key).unwrap();
+
+            // the randomization is gone
+            let response = Engine::sign_randomized(&request, &secret_key).unwrap();
+            assert_eq!(
+                wallet.complete(id, response, &public_key),
+                Err(WalletError::Consumed)
+            );
+        }
+        assert_eq!(wallet.redeemable().count(), 2);
+        assert_eq!(wallet.pending().count(), 0);
+
+        // a token is taken out once
+        let token = wallet.take(second).unwrap();
+        assert_eq!(token.metadata(), b"b");
+        assert!(Engine::verify(&token, &public_key).is_ok());
+        assert_eq!(wallet.take(second).err(), Some(WalletError::Unknown));
+
+        let json = serde_json::to_string(&wallet).unwrap();
+        let wallet: TokenWallet<Engine> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            wallet
+                .redeemable()
+                .map(|(id, _token)| id)
+                .collect::<Vec<_>>(),
+            [first]
+        );
+    }
+
+    #[test]
+    fn fail_token_wallet() {
+        type Engine = PairingTokenEngine<Vec<u8>>;
+        let secret_key = PrivateKey::new();
+        let public_key = PublicKey::from(&secret_key);
+
+        let mut wallet = TokenWallet::<Engine>::new();
+        let id = wallet.insert(Engine::generate(b"a".to_vec()));
+        let request = wallet.request(id).unwrap().clone();
+
+        // signed with another key, the entry keeps waiting
+        let response = Engine::sign_randomized(&request, &PrivateKey::new()).unwrap();
+        assert_eq!(
+            wallet.complete(id, response, &public_key),
+            Err(WalletError::Verify(Error::BadSignature))
+        );
+        assert!(wallet.request(id).is_some());
+
+        let response = Engine::sign_randomized(&request, &secret_key).unwrap();
+        assert!(wallet.complete(id, response, &public_key).is_ok());
+
+        assert!(wallet.remove(id));
+        assert!(wallet.is_empty());
+        let response = Engine::sign_randomized(&request, &secret_key).unwrap();
+        assert_eq!(
+            wallet.complete(id, response, &public_key),
+            Err(WalletError::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_stored() {
+        let seed = [7; 32];
+        assert_eq!(<[u8; 32]>::from_stored(&seed.to_stored()), Some(seed));
+        assert_eq!(<[u8; 32]>::from_stored(&[7; 31]), None);
+
+        let scalar = bls12_381::Scalar::from(7);
+        assert_eq!(
+            bls12_381::Scalar::from_stored(&scalar.to_stored()),
+            Some(scalar)
+        );
+        assert_eq!(bls12_381::Scalar::from_stored(&[0xff; 32]), None);
+
+        let scalar = curve25519_dalek::scalar::Scalar::from(7u64);
+        assert_eq!(
+            curve25519_dalek::scalar::Scalar::from_stored(&scalar.to_stored()),
+            Some(scalar)
+        );
+        assert_eq!(
+            curve25519_dalek::scalar::Scalar::from_stored(&[0xff; 32]),
+            None
+        );
+    }
+}
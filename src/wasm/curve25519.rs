@@ -0,0 +1,201 @@
+//! The curve25519 engine for JS, the tokens are verified with the private key of the issuer
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use wasm_bindgen::prelude::*;
+
+use super::{decode_json, encode_json, js_error, Pending};
+use crate::nizkp_curve25519::{
+    keys::{PrivateKey, PublicKey},
+    tokens::{NizkpSignedToken, NizkpTokenEngine, RandomizedSignedToken},
+};
+use crate::wire::WireFormat;
+use crate::{SignedToken, TokenEngine};
+
+type Engine = NizkpTokenEngine<Box<[u8]>>;
+
+// {{{ Keys
+
+/// The public key of an issuer, the users check the proofs of the issuer with it
+#[wasm_bindgen]
+pub struct NizkpPublicKey(PublicKey);
+
+#[wasm_bindgen]
+impl NizkpPublicKey {
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<NizkpPublicKey, JsValue> {
+        decode_json(json).map(Self)
+    }
+
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<NizkpPublicKey, JsValue> {
+        PublicKey::from_bytes(bytes).map(Self).map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        encode_json(&self.0)
+    }
+
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+}
+
+/// The private key of an issuer, which is needed to verify the tokens
+#[wasm_bindgen]
+pub struct NizkpPrivateKey(PrivateKey);
+
+#[wasm_bindgen]
+impl NizkpPrivateKey {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> NizkpPrivateKey {
+        Self(PrivateKey::new())
+    }
+
+    /// Parse the key the issuer stored, this needs the `private_key_serde` feature
+    #[cfg(feature = "private_key_serde")]
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<NizkpPrivateKey, JsValue> {
+        decode_json(json).map(Self)
+    }
+
+    #[wasm_bindgen(js_name = publicKey)]
+    pub fn public_key(&self) -> NizkpPublicKey {
+        NizkpPublicKey(PublicKey::from(&self.0))
+    }
+}
+
+impl Default for NizkpPrivateKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// }}}
+
+// {{{ Request
+
+/// A token that is generated and randomized, to be sent to the issuer
+#[wasm_bindgen]
+pub struct NizkpTokenRequest(Pending<Engine>);
+
+#[wasm_bindgen]
+impl NizkpTokenRequest {
+    #[wasm_bindgen(constructor)]
+    pub fn new(metadata: &[u8]) -> NizkpTokenRequest {
+        Self(Pending::new(Engine::generate(Box::from(metadata))))
+    }
+
+    /// A token with hidden metadata, which the issuer does not see
+    #[wasm_bindgen(js_name = withHidden)]
+    pub fn with_hidden(metadata: &[u8], hidden: &[u8]) -> NizkpTokenRequest {
+        Self(Pending::new(Engine::generate_with_hidden(
+            Box::from(metadata),
+            Box::from(hidden),
+        )))
+    }
+
+    /// The randomized token for the issuer
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        encode_json(self.0.randomized()?)
+    }
+
+    /// The randomized token for the issuer, in the wire format
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, JsValue> {
+        self.0.randomized()?.try_to_bytes().map_err(js_error)
+    }
+
+    /// Verify the proof of the issuer, and remove the randomization
+    pub fn unrandomize(
+        &mut self,
+        response: &str,
+        key: &NizkpPublicKey,
+    ) -> Result<NizkpToken, JsValue> {
+        let signed: RandomizedSignedToken<Box<[u8]>> = decode_json(response)?;
+        self.0.unrandomize(signed, &key.0).map(NizkpToken)
+    }
+
+    /// [`Self::unrandomize`], with the response in the wire format
+    #[wasm_bindgen(js_name = unrandomizeBytes)]
+    pub fn unrandomize_bytes(
+        &mut self,
+        response: &[u8],
+        key: &NizkpPublicKey,
+    ) -> Result<NizkpToken, JsValue> {
+        let signed = RandomizedSignedToken::from_bytes(response).map_err(js_error)?;
+        self.0.unrandomize(signed, &key.0).map(NizkpToken)
+    }
+}
+
+// }}}
+
+// {{{ Token
+
+/// A signed token
+#[wasm_bindgen]
+pub struct NizkpToken(NizkpSignedToken<Box<[u8]>>);
+
+#[wasm_bindgen]
+impl NizkpToken {
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<NizkpToken, JsValue> {
+        decode_json(json).map(Self)
+    }
+
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<NizkpToken, JsValue> {
+        NizkpSignedToken::from_bytes(bytes)
+            .map(Self)
+            .map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        encode_json(&self.0)
+    }
+
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, JsValue> {
+        self.0.try_to_bytes().map_err(js_error)
+    }
+
+    /// The public metadata
+    pub fn metadata(&self) -> Vec<u8> {
+        self.0.metadata_bytes().to_vec()
+    }
+
+    pub fn verify(&self, key: &NizkpPrivateKey) -> bool {
+        Engine::verify(&self.0, &key.0).is_ok()
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let private_key = NizkpPrivateKey::new();
+        let key = NizkpPublicKey::from_json(&private_key.public_key().to_json().unwrap()).unwrap();
+
+        let mut request = NizkpTokenRequest::with_hidden(b"resource", b"hidden");
+        let randomized = decode_json(&request.to_json().unwrap()).unwrap();
+        let signed = Engine::sign_randomized(&randomized, &private_key.0).unwrap();
+
+        let token = request
+            .unrandomize(&encode_json(&signed).unwrap(), &key)
+            .unwrap();
+        assert!(token.verify(&private_key));
+        assert_eq!(token.metadata(), b"resource");
+
+        let token = NizkpToken::from_bytes(&token.to_bytes().unwrap()).unwrap();
+        assert!(token.verify(&private_key));
+        assert!(!token.verify(&NizkpPrivateKey::new()));
+    }
+}
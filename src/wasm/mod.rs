@@ -0,0 +1,101 @@
+//! # WASM bindings
+//!
+//! [`wasm_bindgen`] wrappers of the pairing and curve25519 engines, for web pages and other JS
+//! hosts. A token request is generated and randomized in one go, unrandomized with the response of
+//! the issuer, and the signed token may then be verified:
+//!
+//! ```js
+//! import { PairingPublicKey, PairingTokenRequest } from "atpmd";
+//!
+//! const key = PairingPublicKey.fromJson(await (await fetch("/keys/public")).text());
+//! const request = new PairingTokenRequest(new TextEncoder().encode("resource"));
+//!
+//! // an issuer that answers with the randomized signed token
+//! const response = await fetch("/sign", { method: "POST", body: request.toJson() });
+//! const token = request.unrandomize(await response.text(), key);
+//!
+//! console.log(token.verify(key), token.toJson());
+//! ```
+//!
+//! The metadata is a `Uint8Array`, and the keys, requests, responses and tokens are either JSON
+//! strings, as the examples send them, or `Uint8Array`s in the [wire format](crate::wire). The
+//! errors are thrown as strings.
+//!
+//! A request that is unrandomized with a response that does not verify is kept, so it may be
+//! unrandomized with another response, see [`VerifyError`].
+
+use alloc::string::{String, ToString};
+use core::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::common::{TokenEngine, VerifyError};
+
+#[cfg(feature = "curve25519")]
+pub mod curve25519;
+#[cfg(feature = "pairings")]
+pub mod pairing;
+
+/// The error of a request that has already been unrandomized
+const UNRANDOMIZED: &str = "the token has already been unrandomized";
+
+/// The error thrown to JS
+fn js_error(e: impl fmt::Display) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+fn decode_json<T: DeserializeOwned>(json: &str) -> Result<T, JsValue> {
+    serde_json::from_str(json).map_err(js_error)
+}
+
+fn encode_json<T: Serialize>(value: &T) -> Result<String, JsValue> {
+    serde_json::to_string(value).map_err(js_error)
+}
+
+/// A randomized token, waiting for the signature of the issuer
+struct Pending<E: TokenEngine> {
+    state: Option<(
+        E::UnsignedToken,
+        E::RandomizedUnsignedToken,
+        E::Randomization,
+    )>,
+}
+
+impl<E: TokenEngine> Pending<E> {
+    fn new(unsigned_token: E::UnsignedToken) -> Self {
+        let (r, randomized) = E::randomize(&unsigned_token);
+        Self {
+            state: Some((unsigned_token, randomized, r)),
+        }
+    }
+
+    fn randomized(&self) -> Result<&E::RandomizedUnsignedToken, JsValue> {
+        match &self.state {
+            Some((_, randomized, _)) => Ok(randomized),
+            None => Err(js_error(UNRANDOMIZED)),
+        }
+    }
+
+    /// Check the signature and remove the randomization, the token is kept if it fails
+    fn unrandomize(
+        &mut self,
+        signed: E::RandomizedSignedToken,
+        verification_data: &E::UserVerification,
+    ) -> Result<E::SignedToken, JsValue> {
+        let (unsigned_token, randomized, r) =
+            self.state.take().ok_or_else(|| js_error(UNRANDOMIZED))?;
+
+        E::verify_signature_and_unrandomize(
+            unsigned_token,
+            randomized,
+            signed,
+            verification_data,
+            r,
+        )
+        .map_err(|e: VerifyError<E>| {
+            self.state = Some((e.unsigned_token, e.randomized_unsigned, e.randomization));
+            js_error(e.error)
+        })
+    }
+}
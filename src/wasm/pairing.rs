@@ -0,0 +1,171 @@
+//! The pairing engine for JS, the tokens are verified with the public key
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use wasm_bindgen::prelude::*;
+
+use super::{decode_json, encode_json, js_error, Pending};
+use crate::atpm_pairing::{
+    keys::PublicKey,
+    tokens::{PairingSignedToken, PairingTokenEngine, RandomizedSignedToken},
+};
+use crate::wire::WireFormat;
+use crate::{SignedToken, TokenEngine};
+
+type Engine = PairingTokenEngine<Box<[u8]>>;
+
+// {{{ Keys
+
+/// The public key of an issuer
+#[wasm_bindgen]
+pub struct PairingPublicKey(PublicKey);
+
+#[wasm_bindgen]
+impl PairingPublicKey {
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<PairingPublicKey, JsValue> {
+        decode_json(json).map(Self)
+    }
+
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<PairingPublicKey, JsValue> {
+        PublicKey::from_bytes(bytes).map(Self).map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        encode_json(&self.0)
+    }
+
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes()
+    }
+}
+
+// }}}
+
+// {{{ Request
+
+/// A token that is generated and randomized, to be sent to the issuer
+#[wasm_bindgen]
+pub struct PairingTokenRequest(Pending<Engine>);
+
+#[wasm_bindgen]
+impl PairingTokenRequest {
+    #[wasm_bindgen(constructor)]
+    pub fn new(metadata: &[u8]) -> PairingTokenRequest {
+        Self(Pending::new(Engine::generate(Box::from(metadata))))
+    }
+
+    /// A token with hidden metadata, which the issuer does not see
+    #[wasm_bindgen(js_name = withHidden)]
+    pub fn with_hidden(metadata: &[u8], hidden: &[u8]) -> PairingTokenRequest {
+        Self(Pending::new(Engine::generate_with_hidden(
+            Box::from(metadata),
+            Box::from(hidden),
+        )))
+    }
+
+    /// The randomized token for the issuer
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        encode_json(self.0.randomized()?)
+    }
+
+    /// The randomized token for the issuer, in the wire format
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, JsValue> {
+        self.0.randomized()?.try_to_bytes().map_err(js_error)
+    }
+
+    /// Verify the signature of the issuer, and remove the randomization
+    pub fn unrandomize(
+        &mut self,
+        response: &str,
+        key: &PairingPublicKey,
+    ) -> Result<PairingToken, JsValue> {
+        let signed: RandomizedSignedToken<Box<[u8]>> = decode_json(response)?;
+        self.0.unrandomize(signed, &key.0).map(PairingToken)
+    }
+
+    /// [`Self::unrandomize`], with the response in the wire format
+    #[wasm_bindgen(js_name = unrandomizeBytes)]
+    pub fn unrandomize_bytes(
+        &mut self,
+        response: &[u8],
+        key: &PairingPublicKey,
+    ) -> Result<PairingToken, JsValue> {
+        let signed = RandomizedSignedToken::from_bytes(response).map_err(js_error)?;
+        self.0.unrandomize(signed, &key.0).map(PairingToken)
+    }
+}
+
+// }}}
+
+// {{{ Token
+
+/// A signed token
+#[wasm_bindgen]
+pub struct PairingToken(PairingSignedToken<Box<[u8]>>);
+
+#[wasm_bindgen]
+impl PairingToken {
+    #[wasm_bindgen(js_name = fromJson)]
+    pub fn from_json(json: &str) -> Result<PairingToken, JsValue> {
+        decode_json(json).map(Self)
+    }
+
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<PairingToken, JsValue> {
+        PairingSignedToken::from_bytes(bytes)
+            .map(Self)
+            .map_err(js_error)
+    }
+
+    #[wasm_bindgen(js_name = toJson)]
+    pub fn to_json(&self) -> Result<String, JsValue> {
+        encode_json(&self.0)
+    }
+
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, JsValue> {
+        self.0.try_to_bytes().map_err(js_error)
+    }
+
+    /// The public metadata
+    pub fn metadata(&self) -> Vec<u8> {
+        self.0.metadata_bytes().to_vec()
+    }
+
+    pub fn verify(&self, key: &PairingPublicKey) -> bool {
+        Engine::verify(&self.0, &key.0).is_ok()
+    }
+}
+
+// }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::atpm_pairing::keys::PrivateKey;
+
+    #[test]
+    fn test_request() {
+        let private_key = PrivateKey::new();
+        let key = PairingPublicKey::from_bytes(&PublicKey::from(&private_key).to_bytes()).unwrap();
+
+        let mut request = PairingTokenRequest::new(b"resource");
+        let randomized = decode_json(&request.to_json().unwrap()).unwrap();
+        let signed = Engine::sign_randomized(&randomized, &private_key).unwrap();
+
+        let token = request.unrandomize_bytes(&signed.to_bytes(), &key).unwrap();
+        assert!(token.verify(&key));
+        assert_eq!(token.metadata(), b"resource");
+
+        let token = PairingToken::from_json(&token.to_json().unwrap()).unwrap();
+        assert!(token.verify(&key));
+        assert!(!token.verify(&PairingPublicKey(PublicKey::from(PrivateKey::new()))));
+    }
+}
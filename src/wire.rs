@@ -0,0 +1,433 @@
+//! # Compact binary encoding
+//!
+//! JSON encodes every byte of a curve point as a number, which more than triples the size of a
+//! token. This is a canonical binary encoding, small enough for QR codes and NFC tags.
+//!
+//! - Points are compressed, and are checked to be valid when decoded.
+//! - Metadata is prefixed with its length as a big endian `u16`.
+//! - Every encoding starts with a byte of the [`Ciphersuite`] and a byte identifying the type, so
+//!   a token of another engine is named as such, and a public key can not be decoded as a token.
+//!
+//! ```
+//!     use atpmd::TokenEngine;
+//!     use atpmd::wire::WireFormat;
+//!     use atpmd::atpm_pairing::{
+//!         keys::{PrivateKey, PublicKey},
+//!         tokens::{PairingSignedToken, PairingTokenEngine},
+//!     };
+//!
+//!     let secret_key = PrivateKey::new();
+//!     let public_key = PublicKey::from(&secret_key);
+//!
+//!     let signed = PairingTokenEngine::sign(
+//!         PairingTokenEngine::generate(Box::from(&b"resource"[..])),
+//!         &public_key,
+//!         |randomized_unsigned| PairingTokenEngine::sign_randomized(randomized_unsigned, &secret_key)
+//!     ).unwrap();
+//!
+//!     let bytes = signed.to_bytes();
+//!     assert!(bytes.len() < serde_json::to_vec(&signed).unwrap().len() / 3);
+//!
+//!     let decoded = PairingSignedToken::<Box<[u8]>>::from_bytes(&bytes).unwrap();
+//!     assert!(PairingTokenEngine::verify(&decoded, &public_key).is_ok());
+//! ```
+
+use alloc::vec::Vec;
+use core::{convert::TryInto, fmt};
+
+use crate::common::{KeyEpoch, KeyId, TokenIdentifier};
+
+/// The version of the encodings, which a verifier may pin, see [`trust`](crate::trust)
+///
+/// The encodings of version 1 started with the type byte, without the ciphersuite.
+pub const WIRE_VERSION: u8 = 2;
+
+/// The engine and the version of an encoding, the first byte of the encoding
+///
+/// The bytes are above the type bytes, so an encoding of wire version 1 is told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ciphersuite {
+    /// The pairing engine
+    PairingV1,
+    /// The pairing engine with the [`HmReduced`](crate::atpm_pairing::hm::HmReduced) hash
+    PairingReducedV1,
+    /// The curve25519 engine
+    RistrettoV1,
+    /// The generic engine of `atpm_nizkp` on secp256k1
+    Secp256k1V1,
+}
+
+impl Ciphersuite {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0xa1 => Some(Self::PairingV1),
+            0xa2 => Some(Self::RistrettoV1),
+            0xa3 => Some(Self::Secp256k1V1),
+            0xa4 => Some(Self::PairingReducedV1),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Self::PairingV1 => 0xa1,
+            Self::RistrettoV1 => 0xa2,
+            Self::Secp256k1V1 => 0xa3,
+            Self::PairingReducedV1 => 0xa4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PairingV1 => "pairing-v1",
+            Self::RistrettoV1 => "ristretto-v1",
+            Self::Secp256k1V1 => "secp256k1-v1",
+            Self::PairingReducedV1 => "pairing-reduced-v1",
+        }
+    }
+}
+
+impl fmt::Display for Ciphersuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The reason some bytes could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    /// The encoding is of another ciphersuite, or of none that is known
+    Ciphersuite,
+    /// The encoding is of wire version 1, without the ciphersuite
+    Version,
+    /// The encoding is of another type
+    Type,
+    /// The bytes ended before the encoding
+    Truncated,
+    /// There are bytes after the encoding
+    TrailingBytes,
+    /// A tag is not one of the known values
+    Tag,
+    /// A point is not on the curve, or a scalar is not canonical
+    InvalidPoint,
+    /// The metadata is too long to be encoded
+    MetadataLength,
+    /// The checksum of a token does not match the token, it was corrupted
+    Integrity,
+    /// Typed metadata is not in its canonical encoding
+    NonCanonical,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ciphersuite => f.write_str("the encoding is of another ciphersuite"),
+            Self::Version => f.write_str("the encoding is of wire version 1"),
+            Self::Type => f.write_str("the encoding is of another type"),
+            Self::Truncated => f.write_str("the encoding is truncated"),
+            Self::TrailingBytes => f.write_str("there are bytes after the encoding"),
+            Self::Tag => f.write_str("unknown tag"),
+            Self::InvalidPoint => f.write_str("invalid point or scalar"),
+            Self::MetadataLength => f.write_str("the metadata is longer than 65535 bytes"),
+            Self::Integrity => f.write_str("the token is corrupted, its checksum does not match"),
+            Self::NonCanonical => f.write_str("the metadata is not canonically encoded"),
+        }
+    }
+}
+
+/// Types with a compact binary encoding
+pub trait WireFormat: Sized {
+    /// The first byte of the encoding
+    const SUITE: Ciphersuite;
+
+    /// The second byte of the encoding
+    const TYPE: u8;
+
+    /// Write the encoding, without the header
+    fn encode(&self, writer: &mut Writer) -> Result<(), WireError>;
+
+    /// Read the encoding, without the header
+    fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError>;
+
+    /// The ciphersuite and type bytes the encoding starts with
+    fn header() -> [u8; 2] {
+        [Self::SUITE.to_byte(), Self::TYPE]
+    }
+
+    /// Encode to bytes
+    ///
+    /// Panics if the metadata is longer than 65535 bytes, see [`WireFormat::try_to_bytes`]
+    fn to_bytes(&self) -> Vec<u8> {
+        self.try_to_bytes().expect("metadata is too long")
+    }
+
+    /// Encode to bytes
+    fn try_to_bytes(&self) -> Result<Vec<u8>, WireError> {
+        let mut writer = Writer {
+            bytes: Self::header().to_vec(),
+        };
+        self.encode(&mut writer)?;
+        Ok(writer.bytes)
+    }
+
+    /// Decode from bytes, all of the bytes has to be used
+    fn from_bytes(bytes: &[u8]) -> Result<Self, WireError> {
+        let mut reader = Reader { bytes };
+        let [suite] = reader.fixed::<1>()?;
+        if suite == Self::TYPE {
+            return Err(WireError::Version);
+        }
+        if suite != Self::SUITE.to_byte() {
+            return Err(WireError::Ciphersuite);
+        }
+        if reader.fixed::<1>()? != [Self::TYPE] {
+            return Err(WireError::Type);
+        }
+
+        let decoded = Self::decode(&mut reader)?;
+
+        if reader.bytes.is_empty() {
+            Ok(decoded)
+        } else {
+            Err(WireError::TrailingBytes)
+        }
+    }
+}
+
+/// Writes the fields of an encoding
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn fixed(&mut self, bytes: impl AsRef<[u8]>) {
+        self.bytes.extend_from_slice(bytes.as_ref());
+    }
+
+    /// Write the bytes, prefixed with their length
+    pub fn prefixed(&mut self, bytes: impl AsRef<[u8]>) -> Result<(), WireError> {
+        let bytes = bytes.as_ref();
+        let len: u16 = bytes
+            .len()
+            .try_into()
+            .map_err(|_e| WireError::MetadataLength)?;
+
+        self.fixed(len.to_be_bytes());
+        self.fixed(bytes);
+        Ok(())
+    }
+
+    pub fn key_epoch(&mut self, epoch: Option<KeyEpoch>) {
+        match epoch {
+            None => self.fixed([0]),
+            Some(epoch) => {
+                self.fixed([1]);
+                self.fixed(epoch.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn key_id(&mut self, key_id: Option<KeyId>) {
+        match key_id {
+            None => self.fixed([0]),
+            Some(key_id) => {
+                self.fixed([1]);
+                self.fixed(key_id.to_bytes());
+            }
+        }
+    }
+
+    pub fn id<M: AsRef<[u8]>>(&mut self, id: &TokenIdentifier<M>) -> Result<(), WireError> {
+        match id.hidden() {
+            None => {
+                self.fixed([0]);
+                self.fixed(id.id());
+            }
+            Some(hidden) => {
+                self.fixed([1]);
+                self.fixed(id.id());
+                self.prefixed(hidden)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the fields of an encoding
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        if self.bytes.len() < len {
+            return Err(WireError::Truncated);
+        }
+
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn fixed<const N: usize>(&mut self) -> Result<[u8; N], WireError> {
+        // take returns exactly N bytes, so this does not fail
+        self.take(N)?.try_into().map_err(|_e| WireError::Truncated)
+    }
+
+    /// Read bytes that are prefixed with their length
+    pub fn prefixed(&mut self) -> Result<&'a [u8], WireError> {
+        let len = u16::from_be_bytes(self.fixed()?);
+        self.take(len as usize)
+    }
+
+    pub fn key_epoch(&mut self) -> Result<Option<KeyEpoch>, WireError> {
+        match self.fixed::<1>()? {
+            [0] => Ok(None),
+            [1] => Ok(Some(KeyEpoch::from_be_bytes(self.fixed()?))),
+            _ => Err(WireError::Tag),
+        }
+    }
+
+    pub fn key_id(&mut self) -> Result<Option<KeyId>, WireError> {
+        match self.fixed::<1>()? {
+            [0] => Ok(None),
+            [1] => Ok(Some(KeyId::from_bytes(self.fixed()?))),
+            _ => Err(WireError::Tag),
+        }
+    }
+
+    pub fn id<M: AsRef<[u8]> + for<'b> From<&'b [u8]>>(
+        &mut self,
+    ) -> Result<TokenIdentifier<M>, WireError> {
+        match self.fixed::<1>()? {
+            [0] => Ok(TokenIdentifier::from_id(self.fixed()?)),
+            [1] => {
+                let t = self.fixed()?;
+                Ok(TokenIdentifier::from_hidden(t, M::from(self.prefixed()?)))
+            }
+            _ => Err(WireError::Tag),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    struct Data {
+        id: TokenIdentifier<Box<[u8]>>,
+        metadata: Box<[u8]>,
+        epoch: Option<KeyEpoch>,
+        key_id: Option<KeyId>,
+    }
+
+    impl WireFormat for Data {
+        const SUITE: Ciphersuite = Ciphersuite::RistrettoV1;
+        const TYPE: u8 = 0xff;
+
+        fn encode(&self, writer: &mut Writer) -> Result<(), WireError> {
+            writer.id(&self.id)?;
+            writer.prefixed(&self.metadata)?;
+            writer.key_epoch(self.epoch);
+            writer.key_id(self.key_id);
+            Ok(())
+        }
+
+        fn decode(reader: &mut Reader<'_>) -> Result<Self, WireError> {
+            Ok(Self {
+                id: reader.id()?,
+                metadata: Box::from(reader.prefixed()?),
+                epoch: reader.key_epoch()?,
+                key_id: reader.key_id()?,
+            })
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = Data {
+            id: TokenIdentifier::with_hidden(Box::from(&b"hidden"[..])),
+            metadata: Box::from(&b"metadata"[..]),
+            epoch: Some(3),
+            key_id: Some(KeyId::from_bytes([7; 8])),
+        };
+
+        let bytes = data.to_bytes();
+        // header, id tag, id, hidden, metadata, epoch, key id
+        assert_eq!(bytes.len(), 2 + 1 + 16 + 2 + 6 + 2 + 8 + 1 + 4 + 1 + 8);
+
+        let decoded = Data::from_bytes(&bytes).unwrap();
+        assert!(decoded.id == data.id);
+        assert_eq!(decoded.metadata, data.metadata);
+        assert_eq!(decoded.epoch, Some(3));
+        assert_eq!(decoded.key_id, data.key_id);
+    }
+
+    #[test]
+    fn fail_bad_bytes() {
+        let data = Data {
+            id: TokenIdentifier::new(),
+            metadata: Box::from(&b"metadata"[..]),
+            epoch: None,
+            key_id: None,
+        };
+        let bytes = data.to_bytes();
+
+        assert_eq!(
+            Data::from_bytes(&bytes[2..]).err(),
+            Some(WireError::Ciphersuite)
+        );
+        // wire version 1
+        assert_eq!(
+            Data::from_bytes(&bytes[1..]).err(),
+            Some(WireError::Version)
+        );
+
+        let mut other_type = bytes.clone();
+        other_type[1] = 0xfe;
+        assert_eq!(Data::from_bytes(&other_type).err(), Some(WireError::Type));
+        assert_eq!(
+            Data::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some(WireError::Truncated)
+        );
+
+        let mut long = bytes.clone();
+        long.push(0);
+        assert_eq!(
+            Data::from_bytes(&long).err(),
+            Some(WireError::TrailingBytes)
+        );
+
+        let mut bad_tag = bytes;
+        bad_tag[2] = 7;
+        assert_eq!(Data::from_bytes(&bad_tag).err(), Some(WireError::Tag));
+
+        let too_long = Data {
+            id: TokenIdentifier::new(),
+            metadata: alloc::vec![0; 1 << 16].into_boxed_slice(),
+            epoch: None,
+            key_id: None,
+        };
+        assert_eq!(
+            too_long.try_to_bytes().err(),
+            Some(WireError::MetadataLength)
+        );
+    }
+}
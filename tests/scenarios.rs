@@ -0,0 +1,387 @@
+//! # Scenarios
+//!
+//! End to end runs of the protocol between an issuer, a client and a verifier, for the pairing and
+//! the curve25519 engines, a test for each scenario and engine, as a regression suite of the
+//! protocol.
+//! There is no network, but everything between the roles is sent as bytes in the wire format.
+//!
+//! - The issuer has a key per epoch, signs with the latest and fills in its epoch.
+//! - The client keeps the public keys and a wallet of tokens, gets them one by one or refills a
+//!   batch.
+//! - The verifier keeps the verification keys of the epochs it accepts in a key ring, and the
+//!   tokens it has seen. The metadata is the day the token was issued, so tokens expire.
+//!
+//! The generic engine of `atpm_nizkp` is not here, as it has no wire format.
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+
+use atpmd::wire::WireFormat;
+use atpmd::{KeyEpoch, KeyRing, PublicKeySet, RandomizedSignedToken, SignedToken, TokenEngine};
+
+/// The number of days a token may be redeemed after the day it was issued
+const MAX_AGE_DAYS: u32 = 7;
+
+/// Why the verifier did not accept a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejected {
+    Malformed,
+    Expired,
+    Invalid,
+    DoubleSpend,
+}
+
+fn day(today: u32) -> Box<[u8]> {
+    Box::from(&today.to_be_bytes()[..])
+}
+
+/// The roles and the scenarios for an engine, the single and batched engines of a backend
+macro_rules! scenarios {
+    (
+        $name:ident,
+        $backend:ident,
+        $engine:ident,
+        $batched:ident,
+        verification_key = |$key:ident| $verification_key:expr,
+        split = |$batch:ident| $split:expr $(,)?
+    ) => {
+        mod $name {
+            use super::*;
+
+            use atpmd::$backend::{
+                keys::{PrivateKey, PublicKey},
+                tokens::{
+                    $engine, RandomizedSignedToken as Response, RandomizedUnsignedToken as Request,
+                },
+                tokens_batched_dyn::$batched,
+            };
+
+            type Engine = $engine<Box<[u8]>>;
+            type Batched = $batched<Box<[u8]>>;
+            type Token = <Engine as TokenEngine>::SignedToken;
+            type VerificationKey = <Token as SignedToken>::VerificationKey;
+
+            fn verification_key($key: &PrivateKey) -> VerificationKey {
+                $verification_key
+            }
+
+            // {{{ Issuer
+
+            struct Issuer {
+                keys: Vec<(KeyEpoch, PrivateKey)>,
+            }
+
+            impl Issuer {
+                fn new() -> Self {
+                    Self {
+                        keys: vec![(1, PrivateKey::new())],
+                    }
+                }
+
+                fn current(&self) -> &(KeyEpoch, PrivateKey) {
+                    self.keys.last().unwrap()
+                }
+
+                /// Start signing with a new key, the old keys are still valid
+                fn rotate(&mut self) -> KeyEpoch {
+                    let epoch = self.current().0 + 1;
+                    self.keys.push((epoch, PrivateKey::new()));
+                    epoch
+                }
+
+                /// Stop accepting the tokens of an epoch
+                fn retire(&mut self, epoch: KeyEpoch) {
+                    self.keys.retain(|(e, _)| *e != epoch);
+                }
+
+                fn public_keys(&self) -> PublicKeySet<PublicKey> {
+                    let mut keys = PublicKeySet::new();
+                    for (epoch, key) in &self.keys {
+                        keys.insert(*epoch, PublicKey::from(key));
+                    }
+                    keys
+                }
+
+                fn verification_keys(&self) -> KeyRing<VerificationKey> {
+                    let mut keys = KeyRing::new();
+                    for (epoch, key) in &self.keys {
+                        keys.insert(*epoch, verification_key(key));
+                    }
+                    keys
+                }
+
+                fn sign(&self, request: &[u8]) -> Vec<u8> {
+                    let (epoch, key) = self.current();
+                    let request = Request::<Box<[u8]>>::from_bytes(request).unwrap();
+
+                    Engine::sign_randomized(&request, key)
+                        .unwrap()
+                        .with_key_epoch(*epoch)
+                        .to_bytes()
+                }
+
+                fn sign_batch(
+                    &self,
+                    request: &<Batched as TokenEngine>::RandomizedUnsignedToken,
+                ) -> <Batched as TokenEngine>::RandomizedSignedToken {
+                    let (epoch, key) = self.current();
+                    Batched::sign_randomized(request, key)
+                        .unwrap()
+                        .with_key_epoch(*epoch)
+                }
+            }
+
+            // }}}
+
+            // {{{ Client
+
+            struct Client {
+                keys: PublicKeySet<PublicKey>,
+                wallet: Vec<Token>,
+            }
+
+            impl Client {
+                fn new(issuer: &Issuer) -> Self {
+                    Self {
+                        keys: issuer.public_keys(),
+                        wallet: Vec::new(),
+                    }
+                }
+
+                /// Fetch the public keys again, e.g. after the issuer rotated its key
+                fn sync(&mut self, issuer: &Issuer) {
+                    self.keys = issuer.public_keys();
+                }
+
+                fn issue(&mut self, issuer: &Issuer, today: u32) -> bool {
+                    let unsigned = Engine::generate(day(today));
+                    let (r, request) = Engine::randomize(&unsigned);
+
+                    let response = issuer.sign(&request.to_bytes());
+                    let response = match Response::from_bytes(&response) {
+                        Ok(response) => response,
+                        Err(_) => return false,
+                    };
+
+                    match Engine::verify_signature_and_unrandomize_with_key_set(
+                        unsigned, request, response, &self.keys, r,
+                    ) {
+                        Ok(token) => {
+                            self.wallet.push(token);
+                            true
+                        }
+                        Err(_) => false,
+                    }
+                }
+
+                fn refill(&mut self, issuer: &Issuer, today: u32, count: usize) -> bool {
+                    let unsigned = Batched::generate((day(today), count));
+                    let (r, request) = Batched::randomize(&unsigned);
+
+                    let response = issuer.sign_batch(&request);
+
+                    match Batched::verify_signature_and_unrandomize_with_key_set(
+                        unsigned, request, response, &self.keys, r,
+                    ) {
+                        Ok($batch) => {
+                            self.wallet.extend($split);
+                            true
+                        }
+                        Err(_) => false,
+                    }
+                }
+
+                /// Take a token out of the wallet, to send it to the verifier
+                fn spend(&mut self) -> Option<Vec<u8>> {
+                    self.wallet.pop().map(|token| token.to_bytes())
+                }
+            }
+
+            // }}}
+
+            // {{{ Verifier
+
+            struct Verifier {
+                keys: KeyRing<VerificationKey>,
+                spent: BTreeSet<Vec<u8>>,
+            }
+
+            impl Verifier {
+                fn new(issuer: &Issuer) -> Self {
+                    Self {
+                        keys: issuer.verification_keys(),
+                        spent: BTreeSet::new(),
+                    }
+                }
+
+                fn sync(&mut self, issuer: &Issuer) {
+                    self.keys = issuer.verification_keys();
+                }
+
+                fn redeem(&mut self, token: &[u8], today: u32) -> Result<(), Rejected> {
+                    let decoded = Token::from_bytes(token).map_err(|_e| Rejected::Malformed)?;
+
+                    let issued = <[u8; 4]>::try_from(decoded.metadata().as_ref())
+                        .map(u32::from_be_bytes)
+                        .map_err(|_e| Rejected::Malformed)?;
+                    if issued > today || today - issued > MAX_AGE_DAYS {
+                        return Err(Rejected::Expired);
+                    }
+
+                    if !self.keys.verify(&decoded) {
+                        return Err(Rejected::Invalid);
+                    }
+
+                    // the encoding is canonical, so it may identify the token
+                    if !self.spent.insert(token.to_vec()) {
+                        return Err(Rejected::DoubleSpend);
+                    }
+
+                    Ok(())
+                }
+            }
+
+            // }}}
+
+            #[test]
+            fn issue_and_redeem() {
+                let issuer = Issuer::new();
+                let mut client = Client::new(&issuer);
+                let mut verifier = Verifier::new(&issuer);
+
+                assert!(client.issue(&issuer, 100));
+                let token = client.spend().unwrap();
+                assert_eq!(verifier.redeem(&token, 100), Ok(()));
+
+                assert!(client.spend().is_none());
+            }
+
+            #[test]
+            fn batched_refill() {
+                let issuer = Issuer::new();
+                let mut client = Client::new(&issuer);
+                let mut verifier = Verifier::new(&issuer);
+
+                assert!(client.refill(&issuer, 100, 8));
+                assert!(client.issue(&issuer, 100));
+                assert_eq!(client.wallet.len(), 9);
+
+                while let Some(token) = client.spend() {
+                    assert_eq!(verifier.redeem(&token, 101), Ok(()));
+                }
+                assert_eq!(verifier.spent.len(), 9);
+            }
+
+            #[test]
+            fn double_spend() {
+                let issuer = Issuer::new();
+                let mut client = Client::new(&issuer);
+                let mut verifier = Verifier::new(&issuer);
+
+                assert!(client.issue(&issuer, 100));
+                let token = client.spend().unwrap();
+
+                assert_eq!(verifier.redeem(&token, 100), Ok(()));
+                assert_eq!(verifier.redeem(&token, 100), Err(Rejected::DoubleSpend));
+            }
+
+            #[test]
+            fn key_rotation() {
+                let mut issuer = Issuer::new();
+                let mut client = Client::new(&issuer);
+                let mut verifier = Verifier::new(&issuer);
+
+                assert!(client.issue(&issuer, 100));
+                let old = client.spend().unwrap();
+
+                // the client can not unrandomize with a key it does not know
+                let epoch = issuer.rotate();
+                assert!(!client.issue(&issuer, 100));
+                assert!(!client.refill(&issuer, 100, 2));
+
+                client.sync(&issuer);
+                assert_eq!(client.keys.latest_epoch(), Some(epoch));
+                assert!(client.issue(&issuer, 100));
+                let new = client.spend().unwrap();
+                let new_key_id = Token::from_bytes(&new).unwrap().key_id();
+                assert_eq!(
+                    verifier.keys.epoch(new_key_id.unwrap()),
+                    None,
+                    "the verifier does not know the new key yet"
+                );
+
+                // the verifier needs the new key, and still accepts the old tokens
+                assert_eq!(verifier.redeem(&new, 100), Err(Rejected::Invalid));
+                verifier.sync(&issuer);
+                assert_eq!(verifier.keys.epoch(new_key_id.unwrap()), Some(epoch));
+                assert_eq!(verifier.redeem(&new, 100), Ok(()));
+
+                // until the old key is retired
+                issuer.retire(1);
+                verifier.sync(&issuer);
+                assert_eq!(verifier.redeem(&old, 100), Err(Rejected::Invalid));
+            }
+
+            #[test]
+            fn expiry() {
+                let issuer = Issuer::new();
+                let mut client = Client::new(&issuer);
+                let mut verifier = Verifier::new(&issuer);
+
+                assert!(client.refill(&issuer, 100, 3));
+
+                let token = client.spend().unwrap();
+                assert_eq!(verifier.redeem(&token, 100 + MAX_AGE_DAYS), Ok(()));
+
+                let token = client.spend().unwrap();
+                assert_eq!(
+                    verifier.redeem(&token, 101 + MAX_AGE_DAYS),
+                    Err(Rejected::Expired)
+                );
+
+                // nor from the future
+                let token = client.spend().unwrap();
+                assert_eq!(verifier.redeem(&token, 99), Err(Rejected::Expired));
+            }
+
+            #[test]
+            fn forged() {
+                let issuer = Issuer::new();
+                let mut verifier = Verifier::new(&issuer);
+
+                // a token from someone else's issuer
+                let other = Issuer::new();
+                let mut client = Client::new(&other);
+                assert!(client.issue(&other, 100));
+                let token = client.spend().unwrap();
+                assert_eq!(verifier.redeem(&token, 100), Err(Rejected::Invalid));
+
+                // and something that is not a token at all
+                assert_eq!(
+                    verifier.redeem(&token[..token.len() - 1], 100),
+                    Err(Rejected::Malformed)
+                );
+            }
+        }
+    };
+}
+
+#[cfg(feature = "pairing")]
+scenarios!(
+    pairing,
+    atpm_pairing,
+    PairingTokenEngine,
+    DynBatchedPairingTokenEngine,
+    verification_key = |key| PublicKey::from(key),
+    split = |batch| batch.iter(),
+);
+
+#[cfg(feature = "curve25519")]
+scenarios!(
+    curve25519,
+    nizkp_curve25519,
+    NizkpTokenEngine,
+    DynBatchedNizkpTokenEngine,
+    verification_key = |key| key.clone(),
+    split = |batch| batch.into_tokens(),
+);
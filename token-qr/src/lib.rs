@@ -7,7 +7,9 @@ use wasm_bindgen::prelude::*;
 
 use reqwasm::http::Request;
 
-use atpmd::{TokenEngine, atpm_pairing::{keys::{PrivateKey, PublicKey}, tokens::{PairingSignedToken, PairingTokenEngine, RandomizedUnsignedToken}}};
+use atpmd::{TokenEngine, atpm_pairing::{keys::{PrivateKey, PublicKey}, refusal::PairingSignedRefusal, tokens::{PairingSignedToken, PairingTokenEngine, RandomizedSignedToken, RandomizedUnsignedToken}}};
+use atpmd::issuance::{IssuanceRequestMsg, IssuanceResponseMsg};
+use atpmd::refusal::SignResponse;
 
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -20,6 +22,10 @@ extern "C" {
     fn log(s: &str);
 
     fn alert(s: &str);
+
+    // the time in milliseconds
+    #[wasm_bindgen(js_namespace = Date)]
+    fn now() -> f64;
 }
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -35,8 +41,8 @@ pub struct QrClient {
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct GetToken<M: AsRef<[u8]>> {
-    pub point: RandomizedUnsignedToken<M>,
+pub struct GetTokens<M: AsRef<[u8]>> {
+    pub request: IssuanceRequestMsg<M>,
     pub username: String,
     pub password: String,
 }
@@ -85,8 +91,8 @@ impl QrClient {
         let (r, randomized) = PairingTokenEngine::randomize(&unsigned_token);
 
         // This is a bad way of using password authentication, do not do the same
-        let get_token_struct = GetToken {
-            point: randomized,
+        let get_token_struct = GetTokens {
+            request: IssuanceRequestMsg::from(randomized),
             username,
             password,
         };
@@ -95,14 +101,11 @@ impl QrClient {
             .map_err(|e| format!("{}", e))?;
 
         // small hack to avoid having to clone randomized.
-        let GetToken {
-            point: randomized,
-            username: _,
-            password: _
-        } = get_token_struct;
+        let randomized = RandomizedUnsignedToken::try_from(get_token_struct.request)
+            .map_err(|e| format!("{}", e))?;
 
         // Send the token and the cidentials to the server to get the token signed
-        let signed = Request::post("/sign")
+        let signed: IssuanceResponseMsg<_> = Request::post("/sign")
                 .body(get_token)
                 .send()
                 .await
@@ -111,10 +114,16 @@ impl QrClient {
                 .await
                 .map_err(|e| format!("{}", e))?;
 
+        // the server says why if it did not sign
+        let signed = SignResponse::<RandomizedSignedToken<_>, PairingSignedRefusal>::try_from(signed)
+            .map_err(|e| format!("{}", e))?
+            .into_result(resource.as_bytes(), &key, (now() / 1000.0) as u64)
+            .map_err(|e| format!("{}", e))?;
+
         PairingTokenEngine::verify_signature_and_unrandomize(unsigned_token, randomized, signed, &key, r)
-            .map(|t| t.try_into().ok())
-            .flatten()
-            .ok_or_else(|| "Bad signature".to_owned())
+            .map_err(|e| format!("{}", e))?
+            .try_into()
+            .map_err(|e| format!("{}", e))
     }
 
     /// Creates a keypair and returns the qr-code of a token signed with this keypair